@@ -0,0 +1,140 @@
+//! Writes the man pages, generated by clap_mangen from the commands of
+//! src/cli/args.rs, to $OUT_DIR: torrent_rs.1 and a torrent_rs-<command>.1
+//! for each command. They go to $TORRENT_RS_MAN_DIR too when it is set for
+//! packages to install them from there. With the ffi feature, the C header
+//! goes to $OUT_DIR/torrent_rs.h and to $TORRENT_RS_INCLUDE_DIR alike. With
+//! the grpc feature, the code of src/grpc/torrent_rs.proto is generated by
+//! tonic with the protoc vendored.
+
+#[allow(dead_code)]
+#[path = "src/cli/args.rs"]
+mod args;
+
+#[allow(dead_code)]
+#[path = "src/ffi/header.rs"]
+mod header;
+
+use clap::CommandFactory;
+use clap_mangen::Man;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+/// the sections clap doesn't know of, after those it writes
+const SECTIONS: &str = "\
+.SH EXIT STATUS
+.TP
+0
+success
+.TP
+1
+any other error, like invalid arguments
+.TP
+2
+the data is incomplete
+.TP
+3
+a piece doesn't match its hash
+.TP
+4
+the torrent can't be read or isn't known
+.TP
+5
+no tracker answered
+.TP
+6
+reading or writing the data failed
+.SH ENVIRONMENT
+.TP
+.B TORRENT_RS_LOG
+what to log when neither \\-v nor \\-q is given, like warn,torrent_rs::tracker=debug, warn by default
+.TP
+.B TORRENT_RS_API_TOKEN
+token the REST api of daemon \\-\\-api asks for
+.TP
+.B XDG_RUNTIME_DIR
+where the daemon listens for commands
+.SH FILES
+.TP
+.B $XDG_DATA_HOME/torrent_rs/dht.state
+nodes of the DHT, kept to rejoin it faster, dht6.state next to it for IPv6. \
+$XDG_DATA_HOME is ~/.local/share when unset
+";
+
+fn main() -> Result<()> {
+    println!("cargo:rerun-if-changed=src/cli/args.rs");
+    println!("cargo:rerun-if-env-changed=TORRENT_RS_MAN_DIR");
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    let mut command = args::Cli::command().disable_help_subcommand(true);
+    command.build();
+    write(
+        &out_dir,
+        "TORRENT_RS_MAN_DIR",
+        "torrent_rs.1",
+        &main_page(&command)?,
+    )?;
+    for command in command
+        .get_subcommands()
+        .filter(|command| !command.is_hide_set())
+    {
+        let man = Man::new(command.clone());
+        let mut page = vec![];
+        man.render(&mut page)?;
+        write(
+            &out_dir,
+            "TORRENT_RS_MAN_DIR",
+            &man.get_filename(),
+            &String::from_utf8_lossy(&page),
+        )?;
+    }
+    if std::env::var_os("CARGO_FEATURE_FFI").is_some() {
+        println!("cargo:rerun-if-changed=src/ffi/mod.rs");
+        println!("cargo:rerun-if-changed=src/ffi/header.rs");
+        println!("cargo:rerun-if-env-changed=TORRENT_RS_INCLUDE_DIR");
+        let source = std::fs::read_to_string("src/ffi/mod.rs")?;
+        let header = header::generate(&source)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+        write(&out_dir, "TORRENT_RS_INCLUDE_DIR", "torrent_rs.h", &header)?;
+    }
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path()
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::NotFound, error))?;
+        std::env::set_var("PROTOC", protoc);
+        // `connect` of the client needs the prelude of edition 2021, a
+        // channel is given to `new` instead
+        tonic_prost_build::configure()
+            .build_transport(false)
+            .compile_protos(&["src/grpc/torrent_rs.proto"], &["src/grpc"])?;
+    }
+    Ok(())
+}
+
+/// the sections of clap_mangen, with `SECTIONS` in place of the exit codes
+/// of the help
+fn main_page(command: &clap::Command) -> Result<String> {
+    let man = Man::new(command.clone());
+    let mut page = vec![];
+    man.render_title(&mut page)?;
+    man.render_name_section(&mut page)?;
+    man.render_synopsis_section(&mut page)?;
+    man.render_description_section(&mut page)?;
+    man.render_options_section(&mut page)?;
+    man.render_subcommands_section(&mut page)?;
+    page.extend_from_slice(SECTIONS.as_bytes());
+    man.render_version_section(&mut page)?;
+    // each section starts with the preamble, it is needed once
+    let preamble = ".ie \\n(.g .ds Aq \\(aq\n.el .ds Aq '\n";
+    let page = String::from_utf8_lossy(&page).replace(preamble, "");
+    Ok(format!("{}{}", preamble, page))
+}
+
+/// to $OUT_DIR, and to the dir in `variable` when it is set
+fn write(out_dir: &Path, variable: &str, name: &str, contents: &str) -> Result<()> {
+    std::fs::write(out_dir.join(name), contents)?;
+    if let Some(dir) = std::env::var_os(variable) {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(name), contents)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1608 @@
+//! A REST api over HTTP to add, list and control the torrents of a session,
+//! read its statistics and change its settings, for web interfaces and
+//! scripts. Requests and answers are JSON:
+//!
+//! ```text
+//! GET    /api/torrents                 every torrent
+//! POST   /api/torrents                 {"uri":"magnet:.."} or {"path":"a.torrent"} with
+//!                                      "save_path" and "paused", or the .torrent itself as
+//!                                      application/x-bittorrent with ?save_path=&paused=
+//! GET    /api/torrents/{id}            one with its files, trackers and peers
+//! POST   /api/torrents/{id}/pause
+//! POST   /api/torrents/{id}/resume
+//! DELETE /api/torrents/{id}            ?delete_data=true deletes its files too
+//! GET    /api/torrents/{id}/peers
+//! GET    /api/stats                    rates, torrents by state and connections
+//! GET    /api/settings                 rate, connection and queue limits
+//! PATCH  /api/settings                 changes the limits given
+//! GET    /api/events                   a snapshot of the torrents, or their events
+//!                                      pushed over a WebSocket
+//! ```
+//!
+//! A torrent `{id}` is a prefix of its info hash or its name, see
+//! `Session::find`. Errors are `{"error":".."}` with a 4xx or 5xx status.
+//! With a token every request needs an `Authorization: Bearer <token>`
+//! header, or basic authentication with the token as password. Without one
+//! the api only listens on loopback addresses, see `Config::validate`.
+//! Bodies are `application/json`, requests from web pages of other origins
+//! than the api and the allowed ones are refused, and the pages of the
+//! allowed origins get CORS headers. Only `.torrent` files are read by
+//! `"path"`.
+//!
+//! Upgraded to a WebSocket, `/api/events` sends `{"type":"snapshot",
+//! "cursor":..,"torrents":[..],"stats":{..}}` then each event of the session
+//! as `{"type":"event","cursor":..,"event":{..}}`, see
+//! `SessionEvent::to_json`. A client reconnecting with `?cursor=` gets the
+//! events it missed if they are still buffered, another snapshot
+//! otherwise, as does a client falling too far behind. Browsers can't set
+//! headers on a WebSocket, the token may be given as `?token=` instead.
+//!
+//! Clients of Transmission find its RPC at `/transmission/rpc`, see
+//! `transmission`. With the `web-ui` feature, a page at `/` manages the
+//! torrents from a browser over this api. With the `grpc` feature, clients
+//! speaking HTTP/2 get the same calls as a gRPC service, see `grpc`.
+
+use crate::base64;
+use crate::events::SessionEvent;
+use crate::http::{self, Request};
+use crate::json::Json;
+use crate::magnet::percent_decode;
+use crate::metainfo::{to_hex, Metainfo};
+use crate::picker::Priority;
+use crate::session::{AddOptions, Session};
+use crate::torrent::TorrentHandle;
+use crate::transmission::{Rpc, SESSION_ID_HEADER};
+use crate::websocket::{self, CLOSE, PING, PONG, TEXT};
+use anyhow::Result;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// how long the session gets to answer, and a client to read it
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// clients served at once, event streams included, each has a thread, the
+/// others wait to be accepted
+const MAX_CLIENTS: usize = 64;
+/// large enough for the .torrent of a big torrent
+pub(crate) const MAX_BODY: usize = 16 * 1024 * 1024;
+/// the type of a .torrent added as the body
+const TORRENT_TYPE: &str = "application/x-bittorrent";
+/// events kept for clients to resume from
+const EVENT_LOG: usize = 4096;
+/// between pings to an event stream with nothing to send, so proxies
+/// don't drop it
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Where and to whom the api is served, see `Config::api`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiConfig {
+    pub addr: SocketAddr,
+    /// None lets in anyone reaching the address
+    pub token: Option<String>,
+    /// origins of the web pages allowed to call the api, "*" for any
+    pub cors_origins: Vec<String>,
+}
+
+/// What a request asks for, once its method and path are known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Torrents,
+    AddTorrent,
+    Torrent(String),
+    Pause(String),
+    Resume(String),
+    Remove(String),
+    Peers(String),
+    Stats,
+    Settings,
+    UpdateSettings,
+    /// the snapshot an event stream starts from
+    Events,
+    /// a call of the RPC of Transmission
+    Transmission,
+}
+
+impl Route {
+    /// the error is the status and message to answer with
+    pub fn parse(method: &str, path: &str) -> Result<Self, ApiError> {
+        let parts: Vec<_> = path.trim_end_matches('/').split('/').skip(1).collect();
+        let id = |id: &str| {
+            percent_decode(id).map_err(|error| ApiError::new(400, format!("{:#}", error)))
+        };
+        let (route, allowed): (Option<Route>, &str) = match parts.as_slice() {
+            ["api", "torrents"] => (
+                match method {
+                    "GET" => Some(Route::Torrents),
+                    "POST" => Some(Route::AddTorrent),
+                    _ => None,
+                },
+                "GET, POST",
+            ),
+            ["api", "torrents", torrent] => (
+                match method {
+                    "GET" => Some(Route::Torrent(id(torrent)?)),
+                    "DELETE" => Some(Route::Remove(id(torrent)?)),
+                    _ => None,
+                },
+                "GET, DELETE",
+            ),
+            ["api", "torrents", torrent, action] => {
+                let (route, allowed) = match *action {
+                    "pause" => (Route::Pause(id(torrent)?), "POST"),
+                    "resume" => (Route::Resume(id(torrent)?), "POST"),
+                    "peers" => (Route::Peers(id(torrent)?), "GET"),
+                    _ => return Err(ApiError::new(404, "not found")),
+                };
+                ((method == allowed).then_some(route), allowed)
+            }
+            ["api", "stats"] => ((method == "GET").then_some(Route::Stats), "GET"),
+            ["api", "settings"] => (
+                match method {
+                    "GET" => Some(Route::Settings),
+                    "PATCH" => Some(Route::UpdateSettings),
+                    _ => None,
+                },
+                "GET, PATCH",
+            ),
+            ["api", "events"] => ((method == "GET").then_some(Route::Events), "GET"),
+            ["transmission", "rpc"] => ((method == "POST").then_some(Route::Transmission), "POST"),
+            _ => return Err(ApiError::new(404, "not found")),
+        };
+        route.ok_or_else(|| ApiError::new(405, format!("only {} is supported", allowed)))
+    }
+}
+
+/// An answer other than 200, with the message of `{"error":..}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(error: anyhow::Error) -> Self {
+        Self::new(400, format!("{:#}", error))
+    }
+
+    fn not_found(error: anyhow::Error) -> Self {
+        Self::new(404, format!("{:#}", error))
+    }
+
+    fn internal(error: anyhow::Error) -> Self {
+        Self::new(500, format!("{:#}", error))
+    }
+}
+
+/// A request waiting for the session to answer it, see `Session::poll_api`
+pub struct PendingApiRequest {
+    pub route: Route,
+    /// decoded parameters of the query string
+    pub query: Vec<(String, String)>,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+    rpc: Arc<Mutex<Rpc>>,
+    events: Arc<EventLog>,
+    reply: Sender<Result<Json, ApiError>>,
+}
+
+impl PendingApiRequest {
+    /// the value of a query parameter
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// answers the request against the session
+    pub fn apply(self, session: &mut Session) {
+        let result = answer(&self, session);
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Serves the api, handing the requests to the loop owning the session
+/// through `poll` like `control::ControlServer`
+pub struct ApiServer {
+    addr: SocketAddr,
+    requests: Receiver<PendingApiRequest>,
+    events: Receiver<SessionEvent>,
+    log: Arc<EventLog>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<JoinHandle<()>>,
+}
+
+impl ApiServer {
+    /// `events` are streamed to the clients of `/api/events`, see
+    /// `Session::subscribe`
+    pub fn start(config: ApiConfig, events: Receiver<SessionEvent>) -> Result<Self> {
+        let listener = TcpListener::bind(config.addr)?;
+        // wakes up regularly to stop when asked to
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let (sender, requests) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let log = Arc::new(EventLog::default());
+        #[cfg(feature = "grpc")]
+        let (grpc, clients) = tokio::sync::mpsc::unbounded_channel();
+        let server = Arc::new(Server {
+            config,
+            rpc: Arc::new(Mutex::new(Rpc::new())),
+            events: Arc::clone(&log),
+            requests: sender,
+            stop: Arc::clone(&stop),
+            serving: AtomicUsize::new(0),
+            #[cfg(feature = "grpc")]
+            grpc,
+        });
+        #[cfg(feature = "grpc")]
+        let grpc = crate::grpc::start(Arc::clone(&server), clients)?;
+        let worker = thread::spawn(move || serve(listener, server));
+        Ok(Self {
+            addr,
+            requests,
+            events,
+            log,
+            stop,
+            worker: Some(worker),
+            #[cfg(feature = "grpc")]
+            grpc: Some(grpc),
+        })
+    }
+
+    /// the address actually listened on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// the next request waiting, without blocking, once the events so far
+    /// are logged for the streams
+    pub fn poll(&self) -> Option<PendingApiRequest> {
+        for event in self.events.try_iter() {
+            self.log.push(event.to_json());
+        }
+        self.requests.try_recv().ok()
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = self.grpc.take() {
+            let _ = grpc.join();
+        }
+    }
+}
+
+/// What the threads answering clients share
+pub(crate) struct Server {
+    pub(crate) config: ApiConfig,
+    rpc: Arc<Mutex<Rpc>>,
+    pub(crate) events: Arc<EventLog>,
+    requests: Sender<PendingApiRequest>,
+    pub(crate) stop: Arc<AtomicBool>,
+    /// connections with a thread, up to `MAX_CLIENTS`
+    serving: AtomicUsize,
+    /// where the clients speaking HTTP/2 go
+    #[cfg(feature = "grpc")]
+    grpc: crate::grpc::Clients,
+}
+
+/// The last events of the session as streamed to clients, numbered from 1
+/// by their cursor. Only the session thread pushes, so a snapshot taken
+/// there is up to date with the cursor it is sent with.
+#[derive(Debug, Default)]
+pub(crate) struct EventLog {
+    log: Mutex<Events>,
+    pushed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Events {
+    /// of the last event, 0 before any
+    cursor: u64,
+    buffered: VecDeque<(u64, Arc<Json>)>,
+}
+
+impl EventLog {
+    fn push(&self, event: Json) {
+        let mut log = self.log.lock().unwrap();
+        log.cursor += 1;
+        let cursor = log.cursor;
+        log.buffered.push_back((cursor, Arc::new(event)));
+        if log.buffered.len() > EVENT_LOG {
+            log.buffered.pop_front();
+        }
+        self.pushed.notify_all();
+    }
+
+    pub(crate) fn cursor(&self) -> u64 {
+        self.log.lock().unwrap().cursor
+    }
+
+    /// the events after `cursor`, waiting up to `timeout` for one, None if
+    /// some were already dropped or the cursor is unknown
+    pub(crate) fn since(&self, cursor: u64, timeout: Duration) -> Option<Vec<(u64, Arc<Json>)>> {
+        let mut log = self.log.lock().unwrap();
+        if log.cursor == cursor {
+            log = self.pushed.wait_timeout(log, timeout).unwrap().0;
+        }
+        let oldest = log.cursor - log.buffered.len() as u64;
+        (oldest..=log.cursor).contains(&cursor).then(|| {
+            log.buffered
+                .iter()
+                .filter(|(event, _)| *event > cursor)
+                .cloned()
+                .collect()
+        })
+    }
+}
+
+fn serve(listener: TcpListener, server: Arc<Server>) {
+    while !server.stop.load(Ordering::Relaxed) {
+        if server.serving.load(Ordering::Relaxed) >= MAX_CLIENTS {
+            thread::sleep(Duration::from_millis(50));
+            continue;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                server.serving.fetch_add(1, Ordering::Relaxed);
+                let server = Arc::clone(&server);
+                // a slow client doesn't hold up the others
+                thread::spawn(move || {
+                    let _ = respond(stream, &server);
+                    server.serving.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, server: &Arc<Server>) -> io::Result<()> {
+    let config = &server.config;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    #[cfg(feature = "grpc")]
+    if speaks_http2(&stream)? {
+        let _ = server.grpc.send(stream);
+        return Ok(());
+    }
+    let request = match http::read_request(&mut stream, MAX_BODY) {
+        Ok(request) => request,
+        Err(error) => {
+            let error = ApiError::new(error.status, error.message);
+            let response = Response::error(&error, None);
+            return stream.write_all(&response.into_bytes());
+        }
+    };
+    let origin = request
+        .header("origin")
+        .and_then(|origin| allowed_origin(config, origin));
+    let response = if request.method == "OPTIONS" {
+        // preflights are sent without the token
+        Response {
+            status: 204,
+            headers: vec![
+                (
+                    "Access-Control-Allow-Methods",
+                    "GET, POST, PATCH, DELETE, OPTIONS".into(),
+                ),
+                (
+                    "Access-Control-Allow-Headers",
+                    format!("Authorization, Content-Type, {}", SESSION_ID_HEADER),
+                ),
+                ("Access-Control-Max-Age", "600".into()),
+            ],
+            body: vec![],
+        }
+    } else if let Some(response) = web_ui(&request) {
+        response
+    } else if !origin_allowed(config, &request) {
+        Response::error(&ApiError::new(403, "origin not allowed"), None)
+    } else if !authorized(config, authorization(&request).as_deref()) {
+        // Transmission clients ask for a password on basic challenges
+        let scheme = match request.path.as_str() {
+            "/transmission/rpc" => "Basic realm=\"torrent_rs\"",
+            _ => "Bearer",
+        };
+        Response::error(&ApiError::new(401, "missing or wrong token"), Some(scheme))
+    } else if request.path == "/api/events" && request.header("upgrade").is_some() {
+        return stream_events(stream, request, server);
+    } else if request.path == "/transmission/rpc"
+        && request.header(&SESSION_ID_HEADER.to_ascii_lowercase())
+            != Some(server.rpc.lock().unwrap().session_id())
+    {
+        // the session id is told to clients, not to pages of other sites
+        let session_id = server.rpc.lock().unwrap().session_id().to_string();
+        let mut response = Response::error(
+            &ApiError::new(409, format!("{} is missing or outdated", SESSION_ID_HEADER)),
+            None,
+        );
+        response.headers.push((SESSION_ID_HEADER, session_id));
+        response
+    } else {
+        match handle(request, server) {
+            Ok(json) => Response::json(200, &json),
+            Err(error) => Response::error(&error, None),
+        }
+    };
+    stream.write_all(&response.with_origin(origin).into_bytes())
+}
+
+/// the files of the page, served without the token as they hold no data,
+/// the page asks for it
+#[cfg(feature = "web-ui")]
+fn web_ui(request: &Request) -> Option<Response> {
+    if request.method != "GET" {
+        return None;
+    }
+    let (body, content_type) = match request.path.as_str() {
+        "/" | "/index.html" => (include_str!("web/index.html"), "text/html"),
+        "/app.js" => (include_str!("web/app.js"), "text/javascript"),
+        "/style.css" => (include_str!("web/style.css"), "text/css"),
+        _ => return None,
+    };
+    Some(Response {
+        status: 200,
+        headers: vec![
+            ("Content-Type", format!("{}; charset=utf-8", content_type)),
+            (
+                "Content-Security-Policy",
+                "default-src 'self'; frame-ancestors 'none'".into(),
+            ),
+            ("X-Content-Type-Options", "nosniff".into()),
+        ],
+        body: body.as_bytes().to_vec(),
+    })
+}
+
+#[cfg(not(feature = "web-ui"))]
+fn web_ui(_request: &Request) -> Option<Response> {
+    None
+}
+
+/// whether the client starts with the preface of HTTP/2, as gRPC clients
+/// do without TLS, left to be read
+#[cfg(feature = "grpc")]
+fn speaks_http2(stream: &TcpStream) -> io::Result<bool> {
+    let preface = crate::grpc::PREFACE;
+    let mut start = [0; 24];
+    loop {
+        let peeked = stream.peek(&mut start)?;
+        if peeked == 0 || start[..peeked] != preface[..peeked] {
+            return Ok(false);
+        }
+        if peeked == preface.len() {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// hands the request to the session and waits for its answer
+fn handle(request: Request, server: &Server) -> Result<Json, ApiError> {
+    let route = Route::parse(&request.method, &request.path)?;
+    let query = parse_query(&request.query)?;
+    let content_type = request.header("content-type").map(String::from);
+    // the RPC of Transmission asks for its session id instead
+    if request.path.starts_with("/api/") && !request.body.is_empty() {
+        check_content_type(&route, content_type.as_deref())?;
+    }
+    forward(server, route, query, content_type, request.body)
+}
+
+/// the answer of a route, as the session gives it
+pub(crate) fn forward(
+    server: &Server,
+    route: Route,
+    query: Vec<(String, String)>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+) -> Result<Json, ApiError> {
+    let (reply, answer) = mpsc::channel();
+    let pending = PendingApiRequest {
+        route,
+        query,
+        content_type,
+        body,
+        rpc: Arc::clone(&server.rpc),
+        events: Arc::clone(&server.events),
+        reply,
+    };
+    let unavailable = || ApiError::new(503, "the session isn't answering");
+    server.requests.send(pending).map_err(|_| unavailable())?;
+    answer.recv_timeout(TIMEOUT).map_err(|_| unavailable())?
+}
+
+/// Upgrades the request to a WebSocket and pushes the events of the
+/// session until the client or the server goes away
+fn stream_events(mut stream: TcpStream, request: Request, server: &Server) -> io::Result<()> {
+    let mut refuse = |error: ApiError, header: Option<(&'static str, String)>| {
+        let mut response = Response::error(&error, None);
+        response.headers.extend(header);
+        stream.write_all(&response.into_bytes())
+    };
+    if request.method != "GET"
+        || !request
+            .header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    {
+        return refuse(
+            ApiError::new(400, "only WebSocket upgrades are supported"),
+            None,
+        );
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        let error = ApiError::new(426, "only version 13 of WebSocket is supported");
+        return refuse(error, Some(("Sec-WebSocket-Version", "13".into())));
+    }
+    let key = match request.header("sec-websocket-key") {
+        Some(key) => key,
+        None => return refuse(ApiError::new(400, "Sec-WebSocket-Key is missing"), None),
+    };
+    let resume = parse_query(&request.query).map(|query| {
+        query
+            .into_iter()
+            .find(|(key, _)| key == "cursor")
+            .map(|(_, cursor)| cursor.parse::<u64>())
+    });
+    let resume = match resume {
+        Ok(Some(Ok(cursor))) => Some(cursor),
+        Ok(None) => None,
+        Ok(Some(Err(_))) => return refuse(ApiError::new(400, "invalid cursor"), None),
+        Err(error) => return refuse(error, None),
+    };
+    stream.write_all(
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            websocket::accept_key(key)
+        )
+        .as_bytes(),
+    )?;
+
+    // the client is only waited on for its pings and close
+    stream.set_read_timeout(None)?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let closed = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let (writer, closed) = (Arc::clone(&writer), Arc::clone(&closed));
+        thread::spawn(move || read_client(stream, &writer, &closed))
+    };
+    let result = push_events(server, resume, &writer, &closed);
+    let mut stream = writer.lock().unwrap();
+    if !closed.load(Ordering::Relaxed) {
+        // going away
+        let _ = websocket::write_frame(&mut *stream, CLOSE, &1001u16.to_be_bytes());
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    drop(stream);
+    let _ = reader.join();
+    result
+}
+
+fn push_events(
+    server: &Server,
+    resume: Option<u64>,
+    writer: &Mutex<TcpStream>,
+    closed: &AtomicBool,
+) -> io::Result<()> {
+    let send = |message: &str| {
+        websocket::write_frame(&mut *writer.lock().unwrap(), TEXT, message.as_bytes())
+    };
+    let mut cursor = resume;
+    let mut idle = Duration::ZERO;
+    while !closed.load(Ordering::Relaxed) && !server.stop.load(Ordering::Relaxed) {
+        let since = match cursor {
+            Some(cursor) => server.events.since(cursor, Duration::from_secs(1)),
+            None => None,
+        };
+        match since {
+            Some(events) if events.is_empty() => {
+                idle += Duration::from_secs(1);
+                if idle >= KEEPALIVE {
+                    websocket::write_frame(&mut *writer.lock().unwrap(), PING, &[])?;
+                    idle = Duration::ZERO;
+                }
+            }
+            Some(events) => {
+                for (event_cursor, event) in events {
+                    let message = Json::object()
+                        .with("type", "event")
+                        .with("cursor", event_cursor)
+                        .with("event", (*event).clone());
+                    send(&message.to_string())?;
+                    cursor = Some(event_cursor);
+                }
+                idle = Duration::ZERO;
+            }
+            // the first or the events missed are gone
+            None => {
+                let snapshot = match forward(server, Route::Events, vec![], None, vec![]) {
+                    Ok(snapshot) => snapshot,
+                    Err(error) => {
+                        let error = Json::object()
+                            .with("type", "error")
+                            .with("error", error.message);
+                        return send(&error.to_string());
+                    }
+                };
+                cursor = snapshot
+                    .get("cursor")
+                    .and_then(Json::as_f64)
+                    .map(|cursor| cursor as u64);
+                send(&snapshot.to_string())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// answers the pings of the client and its close, ignoring its messages
+fn read_client(mut stream: TcpStream, writer: &Mutex<TcpStream>, closed: &AtomicBool) {
+    // large enough for the messages some clients send anyway
+    const MAX_FRAME: usize = 64 * 1024;
+    while let Ok(frame) = websocket::read_frame(&mut stream, MAX_FRAME) {
+        let mut writer = writer.lock().unwrap();
+        match frame.opcode {
+            PING => {
+                let _ = websocket::write_frame(&mut *writer, PONG, &frame.payload);
+            }
+            CLOSE => {
+                // echoes the status code, without the reason
+                let status = &frame.payload[..frame.payload.len().min(2)];
+                let _ = websocket::write_frame(&mut *writer, CLOSE, status);
+                break;
+            }
+            _ => {}
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// the header a request is authorized by, or the token of the query for
+/// the event stream of a browser
+fn authorization(request: &Request) -> Option<String> {
+    if let Some(header) = request.header("authorization") {
+        return Some(header.to_string());
+    }
+    if request.path != "/api/events" {
+        return None;
+    }
+    let query = parse_query(&request.query).ok()?;
+    let (_, token) = query.into_iter().find(|(key, _)| key == "token")?;
+    Some(format!("Bearer {}", token))
+}
+
+/// requests of web pages come from the api itself or an allowed origin,
+/// those of other sites are refused before doing anything: browsers send
+/// simple requests and WebSocket upgrades without asking CORS first
+fn origin_allowed(config: &ApiConfig, request: &Request) -> bool {
+    let origin = match request.header("origin") {
+        Some(origin) => origin,
+        None => return true,
+    };
+    let own = request.header("host").is_some_and(|host| {
+        origin
+            .split_once("://")
+            .is_some_and(|(_, origin)| origin.eq_ignore_ascii_case(host))
+    });
+    own || allowed_origin(config, origin).is_some()
+}
+
+/// bodies are JSON, or the .torrent itself when adding one, which forms of
+/// other sites can't send without asking CORS first
+fn check_content_type(route: &Route, content_type: Option<&str>) -> Result<(), ApiError> {
+    let media_type = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .unwrap_or_default()
+        .trim();
+    if media_type.eq_ignore_ascii_case("application/json")
+        || (*route == Route::AddTorrent && media_type.eq_ignore_ascii_case(TORRENT_TYPE))
+    {
+        return Ok(());
+    }
+    Err(ApiError::new(415, "the body has to be application/json"))
+}
+
+/// what `Access-Control-Allow-Origin` answers to pages of the origin
+fn allowed_origin(config: &ApiConfig, origin: &str) -> Option<String> {
+    config
+        .cors_origins
+        .iter()
+        .find(|allowed| *allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        .cloned()
+}
+
+/// whether the header gives the token, or there is none
+pub(crate) fn authorized(config: &ApiConfig, header: Option<&str>) -> bool {
+    let token = match &config.token {
+        Some(token) => token.as_bytes(),
+        None => return true,
+    };
+    let basic = header
+        .and_then(|header| header.strip_prefix("Basic "))
+        .and_then(|credentials| base64::decode(credentials.trim()).ok());
+    let given = match (
+        header.and_then(|header| header.strip_prefix("Bearer ")),
+        &basic,
+    ) {
+        (Some(given), _) => given.trim().as_bytes(),
+        // any user name, the password is the token
+        (None, Some(credentials)) => match credentials.iter().position(|&c| c == b':') {
+            Some(colon) => &credentials[colon + 1..],
+            None => return false,
+        },
+        (None, None) => return false,
+    };
+    same_token(given, token)
+}
+
+/// compares the whole token whatever the first difference, so the time
+/// taken tells nothing of it
+pub(crate) fn same_token(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn parse_query(query: &str) -> Result<Vec<(String, String)>, ApiError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |text: &str| {
+                percent_decode(&text.replace('+', " "))
+                    .map_err(|error| ApiError::new(400, format!("invalid query: {:#}", error)))
+            };
+            Ok((decode(key)?, decode(value)?))
+        })
+        .collect()
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, json: &Json) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "application/json".into())],
+            body: json.to_string().into_bytes(),
+        }
+    }
+
+    /// with the scheme of `WWW-Authenticate` for 401s
+    fn error(error: &ApiError, scheme: Option<&str>) -> Self {
+        let mut response = Self::json(
+            error.status,
+            &Json::object().with("error", error.message.as_str()),
+        );
+        if let Some(scheme) = scheme {
+            response.headers.push(("WWW-Authenticate", scheme.into()));
+        }
+        response
+    }
+
+    fn with_origin(mut self, origin: Option<String>) -> Self {
+        if let Some(origin) = origin {
+            self.headers.push(("Access-Control-Allow-Origin", origin));
+            self.headers
+                .push(("Access-Control-Expose-Headers", SESSION_ID_HEADER.into()));
+            self.headers.push(("Vary", "Origin".into()));
+        }
+        self
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, http::reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        let mut bytes = head.into_bytes();
+        bytes.extend(self.body);
+        bytes
+    }
+}
+
+fn answer(request: &PendingApiRequest, session: &mut Session) -> Result<Json, ApiError> {
+    let find = |session: &Session, id: &str| session.find(id).cloned().map_err(ApiError::not_found);
+    match &request.route {
+        Route::Torrents => Ok(torrents_json(session)),
+        Route::AddTorrent => add(request, session),
+        Route::Torrent(id) => Ok(details_json(&find(session, id)?)),
+        Route::Pause(id) => {
+            let torrent = find(session, id)?;
+            session.pause(&torrent).map_err(ApiError::internal)?;
+            Ok(torrent_json(&torrent))
+        }
+        Route::Resume(id) => {
+            let torrent = find(session, id)?;
+            session.resume(&torrent);
+            Ok(torrent_json(&torrent))
+        }
+        Route::Remove(id) => {
+            let torrent = find(session, id)?;
+            let delete_data = match request.param("delete_data") {
+                Some(value) => flag(value)?,
+                None => false,
+            };
+            session
+                .remove(&torrent, delete_data)
+                .map_err(ApiError::internal)?;
+            Ok(Json::object().with("info_hash", to_hex(&torrent.info_hash())))
+        }
+        Route::Peers(id) => Ok(peers_json(&find(session, id)?)),
+        Route::Stats => Ok(stats_json(session)),
+        Route::Settings => Ok(settings_json(session)),
+        Route::Events => Ok(Json::object()
+            .with("type", "snapshot")
+            .with("cursor", request.events.cursor())
+            .with("torrents", torrents_json(session))
+            .with("stats", stats_json(session))),
+        Route::UpdateSettings => {
+            let json = body_json(request)?;
+            update_settings(session, &json)?;
+            Ok(settings_json(session))
+        }
+        Route::Transmission => {
+            let json = body_json(request)?;
+            Ok(request.rpc.lock().unwrap().call(session, &json))
+        }
+    }
+}
+
+/// from a JSON body, or a .torrent body with its options in the query
+fn add(request: &PendingApiRequest, session: &mut Session) -> Result<Json, ApiError> {
+    let info_hash = if request.content_type.as_deref() == Some(TORRENT_TYPE) {
+        let options = AddOptions {
+            save_path: request.param("save_path").map(Into::into),
+            paused: match request.param("paused") {
+                Some(value) => flag(value)?,
+                None => false,
+            },
+            ..Default::default()
+        };
+        let metainfo = Metainfo::from_bytes(request.body.clone()).map_err(ApiError::bad_request)?;
+        session
+            .add_torrent_with(metainfo, options)
+            .map_err(ApiError::bad_request)?
+            .info_hash()
+    } else {
+        let json = body_json(request)?;
+        let options = AddOptions {
+            save_path: optional(&json, "save_path", Json::as_str)?.map(Into::into),
+            paused: optional(&json, "paused", Json::as_bool)?.unwrap_or(false),
+            ..Default::default()
+        };
+        match (
+            optional(&json, "uri", Json::as_str)?,
+            optional(&json, "path", Json::as_str)?,
+        ) {
+            (Some(uri), None) => session
+                .add_magnet_with(uri, options)
+                .map_err(ApiError::bad_request)?,
+            (None, Some(path)) => {
+                let data = read_torrent_file(Path::new(path))?;
+                let metainfo = Metainfo::from_bytes(data).map_err(ApiError::bad_request)?;
+                session
+                    .add_torrent_with(metainfo, options)
+                    .map_err(ApiError::bad_request)?
+                    .info_hash()
+            }
+            _ => return Err(ApiError::new(400, "give either a uri or a path")),
+        }
+    };
+    Ok(Json::object().with("info_hash", to_hex(&info_hash)))
+}
+
+/// the .torrent at a path of the host, other files aren't read so the
+/// api can't be used to read them
+pub(crate) fn read_torrent_file(path: &Path) -> Result<Vec<u8>, ApiError> {
+    let is_torrent = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("torrent"));
+    if !is_torrent {
+        return Err(ApiError::new(
+            400,
+            "only .torrent files can be added by path",
+        ));
+    }
+    let unreadable =
+        |error: io::Error| ApiError::new(400, format!("reading {}: {}", path.display(), error));
+    let metadata = std::fs::metadata(path).map_err(unreadable)?;
+    if !metadata.is_file() || metadata.len() > MAX_BODY as u64 {
+        return Err(ApiError::new(
+            400,
+            format!("{} isn't a .torrent", path.display()),
+        ));
+    }
+    std::fs::read(path).map_err(unreadable)
+}
+
+fn body_json(request: &PendingApiRequest) -> Result<Json, ApiError> {
+    let text = std::str::from_utf8(&request.body)
+        .map_err(|_| ApiError::new(400, "the body isn't UTF-8"))?;
+    match Json::parse(text).map_err(ApiError::bad_request)? {
+        json @ Json::Object(_) => Ok(json),
+        _ => Err(ApiError::new(400, "the body has to be a JSON object")),
+    }
+}
+
+/// a key of an object that has to be of a kind when present
+fn optional<'a, T>(
+    json: &'a Json,
+    key: &str,
+    get: impl FnOnce(&'a Json) -> Option<T>,
+) -> Result<Option<T>, ApiError> {
+    match json.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(value) => get(value)
+            .map(Some)
+            .ok_or_else(|| ApiError::new(400, format!("{} has the wrong type", key))),
+    }
+}
+
+fn flag(value: &str) -> Result<bool, ApiError> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(ApiError::new(
+            400,
+            format!("{:?} isn't true or false", value),
+        )),
+    }
+}
+
+/// with the magnet links still fetching their metadata
+fn torrents_json(session: &Session) -> Json {
+    let mut torrents: Vec<_> = session.torrents().map(torrent_json).collect();
+    for info_hash in session.magnet_hashes() {
+        torrents.push(
+            Json::object()
+                .with("info_hash", to_hex(&info_hash))
+                .with("name", None::<String>)
+                .with("state", "fetching metadata"),
+        );
+    }
+    Json::Array(torrents)
+}
+
+fn torrent_json(torrent: &TorrentHandle) -> Json {
+    let progress = torrent.progress_report();
+    let stats = torrent.stats();
+    Json::object()
+        .with("info_hash", to_hex(&torrent.info_hash()))
+        .with("name", torrent.root_name())
+        .with("state", torrent.state().to_string())
+        .with("progress", progress.percent)
+        .with("size", progress.bytes_wanted)
+        .with("done", progress.bytes_done)
+        .with("download_rate", progress.download_rate)
+        .with("upload_rate", progress.upload_rate)
+        .with("downloaded", stats.downloaded)
+        .with("uploaded", stats.uploaded)
+        .with("ratio", torrent.seeding().ratio)
+        .with("peers", stats.peers)
+        .with("eta", progress.eta.map(|eta| eta.as_secs()))
+        .with("save_path", torrent.save_path().display().to_string())
+        .with("category", torrent.category())
+        .with("tags", torrent.tags().into_iter().collect::<Vec<_>>())
+}
+
+fn details_json(torrent: &TorrentHandle) -> Json {
+    let progress = torrent.progress_report();
+    let files: Vec<_> = torrent
+        .file_progress()
+        .into_iter()
+        .filter(|file| !file.padding)
+        .map(|file| {
+            let priority = match file.priority {
+                Priority::Skip => "skip",
+                Priority::Low => "low",
+                Priority::Normal => "normal",
+                Priority::High => "high",
+            };
+            Json::object()
+                .with("path", file.path.display().to_string())
+                .with("length", file.length)
+                .with("done", file.bytes_done)
+                .with("priority", priority)
+        })
+        .collect();
+    torrent_json(torrent)
+        .with("pieces", progress.pieces)
+        .with("piece_count", progress.piece_count)
+        .with("files", Json::Array(files))
+        .with("trackers", torrent.announce_urls())
+        .with("peers_list", peers_json(torrent))
+}
+
+fn peers_json(torrent: &TorrentHandle) -> Json {
+    let pieces = torrent.progress_report().piece_count.max(1);
+    Json::Array(
+        torrent
+            .peer_list()
+            .iter()
+            .map(|peer| {
+                Json::object()
+                    .with("addr", peer.addr.to_string())
+                    .with("source", peer.source.to_string())
+                    .with("client", peer.client.clone())
+                    .with("flags", peer.flags())
+                    .with("download_rate", peer.download_rate)
+                    .with("upload_rate", peer.upload_rate)
+                    .with("progress", peer.pieces as f64 * 100.0 / pieces as f64)
+            })
+            .collect(),
+    )
+}
+
+fn stats_json(session: &Session) -> Json {
+    let mut download_rate = 0.0;
+    let mut upload_rate = 0.0;
+    let mut states = BTreeMap::new();
+    for torrent in session.torrents() {
+        let progress = torrent.progress_report();
+        download_rate += progress.download_rate;
+        upload_rate += progress.upload_rate;
+        *states.entry(torrent.state().to_string()).or_insert(0usize) += 1;
+    }
+    let magnets = session.magnet_hashes().len();
+    if magnets > 0 {
+        states.insert("fetching metadata".into(), magnets);
+    }
+    let states = states
+        .into_iter()
+        .fold(Json::object(), |json, (state, count)| {
+            json.with(&state, count)
+        });
+    Json::object()
+        .with("download_rate", download_rate)
+        .with("upload_rate", upload_rate)
+        .with("torrents", session.torrents().count() + magnets)
+        .with("states", states)
+        .with("connections", session.connection_count())
+        .with("listen_port", u64::from(session.listen_port()))
+        .with("dht_nodes", session.dht().map(|dht| dht.stats().nodes))
+}
+
+/// rates in bytes per second, null is unlimited like for the counts
+fn settings_json(session: &Session) -> Json {
+    let rates = session.rate_limits();
+    let connections = session.connection_limits();
+    let queue = session.queue().lock().unwrap().settings();
+    Json::object()
+        .with("download_rate_limit", rates.download)
+        .with("upload_rate_limit", rates.upload)
+        .with("max_peers", connections.max_peers)
+        .with("max_connections", connections.max_connections)
+        .with("max_active_downloads", queue.max_active_downloads)
+        .with("max_active_seeds", queue.max_active_seeds)
+        .with("max_active_total", queue.max_active_total)
+}
+
+/// the keys of `settings_json` given, null or 0 lifting a limit. Nothing
+/// changes when one of them is invalid.
+fn update_settings(session: &mut Session, json: &Json) -> Result<(), ApiError> {
+    let entries = match json {
+        Json::Object(entries) => entries,
+        _ => return Err(ApiError::new(400, "the body has to be a JSON object")),
+    };
+    let mut rates = session.rate_limits();
+    let mut connections = session.connection_limits();
+    let mut queue = session.queue().lock().unwrap().settings();
+    for (key, value) in entries {
+        let number = || match value.as_i64() {
+            Some(number) if number >= 0 => Ok(number as u64),
+            _ => Err(ApiError::new(
+                400,
+                format!("{} has to be a whole number", key),
+            )),
+        };
+        // null or 0 is unlimited
+        let limit = || match value {
+            Json::Null => Ok(None),
+            _ => number().map(|number| Some(number).filter(|number| *number > 0)),
+        };
+        let active = || number().map(|number| number as usize);
+        match key.as_str() {
+            "download_rate_limit" => rates.download = limit()?,
+            "upload_rate_limit" => rates.upload = limit()?,
+            "max_peers" => connections.max_peers = limit()?.map(|max| max as usize),
+            "max_connections" => connections.max_connections = limit()?.map(|max| max as usize),
+            "max_active_downloads" => queue.max_active_downloads = active()?,
+            "max_active_seeds" => queue.max_active_seeds = active()?,
+            "max_active_total" => queue.max_active_total = active()?,
+            _ => return Err(ApiError::new(400, format!("unknown setting {}", key))),
+        }
+    }
+    session.set_rate_limits(rates);
+    session.set_connection_limits(connections);
+    session.queue().lock().unwrap().set_settings(queue);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transmission;
+    use crate::Config;
+    use std::io::Read;
+
+    #[test]
+    fn parses_routes_and_queries() -> Result<(), ApiError> {
+        assert_eq!(Route::parse("GET", "/api/torrents/")?, Route::Torrents);
+        assert_eq!(
+            Route::parse("POST", "/api/torrents/a%20b/pause")?,
+            Route::Pause("a b".into())
+        );
+        assert_eq!(
+            Route::parse("DELETE", "/api/torrents/8dc3")?,
+            Route::Remove("8dc3".into())
+        );
+        assert_eq!(
+            Route::parse("PATCH", "/api/settings")?,
+            Route::UpdateSettings
+        );
+        assert_eq!(Route::parse("GET", "/api/other").unwrap_err().status, 404);
+        assert_eq!(
+            Route::parse("GET", "/api/torrents/8dc3/pause").unwrap_err(),
+            ApiError::new(405, "only POST is supported")
+        );
+        assert_eq!(
+            parse_query("save_path=%2Fa+b&paused")?,
+            [
+                ("save_path".to_string(), "/a b".to_string()),
+                ("paused".to_string(), String::new())
+            ]
+        );
+        let config = ApiConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            token: Some("secret".into()),
+            cors_origins: vec!["http://ui".into()],
+        };
+        assert!(authorized(&config, Some("Bearer secret")));
+        assert!(!authorized(&config, Some("Bearer secre")));
+        assert!(!authorized(&config, Some("secret")));
+        assert!(!authorized(&config, None));
+        // dXNlcjpzZWNyZXQ= is user:secret
+        assert!(authorized(&config, Some("Basic dXNlcjpzZWNyZXQ=")));
+        assert!(!authorized(&config, Some("Basic dXNlcjpzZWNyZQ==")));
+        assert_eq!(
+            allowed_origin(&config, "http://ui"),
+            Some("http://ui".into())
+        );
+        assert_eq!(allowed_origin(&config, "http://other"), None);
+        Ok(())
+    }
+
+    /// the status, head and body of the answer
+    fn call(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[&str],
+        body: &str,
+    ) -> Result<(u16, String, String)> {
+        let mut stream = TcpStream::connect(addr)?;
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+        for header in headers {
+            request.push_str(&format!("{}\r\n", header));
+        }
+        request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head.split(' ').nth(1).unwrap_or_default().parse()?;
+        Ok((status, head.to_string(), body.to_string()))
+    }
+
+    #[test]
+    fn serves_the_session() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_api_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let config = Config::builder()
+            .save_path(&dir)
+            .listen_port(0)
+            .dht(None)
+            .lsd(false)
+            .trackers(false)
+            .port_mapping(false)
+            .api(ApiConfig {
+                addr: "127.0.0.1:0".parse()?,
+                token: Some("secret".into()),
+                cors_origins: vec!["http://ui".into()],
+            })
+            .build()?;
+        let mut session = Session::new(config)?;
+        let addr = session.api_addr().unwrap();
+
+        let torrent = std::fs::canonicalize("file1.txt.torrent")?;
+        let client = thread::spawn(move || -> Result<_> {
+            let auth = "Authorization: Bearer secret";
+            let json_body = "Content-Type: application/json";
+            let json = |(status, _, body): (u16, String, String)| -> Result<(u16, Json)> {
+                Ok((status, Json::parse(&body)?))
+            };
+            let add = Json::object()
+                .with("path", torrent.display().to_string())
+                .with("paused", true)
+                .to_string();
+            Ok((
+                call(addr, "GET", "/api/torrents", &[], "")?,
+                call(addr, "OPTIONS", "/api/torrents", &["Origin: http://ui"], "")?,
+                json(call(addr, "POST", "/api/torrents", &[auth], &add)?)?,
+                json(call(
+                    addr,
+                    "POST",
+                    "/api/torrents",
+                    &[auth, json_body, "Origin: http://other"],
+                    &add,
+                )?)?,
+                json(call(
+                    addr,
+                    "POST",
+                    "/api/torrents",
+                    &[auth, json_body],
+                    r#"{"path":"Cargo.toml"}"#,
+                )?)?,
+                json(call(
+                    addr,
+                    "POST",
+                    "/api/torrents",
+                    &[auth, "Content-Type: application/json; charset=utf-8"],
+                    &add,
+                )?)?,
+                json(call(
+                    addr,
+                    "GET",
+                    "/api/torrents",
+                    &[auth, "Origin: http://ui"],
+                    "",
+                )?)?,
+                json(call(addr, "GET", "/api/torrents/file1.txt", &[auth], "")?)?,
+                json(call(addr, "GET", "/api/torrents/nothing", &[auth], "")?)?,
+                json(call(
+                    addr,
+                    "PATCH",
+                    "/api/settings",
+                    &[auth, json_body],
+                    r#"{"max_peers":20,"download_rate_limit":1024,"max_active_total":4}"#,
+                )?)?,
+                json(call(
+                    addr,
+                    "PATCH",
+                    "/api/settings",
+                    &[auth, json_body],
+                    r#"{"speed":1}"#,
+                )?)?,
+                json(call(addr, "GET", "/api/stats", &[auth], "")?)?,
+                json(call(addr, "DELETE", "/api/torrents/8dc3", &[auth], "")?)?,
+            ))
+        });
+        while !client.is_finished() {
+            session.poll_api();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (
+            unauthorized,
+            preflight,
+            untyped,
+            foreign,
+            not_torrent,
+            added,
+            list,
+            details,
+            missing,
+            settings,
+            unknown,
+            stats,
+            removed,
+        ) = client.join().unwrap()?;
+        assert_eq!(unauthorized.0, 401);
+        assert!(unauthorized.1.contains("WWW-Authenticate: Bearer"));
+        assert_eq!(preflight.0, 204);
+        assert!(preflight
+            .1
+            .contains("Access-Control-Allow-Origin: http://ui"));
+
+        assert_eq!(
+            untyped,
+            (
+                415,
+                Json::object().with("error", "the body has to be application/json")
+            )
+        );
+        assert_eq!(
+            foreign,
+            (403, Json::object().with("error", "origin not allowed"))
+        );
+        assert_eq!(
+            not_torrent,
+            (
+                400,
+                Json::object().with("error", "only .torrent files can be added by path")
+            )
+        );
+
+        let info_hash = "8dc3b8a5ac6d8002df36541fda949e7109b7397c";
+        assert_eq!(added, (200, Json::object().with("info_hash", info_hash)));
+        let torrents = match list {
+            (200, Json::Array(torrents)) => torrents,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(torrents.len(), 1);
+        assert_eq!(
+            torrents[0].get("name").and_then(Json::as_str),
+            Some("file1.txt")
+        );
+        assert_eq!(
+            torrents[0].get("state").and_then(Json::as_str),
+            Some("paused")
+        );
+        assert_eq!(details.0, 200);
+        match details.1.get("files") {
+            Some(Json::Array(files)) => assert_eq!(files.len(), 1),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            missing,
+            (404, Json::object().with("error", "no torrent nothing"))
+        );
+        assert_eq!(settings.0, 200);
+        assert_eq!(
+            settings.1.get("max_peers").and_then(Json::as_f64),
+            Some(20.0)
+        );
+        assert_eq!(
+            settings.1.get("download_rate_limit").and_then(Json::as_f64),
+            Some(1024.0)
+        );
+        assert_eq!(session.connection_limits().max_peers, Some(20));
+        assert_eq!(
+            session.queue().lock().unwrap().settings().max_active_total,
+            4
+        );
+        assert_eq!(
+            unknown,
+            (400, Json::object().with("error", "unknown setting speed"))
+        );
+        assert_eq!(stats.1.get("torrents").and_then(Json::as_f64), Some(1.0));
+        assert_eq!(removed, (200, Json::object().with("info_hash", info_hash)));
+        assert_eq!(session.torrents().count(), 0);
+
+        session.shutdown(Duration::from_secs(1))?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// the head of the answer to the upgrade, and the stream if upgraded
+    fn upgrade(addr: SocketAddr, target: &str, origin: &str) -> Result<(String, TcpStream)> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nOrigin: {}\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            target, addr, origin
+        )?;
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte)?;
+            head.push(byte[0]);
+        }
+        Ok((String::from_utf8(head)?, stream))
+    }
+
+    /// the opcode and payload of a frame of the server, short and unmasked
+    fn message(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head)?;
+        let mut payload = vec![0; usize::from(head[1])];
+        stream.read_exact(&mut payload)?;
+        Ok((head[0] & 0x0f, payload))
+    }
+
+    #[test]
+    fn streams_events() -> Result<()> {
+        let (events, receiver) = mpsc::channel();
+        let server = ApiServer::start(
+            ApiConfig {
+                addr: "127.0.0.1:0".parse()?,
+                token: Some("secret".into()),
+                cors_origins: vec![],
+            },
+            receiver,
+        )?;
+        let addr = server.local_addr();
+        let client = thread::spawn(move || -> Result<_> {
+            let target = "/api/events?token=secret";
+            let (refused, _) = upgrade(addr, target, "http://example.com")?;
+            let own = format!("http://{}", addr);
+            let (head, mut stream) = upgrade(addr, target, &own)?;
+            let snapshot = message(&mut stream)?;
+            let event = message(&mut stream)?;
+            // a masked close with status 1000
+            stream.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8])?;
+            let close = message(&mut stream)?;
+            let (_, mut resumed) = upgrade(addr, "/api/events?token=secret&cursor=0", &own)?;
+            let replayed = message(&mut resumed)?;
+            Ok((refused, head, snapshot, event, close, replayed))
+        });
+        let mut sent = false;
+        while !client.is_finished() {
+            if let Some(request) = server.poll() {
+                assert_eq!(request.route, Route::Events);
+                let snapshot = Json::object()
+                    .with("type", "snapshot")
+                    .with("cursor", request.events.cursor());
+                request.reply.send(Ok(snapshot))?;
+                if !sent {
+                    events.send(SessionEvent::TorrentAdded { info_hash: [1; 20] })?;
+                    sent = true;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (refused, head, snapshot, event, close, replayed) = client.join().unwrap()?;
+        assert!(refused.starts_with("HTTP/1.1 403 "));
+        assert!(head.starts_with("HTTP/1.1 101 "));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert_eq!(
+            snapshot,
+            (TEXT, br#"{"type":"snapshot","cursor":0}"#.to_vec())
+        );
+        let expected = format!(
+            r#"{{"type":"event","cursor":1,"event":{{"type":"torrent_added","info_hash":"{}"}}}}"#,
+            to_hex(&[1; 20])
+        );
+        assert_eq!(event, (TEXT, expected.clone().into_bytes()));
+        assert_eq!(close, (CLOSE, vec![0x03, 0xe8]));
+        assert_eq!(replayed, (TEXT, expected.into_bytes()));
+        Ok(())
+    }
+
+    #[test]
+    fn hands_transmission_clients_a_session_id() -> Result<()> {
+        let config = Config::builder()
+            .save_path(std::env::temp_dir())
+            .listen_port(0)
+            .dht(None)
+            .lsd(false)
+            .trackers(false)
+            .port_mapping(false)
+            .api(ApiConfig {
+                addr: "127.0.0.1:0".parse()?,
+                token: None,
+                cors_origins: vec![],
+            })
+            .build()?;
+        let mut session = Session::new(config)?;
+        let addr = session.api_addr().unwrap();
+        let client = thread::spawn(move || -> Result<_> {
+            let body = r#"{"method":"session-get","arguments":{"fields":["rpc-version"]},"tag":1}"#;
+            let first = call(addr, "POST", "/transmission/rpc", &[], body)?;
+            let session_id = first
+                .1
+                .lines()
+                .find_map(|line| line.strip_prefix("X-Transmission-Session-Id: "))
+                .unwrap_or_default()
+                .to_string();
+            let header = format!("X-Transmission-Session-Id: {}", session_id);
+            let second = call(addr, "POST", "/transmission/rpc", &[&header], body)?;
+            Ok((first.0, session_id, second))
+        });
+        while !client.is_finished() {
+            session.poll_api();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (status, session_id, (second_status, _, body)) = client.join().unwrap()?;
+        assert_eq!(status, 409);
+        assert_eq!(session_id.len(), 48);
+        assert_eq!(second_status, 200);
+        assert_eq!(
+            Json::parse(&body)?,
+            Json::object()
+                .with(
+                    "arguments",
+                    Json::object().with("rpc-version", transmission::RPC_VERSION)
+                )
+                .with("result", "success")
+                .with("tag", 1u64)
+        );
+        session.shutdown(Duration::from_secs(1))?;
+        Ok(())
+    }
+
+    #[test]
+    fn limits_the_clients() -> Result<()> {
+        let server = ApiServer::start(
+            ApiConfig {
+                addr: "127.0.0.1:0".parse()?,
+                token: Some("secret".into()),
+                cors_origins: vec![],
+            },
+            mpsc::channel().1,
+        )?;
+        let addr = server.local_addr();
+        // clients that don't send their request hold their thread
+        let idle: Vec<_> = (0..MAX_CLIENTS)
+            .map(|_| TcpStream::connect(addr))
+            .collect::<io::Result<_>>()?;
+        thread::sleep(Duration::from_millis(200));
+        let mut waiting = TcpStream::connect(addr)?;
+        write!(waiting, "GET /api/torrents HTTP/1.1\r\n\r\n")?;
+        waiting.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let mut response = String::new();
+        assert!(waiting.read_to_string(&mut response).is_err());
+        drop(idle);
+        waiting.set_read_timeout(Some(Duration::from_secs(5)))?;
+        waiting.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 401 "), "{}", response);
+        Ok(())
+    }
+
+    #[cfg(feature = "web-ui")]
+    #[test]
+    fn serves_the_web_ui() -> Result<()> {
+        let server = ApiServer::start(
+            ApiConfig {
+                addr: "127.0.0.1:0".parse()?,
+                token: Some("secret".into()),
+                cors_origins: vec![],
+            },
+            mpsc::channel().1,
+        )?;
+        let addr = server.local_addr();
+        let (status, head, body) = call(addr, "GET", "/", &[], "")?;
+        assert_eq!(status, 200);
+        assert!(head.contains("Content-Type: text/html; charset=utf-8"));
+        assert!(body.contains("<script src=\"app.js\" defer></script>"));
+        let (status, head, _) = call(addr, "GET", "/app.js", &[], "")?;
+        assert_eq!(status, 200);
+        assert!(head.contains("Content-Security-Policy: default-src 'self'"));
+        assert_eq!(call(addr, "GET", "/api/torrents", &[], "")?.0, 401);
+        assert_eq!(call(addr, "GET", "/other.js", &[], "")?.0, 401);
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use crate::bitfield::Bitfield;
+
+/// Number of connected peers having each piece
+#[derive(Debug, Clone)]
+pub struct Availability {
+    counts: Vec<u32>,
+    peers: usize,
+}
+
+impl Availability {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            counts: vec![0; piece_count],
+            peers: 0,
+        }
+    }
+
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        self.peers += 1;
+        for (piece, count) in self.counts.iter_mut().enumerate() {
+            if bitfield.get(piece) {
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        self.peers = self.peers.saturating_sub(1);
+        for (piece, count) in self.counts.iter_mut().enumerate() {
+            if bitfield.get(piece) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// a peer announced a new piece with a have message
+    pub fn add_piece(&mut self, piece: usize) {
+        if let Some(count) = self.counts.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    pub fn get(&self, piece: usize) -> u32 {
+        self.counts.get(piece).copied().unwrap_or(0)
+    }
+
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    pub fn peers(&self) -> usize {
+        self.peers
+    }
+
+    /// how many full copies of the torrent are spread among the peers, the
+    /// integer part is the availability of the rarest piece and the fraction is
+    /// the share of pieces more common than that
+    pub fn distributed_copies(&self) -> f64 {
+        let min = match self.counts.iter().min() {
+            Some(&min) => min,
+            None => return 0.0,
+        };
+        let above = self.counts.iter().filter(|&&count| count > min).count();
+        min as f64 + above as f64 / self.counts.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distributed_copies() {
+        let mut availability = Availability::new(4);
+        let mut partial = Bitfield::new(4);
+        partial.set(0, true);
+
+        availability.add_peer(&Bitfield::full(4));
+        availability.add_peer(&partial);
+        assert_eq!(availability.distributed_copies(), 1.25);
+
+        availability.remove_peer(&Bitfield::full(4));
+        assert_eq!(availability.distributed_copies(), 0.25);
+        assert_eq!(availability.peers(), 1);
+    }
+}
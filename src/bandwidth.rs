@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Bytes per second allowed each way, None is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl RateLimits {
+    pub fn get(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+    }
+}
+
+/// Token bucket of bytes, holding up to a second worth of them
+#[derive(Debug, Clone)]
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last: now,
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        if let Some(rate) = rate {
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last = self.last.max(now);
+    }
+
+    fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        match self.rate {
+            Some(_) => self.tokens as u64,
+            None => u64::MAX,
+        }
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        if self.rate.is_some() {
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+/// Upload and download buckets of one level
+#[derive(Debug, Clone)]
+struct Buckets {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl Buckets {
+    fn new(limits: RateLimits, now: Instant) -> Self {
+        Self {
+            upload: Bucket::new(limits.upload, now),
+            download: Bucket::new(limits.download, now),
+        }
+    }
+
+    fn set_limits(&mut self, limits: RateLimits, now: Instant) {
+        self.upload.set_rate(limits.upload, now);
+        self.download.set_rate(limits.download, now);
+    }
+
+    fn limits(&self) -> RateLimits {
+        RateLimits {
+            upload: self.upload.rate,
+            download: self.download.rate,
+        }
+    }
+
+    fn get(&mut self, direction: Direction) -> &mut Bucket {
+        match direction {
+            Direction::Upload => &mut self.upload,
+            Direction::Download => &mut self.download,
+        }
+    }
+}
+
+/// Limits the bytes peers send and receive at three levels: the session,
+/// each torrent and each peer. A transfer only gets what every level it
+/// belongs to still allows, so one torrent can't starve the others of the
+/// global rate and limits can be changed while transfers run. Connections
+/// ask for a quota with `request` before writing and try again a bit later
+/// when they get nothing. Reads can't tell how much will arrive, they wait
+/// for some of the quota to be `available` and `consume` what they read.
+#[derive(Debug)]
+pub struct Bandwidth {
+    global: Buckets,
+    torrents: HashMap<[u8; 20], Buckets>,
+    peers: HashMap<([u8; 20], SocketAddr), Buckets>,
+}
+
+impl Bandwidth {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            global: Buckets::new(limits, Instant::now()),
+            torrents: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.global.limits()
+    }
+
+    pub fn set_limits(&mut self, limits: RateLimits) {
+        self.global.set_limits(limits, Instant::now());
+    }
+
+    /// unlimited for torrents without limits
+    pub fn torrent_limits(&self, info_hash: &[u8; 20]) -> RateLimits {
+        self.torrents
+            .get(info_hash)
+            .map(Buckets::limits)
+            .unwrap_or_default()
+    }
+
+    pub fn set_torrent_limits(&mut self, info_hash: [u8; 20], limits: RateLimits) {
+        set_limits(&mut self.torrents, info_hash, limits);
+    }
+
+    pub fn peer_limits(&self, info_hash: &[u8; 20], peer: SocketAddr) -> RateLimits {
+        self.peers
+            .get(&(*info_hash, peer))
+            .map(Buckets::limits)
+            .unwrap_or_default()
+    }
+
+    pub fn set_peer_limits(&mut self, info_hash: [u8; 20], peer: SocketAddr, limits: RateLimits) {
+        set_limits(&mut self.peers, (info_hash, peer), limits);
+    }
+
+    /// forgets the limits of the torrent and of its peers
+    pub fn remove_torrent(&mut self, info_hash: &[u8; 20]) {
+        self.torrents.remove(info_hash);
+        self.peers.retain(|(torrent, _), _| torrent != info_hash);
+    }
+
+    pub fn remove_peer(&mut self, info_hash: &[u8; 20], peer: SocketAddr) {
+        self.peers.remove(&(*info_hash, peer));
+    }
+
+    /// how many of `bytes` the peer may transfer now, taken from the quota
+    /// of every level
+    pub fn request(
+        &mut self,
+        info_hash: &[u8; 20],
+        peer: SocketAddr,
+        direction: Direction,
+        bytes: u64,
+        now: Instant,
+    ) -> u64 {
+        let granted = self.available(info_hash, peer, direction, now).min(bytes);
+        self.consume(info_hash, peer, direction, granted);
+        granted
+    }
+
+    /// how many bytes the peer may transfer now, without taking them
+    pub fn available(
+        &mut self,
+        info_hash: &[u8; 20],
+        peer: SocketAddr,
+        direction: Direction,
+        now: Instant,
+    ) -> u64 {
+        self.levels(info_hash, peer, direction)
+            .into_iter()
+            .map(|bucket| bucket.available(now))
+            .fold(u64::MAX, u64::min)
+    }
+
+    /// takes bytes that were transferred from the quota of every level
+    pub fn consume(
+        &mut self,
+        info_hash: &[u8; 20],
+        peer: SocketAddr,
+        direction: Direction,
+        bytes: u64,
+    ) {
+        for bucket in self.levels(info_hash, peer, direction) {
+            bucket.consume(bytes);
+        }
+    }
+
+    fn levels(
+        &mut self,
+        info_hash: &[u8; 20],
+        peer: SocketAddr,
+        direction: Direction,
+    ) -> Vec<&mut Bucket> {
+        let mut levels = vec![self.global.get(direction)];
+        if let Some(torrent) = self.torrents.get_mut(info_hash) {
+            levels.push(torrent.get(direction));
+        }
+        if let Some(peer) = self.peers.get_mut(&(*info_hash, peer)) {
+            levels.push(peer.get(direction));
+        }
+        levels
+    }
+}
+
+/// unlimited levels aren't kept
+fn set_limits<K: std::hash::Hash + Eq>(
+    levels: &mut HashMap<K, Buckets>,
+    key: K,
+    limits: RateLimits,
+) {
+    let now = Instant::now();
+    if limits == RateLimits::default() {
+        levels.remove(&key);
+    } else if let Some(buckets) = levels.get_mut(&key) {
+        buckets.set_limits(limits, now);
+    } else {
+        levels.insert(key, Buckets::new(limits, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(upload: Option<u64>, download: Option<u64>) -> RateLimits {
+        RateLimits { upload, download }
+    }
+
+    #[test]
+    fn every_level_limits_transfers() {
+        let start = Instant::now();
+        let (a, b) = ([1; 20], [2; 20]);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut bandwidth = Bandwidth::new(limits(Some(1000), None));
+        bandwidth.set_torrent_limits(a, limits(Some(600), Some(100)));
+        bandwidth.set_peer_limits(a, peer, limits(Some(400), None));
+
+        assert_eq!(
+            bandwidth.request(&a, peer, Direction::Upload, 500, start),
+            400
+        );
+        let other = SocketAddr::from(([127, 0, 0, 1], 6882));
+        assert_eq!(
+            bandwidth.request(&a, other, Direction::Upload, 500, start),
+            200
+        );
+        // what is left of the global rate
+        assert_eq!(
+            bandwidth.request(&b, peer, Direction::Upload, 1000, start),
+            400
+        );
+        assert_eq!(bandwidth.request(&b, peer, Direction::Upload, 1, start), 0);
+        assert_eq!(
+            bandwidth.request(&a, peer, Direction::Download, 1000, start),
+            100
+        );
+        assert_eq!(
+            bandwidth.request(&b, peer, Direction::Download, 1000, start),
+            1000
+        );
+
+        // refilled over time, up to a second worth
+        let later = start + Duration::from_millis(500);
+        // the buckets were created a moment after the start
+        let granted = bandwidth.request(&b, peer, Direction::Upload, 1000, later);
+        assert!((499..=500).contains(&granted));
+        let later = start + Duration::from_secs(10);
+        assert_eq!(
+            bandwidth.request(&a, peer, Direction::Upload, 1000, later),
+            400
+        );
+    }
+
+    #[test]
+    fn limits_change_at_runtime() {
+        let start = Instant::now();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut bandwidth = Bandwidth::new(RateLimits::default());
+        assert_eq!(
+            bandwidth.request(&[1; 20], peer, Direction::Download, 1 << 30, start),
+            1 << 30
+        );
+        bandwidth.set_limits(limits(None, Some(100)));
+        assert_eq!(bandwidth.limits(), limits(None, Some(100)));
+        assert!(
+            bandwidth.request(&[1; 20], peer, Direction::Download, 1000, Instant::now()) <= 100
+        );
+
+        bandwidth.set_torrent_limits([1; 20], limits(Some(10), None));
+        assert_eq!(bandwidth.torrent_limits(&[1; 20]), limits(Some(10), None));
+        bandwidth.set_peer_limits([1; 20], peer, limits(Some(5), None));
+        bandwidth.remove_torrent(&[1; 20]);
+        assert_eq!(bandwidth.torrent_limits(&[1; 20]), RateLimits::default());
+        assert_eq!(bandwidth.peer_limits(&[1; 20], peer), RateLimits::default());
+    }
+
+    #[test]
+    fn reads_only_pay_for_what_they_got() {
+        let start = Instant::now();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut bandwidth = Bandwidth::new(limits(None, Some(1000)));
+        bandwidth.set_torrent_limits([1; 20], limits(None, Some(600)));
+        let available = |bandwidth: &mut Bandwidth| {
+            bandwidth.available(&[1; 20], peer, Direction::Download, start)
+        };
+        assert_eq!(available(&mut bandwidth), 600);
+        // idle connections waiting for data take nothing
+        assert_eq!(available(&mut bandwidth), 600);
+        bandwidth.consume(&[1; 20], peer, Direction::Download, 100);
+        assert_eq!(available(&mut bandwidth), 500);
+        assert_eq!(
+            bandwidth.available(&[2; 20], peer, Direction::Download, start),
+            900
+        );
+    }
+}
@@ -1,41 +1,170 @@
-use anyhow::{bail, Result};
+use crate::json::Json;
+use crate::metainfo::to_hex;
+use anyhow::{bail, Context, Result};
 use std::collections::HashMap;
 
-#[derive(Debug, PartialEq)]
+/// marks the byte strings that aren't utf-8 in JSON, see `to_json`
+const HEX: &str = "$hex";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Bencode {
-    Dictionary(HashMap<String, Bencode>),
+    /// keys are byte strings, they are usually but not always valid utf-8
+    Dictionary(HashMap<Vec<u8>, Bencode>),
     List(Vec<Bencode>),
-    Integer(isize),
+    Integer(i64),
     Bytes(Vec<u8>),
 }
 
+impl Bencode {
+    pub fn get(&self, key: &str) -> Option<&Bencode> {
+        match self {
+            Bencode::Dictionary(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&HashMap<Vec<u8>, Bencode>> {
+        match self {
+            Bencode::Dictionary(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bencode::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Bencode::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Bencode]> {
+        match self {
+            Bencode::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    /// dictionary keys are written in sorted order as required by the spec
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Dictionary(dict) => {
+                out.push(b'd');
+                let mut keys: Vec<_> = dict.keys().collect();
+                keys.sort();
+                for key in keys {
+                    out.extend_from_slice(format!("{}:", key.len()).as_bytes());
+                    out.extend_from_slice(key);
+                    dict[key].encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Bencode::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Bencode::Integer(value) => out.extend_from_slice(format!("i{}e", value).as_bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+        }
+    }
+}
+
+impl From<&str> for Bencode {
+    fn from(value: &str) -> Self {
+        Bencode::Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Bencode {
+    fn from(value: Vec<u8>) -> Self {
+        Bencode::Bytes(value)
+    }
+}
+
+impl From<i64> for Bencode {
+    fn from(value: i64) -> Self {
+        Bencode::Integer(value)
+    }
+}
+
+/// lists and dictionaries nested deeper are refused, rather than
+/// overflowing the stack on a packet of `l`s
+const MAX_DEPTH: usize = 64;
+
 pub struct Parser {
     data: Vec<u8>,
     current: usize,
+    /// lists and dictionaries being parsed
+    depth: usize,
 }
 
 impl Parser {
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data, current: 0 }
+        Self {
+            data,
+            current: 0,
+            depth: 0,
+        }
+    }
+
+    /// bytes consumed so far, some messages carry raw data after the bencoded part
+    pub fn position(&self) -> usize {
+        self.current
     }
 
     pub fn parse(&mut self) -> Result<Bencode> {
-        match self.peek() {
+        if matches!(self.peek()?, b'd' | b'l') {
+            if self.depth == MAX_DEPTH {
+                bail!("nested deeper than {} lists and dictionaries", MAX_DEPTH);
+            }
+            self.depth += 1;
+            let value = self.parse_value();
+            self.depth -= 1;
+            return value;
+        }
+        self.parse_value()
+    }
+
+    fn parse_value(&mut self) -> Result<Bencode> {
+        match self.peek()? {
             // dictionary
             b'd' => {
                 self.advance();
                 let mut dict = HashMap::new();
-                while self.peek() != &b'e' {
+                while self.peek()? != &b'e' {
                     let key = self.parse()?;
                     let value = self.parse()?;
 
                     if let Bencode::Bytes(key) = key {
-                        let key = String::from_utf8(key)?;
                         dict.insert(key, value);
                     } else {
                         bail!("key is not a string! {:?}", key);
                     }
                 }
+                self.advance();
 
                 Ok(Bencode::Dictionary(dict))
             }
@@ -44,50 +173,54 @@ impl Parser {
             b'l' => {
                 self.advance();
                 let mut list = vec![];
-                while self.peek() != &b'e' {
+                while self.peek()? != &b'e' {
                     let value = self.parse()?;
                     list.push(value);
                 }
+                self.advance();
                 Ok(Bencode::List(list))
             }
 
             // integer
             b'i' => {
                 self.advance();
-                let value = String::from_utf8(self.advance_to(b'e'))?.parse::<isize>()?;
+                let value = String::from_utf8(self.advance_to(b'e')?)?.parse::<i64>()?;
                 Ok(Bencode::Integer(value))
             }
 
             // bytes
             _x @ b'0'..=b'9' => {
-                let size = String::from_utf8(self.advance_to(b':'))?.parse::<usize>()?;
-                let content = self.advance_exact(size);
+                let size = String::from_utf8(self.advance_to(b':')?)?.parse::<usize>()?;
+                let content = self.advance_exact(size)?;
 
                 Ok(Bencode::Bytes(content))
             }
 
             x => {
-                panic!("Unknwon symbol {:?}", x)
+                bail!("Unknwon symbol {:?}", x)
             }
         }
     }
 
-    fn advance_exact(&mut self, size: usize) -> Vec<u8> {
+    fn advance_exact(&mut self, size: usize) -> Result<Vec<u8>> {
+        if self.data.len() - self.current < size {
+            bail!("unexpected end of data");
+        }
         let mut data = vec![];
         for _ in 0..size {
             data.push(self.advance());
         }
-        data
+        Ok(data)
     }
 
     /// advances up to the specified char and consumes it without returning it
-    fn advance_to(&mut self, char: u8) -> Vec<u8> {
+    fn advance_to(&mut self, char: u8) -> Result<Vec<u8>> {
         let mut data = vec![];
-        while self.peek() != &char {
+        while self.peek()? != &char {
             data.push(self.advance());
         }
         self.advance();
-        data
+        Ok(data)
     }
 
     fn advance(&mut self) -> u8 {
@@ -105,11 +238,84 @@ impl Parser {
         self.current > self.data.len()
     }
 
-    fn peek(&self) -> &u8 {
-        &self.data[self.current]
+    fn peek(&self) -> Result<&u8> {
+        match self.data.get(self.current) {
+            Some(byte) => Ok(byte),
+            None => bail!("unexpected end of data"),
+        }
+    }
+}
+
+/// the JSON of a bencoded value, to look into tracker responses, resume
+/// files or DHT packets. Byte strings that aren't utf-8, like piece hashes
+/// and node ids, become `{"$hex": "..."}` objects, and dictionary keys that
+/// aren't utf-8 `"$hex:..."` keys.
+pub fn to_json(value: &Bencode) -> Json {
+    match value {
+        Bencode::Dictionary(dict) => {
+            let mut keys: Vec<_> = dict.keys().collect();
+            keys.sort();
+            Json::Object(
+                keys.into_iter()
+                    .map(|key| {
+                        let name = match std::str::from_utf8(key) {
+                            Ok(name) => name.to_string(),
+                            Err(_) => format!("{}:{}", HEX, to_hex(key)),
+                        };
+                        (name, to_json(&dict[key]))
+                    })
+                    .collect(),
+            )
+        }
+        Bencode::List(list) => Json::Array(list.iter().map(to_json).collect()),
+        Bencode::Integer(value) => Json::Integer(*value),
+        Bencode::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(text) => Json::String(text.to_string()),
+            Err(_) => Json::object().with(HEX, to_hex(bytes)),
+        },
     }
 }
 
+/// the value `to_json` turned into `json`
+pub fn from_json(json: &Json) -> Result<Bencode> {
+    Ok(match json {
+        Json::Object(entries) => match entries.as_slice() {
+            [(key, Json::String(hex))] if key == HEX => Bencode::Bytes(from_hex(hex)?),
+            _ => {
+                let mut dict = HashMap::new();
+                for (key, value) in entries {
+                    let key = match key.strip_prefix(HEX).and_then(|key| key.strip_prefix(':')) {
+                        Some(hex) => from_hex(hex)?,
+                        None => key.as_bytes().to_vec(),
+                    };
+                    dict.insert(key, from_json(value)?);
+                }
+                Bencode::Dictionary(dict)
+            }
+        },
+        Json::Array(values) => Bencode::List(values.iter().map(from_json).collect::<Result<_>>()?),
+        Json::Integer(_) | Json::Number(_) => match json.as_i64() {
+            Some(value) => Bencode::Integer(value),
+            None => bail!("bencode has no number like {}", json),
+        },
+        Json::String(text) => Bencode::Bytes(text.as_bytes().to_vec()),
+        Json::Bool(_) | Json::Null => bail!("bencode has no {}", json),
+    })
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        bail!("invalid hex {}", hex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&hex[index..index + 2], 16)
+                .with_context(|| format!("invalid hex {}", hex))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,9 +326,9 @@ mod tests {
         let parsed = Parser::new(data.to_vec()).parse()?;
 
         let mut expected = HashMap::new();
-        expected.insert(String::from("month"), Bencode::Integer(4));
+        expected.insert(b"month".to_vec(), Bencode::Integer(4));
         expected.insert(
-            String::from("name"),
+            b"name".to_vec(),
             Bencode::Bytes("april".as_bytes().to_vec()),
         );
         let expected = Bencode::Dictionary(expected);
@@ -137,6 +343,10 @@ mod tests {
         let parsed = Parser::new(data.to_vec()).parse()?;
 
         assert!(matches!(parsed, Bencode::Integer(1234)));
+        // lengths of files over 4 GiB, on 32 bit targets too
+        let parsed = Parser::new(b"i4294967296e".to_vec()).parse()?;
+        assert_eq!(parsed, Bencode::Integer(1 << 32));
+        assert_eq!(parsed.encode(), b"i4294967296e");
         Ok(())
     }
 
@@ -152,4 +362,89 @@ mod tests {
         assert!(parsed == expected);
         Ok(())
     }
+
+    #[test]
+    fn truncated() {
+        assert!(Parser::new(b"d5:month".to_vec()).parse().is_err());
+        assert!(Parser::new(b"10:abc".to_vec()).parse().is_err());
+        assert!(Parser::new(b"x".to_vec()).parse().is_err());
+    }
+
+    #[test]
+    fn limits_nesting() -> Result<()> {
+        let nested = |depth| {
+            let mut data = vec![b'l'; depth];
+            data.extend(vec![b'e'; depth]);
+            data
+        };
+        assert!(Parser::new(nested(MAX_DEPTH)).parse().is_ok());
+        assert!(Parser::new(nested(MAX_DEPTH + 1)).parse().is_err());
+        // would overflow the stack of the thread without the limit
+        assert!(Parser::new(vec![b'l'; 100_000]).parse().is_err());
+        let mut parser = Parser::new(b"lllee".to_vec());
+        assert!(parser.parse().is_err());
+        assert!(Parser::new(b"ld1:alleeel1:aee".to_vec()).parse().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn encode_roundtrip() -> Result<()> {
+        let data = "d4:infod6:lengthi12ee4:listli-3e3:fooee".as_bytes();
+        let parsed = Parser::new(data.to_vec()).parse()?;
+
+        assert_eq!(parsed.encode(), data);
+        Ok(())
+    }
+
+    #[test]
+    fn nested() -> Result<()> {
+        let data = "d4:infod6:lengthi12ee4:name3:fooe".as_bytes();
+        let parsed = Parser::new(data.to_vec()).parse()?;
+
+        assert_eq!(parsed.get("name").and_then(Bencode::as_str), Some("foo"));
+        assert_eq!(
+            parsed
+                .get("info")
+                .and_then(|info| info.get("length"))
+                .and_then(Bencode::as_integer),
+            Some(12)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn converts_to_json_and_back() -> Result<()> {
+        let data = std::fs::read("file1.txt.torrent")?;
+        let torrent = Parser::new(data.clone()).parse()?;
+        let json = to_json(&torrent);
+        assert!(json.to_string().contains(r#""name":"file1.txt""#));
+        assert!(json.to_string().contains(r#""pieces":{"$hex":"#));
+        let back = from_json(&Json::parse(&json.to_string())?)?;
+        assert_eq!(back, torrent);
+        assert_eq!(back.encode(), data);
+
+        let mut dict = HashMap::new();
+        dict.insert(vec![0xff, 0x00], Bencode::Integer(-3));
+        let odd = Bencode::Dictionary(dict);
+        assert_eq!(to_json(&odd).to_string(), r#"{"$hex:ff00":-3}"#);
+        assert_eq!(from_json(&to_json(&odd))?, odd);
+        assert!(from_json(&Json::Number(0.5)).is_err());
+        assert!(from_json(&Json::Null).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_large_integers() -> Result<()> {
+        for value in [(1 << 53) + 1, i64::MAX, i64::MIN] {
+            let length = Bencode::Integer(value);
+            let json = to_json(&length).to_string();
+            assert_eq!(json, value.to_string());
+            let back = from_json(&Json::parse(&json)?)?;
+            assert_eq!(back, length);
+            assert_eq!(back.encode(), length.encode());
+        }
+        // past what an i64 holds there is nothing to keep exactly
+        assert!(from_json(&Json::parse("9223372036854775808")?).is_err());
+        Ok(())
+    }
 }
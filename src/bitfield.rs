@@ -0,0 +1,81 @@
+/// Set of pieces using the wire layout, the high bit of the first byte is piece 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>, len: usize) -> Self {
+        let mut bytes = bytes;
+        bytes.resize(len.div_ceil(8), 0);
+        Self { bytes, len }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len {
+            bitfield.set(index, true);
+        }
+        bitfield
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        if value {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        } else {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        (0..self.len).filter(|&index| self.get(index)).count()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_layout() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+        assert!(bitfield.get(9));
+        assert!(!bitfield.get(10));
+        assert_eq!(bitfield.count_ones(), 2);
+    }
+}
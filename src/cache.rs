@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Least recently used cache of whole pieces read from disk, so popular pieces
+/// requested by many peers are only read once. Pieces larger than the budget
+/// aren't cached.
+#[derive(Debug, Default)]
+pub struct ReadCache {
+    budget: u64,
+    used: u64,
+    /// piece data and the tick it was last used at
+    pieces: HashMap<usize, (Arc<[u8]>, u64)>,
+    /// pieces by last use, the first one is evicted first
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl ReadCache {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: u64) {
+        self.budget = budget;
+        self.evict(0);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// counts a hit or a miss
+    pub fn get(&mut self, piece: usize) -> Option<Arc<[u8]>> {
+        self.tick += 1;
+        match self.pieces.get_mut(&piece) {
+            Some((data, last_used)) => {
+                self.lru.remove(last_used);
+                *last_used = self.tick;
+                self.lru.insert(self.tick, piece);
+                self.hits += 1;
+                Some(Arc::clone(data))
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, piece: usize, data: Arc<[u8]>) {
+        let size = data.len() as u64;
+        if size > self.budget {
+            return;
+        }
+        self.remove(piece);
+        self.evict(size);
+        self.tick += 1;
+        self.used += size;
+        self.pieces.insert(piece, (data, self.tick));
+        self.lru.insert(self.tick, piece);
+    }
+
+    pub fn remove(&mut self, piece: usize) {
+        if let Some((data, last_used)) = self.pieces.remove(&piece) {
+            self.lru.remove(&last_used);
+            self.used -= data.len() as u64;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pieces.clear();
+        self.lru.clear();
+        self.used = 0;
+    }
+
+    /// drops the least recently used pieces until `size` more bytes fit
+    fn evict(&mut self, size: u64) {
+        while self.used + size > self.budget {
+            let piece = match self.lru.values().next() {
+                Some(&piece) => piece,
+                None => break,
+            };
+            self.remove(piece);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ReadCache::new(10);
+        cache.insert(0, Arc::from(&[0; 4][..]));
+        cache.insert(1, Arc::from(&[1; 4][..]));
+        assert!(cache.get(0).is_some());
+        cache.insert(2, Arc::from(&[2; 4][..]));
+        cache.insert(3, Arc::from(&[3; 11][..]));
+
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(3).is_none());
+        assert_eq!(cache.get(2).as_deref(), Some(&[2; 4][..]));
+        assert_eq!((cache.hits(), cache.misses(), cache.used), (2, 2, 8));
+
+        cache.set_budget(4);
+        assert!(cache.get(0).is_none());
+        assert!(cache.get(2).is_some());
+    }
+}
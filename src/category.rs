@@ -0,0 +1,69 @@
+use crate::bandwidth::RateLimits;
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+/// What the torrents of a category share, see `Session::set_torrent_category`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Category {
+    /// where its torrents are downloaded unless added with their own save
+    /// path, None downloads to the save path of the session
+    pub save_path: Option<PathBuf>,
+    /// of each of its torrents, within the limits of the session
+    pub rate_limits: RateLimits,
+    /// torrents of higher priority categories are queued ahead of the
+    /// others, torrents without a category have priority 0
+    pub queue_priority: i32,
+}
+
+impl Category {
+    pub fn validate(&self) -> Result<()> {
+        if self
+            .save_path
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            bail!("the save path of a category is empty");
+        }
+        if self.rate_limits.upload == Some(0) || self.rate_limits.download == Some(0) {
+            bail!("rate limits have to be positive, None is unlimited");
+        }
+        Ok(())
+    }
+}
+
+/// names of categories and tags are shown as is and used in filters, so
+/// they can't be empty, padded or contain commas or control characters
+pub fn validate_label(label: &str) -> Result<()> {
+    if label.is_empty() {
+        bail!("labels can't be empty");
+    }
+    if label.trim() != label {
+        bail!("label {:?} starts or ends with spaces", label);
+    }
+    if label.chars().any(|c| c == ',' || c.is_control()) {
+        bail!("label {:?} contains a comma or a control character", label);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_labels_and_categories() {
+        assert!(validate_label("linux isos").is_ok());
+        for label in ["", " padded", "a,b", "line\nbreak"] {
+            assert!(validate_label(label).is_err(), "{:?}", label);
+        }
+        assert!(Category::default().validate().is_ok());
+        let category = Category {
+            rate_limits: RateLimits {
+                upload: Some(0),
+                download: None,
+            },
+            ..Default::default()
+        };
+        assert!(category.validate().is_err());
+    }
+}
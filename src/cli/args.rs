@@ -0,0 +1,384 @@
+//! The commands and flags of the command line, parsed by clap. Only uses
+//! std and clap so build.rs can include it to write the man pages. Values
+//! of types of the library, like ports and rates, are kept as text and
+//! parsed by the commands.
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum, ValueHint};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+pub const EXIT_CODES: &str = "\
+exit codes:
+  0 success, 1 error, 2 incomplete, 3 corrupt data, 4 invalid torrent,
+  5 tracker failure, 6 disk error";
+
+/// download, seed and create torrents
+#[derive(Debug, Parser)]
+#[command(name = "torrent_rs", version, after_help = EXIT_CODES)]
+pub struct Cli {
+    #[command(flatten)]
+    pub globals: GlobalArgs,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Flags of every command, given anywhere on the command line
+#[derive(Debug, Default, Args)]
+pub struct GlobalArgs {
+    /// settings of the session, see the docs of Config
+    #[arg(short, long, global = true, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+    /// listen port, a range like 6881-6889 or random
+    #[arg(short, long, global = true, value_name = "PORT")]
+    pub port: Option<String>,
+    /// upload rate like 2MiB or 500k, KiB/s without a unit, 0 is unlimited,
+    /// over the rate of the config
+    #[arg(long, global = true, value_name = "RATE", alias = "upload-limit")]
+    pub max_upload_rate: Option<String>,
+    /// download rate like 2MiB or 500k, KiB/s without a unit, 0 is
+    /// unlimited, over the rate of the config
+    #[arg(long, global = true, value_name = "RATE", alias = "download-limit")]
+    pub max_download_rate: Option<String>,
+    /// print JSON instead of text, errors included
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// log info, -vv debug and -vvv trace
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// only print and log errors
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+    /// log to a file a day, named like PATH with the date before its
+    /// extension and kept for a week, instead of stderr
+    #[arg(long, global = true, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub log_file: Option<PathBuf>,
+    /// of the logs
+    #[arg(
+        long,
+        global = true,
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t
+    )]
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// a JSON object per line
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// download torrents in one session, exits once they are complete
+    Download(DownloadArgs),
+    /// make a .torrent of a file or a directory
+    Create(CreateArgs),
+    /// print what a .torrent holds
+    #[command(visible_alias = "inspect")]
+    Show(ShowArgs),
+    /// change the trackers, web seeds, comment or private flag of a .torrent
+    Edit(EditArgs),
+    /// fetch the metadata of a magnet link into a .torrent, or print the
+    /// magnet link of one
+    Magnet(MagnetArgs),
+    /// check the downloaded data of a torrent, exits with 2 if incomplete,
+    /// 3 if corrupt
+    Verify(VerifyArgs),
+    /// look for the peers of a .torrent, or list those of a torrent of the
+    /// daemon
+    Peers(PeersArgs),
+    /// join the DHT and print its statistics
+    Dht(DhtArgs),
+    /// manage the torrents of the session
+    Tui(TuiArgs),
+    /// run the session in the background
+    Daemon(DaemonArgs),
+    /// add a torrent to the running daemon
+    Add(AddArgs),
+    /// list the torrents of the daemon
+    List(SocketArgs),
+    /// details of a torrent, named by its name or a prefix of its info hash
+    Info(TorrentArgs),
+    /// pause a torrent of the daemon
+    Pause(TorrentArgs),
+    /// resume a torrent of the daemon
+    Resume(TorrentArgs),
+    /// remove a torrent from the daemon
+    Remove(RemoveArgs),
+    /// print a bencoded file as JSON, or write JSON as bencode
+    #[command(subcommand)]
+    Bencode(BencodeCommand),
+    /// print the completion script of a shell, for packages
+    #[command(hide = true)]
+    Completions {
+        #[arg(value_parser = ["bash", "zsh", "fish", "elvish", "powershell"])]
+        shell: String,
+    },
+    /// print the man page, for packages
+    #[command(hide = true)]
+    Manpage,
+}
+
+/// Connection limits of the commands running a session
+#[derive(Debug, Default, Args)]
+pub struct LimitArgs {
+    /// peers of each torrent, 0 is unlimited
+    #[arg(long, value_name = "N")]
+    pub max_peers: Option<usize>,
+    /// peers of every torrent together, 0 is unlimited
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+}
+
+/// Which files of the torrents to download
+#[derive(Debug, Default, Args)]
+pub struct SelectArgs {
+    /// only download the files matching, like '*.mkv'
+    #[arg(long, value_name = "PATTERN")]
+    pub select: Vec<String>,
+    /// don't download the files matching
+    #[arg(long, value_name = "PATTERN")]
+    pub skip: Vec<String>,
+    /// list the files and pick them by number
+    #[arg(long)]
+    pub list_files: bool,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct SocketArgs {
+    /// socket of the daemon, in $XDG_RUNTIME_DIR by default
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct DownloadArgs {
+    #[arg(
+        value_name = "TORRENT|MAGNET",
+        required = true,
+        value_hint = ValueHint::FilePath
+    )]
+    pub sources: Vec<String>,
+    /// directory to download to
+    #[arg(short, long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub output: Option<PathBuf>,
+    /// how many download at once
+    #[arg(long, value_name = "N")]
+    pub max_active: Option<usize>,
+    #[command(flatten)]
+    pub selection: SelectArgs,
+    #[command(flatten)]
+    pub limits: LimitArgs,
+    /// don't draw progress bars
+    #[arg(long)]
+    pub no_progress: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CreateArgs {
+    #[arg(value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    pub path: PathBuf,
+    /// tracker, can be given more than once
+    #[arg(short, long, value_name = "URL")]
+    pub announce: Vec<String>,
+    /// piece length in bytes, with a K or M suffix
+    #[arg(long, value_name = "N")]
+    pub piece_size: Option<String>,
+    /// only use the trackers to find peers
+    #[arg(long)]
+    pub private: bool,
+    /// comment
+    #[arg(long, value_name = "TEXT")]
+    pub comment: Option<String>,
+    /// make a v2 only torrent
+    #[arg(long, conflicts_with = "hybrid")]
+    pub v2: bool,
+    /// make a torrent both v1 and v2 clients read
+    #[arg(long)]
+    pub hybrid: bool,
+    /// where to write the .torrent
+    #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+    /// print the magnet link
+    #[arg(long)]
+    pub magnet: bool,
+    /// don't draw the hashing progress
+    #[arg(long)]
+    pub no_progress: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ShowArgs {
+    #[arg(value_name = "TORRENT", value_hint = ValueHint::FilePath)]
+    pub torrent: String,
+    /// only print the paths of the files
+    #[arg(long)]
+    pub files_only: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct EditArgs {
+    #[arg(value_name = "TORRENT", value_hint = ValueHint::FilePath)]
+    pub torrent: String,
+    /// make it the only tracker
+    #[arg(long, value_name = "URL")]
+    pub set_announce: Option<String>,
+    /// add a tracker in a tier of its own
+    #[arg(long, value_name = "URL")]
+    pub add_tracker: Vec<String>,
+    /// remove a tracker
+    #[arg(long, value_name = "URL")]
+    pub remove_tracker: Vec<String>,
+    /// add a web seed
+    #[arg(long, value_name = "URL")]
+    pub add_webseed: Vec<String>,
+    /// remove the web seeds
+    #[arg(long)]
+    pub strip_webseeds: bool,
+    /// set the comment
+    #[arg(long, value_name = "TEXT")]
+    pub set_comment: Option<String>,
+    /// remove the comment
+    #[arg(long)]
+    pub strip_comment: bool,
+    /// set the program it was created by
+    #[arg(long, value_name = "TEXT")]
+    pub set_created_by: Option<String>,
+    /// make it private, which changes its info hash
+    #[arg(long, conflicts_with = "set_public")]
+    pub set_private: bool,
+    /// make it public, which changes its info hash
+    #[arg(long)]
+    pub set_public: bool,
+    /// where to write it, name.edited.torrent by default
+    #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+    /// overwrite the .torrent
+    #[arg(long, conflicts_with = "output")]
+    pub in_place: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct MagnetArgs {
+    #[arg(value_name = "URI", required_unless_present = "from")]
+    pub uri: Option<String>,
+    /// where to write the .torrent, name.torrent by default
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub save_metadata: Option<PathBuf>,
+    /// how long to wait for the metadata
+    #[arg(long, value_name = "N", default_value_t = 300)]
+    pub seconds: u64,
+    /// print the magnet link of this .torrent
+    #[arg(
+        long,
+        value_name = "TORRENT",
+        conflicts_with_all = ["uri", "save_metadata"],
+        value_hint = ValueHint::FilePath
+    )]
+    pub from: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    #[arg(value_name = "TORRENT", value_hint = ValueHint::FilePath)]
+    pub torrent: String,
+    /// the directory used to be the second argument
+    #[arg(hide = true, conflicts_with = "data")]
+    pub old_data: Option<PathBuf>,
+    /// where the data is, the current directory by default
+    #[arg(short, long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub data: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct PeersArgs {
+    #[arg(value_name = "TORRENT|INFO HASH", value_hint = ValueHint::FilePath)]
+    pub torrent: String,
+    /// how long to look for those of a .torrent, 60 by default
+    #[arg(long, value_name = "N")]
+    pub seconds: Option<u64>,
+    /// list the peers of the daemon again every second
+    #[arg(long)]
+    pub watch: bool,
+    #[command(flatten)]
+    pub socket: SocketArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct DhtArgs {
+    /// how long to stay
+    #[arg(long, value_name = "N", default_value_t = 60)]
+    pub seconds: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct TuiArgs {
+    #[arg(value_name = "TORRENT|MAGNET", value_hint = ValueHint::FilePath)]
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct DaemonArgs {
+    #[command(flatten)]
+    pub socket: SocketArgs,
+    #[command(flatten)]
+    pub limits: LimitArgs,
+    /// serve the REST api and the Transmission RPC at an address like
+    /// 127.0.0.1:9091, other than loopback only with TORRENT_RS_API_TOKEN set
+    #[arg(long, value_name = "ADDR")]
+    pub api: Option<SocketAddr>,
+    /// a web page origin allowed to call the api, * for any
+    #[arg(long, value_name = "ORIGIN")]
+    pub api_cors: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct AddArgs {
+    #[arg(value_name = "TORRENT|MAGNET", value_hint = ValueHint::FilePath)]
+    pub source: String,
+    /// directory to download to
+    #[arg(short, long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub output: Option<PathBuf>,
+    /// add it paused
+    #[arg(long)]
+    pub paused: bool,
+    #[command(flatten)]
+    pub socket: SocketArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct TorrentArgs {
+    #[arg(value_name = "TORRENT")]
+    pub torrent: String,
+    #[command(flatten)]
+    pub socket: SocketArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct RemoveArgs {
+    #[arg(value_name = "TORRENT")]
+    pub torrent: String,
+    /// delete its files too
+    #[arg(long)]
+    pub delete_data: bool,
+    #[command(flatten)]
+    pub socket: SocketArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BencodeCommand {
+    /// print a bencoded file, or stdin with -, as JSON
+    Decode {
+        #[arg(value_name = "FILE", value_hint = ValueHint::FilePath)]
+        file: String,
+    },
+    /// write JSON, from stdin by default, as bencode
+    Encode {
+        #[arg(value_name = "FILE", value_hint = ValueHint::FilePath)]
+        file: Option<String>,
+    },
+}
@@ -0,0 +1,52 @@
+//! Converts between bencode and JSON, see `bencode::to_json` for how byte
+//! strings that aren't utf-8 are written.
+
+use super::args::BencodeCommand;
+use super::Globals;
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+use torrent_rs::bencode::{from_json, to_json, Parser};
+use torrent_rs::json::Json;
+
+/// `decode` prints a bencoded file, or stdin with `-`, as JSON, `encode`
+/// writes the JSON of a file, or stdin, back as bencode to stdout, which
+/// `--json` can't be
+pub fn run(command: BencodeCommand, globals: &Globals) -> Result<()> {
+    match command {
+        BencodeCommand::Decode { file } => {
+            let data = read(Some(&file))?;
+            let mut parser = Parser::new(data.clone());
+            let value = parser.parse()?;
+            if parser.position() < data.len() && !globals.quiet {
+                eprintln!(
+                    "{} bytes after the bencoded value",
+                    data.len() - parser.position()
+                );
+            }
+            println!("{}", to_json(&value));
+            Ok(())
+        }
+        BencodeCommand::Encode { .. } if globals.json => {
+            bail!("encode writes bencode, not JSON")
+        }
+        BencodeCommand::Encode { file } => {
+            let data = read(file.as_ref())?;
+            let json = Json::parse(std::str::from_utf8(&data).context("the JSON isn't utf-8")?)?;
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&from_json(&json)?.encode())?;
+            stdout.flush()?;
+            Ok(())
+        }
+    }
+}
+
+fn read(file: Option<&String>) -> Result<Vec<u8>> {
+    match file.map(String::as_str) {
+        Some("-") | None => {
+            let mut data = vec![];
+            std::io::stdin().lock().read_to_end(&mut data)?;
+            Ok(data)
+        }
+        Some(file) => std::fs::read(file).with_context(|| format!("reading {}", file)),
+    }
+}
@@ -0,0 +1,190 @@
+//! Commands sending their request to a running daemon, see `daemon`.
+
+use super::args::{AddArgs, Command, RemoveArgs, SocketArgs, TorrentArgs};
+use super::progress::format_bytes;
+use super::{Failure, Globals};
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use torrent_rs::control::{self, Refused, Request};
+use torrent_rs::json::Json;
+
+/// the fields of a list line
+const LIST_FIELDS: [&str; 8] = [
+    "info_hash",
+    "state",
+    "percent",
+    "download_rate",
+    "upload_rate",
+    "peers",
+    "ratio",
+    "name",
+];
+
+/// with `--json` a list is an array of objects with the `LIST_FIELDS`, info
+/// an object of the fields of the torrent, add `{"info_hash":..}` and the
+/// other commands `{}`
+pub fn run(command: Command, globals: &Globals) -> Result<()> {
+    let (socket, request) = match command {
+        Command::Add(AddArgs {
+            source,
+            output: save_path,
+            paused,
+            socket,
+        }) => {
+            // the daemon may run elsewhere
+            let source = if source.starts_with("magnet:") {
+                source
+            } else {
+                std::fs::canonicalize(&source)
+                    .with_context(|| format!("reading {}", source))
+                    .context(Failure::Torrent)?
+                    .display()
+                    .to_string()
+            };
+            let save_path = match save_path {
+                Some(path) => Some(std::env::current_dir()?.join(path)),
+                None => None,
+            };
+            let request = Request::Add {
+                source,
+                save_path,
+                paused,
+            };
+            (socket, request)
+        }
+        Command::List(socket) => (socket, Request::List),
+        Command::Info(TorrentArgs { torrent, socket }) => (socket, Request::Info(torrent)),
+        Command::Pause(TorrentArgs { torrent, socket }) => (socket, Request::Pause(torrent)),
+        Command::Resume(TorrentArgs { torrent, socket }) => (socket, Request::Resume(torrent)),
+        Command::Remove(RemoveArgs {
+            torrent,
+            delete_data,
+            socket,
+        }) => (
+            socket,
+            Request::Remove {
+                torrent,
+                delete_data,
+            },
+        ),
+        _ => unreachable!("not a client command"),
+    };
+    let socket: PathBuf = socket_path(socket);
+    let lines = match control::request(&socket, &request) {
+        Err(error) if error.is::<Refused>() => return Err(error).context(Failure::Torrent),
+        result => result?,
+    };
+    if globals.json {
+        let json = match request {
+            Request::Add { .. } => Json::object().with("info_hash", lines.join(" ")),
+            Request::List => Json::Array(lines.iter().map(|line| list_json(line)).collect()),
+            Request::Info(_) => info_json(&lines),
+            _ => Json::object(),
+        };
+        println!("{}", json);
+        return Ok(());
+    }
+    match request {
+        Request::Add { .. } => println!("added {}", lines.join(" ")),
+        Request::List => print_list(&lines),
+        Request::Info(_) => {
+            for line in lines {
+                if let Some((key, value)) = line.split_once('\t') {
+                    println!("{:<14} {}", format!("{}:", key), value.replace('\t', "  "));
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// fields of the info and list lines, the others are strings
+const NUMBERS: [&str; 11] = [
+    "progress",
+    "size",
+    "done",
+    "downloaded",
+    "uploaded",
+    "ratio",
+    "download rate",
+    "upload rate",
+    "peers",
+    "eta",
+    "percent",
+];
+
+fn field(key: &str, value: &str) -> Json {
+    match value.parse::<f64>() {
+        Ok(number) if NUMBERS.contains(&key.replace('_', " ").as_str()) => Json::Number(number),
+        _ => Json::from(value),
+    }
+}
+
+fn list_json(line: &str) -> Json {
+    let mut json = Json::object();
+    for (key, value) in LIST_FIELDS.iter().zip(line.split('\t')) {
+        json = json.with(key, field(key, value));
+    }
+    json
+}
+
+/// the files and trackers repeated in the lines become arrays
+fn info_json(lines: &[String]) -> Json {
+    let mut json = Json::object();
+    let (mut files, mut trackers) = (vec![], vec![]);
+    for line in lines {
+        let mut fields = line.split('\t');
+        let key = fields.next().unwrap_or_default();
+        let values: Vec<_> = fields.collect();
+        match (key, values.as_slice()) {
+            ("file", [path, done, length]) => files.push(
+                Json::object()
+                    .with("path", *path)
+                    .with("bytes_done", field("done", done))
+                    .with("length", field("size", length)),
+            ),
+            ("tracker", [url]) => trackers.push(Json::from(*url)),
+            (key, [value]) => json = json.with(&key.replace(' ', "_"), field(key, value)),
+            _ => {}
+        }
+    }
+    json.with("trackers", Json::Array(trackers))
+        .with("files", Json::Array(files))
+}
+
+/// `--socket`, or the default one
+fn socket_path(args: SocketArgs) -> PathBuf {
+    args.socket.unwrap_or_else(control::default_socket_path)
+}
+
+/// the fields of a list line are the info hash, state, percent, download
+/// and upload rates, peers, ratio and name
+fn print_list(lines: &[String]) {
+    if lines.is_empty() {
+        println!("no torrents");
+        return;
+    }
+    println!(
+        "{:<8} {:<18} {:>6} {:>12} {:>12} {:>5} {:>6}  name",
+        "hash", "state", "done", "down", "up", "peers", "ratio"
+    );
+    for line in lines {
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() < 8 {
+            continue;
+        }
+        let rate = |field: &str| format!("{}/s", format_bytes(field.parse().unwrap_or(0.0)));
+        println!(
+            "{:<8} {:<18} {:>5}% {:>12} {:>12} {:>5} {:>6}  {}",
+            &fields[0][..8],
+            fields[1],
+            fields[2],
+            rate(fields[3]),
+            rate(fields[4]),
+            fields[5],
+            fields[6],
+            fields[7]
+        );
+    }
+}
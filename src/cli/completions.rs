@@ -0,0 +1,41 @@
+//! Completion scripts of the shells clap_complete knows, written from the
+//! commands of `args`, printed by the hidden `completions <shell>` command
+//! for packages to install.
+
+use super::args::Cli;
+use anyhow::{anyhow, Result};
+use clap::CommandFactory;
+use clap_complete::Shell;
+use std::io::Write;
+
+pub fn run(shell: &str) -> Result<()> {
+    let script = script(shell.parse().map_err(|error: String| anyhow!(error))?);
+    std::io::stdout().lock().write_all(&script)?;
+    Ok(())
+}
+
+fn script(shell: Shell) -> Vec<u8> {
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut Cli::command(), "torrent_rs", &mut script);
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_every_command() {
+        let bash = String::from_utf8(script(Shell::Bash)).unwrap();
+        let fish = String::from_utf8(script(Shell::Fish)).unwrap();
+        for command in Cli::command().get_subcommands() {
+            if command.is_hide_set() {
+                continue;
+            }
+            let name = command.get_name();
+            assert!(bash.contains(&format!("torrent_rs,{})", name)), "{}", name);
+            assert!(fish.contains(&format!("-a \"{}\"", name)), "{}", name);
+        }
+        assert!(fish.contains("-l delete-data -d 'delete its files too'"));
+    }
+}
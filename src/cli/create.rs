@@ -0,0 +1,114 @@
+use super::args::CreateArgs;
+use super::progress::{format_bytes, hashing_bar};
+use super::{Failure, Globals};
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use torrent_rs::create::{TorrentCreator, TorrentVersion};
+use torrent_rs::json::Json;
+use torrent_rs::metainfo::to_hex;
+use torrent_rs::Metainfo;
+
+/// makes a .torrent of a file or of a directory, written to `-o FILE` or
+/// to the name of the path with .torrent appended in the current directory
+pub fn run(args: CreateArgs, globals: &Globals) -> Result<()> {
+    let CreateArgs {
+        path,
+        announce: trackers,
+        piece_size,
+        private,
+        comment,
+        v2,
+        hybrid,
+        output,
+        magnet,
+        no_progress,
+    } = args;
+    let piece_size = match piece_size {
+        Some(size) => {
+            Some(parse_size(&size).with_context(|| format!("invalid --piece-size {}", size))?)
+        }
+        None => None,
+    };
+    let version = match (v2, hybrid) {
+        (true, _) => TorrentVersion::V2,
+        (false, true) => TorrentVersion::Hybrid,
+        (false, false) => TorrentVersion::V1,
+    };
+
+    let mut creator = TorrentCreator::new(&path).private(private).version(version);
+    for tracker in trackers {
+        creator = creator.tracker(tracker);
+    }
+    if let Some(size) = piece_size {
+        creator = creator.piece_length(size);
+    }
+    if let Some(comment) = comment {
+        creator = creator.comment(comment);
+    }
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let mut name = path
+                .file_name()
+                .with_context(|| format!("{} has no file name", path.display()))?
+                .to_os_string();
+            name.push(".torrent");
+            PathBuf::from(name)
+        }
+    };
+
+    creator.validate()?;
+
+    // indicatif hides it when stderr isn't a terminal
+    let progress = (!no_progress && !globals.json).then(|| hashing_bar(0));
+    let torrent = creator
+        .create(|done, total| {
+            if let Some(progress) = &progress {
+                progress.set_length(total);
+                progress.set_position(done);
+            }
+        })
+        .map_err(Failure::disk)?;
+    if let Some(progress) = progress.filter(|progress| !progress.is_hidden()) {
+        progress.finish();
+        eprintln!();
+    }
+    std::fs::write(&output, &torrent)
+        .with_context(|| format!("writing {}", output.display()))
+        .context(Failure::Disk)?;
+    let metainfo = Metainfo::from_bytes(torrent)?;
+    if globals.json {
+        let json = Json::object()
+            .with("torrent", output.to_string_lossy().into_owned())
+            .with("info_hash", metainfo.info_hash_hex())
+            .with(
+                "info_hash_v2",
+                metainfo.info_hash_v2.map(|hash| to_hex(&hash)),
+            )
+            .with("piece_length", metainfo.info.piece_length)
+            .with("piece_count", metainfo.info.piece_count())
+            .with("magnet", metainfo.magnet_link());
+        println!("{}", json);
+        return Ok(());
+    }
+    eprintln!(
+        "created {} with {} pieces of {}",
+        output.display(),
+        metainfo.info.piece_count(),
+        format_bytes(metainfo.info.piece_length as f64)
+    );
+    if magnet {
+        println!("{}", metainfo.magnet_link());
+    }
+    Ok(())
+}
+
+/// bytes, or KiB and MiB with a K or M suffix
+fn parse_size(text: &str) -> Result<u64> {
+    let (number, unit) = match text.to_ascii_uppercase() {
+        text if text.ends_with('K') => (text[..text.len() - 1].to_string(), 1024),
+        text if text.ends_with('M') => (text[..text.len() - 1].to_string(), 1024 * 1024),
+        text => (text, 1),
+    };
+    Ok(number.parse::<u64>()? * unit)
+}
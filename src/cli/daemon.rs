@@ -0,0 +1,138 @@
+use super::args::DaemonArgs;
+use super::limits::Limits;
+use super::Globals;
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use torrent_rs::api::ApiConfig;
+use torrent_rs::control::{self, ControlServer};
+use torrent_rs::json::Json;
+use torrent_rs::Session;
+
+/// runs the session without a terminal, taking the requests of `add`,
+/// `list` and the other client commands on `--socket`, until Ctrl-C or
+/// SIGTERM. With `--json` what it reports is printed as lines of JSON with
+/// an `event` of listening, with the port and socket, or of metadata, alert
+/// or shutdown with a `message`. The rate and connection flags are those
+/// of `download`, see `Limits`. `--api` serves the REST api of `api` over
+/// that of the config, with the token of `TORRENT_RS_API_TOKEN`.
+pub fn run(args: DaemonArgs, globals: &Globals) -> Result<()> {
+    let socket: PathBuf = args
+        .socket
+        .socket
+        .unwrap_or_else(control::default_socket_path);
+    let limits = Limits::parse(args.limits)?;
+    let api = args.api;
+    let cors_origins = args.api_cors;
+    let mut config = globals.config()?;
+    limits.apply(&mut config)?;
+    if let Some(addr) = api {
+        let api = config.api.get_or_insert(ApiConfig {
+            addr,
+            token: None,
+            cors_origins: vec![],
+        });
+        api.addr = addr;
+        if let Ok(token) = std::env::var("TORRENT_RS_API_TOKEN") {
+            api.token = Some(token);
+        }
+    }
+    match &mut config.api {
+        Some(api) => api.cors_origins.extend(cors_origins),
+        None if !cors_origins.is_empty() => bail!("--api-cors needs the api, see --api"),
+        None => {}
+    }
+    config.validate()?;
+    let token = match config.api.as_ref().is_some_and(|api| api.token.is_some()) {
+        true => "?token=<token>",
+        false => "",
+    };
+    let mut session = Session::new(config)?;
+    let server = ControlServer::start(&socket)?;
+    // also kept in the log file, where the daemon's output usually goes
+    let report = |event: &str, message: String| {
+        if globals.logs_to_file() {
+            tracing::info!(%event, "{}", message);
+        }
+        if globals.json {
+            let event = Json::object().with("event", event).with("message", message);
+            println!("{}", event);
+        } else if !globals.quiet {
+            eprintln!("{}", message);
+        }
+    };
+    if globals.json {
+        let event = Json::object()
+            .with("event", "listening")
+            .with("port", session.listen_port() as u64)
+            .with("socket", server.path().to_string_lossy().into_owned())
+            .with(
+                "metrics",
+                session.metrics_addr().map(|addr| addr.to_string()),
+            )
+            .with("stream", session.stream_addr().map(|addr| addr.to_string()))
+            .with("api", session.api_addr().map(|addr| addr.to_string()));
+        println!("{}", event);
+    } else {
+        report(
+            "listening",
+            format!(
+                "listening on port {} and for commands on {}",
+                session.listen_port(),
+                server.path().display()
+            ),
+        );
+        if let Some(addr) = session.metrics_addr() {
+            report(
+                "listening",
+                format!("serving metrics at http://{}/metrics", addr),
+            );
+        }
+        if let Some(addr) = session.stream_addr() {
+            report(
+                "listening",
+                format!(
+                    "streaming files at http://{}/<info hash>/<file index>{}",
+                    addr, token
+                ),
+            );
+        }
+        if let Some(addr) = session.api_addr() {
+            let message = match cfg!(feature = "web-ui") {
+                true => format!("serving the api and web ui at http://{}/", addr),
+                false => format!("serving the api at http://{}/api", addr),
+            };
+            report("listening", message);
+            if cfg!(feature = "grpc") {
+                report("listening", format!("serving gRPC at {}", addr));
+            }
+        }
+    }
+
+    let interrupted = super::on_ctrl_c();
+    let mut last_tick = Instant::now();
+    while !super::interrupted(interrupted) {
+        while let Some(pending) = server.poll() {
+            let result = pending.request.clone().apply(&mut session);
+            pending.respond(result);
+        }
+        session.poll_api();
+        for torrent in session.poll_magnets()? {
+            report(
+                "metadata",
+                format!("fetched the metadata of {}", torrent.root_name()),
+            );
+        }
+        if last_tick.elapsed() >= Duration::from_secs(1) {
+            last_tick = Instant::now();
+            session.tick()?;
+            for alert in session.take_alerts() {
+                report("alert", alert.to_string());
+            }
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    drop(server);
+    report("shutdown", "shutting down".into());
+    session.shutdown(super::SHUTDOWN_TIMEOUT)
+}
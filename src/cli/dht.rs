@@ -0,0 +1,64 @@
+use super::args::DhtArgs;
+use super::{dht_state_path, Globals};
+use anyhow::{bail, Result};
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use torrent_rs::dht::{DhtConfig, DhtTask};
+use torrent_rs::json::Json;
+use torrent_rs::listen::ListenPort;
+
+/// joins the DHT and prints its statistics every few seconds, the node is
+/// saved to `dht_state_path` to rejoin faster next time. With `--json` every
+/// sample is a line of JSON, the last one with the sizes of the buckets.
+pub fn run(args: DhtArgs, globals: &Globals) -> Result<()> {
+    let seconds = args.seconds;
+    let port = match globals.port() {
+        None => 6881,
+        Some(ListenPort::Fixed(port)) => port,
+        Some(ListenPort::Random) => 0,
+        Some(port) => bail!("the DHT runs on a single port, not {}", port),
+    };
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let config = DhtConfig {
+        state_path: dht_state_path(),
+        ..Default::default()
+    };
+    let task = DhtTask::start(socket, config)?;
+    let end = Instant::now() + Duration::from_secs(seconds);
+    loop {
+        let left = end.saturating_duration_since(Instant::now());
+        std::thread::sleep(left.min(Duration::from_secs(5)));
+        let stats = task.stats();
+        if globals.json {
+            let mut json = Json::object()
+                .with("nodes", stats.nodes)
+                .with("lookups", stats.lookups)
+                .with("queries", stats.queries)
+                .with("packets_in", stats.packets_in)
+                .with("packets_out", stats.packets_out)
+                .with("peers", stats.peers)
+                .with("info_hashes", stats.info_hashes);
+            if left.is_zero() {
+                json = json.with("buckets", stats.buckets.clone());
+            }
+            println!("{}", json);
+        } else {
+            println!(
+            "{} nodes, {} lookups, {} queries, {} packets in, {} out, {} peers of {} torrents stored",
+            stats.nodes,
+            stats.lookups,
+            stats.queries,
+            stats.packets_in,
+            stats.packets_out,
+            stats.peers,
+            stats.info_hashes
+        );
+        }
+        if left.is_zero() {
+            if !globals.json {
+                println!("buckets {:?}", stats.buckets);
+            }
+            return Ok(());
+        }
+    }
+}
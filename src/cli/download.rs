@@ -0,0 +1,375 @@
+use super::args::DownloadArgs;
+use super::limits::Limits;
+use super::progress::{format_bytes, Bars};
+use super::select::{self, Selection};
+use super::{Failure, Globals, EXIT_INCOMPLETE};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use torrent_rs::events::Alert;
+use torrent_rs::json::Json;
+use torrent_rs::metainfo::to_hex;
+use torrent_rs::torrent::TorrentState;
+use torrent_rs::{Session, TorrentHandle};
+
+/// What became of a torrent or magnet link given on the command line
+enum Entry {
+    Added([u8; 20]),
+    Failed {
+        source: String,
+        error: anyhow::Error,
+    },
+}
+
+/// downloads .torrent files and magnet links to `-o DIR`, or the save path
+/// of the config, in one session sharing its rate limits, at most
+/// `--max-active` at once, and exits once every file is complete. Patterns
+/// like *.torrent the shell didn't expand are. Files already there are
+/// checked first. Ctrl-C stops early. Progress bars are drawn while stderr
+/// is a terminal unless `--no-progress`, and `-q` only prints errors
+/// and alerts. `--select`, `--skip` and `--list-files` pick the files of
+/// every torrent once its metadata is there, see `Selection`, and the rate
+/// and connection flags those of the session, see `Limits`. Several torrents end with a summary table. With `--json` the
+/// torrent is printed once it stopped, see `to_json`, or an array of them
+/// for several.
+///
+/// Stopping early exits with `EXIT_INCOMPLETE`, or as a tracker failure when
+/// every tracker of a torrent failed. A disk error stops the download.
+pub fn run(args: DownloadArgs, globals: &Globals) -> Result<()> {
+    let DownloadArgs {
+        sources,
+        output,
+        max_active,
+        selection,
+        limits,
+        no_progress,
+    } = args;
+    let quiet = globals.quiet || globals.json;
+    let selection = Selection::from(selection);
+    let limits = Limits::parse(limits)?;
+    let sources: Vec<String> = sources.into_iter().flat_map(expand).collect();
+    let mut config = globals.config()?;
+    if let Some(dir) = output {
+        config.save_path = dir;
+    }
+    if let Some(max) = max_active {
+        config.queue.max_active_downloads = max;
+        config.queue.max_active_total = config.queue.max_active_total.max(max);
+    }
+    limits.apply(&mut config)?;
+    let mut session = Session::new(config)?;
+    let single = sources.len() == 1;
+    let mut entries = vec![];
+    for source in sources {
+        match add(&mut session, &source, quiet) {
+            Ok(info_hash) => entries.push(Entry::Added(info_hash)),
+            // one bad torrent of many doesn't stop the others
+            Err(error) if !single => {
+                if !globals.json {
+                    eprintln!("Error: {:#}", error);
+                }
+                entries.push(Entry::Failed { source, error });
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    let info_hashes: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Added(info_hash) => Some(*info_hash),
+            Entry::Failed { .. } => None,
+        })
+        .collect();
+
+    let mut bars = (!quiet && !no_progress).then(Bars::new);
+    let interrupted = super::on_ctrl_c();
+    let mut last_tick = Instant::now();
+    let mut announced = HashSet::new();
+    let mut stopped = HashSet::new();
+    loop {
+        session.poll_magnets()?;
+        for info_hash in &info_hashes {
+            let torrent = match session.torrent(info_hash) {
+                Some(torrent) => torrent,
+                None => continue,
+            };
+            if announced.insert(*info_hash) {
+                selection.apply(torrent)?;
+                if !quiet {
+                    say(
+                        &bars,
+                        &format!(
+                            "downloading {} to {}",
+                            torrent.root_name(),
+                            torrent.save_path().display()
+                        ),
+                    );
+                }
+            }
+            let message = if torrent.is_finished() {
+                format!("{} is complete", torrent.root_name())
+            } else if torrent.state() == TorrentState::Errored {
+                format!("{} stopped by a disk error", torrent.root_name())
+            } else {
+                continue;
+            };
+            if stopped.insert(*info_hash) && !quiet {
+                say(&bars, &message);
+            }
+        }
+        if stopped.len() == info_hashes.len() {
+            break;
+        }
+        if super::interrupted(interrupted) {
+            if !quiet {
+                say(
+                    &bars,
+                    "interrupted, the downloads resume where they stopped next time",
+                );
+            }
+            break;
+        }
+        if last_tick.elapsed() >= Duration::from_secs(1) {
+            last_tick = Instant::now();
+            session.tick()?;
+            if let Some(bars) = &mut bars {
+                bars.draw(&session.torrents().cloned().collect::<Vec<_>>());
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    if let Some(bars) = &mut bars {
+        bars.draw(&session.torrents().cloned().collect::<Vec<_>>());
+        bars.finish();
+    }
+
+    let session_alerts = session.take_alerts();
+    let mut results: Vec<Outcome> = vec![];
+    for entry in entries {
+        let result = match entry {
+            Entry::Added(info_hash) => {
+                let torrent = session.torrent(&info_hash).cloned();
+                let alerts = torrent
+                    .as_ref()
+                    .map(TorrentHandle::take_alerts)
+                    .unwrap_or_default();
+                Ok((info_hash, torrent, alerts))
+            }
+            Entry::Failed { source, error } => Err((source, error)),
+        };
+        results.push(result);
+    }
+    if globals.json {
+        let mut documents: Vec<_> = results
+            .iter()
+            .map(|result| match result {
+                Ok((info_hash, torrent, alerts)) => to_json(*info_hash, torrent.as_ref(), alerts),
+                Err((source, error)) => Json::object()
+                    .with("source", source.as_str())
+                    .with("error", format!("{:#}", error)),
+            })
+            .collect();
+        match documents.len() {
+            1 => println!("{}", documents.remove(0)),
+            _ => println!("{}", Json::Array(documents)),
+        }
+    } else {
+        for alert in session_alerts
+            .iter()
+            .chain(results.iter().flatten().flat_map(|(_, _, alerts)| alerts))
+        {
+            eprintln!("{}", alert);
+        }
+        if !single && !quiet {
+            print!("{}", summary(&results));
+        }
+    }
+    session.shutdown(super::SHUTDOWN_TIMEOUT)?;
+
+    match outcome(results, &session_alerts) {
+        // the torrents were printed already, a second document would trip
+        // up scripts
+        Err(error) if globals.json => std::process::exit(super::exit_code(&error)),
+        outcome => outcome,
+    }
+}
+
+/// the worst outcome picks the exit code
+fn outcome(results: Vec<Outcome>, session_alerts: &[Alert]) -> Result<()> {
+    let mut incomplete = false;
+    let mut trackers_failed = None;
+    let mut failed_adds = 0;
+    for result in results {
+        let (torrent, alerts) = match result {
+            Ok((_, torrent, alerts)) => (torrent, alerts),
+            Err(_) => {
+                failed_adds += 1;
+                continue;
+            }
+        };
+        match torrent {
+            Some(torrent) if torrent.is_finished() => {}
+            Some(torrent) if torrent.state() == TorrentState::Errored => {
+                return Err(anyhow!(torrent.error().unwrap_or_default())).context(Failure::Disk);
+            }
+            torrent => {
+                incomplete = true;
+                let urls = torrent.map(|torrent| torrent.announce_urls());
+                let failed = |url: &String| {
+                    alerts.iter().chain(session_alerts).any(|alert| {
+                        matches!(alert, Alert::TrackerFailed { url: failed, .. } if failed == url)
+                    })
+                };
+                if let Some(urls) = urls.filter(|urls| !urls.is_empty() && urls.iter().all(failed))
+                {
+                    trackers_failed = Some(urls.join(", "));
+                }
+            }
+        }
+    }
+    if failed_adds > 0 {
+        return Err(anyhow!("{} of the torrents couldn't be added", failed_adds))
+            .context(Failure::Torrent);
+    }
+    if let Some(urls) = trackers_failed {
+        return Err(anyhow!("every tracker failed: {}", urls)).context(Failure::Tracker);
+    }
+    if incomplete {
+        std::process::exit(EXIT_INCOMPLETE);
+    }
+    Ok(())
+}
+
+fn add(session: &mut Session, source: &str, quiet: bool) -> Result<[u8; 20]> {
+    if source.starts_with("magnet:") {
+        if !quiet {
+            eprintln!("fetching the metadata of {}", source);
+        }
+        return session.add_magnet(source).context(Failure::Torrent);
+    }
+    let torrent = session.add_torrent(super::read_torrent(source)?)?;
+    if torrent.file_paths().iter().any(|path| path.exists()) {
+        torrent.force_recheck().context(Failure::Disk)?;
+    }
+    Ok(torrent.info_hash())
+}
+
+/// the files matching a pattern with * or ? in its file name, for shells
+/// that don't expand them, anything else is kept as is
+fn expand(source: String) -> Vec<String> {
+    let path = Path::new(&source);
+    let pattern = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains(['*', '?']) && !path.exists() => name.to_string(),
+        _ => return vec![source],
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut matches: Vec<_> = std::fs::read_dir(&parent)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            (!name.starts_with('.') && select::glob(&pattern, &name))
+                .then(|| path.with_file_name(name).to_string_lossy().into_owned())
+        })
+        .collect();
+    matches.sort();
+    if matches.is_empty() {
+        // fails to be read with the pattern in the error
+        return vec![source];
+    }
+    matches
+}
+
+/// of a torrent, or of a source that couldn't be added
+type Outcome =
+    std::result::Result<([u8; 20], Option<TorrentHandle>, Vec<Alert>), (String, anyhow::Error)>;
+
+/// a line per torrent with its state, how much of it is done and what was
+/// transferred
+fn summary(results: &[Outcome]) -> String {
+    let mut out = format!(
+        "{:<32} {:<18} {:>6} {:>11} {:>11}\n",
+        "name", "state", "done", "downloaded", "uploaded"
+    );
+    for result in results {
+        let line = match result {
+            Ok((_, Some(torrent), _)) => {
+                let progress = torrent.progress_report();
+                let stats = torrent.stats();
+                format!(
+                    "{:<32} {:<18} {:>5.1}% {:>11} {:>11}",
+                    torrent.root_name(),
+                    torrent.state().to_string(),
+                    progress.percent,
+                    format_bytes(stats.downloaded as f64),
+                    format_bytes(stats.uploaded as f64)
+                )
+            }
+            Ok((info_hash, None, _)) => format!(
+                "{:<32} {:<18} {:>6} {:>11} {:>11}",
+                to_hex(info_hash),
+                TorrentState::FetchingMetadata.to_string(),
+                "-",
+                "-",
+                "-"
+            ),
+            Err((source, _)) => format!("{:<32} {:<18}", source, "failed to add"),
+        };
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// `{"info_hash":..,"name":..,"save_path":..,"state":..,"complete":..,
+/// "downloaded":..,"uploaded":..,"alerts":[..]}`, the name and save path
+/// being null if the metadata of a magnet link never came
+fn to_json(info_hash: [u8; 20], torrent: Option<&TorrentHandle>, alerts: &[Alert]) -> Json {
+    let stats = torrent.map(TorrentHandle::stats);
+    Json::object()
+        .with("info_hash", to_hex(&info_hash))
+        .with("name", torrent.map(TorrentHandle::root_name))
+        .with(
+            "save_path",
+            torrent.map(|torrent| torrent.save_path().to_string_lossy().into_owned()),
+        )
+        .with(
+            "state",
+            torrent
+                .map_or(TorrentState::FetchingMetadata, TorrentHandle::state)
+                .to_string(),
+        )
+        .with("complete", torrent.is_some_and(TorrentHandle::is_finished))
+        .with("downloaded", stats.as_ref().map(|stats| stats.downloaded))
+        .with("uploaded", stats.as_ref().map(|stats| stats.uploaded))
+        .with(
+            "alerts",
+            alerts.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        )
+}
+
+/// a message above the bars when they are shown
+fn say(bars: &Option<Bars>, message: &str) {
+    match bars {
+        Some(bars) => bars.println(message),
+        None => eprintln!("{}", message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_patterns() {
+        assert_eq!(expand("*.txt.torrent".into()), ["file1.txt.torrent"]);
+        assert_eq!(expand("./file?.txt".into()), ["./file1.txt"]);
+        assert_eq!(expand("magnet:?xt=a*".into()), ["magnet:?xt=a*"]);
+        assert_eq!(expand("none*.torrent".into()), ["none*.torrent"]);
+    }
+}
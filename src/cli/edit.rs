@@ -0,0 +1,109 @@
+use super::args::EditArgs;
+use super::{Failure, Globals};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use torrent_rs::json::Json;
+
+/// changes the trackers, web seeds, comment or private flag of a .torrent,
+/// written to `-o FILE`, or next to it as name.edited.torrent unless
+/// `--in-place`. Making it private or public changes its info hash, which
+/// makes it a different torrent for peers, so it is warned about.
+pub fn run(args: EditArgs, globals: &Globals) -> Result<()> {
+    let EditArgs {
+        torrent,
+        set_announce: announce,
+        add_tracker: added,
+        remove_tracker: removed,
+        add_webseed: webseeds,
+        strip_webseeds,
+        set_comment: comment,
+        strip_comment,
+        set_created_by: created_by,
+        set_private,
+        set_public,
+        output,
+        in_place,
+    } = args;
+    let private = (set_private || set_public).then_some(set_private);
+    let output = match (output, in_place) {
+        (Some(output), _) => output,
+        (None, true) => PathBuf::from(&torrent),
+        (None, false) => edited_path(Path::new(&torrent)),
+    };
+
+    let mut metainfo = super::read_torrent(&torrent)?;
+    let info_hash = metainfo.info_hash;
+    if let Some(url) = announce {
+        metainfo.set_announce(&url);
+    }
+    for url in removed {
+        metainfo.remove_tracker(&url);
+    }
+    for url in added {
+        metainfo.add_tracker(&url);
+    }
+    if strip_webseeds {
+        metainfo.url_list.clear();
+    }
+    for url in webseeds {
+        if !metainfo.url_list.contains(&url) {
+            metainfo.url_list.push(url);
+        }
+    }
+    if strip_comment {
+        metainfo.comment = None;
+    }
+    if let Some(comment) = comment {
+        metainfo.comment = Some(comment);
+    }
+    if let Some(created_by) = created_by {
+        metainfo.created_by = Some(created_by);
+    }
+    if let Some(private) = private {
+        metainfo.set_private(private)?;
+    }
+    std::fs::write(&output, metainfo.to_bytes()?)
+        .with_context(|| format!("writing {}", output.display()))
+        .context(Failure::Disk)?;
+
+    let changed = metainfo.info_hash != info_hash;
+    if globals.json {
+        let json = Json::object()
+            .with("torrent", output.to_string_lossy().into_owned())
+            .with("info_hash", metainfo.info_hash_hex())
+            .with("info_hash_changed", changed);
+        println!("{}", json);
+        return Ok(());
+    }
+    if changed {
+        eprintln!(
+            "warning: the info hash changed to {}, peers of the original torrent won't share it",
+            metainfo.info_hash_hex()
+        );
+    }
+    eprintln!("wrote {}", output.display());
+    Ok(())
+}
+
+/// name.torrent becomes name.edited.torrent
+fn edited_path(torrent: &Path) -> PathBuf {
+    let stem = torrent
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    torrent.with_file_name(format!("{}.edited.torrent", stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_next_to_the_torrent() {
+        assert_eq!(
+            edited_path(Path::new("dir/a.torrent")),
+            Path::new("dir/a.edited.torrent")
+        );
+        assert_eq!(edited_path(Path::new("b")), Path::new("b.edited.torrent"));
+    }
+}
@@ -0,0 +1,85 @@
+//! Connection limits of the commands running a session, over those of the
+//! config, and the rates of the global `--max-upload-rate` and
+//! `--max-download-rate`.
+
+use super::args::LimitArgs;
+use anyhow::{anyhow, bail, Result};
+use torrent_rs::Config;
+
+#[derive(Debug, Default)]
+pub struct Limits {
+    max_peers: Option<usize>,
+    max_connections: Option<usize>,
+}
+
+impl Limits {
+    pub fn parse(args: LimitArgs) -> Result<Self> {
+        Ok(Limits {
+            max_peers: args.max_peers,
+            max_connections: args.max_connections,
+        })
+    }
+
+    /// 0 peers or connections is unlimited like a rate of 0
+    pub fn apply(&self, config: &mut Config) -> Result<()> {
+        if let Some(max) = self.max_peers {
+            config.connection_limits.max_peers = Some(max).filter(|max| *max > 0);
+        }
+        if let Some(max) = self.max_connections {
+            config.connection_limits.max_connections = Some(max).filter(|max| *max > 0);
+        }
+        config.validate()
+    }
+}
+
+/// a rate like 2MiB, 500k or 1.5M/s in bytes per second, KiB/s without a
+/// unit like the config, None for 0. kB, MB and GB are powers of 1000, KiB,
+/// MiB and GiB powers of 1024 like the bare k, M and G, B is bytes.
+pub fn parse_rate(text: &str) -> Result<Option<u64>> {
+    let invalid = || anyhow!("{:?} isn't a rate like 2MiB, 500k or 100", text);
+    let lower = text.trim().to_ascii_lowercase();
+    let lower = lower.strip_suffix("/s").unwrap_or(&lower);
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let unit = match unit.trim() {
+        "b" => 1.0,
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        "" | "k" | "kib" => 1024.0,
+        "m" | "mib" => 1024.0 * 1024.0,
+        "g" | "gib" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(invalid()),
+    };
+    let rate = (number * unit).round();
+    if !rate.is_finite() || rate > u64::MAX as f64 {
+        bail!("{} is too high a rate", text);
+    }
+    Ok(Some(rate as u64).filter(|rate| *rate > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() -> Result<()> {
+        assert_eq!(parse_rate("2MiB")?, Some(2 * 1024 * 1024));
+        assert_eq!(parse_rate("500k")?, Some(500 * 1024));
+        assert_eq!(parse_rate("1.5M/s")?, Some(1536 * 1024));
+        assert_eq!(parse_rate("100")?, Some(100 * 1024));
+        assert_eq!(parse_rate("300 B")?, Some(300));
+        assert_eq!(parse_rate("1GB")?, Some(1_000_000_000));
+        assert_eq!(parse_rate("1MB")?, Some(1_000_000));
+        assert_eq!(parse_rate("2kB/s")?, Some(2000));
+        assert_eq!(parse_rate("1GiB")?, Some(1024 * 1024 * 1024));
+        assert_eq!(parse_rate("0")?, None);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("2TiB").is_err());
+        assert!(parse_rate("").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use super::args::MagnetArgs;
+use super::{Failure, Globals};
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use torrent_rs::json::Json;
+use torrent_rs::metainfo::Metainfo;
+use torrent_rs::session::AddOptions;
+use torrent_rs::Session;
+
+/// `--from file.torrent` prints the magnet link of a .torrent. A magnet
+/// link has its metadata fetched from the peers the DHT and its trackers
+/// know, then written to `--save-metadata FILE`, name.torrent by default,
+/// without downloading any of its files. Gives up after `--seconds`, 300 by
+/// default, or Ctrl-C. With `--json` prints `{"magnet":..}` or
+/// `{"torrent":..,"info_hash":..,"name":..}`.
+pub fn run(args: MagnetArgs, globals: &Globals) -> Result<()> {
+    let MagnetArgs {
+        uri,
+        save_metadata: output,
+        seconds,
+        from,
+    } = args;
+    if let Some(path) = from {
+        println!("{}", magnet_link(&path, globals.json)?);
+        return Ok(());
+    }
+    let uri = uri.context("a magnet link or --from is needed")?;
+
+    let mut session = Session::new(globals.config()?)?;
+    let options = AddOptions {
+        paused: true,
+        ..Default::default()
+    };
+    let info_hash = session
+        .add_magnet_with(&uri, options)
+        .context(Failure::Torrent)?;
+    if !globals.json {
+        eprintln!("fetching the metadata of {}", uri);
+    }
+    let interrupted = super::on_ctrl_c();
+    let end = Instant::now() + Duration::from_secs(seconds);
+    let mut last_tick = Instant::now();
+    let torrent = loop {
+        if let Some(torrent) = session.poll_magnets()?.pop() {
+            break Some(torrent);
+        }
+        if Instant::now() >= end || super::interrupted(interrupted) {
+            break None;
+        }
+        if last_tick.elapsed() >= Duration::from_secs(1) {
+            last_tick = Instant::now();
+            session.tick()?;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    let metainfo = torrent.as_ref().map(|torrent| torrent.metainfo());
+    // only the metadata was wanted, the torrent isn't kept
+    if let Some(torrent) = torrent {
+        session.remove(&torrent, false)?;
+    }
+    session.shutdown(super::SHUTDOWN_TIMEOUT)?;
+    let metainfo = match metainfo {
+        Some(metainfo) => metainfo,
+        None => bail!(
+            "no peer sent the metadata of {} in time",
+            torrent_rs::metainfo::to_hex(&info_hash)
+        ),
+    };
+
+    let output = output.unwrap_or_else(|| default_output(&metainfo));
+    std::fs::write(&output, metainfo.to_bytes()?)
+        .with_context(|| format!("writing {}", output.display()))
+        .context(Failure::Disk)?;
+    if globals.json {
+        let json = Json::object()
+            .with("torrent", output.to_string_lossy().into_owned())
+            .with("info_hash", metainfo.info_hash_hex())
+            .with("name", metainfo.info.name.as_str());
+        println!("{}", json);
+    } else {
+        eprintln!(
+            "wrote the metadata of {} to {}",
+            metainfo.info.name,
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+/// the line `--from` prints
+fn magnet_link(torrent: &str, json: bool) -> Result<String> {
+    let link = super::read_torrent(torrent)?.magnet_link();
+    Ok(match json {
+        true => Json::object().with("magnet", link).to_string(),
+        false => link,
+    })
+}
+
+fn default_output(metainfo: &Metainfo) -> PathBuf {
+    format!("{}.torrent", metainfo.info.name).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::args::{Cli, Command};
+    use clap::Parser;
+    use std::path::Path;
+
+    #[test]
+    fn prints_the_magnet_link_of_a_torrent() -> Result<()> {
+        let link = magnet_link("file1.txt.torrent", false)?;
+        assert!(link.starts_with("magnet:?xt=urn:btih:"));
+        assert!(link.contains("&dn=file1.txt"));
+        assert_eq!(
+            magnet_link("file1.txt.torrent", true)?,
+            Json::object().with("magnet", link).to_string()
+        );
+        assert!(magnet_link("missing.torrent", false).is_err());
+
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        assert_eq!(default_output(&metainfo), Path::new("file1.txt.torrent"));
+
+        let cli = Cli::try_parse_from(["torrent_rs", "magnet", "--from", "file1.txt.torrent"])?;
+        let Command::Magnet(args) = cli.command else {
+            panic!("not a magnet");
+        };
+        assert_eq!(args.from.as_deref(), Some("file1.txt.torrent"));
+        assert_eq!((args.uri, args.seconds), (None, 300));
+        Ok(())
+    }
+}
@@ -0,0 +1,531 @@
+//! The command line of the binary, parsed by clap from the commands and
+//! flags of `args`. The global flags can be given anywhere.
+//!
+//! With `--json` commands print JSON documents on stdout instead of text,
+//! errors included, and every command exits with one of the `EXIT_` codes.
+
+mod args;
+mod bencode;
+#[cfg(unix)]
+mod client;
+mod completions;
+mod create;
+#[cfg(unix)]
+mod daemon;
+mod dht;
+mod download;
+mod edit;
+mod limits;
+mod magnet;
+mod peers;
+mod progress;
+mod select;
+mod show;
+mod tui;
+mod verify;
+
+use anyhow::{anyhow, bail, Context, Result};
+use args::{Cli, Command, GlobalArgs, LogFormat};
+use clap::error::ErrorKind;
+use clap::Parser;
+use std::fmt::{self, Display};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Once;
+use std::time::Duration;
+use torrent_rs::dht::DhtConfig;
+use torrent_rs::json::Json;
+use torrent_rs::listen::ListenPort;
+use torrent_rs::{Config, Metainfo};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// anything else that went wrong, like invalid arguments
+pub const EXIT_ERROR: i32 = 1;
+/// pieces are missing
+pub const EXIT_INCOMPLETE: i32 = 2;
+/// a piece doesn't match its hash
+pub const EXIT_CORRUPT: i32 = 3;
+/// see `Failure`
+pub const EXIT_TORRENT: i32 = 4;
+pub const EXIT_TRACKER: i32 = 5;
+pub const EXIT_DISK: i32 = 6;
+
+/// days of logs kept by `--log-file`
+const LOG_FILES: usize = 7;
+
+/// written by build.rs
+const MAN_PAGE: &str = include_str!(concat!(env!("OUT_DIR"), "/torrent_rs.1"));
+
+/// to tell the trackers we stopped and save the state before exiting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// runs the command and returns the code to exit with, errors are printed
+/// on stderr, or on stdout as JSON with `--json`
+pub fn run(args: impl IntoIterator<Item = String>) -> i32 {
+    let args: Vec<String> = args.into_iter().collect();
+    // known before the arguments are, to print their errors as JSON too
+    let json = args
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--json");
+    let cli = match Cli::try_parse_from(std::iter::once("torrent_rs".to_string()).chain(args)) {
+        Ok(cli) => cli,
+        Err(error) => return usage_error(error, json),
+    };
+    match command(cli) {
+        Ok(()) => 0,
+        Err(error) => {
+            let code = exit_code(&error);
+            if json {
+                println!("{}", error_json(&error));
+            } else {
+                eprintln!("Error: {:?}", error);
+            }
+            code
+        }
+    }
+}
+
+/// the help and the version exit with 0, invalid arguments with
+/// `EXIT_ERROR`
+fn usage_error(error: clap::Error, json: bool) -> i32 {
+    let code = match error.kind() {
+        ErrorKind::DisplayHelp
+        | ErrorKind::DisplayVersion
+        | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand => 0,
+        _ => EXIT_ERROR,
+    };
+    if code == 0 || !json {
+        let _ = error.print();
+        return code;
+    }
+    // without the usage and the hint to try --help after it
+    let text = error.render().to_string();
+    let lines: Vec<_> = text
+        .lines()
+        .take_while(|line| !line.starts_with("Usage:") && !line.starts_with("For more"))
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let message = lines.join(" ");
+    let message = message.strip_prefix("error: ").unwrap_or(&message);
+    println!("{}", error_json(&anyhow!("{}", message)));
+    code
+}
+
+fn command(cli: Cli) -> Result<()> {
+    let globals = Globals::parse(cli.globals)?;
+    let _logs = globals.start_logging()?;
+    match cli.command {
+        Command::Download(args) => download::run(args, &globals),
+        Command::Create(args) => create::run(args, &globals),
+        Command::Show(args) => show::run(args, &globals),
+        Command::Edit(args) => edit::run(args, &globals),
+        Command::Magnet(args) => magnet::run(args, &globals),
+        Command::Verify(args) => verify::run(args, &globals),
+        Command::Bencode(command) => bencode::run(command, &globals),
+        Command::Peers(args) => peers::run(args, &globals),
+        Command::Dht(args) => dht::run(args, &globals),
+        Command::Tui(_) if globals.json => bail!("tui has no JSON output"),
+        Command::Tui(args) => tui::run(args, &globals),
+        #[cfg(unix)]
+        Command::Daemon(args) => daemon::run(args, &globals),
+        #[cfg(unix)]
+        command @ (Command::Add(_)
+        | Command::List(_)
+        | Command::Info(_)
+        | Command::Pause(_)
+        | Command::Resume(_)
+        | Command::Remove(_)) => client::run(command, &globals),
+        #[cfg(not(unix))]
+        Command::Daemon(_)
+        | Command::Add(_)
+        | Command::List(_)
+        | Command::Info(_)
+        | Command::Pause(_)
+        | Command::Resume(_)
+        | Command::Remove(_) => bail!("the daemon only runs on unix"),
+        Command::Completions { shell } => completions::run(&shell),
+        Command::Manpage => {
+            print!("{}", MAN_PAGE);
+            Ok(())
+        }
+    }
+}
+
+/// Why a command failed when it is more than a mistake in its arguments,
+/// attached to its error as context to pick the exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// a .torrent or magnet link that can't be read or parsed, or isn't
+    /// known
+    Torrent,
+    /// no tracker answered
+    Tracker,
+    /// reading or writing the data failed
+    Disk,
+}
+
+impl Failure {
+    /// a disk failure if reading or writing a file is what failed, other
+    /// errors, like invalid arguments, are left as they are
+    pub fn disk(error: anyhow::Error) -> anyhow::Error {
+        match error.chain().any(|cause| cause.is::<std::io::Error>()) {
+            true => error.context(Failure::Disk),
+            false => error,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Torrent => "torrent",
+            Failure::Tracker => "tracker",
+            Failure::Disk => "disk",
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Torrent => "invalid torrent",
+            Failure::Tracker => "tracker failure",
+            Failure::Disk => "disk error",
+        })
+    }
+}
+
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    match error.downcast_ref::<Failure>() {
+        Some(Failure::Torrent) => EXIT_TORRENT,
+        Some(Failure::Tracker) => EXIT_TRACKER,
+        Some(Failure::Disk) => EXIT_DISK,
+        None => EXIT_ERROR,
+    }
+}
+
+/// `{"error":{"kind":..,"message":..,"exit_code":..}}`, the kind being one
+/// of the `Failure` names or error
+fn error_json(error: &anyhow::Error) -> Json {
+    let kind = error
+        .downcast_ref::<Failure>()
+        .map_or("error", |failure| failure.name());
+    Json::object().with(
+        "error",
+        Json::object()
+            .with("kind", kind)
+            .with("message", format!("{:#}", error))
+            .with("exit_code", exit_code(error) as i64),
+    )
+}
+
+/// a .torrent file, a `Failure::Torrent` if it can't be read
+fn read_torrent(path: &str) -> Result<Metainfo> {
+    std::fs::read(path)
+        .with_context(|| format!("reading {}", path))
+        .and_then(Metainfo::from_bytes)
+        .context(Failure::Torrent)
+}
+
+/// where the DHT nodes are kept between runs, in the data directory of the
+/// user, none when there isn't one. dht6.state goes next to it.
+fn dht_state_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("torrent_rs").join("dht.state"))
+}
+
+/// Flags of every command, most of them for the commands running a session
+#[derive(Debug, Default)]
+pub struct Globals {
+    /// print JSON instead of text
+    pub json: bool,
+    /// only print errors, logs included
+    pub quiet: bool,
+    /// each raises the log level from warn, to info, debug and trace
+    verbose: usize,
+    log_file: Option<PathBuf>,
+    log_format: LogFormat,
+    config: Option<PathBuf>,
+    port: Option<ListenPort>,
+    /// when given, in bytes per second with None unlimited
+    upload_rate: Option<Option<u64>>,
+    download_rate: Option<Option<u64>>,
+}
+
+impl Globals {
+    fn parse(args: GlobalArgs) -> Result<Self> {
+        let port = match args.port {
+            Some(port) => {
+                Some(ListenPort::parse(&port).with_context(|| format!("invalid --port {}", port))?)
+            }
+            None => None,
+        };
+        let rate = |rate: Option<String>, name: &str| match rate {
+            Some(rate) => limits::parse_rate(&rate)
+                .with_context(|| format!("invalid {} {}", name, rate))
+                .map(Some),
+            None => Ok(None),
+        };
+        Ok(Self {
+            json: args.json,
+            quiet: args.quiet,
+            verbose: args.verbose as usize,
+            log_file: args.log_file,
+            log_format: args.log_format,
+            config: args.config,
+            port,
+            upload_rate: rate(args.max_upload_rate, "--max-upload-rate")?,
+            download_rate: rate(args.max_download_rate, "--max-download-rate")?,
+        })
+    }
+
+    /// `-q` logs errors only, `-v` info, `-vv` debug and `-vvv` trace,
+    /// otherwise the directives of `TORRENT_RS_LOG`, like
+    /// `warn,torrent_rs::tracker=debug`, or warn. Logs go to stderr, or to
+    /// `--log-file` from another thread, see `log_file`, whose guard
+    /// writes what is left when dropped.
+    fn start_logging(&self) -> Result<Option<WorkerGuard>> {
+        let filter = match (self.quiet, self.verbose) {
+            (true, 1..) => bail!("-q and -v can't be used together"),
+            (true, 0) => EnvFilter::new("error"),
+            (false, 0) => match std::env::var("TORRENT_RS_LOG") {
+                Ok(directives) => EnvFilter::try_new(&directives)
+                    .with_context(|| format!("invalid TORRENT_RS_LOG {}", directives))?,
+                Err(_) => EnvFilter::new("warn"),
+            },
+            (false, 1) => EnvFilter::new("info"),
+            (false, 2) => EnvFilter::new("debug"),
+            (false, _) => EnvFilter::new("trace"),
+        };
+        let (writer, guard) = match &self.log_file {
+            Some(path) => {
+                let (writer, guard) = tracing_appender::non_blocking(log_file(path)?);
+                (BoxMakeWriter::new(writer), Some(guard))
+            }
+            None => (BoxMakeWriter::new(std::io::stderr), None),
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_ansi(guard.is_none())
+            .with_writer(writer);
+        match self.log_format {
+            LogFormat::Text => subscriber.try_init(),
+            LogFormat::Json => subscriber.json().with_current_span(true).try_init(),
+        }
+        .map_err(|error| anyhow!(error))?;
+        Ok(guard)
+    }
+
+    /// whether logs go to a file rather than stderr
+    pub fn logs_to_file(&self) -> bool {
+        self.log_file.is_some()
+    }
+
+    /// the port the flags ask for, if any
+    pub fn port(&self) -> Option<ListenPort> {
+        self.port
+    }
+
+    /// loaded from `--config` or the defaults, keeping the DHT nodes in
+    /// `dht_state_path`, with the other flags applied over it
+    pub fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::builder()
+                .dht(Some(DhtConfig {
+                    state_path: dht_state_path(),
+                    ..Default::default()
+                }))
+                .build()?,
+        };
+        if let Some(port) = self.port {
+            config.listen_port = port;
+        }
+        if let Some(rate) = self.upload_rate {
+            config.rate_limits.upload = rate;
+        }
+        if let Some(rate) = self.download_rate {
+            config.rate_limits.download = rate;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// a new file every day named like `path` with the date before its
+/// extension, like torrent_rs.2021-03-04.log, keeping the last `LOG_FILES`
+fn log_file(path: &Path) -> Result<RollingFileAppender> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let name = Path::new(path.file_name().context("the log file needs a name")?);
+    let mut builder = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .max_log_files(LOG_FILES)
+        .filename_prefix(name.file_stem().unwrap_or_default().to_string_lossy());
+    if let Some(extension) = name.extension() {
+        builder = builder.filename_suffix(extension.to_string_lossy());
+    }
+    builder
+        .build(dir)
+        .with_context(|| format!("opening the log file {}", path.display()))
+}
+
+/// lines typed while a command runs, to control it
+fn stdin_commands() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) if sender.send(line.trim().to_string()).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+    receiver
+}
+
+/// set once Ctrl-C is pressed or SIGTERM received, so commands can shut
+/// their session down instead of being killed
+fn on_ctrl_c() -> &'static AtomicBool {
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        if let Err(error) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed)) {
+            tracing::warn!(%error, "Ctrl-C kills the process without saving the session");
+        }
+    });
+    &INTERRUPTED
+}
+
+fn interrupted(flag: &AtomicBool) -> bool {
+    flag.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use torrent_rs::create::TorrentCreator;
+
+    fn parse(line: &str) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("torrent_rs").chain(line.split(' ')))
+    }
+
+    #[test]
+    fn takes_global_flags_anywhere() -> Result<()> {
+        let cli = parse("download -o out --port=7000 a.torrent -v -- -b")?;
+        assert_eq!(cli.globals.verbose, 1);
+        assert!(!cli.globals.quiet);
+        assert_eq!(cli.globals.port.as_deref(), Some("7000"));
+        let Command::Download(args) = cli.command else {
+            panic!("not a download");
+        };
+        assert_eq!(args.output, Some("out".into()));
+        assert_eq!(args.sources, ["a.torrent", "-b"]);
+
+        let Command::Create(args) = parse("create --announce a x -a=b")?.command else {
+            panic!("not a create");
+        };
+        assert_eq!(args.announce, ["a", "b"]);
+
+        assert!(parse("dht --seconds").is_err());
+        assert!(parse("dht --seconds=soon").is_err());
+        assert!(parse("show a --other").is_err());
+        assert!(parse("show a b").is_err());
+        assert!(parse("create --v2 --hybrid x").is_err());
+        assert!(parse("magnet").is_err());
+        assert!(parse("magnet --from a.torrent b").is_err());
+        assert!(parse("inspect a.torrent").is_ok());
+        assert_eq!(parse("-vv dht")?.globals.verbose, 2);
+        assert_eq!(parse("dht --verbose -vv")?.globals.verbose, 3);
+        assert_eq!(usage_error(parse("--help").unwrap_err(), false), 0);
+        assert_eq!(usage_error(parse("dht -x").unwrap_err(), true), EXIT_ERROR);
+        Ok(())
+    }
+
+    #[test]
+    fn applies_global_flags_to_the_config() -> Result<()> {
+        let cli = parse("--port 6881-6889 download --upload-limit 0 --download-limit 10 x")?;
+        let config = Globals::parse(cli.globals)?.config()?;
+        assert_eq!(config.listen_port, ListenPort::Range(6881, 6889));
+        assert_eq!(config.rate_limits.upload, None);
+        assert_eq!(config.rate_limits.download, Some(10 * 1024));
+        let cli = parse("--max-download-rate 1MB download --max-upload-rate 2MiB x")?;
+        let config = Globals::parse(cli.globals)?.config()?;
+        assert_eq!(config.rate_limits.download, Some(1_000_000));
+        assert_eq!(config.rate_limits.upload, Some(2 * 1024 * 1024));
+        assert!(Globals::parse(parse("--max-upload-rate fast list")?.globals).is_err());
+        assert!(Globals::parse(parse("--port 99999 list")?.globals).is_err());
+
+        let globals = Globals::parse(parse("-q --log-format json -v list")?.globals)?;
+        assert!(globals.quiet);
+        assert_eq!(globals.log_format, LogFormat::Json);
+        assert!(globals.start_logging().is_err());
+        assert!(parse("--log-format xml list").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn passes_the_globals_to_bencode() -> Result<()> {
+        let cli = parse("--json bencode encode missing.json")?;
+        let Command::Bencode(command) = cli.command else {
+            panic!("not a bencode");
+        };
+        let error = bencode::run(command, &Globals::parse(cli.globals)?).unwrap_err();
+        assert_eq!(error.to_string(), "encode writes bencode, not JSON");
+        Ok(())
+    }
+
+    #[test]
+    fn names_the_log_files_by_day() -> Result<()> {
+        use std::io::Write;
+        let dir = std::env::temp_dir().join(format!("log-file-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let mut file = log_file(&dir.join("torrent_rs.log"))?;
+        file.write_all(b"line\n")?;
+        file.flush()?;
+        let names: Vec<_> = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_>>()?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with("torrent_rs.20") && names[0].ends_with(".log"));
+        assert!(log_file(Path::new("/")).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn writes_the_man_page() {
+        assert!(MAN_PAGE.contains(".TH torrent_rs 1"));
+        assert_eq!(MAN_PAGE.matches(".ds Aq '").count(), 1);
+        assert!(MAN_PAGE.contains("torrent_rs\\-download(1)"));
+        assert!(MAN_PAGE.contains(".SH EXIT STATUS"));
+        assert!(MAN_PAGE.contains(".B TORRENT_RS_API_TOKEN"));
+    }
+
+    #[test]
+    fn picks_exit_codes() {
+        let error = anyhow!("No such file").context(Failure::Disk);
+        assert_eq!(exit_code(&error), EXIT_DISK);
+        assert_eq!(
+            error_json(&error.context("writing x")).to_string(),
+            r#"{"error":{"kind":"disk","message":"writing x: disk error: No such file","exit_code":6}}"#
+        );
+        assert_eq!(exit_code(&anyhow!("unknown flag")), EXIT_ERROR);
+        let error = TorrentCreator::new("file1.txt")
+            .piece_length(1000)
+            .create(|_, _| ());
+        assert_eq!(exit_code(&Failure::disk(error.unwrap_err())), EXIT_ERROR);
+        let error = TorrentCreator::new("missing.txt").create(|_, _| ());
+        assert_eq!(exit_code(&Failure::disk(error.unwrap_err())), EXIT_DISK);
+        let error = read_torrent("missing.torrent").unwrap_err();
+        assert_eq!(exit_code(&error), EXIT_TORRENT);
+        assert_eq!(exit_code(&error.context("adding")), EXIT_TORRENT);
+    }
+}
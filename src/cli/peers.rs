@@ -0,0 +1,276 @@
+use super::args::PeersArgs;
+use super::Globals;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use torrent_rs::json::Json;
+use torrent_rs::Session;
+
+/// a .torrent file is looked up by a session of its own, anything else is
+/// the info hash or name of a torrent of the daemon, see `watch`
+pub fn run(args: PeersArgs, globals: &Globals) -> Result<()> {
+    let PeersArgs {
+        torrent,
+        seconds,
+        watch,
+        socket,
+    } = args;
+    let socket = socket.socket;
+    if Path::new(&torrent).is_file() || torrent.ends_with(".torrent") {
+        if watch || socket.is_some() {
+            bail!("--watch and --socket are for the torrents of the daemon");
+        }
+        return look_up(&torrent, seconds.unwrap_or(60), globals);
+    }
+    if seconds.is_some() {
+        bail!("--seconds is for .torrent files, the daemon is already looking");
+    }
+    #[cfg(unix)]
+    return daemon::watch(&torrent, socket, watch, globals);
+    #[cfg(not(unix))]
+    bail!(
+        "{} isn't a .torrent file and there is no daemon here",
+        torrent
+    )
+}
+
+/// looks for peers of the torrent with its trackers, the DHT and the local
+/// network, then lists them with where they were found. Typing pause or
+/// resume pauses or resumes the torrent, quit stops early. With `--json`
+/// prints `{"peers":[{"addr":..,"source":..,"connected":..}],
+/// "port_mappings":[..],"alerts":[..]}`.
+fn look_up(torrent: &str, seconds: u64, globals: &Globals) -> Result<()> {
+    let metainfo = super::read_torrent(torrent)?;
+    let mut session = Session::new(globals.config()?)?;
+    if let Some(addr) = session.metrics_addr().filter(|_| !globals.json) {
+        eprintln!("serving metrics at http://{}/metrics", addr);
+    }
+    let torrent = session.add_torrent(metainfo)?;
+    let interrupted = super::on_ctrl_c();
+    let commands = super::stdin_commands();
+    let end = Instant::now() + Duration::from_secs(seconds);
+    let mut last_tick = Instant::now();
+    while Instant::now() < end && !super::interrupted(interrupted) {
+        if last_tick.elapsed() >= Duration::from_secs(1) {
+            last_tick = Instant::now();
+            session.tick()?;
+        }
+        match commands.try_recv().as_deref() {
+            Ok("pause") => session.pause_all()?,
+            Ok("resume") => session.resume_all(),
+            Ok("quit") => break,
+            Ok(command) => eprintln!("unknown command {}, try pause, resume or quit", command),
+            Err(_) => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+
+    let candidates = torrent.peer_candidates();
+    if globals.json {
+        let peers = candidates
+            .iter()
+            .map(|(addr, source)| (addr, source, false))
+            .chain(
+                torrent
+                    .peer_list()
+                    .iter()
+                    .map(|peer| (&peer.addr, &peer.source, true)),
+            )
+            .map(|(addr, source, connected)| {
+                Json::object()
+                    .with("addr", addr.to_string())
+                    .with("source", source.to_string())
+                    .with("connected", connected)
+            })
+            .collect::<Vec<_>>();
+        let json = Json::object()
+            .with("peers", Json::Array(peers))
+            .with(
+                "port_mappings",
+                session
+                    .port_mappings()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+            )
+            .with(
+                "alerts",
+                session
+                    .take_alerts()
+                    .into_iter()
+                    .chain(torrent.take_alerts())
+                    .map(|alert| alert.to_string())
+                    .collect::<Vec<_>>(),
+            );
+        println!("{}", json);
+        return session.shutdown(super::SHUTDOWN_TIMEOUT);
+    }
+    let mut sources = BTreeMap::new();
+    for (addr, source) in &candidates {
+        println!("{} {}", addr, source);
+        *sources.entry(*source).or_insert(0) += 1;
+    }
+    for peer in torrent.peer_list() {
+        println!("{} {} connected", peer.addr, peer.source);
+    }
+    let sources: Vec<_> = sources
+        .iter()
+        .map(|(source, count)| format!("{} from {}", count, source))
+        .collect();
+    if sources.is_empty() {
+        println!("no peers found");
+    } else {
+        println!("{} peers found: {}", candidates.len(), sources.join(", "));
+    }
+    for mapping in session.port_mappings() {
+        println!("{}", mapping);
+    }
+    for alert in session
+        .take_alerts()
+        .into_iter()
+        .chain(torrent.take_alerts())
+    {
+        eprintln!("{}", alert);
+    }
+    session.shutdown(super::SHUTDOWN_TIMEOUT)
+}
+
+#[cfg(unix)]
+mod daemon {
+    use super::super::progress::format_bytes;
+    use super::super::{Failure, Globals};
+    use anyhow::{Context, Result};
+    use std::io::{self, IsTerminal, Write};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use torrent_rs::control::{self, Refused, Request};
+    use torrent_rs::json::Json;
+
+    /// the fields of a peer line of the daemon, see `control::Request::Peers`
+    const FIELDS: [&str; 7] = [
+        "addr",
+        "source",
+        "client",
+        "flags",
+        "download_rate",
+        "upload_rate",
+        "percent",
+    ];
+
+    /// lists the connected peers of a torrent of the daemon with their
+    /// client, flags, rates and how much they have, see
+    /// `PeerInfo::flags`. `--watch` lists them again every second until
+    /// Ctrl-C, redrawn in place on a terminal. With `--json` the peers are
+    /// an array of objects, a line of it per refresh with `--watch`.
+    pub fn watch(
+        torrent: &str,
+        socket: Option<PathBuf>,
+        watch: bool,
+        globals: &Globals,
+    ) -> Result<()> {
+        let socket = socket.unwrap_or_else(control::default_socket_path);
+        let request = Request::Peers(torrent.to_string());
+        let redraw = watch && !globals.json && io::stdout().is_terminal();
+        let interrupted = super::super::on_ctrl_c();
+        loop {
+            let lines = match control::request(&socket, &request) {
+                Err(error) if error.is::<Refused>() => return Err(error).context(Failure::Torrent),
+                result => result?,
+            };
+            let mut out = String::new();
+            if redraw {
+                // to the top left of a cleared screen
+                out.push_str("\x1b[H\x1b[2J");
+            }
+            if globals.json {
+                out.push_str(
+                    &Json::Array(lines.iter().map(|line| to_json(line)).collect()).to_string(),
+                );
+                out.push('\n');
+            } else {
+                out.push_str(&table(&lines));
+            }
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(out.as_bytes())?;
+            stdout.flush()?;
+            if !watch {
+                return Ok(());
+            }
+            let next = Instant::now() + Duration::from_secs(1);
+            while Instant::now() < next {
+                if super::super::interrupted(interrupted) {
+                    return Ok(());
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    fn to_json(line: &str) -> Json {
+        let mut json = Json::object();
+        for (key, value) in FIELDS.iter().zip(line.split('\t')) {
+            json = match *key {
+                "client" if value.is_empty() => json.with(key, Json::Null),
+                "download_rate" | "upload_rate" | "percent" => {
+                    json.with(key, value.parse::<f64>().unwrap_or(0.0))
+                }
+                "flags" => json
+                    .with("choked", value.contains('C'))
+                    .with("interested", value.contains('I'))
+                    .with("choking", value.contains('c'))
+                    .with("peer_interested", value.contains('i'))
+                    .with("encrypted", value.contains('E')),
+                _ => json.with(key, value),
+            };
+        }
+        json
+    }
+
+    fn table(lines: &[String]) -> String {
+        if lines.is_empty() {
+            return "no peers connected\n".into();
+        }
+        let mut out = format!(
+            "{:<22} {:<20} {:<5} {:>12} {:>12} {:>6}  source\n",
+            "address", "client", "flags", "down", "up", "has"
+        );
+        for line in lines {
+            let fields: Vec<_> = line.split('\t').collect();
+            if let [addr, source, client, flags, down, up, percent] = fields[..] {
+                let rate =
+                    |field: &str| format!("{}/s", format_bytes(field.parse().unwrap_or(0.0)));
+                let client = if client.is_empty() { "?" } else { client };
+                out.push_str(&format!(
+                    "{:<22} {:<20} {:<5} {:>12} {:>12} {:>5}%  {}\n",
+                    addr,
+                    client,
+                    flags,
+                    rate(down),
+                    rate(up),
+                    percent,
+                    source
+                ));
+            }
+        }
+        out.push_str("flags: C chokes us, I we are interested, c we choke it, i it is interested, E encrypted\n");
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn shows_peers_of_the_daemon() {
+            let line = "10.0.0.1:6881\ttracker\tqBittorrent 4.6.5.0\tCIE\t2048\t0\t50.0";
+            assert_eq!(
+                to_json(line).to_string(),
+                r#"{"addr":"10.0.0.1:6881","source":"tracker","client":"qBittorrent 4.6.5.0","choked":true,"interested":true,"choking":false,"peer_interested":false,"encrypted":true,"download_rate":2048,"upload_rate":0,"percent":50}"#
+            );
+            let table = table(&[line.to_string()]);
+            assert!(table.contains("10.0.0.1:6881          qBittorrent 4.6.5.0  CIE"));
+            assert!(table.contains("50.0%  tracker"));
+            assert_eq!(super::table(&[]), "no peers connected\n");
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Progress bars of the torrents drawn over each other on stderr by
+//! indicatif, redrawn in place while it is a terminal.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::time::Duration;
+use torrent_rs::TorrentHandle;
+
+const BAR_WIDTH: usize = 20;
+const NAME_WIDTH: usize = 24;
+
+/// What one line shows
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub name: String,
+    pub state: String,
+    /// between 0 and 100
+    pub percent: f64,
+    /// bytes per second
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub eta: Option<Duration>,
+    pub peers: usize,
+    pub ratio: f64,
+}
+
+impl Line {
+    pub fn of(torrent: &TorrentHandle) -> Self {
+        let progress = torrent.progress_report();
+        Self {
+            name: torrent.root_name(),
+            state: torrent.state().to_string(),
+            percent: progress.percent,
+            download_rate: progress.download_rate,
+            upload_rate: progress.upload_rate,
+            eta: progress.eta,
+            peers: torrent.stats().peers,
+            ratio: torrent.seeding().ratio,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        format!(
+            "{:<width$} {} {}",
+            truncate(&self.name, NAME_WIDTH),
+            bar(self.percent),
+            self.status(),
+            width = NAME_WIDTH
+        )
+    }
+
+    /// what follows the bar
+    fn status(&self) -> String {
+        let eta = match self.eta {
+            Some(eta) => format_duration(eta),
+            None => "-".to_string(),
+        };
+        format!(
+            "{:>5.1}% {} down {}/s up {}/s eta {} peers {} ratio {:.2}",
+            self.percent,
+            self.state,
+            format_bytes(self.download_rate),
+            format_bytes(self.upload_rate),
+            eta,
+            self.peers,
+            self.ratio,
+        )
+    }
+}
+
+/// A bar per torrent, hidden when stderr isn't a terminal where redrawing
+/// would only fill logs with lines
+#[derive(Debug, Default)]
+pub struct Bars {
+    multi: MultiProgress,
+    bars: HashMap<[u8; 20], ProgressBar>,
+}
+
+impl Bars {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds the bars of new torrents and updates them all from their stats
+    pub fn draw(&mut self, torrents: &[TorrentHandle]) {
+        for torrent in torrents {
+            let line = Line::of(torrent);
+            let multi = &self.multi;
+            let bar = self
+                .bars
+                .entry(torrent.info_hash())
+                .or_insert_with(|| multi.add(ProgressBar::new(1000).with_style(torrent_style())));
+            bar.set_prefix(truncate(&line.name, NAME_WIDTH));
+            bar.set_position((line.percent * 10.0) as u64);
+            bar.set_message(line.status());
+        }
+    }
+
+    /// above the bars, which are drawn again below it
+    pub fn println(&self, message: &str) {
+        match self.multi.is_hidden() {
+            true => eprintln!("{}", message),
+            false => {
+                let _ = self.multi.println(message);
+            }
+        }
+    }
+
+    /// leaves the bars as they were last drawn
+    pub fn finish(&self) {
+        self.bars.values().for_each(ProgressBar::abandon);
+    }
+}
+
+fn torrent_style() -> ProgressStyle {
+    let template = format!("{{prefix:{}}} [{{bar:{}}}] {{msg}}", NAME_WIDTH, BAR_WIDTH);
+    ProgressStyle::with_template(&template)
+        .unwrap()
+        .progress_chars("##-")
+}
+
+/// of the bytes hashed while creating a torrent
+pub fn hashing_bar(total: u64) -> ProgressBar {
+    let template = format!(
+        "hashing [{{bar:{}}}] {{percent:>3}}% {{bytes}} of {{total_bytes}}",
+        BAR_WIDTH
+    );
+    let style = ProgressStyle::with_template(&template)
+        .unwrap()
+        .progress_chars("##-");
+    ProgressBar::new(total).with_style(style)
+}
+
+/// like [####----] for `percent` between 0 and 100
+fn bar(percent: f64) -> String {
+    let filled = ((percent / 100.0 * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+fn truncate(name: &str, width: usize) -> String {
+    if name.chars().count() <= width {
+        return name.to_string();
+    }
+    let mut name: String = name.chars().take(width - 1).collect();
+    name.push('~');
+    name
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// like 1h02m or 3m05s
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_lines() {
+        let line = Line {
+            name: "a very long name of a torrent.iso".into(),
+            state: "downloading".into(),
+            percent: 45.0,
+            download_rate: 1.5 * 1024.0 * 1024.0,
+            upload_rate: 300.0,
+            eta: Some(Duration::from_secs(192)),
+            peers: 4,
+            ratio: 0.125,
+        };
+        assert_eq!(
+            line.render(),
+            "a very long name of a t~ [#########-----------]  45.0% downloading \
+             down 1.5 MiB/s up 300 B/s eta 3m12s peers 4 ratio 0.12"
+        );
+        let stalled = Line {
+            eta: None,
+            percent: 100.0,
+            ..line
+        };
+        assert!(stalled.render().contains("[####################] 100.0%"));
+        assert!(stalled.render().contains("eta -"));
+        assert_eq!(format_duration(Duration::from_secs(3720)), "1h02m");
+        assert_eq!(format_bytes(2048.0), "2.0 KiB");
+    }
+}
@@ -0,0 +1,178 @@
+//! Which files of a torrent `download` fetches, from `--select` and
+//! `--skip` patterns or picked by their index with `--list-files`.
+
+use super::args::SelectArgs;
+use super::progress::format_bytes;
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use torrent_rs::picker::Priority;
+use torrent_rs::torrent::FileProgress;
+use torrent_rs::TorrentHandle;
+
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// every file when empty
+    select: Vec<String>,
+    skip: Vec<String>,
+    interactive: bool,
+}
+
+impl From<SelectArgs> for Selection {
+    fn from(args: SelectArgs) -> Self {
+        Selection {
+            select: args.select,
+            skip: args.skip,
+            interactive: args.list_files,
+        }
+    }
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        self.select.is_empty() && self.skip.is_empty() && !self.interactive
+    }
+
+    /// skips the files that aren't selected, or don't match `--select`, or
+    /// match `--skip`, then asks which to download with `--list-files`.
+    /// Padding files are never downloaded.
+    pub fn apply(&self, torrent: &TorrentHandle) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let files = torrent.file_progress();
+        let mut selected: Vec<_> = files
+            .iter()
+            .map(|file| {
+                let path = slashed(&file.path);
+                file.priority != Priority::Skip
+                    && !file.padding
+                    && (self.select.is_empty() || self.select.iter().any(|p| glob(p, &path)))
+                    && !self.skip.iter().any(|p| glob(p, &path))
+            })
+            .collect();
+        if self.interactive {
+            selected = ask(&files, selected)?;
+        }
+        if !selected.contains(&true) {
+            bail!("no file of {} is selected", torrent.root_name());
+        }
+        let priorities: Vec<_> = files
+            .iter()
+            .zip(&selected)
+            .map(|(file, &selected)| match (selected, file.priority) {
+                (false, _) => Priority::Skip,
+                (true, Priority::Skip) => Priority::Normal,
+                (true, priority) => priority,
+            })
+            .collect();
+        torrent.set_file_priorities(&priorities)
+    }
+}
+
+/// the path inside the torrent with / between its parts, what patterns match
+fn slashed(path: &Path) -> String {
+    let parts: Vec<_> = path
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+/// * matches any characters, / included, ? one
+pub fn glob(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => (0..=text.len()).any(|skip| matches(rest, &text[skip..])),
+            Some(('?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+            Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+/// prints the files numbered from 1 and reads the numbers to download from
+/// stdin, an empty line keeps the selection
+fn ask(files: &[FileProgress], selected: Vec<bool>) -> Result<Vec<bool>> {
+    let shown: Vec<_> = (0..files.len()).filter(|&i| !files[i].padding).collect();
+    let mut stderr = std::io::stderr().lock();
+    for (number, &i) in shown.iter().enumerate() {
+        writeln!(
+            stderr,
+            "{:>4} [{}] {:>10}  {}",
+            number + 1,
+            if selected[i] { 'x' } else { ' ' },
+            format_bytes(files[i].length as f64),
+            files[i].path.display()
+        )?;
+    }
+    write!(
+        stderr,
+        "files to download, like 1,3-5, or enter to keep the marked ones: "
+    )?;
+    stderr.flush()?;
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .context("failed to read the files to download")?;
+    if line.trim().is_empty() {
+        return Ok(selected);
+    }
+    let mut picked = vec![false; files.len()];
+    for number in parse_numbers(&line, shown.len())? {
+        picked[shown[number - 1]] = true;
+    }
+    Ok(picked)
+}
+
+/// numbers and ranges like `1,3-5 7`, from 1 to `count`
+fn parse_numbers(text: &str, count: usize) -> Result<Vec<usize>> {
+    let number = |text: &str| -> Result<usize> {
+        match text.trim().parse() {
+            Ok(number @ 1..) if number <= count => Ok(number),
+            _ => bail!("{:?} isn't a file number from 1 to {}", text.trim(), count),
+        }
+    };
+    let mut numbers = vec![];
+    for part in text
+        .split([',', ' '])
+        .filter(|part| !part.trim().is_empty())
+    {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if first > last {
+                    bail!("{} is an empty range", part.trim());
+                }
+                numbers.extend(first..=last);
+            }
+            None => numbers.push(number(part)?),
+        }
+    }
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_and_parses_selections() -> Result<()> {
+        assert!(glob("*.mkv", "season 1/episode 1.mkv"));
+        assert!(glob("season 1/*", "season 1/episode 1.mkv"));
+        assert!(glob("f?le*", "file1.txt"));
+        assert!(!glob("*.mkv", "sample.mkv.part"));
+        assert!(!glob("?", ""));
+        assert_eq!(slashed(Path::new("a/b/c.txt")), "a/b/c.txt");
+        assert_eq!(parse_numbers("1,3-5 7\n", 7)?, [1, 3, 4, 5, 7]);
+        assert!(parse_numbers("0", 3).is_err());
+        assert!(parse_numbers("2-4", 3).is_err());
+        assert!(parse_numbers("3-1", 3).is_err());
+        assert!(parse_numbers("x", 3).is_err());
+        Ok(())
+    }
+}
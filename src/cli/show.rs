@@ -0,0 +1,115 @@
+use super::args::ShowArgs;
+use super::progress::format_bytes;
+use super::Globals;
+use anyhow::Result;
+use std::convert::TryFrom;
+use torrent_rs::json::Json;
+use torrent_rs::metainfo::to_hex;
+use torrent_rs::Metainfo;
+
+/// prints what a .torrent holds, `--json` as one JSON document and
+/// `--files-only` as the paths of its files, one per line
+pub fn run(args: ShowArgs, globals: &Globals) -> Result<()> {
+    let files_only = args.files_only;
+    let metainfo = super::read_torrent(&args.torrent)?;
+    if files_only && globals.json {
+        let paths: Vec<_> = metainfo
+            .paths()
+            .into_iter()
+            .map(|(path, _)| path.to_string_lossy().into_owned())
+            .collect();
+        println!("{}", Json::from(paths));
+    } else if files_only {
+        for (path, _) in metainfo.paths() {
+            println!("{}", path.display());
+        }
+    } else if globals.json {
+        println!("{}", metainfo.to_json());
+    } else {
+        print!("{}", describe(&metainfo));
+    }
+    Ok(())
+}
+
+fn creation_date(metainfo: &Metainfo) -> Option<String> {
+    let date = i64::try_from(metainfo.creation_date?).ok()?;
+    Some(jiff::Timestamp::from_second(date).ok()?.to_string())
+}
+
+fn describe(metainfo: &Metainfo) -> String {
+    let info = &metainfo.info;
+    let mut out = format!("name          {}\n", info.name);
+    out.push_str(&format!("info hash     {}\n", metainfo.info_hash_hex()));
+    if let Some(hash) = &metainfo.info_hash_v2 {
+        out.push_str(&format!("info hash v2  {}\n", to_hex(hash)));
+    }
+    out.push_str(&format!("version       {}\n", metainfo.version()));
+    out.push_str(&format!(
+        "size          {} ({} bytes)\n",
+        format_bytes(metainfo.size() as f64),
+        metainfo.size()
+    ));
+    out.push_str(&format!(
+        "pieces        {} of {}\n",
+        info.piece_count(),
+        format_bytes(info.piece_length as f64)
+    ));
+    out.push_str(&format!(
+        "private       {}\n",
+        if info.private { "yes" } else { "no" }
+    ));
+    if let Some(comment) = &metainfo.comment {
+        out.push_str(&format!("comment       {}\n", comment));
+    }
+    if let Some(created_by) = &metainfo.created_by {
+        out.push_str(&format!("created by    {}\n", created_by));
+    }
+    if let Some(date) = creation_date(metainfo) {
+        out.push_str(&format!("created on    {}\n", date));
+    }
+    let trackers = metainfo.trackers();
+    if !trackers.is_empty() {
+        out.push_str("trackers\n");
+        for tracker in trackers {
+            out.push_str(&format!("  {}\n", tracker));
+        }
+    }
+    if !metainfo.url_list.is_empty() {
+        out.push_str("web seeds\n");
+        for url in &metainfo.url_list {
+            out.push_str(&format!("  {}\n", url));
+        }
+    }
+    out.push_str("files\n");
+    for (path, length) in metainfo.paths() {
+        out.push_str(&format!(
+            "  {:>10}  {}\n",
+            format_bytes(length as f64),
+            path.display()
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_torrents() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let text = describe(&metainfo);
+        assert!(text.starts_with("name          file1.txt\n"));
+        assert!(text.contains("version       v1\n"));
+        assert!(text.ends_with("files\n        12 B  file1.txt\n"));
+
+        let json = metainfo.to_json();
+        assert_eq!(json.get("name"), Some(&Json::from("file1.txt")));
+        assert_eq!(json.get("info_hash_v2"), Some(&Json::Null));
+        assert_eq!(json.get("piece_count"), Some(&Json::from(1usize)));
+        assert!(json
+            .to_string()
+            .contains(r#""files":[{"path":"file1.txt","length":12,"pieces_root":null}]"#));
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+use super::args::TuiArgs;
+use super::progress::{format_bytes, Line};
+use super::Globals;
+use anyhow::{Context, Result};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::widgets::{List, ListState, Paragraph, Tabs};
+use ratatui::{DefaultTerminal, Frame};
+use std::time::{Duration, Instant};
+use torrent_rs::bitfield::Bitfield;
+use torrent_rs::peer::PeerInfo;
+use torrent_rs::picker::Priority;
+use torrent_rs::resume::TrackerStats;
+use torrent_rs::torrent::FileProgress;
+use torrent_rs::{Metainfo, Session, TorrentHandle};
+
+/// how often the screen is redrawn when no key is pressed
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+const HELP: &str = "up/down select  tab pane  p pause/resume  +/- queue  [ ] file  \
+                    space file priority  r remove  d delete data  q quit";
+
+/// the torrents of the session, restored from its resume directory, and
+/// the .torrent files and magnet links given, in a full screen list with
+/// the details of the selected one
+pub fn run(args: TuiArgs, globals: &Globals) -> Result<()> {
+    let sources = args.sources;
+    let mut session = Session::new(globals.config()?)?;
+    for source in &sources {
+        if source.starts_with("magnet:") {
+            session.add_magnet(source)?;
+        } else {
+            let data = std::fs::read(source).with_context(|| format!("reading {}", source))?;
+            session.add_torrent(Metainfo::from_bytes(data)?)?;
+        }
+    }
+
+    // the terminal is put back as it was on errors and panics too
+    let mut terminal = ratatui::try_init()?;
+    let result = show(&mut terminal, &mut session);
+    ratatui::restore();
+    result?;
+    session.shutdown(super::SHUTDOWN_TIMEOUT)
+}
+
+/// draws the session and applies the keys pressed until q or Ctrl-C
+fn show(terminal: &mut DefaultTerminal, session: &mut Session) -> Result<()> {
+    let mut app = App::default();
+    let mut last_tick = Instant::now();
+    let mut last_draw = None::<Instant>;
+    loop {
+        let mut pressed = false;
+        while event::poll(Duration::ZERO)? {
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                Event::Resize(..) => {
+                    last_draw = None;
+                    continue;
+                }
+                _ => continue,
+            };
+            pressed = true;
+            let torrents: Vec<_> = session.torrents().cloned().collect();
+            let selected = torrents.get(app.selected);
+            let files = selected.map_or(0, |torrent| torrent.file_priorities().len());
+            match app.key(key, torrents.len(), files) {
+                Some(Action::Quit) => return Ok(()),
+                Some(action) => {
+                    if let Some(torrent) = selected {
+                        if let Err(error) = apply(session, torrent, action) {
+                            app.message = Some(format!("{:#}", error));
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        session.poll_magnets()?;
+        if last_tick.elapsed() >= Duration::from_secs(1) {
+            last_tick = Instant::now();
+            session.tick()?;
+        }
+        if pressed || last_draw.is_none_or(|last| last.elapsed() >= REDRAW_INTERVAL) {
+            last_draw = Some(Instant::now());
+            let torrents: Vec<_> = session.torrents().cloned().collect();
+            let lines: Vec<_> = torrents.iter().map(Line::of).collect();
+            let details = torrents.get(app.selected).map(Details::of);
+            terminal.draw(|frame| app.draw(frame, &lines, details.as_ref()))?;
+        }
+        event::poll(Duration::from_millis(50))?;
+    }
+}
+
+fn apply(session: &mut Session, torrent: &TorrentHandle, action: Action) -> Result<()> {
+    match action {
+        Action::Quit => {}
+        Action::TogglePause if torrent.is_paused() => session.resume(torrent),
+        Action::TogglePause => session.pause(torrent)?,
+        Action::QueueUp => torrent.queue_up(),
+        Action::QueueDown => torrent.queue_down(),
+        Action::CyclePriority(file) => {
+            let mut priorities = torrent.file_priorities();
+            if let Some(priority) = priorities.get_mut(file) {
+                *priority = next_priority(*priority);
+                torrent.set_file_priorities(&priorities)?;
+            }
+        }
+        Action::Remove { delete_data } => session.remove(torrent, delete_data)?,
+    }
+    Ok(())
+}
+
+fn next_priority(priority: Priority) -> Priority {
+    match priority {
+        Priority::Skip => Priority::Low,
+        Priority::Low => Priority::Normal,
+        Priority::Normal => Priority::High,
+        Priority::High => Priority::Skip,
+    }
+}
+
+fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Skip => "skip",
+        Priority::Low => "low",
+        Priority::Normal => "normal",
+        Priority::High => "high",
+    }
+}
+
+/// What a key asks of the selected torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Quit,
+    TogglePause,
+    QueueUp,
+    QueueDown,
+    CyclePriority(usize),
+    Remove { delete_data: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Pane {
+    #[default]
+    Files,
+    Peers,
+    Trackers,
+    Pieces,
+}
+
+impl Pane {
+    const ALL: [Pane; 4] = [Pane::Files, Pane::Peers, Pane::Trackers, Pane::Pieces];
+
+    fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|pane| *pane == self).unwrap();
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Pane::Files => "files",
+            Pane::Peers => "peers",
+            Pane::Trackers => "trackers",
+            Pane::Pieces => "pieces",
+        }
+    }
+}
+
+/// The selected torrent in more depth than its line
+#[derive(Debug, Clone)]
+struct Details {
+    files: Vec<FileProgress>,
+    peers: Vec<PeerInfo>,
+    trackers: Vec<TrackerStats>,
+    have: Bitfield,
+}
+
+impl Details {
+    fn of(torrent: &TorrentHandle) -> Self {
+        Self {
+            files: torrent.file_progress(),
+            peers: torrent.peer_list(),
+            trackers: torrent.trackers(),
+            have: torrent.have(),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame, area: Rect, pane: Pane, file: usize) {
+        let lines: Vec<String> = match pane {
+            Pane::Files => {
+                let files = self.files.iter().map(|progress| {
+                    let percent = match progress.length {
+                        0 => 100.0,
+                        length => progress.bytes_done as f64 * 100.0 / length as f64,
+                    };
+                    format!(
+                        "{:>5.1}% {:<6} {:>10} {}",
+                        percent,
+                        priority_name(progress.priority),
+                        format_bytes(progress.length as f64),
+                        progress.path.display()
+                    )
+                });
+                let mut state = ListState::default().with_selected(Some(file));
+                let list = List::new(files).highlight_symbol("> ");
+                frame.render_stateful_widget(list, area, &mut state);
+                return;
+            }
+            Pane::Peers if self.peers.is_empty() => vec!["no peers connected".into()],
+            Pane::Peers => self
+                .peers
+                .iter()
+                .map(|peer| {
+                    format!(
+                        "{:<40} {:<8} {:>6} pieces  down {}/s",
+                        peer.addr,
+                        peer.source.to_string(),
+                        peer.pieces,
+                        format_bytes(peer.download_rate as f64)
+                    )
+                })
+                .collect(),
+            Pane::Trackers if self.trackers.is_empty() => vec!["no trackers".into()],
+            Pane::Trackers => self
+                .trackers
+                .iter()
+                .map(|tracker| {
+                    format!(
+                        "{}  {} seeders {} leechers {} completed",
+                        tracker.url, tracker.seeders, tracker.leechers, tracker.completed
+                    )
+                })
+                .collect(),
+            Pane::Pieces => pieces_map(&self.have, area.width as usize, area.height as usize),
+        };
+        frame.render_widget(Paragraph::new(lines.join("\n")), area);
+    }
+}
+
+/// a cell per group of pieces, # when all of them are downloaded, + when
+/// some are and - when none are
+fn pieces_map(have: &Bitfield, width: usize, height: usize) -> Vec<String> {
+    let cells = have.len().min(width * height);
+    if cells == 0 {
+        return vec![];
+    }
+    let map: Vec<char> = (0..cells)
+        .map(|cell| {
+            let pieces = cell * have.len() / cells..(cell + 1) * have.len() / cells;
+            let count = pieces.len();
+            match pieces.filter(|&piece| have.get(piece)).count() {
+                0 => '-',
+                done if done == count => '#',
+                _ => '+',
+            }
+        })
+        .collect();
+    map.chunks(width).map(|row| row.iter().collect()).collect()
+}
+
+/// What is selected and shown, apart from the session
+#[derive(Debug, Default)]
+struct App {
+    selected: usize,
+    pane: Pane,
+    /// in the files pane
+    file: usize,
+    /// removing the selected torrent waits for y, deleting its data or not
+    confirm_remove: Option<bool>,
+    /// the last error, shown until the next key
+    message: Option<String>,
+}
+
+impl App {
+    fn key(&mut self, key: KeyEvent, torrents: usize, files: usize) -> Option<Action> {
+        self.message = None;
+        if let Some(delete_data) = self.confirm_remove.take() {
+            return (key.code == KeyCode::Char('y')).then_some(Action::Remove { delete_data });
+        }
+        let action = match key.code {
+            // raw mode turns Ctrl-C into a key
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                Some(Action::Quit)
+            }
+            KeyCode::Char('q') => Some(Action::Quit),
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.select(self.selected.saturating_sub(1));
+                None
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.select(self.selected + 1);
+                None
+            }
+            KeyCode::Tab => {
+                self.pane = self.pane.next();
+                None
+            }
+            KeyCode::Char('[') => {
+                self.file = self.file.saturating_sub(1);
+                None
+            }
+            KeyCode::Char(']') => {
+                self.file += 1;
+                None
+            }
+            KeyCode::Char(' ') if self.pane == Pane::Files => {
+                Some(Action::CyclePriority(self.file))
+            }
+            KeyCode::Char('p') => Some(Action::TogglePause),
+            KeyCode::Char('+') => Some(Action::QueueUp),
+            KeyCode::Char('-') => Some(Action::QueueDown),
+            KeyCode::Char('r') => {
+                self.confirm_remove = Some(false);
+                None
+            }
+            KeyCode::Char('d') => {
+                self.confirm_remove = Some(true);
+                None
+            }
+            _ => None,
+        };
+        self.selected = self.selected.min(torrents.saturating_sub(1));
+        self.file = self.file.min(files.saturating_sub(1));
+        match action {
+            Some(Action::Quit) => action,
+            _ if torrents == 0 => {
+                self.confirm_remove = None;
+                None
+            }
+            _ => action,
+        }
+    }
+
+    fn select(&mut self, selected: usize) {
+        if selected != self.selected {
+            self.selected = selected;
+            self.file = 0;
+        }
+    }
+
+    /// the header, the list of torrents over the panes of the selected
+    /// one, and the help or a question at the bottom
+    fn draw(&self, frame: &mut Frame, torrents: &[Line], details: Option<&Details>) {
+        let [header, list, tabs, pane, status] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Percentage(50),
+            Constraint::Length(1),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let (down, up) = torrents.iter().fold((0.0, 0.0), |(down, up), line| {
+            (down + line.download_rate, up + line.upload_rate)
+        });
+        let summary = format!(
+            "torrent_rs  {} torrents  down {}/s  up {}/s",
+            torrents.len(),
+            format_bytes(down),
+            format_bytes(up)
+        );
+        frame.render_widget(Paragraph::new(summary), header);
+
+        if torrents.is_empty() {
+            let hint = "  no torrents, add some with torrent_rs tui <torrent|magnet>...";
+            frame.render_widget(Paragraph::new(hint), list);
+        } else {
+            let mut state = ListState::default().with_selected(Some(self.selected));
+            let lines = List::new(torrents.iter().map(Line::render)).highlight_symbol("> ");
+            frame.render_stateful_widget(lines, list, &mut state);
+        }
+
+        let selected = Pane::ALL.iter().position(|pane| *pane == self.pane);
+        let names = Tabs::new(Pane::ALL.map(Pane::name))
+            .select(selected)
+            .padding("", "")
+            .divider(" | ");
+        frame.render_widget(names, tabs);
+        if let Some(details) = details {
+            details.draw(frame, pane, self.pane, self.file);
+        }
+
+        let question = match (self.confirm_remove, torrents.get(self.selected)) {
+            (Some(false), Some(line)) => format!("remove {}? y/n", line.name),
+            (Some(true), Some(line)) => format!("remove {} and delete its data? y/n", line.name),
+            _ => self.message.clone().unwrap_or_else(|| HELP.to_string()),
+        };
+        frame.render_widget(Paragraph::new(question), status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::path::PathBuf;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::from(code)
+    }
+
+    fn line(name: &str) -> Line {
+        Line {
+            name: name.into(),
+            state: "downloading".into(),
+            percent: 50.0,
+            download_rate: 1024.0,
+            upload_rate: 0.0,
+            eta: None,
+            peers: 0,
+            ratio: 0.0,
+        }
+    }
+
+    #[test]
+    fn selects_and_confirms() {
+        let mut app = App::default();
+        assert_eq!(app.key(key(KeyCode::Down), 2, 3), None);
+        assert_eq!(app.key(key(KeyCode::Down), 2, 3), None);
+        assert_eq!(app.selected, 1);
+        assert_eq!(app.key(key(KeyCode::Char(']')), 2, 3), None);
+        assert_eq!(
+            app.key(key(KeyCode::Char(' ')), 2, 3),
+            Some(Action::CyclePriority(1))
+        );
+        app.key(key(KeyCode::Tab), 2, 3);
+        assert_eq!(app.key(key(KeyCode::Char(' ')), 2, 3), None);
+        assert_eq!(app.key(key(KeyCode::Char('d')), 2, 3), None);
+        assert_eq!(
+            app.key(key(KeyCode::Char('y')), 2, 3),
+            Some(Action::Remove { delete_data: true })
+        );
+        app.key(key(KeyCode::Char('r')), 2, 3);
+        assert_eq!(app.key(key(KeyCode::Char('n')), 2, 3), None);
+        assert_eq!(app.key(key(KeyCode::Char('p')), 0, 0), None);
+        assert_eq!(
+            app.key(
+                KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+                0,
+                0
+            ),
+            Some(Action::Quit)
+        );
+    }
+
+    #[test]
+    fn renders_the_list_and_the_selected_torrent() {
+        let mut have = Bitfield::new(8);
+        for piece in [0, 1, 2, 3, 5] {
+            have.set(piece, true);
+        }
+        let details = Details {
+            files: vec![FileProgress {
+                path: PathBuf::from("a/b.iso"),
+                bytes_done: 512,
+                length: 1024,
+                priority: Priority::High,
+                padding: false,
+            }],
+            peers: vec![],
+            trackers: vec![],
+            have,
+        };
+        let mut app = App::default();
+        let torrents = [line("first"), line("second")];
+        app.key(key(KeyCode::Down), 2, 1);
+        let mut terminal = Terminal::new(TestBackend::new(120, 12)).unwrap();
+        let mut screen = |app: &App| -> Vec<String> {
+            let frame = terminal
+                .draw(|frame| app.draw(frame, &torrents, Some(&details)))
+                .unwrap();
+            let buffer = frame.buffer;
+            (0..buffer.area.height)
+                .map(|y| {
+                    (0..buffer.area.width)
+                        .map(|x| buffer[(x, y)].symbol())
+                        .collect()
+                })
+                .collect()
+        };
+        let lines = screen(&app);
+        assert_eq!(lines.len(), 12);
+        assert!(lines[0].starts_with("torrent_rs  2 torrents  down 2.0 KiB/s"));
+        assert!(lines[1].starts_with("  first"));
+        assert!(lines[2].starts_with("> second"));
+        assert!(lines[7].starts_with("files | peers | trackers | pieces"));
+        assert!(lines[8].starts_with(">  50.0% high      1.0 KiB a/b.iso"));
+        assert!(lines[11].starts_with("up/down select"));
+
+        app.key(key(KeyCode::Char('r')), 2, 1);
+        assert!(screen(&app)[11].starts_with("remove second? y/n"));
+
+        assert_eq!(pieces_map(&details.have, 4, 4), ["####", "-#--"]);
+        assert_eq!(pieces_map(&details.have, 3, 1), ["#++"]);
+    }
+}
@@ -0,0 +1,98 @@
+use super::args::VerifyArgs;
+use super::{Globals, EXIT_CORRUPT, EXIT_INCOMPLETE};
+use anyhow::Result;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use torrent_rs::json::Json;
+use torrent_rs::torrent::PieceCheck;
+use torrent_rs::Torrent;
+
+/// hashes the data of the torrent saved in `--data DIR`, the current
+/// directory by default, then prints which files are complete and how many
+/// pieces are complete, missing or corrupt. Exits with 0 when everything is
+/// complete, `EXIT_INCOMPLETE` when pieces are missing but none is corrupt
+/// and `EXIT_CORRUPT` otherwise.
+pub fn run(args: VerifyArgs, globals: &Globals) -> Result<()> {
+    let data: PathBuf = args.data.or(args.old_data).unwrap_or_else(|| ".".into());
+    let metainfo = super::read_torrent(&args.torrent)?;
+    let ranges = metainfo.info.file_piece_ranges();
+    let torrent = Torrent::new(metainfo, data);
+    let show_progress = !globals.json && io::stderr().is_terminal();
+    let checks = torrent.check_pieces(|checked, total| {
+        if show_progress {
+            eprint!("\rchecking {}/{}", checked, total);
+        }
+    });
+    if show_progress {
+        eprintln!();
+    }
+
+    let info = &torrent.metainfo().info;
+    let mut files = vec![];
+    for ((file, range), path) in info.files.iter().zip(ranges).zip(torrent.file_paths()) {
+        if file.padding {
+            continue;
+        }
+        let pieces = &checks[range];
+        let complete = pieces
+            .iter()
+            .filter(|check| **check == PieceCheck::Complete)
+            .count();
+        let status = if pieces.contains(&PieceCheck::Corrupt) {
+            "corrupt"
+        } else if complete < pieces.len() || (file.length == 0 && !path.exists()) {
+            "incomplete"
+        } else {
+            "complete"
+        };
+        if globals.json {
+            files.push(
+                Json::object()
+                    .with("path", path.to_string_lossy().into_owned())
+                    .with("status", status)
+                    .with("pieces", pieces.len())
+                    .with("complete_pieces", complete),
+            );
+        } else if status == "complete" {
+            println!("{}  complete", path.display());
+        } else {
+            println!(
+                "{}  {}, {}/{} pieces complete",
+                path.display(),
+                status,
+                complete,
+                pieces.len()
+            );
+        }
+    }
+    let count = |status| checks.iter().filter(|check| **check == status).count();
+    let (complete, missing, corrupt) = (
+        count(PieceCheck::Complete),
+        count(PieceCheck::Missing),
+        count(PieceCheck::Corrupt),
+    );
+    if globals.json {
+        let json = Json::object()
+            .with("pieces", checks.len())
+            .with("complete", complete)
+            .with("missing", missing)
+            .with("corrupt", corrupt)
+            .with("files", Json::Array(files));
+        println!("{}", json);
+    } else {
+        println!(
+            "{} pieces: {} complete, {} missing, {} corrupt",
+            checks.len(),
+            complete,
+            missing,
+            corrupt
+        );
+    }
+    if corrupt > 0 {
+        std::process::exit(EXIT_CORRUPT);
+    }
+    if missing > 0 {
+        std::process::exit(EXIT_INCOMPLETE);
+    }
+    Ok(())
+}
@@ -0,0 +1,1316 @@
+use crate::api::ApiConfig;
+use crate::bandwidth::RateLimits;
+use crate::category::{self, Category};
+use crate::connections::ConnectionLimits;
+use crate::dht::DhtConfig;
+use crate::hooks::HooksConfig;
+use crate::listen::{IpFamilies, ListenPort};
+use crate::queue::QueueSettings;
+use crate::regex::Regex;
+use crate::rss::Feed;
+use crate::schedule::{self, Schedule, ScheduleAction, ScheduleRule};
+use crate::seeding::{SeedLimitAction, SeedLimits};
+use crate::tracker;
+use crate::watch::WatchFolder;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::Value;
+
+/// Where the blocklist of the ip filter comes from, see `ip_filter::IpFilter`
+/// for the formats
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlocklistSource {
+    /// loaded once when the session starts
+    File(PathBuf),
+    /// over http or https, fetched in the background when the session starts
+    /// and then every `refresh`
+    Url { url: String, refresh: Duration },
+}
+
+/// Whether connections to peers use message stream encryption. It isn't
+/// implemented, so only `Disabled` passes `Config::validate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// plaintext only
+    #[default]
+    Disabled,
+    /// encrypted when the peer supports it
+    Enabled,
+    /// peers that can't encrypt are refused
+    Required,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+/// Peer and tracker connections would go through it, none is supported yet
+/// so `Config::validate` refuses one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+}
+
+/// Everything a session is started with, built in code with
+/// `Config::builder` or loaded from a TOML file with `Config::load`:
+///
+/// ```toml
+/// listen_port = 6881 # or a range like "6881-6889", or "random"
+/// listen_interface = "eth0" # or an address, every interface when left out
+/// ip_families = "both" # prefer-ipv4, prefer-ipv6, or only ipv4 or ipv6
+/// save_path = "downloads"
+/// resume_dir = "resume"
+/// lsd = true
+/// trackers = true
+/// port_mapping = true # asks the gateway to forward the listen port
+/// metrics = "127.0.0.1:9100" # serves Prometheus metrics at /metrics
+/// stream = 8090 # serves files as they download on loopback, see `stream`,
+///               # other addresses like "0.0.0.0:8090" need the token of the api
+///
+/// [api] # serves the REST api at /api and the Transmission RPC, see `api`,
+///       # and the gRPC service of `grpc` with that feature
+/// addr = "127.0.0.1:8080"
+/// token = "secret" # asked as Authorization: Bearer secret, none lets anyone in
+/// cors_origins = ["http://localhost:3000"] # web pages allowed to call it, "*" for any
+///
+/// [limits] # KiB/s, 0 is unlimited
+/// upload = 100
+/// download = 0
+/// max_peers = 50 # of each torrent, 0 is unlimited
+/// max_connections = 200 # of every torrent together
+///
+/// [queue]
+/// max_active_downloads = 3
+/// max_active_seeds = 5
+/// max_active_total = 8
+/// slow_torrent_rate = 2 # KiB/s, torrents slower than this don't count, 0 counts all
+///
+/// [dht]
+/// enabled = true
+/// state_path = "dht.state"
+/// routers = ["router.bittorrent.com:6881"]
+///
+/// [blocklist]
+/// path = "blocklist.p2p.gz" # or url = "http://...", gzipped or not
+/// refresh_hours = 24 # for urls
+///
+/// [seeding] # finished torrents stop seeding at the first limit reached
+/// ratio = 2.0 # uploaded over their size
+/// seed_time_minutes = 1440
+/// idle_time_minutes = 60 # without uploading
+/// action = "pause" # or stop, lifting their limits, remove, keeping their
+///                  # files, or remove-with-data, deleting their files too
+///
+/// [schedule.work] # rules are tried in the order of their names
+/// days = "mon-fri" # or lists like "mon,wed,sat-sun"
+/// from = "09:00"
+/// to = "17:00" # before from runs past midnight
+/// pause = true # or upload and download limits in KiB/s, 0 is unlimited
+///
+/// [category.movies]
+/// save_path = "movies" # defaults to the save_path above
+/// upload = 50 # KiB/s for each of its torrents, 0 is unlimited
+/// download = 0
+/// queue_priority = 1 # queued ahead of lower priorities, no category is 0
+///
+/// [watch.movies] # .torrent, .magnet and .uri files dropped here are added
+/// path = "watch/movies"
+/// save_path = "movies" # defaults to the save path of the category
+/// category = "movies"
+/// paused = false
+///
+/// [rss.movies] # the items of the feed whose title matches are added once
+/// url = "http://example.com/movies.xml" # RSS or Atom
+/// include = "1080p|2160p" # regexes ignoring case, both optional
+/// exclude = "\\bcam\\b"
+/// refresh_minutes = 15
+/// save_path = "movies" # defaults to the save path of the category
+/// category = "movies"
+/// paused = false
+///
+/// [hooks] # run as torrents complete, see `hooks::Completion`
+/// command = "unpack.sh" # by the shell, with TORRENT_NAME, TORRENT_PATH, ...
+/// webhook = "http://localhost:9000/done" # POSTed the completion as JSON
+/// files = true # also as each file completes
+/// ```
+///
+/// Keys that are left out keep their default.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// peers connect to us on this tcp port and the DHT runs on the same
+    /// udp port, see `listen::bind`
+    pub listen_port: ListenPort,
+    /// an address or, on linux, the name of an interface, see
+    /// `listen::interface_address`. None listens on every interface.
+    pub listen_interface: Option<String>,
+    /// which IP versions peers, trackers and the DHT are reached over
+    pub ip_families: IpFamilies,
+    /// where the torrents are downloaded
+    pub save_path: PathBuf,
+    /// where the torrents and their resume data are kept, to be added back
+    /// by the next session. None doesn't keep them.
+    pub resume_dir: Option<PathBuf>,
+    pub queue: QueueSettings,
+    /// of the whole session, see `Session::set_rate_limits`
+    pub rate_limits: RateLimits,
+    /// peers of each torrent and of the session, see
+    /// `Session::set_connection_limits`
+    pub connection_limits: ConnectionLimits,
+    /// None runs without the DHT
+    pub dht: Option<DhtConfig>,
+    /// local service discovery
+    pub lsd: bool,
+    pub trackers: bool,
+    /// with UPnP, NAT-PMP or PCP
+    pub port_mapping: bool,
+    /// where `/metrics` is served, None doesn't serve it
+    pub metrics: Option<SocketAddr>,
+    /// where the files are streamed to players, None doesn't stream them.
+    /// Only loopback addresses are served without the token of the api.
+    pub stream: Option<SocketAddr>,
+    /// None doesn't serve the REST api
+    pub api: Option<ApiConfig>,
+    pub encryption: EncryptionPolicy,
+    pub proxy: Option<Proxy>,
+    /// alternative rate limits and pauses by time of day
+    pub schedule: Schedule,
+    /// None blocks no peer
+    pub blocklist: Option<BlocklistSource>,
+    /// of every torrent without its own, see `Session::set_seed_limits`
+    pub seed_limits: SeedLimits,
+    /// by name, see `Session::set_torrent_category`
+    pub categories: BTreeMap<String, Category>,
+    /// folders torrents are added from, see `watch::WatchFolder`
+    pub watch: Vec<WatchFolder>,
+    /// feeds torrents are added from by name, see `rss::Feed`
+    pub feeds: BTreeMap<String, Feed>,
+    /// run as torrents and their files complete
+    pub hooks: HooksConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_port: ListenPort::Fixed(6881),
+            listen_interface: None,
+            ip_families: IpFamilies::default(),
+            save_path: ".".into(),
+            resume_dir: None,
+            queue: QueueSettings::default(),
+            rate_limits: RateLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            dht: Some(DhtConfig::default()),
+            lsd: true,
+            trackers: true,
+            port_mapping: true,
+            metrics: None,
+            stream: None,
+            api: None,
+            encryption: EncryptionPolicy::default(),
+            proxy: None,
+            schedule: Schedule::default(),
+            blocklist: None,
+            seed_limits: SeedLimits::default(),
+            categories: BTreeMap::new(),
+            watch: vec![],
+            feeds: BTreeMap::new(),
+            hooks: HooksConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::from_toml(&text).with_context(|| format!("loading {}", path.display()))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let mut tables = parse_tables(text)?;
+        let mut config = Config::default();
+
+        let mut root = Table::new("", tables.remove("").unwrap_or_default());
+        if let Some(Value::String(_)) = root.keys.get("listen_port") {
+            let ports = root.required_string("listen_port")?;
+            config.listen_port = ListenPort::parse(&ports).context("listen_port")?;
+        } else if let Some(port) = root.integer("listen_port")? {
+            config.listen_port = ListenPort::Fixed(port);
+        }
+        if let Some(interface) = root.string("listen_interface")? {
+            config.listen_interface = Some(interface);
+        }
+        if let Some(families) = root.string("ip_families")? {
+            config.ip_families = IpFamilies::parse(&families).context("ip_families")?;
+        }
+        if let Some(path) = root.string("save_path")? {
+            config.save_path = path.into();
+        }
+        if let Some(path) = root.string("resume_dir")? {
+            config.resume_dir = Some(path.into());
+        }
+        if let Some(policy) = root.string("encryption")? {
+            config.encryption = match policy.as_str() {
+                "disabled" => EncryptionPolicy::Disabled,
+                "enabled" => EncryptionPolicy::Enabled,
+                "required" => EncryptionPolicy::Required,
+                _ => bail!("encryption can't be {}", policy),
+            };
+        }
+        if let Some(lsd) = root.bool("lsd")? {
+            config.lsd = lsd;
+        }
+        if let Some(trackers) = root.bool("trackers")? {
+            config.trackers = trackers;
+        }
+        if let Some(port_mapping) = root.bool("port_mapping")? {
+            config.port_mapping = port_mapping;
+        }
+        if let Some(addr) = root.string("metrics")? {
+            config.metrics = Some(
+                addr.parse()
+                    .map_err(|_| anyhow!("metrics has to be an address like 127.0.0.1:9100"))?,
+            );
+        }
+        // a port alone is on loopback
+        config.stream = root.take(
+            "stream",
+            "an address like 127.0.0.1:8090, or a port",
+            |value| match value.as_integer() {
+                Some(port) => Some(SocketAddr::from((
+                    Ipv4Addr::LOCALHOST,
+                    port.try_into().ok()?,
+                ))),
+                None => value.as_str()?.parse().ok(),
+            },
+        )?;
+        root.finish()?;
+
+        if let Some(keys) = tables.remove("limits") {
+            let mut limits = Table::new("limits", keys);
+            let kib = |rate: Option<u64>| rate.map(|rate| (rate > 0).then(|| rate * 1024));
+            if let Some(upload) = kib(limits.integer("upload")?) {
+                config.rate_limits.upload = upload;
+            }
+            if let Some(download) = kib(limits.integer("download")?) {
+                config.rate_limits.download = download;
+            }
+            let count = |max: Option<usize>| max.map(|max| (max > 0).then_some(max));
+            if let Some(max) = count(limits.integer("max_peers")?) {
+                config.connection_limits.max_peers = max;
+            }
+            if let Some(max) = count(limits.integer("max_connections")?) {
+                config.connection_limits.max_connections = max;
+            }
+            limits.finish()?;
+        }
+
+        if let Some(keys) = tables.remove("api") {
+            let mut table = Table::new("api", keys);
+            let addr = table.required_string("addr")?;
+            config.api = Some(ApiConfig {
+                addr: addr
+                    .parse()
+                    .map_err(|_| anyhow!("api.addr has to be an address like 127.0.0.1:8080"))?,
+                token: table.string("token")?,
+                cors_origins: table.strings("cors_origins")?.unwrap_or_default(),
+            });
+            table.finish()?;
+        }
+
+        if let Some(keys) = tables.remove("queue") {
+            let mut queue = Table::new("queue", keys);
+            if let Some(max) = queue.integer("max_active_downloads")? {
+                config.queue.max_active_downloads = max;
+            }
+            if let Some(max) = queue.integer("max_active_seeds")? {
+                config.queue.max_active_seeds = max;
+            }
+            if let Some(max) = queue.integer("max_active_total")? {
+                config.queue.max_active_total = max;
+            }
+            if let Some(rate) = queue.integer::<u64>("slow_torrent_rate")? {
+                config.queue.slow_torrent_rate = (rate > 0).then(|| rate * 1024);
+            }
+            queue.finish()?;
+        }
+
+        if let Some(keys) = tables.remove("dht") {
+            let mut table = Table::new("dht", keys);
+            let mut dht = DhtConfig::default();
+            if let Some(path) = table.string("state_path")? {
+                dht.state_path = Some(path.into());
+            }
+            if let Some(routers) = table.strings("routers")? {
+                dht.routers = routers;
+            }
+            if let Some(read_only) = table.bool("read_only")? {
+                dht.read_only = read_only;
+            }
+            if let Some(indexer) = table.bool("indexer")? {
+                dht.indexer = indexer;
+            }
+            let enabled = table.bool("enabled")?.unwrap_or(true);
+            table.finish()?;
+            config.dht = enabled.then_some(dht);
+        }
+
+        if let Some(keys) = tables.remove("proxy") {
+            let mut table = Table::new("proxy", keys);
+            let kind = match table.string("type")?.as_deref() {
+                Some("socks5") | None => ProxyKind::Socks5,
+                Some("http") => ProxyKind::Http,
+                Some(kind) => bail!("proxy.type can't be {}", kind),
+            };
+            config.proxy = Some(Proxy {
+                kind,
+                host: table.string("host")?.unwrap_or_default(),
+                port: table.integer("port")?.unwrap_or(0),
+            });
+            table.finish()?;
+        }
+
+        if let Some(keys) = tables.remove("seeding") {
+            let mut table = Table::new("seeding", keys);
+            let minutes = |minutes: Option<u64>| minutes.map(|m| Duration::from_secs(m * 60));
+            config.seed_limits = SeedLimits {
+                ratio: table.float("ratio")?,
+                seed_time: minutes(table.integer("seed_time_minutes")?),
+                idle_time: minutes(table.integer("idle_time_minutes")?),
+                action: match table.string("action")? {
+                    Some(action) => {
+                        SeedLimitAction::parse(&action).with_context(|| table.key("action"))?
+                    }
+                    None => SeedLimitAction::default(),
+                },
+            };
+            table.finish()?;
+        }
+
+        if let Some(keys) = tables.remove("blocklist") {
+            let mut table = Table::new("blocklist", keys);
+            let hours: Option<u64> = table.integer("refresh_hours")?;
+            config.blocklist = match (table.string("path")?, table.string("url")?) {
+                (Some(path), None) if hours.is_none() => Some(BlocklistSource::File(path.into())),
+                (Some(_), None) => bail!("blocklist.refresh_hours only applies to urls"),
+                (None, Some(url)) => Some(BlocklistSource::Url {
+                    url,
+                    refresh: Duration::from_secs(hours.unwrap_or(24) * 3600),
+                }),
+                _ => bail!("blocklist takes either a path or a url"),
+            };
+            table.finish()?;
+        }
+
+        if let Some(keys) = tables.remove("hooks") {
+            let mut table = Table::new("hooks", keys);
+            config.hooks = HooksConfig {
+                command: table.string("command")?,
+                webhook: table.string("webhook")?,
+                files: table.bool("files")?.unwrap_or(false),
+            };
+            table.finish()?;
+        }
+
+        let rules: Vec<_> = tables
+            .keys()
+            .filter(|name| name.starts_with("schedule."))
+            .cloned()
+            .collect();
+        for name in rules {
+            let keys = tables.remove(&name).unwrap();
+            config
+                .schedule
+                .rules
+                .push(schedule_rule(Table::new(name, keys))?);
+        }
+
+        let names: Vec<_> = tables
+            .keys()
+            .filter(|name| name.starts_with("category."))
+            .cloned()
+            .collect();
+        for name in names {
+            let keys = tables.remove(&name).unwrap();
+            let category = category_table(Table::new(name.as_str(), keys))?;
+            config
+                .categories
+                .insert(name["category.".len()..].to_string(), category);
+        }
+
+        let folders: Vec<_> = tables
+            .keys()
+            .filter(|name| name.starts_with("watch."))
+            .cloned()
+            .collect();
+        for name in folders {
+            let keys = tables.remove(&name).unwrap();
+            config.watch.push(watch_folder(Table::new(name, keys))?);
+        }
+
+        let feeds: Vec<_> = tables
+            .keys()
+            .filter(|name| name.starts_with("rss."))
+            .cloned()
+            .collect();
+        for name in feeds {
+            let keys = tables.remove(&name).unwrap();
+            let feed = feed(Table::new(name.as_str(), keys))?;
+            config.feeds.insert(name["rss.".len()..].to_string(), feed);
+        }
+
+        if let Some(name) = tables.keys().next() {
+            bail!("unknown table {}", name);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// settings a session can't start with
+    pub fn validate(&self) -> Result<()> {
+        self.listen_port.validate()?;
+        if self.listen_interface.as_deref() == Some("") {
+            bail!("listen_interface is empty");
+        }
+        if self.save_path.as_os_str().is_empty() {
+            bail!("save_path is empty");
+        }
+        if self
+            .resume_dir
+            .as_ref()
+            .is_some_and(|dir| dir.as_os_str().is_empty())
+        {
+            bail!("resume_dir is empty");
+        }
+        if self.rate_limits.upload == Some(0) || self.rate_limits.download == Some(0) {
+            bail!("rate limits have to be positive, None is unlimited");
+        }
+        let ConnectionLimits {
+            max_peers,
+            max_connections,
+        } = self.connection_limits;
+        if max_peers == Some(0) || max_connections == Some(0) {
+            bail!("connection limits have to be positive, None is unlimited");
+        }
+        if self.queue.slow_torrent_rate == Some(0) {
+            bail!("slow_torrent_rate has to be positive, None counts every torrent");
+        }
+        if let Some(addr) = self.stream {
+            let token = self.api.as_ref().and_then(|api| api.token.as_ref());
+            if token.is_none() && !addr.ip().is_loopback() {
+                bail!("streaming on {} needs the token of the api", addr);
+            }
+        }
+        if let Some(api) = &self.api {
+            if api.token.as_deref() == Some("") {
+                bail!("api token is empty, None lets anyone in");
+            }
+            if api.token.is_none() && !api.addr.ip().is_loopback() {
+                // anyone reaching it could control the session
+                bail!("the api needs a token to listen on {}", api.addr);
+            }
+            if api.cors_origins.iter().any(|origin| origin.is_empty()) {
+                bail!("api cors origins can't be empty");
+            }
+        }
+        if let Some(dht) = &self.dht {
+            if dht.routers.iter().any(|router| !router.contains(':')) {
+                bail!("dht routers have to be host:port");
+            }
+        }
+        if self.encryption != EncryptionPolicy::Disabled {
+            bail!("encryption isn't supported, peer connections are plaintext");
+        }
+        if let Some(proxy) = &self.proxy {
+            if proxy.host.is_empty() {
+                bail!("proxy host is empty");
+            }
+            if proxy.port == 0 {
+                bail!("proxy port is missing");
+            }
+            bail!("proxies aren't supported, connections are direct");
+        }
+        match &self.blocklist {
+            Some(BlocklistSource::File(path)) if path.as_os_str().is_empty() => {
+                bail!("blocklist path is empty")
+            }
+            Some(BlocklistSource::Url { url, .. }) if !tracker::is_http_url(url) => {
+                bail!("blocklist url has to be http:// or https://")
+            }
+            Some(BlocklistSource::Url { refresh, .. }) if refresh.is_zero() => {
+                bail!("blocklist refresh has to be positive")
+            }
+            _ => {}
+        }
+        self.schedule.validate()?;
+        self.seed_limits.validate()?;
+        self.hooks.validate()?;
+        for (name, category) in &self.categories {
+            category::validate_label(name)?;
+            category
+                .validate()
+                .with_context(|| format!("category {}", name))?;
+        }
+        for (index, folder) in self.watch.iter().enumerate() {
+            if let Some(name) = &folder.options.category {
+                if !self.categories.contains_key(name) {
+                    bail!(
+                        "watch folder {} has an unknown category {}",
+                        folder.path.display(),
+                        name
+                    );
+                }
+            }
+            if folder.path.as_os_str().is_empty() {
+                bail!("watch folder {} has an empty path", index);
+            }
+            if self.watch[..index]
+                .iter()
+                .any(|other| other.path == folder.path)
+            {
+                bail!("watch folder {} is watched twice", folder.path.display());
+            }
+        }
+        for (name, feed) in &self.feeds {
+            feed.validate()?;
+            if let Some(category) = &feed.options.category {
+                if !self.categories.contains_key(category) {
+                    bail!("feed {} has an unknown category {}", name, category);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts from the defaults, `build` validates the result
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// a port like 6881, or a `ListenPort`
+    pub fn listen_port(mut self, port: impl Into<ListenPort>) -> Self {
+        self.config.listen_port = port.into();
+        self
+    }
+
+    pub fn listen_interface(mut self, interface: impl Into<String>) -> Self {
+        self.config.listen_interface = Some(interface.into());
+        self
+    }
+
+    pub fn ip_families(mut self, families: IpFamilies) -> Self {
+        self.config.ip_families = families;
+        self
+    }
+
+    pub fn save_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.save_path = path.into();
+        self
+    }
+
+    pub fn resume_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.resume_dir = Some(dir.into());
+        self
+    }
+
+    pub fn queue(mut self, queue: QueueSettings) -> Self {
+        self.config.queue = queue;
+        self
+    }
+
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.config.rate_limits = limits;
+        self
+    }
+
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.config.connection_limits = limits;
+        self
+    }
+
+    /// None runs without the DHT
+    pub fn dht(mut self, dht: Option<DhtConfig>) -> Self {
+        self.config.dht = dht;
+        self
+    }
+
+    pub fn lsd(mut self, lsd: bool) -> Self {
+        self.config.lsd = lsd;
+        self
+    }
+
+    pub fn trackers(mut self, trackers: bool) -> Self {
+        self.config.trackers = trackers;
+        self
+    }
+
+    pub fn port_mapping(mut self, port_mapping: bool) -> Self {
+        self.config.port_mapping = port_mapping;
+        self
+    }
+
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.config.metrics = Some(addr);
+        self
+    }
+
+    pub fn stream(mut self, addr: SocketAddr) -> Self {
+        self.config.stream = Some(addr);
+        self
+    }
+
+    pub fn api(mut self, api: ApiConfig) -> Self {
+        self.config.api = Some(api);
+        self
+    }
+
+    pub fn encryption(mut self, policy: EncryptionPolicy) -> Self {
+        self.config.encryption = policy;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.config.proxy = Some(proxy);
+        self
+    }
+
+    pub fn blocklist(mut self, source: BlocklistSource) -> Self {
+        self.config.blocklist = Some(source);
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.config.schedule = schedule;
+        self
+    }
+
+    pub fn seed_limits(mut self, limits: SeedLimits) -> Self {
+        self.config.seed_limits = limits;
+        self
+    }
+
+    pub fn category(mut self, name: impl Into<String>, category: Category) -> Self {
+        self.config.categories.insert(name.into(), category);
+        self
+    }
+
+    /// adds a folder to those watched
+    pub fn watch(mut self, folder: WatchFolder) -> Self {
+        self.config.watch.push(folder);
+        self
+    }
+
+    /// adds a feed to those fetched
+    pub fn feed(mut self, name: impl Into<String>, feed: Feed) -> Self {
+        self.config.feeds.insert(name.into(), feed);
+        self
+    }
+
+    pub fn hooks(mut self, hooks: HooksConfig) -> Self {
+        self.config.hooks = hooks;
+        self
+    }
+
+    pub fn build(self) -> Result<Config> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// a `[schedule.<name>]` table
+fn schedule_rule(mut table: Table) -> Result<ScheduleRule> {
+    let days = table.required_string("days")?;
+    let from = table.required_string("from")?;
+    let to = table.required_string("to")?;
+    let kib = |rate: Option<u64>| rate.filter(|rate| *rate > 0).map(|rate| rate * 1024);
+    let upload = table.integer("upload")?;
+    let download = table.integer("download")?;
+    let action = match table.bool("pause")? {
+        Some(true) if upload.is_some() || download.is_some() => {
+            bail!("{} can't both pause and limit", table.name)
+        }
+        Some(true) => ScheduleAction::Pause,
+        _ => ScheduleAction::Limit(RateLimits {
+            upload: kib(upload),
+            download: kib(download),
+        }),
+    };
+    let context = |key: &str| table.key(key);
+    let rule = ScheduleRule {
+        days: schedule::parse_days(&days).with_context(|| context("days"))?,
+        start: schedule::parse_time(&from).with_context(|| context("from"))?,
+        end: schedule::parse_time(&to).with_context(|| context("to"))?,
+        action,
+    };
+    table.finish()?;
+    Ok(rule)
+}
+
+/// a `[category.<name>]` table
+fn category_table(mut table: Table) -> Result<Category> {
+    let kib = |rate: Option<u64>| rate.filter(|rate| *rate > 0).map(|rate| rate * 1024);
+    let category = Category {
+        save_path: table.string("save_path")?.map(PathBuf::from),
+        rate_limits: RateLimits {
+            upload: kib(table.integer("upload")?),
+            download: kib(table.integer("download")?),
+        },
+        queue_priority: table.integer("queue_priority")?.unwrap_or(0),
+    };
+    table.finish()?;
+    Ok(category)
+}
+
+/// a `[watch.<name>]` table
+fn watch_folder(mut table: Table) -> Result<WatchFolder> {
+    let mut folder = WatchFolder::new(table.required_string("path")?);
+    folder.options.save_path = table.string("save_path")?.map(PathBuf::from);
+    folder.options.category = table.string("category")?;
+    folder.options.paused = table.bool("paused")?.unwrap_or(false);
+    table.finish()?;
+    Ok(folder)
+}
+
+/// a `[rss.<name>]` table
+fn feed(mut table: Table) -> Result<Feed> {
+    let mut feed = Feed::new(table.required_string("url")?);
+    let mut regex = |key: &str| -> Result<Option<Regex>> {
+        let pattern = table.string(key)?;
+        pattern
+            .map(|pattern| Regex::ignoring_case(&pattern).with_context(|| table.key(key)))
+            .transpose()
+    };
+    feed.include = regex("include")?;
+    feed.exclude = regex("exclude")?;
+    if let Some(minutes) = table.integer::<u64>("refresh_minutes")? {
+        feed.refresh = Duration::from_secs(minutes * 60);
+    }
+    feed.options.save_path = table.string("save_path")?.map(PathBuf::from);
+    feed.options.category = table.string("category")?;
+    feed.options.paused = table.bool("paused")?.unwrap_or(false);
+    table.finish()?;
+    Ok(feed)
+}
+
+/// keys of each table, the keys before the first table header are in the
+/// table named "" and those of `[a.b]` in the table named "a.b"
+type Tables = BTreeMap<String, BTreeMap<String, Value>>;
+
+/// the tables of a TOML file, nested ones flattened under their dotted name
+fn parse_tables(text: &str) -> Result<Tables> {
+    let root: BTreeMap<String, Value> = toml::from_str(text)?;
+    let mut tables = Tables::new();
+    flatten(String::new(), root, &mut tables);
+    Ok(tables)
+}
+
+/// a table only made of tables, like `schedule` for `[schedule.night]`,
+/// isn't kept itself
+fn flatten(name: String, keys: impl IntoIterator<Item = (String, Value)>, tables: &mut Tables) {
+    let mut values = BTreeMap::new();
+    let mut empty = true;
+    for (key, value) in keys {
+        empty = false;
+        match value {
+            Value::Table(table) => {
+                let nested = match name.as_str() {
+                    "" => key,
+                    name => format!("{}.{}", name, key),
+                };
+                flatten(nested, table, tables);
+            }
+            value => {
+                values.insert(key, value);
+            }
+        }
+    }
+    if name.is_empty() || empty || !values.is_empty() {
+        tables.insert(name, values);
+    }
+}
+
+/// Takes the keys of a table out one by one so the ones left are unknown
+struct Table {
+    name: String,
+    keys: BTreeMap<String, Value>,
+}
+
+impl Table {
+    fn new(name: impl Into<String>, keys: BTreeMap<String, Value>) -> Self {
+        Self {
+            name: name.into(),
+            keys,
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        match self.name.as_str() {
+            "" => key.to_string(),
+            name => format!("{}.{}", name, key),
+        }
+    }
+
+    fn take<T>(
+        &mut self,
+        key: &str,
+        kind: &str,
+        get: impl FnOnce(&Value) -> Option<T>,
+    ) -> Result<Option<T>> {
+        match self.keys.remove(key) {
+            Some(value) => get(&value)
+                .map(Some)
+                .ok_or_else(|| anyhow!("{} has to be {}", self.key(key), kind)),
+            None => Ok(None),
+        }
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>> {
+        self.take(key, "a string", |value| value.as_str().map(String::from))
+    }
+
+    fn required_string(&mut self, key: &str) -> Result<String> {
+        self.string(key)?
+            .ok_or_else(|| anyhow!("{} is missing", self.key(key)))
+    }
+
+    fn bool(&mut self, key: &str) -> Result<Option<bool>> {
+        self.take(key, "a boolean", Value::as_bool)
+    }
+
+    /// out of range values are errors too
+    fn integer<T: TryFrom<i64>>(&mut self, key: &str) -> Result<Option<T>> {
+        self.take(key, "an integer in range", |value| {
+            value.as_integer()?.try_into().ok()
+        })
+    }
+
+    fn float(&mut self, key: &str) -> Result<Option<f64>> {
+        self.take(key, "a number", |value| {
+            value
+                .as_float()
+                .or_else(|| value.as_integer().map(|integer| integer as f64))
+        })
+    }
+
+    fn strings(&mut self, key: &str) -> Result<Option<Vec<String>>> {
+        self.take(key, "an array of strings", |value| {
+            value
+                .as_array()?
+                .iter()
+                .map(|value| value.as_str().map(String::from))
+                .collect()
+        })
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.keys.keys().next() {
+            Some(key) => bail!("unknown key {}", self.key(key)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::AddOptions;
+
+    #[test]
+    fn parses_toml() -> Result<()> {
+        let tables = parse_tables(
+            r#"
+            # comment
+            port = 6_881
+            ratio = 1.5
+            path = "C:\\torrents \"new\"" # trailing comment
+            literal = 'C:\torrents'
+            "quoted key" = true
+            limits = { upload = 100, download = 0 }
+            queue.max_active_total = 4
+
+            [dht]
+            routers = [
+                "a:1", # the first
+                'b:2',
+            ]
+
+            [dht.extra]
+            [schedule . work]
+            days = "mon"
+            "#,
+        )?;
+        let root = &tables[""];
+        assert_eq!(root["port"].as_integer(), Some(6881));
+        assert_eq!(root["path"].as_str(), Some("C:\\torrents \"new\""));
+        assert_eq!(root["literal"].as_str(), Some("C:\\torrents"));
+        assert_eq!(root["quoted key"].as_bool(), Some(true));
+        assert_eq!(tables["limits"]["upload"].as_integer(), Some(100));
+        assert_eq!(tables["queue"]["max_active_total"].as_integer(), Some(4));
+        let mut dht = Table::new("dht", tables["dht"].clone());
+        assert_eq!(
+            dht.strings("routers")?,
+            Some(vec!["a:1".into(), "b:2".into()])
+        );
+        assert!(tables["dht.extra"].is_empty());
+        assert_eq!(tables["schedule.work"]["days"].as_str(), Some("mon"));
+        assert!(!tables.contains_key("schedule"));
+        let mut root = Table::new("", root.clone());
+        assert_eq!(root.float("port")?, Some(6881.0));
+
+        for text in ["port", "a = 1\na = 2", "[a]\n[a]", "a = \"b", "a = [1 2]"] {
+            assert!(parse_tables(text).is_err(), "{}", text);
+        }
+        let error = Config::from_toml("save_path = \"a\"\nlsd = yes").unwrap_err();
+        assert!(format!("{:#}", error).contains("line 2"), "{:#}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn loads_toml() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            listen_port = 51413
+            listen_interface = "eth0"
+            ip_families = "prefer-ipv6"
+            save_path = "downloads"
+            encryption = "disabled"
+            lsd = false
+            port_mapping = false
+            metrics = "127.0.0.1:9100"
+            stream = "127.0.0.1:8090"
+
+            [api]
+            addr = "127.0.0.1:8080"
+            token = "secret"
+            cors_origins = ["*"]
+
+            [limits]
+            upload = 100
+            download = 0
+            max_peers = 50
+            max_connections = 0
+
+            [queue]
+            max_active_total = 4
+            slow_torrent_rate = 2
+
+            [dht]
+            state_path = "dht.state"
+            routers = ["localhost:6881"]
+
+            [blocklist]
+            url = "http://localhost/blocklist.gz"
+
+            [seeding]
+            ratio = 1.5
+            idle_time_minutes = 30
+            action = "stop"
+
+            [schedule.night]
+            days = "mon-fri"
+            from = "22:00"
+            to = "06:00"
+            upload = 50
+
+            [schedule.day]
+            days = "sat,sun"
+            from = "09:00"
+            to = "24:00"
+            pause = true
+
+            [category.movies]
+            save_path = "movies"
+            upload = 50
+            queue_priority = -1
+
+            [watch.movies]
+            path = "watch/movies"
+            category = "movies"
+            paused = true
+
+            [rss.movies]
+            url = "http://example.com/movies.xml"
+            include = "1080P"
+            refresh_minutes = 5
+            category = "movies"
+
+            [hooks]
+            command = "unpack.sh"
+            files = true
+            "#,
+        )?;
+        assert_eq!(config.listen_port, ListenPort::Fixed(51413));
+        assert_eq!(config.listen_interface.as_deref(), Some("eth0"));
+        assert_eq!(config.ip_families, IpFamilies::PreferV6);
+        assert_eq!(config.save_path, PathBuf::from("downloads"));
+        assert_eq!(config.resume_dir, None);
+        assert_eq!(config.encryption, EncryptionPolicy::Disabled);
+        assert!(!config.lsd);
+        assert!(config.trackers);
+        assert!(!config.port_mapping);
+        assert_eq!(config.metrics, Some("127.0.0.1:9100".parse()?));
+        assert_eq!(config.stream, Some("127.0.0.1:8090".parse()?));
+        assert_eq!(
+            Config::from_toml("stream = 8091")?.stream,
+            Some("127.0.0.1:8091".parse()?)
+        );
+        assert_eq!(
+            config.api,
+            Some(ApiConfig {
+                addr: "127.0.0.1:8080".parse()?,
+                token: Some("secret".into()),
+                cors_origins: vec!["*".into()],
+            })
+        );
+        assert_eq!(
+            config.rate_limits,
+            RateLimits {
+                upload: Some(100 * 1024),
+                download: None
+            }
+        );
+        assert_eq!(
+            config.connection_limits,
+            ConnectionLimits {
+                max_peers: Some(50),
+                max_connections: None
+            }
+        );
+        assert_eq!(
+            config.queue,
+            QueueSettings {
+                max_active_total: 4,
+                slow_torrent_rate: Some(2048),
+                ..Default::default()
+            }
+        );
+        let dht = config.dht.unwrap();
+        assert_eq!(dht.state_path, Some("dht.state".into()));
+        assert_eq!(dht.routers, ["localhost:6881"]);
+        assert_eq!(config.proxy, None);
+        assert_eq!(
+            config.blocklist,
+            Some(BlocklistSource::Url {
+                url: "http://localhost/blocklist.gz".into(),
+                refresh: Duration::from_secs(24 * 3600)
+            })
+        );
+        assert_eq!(
+            config.schedule.rules,
+            [
+                ScheduleRule {
+                    days: [false, false, false, false, false, true, true],
+                    start: 9 * 60,
+                    end: 24 * 60,
+                    action: ScheduleAction::Pause,
+                },
+                ScheduleRule {
+                    days: [true, true, true, true, true, false, false],
+                    start: 22 * 60,
+                    end: 6 * 60,
+                    action: ScheduleAction::Limit(RateLimits {
+                        upload: Some(50 * 1024),
+                        download: None,
+                    }),
+                },
+            ]
+        );
+
+        assert_eq!(
+            config.seed_limits,
+            SeedLimits {
+                ratio: Some(1.5),
+                seed_time: None,
+                idle_time: Some(Duration::from_secs(30 * 60)),
+                action: SeedLimitAction::Stop,
+            }
+        );
+        assert_eq!(
+            config.hooks,
+            HooksConfig {
+                command: Some("unpack.sh".into()),
+                webhook: None,
+                files: true,
+            }
+        );
+        assert_eq!(config.watch.len(), 1);
+        assert_eq!(config.watch[0].path, PathBuf::from("watch/movies"));
+        assert_eq!(
+            config.watch[0].options,
+            AddOptions {
+                category: Some("movies".into()),
+                paused: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            config.categories["movies"],
+            Category {
+                save_path: Some("movies".into()),
+                rate_limits: RateLimits {
+                    upload: Some(50 * 1024),
+                    download: None
+                },
+                queue_priority: -1,
+            }
+        );
+        let feed = &config.feeds["movies"];
+        assert_eq!(feed.url, "http://example.com/movies.xml");
+        assert_eq!(feed.include, Some(Regex::ignoring_case("1080P")?));
+        assert_eq!(feed.exclude, None);
+        assert_eq!(feed.refresh, Duration::from_secs(300));
+        assert_eq!(feed.options.category.as_deref(), Some("movies"));
+
+        let config = Config::from_toml("[dht]\nenabled = false")?;
+        assert!(config.dht.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        for (text, error) in [
+            ("port = 1", "unknown key port"),
+            ("[dht]\nstate = 'a'", "unknown key dht.state"),
+            ("[peers]", "unknown table peers"),
+            (
+                "listen_port = 70000",
+                "listen_port has to be an integer in range",
+            ),
+            ("listen_port = '9-1'", "listen_port"),
+            ("listen_interface = ''", "listen_interface is empty"),
+            ("ip_families = 'ipv5'", "ip_families"),
+            ("lsd = 1", "lsd has to be a boolean"),
+            (
+                "metrics = '9100'",
+                "metrics has to be an address like 127.0.0.1:9100",
+            ),
+            (
+                "stream = 'localhost'",
+                "stream has to be an address like 127.0.0.1:8090, or a port",
+            ),
+            (
+                "stream = '0.0.0.0:8090'",
+                "streaming on 0.0.0.0:8090 needs the token of the api",
+            ),
+            ("[api]\ntoken = 'a'", "api.addr is missing"),
+            (
+                "[api]\naddr = '127.0.0.1:1'\ntoken = ''",
+                "api token is empty, None lets anyone in",
+            ),
+            (
+                "[api]\naddr = '0.0.0.0:8080'",
+                "the api needs a token to listen on 0.0.0.0:8080",
+            ),
+            ("encryption = 'maybe'", "encryption can't be maybe"),
+            (
+                "encryption = 'required'",
+                "encryption isn't supported, peer connections are plaintext",
+            ),
+            (
+                "[hooks]\nwebhook = 'ftp://a'",
+                "hooks webhook has to be http:// or https://",
+            ),
+            ("save_path = ''", "save_path is empty"),
+            ("[proxy]\nhost = 'localhost'", "proxy port is missing"),
+            (
+                "[proxy]\ntype = 'http'\nhost = 'localhost'\nport = 8080",
+                "proxies aren't supported, connections are direct",
+            ),
+            (
+                "[dht]\nrouters = ['localhost']",
+                "dht routers have to be host:port",
+            ),
+            (
+                "[blocklist]\npath = 'a'\nurl = 'http://b'",
+                "blocklist takes either a path or a url",
+            ),
+            (
+                "[blocklist]\nurl = 'ftp://b'",
+                "blocklist url has to be http:// or https://",
+            ),
+            (
+                "[schedule.a]\ndays = 'mon'\nfrom = '01:00'",
+                "schedule.a.to is missing",
+            ),
+            (
+                "[schedule.a]\ndays = 'mon'\nfrom = '01:00'\nto = '02:00'\npause = true\nupload = 1",
+                "schedule.a can't both pause and limit",
+            ),
+            (
+                "[schedule.a]\ndays = 'mon'\nfrom = '01:00'\nto = '01:00'",
+                "schedule rule 0",
+            ),
+            ("[seeding]\nratio = 0", "the seed ratio has to be positive"),
+            ("[seeding]\nratio = '2'", "seeding.ratio has to be a number"),
+            ("[seeding]\naction = 'delete'", "seeding.action"),
+            ("[category.a]\nqueue_priority = 'high'", "category.a.queue_priority has to be an integer in range"),
+            ("[category.a]\nsave_path = ''", "category a"),
+            (
+                "[watch.a]\npath = 'b'\ncategory = 'c'",
+                "watch folder b has an unknown category c",
+            ),
+            ("[watch.a]\nsave_path = 'b'", "watch.a.path is missing"),
+            (
+                "[watch.a]\npath = 'b'\n[watch.c]\npath = 'b'",
+                "watch folder b is watched twice",
+            ),
+            ("[rss.a]\ninclude = 'b'", "rss.a.url is missing"),
+            (
+                "[rss.a]\nurl = 'http://b'\ncategory = 'c'",
+                "feed a has an unknown category c",
+            ),
+            (
+                "[rss.a]\nurl = 'http://b'\nrefresh_minutes = 0",
+                "feed http://b is refreshed every 0 minutes",
+            ),
+        ] {
+            assert_eq!(
+                Config::from_toml(text).unwrap_err().to_string(),
+                error,
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn builds_in_code() -> Result<()> {
+        let config = Config::builder()
+            .listen_port(0)
+            .save_path("downloads")
+            .dht(None)
+            .encryption(EncryptionPolicy::Disabled)
+            .build()?;
+        assert_eq!(config.listen_port, ListenPort::Fixed(0));
+        assert_eq!(
+            Config::from_toml("listen_port = \"6881-6889\"")?.listen_port,
+            ListenPort::Range(6881, 6889)
+        );
+        assert!(config.dht.is_none());
+        assert_eq!(config.encryption, EncryptionPolicy::Disabled);
+        assert!(Config::builder()
+            .rate_limits(RateLimits {
+                upload: Some(0),
+                download: None
+            })
+            .build()
+            .is_err());
+        Ok(())
+    }
+}
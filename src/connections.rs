@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many peers each torrent and the whole session stay connected to,
+/// None is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// of each torrent
+    pub max_peers: Option<usize>,
+    /// of every torrent together
+    pub max_connections: Option<usize>,
+}
+
+/// Connections open to the peers of every torrent of a session, shared by
+/// the torrents to stay under `ConnectionLimits::max_connections`
+#[derive(Debug, Default)]
+pub struct Connections {
+    open: AtomicUsize,
+    /// 0 is unlimited
+    max: AtomicUsize,
+}
+
+impl Connections {
+    pub fn new(max: Option<usize>) -> Self {
+        let connections = Self::default();
+        connections.set_max(max);
+        connections
+    }
+
+    pub fn set_max(&self, max: Option<usize>) {
+        self.max.store(max.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        Some(self.max.load(Ordering::Relaxed)).filter(|max| *max > 0)
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// counts a new connection, false when the limit is reached and it is
+    /// to be closed
+    pub fn try_open(&self) -> bool {
+        let max = self.max();
+        self.open
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                max.is_none_or(|max| open < max).then_some(open + 1)
+            })
+            .is_ok()
+    }
+
+    pub fn close(&self) {
+        let _ = self
+            .open
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                open.checked_sub(1)
+            });
+    }
+
+    /// whether a connection could be opened now
+    pub fn has_slot(&self) -> bool {
+        self.max().is_none_or(|max| self.open() < max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_connections_up_to_the_limit() {
+        let connections = Connections::new(Some(2));
+        assert!(connections.try_open());
+        assert!(connections.try_open());
+        assert!(!connections.try_open());
+        assert!(!connections.has_slot());
+        connections.close();
+        assert_eq!(connections.open(), 1);
+        assert!(connections.has_slot());
+        connections.set_max(None);
+        assert!(connections.try_open());
+        assert!(connections.try_open());
+        assert_eq!(connections.open(), 3);
+        assert_eq!(connections.max(), None);
+    }
+}
@@ -0,0 +1,572 @@
+use crate::metainfo::to_hex;
+use crate::session::AddOptions;
+use crate::{Metainfo, Session, TorrentHandle};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// how long a client waits for the daemon, and the daemon for a client
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// where the daemon listens unless told otherwise, in the runtime directory
+/// of the user when there is one, else in a directory of the temporary one
+/// named after their uid that only they can enter
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("torrent_rs.sock"),
+        None => std::env::temp_dir()
+            .join(format!("torrent_rs-{}", unsafe { libc::getuid() }))
+            .join("torrent_rs.sock"),
+    }
+}
+
+/// creates the directory of the socket for the user alone. The temporary
+/// directory is shared, so the one of `default_socket_path` there has to
+/// belong to the user and be closed to others if it exists already.
+fn socket_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => return Ok(()),
+    };
+    match std::fs::metadata(dir) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("creating {}", dir.display())),
+        Err(error) => Err(error).with_context(|| format!("reading {}", dir.display())),
+        Ok(metadata)
+            if std::env::var_os("XDG_RUNTIME_DIR").is_none()
+                && path == default_socket_path()
+                && (metadata.uid() != unsafe { libc::getuid() }
+                    || metadata.mode() & 0o077 != 0) =>
+        {
+            bail!(
+                "{} has to belong to the user and be closed to others",
+                dir.display()
+            )
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+/// What a client asks of the daemon. Torrents are named by a prefix of
+/// their info hash in hex or by their name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// a .torrent file, by absolute path, or a magnet link
+    Add {
+        source: String,
+        save_path: Option<PathBuf>,
+        paused: bool,
+    },
+    List,
+    Info(String),
+    /// the connected peers of a torrent
+    Peers(String),
+    Pause(String),
+    Resume(String),
+    Remove {
+        torrent: String,
+        delete_data: bool,
+    },
+}
+
+impl Request {
+    /// a line per word, ended by an empty line
+    pub fn encode(&self) -> String {
+        let words: Vec<String> = match self {
+            Request::Add {
+                source,
+                save_path,
+                paused,
+            } => {
+                let mut words = vec!["add".into(), source.clone()];
+                if let Some(path) = save_path {
+                    words.push("save-path".into());
+                    words.push(path.display().to_string());
+                }
+                if *paused {
+                    words.push("paused".into());
+                }
+                words
+            }
+            Request::List => vec!["list".into()],
+            Request::Info(torrent) => vec!["info".into(), torrent.clone()],
+            Request::Peers(torrent) => vec!["peers".into(), torrent.clone()],
+            Request::Pause(torrent) => vec!["pause".into(), torrent.clone()],
+            Request::Resume(torrent) => vec!["resume".into(), torrent.clone()],
+            Request::Remove {
+                torrent,
+                delete_data,
+            } => {
+                let mut words = vec!["remove".into(), torrent.clone()];
+                if *delete_data {
+                    words.push("delete-data".into());
+                }
+                words
+            }
+        };
+        let mut text = words.join("\n");
+        text.push_str("\n\n");
+        text
+    }
+
+    pub fn parse(words: &[String]) -> Result<Self> {
+        let word = |index: usize| -> Result<String> {
+            words
+                .get(index)
+                .cloned()
+                .ok_or_else(|| anyhow!("{} is missing arguments", words[0]))
+        };
+        let flags = words.get(2..).unwrap_or_default();
+        Ok(match words.first().map(String::as_str) {
+            Some("add") => {
+                let mut save_path = None;
+                let mut paused = false;
+                let mut flags = flags.iter();
+                while let Some(flag) = flags.next() {
+                    match flag.as_str() {
+                        "save-path" => {
+                            save_path = Some(flags.next().context("save-path needs a path")?.into())
+                        }
+                        "paused" => paused = true,
+                        _ => bail!("unknown add option {}", flag),
+                    }
+                }
+                Request::Add {
+                    source: word(1)?,
+                    save_path,
+                    paused,
+                }
+            }
+            Some("list") => Request::List,
+            Some("info") => Request::Info(word(1)?),
+            Some("peers") => Request::Peers(word(1)?),
+            Some("pause") => Request::Pause(word(1)?),
+            Some("resume") => Request::Resume(word(1)?),
+            Some("remove") => Request::Remove {
+                torrent: word(1)?,
+                delete_data: flags.iter().any(|flag| flag == "delete-data"),
+            },
+            Some(command) => bail!("unknown request {}", command),
+            None => bail!("empty request"),
+        })
+    }
+
+    /// runs the request against the session, the lines of the answer have
+    /// their fields separated by tabs
+    pub fn apply(self, session: &mut Session) -> Result<Vec<String>> {
+        match self {
+            Request::Add {
+                source,
+                save_path,
+                paused,
+            } => {
+                let options = AddOptions {
+                    save_path,
+                    paused,
+                    ..Default::default()
+                };
+                let info_hash = if source.starts_with("magnet:") {
+                    session.add_magnet_with(&source, options)?
+                } else {
+                    let data =
+                        std::fs::read(&source).with_context(|| format!("reading {}", source))?;
+                    session
+                        .add_torrent_with(Metainfo::from_bytes(data)?, options)?
+                        .info_hash()
+                };
+                Ok(vec![to_hex(&info_hash)])
+            }
+            Request::List => {
+                let mut lines: Vec<_> = session.torrents().map(list_line).collect();
+                for info_hash in session.magnet_hashes() {
+                    lines.push(format!(
+                        "{}\tfetching metadata\t0\t0\t0\t0\t0\t",
+                        to_hex(&info_hash)
+                    ));
+                }
+                Ok(lines)
+            }
+            Request::Info(torrent) => Ok(info_lines(session.find(&torrent)?)),
+            Request::Peers(torrent) => Ok(peer_lines(session.find(&torrent)?)),
+            Request::Pause(torrent) => {
+                let torrent = session.find(&torrent)?.clone();
+                session.pause(&torrent)?;
+                Ok(vec![])
+            }
+            Request::Resume(torrent) => {
+                let torrent = session.find(&torrent)?.clone();
+                session.resume(&torrent);
+                Ok(vec![])
+            }
+            Request::Remove {
+                torrent,
+                delete_data,
+            } => {
+                let torrent = session.find(&torrent)?.clone();
+                session.remove(&torrent, delete_data)?;
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+/// info hash, state, percent done, download and upload rates, peers, ratio
+/// and name
+fn list_line(torrent: &TorrentHandle) -> String {
+    let progress = torrent.progress_report();
+    format!(
+        "{}\t{}\t{:.1}\t{:.0}\t{:.0}\t{}\t{:.3}\t{}",
+        to_hex(&torrent.info_hash()),
+        torrent.state(),
+        progress.percent,
+        progress.download_rate,
+        progress.upload_rate,
+        torrent.stats().peers,
+        torrent.seeding().ratio,
+        torrent.root_name()
+    )
+}
+
+fn info_lines(torrent: &TorrentHandle) -> Vec<String> {
+    let progress = torrent.progress_report();
+    let stats = torrent.stats();
+    let tags: Vec<_> = torrent.tags().into_iter().collect();
+    let mut lines = vec![
+        ("name", torrent.root_name()),
+        ("info hash", to_hex(&torrent.info_hash())),
+        ("state", torrent.state().to_string()),
+        ("progress", format!("{:.1}", progress.percent)),
+        ("size", progress.bytes_wanted.to_string()),
+        ("done", progress.bytes_done.to_string()),
+        ("downloaded", stats.downloaded.to_string()),
+        ("uploaded", stats.uploaded.to_string()),
+        ("ratio", format!("{:.3}", torrent.seeding().ratio)),
+        ("download rate", format!("{:.0}", progress.download_rate)),
+        ("upload rate", format!("{:.0}", progress.upload_rate)),
+        ("peers", stats.peers.to_string()),
+        (
+            "pieces",
+            format!("{}/{}", progress.pieces, progress.piece_count),
+        ),
+        ("save path", torrent.save_path().display().to_string()),
+        ("category", torrent.category().unwrap_or_default()),
+        ("tags", tags.join(",")),
+    ];
+    if let Some(eta) = progress.eta {
+        lines.push(("eta", eta.as_secs().to_string()));
+    }
+    for tracker in torrent.announce_urls() {
+        lines.push(("tracker", tracker));
+    }
+    for file in torrent.file_progress() {
+        lines.push((
+            "file",
+            format!(
+                "{}\t{}\t{}",
+                file.path.display(),
+                file.bytes_done,
+                file.length
+            ),
+        ));
+    }
+    lines
+        .into_iter()
+        .map(|(key, value)| format!("{}\t{}", key, value))
+        .collect()
+}
+
+/// address, source, client, flags as in `PeerInfo::flags`, download and
+/// upload rates and the percent of the pieces it has, a line per peer
+fn peer_lines(torrent: &TorrentHandle) -> Vec<String> {
+    let pieces = torrent.progress_report().piece_count.max(1);
+    torrent
+        .peer_list()
+        .iter()
+        .map(|peer| {
+            format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{:.1}",
+                peer.addr,
+                peer.source,
+                peer.client.as_deref().unwrap_or_default(),
+                peer.flags(),
+                peer.download_rate,
+                peer.upload_rate,
+                peer.pieces as f64 * 100.0 / pieces as f64
+            )
+        })
+        .collect()
+}
+
+/// A request waiting for the session to answer it
+pub struct PendingRequest {
+    pub request: Request,
+    reply: Sender<Result<Vec<String>, String>>,
+}
+
+impl PendingRequest {
+    pub fn respond(self, result: Result<Vec<String>>) {
+        let _ = self
+            .reply
+            .send(result.map_err(|error| format!("{:#}", error)));
+    }
+}
+
+/// Accepts the requests of clients on a unix socket, handed to the loop
+/// owning the session through `poll`
+pub struct ControlServer {
+    path: PathBuf,
+    requests: Receiver<PendingRequest>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl ControlServer {
+    /// a socket left behind by a daemon that died is replaced, one that
+    /// still answers is an error
+    pub fn start(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        socket_dir(&path)?;
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                bail!("a daemon already listens on {}", path.display());
+            }
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("listening on {}", path.display()))?;
+        // wakes up regularly to stop when asked to
+        listener.set_nonblocking(true)?;
+        let (sender, requests) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || serve(listener, sender, stop))
+        };
+        Ok(Self {
+            path,
+            requests,
+            stop,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the next request waiting for an answer
+    pub fn poll(&self) -> Option<PendingRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(listener: UnixListener, requests: Sender<PendingRequest>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // a client that misbehaves only loses its own request
+                let _ = answer(stream, &requests);
+            }
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+fn answer(stream: UnixStream, requests: &Sender<PendingRequest>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut words = vec![];
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        words.push(line);
+    }
+    let result = match Request::parse(&words) {
+        Ok(request) => {
+            let (reply, answer) = mpsc::channel();
+            requests.send(PendingRequest { request, reply })?;
+            answer
+                .recv_timeout(TIMEOUT)
+                .unwrap_or_else(|_| Err("the daemon didn't answer".into()))
+        }
+        Err(error) => Err(format!("{:#}", error)),
+    };
+    let mut text = match result {
+        Ok(lines) => {
+            let mut text = String::from("ok\n");
+            for line in lines {
+                text.push_str(&line);
+                text.push('\n');
+            }
+            text
+        }
+        Err(error) => format!("error\t{}\n", error.replace('\n', " ")),
+    };
+    text.push('\n');
+    (&stream).write_all(text.as_bytes())?;
+    Ok(())
+}
+
+/// The error the daemon answered a request with, like an unknown torrent,
+/// as opposed to not reaching it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refused(pub String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// sends the request to the daemon listening on `path` and returns the
+/// lines of its answer, or `Refused`
+pub fn request(path: &Path, request: &Request) -> Result<Vec<String>> {
+    let stream = UnixStream::connect(path).map_err(|error| match error.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+            anyhow!("no daemon listening on {}", path.display())
+        }
+        _ => anyhow!("connecting to {}: {}", path.display(), error),
+    })?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    (&stream).write_all(request.encode().as_bytes())?;
+    let mut lines = BufReader::new(&stream).lines();
+    let status = lines.next().context("the daemon closed the connection")??;
+    if let Some(error) = status.strip_prefix("error\t") {
+        return Err(Refused(error.to_string()).into());
+    }
+    let mut answer = vec![];
+    for line in lines {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        answer.push(line);
+    }
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn words(request: &Request) -> Vec<String> {
+        request
+            .encode()
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn encodes_requests() -> Result<()> {
+        for request in &[
+            Request::Add {
+                source: "/a b/c.torrent".into(),
+                save_path: Some("/downloads".into()),
+                paused: true,
+            },
+            Request::List,
+            Request::Info("8dc3".into()),
+            Request::Peers("file1.txt".into()),
+            Request::Remove {
+                torrent: "name with spaces".into(),
+                delete_data: true,
+            },
+        ] {
+            assert_eq!(Request::parse(&words(request))?, *request);
+        }
+        assert!(Request::parse(&["info".to_string()]).is_err());
+        assert!(Request::parse(&["stop".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn answers_clients() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_control_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let config = Config::builder()
+            .save_path(&dir)
+            .listen_port(0)
+            .dht(None)
+            .lsd(false)
+            .trackers(false)
+            .port_mapping(false)
+            .build()?;
+        let mut session = Session::new(config)?;
+        let socket = dir.join("run").join("control.sock");
+        let server = ControlServer::start(&socket)?;
+        assert!(ControlServer::start(&socket).is_err());
+        let mode = std::fs::metadata(dir.join("run"))?.mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let torrent = std::fs::canonicalize("file1.txt.torrent")?;
+        let client = {
+            let socket = socket.clone();
+            thread::spawn(move || -> Result<_> {
+                let add = Request::Add {
+                    source: torrent.display().to_string(),
+                    save_path: None,
+                    paused: true,
+                };
+                Ok((
+                    request(&socket, &add)?,
+                    request(&socket, &Request::List)?,
+                    request(&socket, &Request::Info("nothing".into())),
+                    request(&socket, &Request::Info("8dc3".into()))?,
+                    request(&socket, &Request::Peers("file1.txt".into()))?,
+                ))
+            })
+        };
+        while !client.is_finished() {
+            if let Some(pending) = server.poll() {
+                let result = pending.request.clone().apply(&mut session);
+                pending.respond(result);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (added, list, missing, info, peers) = client.join().unwrap()?;
+        assert_eq!(added, ["8dc3b8a5ac6d8002df36541fda949e7109b7397c"]);
+        assert_eq!(list.len(), 1);
+        assert!(list[0].ends_with("\tfile1.txt"));
+        let missing = missing.unwrap_err();
+        assert_eq!(missing.to_string(), "no torrent nothing");
+        assert!(missing.is::<Refused>());
+        assert_eq!(info[0], "name\tfile1.txt");
+        assert!(info.contains(&"state\tpaused".to_string()));
+        assert!(peers.is_empty());
+
+        drop(server);
+        assert!(!socket.exists());
+        session.shutdown(Duration::from_secs(1))?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use crate::bencode::Bencode;
+use crate::merkle::{self, Hash, LEAF_SIZE};
+use crate::metainfo;
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// pieces are picked so torrents have about this many
+const TARGET_PIECES: u64 = 1500;
+const MIN_PIECE_LENGTH: u64 = LEAF_SIZE;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// Which hashes a created torrent has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TorrentVersion {
+    /// sha1 of every piece, understood by every client
+    #[default]
+    V1,
+    /// merkle trees of every file (BEP 52), only newer clients join
+    V2,
+    /// both, the files are padded to piece boundaries so the same pieces
+    /// work with both hashes
+    Hybrid,
+}
+
+/// Builds a .torrent of a file, or of every file under a directory
+#[derive(Debug, Clone)]
+pub struct TorrentCreator {
+    path: PathBuf,
+    trackers: Vec<String>,
+    piece_length: Option<u64>,
+    private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<SystemTime>,
+    version: TorrentVersion,
+}
+
+/// A file of the torrent, found under the path
+struct Source {
+    /// on disk
+    path: PathBuf,
+    /// in the torrent, relative to its root
+    components: Vec<String>,
+    length: u64,
+}
+
+/// The v2 hashes of one file
+struct FileHashes {
+    pieces_root: Option<Hash>,
+    /// only for files larger than a piece
+    layer: Option<Vec<Hash>>,
+}
+
+impl TorrentCreator {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            trackers: vec![],
+            piece_length: None,
+            private: false,
+            comment: None,
+            created_by: Some(concat!("torrent_rs/", env!("CARGO_PKG_VERSION")).to_string()),
+            creation_date: Some(SystemTime::now()),
+            version: TorrentVersion::V1,
+        }
+    }
+
+    /// trackers are announced to in the order they are added, each in its
+    /// own tier
+    pub fn tracker(mut self, url: impl Into<String>) -> Self {
+        self.trackers.push(url.into());
+        self
+    }
+
+    /// a power of two from 16 KiB to 256 MiB, picked from the size of the
+    /// files when not set
+    pub fn piece_length(mut self, length: u64) -> Self {
+        self.piece_length = Some(length);
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// torrent_rs and its version by default
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// now by default, None leaves it out so the same files always give
+    /// the same .torrent
+    pub fn creation_date(mut self, date: Option<SystemTime>) -> Self {
+        self.creation_date = date;
+        self
+    }
+
+    pub fn version(mut self, version: TorrentVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// checks the settings without touching the files, `create` does it
+    /// too before hashing them
+    pub fn validate(&self) -> Result<()> {
+        if self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_none()
+        {
+            bail!("{} has no file name", self.path.display());
+        }
+        if let Some(length) = self.piece_length {
+            if !(MIN_PIECE_LENGTH..=metainfo::MAX_PIECE_LENGTH).contains(&length) {
+                bail!("the piece length has to be between 16 KiB and 256 MiB");
+            }
+            if !length.is_power_of_two() {
+                bail!("the piece length has to be a power of two");
+            }
+        }
+        Ok(())
+    }
+
+    /// hashes the files and returns the bencoded .torrent, `progress` is
+    /// called with the bytes hashed so far and the total as it goes
+    pub fn create(&self, mut progress: impl FnMut(u64, u64)) -> Result<Vec<u8>> {
+        self.validate()?;
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no file name", self.path.display()))?
+            .to_string();
+        let multi_file = self.path.is_dir();
+        let sources = if multi_file {
+            let mut sources = vec![];
+            walk(&self.path, &mut vec![], &mut sources)?;
+            if sources.is_empty() {
+                bail!("no files under {}", self.path.display());
+            }
+            sources
+        } else {
+            let length = std::fs::metadata(&self.path)
+                .with_context(|| format!("reading {}", self.path.display()))?
+                .len();
+            vec![Source {
+                path: self.path.clone(),
+                components: vec![name.clone()],
+                length,
+            }]
+        };
+        let total: u64 = sources.iter().map(|source| source.length).sum();
+        let piece_length = match self.piece_length {
+            Some(length) => length,
+            None => (total / TARGET_PIECES)
+                .next_power_of_two()
+                .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH),
+        };
+
+        let v1 = self.version != TorrentVersion::V2;
+        let v2 = self.version != TorrentVersion::V1;
+        let mut pieces = vec![];
+        let mut pending = vec![];
+        let mut hashes = vec![];
+        let mut done = 0;
+        let mut buffer = vec![0; piece_length as usize];
+        for (index, source) in sources.iter().enumerate() {
+            let mut file = File::open(&source.path)
+                .with_context(|| format!("opening {}", source.path.display()))?;
+            let mut layer = vec![];
+            let mut blocks = vec![];
+            let mut left = source.length;
+            while left > 0 {
+                let chunk = &mut buffer[..piece_length.min(left) as usize];
+                file.read_exact(chunk)
+                    .with_context(|| format!("reading {}", source.path.display()))?;
+                left -= chunk.len() as u64;
+                if v1 {
+                    pending.extend_from_slice(chunk);
+                    hash_pieces(&mut pending, piece_length, &mut pieces);
+                }
+                if v2 {
+                    let chunk_blocks = merkle::block_hashes(chunk);
+                    let leaves = (piece_length / LEAF_SIZE) as usize;
+                    layer.push(merkle::root(&chunk_blocks, leaves));
+                    blocks.extend(chunk_blocks);
+                }
+                done += chunk.len() as u64;
+                progress(done, total);
+            }
+            let last = index + 1 == sources.len();
+            let remainder = source.length % piece_length;
+            if self.version == TorrentVersion::Hybrid && !last && remainder != 0 {
+                pending.resize(pending.len() + (piece_length - remainder) as usize, 0);
+                hash_pieces(&mut pending, piece_length, &mut pieces);
+            }
+            if v2 {
+                hashes.push(match source.length {
+                    0 => FileHashes {
+                        pieces_root: None,
+                        layer: None,
+                    },
+                    length if length <= piece_length => FileHashes {
+                        pieces_root: Some(merkle::root(
+                            &blocks,
+                            merkle::piece_leaves(piece_length, length),
+                        )),
+                        layer: None,
+                    },
+                    _ => FileHashes {
+                        pieces_root: Some(merkle::root_from_piece_layer(&layer, piece_length)),
+                        layer: Some(layer),
+                    },
+                });
+            }
+        }
+        if !pending.is_empty() {
+            pieces.push(Sha1::digest(&pending).into());
+        }
+
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), name.as_str().into());
+        info.insert(b"piece length".to_vec(), (piece_length as i64).into());
+        if self.private {
+            info.insert(b"private".to_vec(), 1.into());
+        }
+        if v1 {
+            info.insert(b"pieces".to_vec(), pieces.concat().into());
+            if multi_file {
+                let files = self.v1_files(&sources, piece_length);
+                info.insert(b"files".to_vec(), Bencode::List(files));
+            } else {
+                info.insert(b"length".to_vec(), (total as i64).into());
+            }
+        }
+        let mut piece_layers = HashMap::new();
+        if v2 {
+            let mut tree = Bencode::Dictionary(HashMap::new());
+            for (source, hashes) in sources.iter().zip(hashes) {
+                let mut file = HashMap::new();
+                file.insert(b"length".to_vec(), (source.length as i64).into());
+                if let Some(root) = hashes.pieces_root {
+                    file.insert(b"pieces root".to_vec(), root.to_vec().into());
+                    if let Some(layer) = hashes.layer {
+                        piece_layers.insert(root.to_vec(), layer.concat().into());
+                    }
+                }
+                insert_file(&mut tree, &source.components, Bencode::Dictionary(file));
+            }
+            info.insert(b"file tree".to_vec(), tree);
+            info.insert(b"meta version".to_vec(), 2.into());
+        }
+
+        let mut torrent = HashMap::new();
+        if let Some(tracker) = self.trackers.first() {
+            torrent.insert(b"announce".to_vec(), tracker.as_str().into());
+        }
+        if self.trackers.len() > 1 {
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|tracker| Bencode::List(vec![tracker.as_str().into()]))
+                .collect();
+            torrent.insert(b"announce-list".to_vec(), Bencode::List(tiers));
+        }
+        if let Some(comment) = &self.comment {
+            torrent.insert(b"comment".to_vec(), comment.as_str().into());
+        }
+        if let Some(created_by) = &self.created_by {
+            torrent.insert(b"created by".to_vec(), created_by.as_str().into());
+        }
+        if let Some(date) = self.creation_date {
+            let seconds = date
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            torrent.insert(b"creation date".to_vec(), (seconds as i64).into());
+        }
+        torrent.insert(b"info".to_vec(), Bencode::Dictionary(info));
+        if !piece_layers.is_empty() {
+            torrent.insert(b"piece layers".to_vec(), Bencode::Dictionary(piece_layers));
+        }
+        Ok(Bencode::Dictionary(torrent).encode())
+    }
+
+    /// the files list of v1 and hybrid torrents, hybrid ones with a padding
+    /// file after every file not ending on a piece boundary
+    fn v1_files(&self, sources: &[Source], piece_length: u64) -> Vec<Bencode> {
+        let mut files = vec![];
+        for (index, source) in sources.iter().enumerate() {
+            files.push(file_entry(source.length, &source.components, None));
+            let remainder = source.length % piece_length;
+            if self.version == TorrentVersion::Hybrid && index + 1 < sources.len() && remainder != 0
+            {
+                let length = piece_length - remainder;
+                let path = [".pad".to_string(), length.to_string()];
+                files.push(file_entry(length, &path, Some("p")));
+            }
+        }
+        files
+    }
+}
+
+fn file_entry(length: u64, components: &[String], attr: Option<&str>) -> Bencode {
+    let mut file = HashMap::new();
+    file.insert(b"length".to_vec(), (length as i64).into());
+    let path = components
+        .iter()
+        .map(|component| component.as_str().into())
+        .collect();
+    file.insert(b"path".to_vec(), Bencode::List(path));
+    if let Some(attr) = attr {
+        file.insert(b"attr".to_vec(), attr.into());
+    }
+    Bencode::Dictionary(file)
+}
+
+/// hashes the full pieces at the start of `pending`
+fn hash_pieces(pending: &mut Vec<u8>, piece_length: u64, pieces: &mut Vec<[u8; 20]>) {
+    let full = pending.len() / piece_length as usize * piece_length as usize;
+    for piece in pending[..full].chunks(piece_length as usize) {
+        pieces.push(Sha1::digest(piece).into());
+    }
+    pending.drain(..full);
+}
+
+/// files of the v2 file tree are dictionaries with an empty key, under a
+/// dictionary per path component
+fn insert_file(tree: &mut Bencode, components: &[String], file: Bencode) {
+    let Bencode::Dictionary(entries) = tree else {
+        return;
+    };
+    match components {
+        [] => {
+            entries.insert(vec![], file);
+        }
+        [first, rest @ ..] => {
+            let entry = entries
+                .entry(first.as_bytes().to_vec())
+                .or_insert_with(|| Bencode::Dictionary(HashMap::new()));
+            insert_file(entry, rest, file);
+        }
+    }
+}
+
+/// the files under `dir` in path order, hidden ones are skipped
+fn walk(dir: &Path, components: &mut Vec<String>, sources: &mut Vec<Source>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("reading {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .with_context(|| format!("{} is not valid utf-8", entry.path().display()))?;
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = std::fs::metadata(entry.path())?;
+        components.push(name.to_string());
+        if metadata.is_dir() {
+            walk(&entry.path(), components, sources)?;
+        } else if metadata.is_file() {
+            sources.push(Source {
+                path: entry.path(),
+                components: components.clone(),
+                length: metadata.len(),
+            });
+        }
+        components.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Metainfo, Torrent};
+
+    #[test]
+    fn created_torrents_verify() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_create_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let root = dir.join("files");
+        std::fs::create_dir_all(root.join("sub"))?;
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(root.join("a.bin"), &data)?;
+        std::fs::write(root.join("sub").join("b.bin"), &data[..20_000])?;
+        std::fs::write(root.join("empty"), "")?;
+        std::fs::write(root.join(".hidden"), "x")?;
+
+        for version in [
+            TorrentVersion::V1,
+            TorrentVersion::V2,
+            TorrentVersion::Hybrid,
+        ] {
+            let mut calls = 0;
+            let bytes = TorrentCreator::new(&root)
+                .tracker("http://tracker.example/announce")
+                .tracker("udp://other.example:6969")
+                .piece_length(32 * 1024)
+                .private(true)
+                .comment("test files")
+                .version(version)
+                .create(|done, total| {
+                    calls += 1;
+                    assert!(done <= total && total == 120_000);
+                })?;
+            assert!(calls > 0);
+            let metainfo = Metainfo::from_bytes(bytes)?;
+            assert_eq!(metainfo.info.name, "files");
+            assert!(metainfo.info.private);
+            assert!(metainfo.info.total_length() >= 120_000);
+            assert_eq!(
+                metainfo.announce.as_deref(),
+                Some("http://tracker.example/announce")
+            );
+            assert_eq!(metainfo.announce_list.len(), 2);
+            assert_eq!(metainfo.comment.as_deref(), Some("test files"));
+            assert!(metainfo.creation_date.is_some());
+            assert_eq!(
+                metainfo.info_hash_v2.is_some(),
+                version != TorrentVersion::V1
+            );
+            let paths: Vec<_> = metainfo
+                .info
+                .files
+                .iter()
+                .filter(|file| !file.padding)
+                .map(|file| file.path.clone())
+                .collect();
+            assert_eq!(
+                paths,
+                [
+                    PathBuf::from("a.bin"),
+                    PathBuf::from("empty"),
+                    PathBuf::from("sub/b.bin")
+                ]
+            );
+
+            let mut torrent = Torrent::new(metainfo, &dir);
+            torrent.force_recheck()?;
+            while torrent.poll_event().is_some() {}
+            assert!(torrent.have().is_full(), "{:?}", version);
+        }
+
+        let single = TorrentCreator::new(root.join("a.bin")).create(|_, _| {})?;
+        let metainfo = Metainfo::from_bytes(single)?;
+        assert!(!metainfo.info.multi_file);
+        assert_eq!(metainfo.info.piece_length, MIN_PIECE_LENGTH);
+        assert!(TorrentCreator::new(&root)
+            .piece_length(1000)
+            .create(|_, _| {})
+            .is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use super::krpc::Query;
+use super::routing::NodeId;
+use super::{Dht, DhtEvent};
+use crate::magnet::Magnet;
+use crate::metadata::MetadataDownload;
+use crate::metainfo::Metainfo;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// sample_infohashes queries in flight at once
+const MAX_IN_FLIGHT: usize = 8;
+/// info hashes whose peers are looked up at once
+const MAX_LOOKUPS: usize = 4;
+/// nodes remembered to sample, the ones found past this are ignored
+const MAX_NODES: usize = 10_000;
+/// peers kept per info hash to fetch its metadata from
+const MAX_PEERS: usize = 8;
+/// longest wait a node may ask for between samples (BEP 51)
+const MAX_INTERVAL: i64 = 6 * 60 * 60;
+
+/// What the indexer knows about an info hash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// from the metadata, once it is fetched
+    pub name: Option<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexerEvent {
+    /// a node sampled an info hash we didn't know, its peers are looked up
+    Discovered { info_hash: [u8; 20] },
+    /// peers of an info hash without a name yet, to fetch its metadata from
+    /// with `Indexer::metadata_download`
+    Peers {
+        info_hash: [u8; 20],
+        peers: Vec<SocketAddr>,
+    },
+    /// the metadata of the info hash was received
+    Named { info_hash: [u8; 20], name: String },
+}
+
+/// Crawls the DHT for info hashes: every node we hear of is asked for
+/// samples of the info hashes it stores (BEP 51) as often as it allows, and
+/// the peers of the new ones are looked up so their names can be fetched
+/// with ut_metadata. It drives a `Dht` through `tick` and the events of the
+/// node handed to `handle_event`.
+#[derive(Debug, Default)]
+pub struct Indexer {
+    /// nodes to sample and when they may be sampled next
+    nodes: HashMap<SocketAddr, Instant>,
+    in_flight: HashSet<SocketAddr>,
+    /// info hashes waiting for their peers to be looked up
+    pending: VecDeque<[u8; 20]>,
+    lookups: HashSet<[u8; 20]>,
+    index: HashMap<[u8; 20], IndexEntry>,
+    events: VecDeque<IndexerEvent>,
+}
+
+impl Indexer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn index(&self) -> &HashMap<[u8; 20], IndexEntry> {
+        &self.index
+    }
+
+    pub fn poll_event(&mut self) -> Option<IndexerEvent> {
+        self.events.pop_front()
+    }
+
+    fn add_node(&mut self, addr: SocketAddr, now: Instant) {
+        if self.nodes.len() < MAX_NODES {
+            self.nodes.entry(addr).or_insert(now);
+        }
+    }
+
+    /// samples the nodes that are due and looks up the peers of the next
+    /// info hashes, meant to be called about once a second
+    pub fn tick(&mut self, dht: &mut Dht, now: Instant) {
+        for node in dht.routing_table().nodes() {
+            self.add_node(node.addr, now);
+        }
+        let due: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|(addr, next)| **next <= now && !self.in_flight.contains(addr))
+            .map(|(addr, _)| *addr)
+            .take(MAX_IN_FLIGHT.saturating_sub(self.in_flight.len()))
+            .collect();
+        for addr in due {
+            self.in_flight.insert(addr);
+            // random targets spread the nodes returned over the whole DHT
+            dht.sample_infohashes(addr, NodeId::random());
+        }
+        while self.lookups.len() < MAX_LOOKUPS {
+            let info_hash = match self.pending.pop_front() {
+                Some(info_hash) => info_hash,
+                None => break,
+            };
+            self.lookups.insert(info_hash);
+            dht.lookup(info_hash);
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &DhtEvent, now: Instant) {
+        match event {
+            DhtEvent::Response {
+                from,
+                query: Query::SampleInfohashes { .. },
+                response,
+            } => {
+                self.in_flight.remove(from);
+                let interval = response.interval.unwrap_or(MAX_INTERVAL);
+                let interval = Duration::from_secs(interval.clamp(0, MAX_INTERVAL) as u64);
+                self.nodes.insert(*from, now + interval);
+                for (_, addr) in &response.nodes {
+                    self.add_node(*addr, now);
+                }
+                for info_hash in &response.samples {
+                    if !self.index.contains_key(info_hash) {
+                        self.index.insert(*info_hash, IndexEntry::default());
+                        self.pending.push_back(*info_hash);
+                        self.events.push_back(IndexerEvent::Discovered {
+                            info_hash: *info_hash,
+                        });
+                    }
+                }
+            }
+            // nodes without BEP 51 or gone aren't asked again
+            DhtEvent::QueryFailed {
+                to,
+                query: Query::SampleInfohashes { .. },
+            } => {
+                self.in_flight.remove(to);
+                self.nodes.remove(to);
+            }
+            DhtEvent::Peers { info_hash, peers } => {
+                let entry = match self.index.get_mut(info_hash) {
+                    Some(entry) if entry.name.is_none() => entry,
+                    _ => return,
+                };
+                for peer in peers {
+                    if entry.peers.len() < MAX_PEERS && !entry.peers.contains(peer) {
+                        entry.peers.push(*peer);
+                    }
+                }
+                self.events.push_back(IndexerEvent::Peers {
+                    info_hash: *info_hash,
+                    peers: peers.clone(),
+                });
+            }
+            DhtEvent::LookupDone { info_hash } => {
+                self.lookups.remove(info_hash);
+            }
+            _ => {}
+        }
+    }
+
+    /// fetches the metadata of an indexed info hash from its peers, the
+    /// result goes to `metadata_received`
+    pub fn metadata_download(&self, info_hash: [u8; 20]) -> MetadataDownload {
+        MetadataDownload::new(Magnet {
+            info_hash,
+            name: None,
+            trackers: vec![],
+        })
+    }
+
+    pub fn metadata_received(&mut self, metainfo: &Metainfo) {
+        if let Some(entry) = self.index.get_mut(&metainfo.info_hash) {
+            entry.name = Some(metainfo.info.name.clone());
+            entry.peers.clear();
+            self.events.push_back(IndexerEvent::Named {
+                info_hash: metainfo.info_hash,
+                name: metainfo.info.name.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// exchanges the packets of the indexing node on port 1 and the node on
+    /// port 2, handing the events of the first to the indexer
+    fn run(indexer: &mut Indexer, a: &mut Dht, b: &mut Dht) {
+        loop {
+            let mut sent = false;
+            while let Some((_, packet)) = a.poll_packet() {
+                b.handle_packet(addr(1), &packet, Instant::now());
+                sent = true;
+            }
+            while let Some((_, packet)) = b.poll_packet() {
+                a.handle_packet(addr(2), &packet, Instant::now());
+                sent = true;
+            }
+            while let Some(event) = a.poll_event() {
+                indexer.handle_event(&event, Instant::now());
+            }
+            if !sent {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn indexes_sampled_info_hashes() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let info_hash = metainfo.info_hash;
+        let mut a = Dht::new(NodeId([1; 20]));
+        let mut b = Dht::new(NodeId([2; 20]));
+        a.table.insert(b.id(), addr(2), Instant::now());
+        b.peers
+            .entry(info_hash)
+            .or_default()
+            .insert(addr(6881), Instant::now());
+        let mut indexer = Indexer::new();
+
+        indexer.tick(&mut a, Instant::now());
+        run(&mut indexer, &mut a, &mut b);
+        assert_eq!(
+            indexer.poll_event(),
+            Some(IndexerEvent::Discovered { info_hash })
+        );
+
+        // looks up the peers, the node isn't sampled again before its interval
+        indexer.tick(&mut a, Instant::now());
+        run(&mut indexer, &mut a, &mut b);
+        assert_eq!(
+            indexer.poll_event(),
+            Some(IndexerEvent::Peers {
+                info_hash,
+                peers: vec![addr(6881)],
+            })
+        );
+        assert!(indexer.lookups.is_empty());
+        indexer.tick(&mut a, Instant::now());
+        assert!(a.poll_packet().is_none());
+
+        assert_eq!(
+            indexer.metadata_download(info_hash).magnet().info_hash,
+            info_hash
+        );
+        indexer.metadata_received(&metainfo);
+        assert_eq!(
+            indexer.poll_event(),
+            Some(IndexerEvent::Named {
+                info_hash,
+                name: "file1.txt".into(),
+            })
+        );
+        assert_eq!(
+            indexer.index()[&info_hash].name.as_deref(),
+            Some("file1.txt")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use crate::bencode::Bencode;
+pub use crate::magnet::mutable_target;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha1::{Digest, Sha1};
+
+/// largest bencoded value nodes store
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+
+/// Data stored in the DHT (BEP 44). Immutable items are found by the hash
+/// of their value, mutable ones by the public key that signs them and a
+/// salt, and are replaced by items with a higher sequence number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub value: Bencode,
+    pub mutable: Option<MutableItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableItem {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub signature: [u8; 64],
+}
+
+impl Item {
+    pub fn immutable(value: Bencode) -> Self {
+        Self {
+            value,
+            mutable: None,
+        }
+    }
+
+    pub fn mutable(value: Bencode, key: &SigningKey, salt: Vec<u8>, seq: i64) -> Self {
+        let signature = key.sign(&signed_data(&value, &salt, seq)).to_bytes();
+        Self {
+            value,
+            mutable: Some(MutableItem {
+                key: key.verifying_key().to_bytes(),
+                salt,
+                seq,
+                signature,
+            }),
+        }
+    }
+
+    /// where the item is stored
+    pub fn target(&self) -> [u8; 20] {
+        match &self.mutable {
+            Some(mutable) => mutable_target(&mutable.key, &mutable.salt),
+            None => Sha1::digest(self.value.encode()).into(),
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.mutable.as_ref().map(|mutable| mutable.seq)
+    }
+
+    /// small enough and correctly signed
+    pub fn is_valid(&self) -> bool {
+        if self.value.encode().len() > MAX_VALUE_SIZE {
+            return false;
+        }
+        match &self.mutable {
+            Some(mutable) => {
+                mutable.salt.len() <= MAX_SALT_SIZE
+                    && verify(
+                        &mutable.key,
+                        &signed_data(&self.value, &mutable.salt, mutable.seq),
+                        &mutable.signature,
+                    )
+            }
+            None => true,
+        }
+    }
+}
+
+/// strict ed25519 signature check, refusing weak keys and non canonical
+/// signatures
+fn verify(key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    VerifyingKey::from_bytes(key).is_ok_and(|key| {
+        key.verify_strict(message, &Signature::from_bytes(signature))
+            .is_ok()
+    })
+}
+
+/// what the signature of a mutable item covers, the bencoded salt, seq and
+/// value keys without the enclosing dictionary
+fn signed_data(value: &Bencode, salt: &[u8], seq: i64) -> Vec<u8> {
+    let mut data = vec![];
+    if !salt.is_empty() {
+        data.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        data.extend_from_slice(salt);
+    }
+    data.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    data.extend_from_slice(&value.encode());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn hello() -> Bencode {
+        Bencode::Bytes(b"Hello World!".to_vec())
+    }
+
+    #[test]
+    fn bep44_test_vectors() {
+        assert_eq!(
+            Item::immutable(hello()).target().to_vec(),
+            hex("e5f96f6f38320f0f33959cb4d3d656452117aadb")
+        );
+
+        let key = hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let mut item = Item {
+            value: hello(),
+            mutable: Some(MutableItem {
+                key: key.clone().try_into().unwrap(),
+                salt: vec![],
+                seq: 1,
+                signature: hex(concat!(
+                    "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff",
+                    "1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"
+                ))
+                .try_into()
+                .unwrap(),
+            }),
+        };
+        assert!(item.is_valid());
+        assert_eq!(
+            item.target().to_vec(),
+            hex("4a533d47ec9c7d95b1ad75f576cffc641853b750")
+        );
+
+        let mutable = item.mutable.as_mut().unwrap();
+        mutable.salt = b"foobar".to_vec();
+        mutable.signature = hex(concat!(
+            "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d",
+            "df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"
+        ))
+        .try_into()
+        .unwrap();
+        assert!(item.is_valid());
+        assert_eq!(
+            item.target().to_vec(),
+            hex("411eba73b6f087ca51a3795d9c8c938d365e32c1")
+        );
+    }
+
+    #[test]
+    fn signed_items() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let mut item = Item::mutable(hello(), &key, b"salt".to_vec(), 4);
+        assert!(item.is_valid());
+        assert_eq!(item.seq(), Some(4));
+
+        item.mutable.as_mut().unwrap().seq = 5;
+        assert!(!item.is_valid());
+        assert!(!Item::immutable(Bencode::Bytes(vec![0; MAX_VALUE_SIZE])).is_valid());
+    }
+}
@@ -0,0 +1,639 @@
+use super::item::{Item, MutableItem};
+use super::routing::NodeId;
+use crate::bencode::{Bencode, Parser};
+use crate::listen;
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+/// length of a node in the compact `nodes` format, id then address
+const COMPACT_NODE_LEN: usize = 26;
+/// same for ipv6 nodes in `nodes6` (BEP 32)
+const COMPACT_NODE6_LEN: usize = 38;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+pub const ERROR_MESSAGE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+/// BEP 5 message, bencoded dictionaries sent over udp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage {
+    /// chosen by the querying node and echoed in the answer
+    pub transaction: Vec<u8>,
+    pub body: KrpcBody,
+    /// address the answering node sees us at, so nodes learn their
+    /// external ip (BEP 42)
+    pub ip: Option<SocketAddr>,
+    /// set on the queries of nodes that don't answer queries, which are
+    /// left out of routing tables (BEP 43)
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcBody {
+    Query {
+        id: NodeId,
+        query: Query,
+        want: Want,
+    },
+    Response(Response),
+    Error {
+        code: i64,
+        message: String,
+    },
+}
+
+/// Address families of the nodes a query asks for (BEP 32), neither asks
+/// for the family the query is sent over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Want {
+    pub v4: bool,
+    pub v6: bool,
+}
+
+impl Want {
+    pub const BOTH: Want = Want { v4: true, v6: true };
+
+    /// whether the node answering a query from `from` should return nodes
+    /// of the family of `addr`
+    pub fn wants(&self, from: &SocketAddr, addr: &SocketAddr) -> bool {
+        match (self.v4, self.v6) {
+            (false, false) => from.is_ipv6() == addr.is_ipv6(),
+            (v4, v6) => (v4 && addr.is_ipv4()) || (v6 && addr.is_ipv6()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        /// the peer listens on the port the query came from, for peers behind NAT
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    /// item stored at the target, mutable items only if their sequence
+    /// number is higher than `seq` (BEP 44)
+    Get {
+        target: [u8; 20],
+        seq: Option<i64>,
+    },
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        /// replace the mutable item only if its sequence number is this
+        cas: Option<i64>,
+    },
+    /// random info hashes the node stores peers for (BEP 51)
+    SampleInfohashes {
+        target: NodeId,
+    },
+    /// answered with a method unknown error
+    Unknown(String),
+}
+
+/// Every response carries the id of the node, the other fields depend on the
+/// query being answered
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Response {
+    pub id: NodeId,
+    /// sent as `nodes` and `nodes6` for ipv6 nodes
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    /// peers of the info hash, for get_peers
+    pub values: Vec<SocketAddr>,
+    /// to announce or put to the node later, for get_peers and get
+    pub token: Option<Vec<u8>>,
+    /// for get, the salt of mutable items isn't sent back
+    pub item: Option<Item>,
+    /// for sample_infohashes, seconds to wait before sampling the node again
+    pub interval: Option<i64>,
+    /// for sample_infohashes, info hashes the node stores peers for
+    pub num: Option<i64>,
+    pub samples: Vec<[u8; 20]>,
+}
+
+impl Query {
+    fn method(&self) -> &str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+            Query::SampleInfohashes { .. } => "sample_infohashes",
+            Query::Unknown(method) => method,
+        }
+    }
+}
+
+impl KrpcMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+        dict.insert("t".into(), self.transaction.clone().into());
+        match &self.body {
+            KrpcBody::Query { id, query, want } => {
+                let mut args = HashMap::new();
+                args.insert("id".into(), id.0.to_vec().into());
+                let families = [(want.v4, "n4"), (want.v6, "n6")];
+                if want.v4 || want.v6 {
+                    args.insert(
+                        "want".into(),
+                        Bencode::List(
+                            families
+                                .iter()
+                                .filter(|(wanted, _)| *wanted)
+                                .map(|(_, family)| (*family).into())
+                                .collect(),
+                        ),
+                    );
+                }
+                match query {
+                    Query::Ping | Query::Unknown(_) => {}
+                    Query::FindNode { target } | Query::SampleInfohashes { target } => {
+                        args.insert("target".into(), target.0.to_vec().into());
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.insert("info_hash".into(), info_hash.to_vec().into());
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.insert("info_hash".into(), info_hash.to_vec().into());
+                        args.insert("port".into(), (*port as i64).into());
+                        args.insert("implied_port".into(), (*implied_port as i64).into());
+                        args.insert("token".into(), token.clone().into());
+                    }
+                    Query::Get { target, seq } => {
+                        args.insert("target".into(), target.to_vec().into());
+                        if let Some(seq) = seq {
+                            args.insert("seq".into(), (*seq).into());
+                        }
+                    }
+                    Query::Put { token, item, cas } => {
+                        args.insert("token".into(), token.clone().into());
+                        encode_item(&mut args, item, true);
+                        if let Some(cas) = cas {
+                            args.insert("cas".into(), (*cas).into());
+                        }
+                    }
+                }
+                dict.insert("y".into(), "q".into());
+                dict.insert("q".into(), query.method().into());
+                dict.insert("a".into(), Bencode::Dictionary(args));
+            }
+            KrpcBody::Response(response) => {
+                let mut values = HashMap::new();
+                values.insert("id".into(), response.id.0.to_vec().into());
+                let (nodes6, nodes): (Vec<_>, Vec<_>) =
+                    response.nodes.iter().partition(|(_, addr)| addr.is_ipv6());
+                if !nodes.is_empty() {
+                    values.insert("nodes".into(), encode_nodes(nodes).into());
+                }
+                if !nodes6.is_empty() {
+                    values.insert("nodes6".into(), encode_nodes(nodes6).into());
+                }
+                if !response.values.is_empty() {
+                    values.insert(
+                        "values".into(),
+                        Bencode::List(
+                            response
+                                .values
+                                .iter()
+                                .map(|peer| encode_peer(peer).into())
+                                .collect(),
+                        ),
+                    );
+                }
+                if let Some(token) = &response.token {
+                    values.insert("token".into(), token.clone().into());
+                }
+                if let Some(item) = &response.item {
+                    encode_item(&mut values, item, false);
+                }
+                if let Some(interval) = response.interval {
+                    values.insert("interval".into(), interval.into());
+                }
+                if let Some(num) = response.num {
+                    values.insert("num".into(), num.into());
+                }
+                if response.interval.is_some() || !response.samples.is_empty() {
+                    values.insert("samples".into(), response.samples.concat().into());
+                }
+                dict.insert("y".into(), "r".into());
+                dict.insert("r".into(), Bencode::Dictionary(values));
+            }
+            KrpcBody::Error { code, message } => {
+                dict.insert("y".into(), "e".into());
+                dict.insert(
+                    "e".into(),
+                    Bencode::List(vec![(*code).into(), message.as_str().into()]),
+                );
+            }
+        }
+        if let Some(ip) = &self.ip {
+            dict.insert("ip".into(), encode_peer(ip).into());
+        }
+        if self.read_only {
+            dict.insert("ro".into(), 1.into());
+        }
+        Bencode::Dictionary(dict).encode()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let message = Parser::new(data.to_vec()).parse()?;
+        let transaction = message
+            .get("t")
+            .and_then(Bencode::as_bytes)
+            .ok_or_else(|| anyhow!("krpc message without transaction id"))?
+            .to_vec();
+        let body = match message.get("y").and_then(Bencode::as_str) {
+            Some("q") => {
+                let args = message
+                    .get("a")
+                    .ok_or_else(|| anyhow!("query without arguments"))?;
+                let method = message
+                    .get("q")
+                    .and_then(Bencode::as_str)
+                    .ok_or_else(|| anyhow!("query without method"))?;
+                let query = match method {
+                    "ping" => Query::Ping,
+                    "find_node" => Query::FindNode {
+                        target: NodeId(hash(args, "target")?),
+                    },
+                    "get_peers" => Query::GetPeers {
+                        info_hash: hash(args, "info_hash")?,
+                    },
+                    "announce_peer" => Query::AnnouncePeer {
+                        info_hash: hash(args, "info_hash")?,
+                        port: match args.get("port").and_then(Bencode::as_integer) {
+                            Some(port) if (0..=u16::MAX as i64).contains(&port) => port as u16,
+                            _ => bail!("invalid announce port"),
+                        },
+                        implied_port: args.get("implied_port").and_then(Bencode::as_integer)
+                            == Some(1),
+                        token: args
+                            .get("token")
+                            .and_then(Bencode::as_bytes)
+                            .ok_or_else(|| anyhow!("announce without token"))?
+                            .to_vec(),
+                    },
+                    "get" => Query::Get {
+                        target: hash(args, "target")?,
+                        seq: args.get("seq").and_then(Bencode::as_integer),
+                    },
+                    "put" => Query::Put {
+                        token: args
+                            .get("token")
+                            .and_then(Bencode::as_bytes)
+                            .ok_or_else(|| anyhow!("put without token"))?
+                            .to_vec(),
+                        item: decode_item(args)?.ok_or_else(|| anyhow!("put without value"))?,
+                        cas: args.get("cas").and_then(Bencode::as_integer),
+                    },
+                    "sample_infohashes" => Query::SampleInfohashes {
+                        target: NodeId(hash(args, "target")?),
+                    },
+                    method => Query::Unknown(method.to_string()),
+                };
+                let want = args
+                    .get("want")
+                    .and_then(Bencode::as_list)
+                    .unwrap_or_default();
+                KrpcBody::Query {
+                    id: NodeId(hash(args, "id")?),
+                    query,
+                    want: Want {
+                        v4: want.iter().any(|family| family.as_str() == Some("n4")),
+                        v6: want.iter().any(|family| family.as_str() == Some("n6")),
+                    },
+                }
+            }
+            Some("r") => {
+                let values = message
+                    .get("r")
+                    .ok_or_else(|| anyhow!("response without values"))?;
+                KrpcBody::Response(Response {
+                    id: NodeId(hash(values, "id")?),
+                    nodes: match values.get("nodes").and_then(Bencode::as_bytes) {
+                        Some(nodes) => decode_nodes(nodes)?,
+                        None => vec![],
+                    }
+                    .into_iter()
+                    .chain(match values.get("nodes6").and_then(Bencode::as_bytes) {
+                        Some(nodes) => decode_nodes6(nodes)?,
+                        None => vec![],
+                    })
+                    .collect(),
+                    values: values
+                        .get("values")
+                        .and_then(Bencode::as_list)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|peer| peer.as_bytes().and_then(decode_peer))
+                        .collect(),
+                    token: values
+                        .get("token")
+                        .and_then(Bencode::as_bytes)
+                        .map(<[u8]>::to_vec),
+                    item: decode_item(values)?,
+                    interval: values.get("interval").and_then(Bencode::as_integer),
+                    num: values.get("num").and_then(Bencode::as_integer),
+                    samples: match values.get("samples").and_then(Bencode::as_bytes) {
+                        Some(samples) if samples.len().is_multiple_of(20) => samples
+                            .chunks(20)
+                            .map(|sample| sample.try_into().unwrap())
+                            .collect(),
+                        Some(_) => bail!("samples of invalid length"),
+                        None => vec![],
+                    },
+                })
+            }
+            Some("e") => match message.get("e").and_then(Bencode::as_list) {
+                Some([Bencode::Integer(code), Bencode::Bytes(text)]) => KrpcBody::Error {
+                    code: *code,
+                    message: String::from_utf8_lossy(text).into_owned(),
+                },
+                _ => bail!("invalid krpc error"),
+            },
+            _ => bail!("unknown krpc message type"),
+        };
+        let ip = message
+            .get("ip")
+            .and_then(Bencode::as_bytes)
+            .and_then(decode_peer);
+        Ok(Self {
+            transaction,
+            body,
+            ip,
+            read_only: message.get("ro").and_then(Bencode::as_integer) == Some(1),
+        })
+    }
+}
+
+fn encode_item(dict: &mut HashMap<Vec<u8>, Bencode>, item: &Item, with_salt: bool) {
+    dict.insert("v".into(), item.value.clone());
+    if let Some(mutable) = &item.mutable {
+        dict.insert("k".into(), mutable.key.to_vec().into());
+        dict.insert("seq".into(), mutable.seq.into());
+        dict.insert("sig".into(), mutable.signature.to_vec().into());
+        if with_salt && !mutable.salt.is_empty() {
+            dict.insert("salt".into(), mutable.salt.clone().into());
+        }
+    }
+}
+
+/// None without a value, items with a key are mutable
+fn decode_item(dict: &Bencode) -> Result<Option<Item>> {
+    let value = match dict.get("v") {
+        Some(value) => value.clone(),
+        None => return Ok(None),
+    };
+    let mutable = match dict.get("k") {
+        Some(key) => Some(MutableItem {
+            key: key
+                .as_bytes()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| anyhow!("invalid public key"))?,
+            salt: dict
+                .get("salt")
+                .and_then(Bencode::as_bytes)
+                .unwrap_or_default()
+                .to_vec(),
+            seq: dict
+                .get("seq")
+                .and_then(Bencode::as_integer)
+                .ok_or_else(|| anyhow!("mutable item without seq"))?,
+            signature: dict
+                .get("sig")
+                .and_then(Bencode::as_bytes)
+                .and_then(|signature| signature.try_into().ok())
+                .ok_or_else(|| anyhow!("invalid signature"))?,
+        }),
+        None => None,
+    };
+    Ok(Some(Item { value, mutable }))
+}
+
+fn hash(dict: &Bencode, key: &str) -> Result<[u8; 20]> {
+    dict.get(key)
+        .and_then(Bencode::as_bytes)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("invalid or missing {}", key))
+}
+
+/// 6 bytes, ipv4 then port, or 18 bytes for ipv6. IPv4 addresses mapped
+/// to IPv6 ones are sent as IPv4.
+pub fn encode_peer(addr: &SocketAddr) -> Vec<u8> {
+    let addr = listen::unmapped(*addr);
+    let mut bytes = match addr {
+        SocketAddr::V4(addr) => addr.ip().octets().to_vec(),
+        SocketAddr::V6(addr) => addr.ip().octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    match bytes.len() {
+        6 => Some(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+            u16::from_be_bytes([bytes[4], bytes[5]]),
+        ))),
+        18 => {
+            let ip: [u8; 16] = bytes[..16].try_into().ok()?;
+            Some(listen::unmapped(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(ip),
+                u16::from_be_bytes([bytes[16], bytes[17]]),
+                0,
+                0,
+            ))))
+        }
+        _ => None,
+    }
+}
+
+/// the nodes of a compact `nodes` or `nodes6` list, which hold one family
+pub fn encode_nodes<'a>(nodes: impl IntoIterator<Item = &'a (NodeId, SocketAddr)>) -> Vec<u8> {
+    let mut bytes = vec![];
+    for (id, addr) in nodes {
+        bytes.extend_from_slice(&id.0);
+        bytes.extend_from_slice(&encode_peer(addr));
+    }
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Result<Vec<(NodeId, SocketAddr)>> {
+    decode_compact_nodes(bytes, COMPACT_NODE_LEN)
+}
+
+pub fn decode_nodes6(bytes: &[u8]) -> Result<Vec<(NodeId, SocketAddr)>> {
+    decode_compact_nodes(bytes, COMPACT_NODE6_LEN)
+}
+
+fn decode_compact_nodes(bytes: &[u8], node_len: usize) -> Result<Vec<(NodeId, SocketAddr)>> {
+    if !bytes.len().is_multiple_of(node_len) {
+        bail!("compact nodes of invalid length {}", bytes.len());
+    }
+    Ok(bytes
+        .chunks(node_len)
+        .filter_map(|node| {
+            let id = NodeId(node[..20].try_into().ok()?);
+            Some((id, decode_peer(&node[20..])?))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    fn round_trip(body: KrpcBody) -> Result<()> {
+        let message = KrpcMessage {
+            transaction: b"aa".to_vec(),
+            body,
+            ip: Some(SocketAddr::from(([1, 2, 3, 4], 5))),
+            read_only: true,
+        };
+        assert_eq!(KrpcMessage::decode(&message.encode())?, message);
+        Ok(())
+    }
+
+    #[test]
+    fn bep5_example() -> Result<()> {
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let message = KrpcMessage::decode(ping)?;
+        assert_eq!(
+            message.body,
+            KrpcBody::Query {
+                id: NodeId(*b"abcdefghij0123456789"),
+                query: Query::Ping,
+                want: Want::default(),
+            }
+        );
+        assert_eq!(message.encode(), ping.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn messages_round_trip() -> Result<()> {
+        let id = NodeId([1; 20]);
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        for query in [
+            Query::Ping,
+            Query::FindNode {
+                target: NodeId([2; 20]),
+            },
+            Query::GetPeers { info_hash: [3; 20] },
+            Query::AnnouncePeer {
+                info_hash: [3; 20],
+                port: 6881,
+                implied_port: true,
+                token: b"token".to_vec(),
+            },
+            Query::Get {
+                target: [3; 20],
+                seq: Some(2),
+            },
+            Query::Put {
+                token: b"token".to_vec(),
+                item: Item::mutable(
+                    Bencode::Bytes(b"value".to_vec()),
+                    &SigningKey::from_bytes(&[1; 32]),
+                    b"salt".to_vec(),
+                    3,
+                ),
+                cas: Some(2),
+            },
+            Query::SampleInfohashes {
+                target: NodeId([4; 20]),
+            },
+            Query::Unknown("vote".into()),
+        ] {
+            round_trip(KrpcBody::Query {
+                id,
+                query,
+                want: Want::default(),
+            })?;
+        }
+        round_trip(KrpcBody::Query {
+            id,
+            query: Query::GetPeers { info_hash: [3; 20] },
+            want: Want::BOTH,
+        })?;
+        let peer6 = "[2001:db8::1]:6881".parse()?;
+        round_trip(KrpcBody::Response(Response {
+            id,
+            nodes: vec![(NodeId([4; 20]), peer), (NodeId([5; 20]), peer6)],
+            values: vec![peer, peer6],
+            token: Some(b"token".to_vec()),
+            item: Some(Item::immutable(Bencode::Integer(1))),
+            ..Default::default()
+        }))?;
+        round_trip(KrpcBody::Response(Response {
+            id,
+            interval: Some(60),
+            num: Some(2),
+            samples: vec![[6; 20], [7; 20]],
+            ..Default::default()
+        }))?;
+        round_trip(KrpcBody::Error {
+            code: ERROR_METHOD_UNKNOWN,
+            message: "Method Unknown".into(),
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_messages() {
+        assert!(KrpcMessage::decode(b"d1:y1:qe").is_err());
+        assert!(KrpcMessage::decode(b"d1:t2:aa1:y1:q1:q4:ping1:ad2:id3:abcee").is_err());
+        assert!(decode_nodes(&[0; 25]).is_err());
+        assert!(decode_nodes6(&[0; 26]).is_err());
+    }
+
+    #[test]
+    fn compacts_mapped_addresses() {
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:6881".parse().unwrap();
+        let encoded = encode_peer(&mapped);
+        assert_eq!(encoded, [1, 2, 3, 4, 0x1a, 0xe1]);
+        assert_eq!(decode_peer(&encoded), Some("1.2.3.4:6881".parse().unwrap()));
+        let mut long = vec![0; 10];
+        long.extend_from_slice(&[0xff, 0xff, 1, 2, 3, 4, 0x1a, 0xe1]);
+        assert_eq!(decode_peer(&long), Some("1.2.3.4:6881".parse().unwrap()));
+    }
+
+    #[test]
+    fn wanted_families() {
+        let v4 = SocketAddr::from(([1, 2, 3, 4], 5));
+        let v6 = "[2001:db8::1]:6881".parse().unwrap();
+        assert!(Want::default().wants(&v4, &v4));
+        assert!(!Want::default().wants(&v4, &v6));
+        assert!(Want::default().wants(&v6, &v6));
+        assert!(Want::BOTH.wants(&v4, &v6));
+        let v6_only = Want {
+            v4: false,
+            v6: true,
+        };
+        assert!(!v6_only.wants(&v4, &v4));
+        assert!(v6_only.wants(&v4, &v6));
+    }
+}
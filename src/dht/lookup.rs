@@ -0,0 +1,241 @@
+use super::routing::{NodeId, K};
+use std::net::SocketAddr;
+
+/// queries in flight at once for a lookup
+pub const ALPHA: usize = 3;
+/// candidates kept per lookup, the farthest ones are dropped
+const MAX_CANDIDATES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    New,
+    Queried,
+    Answered,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    /// None for the routers we bootstrap from, their id doesn't matter
+    id: Option<NodeId>,
+    addr: SocketAddr,
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupKind {
+    /// looks for peers of the target info hash
+    GetPeers,
+    /// looks for the nodes closest to the target, to fill the routing table
+    FindNode,
+    /// looks for the item stored at the target, or for the nodes to store
+    /// it on
+    Get,
+}
+
+/// Iterative lookup: query the closest nodes we know, then the closer nodes
+/// they return, until the `K` closest nodes all answered or failed
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    target: NodeId,
+    pub kind: LookupKind,
+    /// closest to the target first
+    candidates: Vec<Candidate>,
+    /// announce to the closest nodes when done, the inner None announces
+    /// the port the packets come from
+    pub announce: Option<Option<u16>>,
+}
+
+impl Lookup {
+    pub fn new(
+        target: NodeId,
+        kind: LookupKind,
+        nodes: impl IntoIterator<Item = (NodeId, SocketAddr)>,
+        announce: Option<Option<u16>>,
+    ) -> Self {
+        let mut lookup = Self {
+            target,
+            kind,
+            candidates: vec![],
+            announce,
+        };
+        lookup.add_nodes(nodes);
+        lookup
+    }
+
+    /// nodes of unknown id queried first, they don't count among the
+    /// closest nodes
+    pub fn add_routers(&mut self, routers: impl IntoIterator<Item = SocketAddr>) {
+        for addr in routers {
+            if !self
+                .candidates
+                .iter()
+                .any(|candidate| candidate.addr == addr)
+            {
+                self.candidates.insert(
+                    0,
+                    Candidate {
+                        id: None,
+                        addr,
+                        state: State::New,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn add_nodes(&mut self, nodes: impl IntoIterator<Item = (NodeId, SocketAddr)>) {
+        for (id, addr) in nodes {
+            if self
+                .candidates
+                .iter()
+                .any(|candidate| candidate.id == Some(id) || candidate.addr == addr)
+            {
+                continue;
+            }
+            let distance = id.distance(&self.target);
+            let position = self.candidates.partition_point(|candidate| {
+                candidate
+                    .id
+                    .is_none_or(|other| other.distance(&self.target) < distance)
+            });
+            self.candidates.insert(
+                position,
+                Candidate {
+                    id: Some(id),
+                    addr,
+                    state: State::New,
+                },
+            );
+        }
+        self.candidates.truncate(MAX_CANDIDATES);
+    }
+
+    /// the routers and the `K` closest candidates that didn't fail
+    fn closest(&mut self) -> impl Iterator<Item = &mut Candidate> {
+        let mut nodes = 0;
+        self.candidates
+            .iter_mut()
+            .filter(|candidate| candidate.state != State::Failed)
+            .take_while(move |candidate| {
+                nodes += candidate.id.is_some() as usize;
+                nodes <= K
+            })
+    }
+
+    /// nodes to query now, they are marked as queried
+    pub fn next_queries(&mut self) -> Vec<SocketAddr> {
+        let in_flight = self.in_flight();
+        let mut queries = vec![];
+        for candidate in self.closest() {
+            if in_flight + queries.len() >= ALPHA {
+                break;
+            }
+            if candidate.state == State::New {
+                candidate.state = State::Queried;
+                queries.push(candidate.addr);
+            }
+        }
+        queries
+    }
+
+    fn in_flight(&self) -> usize {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state == State::Queried)
+            .count()
+    }
+
+    fn set_state(&mut self, addr: SocketAddr, state: State) {
+        if let Some(candidate) = self
+            .candidates
+            .iter_mut()
+            .find(|candidate| candidate.addr == addr && candidate.state == State::Queried)
+        {
+            candidate.state = state;
+        }
+    }
+
+    /// the node answered with `nodes` closer to the target
+    pub fn answered(&mut self, addr: SocketAddr, nodes: &[(NodeId, SocketAddr)]) {
+        self.set_state(addr, State::Answered);
+        self.add_nodes(nodes.iter().copied());
+    }
+
+    pub fn failed(&mut self, addr: SocketAddr) {
+        self.set_state(addr, State::Failed);
+    }
+
+    pub fn is_done(&mut self) -> bool {
+        self.in_flight() == 0
+            && self
+                .closest()
+                .all(|candidate| candidate.state != State::New)
+    }
+
+    /// the closest nodes that answered, to announce to
+    pub fn closest_answered(&self) -> Vec<SocketAddr> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.id.is_some() && candidate.state == State::Answered)
+            .take(K)
+            .map(|candidate| candidate.addr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first: u8) -> (NodeId, SocketAddr) {
+        let mut id = [0; 20];
+        id[0] = first;
+        (NodeId(id), SocketAddr::from(([127, 0, 0, 1], first as u16)))
+    }
+
+    #[test]
+    fn converges_on_closest_nodes() {
+        let mut lookup = Lookup::new(
+            node(0).0,
+            LookupKind::GetPeers,
+            (1..=4).map(|first| node(first * 16)),
+            None,
+        );
+
+        let first = lookup.next_queries();
+        assert_eq!(first, vec![node(16).1, node(32).1, node(48).1]);
+        assert!(lookup.next_queries().is_empty());
+
+        lookup.answered(node(16).1, &[node(1), node(2)]);
+        lookup.failed(node(32).1);
+        assert_eq!(lookup.next_queries(), vec![node(1).1, node(2).1]);
+        lookup.answered(node(1).1, &[]);
+        lookup.answered(node(2).1, &[node(1)]);
+        lookup.answered(node(48).1, &[]);
+        assert_eq!(lookup.next_queries(), vec![node(64).1]);
+        assert!(!lookup.is_done());
+        lookup.answered(node(64).1, &[]);
+
+        assert!(lookup.is_done());
+        assert_eq!(
+            lookup.closest_answered(),
+            vec![node(1).1, node(2).1, node(16).1, node(48).1, node(64).1]
+        );
+    }
+
+    #[test]
+    fn routers_are_queried_first() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let mut lookup = Lookup::new(node(0).0, LookupKind::FindNode, vec![node(16)], None);
+        lookup.add_routers(vec![router]);
+
+        assert_eq!(lookup.next_queries(), vec![router, node(16).1]);
+        lookup.answered(router, &[node(1)]);
+        lookup.answered(node(16).1, &[]);
+        assert_eq!(lookup.next_queries(), vec![node(1).1]);
+        lookup.answered(node(1).1, &[]);
+        assert!(lookup.is_done());
+        assert_eq!(lookup.closest_answered(), vec![node(1).1, node(16).1]);
+    }
+}
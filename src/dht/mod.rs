@@ -0,0 +1,2166 @@
+pub mod indexer;
+pub mod item;
+pub mod krpc;
+pub mod lookup;
+pub mod mutable;
+pub mod routing;
+pub mod security;
+pub mod state;
+pub mod throttle;
+
+use crate::magnet::MutableMagnet;
+use crate::metainfo::Metainfo;
+use crate::peer::PeerSource;
+use crate::runtime;
+use crate::torrent::TorrentHandle;
+use anyhow::Result;
+use indexer::{Indexer, IndexerEvent};
+use item::{Item, MAX_SALT_SIZE, MAX_VALUE_SIZE};
+use krpc::{
+    KrpcBody, KrpcMessage, Query, Response, Want, ERROR_CAS_MISMATCH, ERROR_INVALID_SIGNATURE,
+    ERROR_MESSAGE_TOO_BIG, ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL, ERROR_SALT_TOO_BIG,
+    ERROR_SEQ_TOO_LOW,
+};
+use lookup::{Lookup, LookupKind};
+use mutable::MutableTorrent;
+use routing::{random_bytes, NodeId, RoutingTable, K};
+use sha1::{Digest, Sha1};
+use state::DhtState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use throttle::{Throttle, TokenBucket};
+use tokio::sync::watch;
+use tokio::time;
+
+/// queries without an answer after this long count as failed
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// tokens stay valid for one to two rotations
+const SECRET_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// peers that don't announce again within this long are forgotten
+const PEER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// items that aren't put again within this long are forgotten
+const ITEM_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+/// info hashes returned to sample_infohashes are picked again this often
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// info hashes returned to sample_infohashes, to fit in a udp packet
+const MAX_SAMPLES: usize = 20;
+/// peers returned for an info hash, a response has to fit in a udp packet
+const MAX_VALUES: usize = 50;
+/// torrents look up and announce their info hash again this often
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// events of a `DhtTask` nobody polls are dropped past this many
+const TASK_EVENTS: usize = 1024;
+/// bootstrapping starts over this often while the routing table is empty
+const BOOTSTRAP_RETRY: Duration = Duration::from_secs(60);
+/// nodes at different ips that have to agree on our external ip before we
+/// trust it
+const EXTERNAL_IP_VOTES: usize = 5;
+
+/// well known nodes that only exist to let new nodes join the DHT
+pub const DEFAULT_ROUTERS: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "dht.libtorrent.org:25401",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DhtEvent {
+    /// a node answered a get_peers query with peers of the info hash
+    Peers {
+        info_hash: [u8; 20],
+        peers: Vec<SocketAddr>,
+    },
+    /// answer to one of our queries
+    Response {
+        from: SocketAddr,
+        query: Query,
+        response: Box<Response>,
+    },
+    /// one of our queries timed out or was answered with an error
+    QueryFailed { to: SocketAddr, query: Query },
+    /// a get_peers lookup reached the closest nodes to the info hash, and
+    /// announced to them if asked to
+    LookupDone { info_hash: [u8; 20] },
+    /// the lookup of our own id finished, `nodes` is the size of the routing table
+    Bootstrapped { nodes: usize },
+    /// a `get_item` or `put_item` lookup finished, with the valid item of
+    /// highest sequence number the nodes returned
+    Item {
+        target: [u8; 20],
+        item: Option<Item>,
+    },
+    /// the item of a `put_item` was sent to `nodes` of the closest nodes
+    PutDone { target: [u8; 20], nodes: usize },
+    /// a followed torrent was found or its publisher released a new version,
+    /// whose metadata has to be fetched to switch to it
+    TorrentUpdated {
+        target: [u8; 20],
+        previous: Option<[u8; 20]>,
+        info_hash: [u8; 20],
+    },
+}
+
+/// packets waiting to be sent past this many are dropped
+const MAX_OUTBOX: usize = 1024;
+
+/// Bounds on what other nodes can make us do, so a node can't be used to
+/// flood others with answers or exhaust our memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhtLimits {
+    /// packets handled per second, the others are dropped
+    pub packets_in: u32,
+    /// packets sent per second, the others wait
+    pub packets_out: u32,
+    /// queries answered per second for each ip, the others are ignored
+    pub queries_per_ip: u32,
+    /// info hashes we store peers for, the one with the fewest peers makes
+    /// room for a new one
+    pub max_info_hashes: usize,
+    /// peers stored per info hash, the oldest makes room for a new one
+    pub max_peers: usize,
+    /// items stored, the oldest makes room for a new one
+    pub max_items: usize,
+}
+
+impl Default for DhtLimits {
+    fn default() -> Self {
+        Self {
+            packets_in: 500,
+            packets_out: 500,
+            queries_per_ip: 20,
+            max_info_hashes: 2000,
+            max_peers: 200,
+            max_items: 700,
+        }
+    }
+}
+
+/// Snapshot of a node, to check it is healthy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DhtStats {
+    /// in the routing table
+    pub nodes: usize,
+    /// nodes of each bucket, by the length of the prefix they share with our
+    /// id, up to the last bucket with nodes
+    pub buckets: Vec<usize>,
+    pub lookups: usize,
+    /// queries waiting for an answer
+    pub queries: usize,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// over the limits, in either direction
+    pub packets_dropped: u64,
+    /// info hashes we store peers for, and those peers
+    pub info_hashes: usize,
+    pub peers: usize,
+    pub items: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Transaction {
+    addr: SocketAddr,
+    query: Query,
+    sent: Instant,
+    /// sent by the lookup of the info hash or target of the query
+    lookup: bool,
+}
+
+/// info hash we announce periodically, see `Dht::announce`
+#[derive(Debug, Clone)]
+struct Announce {
+    port: Option<u16>,
+    next: Instant,
+}
+
+/// item we look up, see `Dht::get_item`
+#[derive(Debug, Clone)]
+struct ItemLookup {
+    /// mutable items are returned without their salt
+    salt: Vec<u8>,
+    found: Option<Item>,
+    /// put to the closest nodes when the lookup is done, with the sequence
+    /// number it replaces
+    put: Option<(Item, Option<i64>)>,
+}
+
+/// Mainline DHT node (BEP 5). It answers the queries of other nodes, keeps
+/// the peers announced and the items put to it and sends our own queries. Packets go through
+/// `handle_packet` and `poll_packet`, `DhtTask` runs it on a udp socket.
+#[derive(Debug)]
+pub struct Dht {
+    id: NodeId,
+    table: RoutingTable,
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    secret_rotated: Instant,
+    /// peers announced to us for each info hash, with the time of their last announce
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+    /// info hashes returned to sample_infohashes and when they were picked
+    samples: Vec<[u8; 20]>,
+    samples_picked: Option<Instant>,
+    /// items put to us by target (BEP 44), with the time of their last put
+    items: HashMap<[u8; 20], (Item, Instant)>,
+    /// tokens received from the nodes we sent get_peers or get to, to
+    /// announce or put to them
+    tokens: HashMap<(SocketAddr, [u8; 20]), Vec<u8>>,
+    transactions: HashMap<Vec<u8>, Transaction>,
+    next_transaction: u16,
+    /// by target
+    lookups: HashMap<[u8; 20], Lookup>,
+    announces: HashMap<[u8; 20], Announce>,
+    /// by target, along with their lookup
+    item_lookups: HashMap<[u8; 20], ItemLookup>,
+    /// by target of their item
+    mutable_torrents: HashMap<[u8; 20], MutableTorrent>,
+    /// bootstrapped from again if the routing table empties
+    routers: Vec<SocketAddr>,
+    last_bootstrap: Option<Instant>,
+    external_ip: Option<IpAddr>,
+    /// see `set_read_only`
+    read_only: bool,
+    /// whether another node runs over the other address family, see
+    /// `set_dual_stack`
+    dual_stack: bool,
+    /// good nodes of the other node, returned to queries that want them
+    other_family: Vec<(NodeId, SocketAddr)>,
+    /// nodes of the other family we were told about, for the other node
+    other_family_found: VecDeque<(NodeId, SocketAddr)>,
+    /// ips of the nodes that told us each external ip
+    ip_votes: HashMap<IpAddr, HashSet<IpAddr>>,
+    outbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    events: VecDeque<DhtEvent>,
+    limits: DhtLimits,
+    inbound: TokenBucket,
+    outbound: TokenBucket,
+    queries_per_ip: Throttle<IpAddr>,
+    packets_in: u64,
+    packets_out: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    packets_dropped: u64,
+}
+
+impl Dht {
+    pub fn new(id: NodeId) -> Self {
+        let mut secret = [0; 20];
+        random_bytes(&mut secret);
+        let limits = DhtLimits::default();
+        let now = Instant::now();
+        Self {
+            id,
+            table: RoutingTable::new(id),
+            secret,
+            previous_secret: secret,
+            secret_rotated: now,
+            peers: HashMap::new(),
+            samples: vec![],
+            samples_picked: None,
+            items: HashMap::new(),
+            tokens: HashMap::new(),
+            transactions: HashMap::new(),
+            next_transaction: 0,
+            lookups: HashMap::new(),
+            announces: HashMap::new(),
+            item_lookups: HashMap::new(),
+            mutable_torrents: HashMap::new(),
+            routers: vec![],
+            last_bootstrap: None,
+            external_ip: None,
+            read_only: false,
+            dual_stack: false,
+            other_family: vec![],
+            other_family_found: VecDeque::new(),
+            ip_votes: HashMap::new(),
+            outbox: VecDeque::new(),
+            events: VecDeque::new(),
+            limits,
+            inbound: TokenBucket::new(limits.packets_in, now),
+            outbound: TokenBucket::new(limits.packets_out, now),
+            queries_per_ip: Throttle::new(limits.queries_per_ip),
+            packets_in: 0,
+            packets_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            packets_dropped: 0,
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    pub fn set_limits(&mut self, limits: DhtLimits) {
+        let now = Instant::now();
+        self.limits = limits;
+        self.inbound = TokenBucket::new(limits.packets_in, now);
+        self.outbound = TokenBucket::new(limits.packets_out, now);
+        self.queries_per_ip = Throttle::new(limits.queries_per_ip);
+    }
+
+    /// our ip as seen by other nodes, see `set_external_ip`
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    /// picks a new id derived from the ip if ours doesn't match it, other
+    /// nodes may ignore us otherwise (BEP 42). Also learned from the nodes
+    /// that answer our queries.
+    pub fn set_external_ip(&mut self, ip: IpAddr) {
+        self.external_ip = Some(ip);
+        if !security::is_secure_id(&self.id, ip) {
+            self.id = security::secure_id(ip);
+            self.table.set_id(self.id);
+        }
+    }
+
+    /// only looks things up, for nodes behind restrictive NATs or on metered
+    /// connections: queries from other nodes are ignored and ours tell
+    /// them to leave us out of their routing tables (BEP 43)
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// only keeps nodes with ids derived from their ip in the routing table
+    pub fn set_enforce_secure_ids(&mut self, enforce: bool) {
+        self.table.set_enforce_secure_ids(enforce);
+    }
+
+    /// with nodes running over both ipv4 and ipv6 (BEP 32), our queries ask
+    /// for nodes of both families and the ones of the other family are
+    /// handed to the other node with `poll_other_family_node`
+    pub fn set_dual_stack(&mut self, dual_stack: bool) {
+        self.dual_stack = dual_stack;
+        if !dual_stack {
+            self.other_family.clear();
+            self.other_family_found.clear();
+        }
+    }
+
+    /// nodes of the node running over the other address family, returned
+    /// along with ours to queries that want both
+    pub fn set_other_family_nodes(&mut self, nodes: Vec<(NodeId, SocketAddr)>) {
+        self.other_family = nodes;
+    }
+
+    /// pings a node we learned of elsewhere, it enters the routing table if
+    /// it answers
+    pub fn add_node(&mut self, id: NodeId, addr: SocketAddr) {
+        if id != self.id && !self.table.nodes().any(|node| node.addr == addr) {
+            self.ping(addr);
+        }
+    }
+
+    /// next node of the other address family a node told us about
+    pub fn poll_other_family_node(&mut self) -> Option<(NodeId, SocketAddr)> {
+        self.other_family_found.pop_front()
+    }
+
+    fn vote_external_ip(&mut self, voter: IpAddr, ip: IpAddr) {
+        if Some(ip) == self.external_ip {
+            return;
+        }
+        let voters = self.ip_votes.entry(ip).or_default();
+        voters.insert(voter);
+        if voters.len() >= EXTERNAL_IP_VOTES {
+            self.ip_votes.clear();
+            self.set_external_ip(ip);
+        }
+    }
+
+    /// next packet to send, None while over the outgoing rate
+    pub fn poll_packet(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        if self.outbox.is_empty() || !self.outbound.take(Instant::now()) {
+            return None;
+        }
+        let (addr, packet) = self.outbox.pop_front()?;
+        self.packets_out += 1;
+        self.bytes_out += packet.len() as u64;
+        Some((addr, packet))
+    }
+
+    pub fn poll_event(&mut self) -> Option<DhtEvent> {
+        self.events.pop_front()
+    }
+
+    /// peers announced to us for the info hash
+    pub fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        self.peers
+            .get(info_hash)
+            .map(|peers| peers.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn stats(&self) -> DhtStats {
+        let mut buckets: Vec<_> = self.table.buckets().iter().map(Vec::len).collect();
+        while buckets.last() == Some(&0) {
+            buckets.pop();
+        }
+        DhtStats {
+            nodes: self.table.len(),
+            buckets,
+            lookups: self.lookups.len(),
+            queries: self.transactions.len(),
+            packets_in: self.packets_in,
+            packets_out: self.packets_out,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            packets_dropped: self.packets_dropped,
+            info_hashes: self.peers.len(),
+            peers: self.peers.values().map(HashMap::len).sum(),
+            items: self.items.len(),
+        }
+    }
+
+    /// item put to us at the target
+    pub fn stored_item(&self, target: &[u8; 20]) -> Option<&Item> {
+        self.items.get(target).map(|(item, _)| item)
+    }
+
+    pub fn ping(&mut self, addr: SocketAddr) {
+        self.send_query(addr, Query::Ping);
+    }
+
+    pub fn find_node(&mut self, addr: SocketAddr, target: NodeId) {
+        self.send_query(addr, Query::FindNode { target });
+    }
+
+    pub fn get_peers(&mut self, addr: SocketAddr, info_hash: [u8; 20]) {
+        self.send_query(addr, Query::GetPeers { info_hash });
+    }
+
+    /// asks the node for some of the info hashes it stores peers for, and
+    /// for its nodes closest to the target (BEP 51)
+    pub fn sample_infohashes(&mut self, addr: SocketAddr, target: NodeId) {
+        self.send_query(addr, Query::SampleInfohashes { target });
+    }
+
+    /// tells the node we are a peer of the info hash, fails if the node
+    /// didn't give us a token in a get_peers answer first. `port` None
+    /// announces the port the packets come from.
+    pub fn announce_peer(
+        &mut self,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        port: Option<u16>,
+    ) -> bool {
+        let token = match self.tokens.get(&(addr, info_hash)) {
+            Some(token) => token.clone(),
+            None => return false,
+        };
+        self.send_query(
+            addr,
+            Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or(0),
+                implied_port: port.is_none(),
+                token,
+            },
+        );
+        true
+    }
+
+    /// id and good nodes to save for the next run
+    pub fn state(&self) -> DhtState {
+        DhtState {
+            id: self.id,
+            nodes: self
+                .table
+                .nodes()
+                .filter(|node| !node.is_bad())
+                .map(|node| (node.id, node.addr))
+                .collect(),
+        }
+    }
+
+    /// joins the DHT by looking up our own id, starting from `routers` and
+    /// from `nodes` saved by a previous run. Called again while bootstrapping,
+    /// the nodes are added to the running lookup.
+    pub fn bootstrap(
+        &mut self,
+        routers: Vec<SocketAddr>,
+        nodes: Vec<(NodeId, SocketAddr)>,
+        now: Instant,
+    ) {
+        for router in &routers {
+            if !self.routers.contains(router) {
+                self.routers.push(*router);
+            }
+        }
+        self.last_bootstrap = Some(now);
+        let target = self.id.0;
+        if !self.lookups.contains_key(&target) {
+            let closest = self.closest_nodes(&self.id);
+            let lookup = Lookup::new(self.id, LookupKind::FindNode, closest, None);
+            self.lookups.insert(target, lookup);
+        }
+        let lookup = self.lookups.get_mut(&target).unwrap();
+        lookup.add_nodes(nodes);
+        lookup.add_routers(routers);
+        self.step_lookup(target);
+    }
+
+    /// iterative get_peers lookup for the info hash, peers are reported with
+    /// `DhtEvent::Peers` as nodes return them
+    pub fn lookup(&mut self, info_hash: [u8; 20]) {
+        self.start_lookup(info_hash, None);
+    }
+
+    /// looks up the info hash and announces it to the closest nodes now and
+    /// every `ANNOUNCE_INTERVAL` until `stop_announcing`. `port` None
+    /// announces the port the packets come from.
+    pub fn announce(&mut self, info_hash: [u8; 20], port: Option<u16>, now: Instant) {
+        self.announces
+            .insert(info_hash, Announce { port, next: now });
+        self.refresh_announces(now);
+    }
+
+    pub fn stop_announcing(&mut self, info_hash: &[u8; 20]) {
+        self.announces.remove(info_hash);
+        if let Some(lookup) = self.lookups.get_mut(info_hash) {
+            lookup.announce = None;
+        }
+    }
+
+    /// looks up the item stored at the target, reported with
+    /// `DhtEvent::Item`. The target is `Item::target` of immutable items and
+    /// `item::mutable_target` of mutable ones, which need the salt they were
+    /// signed with.
+    pub fn get_item(&mut self, target: [u8; 20], salt: Vec<u8>) {
+        self.start_item_lookup(
+            target,
+            ItemLookup {
+                salt,
+                found: None,
+                put: None,
+            },
+        );
+    }
+
+    /// stores the item on the nodes closest to its target, reported with
+    /// `DhtEvent::PutDone`. Nodes only replace a mutable item with one of
+    /// higher sequence number, and with `cas` only if theirs has that
+    /// sequence number.
+    pub fn put_item(&mut self, item: Item, cas: Option<i64>) {
+        let salt = item
+            .mutable
+            .as_ref()
+            .map(|mutable| mutable.salt.clone())
+            .unwrap_or_default();
+        self.start_item_lookup(
+            item.target(),
+            ItemLookup {
+                salt,
+                found: None,
+                put: Some((item, cas)),
+            },
+        );
+    }
+
+    /// resolves the torrent published under the key and salt and checks for
+    /// new versions until `unfollow_torrent`, reported with
+    /// `DhtEvent::TorrentUpdated` (BEP 46)
+    pub fn follow_torrent(&mut self, public_key: [u8; 32], salt: Vec<u8>, now: Instant) {
+        let torrent = MutableTorrent::new(public_key, salt, now);
+        self.mutable_torrents.insert(torrent.target(), torrent);
+        self.refresh_mutable_torrents(now);
+    }
+
+    pub fn unfollow_torrent(&mut self, target: &[u8; 20]) {
+        self.mutable_torrents.remove(target);
+    }
+
+    /// current info hash of a followed torrent
+    pub fn mutable_torrent(&self, target: &[u8; 20]) -> Option<&MutableTorrent> {
+        self.mutable_torrents.get(target)
+    }
+
+    /// fetches the items of the followed torrents that are due, like
+    /// announces they wait for the routing table to have nodes
+    fn refresh_mutable_torrents(&mut self, now: Instant) {
+        if self.table.is_empty() {
+            return;
+        }
+        let due: Vec<_> = self
+            .mutable_torrents
+            .iter_mut()
+            .filter_map(|(target, torrent)| {
+                torrent
+                    .poll_update(now)
+                    .then(|| (*target, torrent.salt.clone()))
+            })
+            .collect();
+        for (target, salt) in due {
+            self.get_item(target, salt);
+        }
+    }
+
+    fn start_item_lookup(&mut self, target: [u8; 20], item_lookup: ItemLookup) {
+        if let Some(running) = self.item_lookups.get_mut(&target) {
+            if item_lookup.put.is_some() {
+                running.put = item_lookup.put;
+            }
+            return;
+        }
+        self.item_lookups.insert(target, item_lookup);
+        let closest = self.closest_nodes(&NodeId(target));
+        let lookup = Lookup::new(NodeId(target), LookupKind::Get, closest, None);
+        self.lookups.insert(target, lookup);
+        self.step_lookup(target);
+    }
+
+    /// starts the announces that are due, the ones made before we know any
+    /// node are retried on the next tick
+    fn refresh_announces(&mut self, now: Instant) {
+        if self.table.is_empty() {
+            return;
+        }
+        let due: Vec<_> = self
+            .announces
+            .iter_mut()
+            .filter(|(_, announce)| announce.next <= now)
+            .map(|(info_hash, announce)| {
+                announce.next = now + ANNOUNCE_INTERVAL;
+                (*info_hash, announce.port)
+            })
+            .collect();
+        for (info_hash, port) in due {
+            self.start_lookup(info_hash, Some(port));
+        }
+    }
+
+    fn start_lookup(&mut self, info_hash: [u8; 20], announce: Option<Option<u16>>) {
+        if let Some(lookup) = self.lookups.get_mut(&info_hash) {
+            lookup.announce = announce.or(lookup.announce);
+            return;
+        }
+        let target = NodeId(info_hash);
+        let closest = self.closest_nodes(&target);
+        let lookup = Lookup::new(target, LookupKind::GetPeers, closest, announce);
+        self.lookups.insert(info_hash, lookup);
+        self.step_lookup(info_hash);
+    }
+
+    /// sends the next queries of the lookup, or announces when it is done
+    fn step_lookup(&mut self, target: [u8; 20]) {
+        let lookup = match self.lookups.get_mut(&target) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        let query = match lookup.kind {
+            LookupKind::GetPeers => Query::GetPeers { info_hash: target },
+            LookupKind::FindNode => Query::FindNode {
+                target: NodeId(target),
+            },
+            LookupKind::Get => Query::Get { target, seq: None },
+        };
+        for addr in lookup.next_queries() {
+            self.query(addr, query.clone(), true);
+        }
+        if !self.lookups.get_mut(&target).unwrap().is_done() {
+            return;
+        }
+        let lookup = self.lookups.remove(&target).unwrap();
+        match lookup.kind {
+            LookupKind::GetPeers => {
+                if let Some(port) = lookup.announce {
+                    for addr in lookup.closest_answered() {
+                        self.announce_peer(addr, target, port);
+                    }
+                }
+                self.events
+                    .push_back(DhtEvent::LookupDone { info_hash: target });
+            }
+            LookupKind::FindNode => {
+                self.events.push_back(DhtEvent::Bootstrapped {
+                    nodes: self.table.len(),
+                });
+                // announces made while the table was empty can start now
+                self.refresh_announces(Instant::now());
+                self.refresh_mutable_torrents(Instant::now());
+            }
+            LookupKind::Get => {
+                let item_lookup = match self.item_lookups.remove(&target) {
+                    Some(item_lookup) => item_lookup,
+                    None => return,
+                };
+                if let Some(torrent) = self.mutable_torrents.get_mut(&target) {
+                    let previous = torrent.info_hash;
+                    if let Some(info_hash) =
+                        torrent.update(item_lookup.found.as_ref(), Instant::now())
+                    {
+                        self.events.push_back(DhtEvent::TorrentUpdated {
+                            target,
+                            previous,
+                            info_hash,
+                        });
+                    }
+                }
+                self.events.push_back(DhtEvent::Item {
+                    target,
+                    item: item_lookup.found,
+                });
+                if let Some((item, cas)) = item_lookup.put {
+                    let mut nodes = 0;
+                    for addr in lookup.closest_answered() {
+                        if let Some(token) = self.tokens.get(&(addr, target)) {
+                            let token = token.clone();
+                            self.send_query(
+                                addr,
+                                Query::Put {
+                                    token,
+                                    item: item.clone(),
+                                    cas,
+                                },
+                            );
+                            nodes += 1;
+                        }
+                    }
+                    self.events.push_back(DhtEvent::PutDone { target, nodes });
+                }
+            }
+        }
+    }
+
+    /// keeps the item a node returned if it is valid and newer than the
+    /// ones found so far
+    fn item_found(&mut self, target: [u8; 20], mut item: Item) {
+        let item_lookup = match self.item_lookups.get_mut(&target) {
+            Some(item_lookup) => item_lookup,
+            None => return,
+        };
+        if let Some(mutable) = &mut item.mutable {
+            mutable.salt = item_lookup.salt.clone();
+        }
+        if !item.is_valid() || item.target() != target {
+            return;
+        }
+        let newer = match &item_lookup.found {
+            Some(found) => item.seq() > found.seq(),
+            None => true,
+        };
+        if newer {
+            item_lookup.found = Some(item);
+        }
+    }
+
+    fn send_query(&mut self, addr: SocketAddr, query: Query) {
+        self.query(addr, query, false);
+    }
+
+    fn query(&mut self, addr: SocketAddr, query: Query, lookup: bool) {
+        let transaction = self.next_transaction.to_be_bytes().to_vec();
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let finds_nodes = matches!(
+            query,
+            Query::FindNode { .. }
+                | Query::GetPeers { .. }
+                | Query::Get { .. }
+                | Query::SampleInfohashes { .. }
+        );
+        let want = if self.dual_stack && finds_nodes {
+            Want::BOTH
+        } else {
+            Want::default()
+        };
+        let message = KrpcMessage {
+            transaction: transaction.clone(),
+            body: KrpcBody::Query {
+                id: self.id,
+                query: query.clone(),
+                want,
+            },
+            ip: None,
+            read_only: self.read_only,
+        };
+        self.queue(addr, message.encode());
+        self.transactions.insert(
+            transaction,
+            Transaction {
+                addr,
+                query,
+                sent: Instant::now(),
+                lookup,
+            },
+        );
+    }
+
+    fn send(&mut self, addr: SocketAddr, transaction: Vec<u8>, body: KrpcBody) {
+        let message = KrpcMessage {
+            transaction,
+            body,
+            ip: Some(addr),
+            read_only: false,
+        };
+        self.queue(addr, message.encode());
+    }
+
+    /// packets past `MAX_OUTBOX` are dropped, like lost ones
+    fn queue(&mut self, addr: SocketAddr, packet: Vec<u8>) {
+        if self.outbox.len() >= MAX_OUTBOX {
+            self.packets_dropped += 1;
+            return;
+        }
+        self.outbox.push_back((addr, packet));
+    }
+
+    /// invalid packets and packets over the limits are ignored
+    pub fn handle_packet(&mut self, from: SocketAddr, data: &[u8], now: Instant) {
+        self.packets_in += 1;
+        self.bytes_in += data.len() as u64;
+        if !self.inbound.take(now) {
+            self.packets_dropped += 1;
+            return;
+        }
+        let message = match KrpcMessage::decode(data) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        match message.body {
+            KrpcBody::Query { id, query, want } => {
+                if self.read_only {
+                    return;
+                }
+                // answers are larger than queries, and are sent to whatever
+                // ip the query claims to come from
+                if !self.queries_per_ip.take(from.ip(), now) {
+                    self.packets_dropped += 1;
+                    return;
+                }
+                // read only nodes wouldn't answer our queries
+                if !message.read_only {
+                    self.table.insert(id, from, now);
+                }
+                let body = self.answer(from, query, want, now);
+                self.send(from, message.transaction, body);
+            }
+            KrpcBody::Response(response) => {
+                let transaction = match self.transactions.get(&message.transaction) {
+                    Some(transaction) if transaction.addr == from => {
+                        self.transactions.remove(&message.transaction).unwrap()
+                    }
+                    _ => return,
+                };
+                if let Some(ip) = message.ip {
+                    self.vote_external_ip(from.ip(), ip.ip());
+                }
+                self.table.insert(response.id, from, now);
+                // other nodes return us once they know us, the nodes of
+                // the other family can't be reached from our socket
+                let id = self.id;
+                let (nodes, other_family): (Vec<_>, Vec<_>) = response
+                    .nodes
+                    .iter()
+                    .copied()
+                    .filter(|(node, _)| *node != id)
+                    .partition(|(_, addr)| addr.is_ipv6() == from.is_ipv6());
+                if self.dual_stack {
+                    self.other_family_found.extend(other_family);
+                }
+                if let Some(lookup) =
+                    lookup_target(&transaction).and_then(|target| self.lookups.get_mut(&target))
+                {
+                    lookup.answered(from, &nodes);
+                }
+                if let Query::GetPeers { info_hash } = transaction.query {
+                    if let Some(token) = &response.token {
+                        self.tokens.insert((from, info_hash), token.clone());
+                    }
+                    if !response.values.is_empty() {
+                        self.events.push_back(DhtEvent::Peers {
+                            info_hash,
+                            peers: response.values.clone(),
+                        });
+                    }
+                }
+                if let Query::Get { target, .. } = transaction.query {
+                    if let Some(token) = &response.token {
+                        self.tokens.insert((from, target), token.clone());
+                    }
+                    if let Some(item) = &response.item {
+                        self.item_found(target, item.clone());
+                    }
+                }
+                self.events.push_back(DhtEvent::Response {
+                    from,
+                    query: transaction.query.clone(),
+                    response: Box::new(response),
+                });
+                self.lookup_progressed(&transaction);
+            }
+            KrpcBody::Error { .. } => {
+                if let Some(transaction) = self.transactions.get(&message.transaction) {
+                    if transaction.addr == from {
+                        let transaction = self.transactions.remove(&message.transaction).unwrap();
+                        self.query_failed(transaction);
+                    }
+                }
+            }
+        }
+    }
+
+    fn query_failed(&mut self, transaction: Transaction) {
+        if let Some(lookup) =
+            lookup_target(&transaction).and_then(|target| self.lookups.get_mut(&target))
+        {
+            lookup.failed(transaction.addr);
+        }
+        self.events.push_back(DhtEvent::QueryFailed {
+            to: transaction.addr,
+            query: transaction.query.clone(),
+        });
+        self.lookup_progressed(&transaction);
+    }
+
+    fn lookup_progressed(&mut self, transaction: &Transaction) {
+        if let Some(target) = lookup_target(transaction) {
+            self.step_lookup(target);
+        }
+    }
+
+    fn answer(&mut self, from: SocketAddr, query: Query, want: Want, now: Instant) -> KrpcBody {
+        let mut response = Response {
+            id: self.id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => response.nodes = self.wanted_nodes(&target, from, want),
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.token(from.ip(), &self.secret));
+                response.values = self.stored_peers(&info_hash);
+                response.values.truncate(MAX_VALUES);
+                if response.values.is_empty() {
+                    response.nodes = self.wanted_nodes(&NodeId(info_hash), from, want);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.is_valid_token(from.ip(), &token) {
+                    return KrpcBody::Error {
+                        code: ERROR_PROTOCOL,
+                        message: "invalid token".into(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                if port == 0 {
+                    return KrpcBody::Error {
+                        code: ERROR_PROTOCOL,
+                        message: "invalid port".into(),
+                    };
+                }
+                self.store_peer(info_hash, SocketAddr::new(from.ip(), port), now);
+            }
+            Query::Get { target, seq } => {
+                response.token = Some(self.token(from.ip(), &self.secret));
+                response.nodes = self.wanted_nodes(&NodeId(target), from, want);
+                // mutable items only if newer than the one the node has
+                response.item = self
+                    .stored_item(&target)
+                    .filter(|item| seq.is_none() || item.seq() > seq)
+                    .cloned();
+            }
+            Query::Put { token, item, cas } => {
+                if let Err((code, message)) = self.put(from.ip(), &token, item, cas, now) {
+                    return KrpcBody::Error {
+                        code,
+                        message: message.into(),
+                    };
+                }
+            }
+            Query::SampleInfohashes { target } => {
+                let picked = self
+                    .samples_picked
+                    .filter(|picked| now.duration_since(*picked) < SAMPLE_INTERVAL);
+                let picked = match picked {
+                    Some(picked) => picked,
+                    None => {
+                        self.pick_samples();
+                        self.samples_picked = Some(now);
+                        now
+                    }
+                };
+                let next = SAMPLE_INTERVAL.saturating_sub(now.duration_since(picked));
+                response.interval = Some(next.as_secs() as i64);
+                response.num = Some(self.peers.len() as i64);
+                response.samples = self.samples.clone();
+                response.nodes = self.wanted_nodes(&target, from, want);
+            }
+            Query::Unknown(_) => {
+                return KrpcBody::Error {
+                    code: ERROR_METHOD_UNKNOWN,
+                    message: "Method Unknown".into(),
+                }
+            }
+        }
+        KrpcBody::Response(response)
+    }
+
+    /// random info hashes we store peers for
+    fn pick_samples(&mut self) {
+        let mut info_hashes: Vec<_> = self.peers.keys().copied().collect();
+        for index in 0..info_hashes.len().min(MAX_SAMPLES) {
+            let mut random = [0; 8];
+            random_bytes(&mut random);
+            let other = index + u64::from_le_bytes(random) as usize % (info_hashes.len() - index);
+            info_hashes.swap(index, other);
+        }
+        info_hashes.truncate(MAX_SAMPLES);
+        self.samples = info_hashes;
+    }
+
+    /// stores the item put by a node, or the error to answer with
+    fn put(
+        &mut self,
+        ip: IpAddr,
+        token: &[u8],
+        item: Item,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> std::result::Result<(), (i64, &'static str)> {
+        if !self.is_valid_token(ip, token) {
+            return Err((ERROR_PROTOCOL, "invalid token"));
+        }
+        if item.value.encode().len() > MAX_VALUE_SIZE {
+            return Err((ERROR_MESSAGE_TOO_BIG, "message (v field) too big"));
+        }
+        let target = item.target();
+        if let Some(mutable) = &item.mutable {
+            if mutable.salt.len() > MAX_SALT_SIZE {
+                return Err((ERROR_SALT_TOO_BIG, "salt (salt field) too big"));
+            }
+            if !item.is_valid() {
+                return Err((ERROR_INVALID_SIGNATURE, "invalid signature"));
+            }
+            if let Some((stored, _)) = self.items.get(&target) {
+                if cas.is_some() && stored.seq() != cas {
+                    return Err((
+                        ERROR_CAS_MISMATCH,
+                        "CAS mismatch, re-read value and try again",
+                    ));
+                }
+                if stored.seq() > item.seq() {
+                    return Err((ERROR_SEQ_TOO_LOW, "sequence number less than current"));
+                }
+            }
+        }
+        if !self.items.contains_key(&target) && self.items.len() >= self.limits.max_items {
+            let oldest = self.items.iter().min_by_key(|(_, (_, put))| *put);
+            if let Some(oldest) = oldest.map(|(target, _)| *target) {
+                self.items.remove(&oldest);
+            }
+        }
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    /// stores an announced peer within the storage limits
+    fn store_peer(&mut self, info_hash: [u8; 20], peer: SocketAddr, now: Instant) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= self.limits.max_info_hashes {
+            let fewest = self.peers.iter().min_by_key(|(_, peers)| peers.len());
+            if let Some(fewest) = fewest.map(|(info_hash, _)| *info_hash) {
+                self.peers.remove(&fewest);
+            }
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        if !peers.contains_key(&peer) && peers.len() >= self.limits.max_peers {
+            let oldest = peers.iter().min_by_key(|(_, announced)| **announced);
+            if let Some(oldest) = oldest.map(|(peer, _)| *peer) {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer, now);
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> Vec<(NodeId, SocketAddr)> {
+        self.table
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect()
+    }
+
+    /// closest nodes of the families the querying node wants, from our
+    /// routing table and the other family's nodes
+    fn wanted_nodes(
+        &self,
+        target: &NodeId,
+        from: SocketAddr,
+        want: Want,
+    ) -> Vec<(NodeId, SocketAddr)> {
+        let mut nodes: Vec<_> = self
+            .closest_nodes(target)
+            .into_iter()
+            .filter(|(_, addr)| want.wants(&from, addr))
+            .collect();
+        let mut other_family: Vec<_> = self
+            .other_family
+            .iter()
+            .copied()
+            .filter(|(_, addr)| want.wants(&from, addr))
+            .collect();
+        other_family.sort_by_key(|(id, _)| id.distance(target));
+        other_family.truncate(K);
+        nodes.extend(other_family);
+        nodes
+    }
+
+    /// tokens prove the announcing node received our answer at its address
+    fn token(&self, ip: IpAddr, secret: &[u8; 20]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(secret);
+        hasher.finalize().to_vec()
+    }
+
+    fn is_valid_token(&self, ip: IpAddr, token: &[u8]) -> bool {
+        token == self.token(ip, &self.secret).as_slice()
+            || token == self.token(ip, &self.previous_secret).as_slice()
+    }
+
+    /// expires queries, tokens, peers and items and refreshes announces, meant to
+    /// be called about once a second
+    pub fn tick(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| now.duration_since(transaction.sent) >= QUERY_TIMEOUT)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            let transaction = self.transactions.remove(&id).unwrap();
+            self.table.failed(transaction.addr);
+            self.query_failed(transaction);
+        }
+        self.refresh_announces(now);
+        self.refresh_mutable_torrents(now);
+
+        let retry = self
+            .last_bootstrap
+            .is_some_and(|last| now.duration_since(last) >= BOOTSTRAP_RETRY);
+        if retry && self.table.is_empty() && !self.lookups.contains_key(&self.id.0) {
+            self.bootstrap(self.routers.clone(), vec![], now);
+        }
+
+        if now.duration_since(self.secret_rotated) >= SECRET_ROTATION {
+            self.previous_secret = self.secret;
+            random_bytes(&mut self.secret);
+            self.secret_rotated = now;
+        }
+
+        for peers in self.peers.values_mut() {
+            peers.retain(|_, announced| now.duration_since(*announced) < PEER_TIMEOUT);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+        self.items
+            .retain(|_, (_, put)| now.duration_since(*put) < ITEM_TIMEOUT);
+        self.queries_per_ip.prune(now);
+    }
+}
+
+/// target of the lookup that sent the query
+fn lookup_target(transaction: &Transaction) -> Option<[u8; 20]> {
+    match (transaction.lookup, &transaction.query) {
+        (true, Query::GetPeers { info_hash }) => Some(*info_hash),
+        (true, Query::FindNode { target }) => Some(target.0),
+        (true, Query::Get { target, .. }) => Some(*target),
+        _ => None,
+    }
+}
+
+/// Where `DhtTask::start` bootstraps from and keeps its state
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// `host:port` of the routers, resolved when the task starts
+    pub routers: Vec<String>,
+    /// the id and routing table are loaded from and saved to this file
+    pub state_path: Option<PathBuf>,
+    /// crawls the DHT for info hashes, see `DhtTask::start_indexer`
+    pub indexer: bool,
+    /// see `Dht::set_read_only`
+    pub read_only: bool,
+    pub limits: DhtLimits,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            routers: DEFAULT_ROUTERS
+                .iter()
+                .map(|router| router.to_string())
+                .collect(),
+            state_path: None,
+            indexer: false,
+            read_only: false,
+            limits: DhtLimits::default(),
+        }
+    }
+}
+
+/// Runs a DHT node in a task of the runtime, reading packets from the socket and
+/// sending the ones the node queues. The node is shared to send queries, its
+/// events are forwarded to `poll_event` and the peers found for the torrents
+/// added with `add_torrent` are handed to them. A second node can run over
+/// ipv6, see `start_dual_stack`.
+pub struct DhtTask {
+    dht: Arc<Mutex<Dht>>,
+    dht6: Option<Arc<Mutex<Dht>>>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>,
+    events: Receiver<DhtEvent>,
+    /// driven by the worker of the ipv4 node while running
+    indexer: Arc<Mutex<Option<Indexer>>>,
+    /// where the state is saved when the task stops
+    state_path: Option<PathBuf>,
+    stop: watch::Sender<bool>,
+    /// disconnected once the workers are done
+    done: Receiver<()>,
+}
+
+impl DhtTask {
+    /// starts a node with the id saved by the last run, or a new one, and
+    /// bootstraps it from the saved nodes right away and from the routers
+    /// once their names are resolved
+    pub fn start(socket: UdpSocket, config: DhtConfig) -> Result<Self> {
+        let (id, nodes) = load_state(config.state_path.as_deref());
+        let mut task = Self::spawn(socket, new_dht(id, &config))?;
+        task.bootstrap(nodes, vec![], config);
+        Ok(task)
+    }
+
+    /// like `start`, with a second node on the ipv6 socket so peers only
+    /// reachable over ipv6 are found too (BEP 32). Its state is saved next
+    /// to the one of the ipv4 node.
+    pub fn start_dual_stack(
+        socket: UdpSocket,
+        socket6: UdpSocket,
+        config: DhtConfig,
+    ) -> Result<Self> {
+        let (id, nodes) = load_state(config.state_path.as_deref());
+        let path6 = config.state_path.as_deref().map(ipv6_state_path);
+        let (id6, nodes6) = load_state(path6.as_deref());
+        let mut task =
+            Self::spawn_dual_stack(socket, new_dht(id, &config), socket6, new_dht(id6, &config))?;
+        task.bootstrap(nodes, nodes6, config);
+        Ok(task)
+    }
+
+    fn bootstrap(
+        &mut self,
+        nodes: Vec<(NodeId, SocketAddr)>,
+        nodes6: Vec<(NodeId, SocketAddr)>,
+        config: DhtConfig,
+    ) {
+        self.state_path = config.state_path;
+        if config.indexer {
+            self.start_indexer();
+        }
+        let now = Instant::now();
+        self.dht.lock().unwrap().bootstrap(vec![], nodes, now);
+        if let Some(dht6) = &self.dht6 {
+            dht6.lock().unwrap().bootstrap(vec![], nodes6, now);
+        }
+
+        let dht = Arc::clone(&self.dht);
+        let dht6 = self.dht6.clone();
+        let routers = config.routers;
+        runtime::handle().spawn_blocking(move || {
+            let (routers6, routers): (Vec<_>, Vec<_>) = routers
+                .iter()
+                .filter_map(|router| router.to_socket_addrs().ok())
+                .flatten()
+                .partition(SocketAddr::is_ipv6);
+            dht.lock()
+                .unwrap()
+                .bootstrap(routers, vec![], Instant::now());
+            if let Some(dht6) = dht6 {
+                dht6.lock()
+                    .unwrap()
+                    .bootstrap(routers6, vec![], Instant::now());
+            }
+        });
+    }
+
+    pub fn spawn(socket: UdpSocket, dht: Dht) -> io::Result<Self> {
+        Self::spawn_nodes(vec![(socket, dht)])
+    }
+
+    /// runs the ipv4 and ipv6 nodes, each hands the other the nodes of its
+    /// family it is told about
+    pub fn spawn_dual_stack(
+        socket: UdpSocket,
+        mut dht: Dht,
+        socket6: UdpSocket,
+        mut dht6: Dht,
+    ) -> io::Result<Self> {
+        dht.set_dual_stack(true);
+        dht6.set_dual_stack(true);
+        Self::spawn_nodes(vec![(socket, dht), (socket6, dht6)])
+    }
+
+    fn spawn_nodes(nodes: Vec<(UdpSocket, Dht)>) -> io::Result<Self> {
+        let runtime = runtime::handle();
+        let _entered = runtime.enter();
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        let indexer = Arc::new(Mutex::new(None));
+        let (events, receiver) = mpsc::sync_channel(TASK_EVENTS);
+        let (stop, stopped) = watch::channel(false);
+        let (finished, done) = mpsc::channel();
+        let mut sockets = vec![];
+        let mut dhts = vec![];
+        for (socket, dht) in nodes {
+            socket.set_nonblocking(true)?;
+            sockets.push(tokio::net::UdpSocket::from_std(socket)?);
+            dhts.push(Arc::new(Mutex::new(dht)));
+        }
+        for (index, socket) in sockets.into_iter().enumerate() {
+            let worker = Worker {
+                socket,
+                dht: Arc::clone(&dhts[index]),
+                other: if dhts.len() > 1 {
+                    Some(Arc::clone(&dhts[1 - index]))
+                } else {
+                    None
+                },
+                torrents: Arc::clone(&torrents),
+                events: events.clone(),
+                indexer: if index == 0 {
+                    Arc::clone(&indexer)
+                } else {
+                    Arc::new(Mutex::new(None))
+                },
+                _done: finished.clone(),
+            };
+            runtime.spawn(worker.run(stopped.clone()));
+        }
+        let mut dhts = dhts.into_iter();
+        Ok(Self {
+            dht: dhts.next().unwrap(),
+            dht6: dhts.next(),
+            torrents,
+            events: receiver,
+            indexer,
+            state_path: None,
+            stop,
+            done,
+        })
+    }
+
+    pub fn dht(&self) -> &Arc<Mutex<Dht>> {
+        &self.dht
+    }
+
+    /// the node running over ipv6, if any
+    pub fn dht6(&self) -> Option<&Arc<Mutex<Dht>>> {
+        self.dht6.as_ref()
+    }
+
+    /// every node, to announce and look up torrents on all of them
+    fn dhts(&self) -> impl Iterator<Item = &Arc<Mutex<Dht>>> {
+        std::iter::once(&self.dht).chain(&self.dht6)
+    }
+
+    pub fn poll_event(&self) -> Option<DhtEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// saves the id and routing table to the configured file, also done
+    /// when the task stops
+    pub fn save_state(&self) -> Result<()> {
+        if let Some(path) = &self.state_path {
+            self.dht.lock().unwrap().state().save(path)?;
+            if let Some(dht6) = &self.dht6 {
+                dht6.lock().unwrap().state().save(&ipv6_state_path(path))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// looks for peers of the torrent and announces that we listen on
+    /// `port` for it, until it is removed. Private torrents only get peers
+    /// from their trackers and aren't added.
+    pub fn add_torrent(&self, torrent: &TorrentHandle, port: u16) -> bool {
+        if torrent.is_private() {
+            return false;
+        }
+        let info_hash = torrent.info_hash();
+        self.torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash, torrent.clone());
+        for dht in self.dhts() {
+            dht.lock()
+                .unwrap()
+                .announce(info_hash, Some(port), Instant::now());
+        }
+        true
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+        for dht in self.dhts() {
+            dht.lock().unwrap().stop_announcing(info_hash);
+        }
+    }
+    pub fn stats(&self) -> DhtStats {
+        self.dht.lock().unwrap().stats()
+    }
+
+    /// of the node running over ipv6, if any
+    pub fn stats6(&self) -> Option<DhtStats> {
+        self.dht6.as_ref().map(|dht6| dht6.lock().unwrap().stats())
+    }
+
+    /// crawls the DHT from the ipv4 node, collecting the info hashes the
+    /// nodes sample for us (BEP 51), see `Indexer`
+    pub fn start_indexer(&self) {
+        let mut indexer = self.indexer.lock().unwrap();
+        if indexer.is_none() {
+            *indexer = Some(Indexer::new());
+        }
+    }
+
+    /// stops crawling, returning what was indexed
+    pub fn stop_indexer(&self) -> Option<Indexer> {
+        self.indexer.lock().unwrap().take()
+    }
+
+    /// runs `f` on the indexer while it crawls, to read the index
+    pub fn with_indexer<T>(&self, f: impl FnOnce(&mut Indexer) -> T) -> Option<T> {
+        self.indexer.lock().unwrap().as_mut().map(f)
+    }
+
+    pub fn poll_indexer_event(&self) -> Option<IndexerEvent> {
+        self.with_indexer(Indexer::poll_event).flatten()
+    }
+
+    /// names the indexed info hash with the metadata fetched from its peers
+    pub fn metadata_received(&self, metainfo: &Metainfo) {
+        self.with_indexer(|indexer| indexer.metadata_received(metainfo));
+    }
+
+    /// stores the item in the DHT, see `Dht::put_item`
+    pub fn dht_put(&self, item: Item, cas: Option<i64>) {
+        self.dht.lock().unwrap().put_item(item, cas);
+    }
+
+    /// looks up the item at the target, see `Dht::get_item`
+    pub fn dht_get(&self, target: [u8; 20], salt: Vec<u8>) {
+        self.dht.lock().unwrap().get_item(target, salt);
+    }
+
+    /// resolves the torrent of a `magnet:?xs=urn:btpk:` link and follows
+    /// its updates, see `Dht::follow_torrent`. Each version is started from
+    /// `MutableMagnet::magnet` with the info hash of the update event.
+    pub fn follow_torrent(&self, magnet: &MutableMagnet) {
+        self.dht.lock().unwrap().follow_torrent(
+            magnet.public_key,
+            magnet.salt.clone(),
+            Instant::now(),
+        );
+    }
+
+    pub fn unfollow_torrent(&self, magnet: &MutableMagnet) {
+        self.dht.lock().unwrap().unfollow_torrent(&magnet.target());
+    }
+}
+
+/// Task of one node of a `DhtTask`
+struct Worker {
+    socket: tokio::net::UdpSocket,
+    dht: Arc<Mutex<Dht>>,
+    /// the node of the other address family
+    other: Option<Arc<Mutex<Dht>>>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>,
+    events: SyncSender<DhtEvent>,
+    /// locked after the node, never before
+    indexer: Arc<Mutex<Option<Indexer>>>,
+    /// dropped with the worker, which the task waits for
+    _done: Sender<()>,
+}
+
+impl Worker {
+    async fn run(self, mut stop: watch::Receiver<bool>) {
+        let mut buffer = [0; 2048];
+        // wakes up regularly to send queued packets
+        let mut wakeups = time::interval(Duration::from_millis(100));
+        let mut last_tick = Instant::now();
+        loop {
+            let received = tokio::select! {
+                _ = stop.changed() => break,
+                received = self.socket.recv_from(&mut buffer) => received.ok(),
+                _ = wakeups.tick() => None,
+            };
+            let now = Instant::now();
+            let ticked = now.duration_since(last_tick) >= Duration::from_secs(1);
+            let (events, packets, other_family) = {
+                let mut dht = self.dht.lock().unwrap();
+                if let Some((length, from)) = received {
+                    dht.handle_packet(from, &buffer[..length], now);
+                }
+                if ticked {
+                    dht.tick(now);
+                    last_tick = now;
+                }
+                let events: Vec<_> = std::iter::from_fn(|| dht.poll_event()).collect();
+                if let Some(indexer) = self.indexer.lock().unwrap().as_mut() {
+                    for event in &events {
+                        indexer.handle_event(event, now);
+                    }
+                    if ticked {
+                        indexer.tick(&mut dht, now);
+                    }
+                }
+                let packets: Vec<_> = std::iter::from_fn(|| dht.poll_packet()).collect();
+                let other_family: Vec<_> =
+                    std::iter::from_fn(|| dht.poll_other_family_node()).collect();
+                (events, packets, other_family)
+            };
+            for (addr, packet) in packets {
+                // the node treats lost packets like unanswered queries
+                let _ = self.socket.send_to(&packet, addr).await;
+            }
+            for event in events {
+                dispatch(&self.torrents, &self.events, event);
+            }
+            // only one node is locked at a time, the other worker does the
+            // same the other way around
+            if let Some(other) = &self.other {
+                let nodes = {
+                    let mut other = other.lock().unwrap();
+                    for (id, addr) in other_family {
+                        other.add_node(id, addr);
+                    }
+                    ticked.then(|| other.state().nodes)
+                };
+                if let Some(nodes) = nodes {
+                    self.dht.lock().unwrap().set_other_family_nodes(nodes);
+                }
+            }
+        }
+    }
+}
+
+fn new_dht(id: NodeId, config: &DhtConfig) -> Dht {
+    let mut dht = Dht::new(id);
+    dht.set_limits(config.limits);
+    dht.set_read_only(config.read_only);
+    dht
+}
+
+/// id and nodes saved at the path, or a new id
+fn load_state(path: Option<&Path>) -> (NodeId, Vec<(NodeId, SocketAddr)>) {
+    // a corrupt state is replaced when the task stops
+    match path.and_then(|path| DhtState::load(path).unwrap_or(None)) {
+        Some(state) => (state.id, state.nodes),
+        None => (NodeId::random(), vec![]),
+    }
+}
+
+/// `dht.state` becomes `dht6.state`
+fn ipv6_state_path(path: &Path) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push("6");
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// hands peers to their torrent and forwards the event, dropping it when
+/// nobody polls the task
+fn dispatch(
+    torrents: &Mutex<HashMap<[u8; 20], TorrentHandle>>,
+    events: &SyncSender<DhtEvent>,
+    event: DhtEvent,
+) {
+    if let DhtEvent::Peers { info_hash, peers } = &event {
+        if let Some(torrent) = torrents.lock().unwrap().get(info_hash) {
+            torrent.add_peers(peers.iter().copied(), PeerSource::Dht);
+        }
+    }
+    let _ = events.try_send(event);
+}
+
+impl Drop for DhtTask {
+    fn drop(&mut self) {
+        let _ = self.stop.send(true);
+        let _ = self.done.recv();
+        let _ = self.save_state();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::Bencode;
+    use ed25519_dalek::SigningKey;
+    use std::thread;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// delivers the packets queued by `from` to `to`
+    fn deliver(from: (&mut Dht, SocketAddr), to: (&mut Dht, SocketAddr)) {
+        while let Some((addr, packet)) = from.0.poll_packet() {
+            assert_eq!(addr, to.1);
+            to.0.handle_packet(from.1, &packet, Instant::now());
+        }
+    }
+
+    #[test]
+    fn announce_and_get_peers() {
+        let (mut a, mut b, mut c) = (
+            Dht::new(NodeId([1; 20])),
+            Dht::new(NodeId([2; 20])),
+            Dht::new(NodeId([3; 20])),
+        );
+        let info_hash = [9; 20];
+
+        // announcing needs a token from get_peers
+        assert!(!a.announce_peer(addr(2), info_hash, Some(6881)));
+        a.get_peers(addr(2), info_hash);
+        deliver((&mut a, addr(1)), (&mut b, addr(2)));
+        deliver((&mut b, addr(2)), (&mut a, addr(1)));
+        assert!(b.routing_table().nodes().any(|node| node.addr == addr(1)));
+        assert!(a.routing_table().nodes().any(|node| node.addr == addr(2)));
+        assert!(matches!(a.poll_event(), Some(DhtEvent::Response { .. })));
+
+        assert!(a.announce_peer(addr(2), info_hash, Some(6881)));
+        deliver((&mut a, addr(1)), (&mut b, addr(2)));
+        deliver((&mut b, addr(2)), (&mut a, addr(1)));
+        assert_eq!(b.stored_peers(&info_hash), vec![addr(6881)]);
+
+        let stats = b.stats();
+        assert_eq!((stats.info_hashes, stats.peers), (1, 1));
+        assert_eq!((stats.packets_in, stats.packets_out), (2, 2));
+        assert_eq!(stats.nodes, 1);
+        assert_eq!(stats.buckets.iter().sum::<usize>(), 1);
+        assert_eq!(a.stats().bytes_out, stats.bytes_in);
+
+        c.get_peers(addr(2), info_hash);
+        deliver((&mut c, addr(3)), (&mut b, addr(2)));
+        deliver((&mut b, addr(2)), (&mut c, addr(3)));
+        assert_eq!(
+            c.poll_event(),
+            Some(DhtEvent::Peers {
+                info_hash,
+                peers: vec![addr(6881)],
+            })
+        );
+    }
+
+    #[test]
+    fn counts_nodes_buckets_and_lookups() {
+        let mut dht = Dht::new(NodeId([0; 20]));
+        assert_eq!(dht.stats(), DhtStats::default());
+        // sharing 0, 1, 1 and 3 bits with our id
+        for (port, first) in [(1, 0x80), (2, 0x40), (3, 0x41), (4, 0x10)] {
+            let mut id = [0xff; 20];
+            id[0] = first;
+            dht.table.insert(NodeId(id), addr(port), Instant::now());
+        }
+        let stats = dht.stats();
+        assert_eq!(stats.nodes, 4);
+        assert_eq!(stats.buckets, [1, 2, 0, 1]);
+
+        dht.lookup([1; 20]);
+        dht.lookup([1; 20]);
+        dht.lookup([2; 20]);
+        let stats = dht.stats();
+        assert_eq!(stats.lookups, 2);
+        let sent = std::iter::from_fn(|| dht.poll_packet()).count();
+        assert_eq!(stats.queries, sent);
+        assert_eq!(dht.stats().packets_out, sent as u64);
+
+        // nobody answers, the queries time out and the lookups give up
+        for seconds in 1..=5 {
+            dht.tick(Instant::now() + Duration::from_secs(60 * seconds));
+        }
+        let stats = dht.stats();
+        assert_eq!((stats.lookups, stats.queries), (0, 0));
+    }
+
+    /// delivers packets between the nodes, node `i` listening on port `i + 1`,
+    /// until none is left
+    fn run(nodes: &mut [Dht]) {
+        loop {
+            let mut packets = vec![];
+            for (index, node) in nodes.iter_mut().enumerate() {
+                while let Some((to, packet)) = node.poll_packet() {
+                    packets.push((addr(index as u16 + 1), to, packet));
+                }
+            }
+            if packets.is_empty() {
+                return;
+            }
+            for (from, to, packet) in packets {
+                nodes[to.port() as usize - 1].handle_packet(from, &packet, Instant::now());
+            }
+        }
+    }
+
+    #[test]
+    fn lookups_find_announced_peers() {
+        let info_hash = [0xf0; 20];
+        // ids closer to the info hash as the port grows, every node only
+        // knows the next one
+        let mut nodes: Vec<_> = (1..=5u8)
+            .map(|port| Dht::new(NodeId([0xf0 - 5 + port; 20])))
+            .collect();
+        for index in 0..4 {
+            let next = nodes[index + 1].id();
+            nodes[index]
+                .table
+                .insert(next, addr(index as u16 + 2), Instant::now());
+        }
+
+        nodes[0].announce(info_hash, Some(6881), Instant::now());
+        run(&mut nodes);
+        let events: Vec<_> = std::iter::from_fn(|| nodes[0].poll_event()).collect();
+        assert!(events.contains(&DhtEvent::LookupDone { info_hash }));
+        // announced to the closest nodes, the far end of the chain
+        assert_eq!(nodes[4].stored_peers(&info_hash), vec![addr(6881)]);
+
+        // the others learned about node 1 from its queries
+        nodes[3].lookup(info_hash);
+        run(&mut nodes);
+        let events: Vec<_> = std::iter::from_fn(|| nodes[3].poll_event()).collect();
+        assert!(events.contains(&DhtEvent::Peers {
+            info_hash,
+            peers: vec![addr(6881)],
+        }));
+        assert!(events.contains(&DhtEvent::LookupDone { info_hash }));
+
+        // announced again once the interval passed
+        nodes[4].peers.clear();
+        nodes[0].tick(Instant::now() + ANNOUNCE_INTERVAL);
+        run(&mut nodes);
+        assert_eq!(nodes[4].stored_peers(&info_hash), vec![addr(6881)]);
+    }
+
+    #[test]
+    fn put_and_get_items() {
+        let mut nodes: Vec<_> = (1..=4u8).map(|port| Dht::new(NodeId([port; 20]))).collect();
+        for index in 1..4 {
+            let id = nodes[index].id();
+            nodes[0]
+                .table
+                .insert(id, addr(index as u16 + 1), Instant::now());
+        }
+        let items = |dht: &mut Dht| -> Vec<_> { std::iter::from_fn(|| dht.poll_event()).collect() };
+
+        let immutable = Item::immutable(Bencode::Bytes(b"Hello World!".to_vec()));
+        let target = immutable.target();
+        nodes[0].put_item(immutable.clone(), None);
+        run(&mut nodes);
+        assert!(items(&mut nodes[0]).contains(&DhtEvent::PutDone { target, nodes: 3 }));
+        assert_eq!(nodes[3].stored_item(&target), Some(&immutable));
+
+        // a node that didn't take part finds it through node 1
+        let mut other = Dht::new(NodeId([5; 20]));
+        other.table.insert(nodes[0].id(), addr(1), Instant::now());
+        nodes.push(other);
+        nodes[4].get_item(target, vec![]);
+        run(&mut nodes);
+        assert!(items(&mut nodes[4]).contains(&DhtEvent::Item {
+            target,
+            item: Some(immutable),
+        }));
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let salt = b"salt".to_vec();
+        let mutable = |seq| Item::mutable(Bencode::Integer(seq), &key, salt.clone(), seq);
+        let target = mutable(1).target();
+        nodes[4].put_item(mutable(2), None);
+        run(&mut nodes);
+        // rejected as older than the stored item, then for the wrong cas
+        nodes[0].put_item(mutable(1), None);
+        run(&mut nodes);
+        nodes[0].put_item(mutable(3), Some(1));
+        run(&mut nodes);
+        let failures = items(&mut nodes[0])
+            .into_iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    DhtEvent::QueryFailed {
+                        query: Query::Put { .. },
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(failures, 6);
+        assert_eq!(nodes[1].stored_item(&target), Some(&mutable(2)));
+
+        nodes[4].get_item(target, salt.clone());
+        run(&mut nodes);
+        assert!(items(&mut nodes[4]).contains(&DhtEvent::Item {
+            target,
+            item: Some(mutable(2)),
+        }));
+    }
+
+    #[test]
+    fn follow_mutable_torrents() {
+        let mut nodes: Vec<_> = (1..=3u8).map(|port| Dht::new(NodeId([port; 20]))).collect();
+        for index in 1..3 {
+            let id = nodes[index].id();
+            nodes[0]
+                .table
+                .insert(id, addr(index as u16 + 1), Instant::now());
+        }
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let magnet = MutableMagnet::parse(&format!(
+            "magnet:?xs=urn:btpk:{}&s=73616c74",
+            crate::metainfo::to_hex(key.verifying_key().as_bytes())
+        ))
+        .unwrap();
+        let target = magnet.target();
+        let updates = |dht: &mut Dht| -> Vec<_> {
+            std::iter::from_fn(|| dht.poll_event())
+                .filter(|event| matches!(event, DhtEvent::TorrentUpdated { .. }))
+                .collect()
+        };
+
+        nodes[0].put_item(
+            mutable::torrent_item([1; 20], &key, magnet.salt.clone(), 1),
+            None,
+        );
+        run(&mut nodes);
+        nodes[1].follow_torrent(magnet.public_key, magnet.salt.clone(), Instant::now());
+        run(&mut nodes);
+        assert_eq!(
+            updates(&mut nodes[1]),
+            [DhtEvent::TorrentUpdated {
+                target,
+                previous: None,
+                info_hash: [1; 20],
+            }]
+        );
+
+        // the publisher releases a new version, seen on the next check
+        nodes[0].put_item(
+            mutable::torrent_item([2; 20], &key, magnet.salt.clone(), 2),
+            None,
+        );
+        run(&mut nodes);
+        nodes[1].tick(Instant::now() + Duration::from_secs(60));
+        run(&mut nodes);
+        assert!(updates(&mut nodes[1]).is_empty());
+        nodes[1].tick(Instant::now() + Duration::from_secs(30 * 60));
+        run(&mut nodes);
+        assert_eq!(
+            updates(&mut nodes[1]),
+            [DhtEvent::TorrentUpdated {
+                target,
+                previous: Some([1; 20]),
+                info_hash: [2; 20],
+            }]
+        );
+        assert_eq!(
+            nodes[1].mutable_torrent(&target).unwrap().info_hash,
+            Some([2; 20])
+        );
+    }
+
+    #[test]
+    fn bootstrap_and_rejoin_from_saved_nodes() -> Result<()> {
+        let mut nodes: Vec<_> = (1..=5u8).map(|port| Dht::new(NodeId([port; 20]))).collect();
+        // node 2 acts as the router and knows the others
+        for index in 2..5 {
+            let id = nodes[index].id();
+            nodes[1]
+                .table
+                .insert(id, addr(index as u16 + 1), Instant::now());
+        }
+
+        nodes[0].bootstrap(vec![addr(2)], vec![], Instant::now());
+        run(&mut nodes);
+        let events: Vec<_> = std::iter::from_fn(|| nodes[0].poll_event()).collect();
+        assert!(events.contains(&DhtEvent::Bootstrapped { nodes: 4 }));
+
+        let path = std::env::temp_dir().join("torrent_rs_dht_state_test/dht.state");
+        nodes[0].state().save(&path)?;
+        let state = DhtState::load(&path)?.unwrap();
+        assert_eq!(state, nodes[0].state());
+        assert_eq!(state.nodes.len(), 4);
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+
+        // restarted without routers
+        nodes[0] = Dht::new(state.id);
+        nodes[0].bootstrap(vec![], state.nodes, Instant::now());
+        run(&mut nodes);
+        assert_eq!(nodes[0].routing_table().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn bootstrap_is_retried() {
+        let mut dht = Dht::new(NodeId([1; 20]));
+        let start = Instant::now();
+        dht.bootstrap(vec![addr(2)], vec![], start);
+        assert!(dht.poll_packet().is_some());
+
+        dht.tick(Instant::now() + QUERY_TIMEOUT);
+        let events: Vec<_> = std::iter::from_fn(|| dht.poll_event()).collect();
+        assert!(events.contains(&DhtEvent::Bootstrapped { nodes: 0 }));
+        assert!(dht.poll_packet().is_none());
+
+        dht.tick(start + BOOTSTRAP_RETRY);
+        assert_eq!(dht.poll_packet().map(|(to, _)| to), Some(addr(2)));
+    }
+
+    #[test]
+    fn id_follows_external_ip() {
+        let mut dht = Dht::new(NodeId([1; 20]));
+        let external = SocketAddr::from(([124, 31, 75, 21], 6881));
+        for voter in 1..=EXTERNAL_IP_VOTES as u8 {
+            let node = SocketAddr::from(([21, 75, 31, voter], 6881));
+            dht.ping(node);
+            let (_, packet) = dht.poll_packet().unwrap();
+            let answer = KrpcMessage {
+                transaction: KrpcMessage::decode(&packet).unwrap().transaction,
+                body: KrpcBody::Response(Response {
+                    id: NodeId([voter + 10; 20]),
+                    ..Default::default()
+                }),
+                ip: Some(external),
+                read_only: false,
+            };
+            assert_eq!(dht.id(), NodeId([1; 20]));
+            dht.handle_packet(node, &answer.encode(), Instant::now());
+        }
+
+        assert_eq!(dht.external_ip(), Some(external.ip()));
+        assert!(security::is_secure_id(&dht.id(), external.ip()));
+        assert_eq!(dht.routing_table().id(), dht.id());
+        assert_eq!(dht.routing_table().len(), EXTERNAL_IP_VOTES);
+    }
+
+    #[test]
+    fn invalid_token_and_unknown_method() {
+        let mut dht = Dht::new(NodeId([1; 20]));
+        let mut other = Dht::new(NodeId([2; 20]));
+        other.tokens.insert((addr(1), [9; 20]), b"forged".to_vec());
+        other.announce_peer(addr(1), [9; 20], None);
+        other.send_query(addr(1), Query::Unknown("vote".into()));
+        deliver((&mut other, addr(2)), (&mut dht, addr(1)));
+        deliver((&mut dht, addr(1)), (&mut other, addr(2)));
+
+        assert!(dht.stored_peers(&[9; 20]).is_empty());
+        let failures = std::iter::from_fn(|| other.poll_event())
+            .filter(|event| matches!(event, DhtEvent::QueryFailed { .. }))
+            .count();
+        assert_eq!(failures, 2);
+    }
+
+    #[test]
+    fn limits_queries_and_storage() {
+        let mut dht = Dht::new(NodeId([1; 20]));
+        dht.set_limits(DhtLimits {
+            queries_per_ip: 3,
+            max_info_hashes: 2,
+            max_peers: 2,
+            ..Default::default()
+        });
+        let mut other = Dht::new(NodeId([2; 20]));
+        for _ in 0..5 {
+            other.ping(addr(1));
+        }
+        deliver((&mut other, addr(2)), (&mut dht, addr(1)));
+        assert_eq!(dht.outbox.len(), 3);
+        assert_eq!(dht.stats().packets_dropped, 2);
+
+        let now = Instant::now();
+        for port in 1..=3 {
+            dht.store_peer([1; 20], addr(port), now + Duration::from_secs(port as u64));
+        }
+        assert_eq!(dht.stored_peers(&[1; 20]).len(), 2);
+        assert!(!dht.stored_peers(&[1; 20]).contains(&addr(1)));
+        dht.store_peer([2; 20], addr(1), now);
+        dht.store_peer([3; 20], addr(1), now);
+        assert_eq!(dht.stats().info_hashes, 2);
+        assert_eq!(dht.stored_peers(&[1; 20]).len(), 2);
+    }
+
+    #[test]
+    fn read_only_nodes() {
+        let mut dht = Dht::new(NodeId([1; 20]));
+        let mut read_only = Dht::new(NodeId([2; 20]));
+        read_only.set_read_only(true);
+
+        read_only.get_peers(addr(1), [9; 20]);
+        deliver((&mut read_only, addr(2)), (&mut dht, addr(1)));
+        deliver((&mut dht, addr(1)), (&mut read_only, addr(2)));
+        assert!(dht.routing_table().is_empty());
+        assert_eq!(read_only.routing_table().len(), 1);
+
+        // answers nothing
+        dht.ping(addr(2));
+        deliver((&mut dht, addr(1)), (&mut read_only, addr(2)));
+        assert!(read_only.poll_packet().is_none());
+    }
+
+    #[test]
+    fn queries_time_out() {
+        let mut dht = Dht::new(NodeId([1; 20]));
+        dht.table.insert(NodeId([2; 20]), addr(2), Instant::now());
+        dht.ping(addr(2));
+        dht.tick(Instant::now() + QUERY_TIMEOUT);
+
+        assert_eq!(
+            dht.poll_event(),
+            Some(DhtEvent::QueryFailed {
+                to: addr(2),
+                query: Query::Ping,
+            })
+        );
+        assert_eq!(dht.routing_table().nodes().next().unwrap().failures, 1);
+    }
+
+    #[test]
+    fn task_answers_over_udp() -> io::Result<()> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let node = socket.local_addr()?;
+        let _task = DhtTask::spawn(socket, Dht::new(NodeId([1; 20])))?;
+
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let ping = KrpcMessage {
+            transaction: b"aa".to_vec(),
+            body: KrpcBody::Query {
+                id: NodeId([2; 20]),
+                query: Query::Ping,
+                want: Want::default(),
+            },
+            ip: None,
+            read_only: false,
+        };
+        client.send_to(&ping.encode(), node)?;
+        let mut buffer = [0; 2048];
+        let (length, _) = client.recv_from(&mut buffer)?;
+        let answer = KrpcMessage::decode(&buffer[..length]).unwrap();
+        assert_eq!(answer.ip, Some(client.local_addr()?));
+        assert_eq!(
+            answer.body,
+            KrpcBody::Response(Response {
+                id: NodeId([1; 20]),
+                ..Default::default()
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn nodes_of_both_families() {
+        let mut a = Dht::new(NodeId([1; 20]));
+        let mut b = Dht::new(NodeId([2; 20]));
+        let node6: SocketAddr = "[2001:db8::3]:6881".parse().unwrap();
+        b.set_dual_stack(true);
+        b.table.insert(NodeId([4; 20]), addr(4), Instant::now());
+        b.set_other_family_nodes(vec![(NodeId([3; 20]), node6)]);
+
+        // a single stack node only gets nodes of its family
+        a.find_node(addr(2), NodeId([3; 20]));
+        deliver((&mut a, addr(1)), (&mut b, addr(2)));
+        deliver((&mut b, addr(2)), (&mut a, addr(1)));
+        match a.poll_event() {
+            Some(DhtEvent::Response { response, .. }) => {
+                assert!(response.nodes.iter().all(|(_, addr)| addr.is_ipv4()))
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+
+        a.set_dual_stack(true);
+        a.find_node(addr(2), NodeId([3; 20]));
+        deliver((&mut a, addr(1)), (&mut b, addr(2)));
+        deliver((&mut b, addr(2)), (&mut a, addr(1)));
+        assert_eq!(a.poll_other_family_node(), Some((NodeId([3; 20]), node6)));
+        assert_eq!(a.poll_other_family_node(), None);
+
+        let state = DhtState {
+            id: NodeId([1; 20]),
+            nodes: vec![(NodeId([4; 20]), addr(4)), (NodeId([3; 20]), node6)],
+        };
+        assert_eq!(DhtState::from_bencode(&state.to_bencode()).unwrap(), state);
+        assert_eq!(
+            ipv6_state_path(Path::new("dir/dht.state")),
+            Path::new("dir/dht6.state")
+        );
+    }
+
+    #[test]
+    fn dual_stack_task_shares_nodes() -> io::Result<()> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let socket6 = UdpSocket::bind("[::1]:0")?;
+        let (node, node6) = (socket.local_addr()?, socket6.local_addr()?);
+        let mut dht6 = Dht::new(NodeId([2; 20]));
+        let known6: SocketAddr = "[::1]:6881".parse().unwrap();
+        dht6.table.insert(NodeId([3; 20]), known6, Instant::now());
+        let _task = DhtTask::spawn_dual_stack(socket, Dht::new(NodeId([1; 20])), socket6, dht6)?;
+
+        let query = |client: &UdpSocket, to: SocketAddr, want: Want| -> io::Result<Response> {
+            let find_node = KrpcMessage {
+                transaction: b"aa".to_vec(),
+                body: KrpcBody::Query {
+                    id: NodeId([9; 20]),
+                    query: Query::FindNode {
+                        target: NodeId([3; 20]),
+                    },
+                    want,
+                },
+                ip: None,
+                read_only: false,
+            };
+            client.send_to(&find_node.encode(), to)?;
+            let mut buffer = [0; 2048];
+            let (length, _) = client.recv_from(&mut buffer)?;
+            match KrpcMessage::decode(&buffer[..length]).unwrap().body {
+                KrpcBody::Response(response) => Ok(response),
+                body => panic!("unexpected {:?}", body),
+            }
+        };
+        let client6 = UdpSocket::bind("[::1]:0")?;
+        client6.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let response = query(&client6, node6, Want::default())?;
+        assert!(response.nodes.contains(&(NodeId([3; 20]), known6)));
+
+        // the ipv4 node gets the nodes of the ipv6 one on its next tick
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let response = query(&client, node, Want::BOTH)?;
+            if response.nodes.contains(&(NodeId([3; 20]), known6)) {
+                return Ok(());
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
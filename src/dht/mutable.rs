@@ -0,0 +1,114 @@
+use super::item::{mutable_target, Item};
+use crate::bencode::Bencode;
+use ed25519_dalek::SigningKey;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+/// the item of a resolved torrent is fetched again this often for updates
+const UPDATE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// until the item is found
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// item a publisher puts to point the torrent of its key and salt to a new
+/// version (BEP 46)
+pub fn torrent_item(info_hash: [u8; 20], key: &SigningKey, salt: Vec<u8>, seq: i64) -> Item {
+    let mut dict = HashMap::new();
+    dict.insert("ih".into(), info_hash.to_vec().into());
+    Item::mutable(Bencode::Dictionary(dict), key, salt, seq)
+}
+
+/// info hash the item points to
+pub fn item_info_hash(item: &Item) -> Option<[u8; 20]> {
+    item.value
+        .get("ih")
+        .and_then(Bencode::as_bytes)
+        .and_then(|info_hash| info_hash.try_into().ok())
+}
+
+/// Torrent published under a public key, see `Dht::follow_torrent`. Its
+/// item is fetched regularly and every newer version is reported.
+#[derive(Debug, Clone)]
+pub struct MutableTorrent {
+    pub public_key: [u8; 32],
+    pub salt: Vec<u8>,
+    /// of the current version, None until the item is found
+    pub info_hash: Option<[u8; 20]>,
+    seq: Option<i64>,
+    next_update: Instant,
+}
+
+impl MutableTorrent {
+    pub fn new(public_key: [u8; 32], salt: Vec<u8>, now: Instant) -> Self {
+        Self {
+            public_key,
+            salt,
+            info_hash: None,
+            seq: None,
+            next_update: now,
+        }
+    }
+
+    pub fn target(&self) -> [u8; 20] {
+        mutable_target(&self.public_key, &self.salt)
+    }
+
+    /// whether the item should be fetched now, it isn't due again until
+    /// `update` or the retry interval
+    pub fn poll_update(&mut self, now: Instant) -> bool {
+        if self.next_update > now {
+            return false;
+        }
+        self.next_update = now + RETRY_INTERVAL;
+        true
+    }
+
+    /// the new info hash if the item found is a newer version, the item
+    /// must be a valid one of our target
+    pub fn update(&mut self, item: Option<&Item>, now: Instant) -> Option<[u8; 20]> {
+        let item = item?;
+        let info_hash = item_info_hash(item)?;
+        if item.seq() <= self.seq {
+            return None;
+        }
+        self.next_update = now + UPDATE_INTERVAL;
+        self.seq = item.seq();
+        if self.info_hash == Some(info_hash) {
+            return None;
+        }
+        self.info_hash = Some(info_hash);
+        Some(info_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_newer_versions() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let start = Instant::now();
+        let mut torrent =
+            MutableTorrent::new(key.verifying_key().to_bytes(), b"salt".to_vec(), start);
+        assert!(torrent.poll_update(start));
+        assert!(!torrent.poll_update(start));
+        assert!(torrent.poll_update(start + RETRY_INTERVAL));
+
+        let first = torrent_item([1; 20], &key, b"salt".to_vec(), 1);
+        assert_eq!(first.target(), torrent.target());
+        assert_eq!(torrent.update(Some(&first), start), Some([1; 20]));
+        assert!(!torrent.poll_update(start + RETRY_INTERVAL));
+        assert!(torrent.poll_update(start + UPDATE_INTERVAL));
+
+        // older, or a new seq for the same version
+        let old = torrent_item([0; 20], &key, b"salt".to_vec(), 0);
+        assert_eq!(torrent.update(Some(&old), start), None);
+        let same = torrent_item([1; 20], &key, b"salt".to_vec(), 2);
+        assert_eq!(torrent.update(Some(&same), start), None);
+        let second = torrent_item([2; 20], &key, b"salt".to_vec(), 3);
+        assert_eq!(torrent.update(Some(&second), start), Some([2; 20]));
+        assert_eq!(torrent.info_hash, Some([2; 20]));
+        assert_eq!(torrent.update(None, start), None);
+    }
+}
@@ -0,0 +1,275 @@
+use super::security::is_secure_id;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// nodes per bucket
+pub const K: usize = 8;
+/// nodes that didn't answer this many queries in a row are replaced by new ones
+const MAX_FAILURES: u32 = 3;
+
+/// 160 bit identifier of a DHT node, in the same space as info hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0; 20];
+        random_bytes(&mut id);
+        Self(id)
+    }
+
+    /// xor metric of kademlia, compared as a big endian number
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (byte, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(&other.0)) {
+            *byte = a ^ b;
+        }
+        distance
+    }
+
+    /// length of the prefix shared with `other`, None for the same id
+    pub fn common_prefix(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let byte = distance.iter().position(|&byte| byte != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+}
+
+/// fills `buffer` with bytes from the random number generator of the OS, for
+/// the ids, secrets, cookies and nonces that must not be guessed
+pub fn random_bytes(buffer: &mut [u8]) {
+    getrandom::getrandom(buffer).expect("the OS has no random numbers to give")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    /// queries in a row that timed out
+    pub failures: u32,
+    /// the id matches the ip of the node, see `security`
+    pub secure: bool,
+}
+
+impl Node {
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// Known nodes grouped in buckets of `K` by the length of the prefix they
+/// share with our id, so we know many nodes close to us and a few far away.
+/// Nodes with ids that match their ip are preferred, so nodes choosing ids
+/// close to a target can't take over the buckets around it.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+    /// nodes with ids that don't match their ip are left out
+    enforce_secure_ids: bool,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![vec![]; 160],
+            enforce_secure_ids: false,
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// sorts the nodes into the buckets of the new id
+    pub fn set_id(&mut self, id: NodeId) {
+        let nodes: Vec<_> = self.buckets.iter_mut().flat_map(std::mem::take).collect();
+        self.id = id;
+        for node in nodes {
+            if let Some(prefix) = id.common_prefix(&node.id) {
+                if self.buckets[prefix].len() < K {
+                    self.buckets[prefix].push(node);
+                }
+            }
+        }
+    }
+
+    pub fn set_enforce_secure_ids(&mut self, enforce: bool) {
+        self.enforce_secure_ids = enforce;
+        if enforce {
+            for bucket in &mut self.buckets {
+                bucket.retain(|node| node.secure);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn buckets(&self) -> &[Vec<Node>] {
+        &self.buckets
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+
+    /// records a node that sent us a message, a full bucket only makes room
+    /// by evicting a bad node, or a node with an insecure id for a secure
+    /// one. Returns whether the node is in the table.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr, now: Instant) -> bool {
+        let secure = is_secure_id(&id, addr.ip());
+        if self.enforce_secure_ids && !secure {
+            return false;
+        }
+        let bucket = match self.id.common_prefix(&id) {
+            Some(prefix) => &mut self.buckets[prefix],
+            None => return false,
+        };
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = now;
+            node.failures = 0;
+            return true;
+        }
+        let node = Node {
+            id,
+            addr,
+            last_seen: now,
+            failures: 0,
+            secure,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+        let evicted = match bucket.iter().position(Node::is_bad) {
+            Some(bad) => Some(bad),
+            None if secure => bucket.iter().position(|node| !node.secure),
+            None => None,
+        };
+        match evicted {
+            Some(evicted) => {
+                bucket[evicted] = node;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// a query to the node timed out
+    pub fn failed(&mut self, addr: SocketAddr) {
+        if let Some(node) = self
+            .buckets
+            .iter_mut()
+            .flatten()
+            .find(|node| node.addr == addr)
+        {
+            node.failures += 1;
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(prefix) = self.id.common_prefix(id) {
+            self.buckets[prefix].retain(|node| node.id != *id);
+        }
+    }
+
+    /// the `count` good nodes closest to `target`, closest first
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<_> = self.nodes().filter(|node| !node.is_bad()).collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.into_iter().take(count).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::security::secure_id;
+    use super::*;
+
+    fn id(first: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first;
+        NodeId(id)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn prefix_and_distance() {
+        assert_eq!(id(0).common_prefix(&id(0)), None);
+        assert_eq!(id(0).common_prefix(&id(0x80)), Some(0));
+        assert_eq!(id(0).common_prefix(&id(1)), Some(7));
+        assert_eq!(id(3).distance(&id(5))[0], 6);
+        assert_ne!(NodeId::random(), NodeId::random());
+    }
+
+    #[test]
+    fn buckets_fill_and_evict_bad_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(id(0));
+        assert!(!table.insert(id(0), addr(1), now));
+        // every id starting with a set bit lands in the first bucket
+        for port in 0..K as u16 {
+            assert!(table.insert(id(0x80 + port as u8), addr(port), now));
+        }
+        assert!(!table.insert(id(0xff), addr(100), now));
+        assert_eq!(table.buckets()[0].len(), K);
+
+        for _ in 0..MAX_FAILURES {
+            table.failed(addr(3));
+        }
+        assert!(table.insert(id(0xff), addr(100), now));
+        assert_eq!(table.len(), K);
+        assert!(table.nodes().all(|node| node.addr != addr(3)));
+    }
+
+    #[test]
+    fn secure_ids_are_preferred() {
+        let now = Instant::now();
+        let ip = |last| SocketAddr::from(([124, 31, 75, last], 6881));
+        let secure = secure_id(ip(100).ip());
+        // the secure node and the ones below share no prefix with our id
+        let high_bit = secure.0[0] & 0x80;
+        let mut table = RoutingTable::new(id(high_bit ^ 0x80));
+        for port in 0..K as u8 {
+            assert!(table.insert(id(high_bit | port), ip(port), now));
+        }
+        assert!(table.nodes().all(|node| !node.secure));
+
+        assert!(table.insert(secure, ip(100), now));
+        assert_eq!(table.len(), K);
+        assert!(table.nodes().any(|node| node.id == secure && node.secure));
+
+        table.set_enforce_secure_ids(true);
+        assert_eq!(table.len(), 1);
+        assert!(!table.insert(id(high_bit | 0x40), ip(101), now));
+        assert!(table.insert(id(high_bit | 0x40), addr(101), now));
+    }
+
+    #[test]
+    fn closest_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(id(0));
+        for first in [0x10, 0x20, 0x30, 0x40] {
+            table.insert(id(first), addr(first as u16), now);
+        }
+
+        let closest: Vec<_> = table
+            .closest(&id(0x31), 2)
+            .into_iter()
+            .map(|node| node.id)
+            .collect();
+        assert_eq!(closest, vec![id(0x30), id(0x20)]);
+    }
+}
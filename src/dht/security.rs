@@ -0,0 +1,116 @@
+use super::routing::{random_bytes, NodeId};
+use std::net::IpAddr;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// crc32 with the Castagnoli polynomial, computed bit by bit as it only
+/// hashes a few bytes per node
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// the 21 bits of the id that depend on the ip, for the random byte `r`
+fn id_prefix(ip: IpAddr, r: u8) -> u32 {
+    let mut bytes: Vec<u8> = match ip {
+        IpAddr::V4(ip) => ip
+            .octets()
+            .iter()
+            .zip(&V4_MASK)
+            .map(|(a, b)| a & b)
+            .collect(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .zip(&V6_MASK)
+            .map(|(a, b)| a & b)
+            .collect(),
+    };
+    bytes[0] |= (r & 0x7) << 5;
+    crc32c(&bytes)
+}
+
+/// nodes on local networks can't have a public ip to derive their id from
+pub fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// node id that other nodes accept from `ip` (BEP 42), the id can't be
+/// chosen freely so an attacker can't place many nodes next to a target
+pub fn secure_id(ip: IpAddr) -> NodeId {
+    let mut id = [0; 20];
+    random_bytes(&mut id);
+    let crc = id_prefix(ip, id[19]);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x7);
+    NodeId(id)
+}
+
+/// whether the id was derived from the ip the node uses
+pub fn is_secure_id(id: &NodeId, ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+    let crc = id_prefix(ip, id.0[19]);
+    id.0[0] == (crc >> 24) as u8
+        && id.0[1] == (crc >> 16) as u8
+        && id.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn bep42_test_vectors() {
+        let vectors = [
+            ([124, 31, 75, 21], 1, [0x5f, 0xbf, 0xbf]),
+            ([21, 75, 31, 124], 86, [0x5a, 0x3c, 0xe9]),
+            ([65, 23, 51, 170], 22, [0xa5, 0xd4, 0x32]),
+            ([84, 124, 73, 14], 65, [0x1b, 0x03, 0x21]),
+            ([43, 213, 53, 83], 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, r, prefix) in vectors {
+            let ip = IpAddr::V4(Ipv4Addr::from(ip));
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = r;
+            assert!(is_secure_id(&NodeId(id), ip));
+            id[1] ^= 1;
+            assert!(!is_secure_id(&NodeId(id), ip));
+        }
+    }
+
+    #[test]
+    fn generated_ids_are_secure() {
+        let ip = IpAddr::V4(Ipv4Addr::new(124, 31, 75, 21));
+        let other = IpAddr::V4(Ipv4Addr::new(21, 75, 31, 124));
+        let id = secure_id(ip);
+        assert!(is_secure_id(&id, ip));
+        assert!(!is_secure_id(&id, other));
+        assert!(is_secure_id(&id, "192.168.1.2".parse().unwrap()));
+        assert!(is_secure_id(
+            &secure_id("2001:db8::1".parse().unwrap()),
+            "2001:db8::2".parse().unwrap()
+        ));
+    }
+}
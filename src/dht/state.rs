@@ -0,0 +1,73 @@
+use super::krpc::{decode_nodes, decode_nodes6, encode_nodes};
+use super::routing::NodeId;
+use crate::bencode::{Bencode, Parser};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// What a DHT node keeps across restarts: its id, so the nodes close to it
+/// stay close, and the nodes of its routing table to bootstrap from. The
+/// ipv6 ones are saved as `nodes6`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+}
+
+impl DhtState {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&self.to_bencode().encode())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// None if nothing was saved yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bencode = Parser::new(std::fs::read(path)?).parse()?;
+        Ok(Some(Self::from_bencode(&bencode)?))
+    }
+
+    pub fn to_bencode(&self) -> Bencode {
+        let mut dict = HashMap::new();
+        dict.insert("node-id".into(), self.id.0.to_vec().into());
+        let (nodes6, nodes): (Vec<_>, Vec<_>) =
+            self.nodes.iter().partition(|(_, addr)| addr.is_ipv6());
+        dict.insert("nodes".into(), encode_nodes(nodes).into());
+        if !nodes6.is_empty() {
+            dict.insert("nodes6".into(), encode_nodes(nodes6).into());
+        }
+        Bencode::Dictionary(dict)
+    }
+
+    pub fn from_bencode(bencode: &Bencode) -> Result<Self> {
+        let id = bencode
+            .get("node-id")
+            .and_then(Bencode::as_bytes)
+            .and_then(|id| id.try_into().ok())
+            .ok_or_else(|| anyhow!("invalid node id"))?;
+        let nodes = bencode
+            .get("nodes")
+            .and_then(Bencode::as_bytes)
+            .ok_or_else(|| anyhow!("missing nodes"))?;
+        let mut nodes = decode_nodes(nodes)?;
+        if let Some(nodes6) = bencode.get("nodes6").and_then(Bencode::as_bytes) {
+            nodes.extend(decode_nodes6(nodes6)?);
+        }
+        Ok(Self {
+            id: NodeId(id),
+            nodes,
+        })
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// Allows `rate` events per second on average, and as many at once after
+/// being idle for a second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// starts full
+    pub fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = self.last.max(now);
+    }
+
+    /// whether the event is allowed now
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate
+    }
+}
+
+/// One `TokenBucket` per key, like the ip of the nodes querying us
+#[derive(Debug, Clone)]
+pub struct Throttle<T> {
+    rate: u32,
+    buckets: HashMap<T, TokenBucket>,
+}
+
+impl<T: Hash + Eq> Throttle<T> {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn take(&mut self, key: T, now: Instant) -> bool {
+        let rate = self.rate;
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .take(now)
+    }
+
+    /// forgets the keys idle long enough to be allowed a full burst again
+    pub fn prune(&mut self, now: Instant) {
+        self.buckets.retain(|_, bucket| !bucket.is_full(now));
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_rate_per_key() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(2);
+        assert!(throttle.take(1, start));
+        assert!(throttle.take(1, start));
+        assert!(!throttle.take(1, start));
+        assert!(throttle.take(2, start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(throttle.take(1, later));
+        assert!(!throttle.take(1, later));
+        // key 2 is allowed a full burst again
+        throttle.prune(later);
+        assert_eq!(throttle.len(), 1);
+        throttle.prune(start + Duration::from_secs(2));
+        assert_eq!(throttle.len(), 0);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::storage::Storage;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiskJob {
+    Write {
+        piece: usize,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    /// reads a block to upload to a peer
+    Read {
+        piece: usize,
+        offset: u32,
+        length: u32,
+    },
+    /// makes every write completed so far durable, `sequence` is handed back
+    /// so the caller knows which writes it covered
+    Sync { sequence: u64 },
+}
+
+#[derive(Debug)]
+pub enum DiskCompletion {
+    Written {
+        piece: usize,
+        offset: u32,
+        length: u32,
+    },
+    Read {
+        piece: usize,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    Failed {
+        piece: usize,
+        offset: u32,
+        /// bytes of the job, so the write queue can still be accounted for
+        length: u32,
+        error: anyhow::Error,
+    },
+    Synced {
+        sequence: u64,
+    },
+    SyncFailed {
+        error: anyhow::Error,
+    },
+}
+
+impl DiskCompletion {
+    fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            DiskCompletion::Failed { .. } | DiskCompletion::SyncFailed { .. }
+        )
+    }
+}
+
+impl DiskJob {
+    /// performs the job on the calling thread
+    pub fn run(self, storage: &dyn Storage) -> DiskCompletion {
+        let span = tracing::trace_span!("disk_job", job = %self.kind());
+        let _entered = span.enter();
+        let started = Instant::now();
+        let completion = self.run_untimed(storage);
+        tracing::trace!(
+            failed = completion.is_failure(),
+            micros = started.elapsed().as_micros() as u64,
+            "disk job done"
+        );
+        completion
+    }
+
+    fn kind(&self) -> String {
+        match self {
+            DiskJob::Write {
+                piece,
+                offset,
+                data,
+            } => format!("write {}+{}:{}", piece, offset, data.len()),
+            DiskJob::Read {
+                piece,
+                offset,
+                length,
+            } => format!("read {}+{}:{}", piece, offset, length),
+            DiskJob::Sync { sequence } => format!("sync {}", sequence),
+        }
+    }
+
+    fn run_untimed(self, storage: &dyn Storage) -> DiskCompletion {
+        match self {
+            DiskJob::Write {
+                piece,
+                offset,
+                data,
+            } => match storage.write_block(piece, offset, &data) {
+                Ok(()) => DiskCompletion::Written {
+                    piece,
+                    offset,
+                    length: data.len() as u32,
+                },
+                Err(error) => DiskCompletion::Failed {
+                    piece,
+                    offset,
+                    length: data.len() as u32,
+                    error,
+                },
+            },
+            DiskJob::Read {
+                piece,
+                offset,
+                length,
+            } => match storage.read_block(piece, offset, length) {
+                Ok(data) => DiskCompletion::Read {
+                    piece,
+                    offset,
+                    data,
+                },
+                Err(error) => DiskCompletion::Failed {
+                    piece,
+                    offset,
+                    length,
+                    error,
+                },
+            },
+            DiskJob::Sync { sequence } => match storage.flush() {
+                Ok(()) => DiskCompletion::Synced { sequence },
+                Err(error) => DiskCompletion::SyncFailed { error },
+            },
+        }
+    }
+}
+
+/// Threads doing the reads and writes of a torrent so the network thread never
+/// blocks on the disk. Jobs of the same piece can complete out of order when
+/// there is more than one thread.
+pub struct DiskIo {
+    jobs: Option<Sender<DiskJob>>,
+    results: Receiver<DiskCompletion>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl DiskIo {
+    pub fn new(storage: Arc<dyn Storage>, threads: usize) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<DiskJob>();
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let jobs = Arc::clone(&job_receiver);
+                let results = result_sender.clone();
+                let storage = Arc::clone(&storage);
+                thread::spawn(move || loop {
+                    let job = match jobs.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    if results.send(job.run(&*storage)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            results,
+            workers,
+        }
+    }
+
+    /// queues the job and returns immediately
+    pub fn submit(&self, job: DiskJob) {
+        if let Some(jobs) = &self.jobs {
+            // workers only exit once the sender is dropped
+            jobs.send(job).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<DiskCompletion> {
+        self.results.try_recv().ok()
+    }
+
+    /// blocks until the next job is done
+    pub fn recv(&self) -> Option<DiskCompletion> {
+        self.results.recv().ok()
+    }
+}
+
+impl Drop for DiskIo {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+    use anyhow::Result;
+
+    #[test]
+    fn writes_then_reads() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_disk_test");
+        let storage = FileStorage::new(vec![(dir.join("a"), 8)], 4);
+        let disk = DiskIo::new(Arc::new(storage), 1);
+
+        disk.submit(DiskJob::Write {
+            piece: 1,
+            offset: 0,
+            data: b"efgh".to_vec(),
+        });
+        disk.submit(DiskJob::Read {
+            piece: 1,
+            offset: 1,
+            length: 2,
+        });
+        disk.submit(DiskJob::Write {
+            piece: 2,
+            offset: 0,
+            data: b"ijkl".to_vec(),
+        });
+
+        assert!(matches!(
+            disk.recv(),
+            Some(DiskCompletion::Written { piece: 1, .. })
+        ));
+        match disk.recv() {
+            Some(DiskCompletion::Read { data, .. }) => assert_eq!(data, b"fg"),
+            completion => panic!("unexpected completion {:?}", completion),
+        }
+        assert!(matches!(
+            disk.recv(),
+            Some(DiskCompletion::Failed { piece: 2, .. })
+        ));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
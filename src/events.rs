@@ -0,0 +1,318 @@
+use crate::json::Json;
+use crate::metainfo::{to_hex, Metainfo};
+use crate::peer::PeerSource;
+use crate::seeding::SeedLimit;
+use crate::torrent::{Event, TorrentState};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// What happens in a session, see `Session::subscribe`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    TorrentAdded {
+        info_hash: [u8; 20],
+    },
+    TorrentRemoved {
+        info_hash: [u8; 20],
+    },
+    /// the metadata of a magnet link was fetched, the torrent is added
+    MetadataReceived {
+        info_hash: [u8; 20],
+        metainfo: Box<Metainfo>,
+    },
+    PieceVerified {
+        info_hash: [u8; 20],
+        piece: usize,
+    },
+    /// every selected file is downloaded
+    TorrentFinished {
+        info_hash: [u8; 20],
+    },
+    TorrentPaused {
+        info_hash: [u8; 20],
+    },
+    /// the torrent is paused or removed, depending on the action of its
+    /// seed limits
+    SeedLimitReached {
+        info_hash: [u8; 20],
+        limit: SeedLimit,
+    },
+    TorrentResumed {
+        info_hash: [u8; 20],
+    },
+    StateChanged {
+        info_hash: [u8; 20],
+        state: TorrentState,
+    },
+    PeerConnected {
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+        source: PeerSource,
+    },
+    PeerDisconnected {
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+    },
+    TrackerAnnounced {
+        info_hash: [u8; 20],
+        url: String,
+        peers: usize,
+    },
+    TrackerError {
+        info_hash: [u8; 20],
+        url: String,
+        error: String,
+    },
+    /// a recoverable error of the torrent, also kept until
+    /// `Torrent::take_alerts`
+    Alert {
+        info_hash: [u8; 20],
+        alert: Alert,
+    },
+    /// the other events of a torrent, see `torrent::Event`
+    Torrent {
+        info_hash: [u8; 20],
+        event: Event,
+    },
+}
+
+impl SessionEvent {
+    pub fn from_torrent(info_hash: [u8; 20], event: Event) -> Self {
+        match event {
+            Event::PieceVerified(piece) => SessionEvent::PieceVerified { info_hash, piece },
+            Event::Finished => SessionEvent::TorrentFinished { info_hash },
+            Event::StateChanged(state) => SessionEvent::StateChanged { info_hash, state },
+            event => SessionEvent::Torrent { info_hash, event },
+        }
+    }
+
+    /// of the torrent the event is about
+    pub fn info_hash(&self) -> [u8; 20] {
+        match self {
+            SessionEvent::TorrentAdded { info_hash }
+            | SessionEvent::TorrentRemoved { info_hash }
+            | SessionEvent::MetadataReceived { info_hash, .. }
+            | SessionEvent::PieceVerified { info_hash, .. }
+            | SessionEvent::TorrentFinished { info_hash }
+            | SessionEvent::TorrentPaused { info_hash }
+            | SessionEvent::SeedLimitReached { info_hash, .. }
+            | SessionEvent::TorrentResumed { info_hash }
+            | SessionEvent::StateChanged { info_hash, .. }
+            | SessionEvent::PeerConnected { info_hash, .. }
+            | SessionEvent::PeerDisconnected { info_hash, .. }
+            | SessionEvent::TrackerAnnounced { info_hash, .. }
+            | SessionEvent::TrackerError { info_hash, .. }
+            | SessionEvent::Alert { info_hash, .. }
+            | SessionEvent::Torrent { info_hash, .. } => *info_hash,
+        }
+    }
+
+    /// `{"type":"piece_verified","info_hash":..,"piece":3}`, the type in
+    /// snake case with the fields of the event
+    pub fn to_json(&self) -> Json {
+        let json = |kind: &str| Json::object().with("type", kind);
+        let event = match self {
+            SessionEvent::TorrentAdded { .. } => json("torrent_added"),
+            SessionEvent::TorrentRemoved { .. } => json("torrent_removed"),
+            SessionEvent::MetadataReceived { metainfo, .. } => json("metadata_received")
+                .with("name", metainfo.info.name.as_str())
+                .with("size", metainfo.info.total_length()),
+            SessionEvent::PieceVerified { piece, .. } => {
+                json("piece_verified").with("piece", *piece)
+            }
+            SessionEvent::TorrentFinished { .. } => json("torrent_finished"),
+            SessionEvent::TorrentPaused { .. } => json("torrent_paused"),
+            SessionEvent::SeedLimitReached { limit, .. } => {
+                json("seed_limit_reached").with("limit", limit.to_string())
+            }
+            SessionEvent::TorrentResumed { .. } => json("torrent_resumed"),
+            SessionEvent::StateChanged { state, .. } => {
+                json("state_changed").with("state", state.to_string())
+            }
+            SessionEvent::PeerConnected { addr, source, .. } => json("peer_connected")
+                .with("addr", addr.to_string())
+                .with("source", source.to_string()),
+            SessionEvent::PeerDisconnected { addr, .. } => {
+                json("peer_disconnected").with("addr", addr.to_string())
+            }
+            SessionEvent::TrackerAnnounced { url, peers, .. } => json("tracker_announced")
+                .with("url", url.as_str())
+                .with("peers", *peers),
+            SessionEvent::TrackerError { url, error, .. } => json("tracker_error")
+                .with("url", url.as_str())
+                .with("error", error.as_str()),
+            SessionEvent::Alert { alert, .. } => json("alert").with("message", alert.to_string()),
+            SessionEvent::Torrent { event, .. } => match event {
+                Event::Finished => json("torrent_finished"),
+                Event::PieceVerified(piece) => json("piece_verified").with("piece", *piece),
+                Event::HashFailed(piece) => json("hash_failed").with("piece", *piece),
+                Event::Checking { checked, total } => json("checking")
+                    .with("checked", *checked)
+                    .with("total", *total),
+                Event::Activated => json("activated"),
+                Event::Queued => json("queued"),
+                Event::DiskError(error) => json("disk_error").with("error", error.as_str()),
+                Event::Corrupted(piece) => json("corrupted").with("piece", *piece),
+                Event::StateChanged(state) => {
+                    json("state_changed").with("state", state.to_string())
+                }
+                Event::FileMoved { file, path } => json("file_moved")
+                    .with("file", *file)
+                    .with("path", path.display().to_string()),
+                Event::FileCompleted { file, path } => json("file_completed")
+                    .with("file", *file)
+                    .with("path", path.display().to_string()),
+                Event::MovingStorage { moved, total } => json("moving_storage")
+                    .with("moved", *moved)
+                    .with("total", *total),
+                Event::StorageMoved(path) => {
+                    json("storage_moved").with("path", path.display().to_string())
+                }
+                Event::StorageMoveFailed(error) => {
+                    json("storage_move_failed").with("error", error.as_str())
+                }
+            },
+        };
+        event.with("info_hash", to_hex(&self.info_hash()))
+    }
+}
+
+/// An error the session or a torrent recovered from, or will once the
+/// cause is fixed, for the user to see and react to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    /// the announce is retried later
+    TrackerFailed { url: String, error: String },
+    /// writing, syncing or allocating the files failed, the torrent stops
+    /// downloading until its error is cleared
+    DiskWriteFailed { error: String },
+    /// the block wasn't uploaded
+    DiskReadFailed { piece: usize, error: String },
+    /// the piece is downloaded again
+    HashFailed { piece: usize },
+    /// the configured port was taken, the session listens on another one
+    ListenFailed { port: u16, error: String },
+    /// refreshing the blocklist failed, the previous one stays in use
+    BlocklistFailed { error: String },
+    /// a torrent of the last session couldn't be added back, `torrent` is
+    /// its info hash in hex
+    RestoreFailed { torrent: String, error: String },
+    /// a file of a watch folder couldn't be added, it was moved to the
+    /// `failed` subfolder if it could be
+    WatchFailed { file: String, error: String },
+    /// the command or webhook run as `torrent` completed failed, see
+    /// `hooks`
+    HookFailed { torrent: String, error: String },
+    /// fetching the rss feed of that name, or adding one of its items,
+    /// failed, see `rss`
+    FeedFailed { feed: String, error: String },
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Alert::TrackerFailed { url, error } => write!(f, "tracker {} failed: {}", url, error),
+            Alert::DiskWriteFailed { error } => write!(f, "writing failed: {}", error),
+            Alert::DiskReadFailed { piece, error } => {
+                write!(f, "reading piece {} failed: {}", piece, error)
+            }
+            Alert::HashFailed { piece } => write!(f, "piece {} failed its hash check", piece),
+            Alert::ListenFailed { port, error } => {
+                write!(f, "listening on port {} failed: {}", port, error)
+            }
+            Alert::BlocklistFailed { error } => {
+                write!(f, "updating the blocklist failed: {}", error)
+            }
+            Alert::RestoreFailed { torrent, error } => {
+                write!(f, "restoring torrent {} failed: {}", torrent, error)
+            }
+            Alert::WatchFailed { file, error } => {
+                write!(f, "adding {} from a watch folder failed: {}", file, error)
+            }
+            Alert::HookFailed { torrent, error } => {
+                write!(f, "the completion hook of {} failed: {}", torrent, error)
+            }
+            Alert::FeedFailed { feed, error } => {
+                write!(f, "the rss feed {} failed: {}", feed, error)
+            }
+        }
+    }
+}
+
+/// Hands every event to each subscriber as it happens, so UIs block on
+/// their receiver instead of polling the torrents. Subscribers that hung up
+/// are dropped on the next event.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<SessionEvent>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the events from now on
+    pub fn subscribe(&self) -> Receiver<SessionEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn send(&self, event: SessionEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcasts_to_subscribers() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        bus.send(SessionEvent::TorrentAdded { info_hash: [1; 20] });
+        drop(second);
+        bus.send(SessionEvent::from_torrent([1; 20], Event::Finished));
+        assert_eq!(
+            first.try_iter().collect::<Vec<_>>(),
+            [
+                SessionEvent::TorrentAdded { info_hash: [1; 20] },
+                SessionEvent::TorrentFinished { info_hash: [1; 20] }
+            ]
+        );
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn encodes_events_as_json() -> anyhow::Result<()> {
+        let hex = to_hex(&[1; 20]);
+        assert_eq!(
+            SessionEvent::from_torrent([1; 20], Event::PieceVerified(3))
+                .to_json()
+                .to_string(),
+            format!(
+                r#"{{"type":"piece_verified","piece":3,"info_hash":"{}"}}"#,
+                hex
+            )
+        );
+        let moved =
+            SessionEvent::from_torrent([1; 20], Event::MovingStorage { moved: 1, total: 2 });
+        assert_eq!(
+            Json::parse(&moved.to_json().to_string())?,
+            Json::object()
+                .with("type", "moving_storage")
+                .with("moved", 1u64)
+                .with("total", 2u64)
+                .with("info_hash", hex.as_str())
+        );
+        Ok(())
+    }
+}
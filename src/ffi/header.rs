@@ -0,0 +1,277 @@
+//! Writes the C header of `ffi` from its source, so the two can't drift
+//! apart. Only what `ffi` uses is understood: `//!` and `///` comments,
+//! `pub const`, opaque `pub struct`s, `#[repr(C)]` structs, callback
+//! `pub type`s and `#[no_mangle]` functions. Shared with build.rs, so it
+//! only uses std.
+
+/// the header of the source of `ffi`, an error names what isn't understood
+pub fn generate(source: &str) -> Result<String, String> {
+    let mut header = String::new();
+    let mut lines = source.lines().map(str::trim).peekable();
+    while let Some(line) = lines.peek() {
+        match line.strip_prefix("//!") {
+            Some(doc) => {
+                header.push_str(&comment(doc));
+                lines.next();
+            }
+            None => break,
+        }
+    }
+    header.push_str(
+        "\n#ifndef TORRENT_RS_H\n#define TORRENT_RS_H\n\n\
+         #include <stdbool.h>\n#include <stddef.h>\n#include <stdint.h>\n\n\
+         #ifdef __cplusplus\nextern \"C\" {\n#endif\n",
+    );
+    let mut docs = String::new();
+    let mut repr_c = false;
+    let mut no_mangle = false;
+    while let Some(line) = lines.next() {
+        if line == "#[cfg(test)]" {
+            break;
+        }
+        if let Some(doc) = line.strip_prefix("///") {
+            docs.push_str(&comment(doc));
+            continue;
+        }
+        match line {
+            "#[repr(C)]" => repr_c = true,
+            "#[no_mangle]" => no_mangle = true,
+            _ if line.starts_with("#[") => {}
+            _ if line.starts_with("pub const ") => {
+                let item = line.trim_start_matches("pub const ").trim_end_matches(';');
+                let (name, value) = item
+                    .split_once(':')
+                    .and_then(|(name, rest)| Some((name, rest.split_once('=')?.1)))
+                    .ok_or_else(|| format!("unknown const {}", line))?;
+                header.push_str(&format!(
+                    "\n{}#define {} {}\n",
+                    docs,
+                    name.trim(),
+                    value.trim()
+                ));
+            }
+            _ if line.starts_with("pub struct ") => {
+                let name = line
+                    .trim_start_matches("pub struct ")
+                    .trim_end_matches(['{', ';'])
+                    .trim();
+                let mut fields = String::new();
+                let mut field_docs = String::new();
+                if line.ends_with('{') {
+                    for line in lines.by_ref() {
+                        if line == "}" {
+                            break;
+                        }
+                        // the fields of opaque structs stay private
+                        if !repr_c {
+                            continue;
+                        }
+                        if let Some(doc) = line.strip_prefix("///") {
+                            field_docs.push_str(&format!("  {}", comment(doc)));
+                            continue;
+                        }
+                        let (field, kind) = line
+                            .trim_start_matches("pub ")
+                            .trim_end_matches(',')
+                            .split_once(':')
+                            .ok_or_else(|| format!("unknown field {}", line))?;
+                        fields.push_str(&field_docs);
+                        fields
+                            .push_str(&format!("  {};\n", declaration(kind.trim(), field.trim())?));
+                        field_docs.clear();
+                    }
+                }
+                match repr_c {
+                    true => header.push_str(&format!(
+                        "\n{}typedef struct {} {{\n{}}} {};\n",
+                        docs, name, fields, name
+                    )),
+                    // only handled through pointers
+                    false => {
+                        header.push_str(&format!("\n{}typedef struct {} {};\n", docs, name, name))
+                    }
+                }
+            }
+            _ if line.starts_with("pub type ") => {
+                let item = until(line, &mut lines, ';');
+                let (name, kind) = item
+                    .trim_start_matches("pub type ")
+                    .trim_end_matches(';')
+                    .split_once('=')
+                    .ok_or_else(|| format!("unknown type {}", item))?;
+                let function = kind
+                    .trim()
+                    .strip_prefix("Option<")
+                    .and_then(|kind| kind.strip_suffix('>'))
+                    .ok_or_else(|| format!("{} has to be an Option of a function", item))?;
+                let (params, returns) = signature(function)?;
+                header.push_str(&format!(
+                    "\n{}typedef {}(*{})({});\n",
+                    docs,
+                    spaced(&returns),
+                    name.trim(),
+                    params
+                ));
+            }
+            _ if no_mangle => {
+                let item = until(line, &mut lines, '{');
+                let (params, returns) = signature(item.trim_end_matches('{').trim())?;
+                let name = item
+                    .split("fn ")
+                    .nth(1)
+                    .and_then(|rest| rest.split('(').next())
+                    .ok_or_else(|| format!("unknown function {}", item))?;
+                header.push_str(&format!(
+                    "\n{}{}{}({});\n",
+                    docs,
+                    spaced(&returns),
+                    name.trim(),
+                    params
+                ));
+            }
+            _ => {}
+        }
+        if !line.starts_with("#[") {
+            docs.clear();
+            repr_c = false;
+            no_mangle = false;
+        }
+    }
+    header.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n#endif\n");
+    Ok(header)
+}
+
+/// the line and the ones after it, joined, up to the one ending with `end`
+fn until<'a>(line: &str, lines: &mut impl Iterator<Item = &'a str>, end: char) -> String {
+    let mut item = line.to_string();
+    while !item.ends_with(end) {
+        match lines.next() {
+            Some(line) => {
+                if !item.ends_with('(') && !line.starts_with(')') {
+                    item.push(' ');
+                }
+                item.push_str(line);
+            }
+            None => break,
+        }
+    }
+    item.replace(",)", ")")
+}
+
+fn comment(doc: &str) -> String {
+    match doc.trim() {
+        "" => "//\n".to_string(),
+        doc => format!("// {}\n", doc.replace('`', "")),
+    }
+}
+
+/// the C parameters and return type of `extern "C" fn(..) -> ..`
+fn signature(function: &str) -> Result<(String, String), String> {
+    let open = function
+        .find('(')
+        .ok_or_else(|| format!("unknown function {}", function))?;
+    let close = function
+        .rfind(')')
+        .ok_or_else(|| format!("unknown function {}", function))?;
+    let returns = match function[close + 1..].trim().strip_prefix("->") {
+        Some(kind) => c_type(kind.trim())?,
+        None => "void".to_string(),
+    };
+    let params = split_params(&function[open + 1..close])
+        .into_iter()
+        .map(|param| {
+            let (name, kind) = param
+                .split_once(':')
+                .ok_or_else(|| format!("unnamed parameter {}", param))?;
+            declaration(kind.trim(), name.trim())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    match params.is_empty() {
+        true => Ok(("void".to_string(), returns)),
+        false => Ok((params.join(", "), returns)),
+    }
+}
+
+/// the parameters split at the commas that aren't nested
+fn split_params(params: &str) -> Vec<String> {
+    let mut split = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in params.chars() {
+        match c {
+            '(' | '<' | '[' => depth += 1,
+            // of a return type
+            '>' if current.ends_with('-') => {}
+            ')' | '>' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    split.push(current);
+    split
+        .into_iter()
+        .map(|param| param.trim().to_string())
+        .filter(|param| !param.is_empty())
+        .collect()
+}
+
+/// `kind name`, or `kind name[n]` for arrays
+fn declaration(kind: &str, name: &str) -> Result<String, String> {
+    if let Some(array) = kind
+        .strip_prefix('[')
+        .and_then(|kind| kind.strip_suffix(']'))
+    {
+        let (element, length) = array
+            .split_once(';')
+            .ok_or_else(|| format!("unknown array {}", kind))?;
+        return Ok(format!(
+            "{}{}[{}]",
+            spaced(&c_type(element.trim())?),
+            name,
+            length.trim()
+        ));
+    }
+    Ok(format!("{}{}", spaced(&c_type(kind)?), name))
+}
+
+/// with the space that goes before a name, none after a `*`
+fn spaced(kind: &str) -> String {
+    match kind.ends_with('*') {
+        true => kind.to_string(),
+        false => format!("{} ", kind),
+    }
+}
+
+fn c_type(kind: &str) -> Result<String, String> {
+    if let Some(pointee) = kind.strip_prefix("*mut ") {
+        return Ok(format!("{}*", spaced(&c_type(pointee.trim())?)));
+    }
+    if let Some(pointee) = kind.strip_prefix("*const ") {
+        let pointee = c_type(pointee.trim())?;
+        return match pointee.ends_with('*') {
+            true => Ok(format!("{}const *", pointee)),
+            false => Ok(format!("const {} *", pointee)),
+        };
+    }
+    let kind = match kind {
+        "c_char" => "char",
+        "c_int" => "int",
+        "c_void" => "void",
+        "bool" => "bool",
+        "u8" => "uint8_t",
+        "u16" => "uint16_t",
+        "u32" => "uint32_t",
+        "u64" => "uint64_t",
+        "i64" => "int64_t",
+        "usize" => "size_t",
+        "f64" => "double",
+        // the types of `ffi` itself
+        _ if kind.starts_with("TorrentRs") => kind,
+        _ => return Err(format!("{} has no C type", kind)),
+    };
+    Ok(kind.to_string())
+}
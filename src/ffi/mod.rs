@@ -0,0 +1,697 @@
+//! torrent_rs: a C interface to the BitTorrent engine, generated from
+//! src/ffi/mod.rs, see `ffi` in the documentation of the crate.
+//!
+//! A session is created from a config file and driven by calling
+//! torrent_rs_session_poll regularly, every 100 ms or so, from the thread
+//! that owns it, which is also where its events are passed to the callback.
+//! Torrents are opaque handles, each freed on its own, that stay valid
+//! after their torrent is removed. Functions return TORRENT_RS_OK or an
+//! error code, with a message from torrent_rs_last_error. Strings are UTF-8
+//! and those handed out are freed with torrent_rs_string_free.
+//!
+//! Every pointer is valid, or NULL where a function says it may be, and a
+//! session is used by one thread at a time.
+//!
+//! The crate is only built as a Rust library, the C ones are asked of
+//! cargo with the feature:
+//!
+//! ```text
+//! cargo rustc --lib --release --features ffi --crate-type cdylib
+//! cargo rustc --lib --release --features ffi --crate-type staticlib
+//! ```
+
+// the safety rules are the same for every function, see above
+#![allow(clippy::missing_safety_doc)]
+
+pub mod header;
+
+use crate::config::Config;
+use crate::events::SessionEvent;
+use crate::metainfo::Metainfo;
+use crate::session::{AddOptions, Session};
+use crate::torrent::{TorrentHandle, TorrentState};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+/// how long freeing a session waits for its trackers and disk
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// between ticks of the session, see `Session::tick`
+const TICK: Duration = Duration::from_secs(1);
+
+/// the call succeeded
+pub const TORRENT_RS_OK: c_int = 0;
+/// a pointer is NULL or a string isn't UTF-8
+pub const TORRENT_RS_ERROR_INVALID_ARGUMENT: c_int = 1;
+/// no torrent matches
+pub const TORRENT_RS_ERROR_NOT_FOUND: c_int = 2;
+/// reading or writing a file or socket failed
+pub const TORRENT_RS_ERROR_IO: c_int = 3;
+/// any other failure, see torrent_rs_last_error
+pub const TORRENT_RS_ERROR_FAILED: c_int = 4;
+/// a bug of the engine, the session may be left in a bad state
+pub const TORRENT_RS_ERROR_PANIC: c_int = 5;
+
+/// the states of TorrentRsStats
+pub const TORRENT_RS_STATE_FETCHING_METADATA: c_int = 0;
+pub const TORRENT_RS_STATE_ALLOCATING: c_int = 1;
+pub const TORRENT_RS_STATE_CHECKING_FILES: c_int = 2;
+pub const TORRENT_RS_STATE_DOWNLOADING: c_int = 3;
+pub const TORRENT_RS_STATE_SEEDING: c_int = 4;
+pub const TORRENT_RS_STATE_PAUSED: c_int = 5;
+pub const TORRENT_RS_STATE_ERRORED: c_int = 6;
+
+/// A session with its torrents
+pub struct TorrentRsSession {
+    session: Session,
+    events: Receiver<SessionEvent>,
+    callback: TorrentRsEventCallback,
+    user_data: *mut c_void,
+    last_tick: Instant,
+}
+
+/// A torrent of a session
+pub struct TorrentRsTorrent {
+    torrent: TorrentHandle,
+}
+
+/// Called with each event of the session as JSON, like
+/// {"type":"torrent_finished","info_hash":".."}, valid during the call
+pub type TorrentRsEventCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, event: *const c_char)>;
+
+/// What a torrent is doing, as filled by torrent_rs_torrent_stats
+#[repr(C)]
+pub struct TorrentRsStats {
+    /// one of TORRENT_RS_STATE_*
+    pub state: c_int,
+    /// between 0 and 1, of the selected files
+    pub progress: f64,
+    /// bytes per second
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// of every file
+    pub size: u64,
+    pub peers: usize,
+    pub pieces: usize,
+    pub piece_count: usize,
+}
+
+/// A failed call, as told by its code and `torrent_rs_last_error`
+struct Failure {
+    code: c_int,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: c_int, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(error: anyhow::Error) -> Self {
+        let io = error.chain().any(|cause| cause.is::<io::Error>());
+        let code = match io {
+            true => TORRENT_RS_ERROR_IO,
+            false => TORRENT_RS_ERROR_FAILED,
+        };
+        Self::new(code, format!("{:#}", error))
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        Self::new(TORRENT_RS_ERROR_IO, error.to_string())
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// the code of what `call` returns, keeping the message of a failure for
+/// `torrent_rs_last_error`. Panics don't cross into C.
+fn call(call: impl FnOnce() -> Result<(), Failure>) -> c_int {
+    let failure = match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => return TORRENT_RS_OK,
+        Ok(Err(failure)) => failure,
+        Err(_) => Failure::new(TORRENT_RS_ERROR_PANIC, "the engine panicked"),
+    };
+    let message = CString::new(failure.message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    failure.code
+}
+
+unsafe fn string<'a>(pointer: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if pointer.is_null() {
+        return Err(Failure::new(
+            TORRENT_RS_ERROR_INVALID_ARGUMENT,
+            format!("{} is NULL", name),
+        ));
+    }
+    CStr::from_ptr(pointer).to_str().map_err(|_| {
+        Failure::new(
+            TORRENT_RS_ERROR_INVALID_ARGUMENT,
+            format!("{} isn't UTF-8", name),
+        )
+    })
+}
+
+unsafe fn optional_string<'a>(
+    pointer: *const c_char,
+    name: &str,
+) -> Result<Option<&'a str>, Failure> {
+    match pointer.is_null() {
+        true => Ok(None),
+        false => string(pointer, name).map(Some),
+    }
+}
+
+unsafe fn reference<'a, T>(pointer: *mut T, name: &str) -> Result<&'a mut T, Failure> {
+    pointer.as_mut().ok_or_else(|| {
+        Failure::new(
+            TORRENT_RS_ERROR_INVALID_ARGUMENT,
+            format!("{} is NULL", name),
+        )
+    })
+}
+
+/// writes `value` to `out` unless it is NULL
+unsafe fn output<T>(out: *mut T, value: T) {
+    if let Some(out) = out.as_mut() {
+        *out = value;
+    }
+}
+
+fn owned_string(text: &str) -> *mut c_char {
+    CString::new(text.replace('\0', " "))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+fn torrent_handle(torrent: &TorrentHandle) -> *mut TorrentRsTorrent {
+    Box::into_raw(Box::new(TorrentRsTorrent {
+        torrent: torrent.clone(),
+    }))
+}
+
+fn add_options(save_path: Option<&str>, paused: bool) -> AddOptions {
+    AddOptions {
+        save_path: save_path.map(PathBuf::from),
+        paused,
+        ..AddOptions::default()
+    }
+}
+
+/// the version of the engine, a static string
+#[no_mangle]
+pub extern "C" fn torrent_rs_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// the message of the last call that failed on this thread, NULL if none
+/// did, valid until the next call that fails
+#[no_mangle]
+pub extern "C" fn torrent_rs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// frees a string handed out by the engine, NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// starts a session with the TOML config at config_path, or the defaults
+/// when NULL, see `config::Config`
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_new(
+    config_path: *const c_char,
+    session: *mut *mut TorrentRsSession,
+) -> c_int {
+    call(|| {
+        let out = reference(session, "session")?;
+        let config = match optional_string(config_path, "config_path")? {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        let started = Session::new(config)?;
+        *out = Box::into_raw(Box::new(TorrentRsSession {
+            events: started.subscribe(),
+            session: started,
+            callback: None,
+            user_data: ptr::null_mut(),
+            last_tick: Instant::now(),
+        }));
+        Ok(())
+    })
+}
+
+/// saves the state of the session, tells the trackers its torrents stopped
+/// and frees it, NULL is ignored. The session is freed even if this fails.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_free(session: *mut TorrentRsSession) -> c_int {
+    call(|| {
+        if session.is_null() {
+            return Ok(());
+        }
+        let session = Box::from_raw(session);
+        session.session.shutdown(SHUTDOWN_TIMEOUT)?;
+        Ok(())
+    })
+}
+
+/// the events of the session are passed to callback with user_data, from
+/// within torrent_rs_session_poll, NULL stops passing them
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_set_event_callback(
+    session: *mut TorrentRsSession,
+    callback: TorrentRsEventCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let session = reference(session, "session")?;
+        session.callback = callback;
+        session.user_data = user_data;
+        Ok(())
+    })
+}
+
+/// answers the api, adds the magnet links whose metadata arrived, ticks the
+/// session once a second and passes the events since the last poll to the
+/// callback
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_poll(session: *mut TorrentRsSession) -> c_int {
+    call(|| {
+        let session = reference(session, "session")?;
+        session.session.poll_api();
+        let added = session.session.poll_magnets();
+        if session.last_tick.elapsed() >= TICK {
+            session.last_tick = Instant::now();
+            session.session.tick()?;
+        }
+        for event in session.events.try_iter() {
+            if let Some(callback) = session.callback {
+                let json = CString::new(event.to_json().to_string()).unwrap_or_default();
+                callback(session.user_data, json.as_ptr());
+            }
+        }
+        added?;
+        Ok(())
+    })
+}
+
+/// the port peers connect to
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_listen_port(session: *const TorrentRsSession) -> u16 {
+    session
+        .as_ref()
+        .map_or(0, |session| session.session.listen_port())
+}
+
+/// adds the .torrent file at path, downloading to save_path or to the save
+/// path of the session when NULL. torrent may be NULL, otherwise it gets a
+/// handle to free.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_add_torrent(
+    session: *mut TorrentRsSession,
+    path: *const c_char,
+    save_path: *const c_char,
+    paused: bool,
+    torrent: *mut *mut TorrentRsTorrent,
+) -> c_int {
+    call(|| {
+        let session = reference(session, "session")?;
+        let metainfo = Metainfo::from_bytes(std::fs::read(string(path, "path")?)?)?;
+        let options = add_options(optional_string(save_path, "save_path")?, paused);
+        let added = session.session.add_torrent_with(metainfo, options)?;
+        if !torrent.is_null() {
+            *torrent = torrent_handle(&added);
+        }
+        Ok(())
+    })
+}
+
+/// adds a magnet link, its torrent is added by torrent_rs_session_poll once
+/// its metadata is fetched. info_hash may be NULL, otherwise it gets the 20
+/// bytes of the info hash.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_add_magnet(
+    session: *mut TorrentRsSession,
+    uri: *const c_char,
+    save_path: *const c_char,
+    paused: bool,
+    info_hash: *mut u8,
+) -> c_int {
+    call(|| {
+        let session = reference(session, "session")?;
+        let uri = string(uri, "uri")?;
+        let options = add_options(optional_string(save_path, "save_path")?, paused);
+        let hash = session.session.add_magnet_with(uri, options)?;
+        if !info_hash.is_null() {
+            ptr::copy_nonoverlapping(hash.as_ptr(), info_hash, hash.len());
+        }
+        Ok(())
+    })
+}
+
+/// of the session, not counting the magnet links waiting for metadata
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_torrent_count(
+    session: *const TorrentRsSession,
+) -> usize {
+    session
+        .as_ref()
+        .map_or(0, |session| session.session.torrents().count())
+}
+
+/// the torrent at index, in the order they were added, as a handle to free
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_torrent_at(
+    session: *mut TorrentRsSession,
+    index: usize,
+    torrent: *mut *mut TorrentRsTorrent,
+) -> c_int {
+    call(|| {
+        let session = reference(session, "session")?;
+        let out = reference(torrent, "torrent")?;
+        let found = session.session.torrents().nth(index).ok_or_else(|| {
+            Failure::new(
+                TORRENT_RS_ERROR_NOT_FOUND,
+                format!("no torrent at {}", index),
+            )
+        })?;
+        *out = torrent_handle(found);
+        Ok(())
+    })
+}
+
+/// the torrent named id, or whose info hash starts with it, as a handle to
+/// free
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_find(
+    session: *mut TorrentRsSession,
+    id: *const c_char,
+    torrent: *mut *mut TorrentRsTorrent,
+) -> c_int {
+    call(|| {
+        let session = reference(session, "session")?;
+        let out = reference(torrent, "torrent")?;
+        let found = session
+            .session
+            .find(string(id, "id")?)
+            .map_err(|error| Failure::new(TORRENT_RS_ERROR_NOT_FOUND, format!("{:#}", error)))?;
+        *out = torrent_handle(found);
+        Ok(())
+    })
+}
+
+/// removes the torrent from the session, deleting its files too with
+/// delete_data. Its handle still has to be freed.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_session_remove(
+    session: *mut TorrentRsSession,
+    torrent: *const TorrentRsTorrent,
+    delete_data: bool,
+) -> c_int {
+    call(|| {
+        let session = reference(session, "session")?;
+        let torrent = reference(torrent as *mut TorrentRsTorrent, "torrent")?;
+        session.session.remove(&torrent.torrent, delete_data)?;
+        Ok(())
+    })
+}
+
+/// frees a torrent handle, the torrent stays in its session, NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_torrent_free(torrent: *mut TorrentRsTorrent) {
+    if !torrent.is_null() {
+        drop(Box::from_raw(torrent));
+    }
+}
+
+/// writes the 20 bytes of the info hash to info_hash
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_torrent_info_hash(
+    torrent: *const TorrentRsTorrent,
+    info_hash: *mut u8,
+) -> c_int {
+    call(|| {
+        let torrent = reference(torrent as *mut TorrentRsTorrent, "torrent")?;
+        reference(info_hash, "info_hash")?;
+        let hash = torrent.torrent.info_hash();
+        ptr::copy_nonoverlapping(hash.as_ptr(), info_hash, hash.len());
+        Ok(())
+    })
+}
+
+/// the name of its file or folder, as a string to free
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_torrent_name(
+    torrent: *const TorrentRsTorrent,
+    name: *mut *mut c_char,
+) -> c_int {
+    call(|| {
+        let torrent = reference(torrent as *mut TorrentRsTorrent, "torrent")?;
+        let out = reference(name, "name")?;
+        *out = owned_string(&torrent.torrent.root_name());
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_torrent_stats(
+    torrent: *const TorrentRsTorrent,
+    stats: *mut TorrentRsStats,
+) -> c_int {
+    call(|| {
+        let torrent = &reference(torrent as *mut TorrentRsTorrent, "torrent")?.torrent;
+        reference(stats, "stats")?;
+        let report = torrent.stats();
+        let (download_rate, upload_rate) = torrent.rates();
+        let state = match torrent.state() {
+            TorrentState::FetchingMetadata => TORRENT_RS_STATE_FETCHING_METADATA,
+            TorrentState::Allocating => TORRENT_RS_STATE_ALLOCATING,
+            TorrentState::CheckingFiles => TORRENT_RS_STATE_CHECKING_FILES,
+            TorrentState::Downloading => TORRENT_RS_STATE_DOWNLOADING,
+            TorrentState::Seeding => TORRENT_RS_STATE_SEEDING,
+            TorrentState::Paused => TORRENT_RS_STATE_PAUSED,
+            TorrentState::Errored => TORRENT_RS_STATE_ERRORED,
+        };
+        output(
+            stats,
+            TorrentRsStats {
+                state,
+                progress: report.progress,
+                download_rate,
+                upload_rate,
+                downloaded: report.downloaded,
+                uploaded: report.uploaded,
+                size: torrent.metainfo().info.total_length(),
+                peers: report.peers,
+                pieces: report.pieces,
+                piece_count: report.piece_count,
+            },
+        );
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_torrent_pause(torrent: *const TorrentRsTorrent) -> c_int {
+    call(|| {
+        reference(torrent as *mut TorrentRsTorrent, "torrent")?
+            .torrent
+            .pause();
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_torrent_resume(torrent: *const TorrentRsTorrent) -> c_int {
+    call(|| {
+        reference(torrent as *mut TorrentRsTorrent, "torrent")?
+            .torrent
+            .resume();
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Json;
+
+    unsafe extern "C" fn collect(user_data: *mut c_void, event: *const c_char) {
+        let events = &mut *(user_data as *mut Vec<String>);
+        events.push(CStr::from_ptr(event).to_string_lossy().into_owned());
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(torrent_rs_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn drives_a_session() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join("torrent_rs_ffi");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let config = dir.join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                "listen_port = 0\nsave_path = {:?}\nlsd = false\ntrackers = false\n\
+                 port_mapping = false\n[dht]\nenabled = false\n",
+                dir.join("downloads").display().to_string()
+            ),
+        )?;
+        let config = CString::new(config.display().to_string())?;
+        let torrent_path = CString::new("file1.txt.torrent")?;
+        let mut events: Vec<String> = vec![];
+        unsafe {
+            let mut session = ptr::null_mut();
+            assert_eq!(
+                torrent_rs_session_new(config.as_ptr(), &mut session),
+                TORRENT_RS_OK
+            );
+            assert_ne!(torrent_rs_session_listen_port(session), 0);
+            let user_data = &mut events as *mut Vec<String> as *mut c_void;
+            torrent_rs_session_set_event_callback(session, Some(collect), user_data);
+
+            let mut torrent = ptr::null_mut();
+            let code = torrent_rs_session_add_torrent(
+                session,
+                torrent_path.as_ptr(),
+                ptr::null(),
+                true,
+                &mut torrent,
+            );
+            assert_eq!(code, TORRENT_RS_OK);
+            assert_eq!(torrent_rs_session_poll(session), TORRENT_RS_OK);
+            let kinds: Vec<_> = events
+                .iter()
+                .map(|event| Json::parse(event).unwrap())
+                .filter_map(|event| event.get("type")?.as_str().map(String::from))
+                .collect();
+            assert!(kinds.contains(&"torrent_added".to_string()), "{:?}", kinds);
+
+            let mut name = ptr::null_mut();
+            assert_eq!(torrent_rs_torrent_name(torrent, &mut name), TORRENT_RS_OK);
+            assert_eq!(CStr::from_ptr(name).to_str()?, "file1.txt");
+            torrent_rs_string_free(name);
+            let mut stats: TorrentRsStats = std::mem::zeroed();
+            assert_eq!(torrent_rs_torrent_stats(torrent, &mut stats), TORRENT_RS_OK);
+            assert_eq!(stats.state, TORRENT_RS_STATE_PAUSED);
+            assert!(stats.piece_count > 0);
+            assert_eq!(stats.size, std::fs::metadata("file1.txt")?.len());
+
+            let mut found = ptr::null_mut();
+            let id = CString::new("file1.txt")?;
+            assert_eq!(
+                torrent_rs_session_find(session, id.as_ptr(), &mut found),
+                TORRENT_RS_OK
+            );
+            let (mut first, mut second) = ([0; 20], [1; 20]);
+            torrent_rs_torrent_info_hash(torrent, first.as_mut_ptr());
+            torrent_rs_torrent_info_hash(found, second.as_mut_ptr());
+            assert_eq!(first, second);
+            torrent_rs_torrent_free(found);
+
+            assert_eq!(
+                torrent_rs_session_torrent_at(session, 1, &mut found),
+                TORRENT_RS_ERROR_NOT_FOUND
+            );
+            assert_eq!(last_error(), "no torrent at 1");
+            let missing = CString::new("missing.torrent")?;
+            let code = torrent_rs_session_add_torrent(
+                session,
+                missing.as_ptr(),
+                ptr::null(),
+                false,
+                ptr::null_mut(),
+            );
+            assert_eq!(code, TORRENT_RS_ERROR_IO);
+            let code = torrent_rs_session_add_torrent(
+                session,
+                ptr::null(),
+                ptr::null(),
+                false,
+                ptr::null_mut(),
+            );
+            assert_eq!(code, TORRENT_RS_ERROR_INVALID_ARGUMENT);
+            assert_eq!(last_error(), "path is NULL");
+
+            assert_eq!(
+                torrent_rs_session_remove(session, torrent, false),
+                TORRENT_RS_OK
+            );
+            assert_eq!(torrent_rs_session_torrent_count(session), 0);
+            // the handle outlives its torrent
+            assert_eq!(torrent_rs_torrent_stats(torrent, &mut stats), TORRENT_RS_OK);
+            torrent_rs_torrent_free(torrent);
+            assert_eq!(torrent_rs_session_free(session), TORRENT_RS_OK);
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn generates_the_header() -> Result<(), String> {
+        let header = header::generate(include_str!("mod.rs"))?;
+        for declaration in [
+            "#define TORRENT_RS_ERROR_NOT_FOUND 2\n",
+            "typedef struct TorrentRsSession TorrentRsSession;\n",
+            "typedef void (*TorrentRsEventCallback)(void *user_data, const char *event);\n",
+            "  // bytes per second\n  double download_rate;\n",
+            "const char *torrent_rs_version(void);\n",
+            "void torrent_rs_string_free(char *string);\n",
+            "int torrent_rs_session_new(const char *config_path, TorrentRsSession **session);\n",
+            "uint16_t torrent_rs_session_listen_port(const TorrentRsSession *session);\n",
+            "int torrent_rs_session_add_magnet(TorrentRsSession *session, const char *uri, \
+             const char *save_path, bool paused, uint8_t *info_hash);\n",
+        ] {
+            assert!(header.contains(declaration), "{}\n{}", declaration, header);
+        }
+        assert!(!header.contains("mod tests"));
+        // a compiler finds no fault with it, when there is one to ask
+        let dir = std::env::temp_dir().join("torrent_rs_ffi_header");
+        let _ = std::fs::create_dir_all(&dir);
+        let source = dir.join("check.c");
+        std::fs::write(dir.join("torrent_rs.h"), &header).map_err(|error| error.to_string())?;
+        std::fs::write(&source, "#include \"torrent_rs.h\"\n")
+            .map_err(|error| error.to_string())?;
+        let checked = std::process::Command::new("cc")
+            .args(["-std=c99", "-Wall", "-Werror", "-fsyntax-only"])
+            .arg(&source)
+            .output();
+        if let Ok(output) = checked {
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+        Ok(())
+    }
+}
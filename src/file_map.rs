@@ -0,0 +1,230 @@
+use crate::metainfo::Info;
+use std::ops::Range;
+
+/// Converts between pieces and files. The data of a torrent is its files laid
+/// end to end and cut into pieces, so a block can span several files and a
+/// file several pieces. Zero-length files take no room and never show up in
+/// spans, padding files do and it is up to the caller to skip them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMap {
+    /// offset of every file in the torrent, plus the total length at the end
+    starts: Vec<u64>,
+    padding: Vec<bool>,
+    piece_length: u64,
+}
+
+/// Part of a file covered by a range of the torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file: usize,
+    /// offset in the file
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FileMap {
+    /// `files` has the length of every file and whether it is padding
+    pub fn new(files: impl IntoIterator<Item = (u64, bool)>, piece_length: u64) -> Self {
+        let mut starts = vec![0];
+        let mut padding = vec![];
+        for (length, is_padding) in files {
+            starts.push(starts.last().unwrap() + length);
+            padding.push(is_padding);
+        }
+        Self {
+            starts,
+            padding,
+            piece_length,
+        }
+    }
+
+    pub fn from_info(info: &Info) -> Self {
+        Self::new(
+            info.files.iter().map(|file| (file.length, file.padding)),
+            info.piece_length,
+        )
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.padding.len()
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn total_length(&self) -> u64 {
+        *self.starts.last().unwrap()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.total_length().div_ceil(self.piece_length) as usize
+    }
+
+    /// the last piece is usually shorter, 0 past the end
+    pub fn piece_size(&self, piece: usize) -> u64 {
+        let start = piece as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length().saturating_sub(start))
+    }
+
+    pub fn file_length(&self, file: usize) -> u64 {
+        self.starts[file + 1] - self.starts[file]
+    }
+
+    /// offset of the file in the torrent
+    pub fn file_offset(&self, file: usize) -> u64 {
+        self.starts[file]
+    }
+
+    pub fn is_padding(&self, file: usize) -> bool {
+        self.padding[file]
+    }
+
+    /// spans of the files overlapping `length` bytes at `offset` in the
+    /// torrent, in order, None if the range goes past the end
+    pub fn map_range(&self, offset: u64, length: u64) -> Option<Vec<FileSpan>> {
+        let end = offset.checked_add(length)?;
+        if end > self.total_length() {
+            return None;
+        }
+        let mut spans = vec![];
+        // last file starting at or before the offset, skipping the
+        // zero-length files that start at the same offset
+        let mut file = self.starts.partition_point(|&start| start <= offset) - 1;
+        while file < self.file_count() && self.starts[file] < end {
+            let (file_start, file_end) = (self.starts[file], self.starts[file + 1]);
+            if file_end > offset.max(file_start) {
+                let start = offset.max(file_start);
+                spans.push(FileSpan {
+                    file,
+                    offset: start - file_start,
+                    length: end.min(file_end) - start,
+                });
+            }
+            file += 1;
+        }
+        Some(spans)
+    }
+
+    /// spans of the files overlapping a block of a piece
+    pub fn map_block(&self, piece: usize, offset: u32, length: u64) -> Option<Vec<FileSpan>> {
+        if offset as u64 + length > self.piece_size(piece) {
+            return None;
+        }
+        self.map_range(piece as u64 * self.piece_length + offset as u64, length)
+    }
+
+    /// (piece, offset in the piece) of a byte of a file, None past its end
+    pub fn map_file(&self, file: usize, offset: u64) -> Option<(usize, u32)> {
+        if file >= self.file_count() || offset >= self.file_length(file) {
+            return None;
+        }
+        let position = self.starts[file] + offset;
+        Some((
+            (position / self.piece_length) as usize,
+            (position % self.piece_length) as u32,
+        ))
+    }
+
+    /// pieces overlapping the file as a half open range, empty for
+    /// zero-length files
+    pub fn file_pieces(&self, file: usize) -> Range<usize> {
+        let (start, end) = (self.starts[file], self.starts[file + 1]);
+        let first = (start / self.piece_length) as usize;
+        if start == end {
+            first..first
+        } else {
+            first..((end - 1) / self.piece_length) as usize + 1
+        }
+    }
+
+    /// (file, bytes) of every file overlapping the piece
+    pub fn piece_files(&self, piece: usize) -> Vec<(usize, u64)> {
+        let start = piece as u64 * self.piece_length;
+        self.map_range(start, self.piece_size(piece))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|span| (span.file, span.length))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(file: usize, offset: u64, length: u64) -> FileSpan {
+        FileSpan {
+            file,
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn blocks_spanning_files() {
+        // files of 3 and 5 bytes with 4 byte pieces
+        let map = FileMap::new(vec![(3, false), (5, false)], 4);
+
+        assert_eq!(map.piece_count(), 2);
+        assert_eq!(
+            map.map_block(0, 0, 4),
+            Some(vec![span(0, 0, 3), span(1, 0, 1)])
+        );
+        assert_eq!(
+            map.map_block(0, 2, 2),
+            Some(vec![span(0, 2, 1), span(1, 0, 1)])
+        );
+        assert_eq!(map.map_block(1, 1, 3), Some(vec![span(1, 2, 3)]));
+        assert_eq!(map.map_block(1, 1, 4), None);
+        assert_eq!(map.map_range(0, 0), Some(vec![]));
+        assert_eq!(map.piece_files(0), vec![(0, 3), (1, 1)]);
+        assert_eq!(map.file_pieces(0), 0..1);
+        assert_eq!(map.file_pieces(1), 0..2);
+    }
+
+    #[test]
+    fn zero_length_and_padding_files() {
+        let map = FileMap::new(
+            vec![
+                (0, false),
+                (3, false),
+                (0, false),
+                (1, true),
+                (4, false),
+                (0, false),
+            ],
+            4,
+        );
+
+        assert_eq!(
+            map.map_block(0, 0, 4),
+            Some(vec![span(1, 0, 3), span(3, 0, 1)])
+        );
+        assert!(map.is_padding(3));
+        assert_eq!(map.map_block(1, 0, 4), Some(vec![span(4, 0, 4)]));
+        assert_eq!(map.file_pieces(0), 0..0);
+        assert_eq!(map.file_pieces(2), 0..0);
+        assert_eq!(map.file_pieces(5), 2..2);
+        assert_eq!(map.map_file(0, 0), None);
+        assert_eq!(map.map_file(5, 0), None);
+    }
+
+    #[test]
+    fn file_offsets_round_trip() {
+        let map = FileMap::new(vec![(5, false), (7, false), (1, false)], 4);
+
+        for file in 0..map.file_count() {
+            for offset in 0..map.file_length(file) {
+                let (piece, piece_offset) = map.map_file(file, offset).unwrap();
+                assert_eq!(
+                    map.map_block(piece, piece_offset, 1),
+                    Some(vec![span(file, offset, 1)])
+                );
+            }
+        }
+        assert_eq!(map.map_file(1, 7), None);
+        assert_eq!(map.piece_size(3), 1);
+    }
+}
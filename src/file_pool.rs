@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Open file handles of a storage, bounded so torrents with thousands of files
+/// don't run out of descriptors. Files are opened on demand and the least
+/// recently used handle is closed when the pool is full.
+#[derive(Debug)]
+pub struct FilePool {
+    capacity: usize,
+    /// handle, whether it is writable and the tick it was last used at
+    files: HashMap<usize, (Arc<File>, bool, u64)>,
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+}
+
+impl FilePool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            files: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        while self.files.len() > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// handle of the file, a read only handle is reopened when writing.
+    /// Writable files are created if missing, along with their directories,
+    /// the flag tells if it was.
+    pub fn open(
+        &mut self,
+        index: usize,
+        path: &Path,
+        writable: bool,
+    ) -> io::Result<(Arc<File>, bool)> {
+        self.tick += 1;
+        if let Some((file, file_writable, last_used)) = self.files.get_mut(&index) {
+            if *file_writable || !writable {
+                self.lru.remove(last_used);
+                *last_used = self.tick;
+                self.lru.insert(self.tick, index);
+                return Ok((Arc::clone(file), false));
+            }
+        }
+        self.close(index);
+
+        let created = writable && !path.exists();
+        if created {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let file = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .create(writable)
+                .truncate(false)
+                .open(path)?,
+        );
+        if self.files.len() >= self.capacity {
+            self.evict();
+        }
+        self.files
+            .insert(index, (Arc::clone(&file), writable, self.tick));
+        self.lru.insert(self.tick, index);
+        Ok((file, created))
+    }
+
+    pub fn close(&mut self, index: usize) {
+        if let Some((_, _, last_used)) = self.files.remove(&index) {
+            self.lru.remove(&last_used);
+        }
+    }
+
+    /// handles still used by a read or write stay open until it is done
+    pub fn close_all(&mut self) {
+        self.files.clear();
+        self.lru.clear();
+    }
+
+    /// flushes the data written through the open handles to the disk
+    pub fn sync_all(&self) -> io::Result<()> {
+        for (file, writable, _) in self.files.values() {
+            if *writable {
+                file.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn evict(&mut self) {
+        if let Some(&index) = self.lru.values().next() {
+            self.close(index);
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+#[cfg(unix)]
+pub fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buffer = &mut buffer[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn write_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        let written = file.seek_write(data, offset)?;
+        data = &data[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+/// wasm32 and the like have no files, only the parsers are used there
+#[cfg(not(any(unix, windows)))]
+pub fn read_at(_: &File, _: &mut [u8], _: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn write_at(_: &File, _: &[u8], _: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closes_least_recently_used() -> io::Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_file_pool_test");
+        std::fs::create_dir_all(&dir)?;
+        let mut pool = FilePool::new(2);
+
+        assert!(pool.open(0, &dir.join("a"), true)?.1);
+        pool.open(1, &dir.join("b"), true)?;
+        assert!(!pool.open(0, &dir.join("a"), false)?.1);
+        pool.open(2, &dir.join("c"), true)?;
+        assert_eq!(pool.files.len(), 2);
+        assert!(pool.files.contains_key(&0) && !pool.files.contains_key(&1));
+
+        let (file, _) = pool.open(2, &dir.join("c"), false)?;
+        write_at(&file, b"data", 2)?;
+        let mut buffer = [0; 3];
+        read_at(&file, &mut buffer, 3)?;
+        assert_eq!(&buffer, b"ata");
+        assert!(pool.open(3, &dir.join("missing"), false).is_err());
+        assert!(pool.open(3, &dir.join("sub/dir/d"), true)?.1);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+//! The api as a gRPC service for typed clients, with the `grpc` feature.
+//! Clients speaking HTTP/2 without TLS to the address of the api get the
+//! service of `PROTO`, served by tonic, the others the REST api. Each call
+//! is answered like the route it mirrors, see `forward`, its JSON read into
+//! the message of the method so both stay the same. The token of the api is
+//! asked as `authorization` metadata. The code generated from `PROTO`,
+//! client included, is in this module.
+
+tonic::include_proto!("torrent_rs.v1");
+
+use crate::api::{self, authorized, ApiError, Route, Server, MAX_BODY};
+use crate::json::Json;
+use session_server::SessionServer;
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
+use tokio_stream::StreamExt;
+use tonic::service::interceptor::InterceptedService;
+use tonic::{Request, Response, Status};
+
+/// the service, for clients to generate their code from
+pub const PROTO: &str = include_str!("torrent_rs.proto");
+
+/// what a client sends first, after which it speaks in frames
+pub const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The clients the api found to speak HTTP/2, their preface left unread
+pub(crate) type Clients = mpsc::UnboundedSender<TcpStream>;
+
+/// Serves the clients sent to `Clients` on a runtime of its own, until the
+/// server is stopped
+pub(crate) fn start(
+    server: Arc<Server>,
+    clients: mpsc::UnboundedReceiver<TcpStream>,
+) -> io::Result<JoinHandle<()>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("grpc")
+        .enable_all()
+        .build()?;
+    Ok(thread::spawn(move || {
+        let config = server.config.clone();
+        let service = SessionServer::new(Service {
+            server: Arc::clone(&server),
+        })
+        .max_decoding_message_size(MAX_BODY);
+        let service = InterceptedService::new(service, move |request: Request<()>| {
+            let header = request.metadata().get("authorization");
+            match authorized(&config, header.and_then(|header| header.to_str().ok())) {
+                true => Ok(request),
+                false => Err(Status::unauthenticated("missing or wrong token")),
+            }
+        });
+        let incoming = UnboundedReceiverStream::new(clients).map(|stream| {
+            stream.set_nonblocking(true)?;
+            tokio::net::TcpStream::from_std(stream)
+        });
+        runtime.spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+        runtime.block_on(async {
+            while !server.stop.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        // the calls still open are cut, the watches end on their own
+        runtime.shutdown_timeout(Duration::from_secs(1));
+    }))
+}
+
+fn status(error: ApiError) -> Status {
+    let code = match error.status {
+        400 => tonic::Code::InvalidArgument,
+        401 => tonic::Code::Unauthenticated,
+        403 => tonic::Code::PermissionDenied,
+        404 => tonic::Code::NotFound,
+        413 => tonic::Code::ResourceExhausted,
+        500 => tonic::Code::Internal,
+        503 => tonic::Code::Unavailable,
+        _ => tonic::Code::Unknown,
+    };
+    Status::new(code, error.message)
+}
+
+struct Service {
+    server: Arc<Server>,
+}
+
+impl Service {
+    /// `api::forward` off the runtime, as it waits for the session
+    async fn forward(
+        &self,
+        route: Route,
+        query: Vec<(String, String)>,
+        content_type: Option<String>,
+        body: Vec<u8>,
+    ) -> Result<Json, Status> {
+        let server = Arc::clone(&self.server);
+        let answer = tokio::task::spawn_blocking(move || {
+            api::forward(&server, route, query, content_type, body)
+        });
+        let answer = answer
+            .await
+            .map_err(|error| Status::internal(error.to_string()))?;
+        answer.map_err(status)
+    }
+
+    async fn get(&self, route: Route) -> Result<Json, Status> {
+        self.forward(route, vec![], None, vec![]).await
+    }
+}
+
+fn id(request: Request<TorrentRequest>) -> Result<String, Status> {
+    let id = request.into_inner().id;
+    match id.is_empty() {
+        true => Err(Status::invalid_argument("id is missing")),
+        false => Ok(id),
+    }
+}
+
+#[tonic::async_trait]
+impl session_server::Session for Service {
+    async fn add_torrent(
+        &self,
+        request: Request<AddTorrentRequest>,
+    ) -> Result<Response<AddTorrentResponse>, Status> {
+        let request = request.into_inner();
+        let save_path = Some(request.save_path).filter(|path| !path.is_empty());
+        // as a .torrent body or the JSON of the REST api
+        let json = match request.source {
+            Some(add_torrent_request::Source::Metainfo(metainfo)) => {
+                let mut query = vec![("paused".to_string(), request.paused.to_string())];
+                query.extend(save_path.map(|path| ("save_path".to_string(), path)));
+                let content_type = Some("application/x-bittorrent".to_string());
+                self.forward(Route::AddTorrent, query, content_type, metainfo)
+                    .await?
+            }
+            source => {
+                let mut body = Json::object().with("paused", request.paused);
+                match source {
+                    Some(add_torrent_request::Source::Uri(uri)) => body = body.with("uri", uri),
+                    Some(add_torrent_request::Source::Path(path)) => body = body.with("path", path),
+                    _ => {}
+                }
+                if let Some(save_path) = save_path {
+                    body = body.with("save_path", save_path);
+                }
+                let body = body.to_string().into_bytes();
+                self.forward(Route::AddTorrent, vec![], None, body).await?
+            }
+        };
+        Ok(Response::new(AddTorrentResponse {
+            info_hash: text(&json, "info_hash"),
+        }))
+    }
+
+    async fn list_torrents(
+        &self,
+        _request: Request<ListTorrentsRequest>,
+    ) -> Result<Response<ListTorrentsResponse>, Status> {
+        let torrents = self.get(Route::Torrents).await?;
+        Ok(Response::new(ListTorrentsResponse {
+            torrents: items(&torrents).iter().map(torrent).collect(),
+        }))
+    }
+
+    async fn get_torrent(
+        &self,
+        request: Request<TorrentRequest>,
+    ) -> Result<Response<Torrent>, Status> {
+        let json = self.get(Route::Torrent(id(request)?)).await?;
+        Ok(Response::new(torrent(&json)))
+    }
+
+    async fn pause_torrent(
+        &self,
+        request: Request<TorrentRequest>,
+    ) -> Result<Response<Torrent>, Status> {
+        let json = self.get(Route::Pause(id(request)?)).await?;
+        Ok(Response::new(torrent(&json)))
+    }
+
+    async fn resume_torrent(
+        &self,
+        request: Request<TorrentRequest>,
+    ) -> Result<Response<Torrent>, Status> {
+        let json = self.get(Route::Resume(id(request)?)).await?;
+        Ok(Response::new(torrent(&json)))
+    }
+
+    async fn remove_torrent(
+        &self,
+        request: Request<RemoveTorrentRequest>,
+    ) -> Result<Response<RemoveTorrentResponse>, Status> {
+        let request = request.into_inner();
+        if request.id.is_empty() {
+            return Err(Status::invalid_argument("id is missing"));
+        }
+        let query = vec![("delete_data".to_string(), request.delete_data.to_string())];
+        let json = self
+            .forward(Route::Remove(request.id), query, None, vec![])
+            .await?;
+        Ok(Response::new(RemoveTorrentResponse {
+            info_hash: text(&json, "info_hash"),
+        }))
+    }
+
+    async fn list_peers(
+        &self,
+        request: Request<TorrentRequest>,
+    ) -> Result<Response<ListPeersResponse>, Status> {
+        let peers = self.get(Route::Peers(id(request)?)).await?;
+        Ok(Response::new(ListPeersResponse {
+            peers: items(&peers).iter().map(peer).collect(),
+        }))
+    }
+
+    async fn get_stats(
+        &self,
+        _request: Request<GetStatsRequest>,
+    ) -> Result<Response<Stats>, Status> {
+        Ok(Response::new(stats(&self.get(Route::Stats).await?)))
+    }
+
+    type WatchStatsStream = ReceiverStream<Result<Stats, Status>>;
+
+    async fn watch_stats(
+        &self,
+        request: Request<WatchStatsRequest>,
+    ) -> Result<Response<Self::WatchStatsStream>, Status> {
+        let interval = match request.into_inner().interval_ms {
+            0 => Duration::from_secs(1),
+            interval => Duration::from_millis(u64::from(interval.max(100))),
+        };
+        let server = Arc::clone(&self.server);
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::task::spawn_blocking(move || {
+            while !sender.is_closed() && !server.stop.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                let answer = api::forward(&server, Route::Stats, vec![], None, vec![]);
+                let failed = answer.is_err();
+                let answer = answer.map(|json| stats(&json)).map_err(status);
+                if sender.blocking_send(answer).is_err() || failed {
+                    return;
+                }
+                while started.elapsed() < interval && !sender.is_closed() {
+                    thread::sleep((interval - started.elapsed()).min(Duration::from_millis(100)));
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_settings(
+        &self,
+        _request: Request<GetSettingsRequest>,
+    ) -> Result<Response<Settings>, Status> {
+        Ok(Response::new(settings(&self.get(Route::Settings).await?)))
+    }
+
+    async fn update_settings(
+        &self,
+        request: Request<Settings>,
+    ) -> Result<Response<Settings>, Status> {
+        let request = request.into_inner();
+        let limits = [
+            ("download_rate_limit", request.download_rate_limit),
+            ("upload_rate_limit", request.upload_rate_limit),
+            ("max_peers", request.max_peers),
+            ("max_connections", request.max_connections),
+            ("max_active_downloads", request.max_active_downloads),
+            ("max_active_seeds", request.max_active_seeds),
+            ("max_active_total", request.max_active_total),
+        ];
+        // only the limits given are changed
+        let body = limits
+            .iter()
+            .filter_map(|(key, limit)| limit.map(|limit| (key, limit)))
+            .fold(Json::object(), |json, (key, limit)| json.with(key, limit));
+        let body = body.to_string().into_bytes();
+        let json = self
+            .forward(Route::UpdateSettings, vec![], None, body)
+            .await?;
+        Ok(Response::new(settings(&json)))
+    }
+
+    type WatchEventsStream = ReceiverStream<Result<Event, Status>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let server = Arc::clone(&self.server);
+        let mut cursor = request
+            .into_inner()
+            .cursor
+            .unwrap_or_else(|| server.events.cursor());
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::task::spawn_blocking(move || {
+            while !sender.is_closed() && !server.stop.load(Ordering::Relaxed) {
+                let events = match server.events.since(cursor, Duration::from_secs(1)) {
+                    Some(events) => events,
+                    None => {
+                        let message = format!("the events after cursor {} were dropped", cursor);
+                        let _ = sender.blocking_send(Err(Status::out_of_range(message)));
+                        return;
+                    }
+                };
+                for (event_cursor, json) in events {
+                    if sender
+                        .blocking_send(Ok(event(event_cursor, &json)))
+                        .is_err()
+                    {
+                        return;
+                    }
+                    cursor = event_cursor;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+fn text(json: &Json, key: &str) -> String {
+    maybe_text(json, key).unwrap_or_default()
+}
+
+fn maybe_text(json: &Json, key: &str) -> Option<String> {
+    json.get(key).and_then(Json::as_str).map(String::from)
+}
+
+fn number(json: &Json, key: &str) -> f64 {
+    json.get(key).and_then(Json::as_f64).unwrap_or_default()
+}
+
+fn count(json: &Json, key: &str) -> u64 {
+    maybe_count(json, key).unwrap_or_default()
+}
+
+fn maybe_count(json: &Json, key: &str) -> Option<u64> {
+    json.get(key)
+        .and_then(Json::as_f64)
+        .map(|number| number as u64)
+}
+
+fn items(json: &Json) -> &[Json] {
+    match json {
+        Json::Array(items) => items,
+        _ => &[],
+    }
+}
+
+fn texts(json: &Json, key: &str) -> Vec<String> {
+    let items = json.get(key).map(items).unwrap_or_default();
+    items
+        .iter()
+        .filter_map(Json::as_str)
+        .map(String::from)
+        .collect()
+}
+
+fn torrent(json: &Json) -> Torrent {
+    let list = |key| json.get(key).map(items).unwrap_or_default();
+    Torrent {
+        info_hash: text(json, "info_hash"),
+        name: maybe_text(json, "name"),
+        state: text(json, "state"),
+        progress: number(json, "progress"),
+        size: count(json, "size"),
+        done: count(json, "done"),
+        download_rate: number(json, "download_rate"),
+        upload_rate: number(json, "upload_rate"),
+        downloaded: count(json, "downloaded"),
+        uploaded: count(json, "uploaded"),
+        ratio: number(json, "ratio"),
+        peers: count(json, "peers"),
+        eta: maybe_count(json, "eta"),
+        save_path: text(json, "save_path"),
+        category: maybe_text(json, "category"),
+        tags: texts(json, "tags"),
+        pieces: count(json, "pieces"),
+        piece_count: count(json, "piece_count"),
+        files: list("files").iter().map(file).collect(),
+        trackers: texts(json, "trackers"),
+        peers_list: list("peers_list").iter().map(peer).collect(),
+    }
+}
+
+fn file(json: &Json) -> File {
+    File {
+        path: text(json, "path"),
+        length: count(json, "length"),
+        done: count(json, "done"),
+        priority: text(json, "priority"),
+    }
+}
+
+fn peer(json: &Json) -> Peer {
+    Peer {
+        addr: text(json, "addr"),
+        source: text(json, "source"),
+        client: maybe_text(json, "client"),
+        flags: text(json, "flags"),
+        download_rate: count(json, "download_rate"),
+        upload_rate: count(json, "upload_rate"),
+        progress: number(json, "progress"),
+    }
+}
+
+fn stats(json: &Json) -> Stats {
+    let states = match json.get("states") {
+        Some(Json::Object(states)) => states
+            .iter()
+            .map(|(state, count)| (state.clone(), count.as_f64().unwrap_or_default() as u64))
+            .collect(),
+        _ => Default::default(),
+    };
+    Stats {
+        download_rate: number(json, "download_rate"),
+        upload_rate: number(json, "upload_rate"),
+        torrents: count(json, "torrents"),
+        states,
+        connections: count(json, "connections"),
+        listen_port: count(json, "listen_port") as u32,
+        dht_nodes: maybe_count(json, "dht_nodes"),
+    }
+}
+
+fn settings(json: &Json) -> Settings {
+    Settings {
+        download_rate_limit: maybe_count(json, "download_rate_limit"),
+        upload_rate_limit: maybe_count(json, "upload_rate_limit"),
+        max_peers: maybe_count(json, "max_peers"),
+        max_connections: maybe_count(json, "max_connections"),
+        max_active_downloads: maybe_count(json, "max_active_downloads"),
+        max_active_seeds: maybe_count(json, "max_active_seeds"),
+        max_active_total: maybe_count(json, "max_active_total"),
+    }
+}
+
+fn event(cursor: u64, json: &Json) -> Event {
+    Event {
+        cursor,
+        r#type: text(json, "type"),
+        info_hash: text(json, "info_hash"),
+        piece: maybe_count(json, "piece"),
+        state: maybe_text(json, "state"),
+        addr: maybe_text(json, "addr"),
+        source: maybe_text(json, "source"),
+        url: maybe_text(json, "url"),
+        peers: maybe_count(json, "peers"),
+        error: maybe_text(json, "error"),
+        message: maybe_text(json, "message"),
+        name: maybe_text(json, "name"),
+        size: maybe_count(json, "size"),
+        path: maybe_text(json, "path"),
+        file: maybe_count(json, "file"),
+        checked: maybe_count(json, "checked"),
+        moved: maybe_count(json, "moved"),
+        total: maybe_count(json, "total"),
+        limit: maybe_text(json, "limit"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiConfig;
+    use crate::{Config, Session};
+    use anyhow::Result;
+    use session_client::SessionClient;
+
+    fn request<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        let authorization = format!("Bearer {}", token).parse().unwrap();
+        request
+            .metadata_mut()
+            .insert("authorization", authorization);
+        request
+    }
+
+    #[test]
+    fn serves_the_session() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_grpc_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let config = Config::builder()
+            .save_path(&dir)
+            .listen_port(0)
+            .dht(None)
+            .lsd(false)
+            .trackers(false)
+            .port_mapping(false)
+            .api(ApiConfig {
+                addr: "127.0.0.1:0".parse()?,
+                token: Some("secret".into()),
+                cors_origins: vec![],
+            })
+            .build()?;
+        let mut session = Session::new(config)?;
+        let addr = session.api_addr().unwrap();
+
+        let torrent = std::fs::canonicalize("file1.txt.torrent")?;
+        let client = thread::spawn(move || -> Result<_> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async {
+                let channel = tonic::transport::Endpoint::new(format!("http://{}", addr))?;
+                let mut client = SessionClient::new(channel.connect().await?);
+                let unauthenticated = client.get_stats(request(GetStatsRequest {}, "wrong")).await;
+                let add = AddTorrentRequest {
+                    source: Some(add_torrent_request::Source::Path(
+                        torrent.display().to_string(),
+                    )),
+                    save_path: String::new(),
+                    paused: true,
+                };
+                let added = client.add_torrent(request(add, "secret")).await?;
+                let list = client
+                    .list_torrents(request(ListTorrentsRequest {}, "secret"))
+                    .await?;
+                let id = TorrentRequest {
+                    id: "file1.txt".into(),
+                };
+                let details = client.get_torrent(request(id, "secret")).await?;
+                let missing = client
+                    .get_torrent(request(TorrentRequest::default(), "secret"))
+                    .await;
+                Ok((
+                    unauthenticated.map(|_| ()),
+                    added.into_inner(),
+                    list.into_inner(),
+                    details.into_inner(),
+                    missing.map(|_| ()),
+                ))
+            })
+        });
+        while !client.is_finished() {
+            session.poll_api();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (unauthenticated, added, list, details, missing) = client.join().unwrap()?;
+        assert_eq!(
+            unauthenticated.unwrap_err().code(),
+            tonic::Code::Unauthenticated
+        );
+        assert_eq!(added.info_hash, "8dc3b8a5ac6d8002df36541fda949e7109b7397c");
+        assert_eq!(list.torrents.len(), 1);
+        assert_eq!(list.torrents[0].name.as_deref(), Some("file1.txt"));
+        assert_eq!(list.torrents[0].state, "paused");
+        assert_eq!(details.files.len(), 1);
+        assert_eq!(missing.unwrap_err().code(), tonic::Code::InvalidArgument);
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{bail, Result};
+use flate2::read::MultiGzDecoder;
+use std::io::Read;
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+/// the content of a gzip file, members after the first are appended to it.
+/// Fails past `limit` bytes rather than fill the memory with a small file
+/// that expands without end.
+pub fn decompress(data: &[u8], limit: u64) -> Result<Vec<u8>> {
+    if !is_gzip(data) {
+        bail!("not a gzip file");
+    }
+    let mut output = vec![];
+    MultiGzDecoder::new(data)
+        .take(limit + 1)
+        .read_to_end(&mut output)?;
+    if output.len() as u64 > limit {
+        bail!("decompresses to more than {} bytes", limit);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decompresses_each_block_type() -> Result<()> {
+        // fixed codes, with a file name in the header
+        let fixed = hex("1f8b08080000000002ff612e74787400cb48cdc9c957c840905c003b7c8adf12000000");
+        assert_eq!(decompress(&fixed, 100)?, b"hello hello hello\n");
+
+        let stored = hex("1f8b08000000000000ff010600f9ff73746f7265640bf9435606000000");
+        assert_eq!(decompress(&stored, 100)?, b"stored");
+        // members are concatenated
+        let both = [stored.clone(), fixed].concat();
+        assert_eq!(decompress(&both, 100)?, b"storedhello hello hello\n");
+
+        let dynamic = hex(concat!(
+            "1f8b08000000000002ff55d33b4e04411083e19c537001d0da9e27c7d81b102032ee1f226dbb3d1e",
+            "75d2d21fd5a7aae7f7dfefcffbe30b8fcfd7fbf087ebfaf67c358c86d9508da3713656d3689a4dd5",
+            "96d196d9966aeb68eb6c6bb56db46db6adda3eda3edb5eed18ed98eda8768e76ce76f6ec86416470",
+            "a39936174eebc03c880f1a08164288d0463012a28466829d1028b4144c8558a1b1602d840bed0583",
+            "21626832d80c4143abd16a8c1a5b8d5663d478dba9b954d756b51aadc6a8b1d56835468dad46ab31",
+            "6a6c355a8d5163abd16a8c1a5b8d5663d4d86ab41aa3c65693d51435b59aaca6a8a9d56435454db7",
+            "639cd7789d63abc96a8a9a5a4d5653d4d46ab29aa2a65693d51435b59aaca6a8a9d56435454d43ed",
+            "1f3c2d6ed5ba040000"
+        ));
+        let expected: String = (0..40)
+            .map(|i| format!("Range {}:10.0.{}.0-10.0.{}.255\n", i, i, i))
+            .collect();
+        assert_eq!(decompress(&dynamic, 10_000)?, expected.as_bytes());
+
+        let mut corrupt = stored;
+        corrupt[20] ^= 1;
+        assert!(decompress(&corrupt, 100).is_err());
+        assert!(decompress(b"plain text", 100).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stops_at_the_limit() -> Result<()> {
+        let mut encoder = GzEncoder::new(vec![], Compression::best());
+        encoder.write_all(&vec![0; 1 << 20])?;
+        let bomb = encoder.finish()?;
+        assert!(bomb.len() < 2000);
+        assert_eq!(decompress(&bomb, 1 << 20)?.len(), 1 << 20);
+        let error = decompress(&bomb, 1000).unwrap_err();
+        assert_eq!(error.to_string(), "decompresses to more than 1000 bytes");
+        Ok(())
+    }
+}
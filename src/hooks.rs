@@ -0,0 +1,230 @@
+use crate::json::Json;
+use crate::metainfo::to_hex;
+use crate::tracker;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// What is run as torrents complete, so post-processing like unpacking or
+/// importing into a media library starts on its own. Both are run on a
+/// thread of their own, see `Session::tick`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HooksConfig {
+    /// run by the shell with the variables of `Completion::environment`
+    pub command: Option<String>,
+    /// over plain http, POSTed `Completion::to_json`
+    pub webhook: Option<String>,
+    /// also as each file completes, not only the whole torrent
+    pub files: bool,
+}
+
+impl HooksConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.command.as_deref() == Some("") {
+            bail!("hooks command is empty");
+        }
+        if let Some(url) = &self.webhook {
+            if !tracker::is_http_url(url) {
+                bail!("hooks webhook has to be http:// or https://");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.command.is_none() && self.webhook.is_none()
+    }
+}
+
+/// A torrent, or one of its files, that completed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    /// the index of the file, None for the whole torrent
+    pub file: Option<usize>,
+    /// of the file, or of the folder of a multi-file torrent
+    pub path: PathBuf,
+    pub save_path: PathBuf,
+    /// the first of the torrent
+    pub tracker: Option<String>,
+    pub category: Option<String>,
+    pub tags: BTreeSet<String>,
+}
+
+impl Completion {
+    /// `torrent_finished` or `file_completed`, as the events are named
+    pub fn kind(&self) -> &'static str {
+        match self.file {
+            Some(_) => "file_completed",
+            None => "torrent_finished",
+        }
+    }
+
+    /// `TORRENT_EVENT`, `TORRENT_NAME`, `TORRENT_PATH`, `TORRENT_SAVE_PATH`,
+    /// `TORRENT_INFOHASH`, `TORRENT_TRACKER`, `TORRENT_LABEL` with the
+    /// category, `TORRENT_TAGS` separated by commas and `TORRENT_FILE` with
+    /// the index of the file. Missing values are empty.
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        let tags: Vec<_> = self.tags.iter().map(String::as_str).collect();
+        vec![
+            ("TORRENT_EVENT", self.kind().to_string()),
+            ("TORRENT_NAME", self.name.clone()),
+            ("TORRENT_PATH", self.path.display().to_string()),
+            ("TORRENT_SAVE_PATH", self.save_path.display().to_string()),
+            ("TORRENT_INFOHASH", to_hex(&self.info_hash)),
+            ("TORRENT_TRACKER", self.tracker.clone().unwrap_or_default()),
+            ("TORRENT_LABEL", self.category.clone().unwrap_or_default()),
+            ("TORRENT_TAGS", tags.join(",")),
+            (
+                "TORRENT_FILE",
+                self.file.map(|file| file.to_string()).unwrap_or_default(),
+            ),
+        ]
+    }
+
+    /// `{"event":"torrent_finished","info_hash":..,"name":..,"path":..}`
+    /// with the other values, missing ones null
+    pub fn to_json(&self) -> Json {
+        Json::object()
+            .with("event", self.kind())
+            .with("info_hash", to_hex(&self.info_hash))
+            .with("name", self.name.as_str())
+            .with("file", self.file)
+            .with("path", self.path.display().to_string())
+            .with("save_path", self.save_path.display().to_string())
+            .with("tracker", self.tracker.clone())
+            .with("label", self.category.clone())
+            .with(
+                "tags",
+                self.tags.iter().map(String::as_str).collect::<Vec<_>>(),
+            )
+    }
+}
+
+/// runs the command until it exits then calls the webhook, the webhook is
+/// called even if the command failed
+pub fn run(config: &HooksConfig, completion: &Completion) -> Result<()> {
+    let command = config
+        .command
+        .as_deref()
+        .map(|command| run_command(command, completion));
+    let webhook = config.webhook.as_deref().map(|url| {
+        let body = completion.to_json().to_string();
+        tracker::http_post(
+            url,
+            &[("Content-Type", "application/json")],
+            body.as_bytes(),
+        )
+        .with_context(|| format!("calling {}", url))
+    });
+    command.transpose()?;
+    webhook.transpose()?;
+    Ok(())
+}
+
+fn run_command(command: &str, completion: &Completion) -> Result<()> {
+    let mut shell = match cfg!(windows) {
+        true => {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        }
+        false => {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        }
+    };
+    let status = shell
+        .arg(command)
+        .envs(completion.environment())
+        .status()
+        .with_context(|| format!("running {}", command))?;
+    if !status.success() {
+        bail!("{} exited with {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn runs_the_command_and_webhook() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_hooks");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/done", listener.local_addr()?);
+        let server = thread::spawn(move || -> Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            let mut request = String::new();
+            let mut buffer = [0; 4096];
+            while !request.ends_with('}') {
+                let read = stream.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                request.push_str(&String::from_utf8_lossy(&buffer[..read]));
+            }
+            stream.write_all(b"HTTP/1.0 204 No Content\r\n\r\n")?;
+            Ok(request)
+        });
+
+        let out = dir.join("env");
+        let completion = Completion {
+            info_hash: [0xab; 20],
+            name: "a b".into(),
+            file: Some(1),
+            path: dir.join("a b").join("c"),
+            save_path: dir.clone(),
+            tracker: Some("http://tracker/announce".into()),
+            category: Some("movies".into()),
+            tags: vec!["x".to_string(), "y".to_string()].into_iter().collect(),
+        };
+        let config = HooksConfig {
+            command: Some(format!(
+                "echo \"$TORRENT_EVENT|$TORRENT_NAME|$TORRENT_LABEL|$TORRENT_TAGS|$TORRENT_FILE\" > {}",
+                out.display()
+            )),
+            webhook: Some(url),
+            files: true,
+        };
+        config.validate()?;
+        run(&config, &completion)?;
+        if cfg!(unix) {
+            assert_eq!(
+                std::fs::read_to_string(&out)?,
+                "file_completed|a b|movies|x,y|1\n"
+            );
+        }
+        let request = server.join().unwrap()?;
+        assert!(request.starts_with("POST /done HTTP/1.0\r\n"));
+        let body = request.split("\r\n\r\n").nth(1).unwrap_or_default();
+        assert_eq!(Json::parse(body)?, completion.to_json());
+        assert_eq!(
+            completion.to_json().get("info_hash").and_then(Json::as_str),
+            Some("abababababababababababababababababababab")
+        );
+
+        let failing = HooksConfig {
+            command: Some("exit 3".into()),
+            ..HooksConfig::default()
+        };
+        assert!(run(&failing, &completion).is_err());
+        assert!(HooksConfig {
+            webhook: Some("ftp://a".into()),
+            ..HooksConfig::default()
+        }
+        .validate()
+        .is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
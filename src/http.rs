@@ -0,0 +1,250 @@
+//! Reads the requests of the HTTP servers of the session, the api, the
+//! stream server and the metrics, with the same limits for all of them.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// how long a client gets to send its whole request, however slowly it
+/// trickles in
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+/// the request line and headers
+pub const MAX_HEAD: usize = 16 * 1024;
+/// more headers than that is refused like a head too large
+const MAX_HEADERS: usize = 64;
+
+/// A request as read from the client
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    /// names in lower case
+    pub headers: BTreeMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// `name` in lower case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// Why a request couldn't be read, as the status to answer it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error {
+    pub status: u16,
+    pub message: &'static str,
+}
+
+impl Error {
+    const INVALID: Self = Self::new(400, "invalid request");
+    const TIMEOUT: Self = Self::new(408, "the request took too long");
+
+    const fn new(status: u16, message: &'static str) -> Self {
+        Self { status, message }
+    }
+}
+
+/// the request line, headers and body of up to `max_body` bytes, all
+/// within `TIMEOUT`. Clients expecting a 100 Continue get it.
+pub fn read_request(stream: &mut TcpStream, max_body: usize) -> Result<Request, Error> {
+    let deadline = Instant::now() + TIMEOUT;
+    let read = |stream: &mut TcpStream, buffer: &mut [u8]| {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::TIMEOUT);
+        }
+        stream
+            .set_read_timeout(Some(left))
+            .map_err(|_| Error::INVALID)?;
+        match stream.read(buffer) {
+            Ok(0) => Err(Error::INVALID),
+            Ok(read) => Ok(read),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Err(Error::TIMEOUT)
+            }
+            Err(_) => Err(Error::INVALID),
+        }
+    };
+    let mut data = vec![];
+    let mut buffer = [0; 4096];
+    let (head_length, mut request) = loop {
+        if let Some(head) = parse_head(&data)? {
+            break head;
+        }
+        if data.len() > MAX_HEAD {
+            return Err(Error::new(431, "headers too large"));
+        }
+        let count = read(stream, &mut buffer)?;
+        data.extend_from_slice(&buffer[..count]);
+    };
+    if head_length > MAX_HEAD {
+        return Err(Error::new(431, "headers too large"));
+    }
+    if request.headers.contains_key("transfer-encoding") {
+        return Err(Error::new(411, "a Content-Length is needed"));
+    }
+    let length = match request.header("content-length") {
+        Some(length) => length.parse::<usize>().map_err(|_| Error::INVALID)?,
+        None => 0,
+    };
+    if length > max_body {
+        return Err(Error::new(413, "the body is too large"));
+    }
+    let mut body = data.split_off(head_length);
+    if body.len() < length
+        && request
+            .header("expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .map_err(|_| Error::INVALID)?;
+    }
+    while body.len() < length {
+        let count = read(stream, &mut buffer)?;
+        body.extend_from_slice(&buffer[..count]);
+    }
+    body.truncate(length);
+    request.body = body;
+    Ok(request)
+}
+
+/// the length of the head and the request it holds, without its body, or
+/// None while it isn't complete
+fn parse_head(data: &[u8]) -> Result<Option<(usize, Request)>, Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let length = match parsed.parse(data) {
+        Ok(httparse::Status::Complete(length)) => length,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(httparse::Error::TooManyHeaders) => return Err(Error::new(431, "too many headers")),
+        Err(_) => return Err(Error::INVALID),
+    };
+    let (method, target) = match (parsed.method, parsed.path) {
+        (Some(method), Some(target)) if target.starts_with('/') => (method, target),
+        _ => return Err(Error::INVALID),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut headers = BTreeMap::new();
+    for header in parsed.headers.iter() {
+        let value = std::str::from_utf8(header.value).map_err(|_| Error::INVALID)?;
+        headers.insert(header.name.to_ascii_lowercase(), value.trim().to_string());
+    }
+    let request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: vec![],
+    };
+    Ok(Some((length, request)))
+}
+
+/// of the status line
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// what the server reads of `data` sent by a client
+    fn read(data: &[u8], max_body: usize) -> Result<Request, Error> {
+        let data = data.to_vec();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // the server may hang up before it is all sent
+            let _ = stream.write_all(&data);
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+            // the 100 Continue, if any
+            let mut answer = vec![];
+            let _ = stream.read_to_end(&mut answer);
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_request(&mut stream, max_body);
+        drop(stream);
+        client.join().unwrap();
+        request
+    }
+
+    #[test]
+    fn reads_requests() {
+        let request = read(
+            b"POST /api/torrents?paused HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nX-Long:  b c \r\n\r\nhelloextra",
+            5,
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(
+            (request.path.as_str(), request.query.as_str()),
+            ("/api/torrents", "paused")
+        );
+        assert_eq!(request.header("x-long"), Some("b c"));
+        assert_eq!(request.header("host"), Some("a"));
+        assert_eq!(request.body, b"hello");
+
+        let request = read(b"GET /ab/0 HTTP/1.1\r\nRange: bytes=0-\r\n\r\n", 0).unwrap();
+        assert_eq!(request.header("range"), Some("bytes=0-"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn refuses_what_goes_past_the_limits() {
+        let status = |data, max_body| read(data, max_body).unwrap_err().status;
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nContent-Length: 6\r\n\r\n", 5),
+            413
+        );
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n", 5),
+            411
+        );
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n", 5),
+            400
+        );
+        assert_eq!(status(b"GET nothing HTTP/1.1\r\n\r\n", 0), 400);
+        assert_eq!(status(b"GET / HTTP/1.1\r\nbad header\r\n\r\n", 0), 400);
+        // the client hangs up before the end of the head
+        assert_eq!(status(b"GET / HTTP/1.1\r\n", 0), 400);
+
+        let long = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(MAX_HEAD));
+        assert_eq!(status(long.as_bytes(), 0), 431);
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "A: a\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(status(many.as_bytes(), 0), 431);
+    }
+}
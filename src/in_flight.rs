@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Blocks requested from the peers of every torrent of a session and not
+/// received yet, shared by the torrents so that together they stay under
+/// one memory budget however many there are
+#[derive(Debug, Default)]
+pub struct InFlight {
+    requests: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl InFlight {
+    pub fn new(max: usize) -> Self {
+        let in_flight = Self::default();
+        in_flight.set_max(max);
+        in_flight
+    }
+
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// counts a new request, false when the cap is reached and it isn't to
+    /// be sent
+    pub fn try_add(&self) -> bool {
+        let max = self.max();
+        self.requests
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |requests| {
+                (requests < max).then_some(requests + 1)
+            })
+            .is_ok()
+    }
+
+    /// forgets requests that were answered, cancelled or lost with their peer
+    pub fn remove(&self, count: usize) {
+        let _ = self
+            .requests
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |requests| {
+                Some(requests.saturating_sub(count))
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_up_to_the_cap() {
+        let in_flight = InFlight::new(2);
+        assert!(in_flight.try_add());
+        assert!(in_flight.try_add());
+        assert!(!in_flight.try_add());
+        in_flight.remove(1);
+        assert_eq!(in_flight.requests(), 1);
+        assert!(in_flight.try_add());
+        in_flight.remove(5);
+        assert_eq!(in_flight.requests(), 0);
+    }
+}
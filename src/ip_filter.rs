@@ -0,0 +1,315 @@
+use crate::gzip;
+use crate::tracker;
+use anyhow::{anyhow, bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// eMule .dat ranges with an access level above this are allowed
+const EMULE_MAX_BLOCKED_LEVEL: u32 = 127;
+/// gzipped lists decompressing to more are refused, the largest public
+/// lists are a few tens of MiB
+const MAX_BLOCKLIST_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Blocked address ranges, peers in them are neither connected to nor
+/// accepted. Lists can be loaded from files or urls, gzipped or not, and
+/// mix the formats blocklists come in:
+///
+/// ```text
+/// # CIDR blocks or single addresses
+/// 10.0.0.0/8
+/// 2001:db8::/32
+/// 192.168.1.1
+/// # PeerGuardian
+/// Some organization:1.2.4.0-1.2.4.255
+/// # eMule .dat, the third column is the access level
+/// 001.002.008.000 - 001.002.008.255 , 000 , Some organization
+/// ```
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    /// sorted and merged, IPv4 addresses are mapped to IPv6 ones
+    ranges: Vec<(u128, u128)>,
+    hits: AtomicU64,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut ranges = vec![];
+        for (index, line) in text.lines().enumerate() {
+            if let Some(range) =
+                parse_line(line).map_err(|error| anyhow!("line {}: {}", index + 1, error))?
+            {
+                ranges.push(range);
+            }
+        }
+        let mut filter = Self::new();
+        filter.ranges = ranges;
+        filter.merge();
+        Ok(filter)
+    }
+
+    /// the contents of a blocklist, gzipped or not
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let data = match gzip::is_gzip(data) {
+            true => gzip::decompress(data, MAX_BLOCKLIST_SIZE)?,
+            false => data.to_vec(),
+        };
+        Self::parse(&String::from_utf8_lossy(&data))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::from_bytes(&data).with_context(|| format!("loading {}", path.display()))
+    }
+
+    /// over plain http
+    pub fn fetch(url: &str) -> Result<Self> {
+        let data = tracker::http_get(url).with_context(|| format!("fetching {}", url))?;
+        Self::from_bytes(&data).with_context(|| format!("loading {}", url))
+    }
+
+    /// from `first` to `last` included, both of the same family
+    pub fn add_range(&mut self, first: IpAddr, last: IpAddr) -> Result<()> {
+        self.ranges.push(range(first, last)?);
+        self.merge();
+        Ok(())
+    }
+
+    /// like 10.0.0.0/8
+    pub fn add_cidr(&mut self, cidr: &str) -> Result<()> {
+        self.ranges.push(parse_cidr(cidr)?);
+        self.merge();
+        Ok(())
+    }
+
+    /// merged ranges, overlapping ones count once
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        let ip = to_u128(ip);
+        let next = self.ranges.partition_point(|(first, _)| *first <= ip);
+        next > 0 && self.ranges[next - 1].1 >= ip
+    }
+
+    /// like `is_blocked`, and counts the address as a hit when it is
+    pub fn blocks(&self, ip: IpAddr) -> bool {
+        let blocked = self.is_blocked(ip);
+        if blocked {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        blocked
+    }
+
+    /// addresses blocked so far
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// the ranges of `other`, keeping the hits counted so far
+    pub fn replace(&mut self, other: IpFilter) {
+        self.ranges = other.ranges;
+    }
+
+    fn merge(&mut self) {
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(self.ranges.len());
+        for (first, last) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(previous) if first <= previous.1.saturating_add(1) => {
+                    previous.1 = previous.1.max(last)
+                }
+                _ => merged.push((first, last)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn range(first: IpAddr, last: IpAddr) -> Result<(u128, u128)> {
+    if first.is_ipv4() != last.is_ipv4() {
+        bail!("range from {} to {} mixes IPv4 and IPv6", first, last);
+    }
+    let (first, last) = (to_u128(first), to_u128(last));
+    if first > last {
+        bail!("range ends before it starts");
+    }
+    Ok((first, last))
+}
+
+/// blocked range of a line in any of the formats, None for comments, blank
+/// lines and allowed eMule ranges
+fn parse_line(line: &str) -> Result<Option<(u128, u128)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return Ok(None);
+    }
+    if let Some((addresses, rest)) = line.split_once(',') {
+        let level = rest.split(',').next().unwrap_or_default().trim();
+        if let (Ok(level), Ok(range)) = (level.parse::<u32>(), parse_range(addresses)) {
+            return Ok((level <= EMULE_MAX_BLOCKED_LEVEL).then_some(range));
+        }
+    }
+    if let Ok(range) = parse_cidr(line) {
+        return Ok(Some(range));
+    }
+    let error = match parse_range(line) {
+        Ok(range) => return Ok(Some(range)),
+        Err(error) => error,
+    };
+    // a PeerGuardian name, which may contain colons itself
+    match line.rsplit_once(':') {
+        Some((_, addresses)) => parse_range(addresses).map(Some).map_err(|_| error),
+        None => Err(error),
+    }
+}
+
+/// `first-last` or a single address
+fn parse_range(text: &str) -> Result<(u128, u128)> {
+    match text.split_once('-') {
+        Some((first, last)) => range(parse_ip(first)?, parse_ip(last)?),
+        None => {
+            let ip = parse_ip(text)?;
+            range(ip, ip)
+        }
+    }
+}
+
+fn parse_cidr(text: &str) -> Result<(u128, u128)> {
+    let (ip, prefix) = text
+        .trim()
+        .split_once('/')
+        .ok_or_else(|| anyhow!("invalid CIDR block {}", text))?;
+    let ip = parse_ip(ip)?;
+    let bits = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix: u32 = prefix
+        .trim()
+        .parse()
+        .ok()
+        .filter(|prefix| *prefix <= bits)
+        .ok_or_else(|| anyhow!("invalid prefix length in {}", text))?;
+    // IPv4 prefixes apply to the low 32 bits of the mapped address
+    let host_bits = bits - prefix;
+    let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+    let first = to_u128(ip) & mask;
+    Ok((first, first | !mask))
+}
+
+/// eMule lists pad IPv4 addresses with zeros, like 001.002.003.004
+fn parse_ip(text: &str) -> Result<IpAddr> {
+    let text = text.trim();
+    if text.contains(':') {
+        let ip: Ipv6Addr = text
+            .parse()
+            .map_err(|_| anyhow!("invalid address {}", text))?;
+        return Ok(IpAddr::V6(ip));
+    }
+    let octets: Option<Vec<u8>> = text.split('.').map(|octet| octet.parse().ok()).collect();
+    match octets.as_deref() {
+        Some([a, b, c, d]) => Ok(IpAddr::V4(Ipv4Addr::new(*a, *b, *c, *d))),
+        _ => bail!("invalid address {}", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_every_format() -> Result<()> {
+        let filter = IpFilter::parse(
+            "# comment\n\
+             10.0.0.0/8\n\
+             2001:db8::/32\n\
+             192.168.1.1\n\
+             Evil corp, ltd: 1/2:1.2.4.0-1.2.4.255\n\
+             001.002.008.000 - 001.002.008.255 , 000 , Blocked\n\
+             001.002.009.000 - 001.002.009.255 , 200 , Allowed\n\
+             \n\
+             10.1.0.0/16\n",
+        )?;
+        // 10.1.0.0/16 is within 10.0.0.0/8
+        assert_eq!(filter.len(), 5);
+        for blocked in [
+            "10.0.0.0",
+            "10.255.255.255",
+            "2001:db8::1",
+            "192.168.1.1",
+            "1.2.4.7",
+            "1.2.8.255",
+        ] {
+            assert!(filter.is_blocked(ip(blocked)), "{}", blocked);
+        }
+        for allowed in [
+            "11.0.0.0",
+            "9.255.255.255",
+            "2001:db9::",
+            "192.168.1.2",
+            "1.2.9.1",
+            "::ffff:11.0.0.0",
+        ] {
+            assert!(!filter.is_blocked(ip(allowed)), "{}", allowed);
+        }
+
+        assert_eq!(
+            IpFilter::parse("10.0.0.0/8\nnonsense")
+                .unwrap_err()
+                .to_string(),
+            "line 2: invalid address nonsense"
+        );
+        assert!(IpFilter::parse("10.0.0.0/33").is_err());
+        assert!(IpFilter::parse("1.2.3.4-::1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn counts_hits() -> Result<()> {
+        let mut filter = IpFilter::new();
+        filter.add_range(ip("1.0.0.0"), ip("1.0.0.255"))?;
+        filter.add_cidr("1.0.1.0/24")?;
+        assert_eq!(filter.len(), 1);
+        assert!(filter.blocks(ip("1.0.1.5")));
+        assert!(!filter.blocks(ip("1.0.2.5")));
+        assert_eq!(filter.hits(), 1);
+
+        filter.replace(IpFilter::parse("2.0.0.0/8")?);
+        assert!(filter.blocks(ip("2.1.1.1")));
+        assert!(!filter.blocks(ip("1.0.1.5")));
+        assert_eq!(filter.hits(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn loads_gzipped_lists() -> Result<()> {
+        // "10.0.0.0/8\n"
+        let gzipped = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x33, 0x34, 0xd0, 0x03,
+            0x43, 0x7d, 0x0b, 0x2e, 0x00, 0xd4, 0xd5, 0x17, 0xf4, 0x0b, 0x00, 0x00, 0x00,
+        ];
+        let filter = IpFilter::from_bytes(&gzipped)?;
+        assert!(filter.is_blocked(ip("10.1.2.3")));
+        assert_eq!(filter.len(), 1);
+        Ok(())
+    }
+}
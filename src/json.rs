@@ -0,0 +1,439 @@
+use anyhow::{bail, Context, Result};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A JSON document, written compactly by `Display` and read by `parse`.
+/// Objects keep their keys in the order they were added. Whole numbers are
+/// kept exactly as `Integer`, they equal the same `Number`.
+#[derive(Debug, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object() -> Self {
+        Json::Object(vec![])
+    }
+
+    /// adds a key to an object, anything else is left as is
+    pub fn with(mut self, key: &str, value: impl Into<Json>) -> Self {
+        if let Json::Object(entries) = &mut self {
+            entries.push((key.to_string(), value.into()));
+        }
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Integer(number) => Some(*number as f64),
+            Json::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// whole numbers, those written with a fraction or exponent only when
+    /// they are exact
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Integer(number) => Some(*number),
+            Json::Number(number) if number.fract() == 0.0 && number.abs() < 2f64.powi(53) => {
+                Some(*number as i64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut reader = Reader {
+            text: text.as_bytes(),
+            current: 0,
+        };
+        let value = reader.value(0)?;
+        reader.skip_whitespace();
+        if reader.current < reader.text.len() {
+            bail!("trailing characters at {}", reader.current);
+        }
+        Ok(value)
+    }
+}
+
+/// deeper documents are refused rather than overflowing the stack
+const MAX_DEPTH: usize = 128;
+
+struct Reader<'a> {
+    text: &'a [u8],
+    current: usize,
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.text.get(self.current) {
+            self.current += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Result<u8> {
+        self.skip_whitespace();
+        match self.text.get(self.current) {
+            Some(byte) => Ok(*byte),
+            None => bail!("unexpected end of the document"),
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        if self.peek()? != byte {
+            bail!("expected {} at {}", byte as char, self.current);
+        }
+        self.current += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json> {
+        if !self.text[self.current..].starts_with(word.as_bytes()) {
+            bail!("unexpected character at {}", self.current);
+        }
+        self.current += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json> {
+        if depth > MAX_DEPTH {
+            bail!("nested too deeply");
+        }
+        match self.peek()? {
+            b'{' => {
+                self.current += 1;
+                let mut entries = vec![];
+                if self.peek()? == b'}' {
+                    self.current += 1;
+                    return Ok(Json::Object(entries));
+                }
+                loop {
+                    if self.peek()? != b'"' {
+                        bail!("expected a key at {}", self.current);
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    entries.push((key, self.value(depth + 1)?));
+                    match self.peek()? {
+                        b',' => self.current += 1,
+                        b'}' => {
+                            self.current += 1;
+                            return Ok(Json::Object(entries));
+                        }
+                        _ => bail!("expected , or }} at {}", self.current),
+                    }
+                }
+            }
+            b'[' => {
+                self.current += 1;
+                let mut values = vec![];
+                if self.peek()? == b']' {
+                    self.current += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    match self.peek()? {
+                        b',' => self.current += 1,
+                        b']' => {
+                            self.current += 1;
+                            return Ok(Json::Array(values));
+                        }
+                        _ => bail!("expected , or ] at {}", self.current),
+                    }
+                }
+            }
+            b'"' => Ok(Json::String(self.string()?)),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'n' => self.literal("null", Json::Null),
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => bail!("unexpected character at {}", self.current),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.current;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.text.get(self.current)
+        {
+            self.current += 1;
+        }
+        let text = std::str::from_utf8(&self.text[start..self.current])?;
+        if let Ok(value) = text.parse() {
+            return Ok(Json::Integer(value));
+        }
+        let value = text
+            .parse()
+            .with_context(|| format!("invalid number {}", text))?;
+        Ok(Json::Number(value))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .text
+            .get(self.current..self.current + 4)
+            .context("unexpected end of the document")?;
+        self.current += 4;
+        let digits = std::str::from_utf8(digits)?;
+        u32::from_str_radix(digits, 16).with_context(|| format!("invalid escape \\u{}", digits))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            let byte = *self.text.get(self.current).context("unterminated string")?;
+            self.current += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.text.get(self.current).context("unterminated string")?;
+                    self.current += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // characters past the basic plane come as a
+                            // surrogate pair
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.text[self.current..].starts_with(b"\\u")
+                            {
+                                self.current += 2;
+                                let low = self.hex4()?;
+                                code =
+                                    0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                            }
+                            char::from_u32(code).context("invalid unicode escape")?
+                        }
+                        _ => bail!("invalid escape at {}", self.current),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                byte if byte < 0x20 => bail!("control character in a string at {}", self.current),
+                byte => bytes.push(byte),
+            }
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl PartialEq for Json {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Json::Null, Json::Null) => true,
+            (Json::Bool(a), Json::Bool(b)) => a == b,
+            (Json::Integer(a), Json::Integer(b)) => a == b,
+            (Json::Integer(a), Json::Number(b)) | (Json::Number(b), Json::Integer(a)) => {
+                *a as f64 == *b && b.fract() == 0.0 && *b as i64 == *a
+            }
+            (Json::Number(a), Json::Number(b)) => a == b,
+            (Json::String(a), Json::String(b)) => a == b,
+            (Json::Array(a), Json::Array(b)) => a == b,
+            (Json::Object(a), Json::Object(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Integer(value) => write!(f, "{}", value),
+            // JSON has no infinities or NaN
+            Json::Number(value) if !value.is_finite() => f.write_str("null"),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(text) => write_string(f, text),
+            Json::Array(values) => {
+                f.write_str("[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(entries) => {
+                f.write_str("{")?;
+                for (index, (key, value)) in entries.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(value: f64) -> Self {
+        Json::Number(value)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        match i64::try_from(value) {
+            Ok(value) => Json::Integer(value),
+            Err(_) => Json::Number(value as f64),
+        }
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::from(value as u64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Self {
+        Json::Integer(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(values: Vec<T>) -> Self {
+        Json::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_documents() {
+        let json = Json::object()
+            .with("name", "a \"quoted\"\nname")
+            .with("size", 1024u64)
+            .with("ratio", 0.5)
+            .with("comment", None::<String>)
+            .with("tags", vec!["a", "b"])
+            .with("private", false);
+        assert_eq!(
+            json.to_string(),
+            r#"{"name":"a \"quoted\"\nname","size":1024,"ratio":0.5,"comment":null,"tags":["a","b"],"private":false}"#
+        );
+        assert_eq!(json.get("size"), Some(&Json::Number(1024.0)));
+        assert_eq!(Json::Number(f64::NAN).to_string(), "null");
+    }
+
+    #[test]
+    fn parses_documents() -> Result<()> {
+        let text = r#"{"name":"a \"quoted\"\nname","size":1024,"ratio":0.5,"comment":null,"tags":["a","b"],"private":false}"#;
+        let json = Json::parse(text)?;
+        assert_eq!(json.to_string(), text);
+        assert_eq!(
+            json.get("name").and_then(Json::as_str),
+            Some("a \"quoted\"\nname")
+        );
+        assert_eq!(json.get("ratio").and_then(Json::as_f64), Some(0.5));
+        assert_eq!(json.get("private").and_then(Json::as_bool), Some(false));
+        assert_eq!(json.get("comment").and_then(Json::as_str), None);
+        assert_eq!(
+            Json::parse(" [ -1.5e3 , true, {} ,\"\\u00e9\\ud83d\\ude00\" ] ")?,
+            Json::Array(vec![
+                Json::Number(-1500.0),
+                Json::Bool(true),
+                Json::object(),
+                Json::String("\u{e9}\u{1f600}".into())
+            ])
+        );
+        for invalid in &["", "[1,]", "{\"a\" 1}", "\"open", "nul", "[1] 2", "{1:2}"] {
+            assert!(Json::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert!(Json::parse(&"[".repeat(1000)).is_err());
+
+        let large = Json::parse("9007199254740993")?;
+        assert_eq!(large.as_i64(), Some((1 << 53) + 1));
+        assert_eq!(large.to_string(), "9007199254740993");
+        assert_eq!(Json::parse("2")?, Json::Number(2.0));
+        assert_ne!(large, Json::Number(9007199254740992.0));
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! BitTorrent engine: torrents with their pieces, storage and peers, magnet
+//! links, trackers, the DHT and local service discovery, tied together by a
+//! `Session`. The binary is a small CLI over it.
+//!
+//! All of it but the parsers of bencode, .torrent files and magnet links is
+//! behind the default `engine` feature, for builds like that of `wasm`.
+//!
+//! # Execution model
+//!
+//! The protocol logic is written as state machines doing no I/O: `Torrent`,
+//! `dht::Dht`, `lsd::Lsd` and `metadata::MetadataDownload` are fed what
+//! arrives and polled for what to send. The engine drives them as tasks of
+//! the tokio runtime of `runtime::handle`, talking over channels, one task
+//! per concern:
+//!
+//! - `swarm::SwarmTask` runs the engine loop: it accepts and dials the peers
+//!   of the torrents, each connection a task of its own, and dispatches the
+//!   disk jobs and hash checks of the torrents to the blocking threads of
+//!   the runtime
+//! - `tracker::TrackerTask` announces the torrents to their trackers, the
+//!   announces running on the blocking threads
+//! - `dht::DhtTask` and `lsd::LsdTask` run a node each on their own socket
+//!
+//! The servers of the session and the helpers that block on their own I/O
+//! keep std threads:
+//!
+//! - `port_mapping::PortMapper` keeps the listen port mapped on the gateway
+//! - `metrics::MetricsServer` serves the metrics of the session to Prometheus
+//! - `stream::StreamServer` streams files to players as they download
+//! - `metadata::MetadataTask` fetches the metadata of a magnet link with
+//!   blocking connections of its own, one thread per peer it asks, not
+//!   those of the swarm
+//! - `disk::DiskIo` and `verifier::Verifier` are thread pools for the
+//!   callers driving a torrent themselves
+//!
+//! The state of a torrent has no single owner, it is shared behind the
+//! lock of its `TorrentHandle` by everything that drives it. The engine
+//! loop feeds it what its peers send, the disk completions through
+//! `Torrent::disk_job_done` and the hash checks through
+//! `Torrent::verification_done`. The tracker, DHT and LSD tasks hand it
+//! peers through `TorrentHandle::add_peers`, and the session, for the api
+//! and the bindings too, reads and changes it directly, pausing it or
+//! setting the priorities of its files. The lock is only held for the duration of one
+//! such call, never while waiting on a socket or the disk, so each call
+//! sees the torrent as the previous one left it, whoever made it.
+//!
+//! What the tasks and torrents do is logged with `tracing`, in spans and
+//! fields carrying the info hash, the peer or the tracker concerned, for
+//! the subscriber of the program to record.
+
+#[cfg(feature = "engine")]
+pub mod api;
+#[cfg(feature = "engine")]
+pub mod availability;
+#[cfg(feature = "engine")]
+pub mod bandwidth;
+#[cfg(feature = "engine")]
+mod base64;
+pub mod bencode;
+#[cfg(feature = "engine")]
+pub mod bitfield;
+#[cfg(feature = "engine")]
+mod cache;
+#[cfg(feature = "engine")]
+pub mod category;
+#[cfg(feature = "engine")]
+pub mod config;
+#[cfg(feature = "engine")]
+pub mod connections;
+#[cfg(all(feature = "engine", unix))]
+pub mod control;
+#[cfg(feature = "engine")]
+pub mod create;
+#[cfg(feature = "engine")]
+pub mod dht;
+#[cfg(feature = "engine")]
+pub mod disk;
+#[cfg(feature = "engine")]
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod file_map;
+#[cfg(feature = "engine")]
+mod file_pool;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "engine")]
+mod gzip;
+#[cfg(feature = "engine")]
+pub mod hooks;
+#[cfg(feature = "engine")]
+mod http;
+#[cfg(feature = "engine")]
+pub mod in_flight;
+#[cfg(feature = "engine")]
+pub mod ip_filter;
+pub mod json;
+#[cfg(feature = "engine")]
+pub mod listen;
+#[cfg(feature = "engine")]
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+#[cfg(feature = "engine")]
+pub mod message;
+#[cfg(feature = "engine")]
+pub mod metadata;
+pub mod metainfo;
+#[cfg(feature = "engine")]
+pub mod metrics;
+#[cfg(feature = "engine")]
+pub mod peer;
+#[cfg(feature = "engine")]
+pub mod pex;
+#[cfg(feature = "engine")]
+pub mod picker;
+#[cfg(feature = "engine")]
+pub mod port_mapping;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "engine")]
+pub mod queue;
+#[cfg(feature = "engine")]
+mod rate;
+#[cfg(feature = "engine")]
+pub mod regex;
+#[cfg(feature = "engine")]
+pub mod resume;
+#[cfg(feature = "engine")]
+pub mod rss;
+#[cfg(feature = "engine")]
+pub mod runtime;
+#[cfg(feature = "engine")]
+pub mod schedule;
+#[cfg(feature = "engine")]
+pub mod scheduler;
+#[cfg(feature = "engine")]
+pub mod seeding;
+#[cfg(feature = "engine")]
+pub mod session;
+#[cfg(feature = "engine")]
+pub mod storage;
+#[cfg(feature = "engine")]
+pub mod stream;
+#[cfg(feature = "engine")]
+pub mod swarm;
+#[cfg(feature = "engine")]
+pub mod torrent;
+#[cfg(feature = "engine")]
+pub mod tracker;
+#[cfg(feature = "engine")]
+pub mod transmission;
+#[cfg(feature = "engine")]
+pub mod upload;
+#[cfg(feature = "engine")]
+pub mod verifier;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "engine")]
+pub mod watch;
+#[cfg(feature = "engine")]
+mod websocket;
+
+#[cfg(feature = "engine")]
+pub use config::Config;
+pub use magnet::Magnet;
+pub use metainfo::Metainfo;
+#[cfg(feature = "engine")]
+pub use session::Session;
+#[cfg(feature = "engine")]
+pub use torrent::{Torrent, TorrentHandle};
@@ -0,0 +1,570 @@
+use crate::dht::routing::random_bytes;
+use crate::events::Alert;
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::ops::RangeInclusive;
+
+/// picked by `ListenPort::Random`, the dynamic ports of IANA
+const RANDOM_PORTS: RangeInclusive<u16> = 49152..=65535;
+/// ports tried when they are picked at random, by us or by the system
+const TRIES: usize = 20;
+
+/// Which port the session listens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenPort {
+    /// 0 lets the system pick one
+    Fixed(u16),
+    /// the first free one, both ends included
+    Range(u16, u16),
+    /// a different one at each start, see `RANDOM_PORTS`
+    Random,
+}
+
+impl ListenPort {
+    /// `6881`, `6881-6889` or `random`
+    pub fn parse(text: &str) -> Result<Self> {
+        let port = |text: &str| {
+            text.trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid port {}", text.trim()))
+        };
+        let ports = match text.trim() {
+            "random" => ListenPort::Random,
+            text => match text.split_once('-') {
+                Some((first, last)) => ListenPort::Range(port(first)?, port(last)?),
+                None => ListenPort::Fixed(port(text)?),
+            },
+        };
+        ports.validate()?;
+        Ok(ports)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let ListenPort::Range(first, last) = *self {
+            if first == 0 || first > last {
+                bail!("invalid port range {}", self);
+            }
+        }
+        Ok(())
+    }
+
+    /// to try in order
+    fn candidates(&self) -> Vec<u16> {
+        match *self {
+            ListenPort::Fixed(0) => vec![0; TRIES],
+            ListenPort::Fixed(port) => vec![port],
+            ListenPort::Range(first, last) => (first..=last).collect(),
+            ListenPort::Random => {
+                let mut random = [0; TRIES * 2];
+                random_bytes(&mut random);
+                let count = (RANDOM_PORTS.end() - RANDOM_PORTS.start()) as u32 + 1;
+                random
+                    .chunks(2)
+                    .map(|bytes| {
+                        let random = u16::from_be_bytes([bytes[0], bytes[1]]) as u32;
+                        RANDOM_PORTS.start() + (random % count) as u16
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl From<u16> for ListenPort {
+    fn from(port: u16) -> Self {
+        ListenPort::Fixed(port)
+    }
+}
+
+impl fmt::Display for ListenPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenPort::Fixed(port) => write!(f, "{}", port),
+            ListenPort::Range(first, last) => write!(f, "{}-{}", first, last),
+            ListenPort::Random => write!(f, "random"),
+        }
+    }
+}
+
+/// Which IP versions peers, trackers and the DHT are reached over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpFamilies {
+    #[default]
+    Both,
+    /// both, IPv4 addresses are tried first
+    PreferV4,
+    /// both, IPv6 addresses are tried first
+    PreferV6,
+    V4Only,
+    V6Only,
+}
+
+impl IpFamilies {
+    /// `both`, `prefer-ipv4`, `prefer-ipv6`, `ipv4` or `ipv6`
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(match text {
+            "both" => IpFamilies::Both,
+            "prefer-ipv4" => IpFamilies::PreferV4,
+            "prefer-ipv6" => IpFamilies::PreferV6,
+            "ipv4" => IpFamilies::V4Only,
+            "ipv6" => IpFamilies::V6Only,
+            _ => bail!("unknown ip families {}", text),
+        })
+    }
+
+    /// IPv4 addresses mapped to IPv6 ones count as IPv4
+    pub fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            IpFamilies::V4Only => ip.to_canonical().is_ipv4(),
+            IpFamilies::V6Only => ip.to_canonical().is_ipv6(),
+            _ => true,
+        }
+    }
+
+    /// whether `ip` is of the family tried first, every allowed address is
+    /// when neither is preferred
+    pub fn prefers(self, ip: IpAddr) -> bool {
+        match self {
+            IpFamilies::PreferV4 => ip.to_canonical().is_ipv4(),
+            IpFamilies::PreferV6 => ip.to_canonical().is_ipv6(),
+            families => families.allows(ip),
+        }
+    }
+
+    /// the first preferred of the allowed addresses
+    pub fn pick(self, addrs: impl IntoIterator<Item = SocketAddr>) -> Option<SocketAddr> {
+        let allowed: Vec<_> = addrs
+            .into_iter()
+            .filter(|addr| self.allows(addr.ip()))
+            .collect();
+        allowed
+            .iter()
+            .find(|addr| self.prefers(addr.ip()))
+            .or_else(|| allowed.first())
+            .copied()
+    }
+}
+
+impl fmt::Display for IpFamilies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            IpFamilies::Both => "both",
+            IpFamilies::PreferV4 => "prefer-ipv4",
+            IpFamilies::PreferV6 => "prefer-ipv6",
+            IpFamilies::V4Only => "ipv4",
+            IpFamilies::V6Only => "ipv6",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// IPv4 addresses mapped to IPv6 ones, like dual-stack sockets see IPv4
+/// peers, as plain IPv4 ones
+pub fn unmapped(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// our address that is reachable from the internet, if the system routes
+/// IPv4 traffic from one
+pub fn public_ipv4() -> Option<Ipv4Addr> {
+    match source_address(SocketAddr::from(([192, 0, 2, 1], 9)))? {
+        IpAddr::V4(ip)
+            if !ip.is_private()
+                && !ip.is_loopback()
+                && !ip.is_link_local()
+                && !ip.is_unspecified() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    }
+}
+
+/// our global IPv6 address, if the system routes IPv6 traffic from one
+pub fn public_ipv6() -> Option<Ipv6Addr> {
+    let documentation = SocketAddr::new(
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        9,
+    );
+    match source_address(documentation)? {
+        // global unicast, 2000::/3
+        IpAddr::V6(ip) if ip.segments()[0] & 0xe000 == 0x2000 => Some(ip),
+        _ => None,
+    }
+}
+
+/// nothing is sent, the system only picks the address it would send from
+fn source_address(to: SocketAddr) -> Option<IpAddr> {
+    let any: SocketAddr = match to {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(any).ok()?;
+    socket.connect(to).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// The sockets of a session, on the same port
+#[derive(Debug)]
+pub struct Listeners {
+    /// on every interface it takes both families, unless one is required
+    pub tcp: TcpListener,
+    /// IPv4, for the DHT
+    pub udp: Option<UdpSocket>,
+    /// IPv6, for the DHT
+    pub udp6: Option<UdpSocket>,
+    /// when none of the ports asked for was free and the system picked one
+    pub alert: Option<Alert>,
+}
+
+impl Listeners {
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.tcp.local_addr()?.port())
+    }
+}
+
+/// Binds a tcp listener to the first free port of `ports`, and udp sockets
+/// on the same port with `udp`. Without an `ip` it listens on every
+/// interface over the allowed families. Ports taken by other programs are
+/// skipped, once they all are the system picks one and the alert says so.
+pub fn bind(
+    ip: Option<IpAddr>,
+    ports: ListenPort,
+    udp: bool,
+    families: IpFamilies,
+) -> Result<Listeners> {
+    let candidates = ports.candidates();
+    let bind_any = |ports: &[u16]| {
+        let mut last_error = io::Error::new(io::ErrorKind::AddrInUse, "no port to try");
+        for port in ports {
+            match bind_port(ip, *port, udp, families) {
+                Err(error) if is_taken(&error) => last_error = error,
+                bound => return bound,
+            }
+        }
+        Err(last_error)
+    };
+    let alert = match bind_any(&candidates) {
+        Ok(listeners) => return Ok(listeners),
+        Err(error) if is_taken(&error) => Alert::ListenFailed {
+            port: candidates.first().copied().unwrap_or_default(),
+            error: error.to_string(),
+        },
+        Err(error) => match ip {
+            Some(ip) => bail!("listening on {}: {}", ip, error),
+            None => bail!("listening: {}", error),
+        },
+    };
+    let listeners = bind_any(&ListenPort::Fixed(0).candidates())
+        .map_err(|error| anyhow!("no free port to listen on: {}", error))?;
+    Ok(Listeners {
+        alert: Some(alert),
+        ..listeners
+    })
+}
+
+/// stops at errors other than taken ports, like addresses that aren't ours
+fn bind_port(
+    ip: Option<IpAddr>,
+    port: u16,
+    udp: bool,
+    families: IpFamilies,
+) -> io::Result<Listeners> {
+    let v4 = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let tcp = match (ip, families) {
+        (Some(ip), _) => TcpListener::bind((ip, port))?,
+        (None, IpFamilies::V4Only) => TcpListener::bind((v4, port))?,
+        (None, IpFamilies::V6Only) => bind_tcp6(port, true)?,
+        // hosts without IPv6 only listen over IPv4
+        (None, _) => match bind_tcp6(port, false) {
+            Err(error) if !is_taken(&error) => TcpListener::bind((v4, port))?,
+            tcp => tcp?,
+        },
+    };
+    let port = tcp.local_addr()?.port();
+    let mut listeners = Listeners {
+        tcp,
+        udp: None,
+        udp6: None,
+        alert: None,
+    };
+    if !udp {
+        return Ok(listeners);
+    }
+    match ip {
+        Some(IpAddr::V4(ip)) => listeners.udp = Some(UdpSocket::bind((ip, port))?),
+        Some(IpAddr::V6(ip)) => listeners.udp6 = Some(UdpSocket::bind((ip, port))?),
+        None => {
+            if families != IpFamilies::V6Only {
+                listeners.udp = Some(UdpSocket::bind((v4, port))?);
+            }
+            if families != IpFamilies::V4Only {
+                listeners.udp6 = match bind_udp6(port) {
+                    Err(error) if !is_taken(&error) && families != IpFamilies::V6Only => None,
+                    udp6 => Some(udp6?),
+                };
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+fn is_taken(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// on every interface, over IPv4 too unless `v6_only`
+#[cfg(target_os = "linux")]
+fn bind_tcp6(port: u16, v6_only: bool) -> io::Result<TcpListener> {
+    let fd = bind6(libc::SOCK_STREAM, port, v6_only)?;
+    // SAFETY: the descriptor is a bound stream socket
+    if unsafe { libc::listen(std::os::unix::io::AsRawFd::as_raw_fd(&fd), 128) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(TcpListener::from(fd))
+}
+
+/// IPv6 only, so it can share its port with the IPv4 socket
+#[cfg(target_os = "linux")]
+fn bind_udp6(port: u16) -> io::Result<UdpSocket> {
+    Ok(UdpSocket::from(bind6(libc::SOCK_DGRAM, port, true)?))
+}
+
+/// the system decides whether IPv4 connections are taken without libc
+#[cfg(not(target_os = "linux"))]
+fn bind_tcp6(port: u16, _v6_only: bool) -> io::Result<TcpListener> {
+    TcpListener::bind((Ipv6Addr::UNSPECIFIED, port))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_udp6(port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+}
+
+/// a socket bound to [::] with IPV6_V6ONLY set as asked, which std can't do
+#[cfg(target_os = "linux")]
+fn bind6(kind: libc::c_int, port: u16, v6_only: bool) -> io::Result<std::os::unix::io::OwnedFd> {
+    use std::os::unix::io::{FromRawFd, OwnedFd};
+
+    // SAFETY: the descriptor is owned as soon as it is created, which
+    // closes it on errors
+    let fd = unsafe { libc::socket(libc::AF_INET6, kind | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+    let set = |level, option, value: libc::c_int| {
+        // SAFETY: the value lives through the call
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                option,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+    set(
+        libc::IPPROTO_IPV6,
+        libc::IPV6_V6ONLY,
+        v6_only as libc::c_int,
+    )?;
+    if kind == libc::SOCK_STREAM {
+        set(libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    }
+    // SAFETY: all zeros is the unspecified address
+    let mut address: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    address.sin6_port = port.to_be();
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(owned)
+}
+
+/// an address like 192.168.1.5, or the name of a network interface like
+/// eth0 whose IPv4 address is preferred
+pub fn interface_address(interface: &str) -> Result<IpAddr> {
+    if let Ok(ip) = interface.parse() {
+        return Ok(ip);
+    }
+    interface_by_name(interface)
+}
+
+#[cfg(target_os = "linux")]
+fn interface_by_name(name: &str) -> Result<IpAddr> {
+    use std::ffi::CStr;
+
+    let mut addresses: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: the list is only read until it is freed below
+    if unsafe { libc::getifaddrs(&mut addresses) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut found = None;
+    let mut current = addresses;
+    while !current.is_null() {
+        let entry = unsafe { &*current };
+        current = entry.ifa_next;
+        if entry.ifa_addr.is_null()
+            || unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != name.as_bytes()
+        {
+            continue;
+        }
+        // SAFETY: the family says which kind of address it points to
+        match unsafe { (*entry.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                found = Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))));
+                break;
+            }
+            libc::AF_INET6 if found.is_none() => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                found = Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(addresses) };
+    found.ok_or_else(|| anyhow!("no interface {} with an address", name))
+}
+
+/// interfaces can only be given by address without libc
+#[cfg(not(target_os = "linux"))]
+fn interface_by_name(name: &str) -> Result<IpAddr> {
+    bail!("invalid address {}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn parses_ports() -> Result<()> {
+        assert_eq!(ListenPort::parse("6881")?, ListenPort::Fixed(6881));
+        assert_eq!(
+            ListenPort::parse("6881-6889")?,
+            ListenPort::Range(6881, 6889)
+        );
+        assert_eq!(ListenPort::parse("random")?, ListenPort::Random);
+        for invalid in ["6889-6881", "0-10", "port", "70000"] {
+            assert!(ListenPort::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(ListenPort::Range(1, 2).to_string(), "1-2");
+        Ok(())
+    }
+
+    #[test]
+    fn skips_taken_ports() -> Result<()> {
+        let taken = TcpListener::bind((LOCALHOST, 0))?;
+        let port = taken.local_addr()?.port();
+        let listeners = bind(
+            Some(LOCALHOST),
+            ListenPort::Range(port, port),
+            false,
+            IpFamilies::Both,
+        )?;
+        assert_ne!(listeners.port()?, port);
+        assert!(matches!(
+            listeners.alert,
+            Some(Alert::ListenFailed { port: failed, .. }) if failed == port
+        ));
+
+        // the udp port of the DHT has to be free too
+        let taken = UdpSocket::bind((LOCALHOST, 0))?;
+        let port = taken.local_addr()?.port();
+        let listeners = bind(
+            Some(LOCALHOST),
+            ListenPort::Fixed(port),
+            true,
+            IpFamilies::Both,
+        )?;
+        let udp = listeners.udp.as_ref().unwrap().local_addr()?.port();
+        assert_ne!(listeners.port()?, port);
+        assert_eq!(listeners.port()?, udp);
+        Ok(())
+    }
+
+    #[test]
+    fn picks_random_ports() -> Result<()> {
+        let listeners = bind(Some(LOCALHOST), ListenPort::Random, true, IpFamilies::Both)?;
+        assert!(RANDOM_PORTS.contains(&listeners.port()?));
+        assert!(listeners.alert.is_none());
+        assert_eq!(listeners.tcp.local_addr()?.ip(), LOCALHOST);
+        Ok(())
+    }
+
+    #[test]
+    fn listens_over_both_families() -> Result<()> {
+        let listeners = bind(None, ListenPort::Fixed(0), true, IpFamilies::Both)?;
+        let port = listeners.port()?;
+        for ip in ["127.0.0.1", "::1"] {
+            std::net::TcpStream::connect((ip.parse::<IpAddr>()?, port))?;
+        }
+        assert_eq!(listeners.udp.as_ref().unwrap().local_addr()?.port(), port);
+        assert_eq!(listeners.udp6.as_ref().unwrap().local_addr()?.port(), port);
+
+        let listeners = bind(None, ListenPort::Fixed(0), true, IpFamilies::V4Only)?;
+        assert!(listeners.tcp.local_addr()?.is_ipv4());
+        assert!(listeners.udp6.is_none());
+        let listeners = bind(None, ListenPort::Fixed(0), true, IpFamilies::V6Only)?;
+        assert!(std::net::TcpStream::connect(("127.0.0.1", listeners.port()?)).is_err());
+        assert!(listeners.udp.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn prefers_and_requires_families() -> Result<()> {
+        let v4: SocketAddr = "1.2.3.4:1".parse()?;
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:1".parse()?;
+        let v6: SocketAddr = "[2001:db8::1]:1".parse()?;
+        assert_eq!(unmapped(mapped), v4);
+        assert_eq!(IpFamilies::Both.pick([v4, v6]), Some(v4));
+        assert_eq!(IpFamilies::PreferV6.pick([v4, v6]), Some(v6));
+        assert_eq!(IpFamilies::PreferV6.pick([v4]), Some(v4));
+        assert_eq!(IpFamilies::V6Only.pick([v4, mapped]), None);
+        assert!(IpFamilies::V4Only.allows(mapped.ip()));
+        assert!(!IpFamilies::V4Only.allows(v6.ip()));
+        for families in [
+            IpFamilies::Both,
+            IpFamilies::PreferV4,
+            IpFamilies::PreferV6,
+            IpFamilies::V4Only,
+            IpFamilies::V6Only,
+        ] {
+            assert_eq!(IpFamilies::parse(&families.to_string())?, families);
+        }
+        assert!(IpFamilies::parse("ipv5").is_err());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finds_interfaces_by_name() -> Result<()> {
+        assert_eq!(interface_address("lo")?, LOCALHOST);
+        assert_eq!(interface_address("127.0.0.1")?, LOCALHOST);
+        assert!(interface_address("no such interface").is_err());
+        Ok(())
+    }
+}
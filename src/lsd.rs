@@ -0,0 +1,486 @@
+use crate::dht::routing::random_bytes;
+use crate::dht::throttle::Throttle;
+use crate::metainfo::to_hex;
+use crate::peer::PeerSource;
+use crate::runtime;
+use crate::torrent::TorrentHandle;
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time;
+
+/// multicast groups local peers announce their torrents to (BEP 14)
+pub const LSD_GROUP: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771));
+pub const LSD_GROUP6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)),
+    6771,
+);
+
+/// each torrent is announced again this often
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// info hashes per announce, keeps the packets well under the mtu
+const MAX_INFO_HASHES: usize = 20;
+/// announces allowed per second from one ip, a peer with many torrents
+/// sends a burst of them
+const PACKETS_PER_IP: u32 = 20;
+/// events kept for `LsdTask::poll_event`, the next ones are dropped
+const TASK_EVENTS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// tells our own announces apart when they loop back
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    /// `BT-SEARCH` request sent to the multicast group
+    pub fn encode(&self, group: SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", to_hex(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// None if the packet isn't an announce with a port and an info hash
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(packet).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':')?;
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok().filter(|port| *port != 0),
+                "infohash" => {
+                    if let Some(info_hash) = hex_info_hash(value) {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+fn hex_info_hash(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..40)
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect();
+    bytes?.try_into().ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LsdEvent {
+    /// a peer on the local network announced one of our torrents
+    Peer {
+        info_hash: [u8; 20],
+        peer: SocketAddr,
+    },
+}
+
+/// Local Service Discovery (BEP 14): announces our torrents to a multicast
+/// group of the local network and reports the peers announcing the same
+/// ones, so they connect without trackers or the DHT. Like the DHT node it
+/// does no I/O, packets are fed to `handle_packet` and the ones queued by
+/// `tick` are sent to the group with `poll_packet`.
+#[derive(Debug)]
+pub struct Lsd {
+    group: SocketAddr,
+    /// where we accept peer connections
+    port: u16,
+    cookie: String,
+    /// when each torrent is announced next
+    torrents: HashMap<[u8; 20], Instant>,
+    throttle: Throttle<IpAddr>,
+    outbox: VecDeque<Vec<u8>>,
+    events: VecDeque<LsdEvent>,
+}
+
+impl Lsd {
+    pub fn new(group: SocketAddr, port: u16) -> Self {
+        let mut cookie = [0; 8];
+        random_bytes(&mut cookie);
+        Self {
+            group,
+            port,
+            cookie: to_hex(&cookie),
+            torrents: HashMap::new(),
+            throttle: Throttle::new(PACKETS_PER_IP),
+            outbox: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn group(&self) -> SocketAddr {
+        self.group
+    }
+
+    /// announced on the next tick, then every `ANNOUNCE_INTERVAL`
+    pub fn add_torrent(&mut self, info_hash: [u8; 20], now: Instant) {
+        self.torrents.entry(info_hash).or_insert(now);
+    }
+
+    pub fn remove_torrent(&mut self, info_hash: &[u8; 20]) {
+        self.torrents.remove(info_hash);
+    }
+
+    /// announces the torrents that are due
+    pub fn tick(&mut self, now: Instant) {
+        let due: Vec<_> = self
+            .torrents
+            .iter_mut()
+            .filter(|(_, next)| **next <= now)
+            .map(|(info_hash, next)| {
+                *next = now + ANNOUNCE_INTERVAL;
+                *info_hash
+            })
+            .collect();
+        for info_hashes in due.chunks(MAX_INFO_HASHES) {
+            let announce = Announce {
+                port: self.port,
+                info_hashes: info_hashes.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+            self.outbox.push_back(announce.encode(self.group));
+        }
+        self.throttle.prune(now);
+    }
+
+    pub fn handle_packet(&mut self, from: SocketAddr, packet: &[u8], now: Instant) {
+        if !self.throttle.take(from.ip(), now) {
+            return;
+        }
+        let announce = match Announce::parse(packet) {
+            Some(announce) => announce,
+            None => return,
+        };
+        if announce.cookie.as_ref() == Some(&self.cookie) {
+            return;
+        }
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        for info_hash in announce.info_hashes {
+            if self.torrents.contains_key(&info_hash) {
+                self.events.push_back(LsdEvent::Peer { info_hash, peer });
+            }
+        }
+    }
+
+    /// next announce to send to `group`
+    pub fn poll_packet(&mut self) -> Option<Vec<u8>> {
+        self.outbox.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<LsdEvent> {
+        self.events.pop_front()
+    }
+}
+
+/// Runs `Lsd` in a task of the runtime over a socket joined to the
+/// multicast group, handing the peers found to the torrents added with
+/// `add_torrent`
+pub struct LsdTask {
+    lsd: Arc<Mutex<Lsd>>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>,
+    events: Receiver<LsdEvent>,
+    stop: watch::Sender<bool>,
+    /// disconnected once the task is done
+    done: Receiver<()>,
+}
+
+impl LsdTask {
+    /// listens to the ipv4 group, announcing that we accept peers on `port`
+    pub fn start(port: u16) -> Result<Self> {
+        let socket = bind_reusable(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LSD_GROUP.port()))?;
+        if let IpAddr::V4(group) = LSD_GROUP.ip() {
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        }
+        // other clients on this host hear us too, our own announces are
+        // recognized by their cookie
+        socket.set_multicast_loop_v4(true)?;
+        Ok(Self::spawn(socket, Lsd::new(LSD_GROUP, port))?)
+    }
+
+    pub fn spawn(socket: UdpSocket, lsd: Lsd) -> io::Result<Self> {
+        let runtime = runtime::handle();
+        let _entered = runtime.enter();
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let lsd = Arc::new(Mutex::new(lsd));
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        let (events, receiver) = mpsc::sync_channel(TASK_EVENTS);
+        let (stop, stopped) = watch::channel(false);
+        let (finished, done) = mpsc::channel();
+        let worker = Worker {
+            socket,
+            lsd: Arc::clone(&lsd),
+            torrents: Arc::clone(&torrents),
+            events,
+            _done: finished,
+        };
+        runtime.spawn(worker.run(stopped));
+        Ok(Self {
+            lsd,
+            torrents,
+            events: receiver,
+            stop,
+            done,
+        })
+    }
+
+    pub fn lsd(&self) -> &Arc<Mutex<Lsd>> {
+        &self.lsd
+    }
+
+    pub fn poll_event(&self) -> Option<LsdEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// announces the torrent on the local network until it is removed,
+    /// private torrents only get peers from their trackers and aren't added
+    pub fn add_torrent(&self, torrent: &TorrentHandle) -> bool {
+        if torrent.is_private() {
+            return false;
+        }
+        let info_hash = torrent.info_hash();
+        self.torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash, torrent.clone());
+        self.lsd
+            .lock()
+            .unwrap()
+            .add_torrent(info_hash, Instant::now());
+        true
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+        self.lsd.lock().unwrap().remove_torrent(info_hash);
+    }
+}
+
+impl Drop for LsdTask {
+    fn drop(&mut self) {
+        let _ = self.stop.send(true);
+        let _ = self.done.recv();
+    }
+}
+
+struct Worker {
+    socket: tokio::net::UdpSocket,
+    lsd: Arc<Mutex<Lsd>>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>,
+    events: SyncSender<LsdEvent>,
+    /// dropped with the worker, which the task waits for
+    _done: Sender<()>,
+}
+
+impl Worker {
+    async fn run(self, mut stop: watch::Receiver<bool>) {
+        let mut buffer = [0; 2048];
+        // wakes up regularly to send announces
+        let mut wakeups = time::interval(Duration::from_millis(100));
+        let mut last_tick = None;
+        loop {
+            let received = tokio::select! {
+                _ = stop.changed() => break,
+                received = self.socket.recv_from(&mut buffer) => received.ok(),
+                _ = wakeups.tick() => None,
+            };
+            let now = Instant::now();
+            let (group, packets, events) = {
+                let mut lsd = self.lsd.lock().unwrap();
+                if let Some((length, from)) = received {
+                    lsd.handle_packet(from, &buffer[..length], now);
+                }
+                if last_tick.is_none_or(|last| now.duration_since(last) >= Duration::from_secs(1)) {
+                    lsd.tick(now);
+                    last_tick = Some(now);
+                }
+                let packets: Vec<_> = std::iter::from_fn(|| lsd.poll_packet()).collect();
+                let events: Vec<_> = std::iter::from_fn(|| lsd.poll_event()).collect();
+                (lsd.group(), packets, events)
+            };
+            for packet in packets {
+                // the next announce is only minutes away
+                let _ = self.socket.send_to(&packet, group).await;
+            }
+            for event in events {
+                let LsdEvent::Peer { info_hash, peer } = &event;
+                if let Some(torrent) = self.torrents.lock().unwrap().get(info_hash) {
+                    torrent.add_peers(Some(*peer), PeerSource::Lsd);
+                }
+                let _ = self.events.try_send(event);
+            }
+        }
+    }
+}
+
+/// several clients on a host can listen to the group port
+#[cfg(target_os = "linux")]
+fn bind_reusable(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+    // SAFETY: the descriptor is owned by the socket as soon as it is
+    // created, which closes it on errors
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let enable: libc::c_int = 1;
+    // SAFETY: the option and address point to values living through the calls
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_reusable(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    UdpSocket::bind(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, port as u8], port))
+    }
+
+    #[test]
+    fn parse_announces() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [1; 20]],
+            cookie: Some("cookie".into()),
+        };
+        let packet = announce.encode(LSD_GROUP);
+        assert!(packet.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert_eq!(Announce::parse(&packet), Some(announce));
+
+        let packet = b"BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\nport:  51413\r\nINFOHASH: 0123456789ABCDEF0123456789abcdef01234567\r\n\r\n\r\n";
+        let announce = Announce::parse(packet).unwrap();
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes[0][..2], [0x01, 0x23]);
+        assert_eq!(announce.cookie, None);
+
+        // no port or info hash
+        assert_eq!(
+            Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+        let packet =
+            b"BT-SEARCH * HTTP/1.1\r\nInfohash: 0123456789abcdef0123456789abcdef01234567\r\n\r\n";
+        assert_eq!(Announce::parse(packet), None);
+        assert_eq!(Announce::parse(b"M-SEARCH * HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn finds_local_peers() {
+        let start = Instant::now();
+        let mut a = Lsd::new(LSD_GROUP, 6881);
+        let mut b = Lsd::new(LSD_GROUP, 6882);
+        a.add_torrent([1; 20], start);
+        a.add_torrent([2; 20], start);
+        b.add_torrent([2; 20], start);
+
+        a.tick(start);
+        let packet = a.poll_packet().unwrap();
+        assert!(a.poll_packet().is_none());
+        b.handle_packet(addr(1), &packet, start);
+        assert_eq!(
+            b.poll_event(),
+            Some(LsdEvent::Peer {
+                info_hash: [2; 20],
+                peer: SocketAddr::new(addr(1).ip(), 6881),
+            })
+        );
+        assert!(b.poll_event().is_none());
+
+        // our own announce looping back
+        a.handle_packet(addr(1), &packet, start);
+        assert!(a.poll_event().is_none());
+
+        // not again before the interval
+        a.tick(start + Duration::from_secs(1));
+        assert!(a.poll_packet().is_none());
+        a.tick(start + ANNOUNCE_INTERVAL);
+        assert!(a.poll_packet().is_some());
+    }
+
+    #[test]
+    fn batches_info_hashes() {
+        let start = Instant::now();
+        let mut lsd = Lsd::new(LSD_GROUP, 6881);
+        for index in 0..MAX_INFO_HASHES + 1 {
+            lsd.add_torrent([index as u8; 20], start);
+        }
+        lsd.tick(start);
+        let packets: Vec<_> = std::iter::from_fn(|| lsd.poll_packet()).collect();
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|packet| packet.len() < 1400));
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::{anyhow, bail, Result};
+use sha1::{Digest, Sha1};
+use std::convert::TryInto;
+
+/// Parsed `magnet:?xt=urn:btih:...` link, the metadata has to be fetched from
+/// peers before the torrent can start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// display name
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+        for (key, value) in parameters(uri)? {
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or_else(|| anyhow!("magnet link has no btih info hash"))?,
+            name,
+            trackers,
+        })
+    }
+}
+
+/// Parsed `magnet:?xs=urn:btpk:...` link to a torrent its publisher can
+/// update (BEP 46). The info hash of the current version is the `ih` of the
+/// mutable DHT item signed by the public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableMagnet {
+    pub public_key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl MutableMagnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let mut public_key = None;
+        let mut salt = vec![];
+        let mut name = None;
+        let mut trackers = vec![];
+        for (key, value) in parameters(uri)? {
+            match key {
+                "xs" => {
+                    if let Some(key) = value.strip_prefix("urn:btpk:") {
+                        public_key = Some(
+                            hex_decode(key)?
+                                .try_into()
+                                .map_err(|_| anyhow!("invalid public key {}", key))?,
+                        );
+                    }
+                }
+                "s" => salt = hex_decode(&value)?,
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            public_key: public_key.ok_or_else(|| anyhow!("magnet link has no btpk public key"))?,
+            salt,
+            name,
+            trackers,
+        })
+    }
+
+    /// where the item pointing to the current version is stored
+    pub fn target(&self) -> [u8; 20] {
+        mutable_target(&self.public_key, &self.salt)
+    }
+
+    /// link to one version of the torrent, to fetch its metadata
+    pub fn magnet(&self, info_hash: [u8; 20]) -> Magnet {
+        Magnet {
+            info_hash,
+            name: self.name.clone(),
+            trackers: self.trackers.clone(),
+        }
+    }
+}
+
+/// where the DHT stores the mutable items signed by `key` with `salt`
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> [u8; 20] {
+    Sha1::new()
+        .chain_update(key)
+        .chain_update(salt)
+        .finalize()
+        .into()
+}
+
+/// decoded key value pairs of the link
+fn parameters(uri: &str) -> Result<Vec<(&str, String)>> {
+    let query = uri
+        .strip_prefix("magnet:?")
+        .ok_or_else(|| anyhow!("not a magnet link"))?;
+    query
+        .split('&')
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((key, percent_decode(value)?))
+        })
+        .collect()
+}
+
+/// 40 hex characters or 32 base32 characters
+fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex_decode(hash)?,
+        32 => base32_decode(hash)?,
+        _ => bail!("invalid info hash {}", hash),
+    };
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&bytes);
+    Ok(info_hash)
+}
+
+fn hex_decode(data: &str) -> Result<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        bail!("odd length hex {}", data);
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|index| {
+            data.get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow!("invalid hex {}", data))
+        })
+        .collect()
+}
+
+fn base32_decode(data: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for char in data.chars() {
+        let value = match char.to_ascii_uppercase() {
+            char @ 'A'..='Z' => char as u64 - 'A' as u64,
+            char @ '2'..='7' => char as u64 - '2' as u64 + 26,
+            _ => bail!("invalid base32 character {:?}", char),
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let hex = value
+                    .get(index + 1..index + 3)
+                    .ok_or_else(|| anyhow!("truncated percent encoding"))?;
+                decoded.push(u8::from_str_radix(hex, 16)?);
+                index += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::to_hex;
+
+    #[test]
+    fn parse() -> Result<()> {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:8dc3b8a5ac6d8002df36541fda949e7109b7397c&dn=file1.txt&tr=http%3A%2F%2Ftracker.example%2Fannounce")?;
+
+        assert_eq!(
+            to_hex(&magnet.info_hash),
+            "8dc3b8a5ac6d8002df36541fda949e7109b7397c"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("file1.txt"));
+        assert_eq!(magnet.trackers, ["http://tracker.example/announce"]);
+
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:RXB3RJNMNWAAFXZWKQP5VFE6OEE3OOL4")?;
+        assert_eq!(base32.info_hash, magnet.info_hash);
+        assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_mutable() -> Result<()> {
+        let key = "8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e";
+        let magnet = MutableMagnet::parse(&format!(
+            "magnet:?xs=urn:btpk:{}&s=666f6f626172&dn=name",
+            key
+        ))?;
+
+        assert_eq!(to_hex(&magnet.public_key), key);
+        assert_eq!(magnet.salt, b"foobar");
+        assert_eq!(
+            magnet.target(),
+            mutable_target(&magnet.public_key, b"foobar")
+        );
+        assert_eq!(magnet.magnet([1; 20]).name.as_deref(), Some("name"));
+        assert!(MutableMagnet::parse("magnet:?xs=urn:btpk:8543").is_err());
+        assert!(
+            MutableMagnet::parse("magnet:?xt=urn:btih:RXB3RJNMNWAAFXZWKQP5VFE6OEE3OOL4").is_err()
+        );
+        Ok(())
+    }
+}
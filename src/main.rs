@@ -1,12 +1,5 @@
-use anyhow::Result;
+mod cli;
 
-mod bencode;
-
-fn main() -> Result<()> {
-    let file = std::fs::read("file1.txt.torrent")?;
-    let mut parser = bencode::Parser::new(file);
-    let data = parser.parse()?;
-    println!("{:#?}", data);
-
-    Ok(())
+fn main() {
+    std::process::exit(cli::run(std::env::args().skip(1)))
 }
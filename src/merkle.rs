@@ -0,0 +1,175 @@
+use sha2::{Digest, Sha256};
+
+/// Hashes of v2 torrents are merkle trees over 16 KiB blocks
+pub const LEAF_SIZE: u64 = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_block(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// root of a subtree of the given height where every leaf is zero
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold([0; 32], |hash, _| hash_pair(&hash, &hash))
+}
+
+/// root over `leaves`, padded with zero leaves up to `leaf_count` which must
+/// be a power of two
+pub fn root(leaves: &[Hash], leaf_count: usize) -> Hash {
+    debug_assert!(leaf_count.is_power_of_two() && leaves.len() <= leaf_count);
+    let mut layer = leaves.to_vec();
+    let mut width = leaf_count;
+    let mut height = 0;
+    while width > 1 {
+        let pad = pad_hash(height);
+        layer = (0..width / 2)
+            .map(|index| {
+                let left = layer.get(2 * index).unwrap_or(&pad);
+                let right = layer.get(2 * index + 1).unwrap_or(&pad);
+                hash_pair(left, right)
+            })
+            .take(layer.len().div_ceil(2).max(1))
+            .collect();
+        width /= 2;
+        height += 1;
+    }
+    layer.first().copied().unwrap_or_else(|| pad_hash(height))
+}
+
+/// hashes of every 16 KiB block of `data`, the last block can be shorter
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(LEAF_SIZE as usize).map(hash_block).collect()
+}
+
+/// number of leaves under one piece, or under the whole file when it fits in a
+/// single piece
+pub fn piece_leaves(piece_length: u64, file_length: u64) -> usize {
+    if file_length <= piece_length {
+        (file_length.div_ceil(LEAF_SIZE) as usize)
+            .max(1)
+            .next_power_of_two()
+    } else {
+        (piece_length / LEAF_SIZE) as usize
+    }
+}
+
+/// pieces root of a file from its piece layer, padded up to a power of two
+/// with the root of an all zero piece
+pub fn root_from_piece_layer(layer: &[Hash], piece_length: u64) -> Hash {
+    let piece_height = (piece_length / LEAF_SIZE).trailing_zeros();
+    let width = layer.len().max(1).next_power_of_two();
+    let pad = pad_hash(piece_height);
+    let mut layer = layer.to_vec();
+    layer.resize(width, pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+/// the layers of the tree over `layer`, from it up to the root. The layer is
+/// padded up to a power of two with the root of `height` layers of zero
+/// leaves.
+pub fn tree(layer: &[Hash], height: u32) -> Vec<Vec<Hash>> {
+    let mut base = layer.to_vec();
+    base.resize(layer.len().max(1).next_power_of_two(), pad_hash(height));
+    let mut layers = vec![base];
+    while let Some(top) = layers.last().filter(|top| top.len() > 1) {
+        let parents = top
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        layers.push(parents);
+    }
+    layers
+}
+
+/// `length` hashes of `layer` of the tree starting at `index`, followed by
+/// the uncle hashes of up to `proof_layers` layers above the subtree they
+/// form. None when they are not a whole subtree of the tree.
+pub fn hashes_with_proof(
+    tree: &[Vec<Hash>],
+    layer: usize,
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    if !length.is_power_of_two() || !index.is_multiple_of(length) {
+        return None;
+    }
+    let mut hashes = tree.get(layer)?.get(index..index + length)?.to_vec();
+    let mut position = index / length;
+    let subtrees = tree[layer + length.trailing_zeros() as usize..]
+        .iter()
+        .take_while(|subtrees| subtrees.len() > 1)
+        .take(proof_layers);
+    for subtrees in subtrees {
+        hashes.push(subtrees[position ^ 1]);
+        position /= 2;
+    }
+    Some(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_root() {
+        let a = hash_block(b"a");
+        let b = hash_block(b"b");
+        let zero = [0; 32];
+
+        assert_eq!(root(&[a, b], 2), hash_pair(&a, &b));
+        assert_eq!(
+            root(&[a], 4),
+            hash_pair(&hash_pair(&a, &zero), &pad_hash(1))
+        );
+        assert_eq!(root(&[a], 1), a);
+    }
+
+    #[test]
+    fn piece_layer_root_matches_full_tree() {
+        // 3 pieces of 2 blocks each, the tree is padded to 4 pieces
+        let leaves: Vec<_> = (0..6u8).map(|i| hash_block(&[i])).collect();
+        let layer: Vec<_> = leaves.chunks(2).map(|pair| root(pair, 2)).collect();
+
+        assert_eq!(
+            root_from_piece_layer(&layer, 2 * LEAF_SIZE),
+            root(&leaves, 8)
+        );
+    }
+
+    #[test]
+    fn proves_a_subtree() {
+        let layer: Vec<_> = (0..3u8).map(|i| hash_block(&[i])).collect();
+        let tree = tree(&layer, 1);
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree[0][3], pad_hash(1));
+        assert_eq!(tree[2][0], root_from_piece_layer(&layer, 2 * LEAF_SIZE));
+
+        // the whole layer needs no proof
+        assert_eq!(hashes_with_proof(&tree, 0, 0, 4, 1), Some(tree[0].clone()));
+        // the second pair is proven with the root of the first one
+        let hashes = hashes_with_proof(&tree, 0, 2, 2, 5).unwrap();
+        assert_eq!(hashes, vec![tree[0][2], tree[0][3], tree[1][0]]);
+        assert_eq!(
+            hash_pair(&hashes[2], &hash_pair(&hashes[0], &hashes[1])),
+            tree[2][0]
+        );
+        assert_eq!(hashes_with_proof(&tree, 0, 1, 2, 0), None);
+        assert_eq!(hashes_with_proof(&tree, 0, 0, 8, 0), None);
+        assert_eq!(hashes_with_proof(&tree, 3, 0, 1, 0), None);
+    }
+}
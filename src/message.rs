@@ -0,0 +1,373 @@
+use anyhow::{bail, Result};
+use std::convert::TryInto;
+
+/// Peer wire protocol message, the 4 byte length prefix is handled by
+/// `encode` and `decode`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        piece: u32,
+        offset: u32,
+        length: u32,
+    },
+    Piece {
+        piece: u32,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    Cancel {
+        piece: u32,
+        offset: u32,
+        length: u32,
+    },
+    Port(u16),
+    /// BEP 10 extension message, id 0 is the extension handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// BEP 52 hash transfer, asks for a range of a file's merkle tree
+    HashRequest(HashRequest),
+    /// the requested hashes followed by the proof layers
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(HashRequest),
+}
+
+/// peers reject hash requests for more hashes than this
+pub const MAX_HASHES: u32 = 512;
+
+/// `length` hashes of layer `base_layer` starting at `index`, the leaves are
+/// layer 0. `proof_layers` uncle hashes are sent along to verify them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    fn encode(&self, body: &mut Vec<u8>) {
+        body.extend_from_slice(&self.pieces_root);
+        for value in [self.base_layer, self.index, self.length, self.proof_layers] {
+            body.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        if payload.len() < 48 {
+            bail!("hash request is too short");
+        }
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                payload[offset],
+                payload[offset + 1],
+                payload[offset + 2],
+                payload[offset + 3],
+            ])
+        };
+        let mut pieces_root = [0; 32];
+        pieces_root.copy_from_slice(&payload[..32]);
+        Ok(Self {
+            pieces_root,
+            base_layer: u32_at(32),
+            index: u32_at(36),
+            length: u32_at(40),
+            proof_layers: u32_at(44),
+        })
+    }
+}
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LENGTH: usize = 68;
+
+/// First message of a connection, before the length prefixed ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    /// extension bits
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// advertises the extension protocol (BEP 10)
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[5] |= 0x10;
+        Self {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn encode(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut data = [0; HANDSHAKE_LENGTH];
+        data[0] = PROTOCOL.len() as u8;
+        data[1..20].copy_from_slice(PROTOCOL);
+        data[20..28].copy_from_slice(&self.reserved);
+        data[28..48].copy_from_slice(&self.info_hash);
+        data[48..].copy_from_slice(&self.peer_id);
+        data
+    }
+
+    pub fn decode(data: &[u8; HANDSHAKE_LENGTH]) -> Result<Self> {
+        if data[0] as usize != PROTOCOL.len() || &data[1..20] != PROTOCOL {
+            bail!("not a bittorrent handshake");
+        }
+        Ok(Self {
+            reserved: data[20..28].try_into().unwrap(),
+            info_hash: data[28..48].try_into().unwrap(),
+            peer_id: data[48..].try_into().unwrap(),
+        })
+    }
+}
+
+/// length prefix, id and position of a piece message whose `length` bytes of
+/// data are written separately
+pub fn piece_header(piece: u32, offset: u32, length: u32) -> [u8; 13] {
+    let mut header = [0; 13];
+    header[..4].copy_from_slice(&(9 + length).to_be_bytes());
+    header[4] = 7;
+    header[5..9].copy_from_slice(&piece.to_be_bytes());
+    header[9..].copy_from_slice(&offset.to_be_bytes());
+    header
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke => body.push(0),
+            Message::Unchoke => body.push(1),
+            Message::Interested => body.push(2),
+            Message::NotInterested => body.push(3),
+            Message::Have(piece) => {
+                body.push(4);
+                body.extend_from_slice(&piece.to_be_bytes());
+            }
+            Message::Bitfield(bytes) => {
+                body.push(5);
+                body.extend_from_slice(bytes);
+            }
+            Message::Request {
+                piece,
+                offset,
+                length,
+            } => {
+                body.push(6);
+                body.extend_from_slice(&piece.to_be_bytes());
+                body.extend_from_slice(&offset.to_be_bytes());
+                body.extend_from_slice(&length.to_be_bytes());
+            }
+            Message::Piece {
+                piece,
+                offset,
+                data,
+            } => {
+                body.push(7);
+                body.extend_from_slice(&piece.to_be_bytes());
+                body.extend_from_slice(&offset.to_be_bytes());
+                body.extend_from_slice(data);
+            }
+            Message::Cancel {
+                piece,
+                offset,
+                length,
+            } => {
+                body.push(8);
+                body.extend_from_slice(&piece.to_be_bytes());
+                body.extend_from_slice(&offset.to_be_bytes());
+                body.extend_from_slice(&length.to_be_bytes());
+            }
+            Message::Port(port) => {
+                body.push(9);
+                body.extend_from_slice(&port.to_be_bytes());
+            }
+            Message::Extended { id, payload } => {
+                body.push(20);
+                body.push(*id);
+                body.extend_from_slice(payload);
+            }
+            Message::HashRequest(request) => {
+                body.push(21);
+                request.encode(&mut body);
+            }
+            Message::Hashes { request, hashes } => {
+                body.push(22);
+                request.encode(&mut body);
+                for hash in hashes {
+                    body.extend_from_slice(hash);
+                }
+            }
+            Message::HashReject(request) => {
+                body.push(23);
+                request.encode(&mut body);
+            }
+        }
+        let mut out = (body.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(&body);
+        out
+    }
+
+    /// decodes the first message of `data`, returns None if more data is
+    /// needed and the number of bytes consumed otherwise
+    pub fn decode(data: &[u8]) -> Result<Option<(Message, usize)>> {
+        if data.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() < 4 + length {
+            return Ok(None);
+        }
+        let body = &data[4..4 + length];
+        let message = match body.split_first() {
+            None => Message::KeepAlive,
+            Some((&id, payload)) => Self::decode_body(id, payload)?,
+        };
+        Ok(Some((message, 4 + length)))
+    }
+
+    fn decode_body(id: u8, payload: &[u8]) -> Result<Message> {
+        let u32_at = |index: usize| -> Result<u32> {
+            match payload.get(index * 4..index * 4 + 4) {
+                Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                None => bail!("message {} is too short", id),
+            }
+        };
+        let message = match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(u32_at(0)?),
+            5 => Message::Bitfield(payload.to_vec()),
+            6 => Message::Request {
+                piece: u32_at(0)?,
+                offset: u32_at(1)?,
+                length: u32_at(2)?,
+            },
+            7 => Message::Piece {
+                piece: u32_at(0)?,
+                offset: u32_at(1)?,
+                data: payload[8..].to_vec(),
+            },
+            8 => Message::Cancel {
+                piece: u32_at(0)?,
+                offset: u32_at(1)?,
+                length: u32_at(2)?,
+            },
+            9 => match payload {
+                [high, low] => Message::Port(u16::from_be_bytes([*high, *low])),
+                _ => bail!("invalid port message"),
+            },
+            20 => match payload.split_first() {
+                Some((&id, payload)) => Message::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                },
+                None => bail!("extended message without id"),
+            },
+            21 => Message::HashRequest(HashRequest::decode(payload)?),
+            22 => {
+                let hashes = &payload[48.min(payload.len())..];
+                if !hashes.len().is_multiple_of(32) {
+                    bail!("hashes message has a partial hash");
+                }
+                Message::Hashes {
+                    request: HashRequest::decode(payload)?,
+                    hashes: hashes
+                        .chunks_exact(32)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                }
+            }
+            23 => Message::HashReject(HashRequest::decode(payload)?),
+            _ => bail!("unknown message id {}", id),
+        };
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshakes() -> Result<()> {
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        let data = handshake.encode();
+        assert_eq!(&data[..20], b"\x13BitTorrent protocol");
+        let decoded = Handshake::decode(&data)?;
+        assert_eq!(decoded, handshake);
+        assert!(decoded.supports_extensions());
+        assert!(Handshake::decode(&[0; HANDSHAKE_LENGTH]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let messages = vec![
+            Message::KeepAlive,
+            Message::Have(7),
+            Message::Request {
+                piece: 1,
+                offset: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                piece: 1,
+                offset: 0,
+                data: vec![1, 2, 3],
+            },
+            Message::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+            Message::Hashes {
+                request: HashRequest {
+                    pieces_root: [3; 32],
+                    base_layer: 0,
+                    index: 4,
+                    length: 2,
+                    proof_layers: 0,
+                },
+                hashes: vec![[1; 32], [2; 32]],
+            },
+        ];
+        let mut data: Vec<u8> = messages.iter().flat_map(Message::encode).collect();
+
+        for expected in messages {
+            let (message, consumed) = Message::decode(&data)?.unwrap();
+            assert_eq!(message, expected);
+            data.drain(..consumed);
+        }
+        assert!(data.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn partial_and_invalid() {
+        let encoded = Message::Have(1).encode();
+        assert!(Message::decode(&encoded[..6]).unwrap().is_none());
+        assert!(Message::decode(&[0, 0, 0, 1, 99]).is_err());
+        assert!(Message::decode(&[0, 0, 0, 2, 4, 0]).is_err());
+    }
+}
@@ -0,0 +1,604 @@
+use crate::bencode::{Bencode, Parser};
+use crate::ip_filter::IpFilter;
+use crate::listen::IpFamilies;
+use crate::magnet::Magnet;
+use crate::message::{Handshake, Message, HANDSHAKE_LENGTH};
+use crate::metainfo::{to_hex, Metainfo};
+use crate::tracker::{self, AnnounceEvent, AnnounceRequest};
+use anyhow::{anyhow, bail, Result};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// metadata is exchanged in blocks of this size, the last one is shorter
+pub const METADATA_BLOCK_SIZE: usize = 16 * 1024;
+/// id peers use to send us ut_metadata messages, from our extension handshake
+pub const UT_METADATA_ID: u8 = 1;
+/// peers lying about the metadata size shouldn't make us allocate gigabytes
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// peers a `MetadataTask` talks to at once
+const MAX_CONNECTIONS: usize = 8;
+/// connecting to and hearing back from a peer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// BEP 9 message, the payload of an extended message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(usize),
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject(usize),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request(piece) => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject(piece) => (2, piece),
+        };
+        let mut dict = HashMap::new();
+        dict.insert("msg_type".into(), msg_type.into());
+        dict.insert("piece".into(), (*piece as i64).into());
+        if let MetadataMessage::Data { total_size, .. } = self {
+            dict.insert("total_size".into(), (*total_size as i64).into());
+        }
+        let mut payload = Bencode::Dictionary(dict).encode();
+        if let MetadataMessage::Data { data, .. } = self {
+            payload.extend_from_slice(data);
+        }
+        payload
+    }
+
+    /// data messages carry the metadata block right after the dictionary
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let mut parser = Parser::new(payload.to_vec());
+        let dict = parser.parse()?;
+        let integer = |key: &str| match dict.get(key).and_then(Bencode::as_integer) {
+            Some(value) if value >= 0 => Ok(value as usize),
+            _ => bail!("invalid or missing {} in metadata message", key),
+        };
+        let piece = integer("piece")?;
+        Ok(match integer("msg_type")? {
+            0 => MetadataMessage::Request(piece),
+            1 => MetadataMessage::Data {
+                piece,
+                total_size: integer("total_size")?,
+                data: payload[parser.position()..].to_vec(),
+            },
+            2 => MetadataMessage::Reject(piece),
+            msg_type => bail!("unknown metadata message type {}", msg_type),
+        })
+    }
+}
+
+/// payload of our extension handshake, advertises ut_metadata
+pub fn extension_handshake() -> Vec<u8> {
+    let mut extensions = HashMap::new();
+    extensions.insert("ut_metadata".into(), (UT_METADATA_ID as i64).into());
+    let mut dict = HashMap::new();
+    dict.insert("m".into(), Bencode::Dictionary(extensions));
+    Bencode::Dictionary(dict).encode()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataEvent {
+    /// the metadata matched the info hash, the torrent can be started with it
+    Received(Box<Metainfo>),
+    /// metadata only mode wrote the .torrent file and stopped
+    Saved(PathBuf),
+}
+
+/// Fetches the info dictionary of a magnet link from peers supporting the
+/// ut_metadata extension
+pub struct MetadataDownload {
+    magnet: Magnet,
+    /// ut_metadata id of every peer supporting the extension
+    peers: HashMap<SocketAddr, u8>,
+    size: Option<usize>,
+    blocks: Vec<Option<Vec<u8>>>,
+    requested: HashMap<usize, SocketAddr>,
+    /// writes the .torrent to this directory and stops instead of handing the
+    /// metadata over for the payload download
+    metadata_only: Option<PathBuf>,
+    stopped: bool,
+    events: VecDeque<MetadataEvent>,
+}
+
+impl MetadataDownload {
+    pub fn new(magnet: Magnet) -> Self {
+        Self {
+            magnet,
+            peers: HashMap::new(),
+            size: None,
+            blocks: vec![],
+            requested: HashMap::new(),
+            metadata_only: None,
+            stopped: false,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn magnet(&self) -> &Magnet {
+        &self.magnet
+    }
+
+    pub fn set_metadata_only(&mut self, torrent_dir: Option<PathBuf>) {
+        self.metadata_only = torrent_dir;
+    }
+
+    /// nothing is left to do, the peers can be disconnected
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// reads the ut_metadata id and metadata size from the peer's extension handshake
+    pub fn peer_handshake(&mut self, addr: SocketAddr, payload: &[u8]) -> Result<()> {
+        let handshake = Parser::new(payload.to_vec()).parse()?;
+        let id = handshake
+            .get("m")
+            .and_then(|extensions| extensions.get("ut_metadata"))
+            .and_then(Bencode::as_integer)
+            .filter(|&id| id > 0 && id <= u8::MAX as i64);
+        let size = handshake
+            .get("metadata_size")
+            .and_then(Bencode::as_integer)
+            .filter(|&size| size > 0 && size as usize <= MAX_METADATA_SIZE);
+        let (id, size) = match (id, size) {
+            (Some(id), Some(size)) => (id as u8, size as usize),
+            _ => return Ok(()),
+        };
+        match self.size {
+            None => {
+                self.size = Some(size);
+                self.blocks = vec![None; size.div_ceil(METADATA_BLOCK_SIZE)];
+            }
+            Some(known) if known != size => {
+                bail!("peer reports metadata size {} instead of {}", size, known)
+            }
+            Some(_) => {}
+        }
+        self.peers.insert(addr, id);
+        Ok(())
+    }
+
+    /// blocks requested from the peer are requested from someone else
+    pub fn peer_disconnected(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+        self.requested.retain(|_, peer| *peer != addr);
+    }
+
+    /// next block request to send to the peer, if any
+    pub fn request(&mut self, addr: SocketAddr) -> Option<Message> {
+        if self.stopped {
+            return None;
+        }
+        let id = *self.peers.get(&addr)?;
+        let block = (0..self.blocks.len())
+            .find(|block| self.blocks[*block].is_none() && !self.requested.contains_key(block))?;
+        self.requested.insert(block, addr);
+        Some(Message::Extended {
+            id,
+            payload: MetadataMessage::Request(block).encode(),
+        })
+    }
+
+    /// handles a ut_metadata message, requests are rejected since we don't
+    /// have the metadata to share yet
+    pub fn message_received(
+        &mut self,
+        addr: SocketAddr,
+        payload: &[u8],
+    ) -> Result<Option<Message>> {
+        match MetadataMessage::decode(payload)? {
+            MetadataMessage::Request(piece) => {
+                let id = self
+                    .peers
+                    .get(&addr)
+                    .ok_or_else(|| anyhow!("metadata request from a peer without ut_metadata"))?;
+                Ok(Some(Message::Extended {
+                    id: *id,
+                    payload: MetadataMessage::Reject(piece).encode(),
+                }))
+            }
+            MetadataMessage::Reject(piece) => {
+                self.requested.remove(&piece);
+                Ok(None)
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                if self.requested.get(&piece) != Some(&addr) {
+                    bail!("received unrequested metadata block {}", piece);
+                }
+                self.requested.remove(&piece);
+                let size = self.size.unwrap_or_default();
+                let expected = METADATA_BLOCK_SIZE.min(size - piece * METADATA_BLOCK_SIZE);
+                if total_size != size || data.len() != expected {
+                    bail!("received metadata block {} of the wrong size", piece);
+                }
+                self.blocks[piece] = Some(data);
+                if self.blocks.iter().all(Option::is_some) {
+                    self.metadata_complete()?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn metadata_complete(&mut self) -> Result<()> {
+        let info: Vec<u8> = self
+            .blocks
+            .iter_mut()
+            .flat_map(|block| block.take().unwrap())
+            .collect();
+        if Sha1::digest(&info).as_slice() != self.magnet.info_hash {
+            bail!("metadata doesn't match the info hash");
+        }
+        let torrent = torrent_file(&info, &self.magnet.trackers);
+        let metainfo = Metainfo::from_bytes(torrent.clone())?;
+        match &self.metadata_only {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                let path = dir.join(format!("{}.torrent", to_hex(&self.magnet.info_hash)));
+                std::fs::write(&path, torrent)?;
+                self.stopped = true;
+                self.events.push_back(MetadataEvent::Saved(path));
+            }
+            None => {
+                self.stopped = true;
+                self.events
+                    .push_back(MetadataEvent::Received(Box::new(metainfo)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn poll_event(&mut self) -> Option<MetadataEvent> {
+        self.events.pop_front()
+    }
+}
+
+/// Fetches the metadata of a magnet link on its own threads. The trackers of
+/// the link are asked for peers once, more can be handed to `add_peers`, and
+/// a few peers at a time are connected to and asked for the metadata blocks
+/// until it is complete.
+pub struct MetadataTask {
+    info_hash: [u8; 20],
+    download: Arc<Mutex<MetadataDownload>>,
+    candidates: Arc<Mutex<Candidates>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Candidates {
+    queue: VecDeque<SocketAddr>,
+    /// connected to already, peers aren't tried twice
+    tried: HashSet<SocketAddr>,
+    /// blocked peers are skipped when their turn comes
+    ip_filter: Option<Arc<RwLock<IpFilter>>>,
+}
+
+impl MetadataTask {
+    /// `port` is where we accept peer connections, for the trackers
+    pub fn start(download: MetadataDownload, peer_id: [u8; 20], port: u16) -> Self {
+        let info_hash = download.magnet().info_hash;
+        let trackers = download.magnet().trackers.clone();
+        let download = Arc::new(Mutex::new(download));
+        let candidates = Arc::new(Mutex::new(Candidates::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let fetch = Fetch {
+                info_hash,
+                peer_id,
+                download: Arc::clone(&download),
+                stop: Arc::clone(&stop),
+            };
+            let candidates = Arc::clone(&candidates);
+            thread::spawn(move || fetch.run(&trackers, port, &candidates))
+        };
+        Self {
+            info_hash,
+            download,
+            candidates,
+            stop,
+            worker: Some(worker),
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn download(&self) -> &Arc<Mutex<MetadataDownload>> {
+        &self.download
+    }
+
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        let mut candidates = self.candidates.lock().unwrap();
+        for peer in peers {
+            if !candidates.tried.contains(&peer) && !candidates.queue.contains(&peer) {
+                candidates.queue.push_back(peer);
+            }
+        }
+    }
+
+    pub fn poll_event(&self) -> Option<MetadataEvent> {
+        self.download.lock().unwrap().poll_event()
+    }
+
+    /// the peers it blocks aren't connected to
+    pub fn set_ip_filter(&self, filter: Arc<RwLock<IpFilter>>) {
+        self.candidates.lock().unwrap().ip_filter = Some(filter);
+    }
+}
+
+impl Drop for MetadataTask {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// What the threads of a `MetadataTask` share
+#[derive(Clone)]
+struct Fetch {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    download: Arc<Mutex<MetadataDownload>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Fetch {
+    fn is_done(&self) -> bool {
+        self.stop.load(Ordering::Relaxed) || self.download.lock().unwrap().is_stopped()
+    }
+
+    fn run(self, trackers: &[String], port: u16, candidates: &Mutex<Candidates>) {
+        let request = AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            // the size isn't known without the metadata
+            left: 1,
+            event: AnnounceEvent::Started,
+            num_want: 50,
+            families: IpFamilies::Both,
+            ipv4: None,
+            ipv6: None,
+        };
+        for url in trackers {
+            if self.is_done() {
+                return;
+            }
+            if let Ok(response) = tracker::announce(url, &request) {
+                let mut candidates = candidates.lock().unwrap();
+                candidates.queue.extend(response.peers);
+            }
+        }
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        while !self.is_done() {
+            while connections.load(Ordering::Relaxed) < MAX_CONNECTIONS {
+                let peer = {
+                    let mut candidates = candidates.lock().unwrap();
+                    let peer = match candidates.queue.pop_front() {
+                        Some(peer) => peer,
+                        None => break,
+                    };
+                    if !candidates.tried.insert(peer) {
+                        continue;
+                    }
+                    let blocked = candidates
+                        .ip_filter
+                        .as_ref()
+                        .is_some_and(|filter| filter.read().unwrap().blocks(peer.ip()));
+                    if blocked {
+                        continue;
+                    }
+                    peer
+                };
+                connections.fetch_add(1, Ordering::Relaxed);
+                let fetch = self.clone();
+                let connections = Arc::clone(&connections);
+                thread::spawn(move || {
+                    let span = tracing::debug_span!(
+                        "metadata_peer",
+                        info_hash = %to_hex(&fetch.info_hash),
+                        %peer,
+                    );
+                    let _entered = span.enter();
+                    match fetch.ask_peer(peer) {
+                        Ok(()) => tracing::debug!("done with the peer"),
+                        Err(error) => {
+                            tracing::debug!(error = format_args!("{:#}", error), "peer failed")
+                        }
+                    }
+                    fetch.download.lock().unwrap().peer_disconnected(peer);
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// asks the peer for metadata blocks until the metadata is complete
+    fn ask_peer(&self, peer: SocketAddr) -> Result<()> {
+        let mut stream = TcpStream::connect_timeout(&peer, TIMEOUT)?;
+        tracing::debug!("connected");
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        // short so the connection notices when the metadata is complete
+        stream.set_read_timeout(Some(Duration::from_millis(200)))?;
+        stream.write_all(&Handshake::new(self.info_hash, self.peer_id).encode())?;
+        let mut data = vec![];
+        let mut idle = Instant::now();
+        let mut buffer = [0; 16 * 1024];
+        let mut handshaken = false;
+        while !self.is_done() {
+            if idle.elapsed() > TIMEOUT {
+                bail!("peer stopped answering");
+            }
+            match stream.read(&mut buffer) {
+                Ok(0) => bail!("peer disconnected"),
+                Ok(length) => {
+                    data.extend_from_slice(&buffer[..length]);
+                    idle = Instant::now();
+                }
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(error) => return Err(error.into()),
+            }
+            if !handshaken {
+                if data.len() < HANDSHAKE_LENGTH {
+                    continue;
+                }
+                let theirs = Handshake::decode(data[..HANDSHAKE_LENGTH].try_into().unwrap())?;
+                if theirs.info_hash != self.info_hash || !theirs.supports_extensions() {
+                    bail!("peer can't send the metadata");
+                }
+                data.drain(..HANDSHAKE_LENGTH);
+                handshaken = true;
+                let ours = Message::Extended {
+                    id: 0,
+                    payload: extension_handshake(),
+                };
+                stream.write_all(&ours.encode())?;
+            }
+            while let Some((message, length)) = Message::decode(&data)? {
+                data.drain(..length);
+                let reply = match message {
+                    Message::Extended { id: 0, payload } => {
+                        let mut download = self.download.lock().unwrap();
+                        download.peer_handshake(peer, &payload)?;
+                        download.request(peer)
+                    }
+                    Message::Extended {
+                        id: UT_METADATA_ID,
+                        payload,
+                    } => {
+                        let mut download = self.download.lock().unwrap();
+                        match download.message_received(peer, &payload)? {
+                            Some(reply) => Some(reply),
+                            None => download.request(peer),
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    stream.write_all(&reply.encode())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the info dictionary is copied as is, re-encoding it could change the info hash
+fn torrent_file(info: &[u8], trackers: &[String]) -> Vec<u8> {
+    let mut torrent = b"d".to_vec();
+    if let Some(tracker) = trackers.first() {
+        torrent.extend_from_slice(&Bencode::from("announce").encode());
+        torrent.extend_from_slice(&Bencode::from(tracker.as_str()).encode());
+    }
+    if trackers.len() > 1 {
+        let tiers = trackers
+            .iter()
+            .map(|tracker| Bencode::List(vec![tracker.as_str().into()]))
+            .collect();
+        torrent.extend_from_slice(&Bencode::from("announce-list").encode());
+        torrent.extend_from_slice(&Bencode::List(tiers).encode());
+    }
+    torrent.extend_from_slice(&Bencode::from("info").encode());
+    torrent.extend_from_slice(info);
+    torrent.push(b'e');
+    torrent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(id: i64, size: usize) -> Vec<u8> {
+        let mut extensions = HashMap::new();
+        extensions.insert("ut_metadata".into(), id.into());
+        let mut dict = HashMap::new();
+        dict.insert("m".into(), Bencode::Dictionary(extensions));
+        dict.insert("metadata_size".into(), (size as i64).into());
+        Bencode::Dictionary(dict).encode()
+    }
+
+    #[test]
+    fn metadata_only() -> Result<()> {
+        let torrent = Parser::new(std::fs::read("file1.txt.torrent")?).parse()?;
+        let info = torrent.get("info").unwrap().encode();
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:8dc3b8a5ac6d8002df36541fda949e7109b7397c&tr=udp%3A%2F%2Fa&tr=udp%3A%2F%2Fb"
+        )?;
+        let dir = std::env::temp_dir().join("torrent_rs_metadata_only");
+        let mut download = MetadataDownload::new(magnet);
+        download.set_metadata_only(Some(dir.clone()));
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+
+        download.peer_handshake(addr, &handshake(3, info.len()))?;
+        let request = download.request(addr).unwrap();
+        assert_eq!(
+            request,
+            Message::Extended {
+                id: 3,
+                payload: MetadataMessage::Request(0).encode(),
+            }
+        );
+        assert_eq!(download.request(addr), None);
+
+        // corrupt metadata is thrown away and requested again
+        let data = |data: Vec<u8>| MetadataMessage::Data {
+            piece: 0,
+            total_size: info.len(),
+            data,
+        };
+        let mut corrupt = info.clone();
+        corrupt[10] ^= 1;
+        assert!(download
+            .message_received(addr, &data(corrupt).encode())
+            .is_err());
+        assert_eq!(download.request(addr), Some(request));
+        download.message_received(addr, &data(info.clone()).encode())?;
+
+        let path = match download.poll_event() {
+            Some(MetadataEvent::Saved(path)) => path,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert!(download.is_stopped());
+        let metainfo = Metainfo::from_bytes(std::fs::read(&path)?)?;
+        assert_eq!(
+            metainfo.info_hash_hex(),
+            "8dc3b8a5ac6d8002df36541fda949e7109b7397c"
+        );
+        assert_eq!(metainfo.announce.as_deref(), Some("udp://a"));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,926 @@
+use crate::bencode::{Bencode, Parser};
+use crate::file_map::FileMap;
+use crate::json::Json;
+use crate::merkle::{self, Hash, LEAF_SIZE};
+use anyhow::{anyhow, bail, Result};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::path::{Component, Path, PathBuf};
+
+/// larger pieces are refused, they are held whole in memory while they
+/// download and their blocks are addressed with 32 bit offsets
+pub const MAX_PIECE_LENGTH: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metainfo {
+    pub announce: Option<String>,
+    /// tiers of trackers (BEP 12), tried in order, replacing `announce` for
+    /// clients that support it
+    pub announce_list: Vec<Vec<String>>,
+    /// urls of web seeds (BEP 19), serving the files over http
+    pub url_list: Vec<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// seconds since the unix epoch
+    pub creation_date: Option<u64>,
+    pub info: Info,
+    /// sha1 of the bencoded info dictionary
+    pub info_hash: [u8; 20],
+    /// sha256 of the bencoded info dictionary, for v2 and hybrid torrents
+    pub info_hash_v2: Option<Hash>,
+    /// hashes of every piece of the files larger than a piece, keyed by pieces
+    /// root. Missing layers can be requested from peers.
+    pub piece_layers: HashMap<Hash, Vec<Hash>>,
+    /// the bencoded info dictionary the hashes are computed over
+    pub info_bytes: Vec<u8>,
+}
+
+/// What the data of a piece is checked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHash {
+    V1([u8; 20]),
+    /// merkle root of the piece, over `leaves` blocks padded with zero hashes,
+    /// only the first `file_bytes` of the piece belong to the file
+    V2 {
+        root: Hash,
+        leaves: usize,
+        file_bytes: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Info {
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; 20]>,
+    pub files: Vec<FileInfo>,
+    /// multi-file torrents store their files under a directory named after the torrent
+    pub multi_file: bool,
+    /// 1 for v1 torrents, 2 for v2 and hybrid torrents
+    pub meta_version: u8,
+    /// peers only come from the trackers, not the DHT (BEP 27)
+    pub private: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    /// path relative to the torrent root
+    pub path: PathBuf,
+    pub length: u64,
+    /// padding files align the next file to a piece boundary, their content is all zeros
+    pub padding: bool,
+    /// merkle root of the file, for v2 torrents
+    pub pieces_root: Option<Hash>,
+}
+
+impl Metainfo {
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        let bencode = Parser::new(data).parse()?;
+        Self::from_bencode(&bencode)
+    }
+
+    pub fn from_bencode(bencode: &Bencode) -> Result<Self> {
+        let announce = bencode
+            .get("announce")
+            .and_then(Bencode::as_str)
+            .map(String::from);
+        let announce_list = bencode
+            .get("announce-list")
+            .and_then(Bencode::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|tier| {
+                let tier: Vec<_> = tier
+                    .as_list()?
+                    .iter()
+                    .filter_map(|url| url.as_str().map(String::from))
+                    .collect();
+                (!tier.is_empty()).then_some(tier)
+            })
+            .collect();
+        // a single url or a list of them
+        let url_list = match bencode.get("url-list") {
+            Some(Bencode::List(urls)) => urls
+                .iter()
+                .filter_map(|url| url.as_str().map(String::from))
+                .collect(),
+            Some(url) => url.as_str().map(String::from).into_iter().collect(),
+            None => vec![],
+        };
+        let text = |key| bencode.get(key).and_then(Bencode::as_str).map(String::from);
+        let creation_date = bencode
+            .get("creation date")
+            .and_then(Bencode::as_integer)
+            .and_then(|date| u64::try_from(date).ok());
+        let info = bencode
+            .get("info")
+            .ok_or_else(|| anyhow!("missing info dictionary"))?;
+
+        let encoded = info.encode();
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&Sha1::digest(&encoded));
+        let info = Info::from_bencode(info)?;
+        let info_hash_v2 = (info.meta_version == 2).then(|| Sha256::digest(&encoded).into());
+
+        let mut piece_layers = HashMap::new();
+        if let Some(layers) = bencode.get("piece layers") {
+            let layers = layers
+                .as_dict()
+                .ok_or_else(|| anyhow!("piece layers is not a dictionary"))?;
+            for (root, layer) in layers {
+                let root = to_hash(root)?;
+                let layer = layer
+                    .as_bytes()
+                    .filter(|layer| layer.len() % 32 == 0)
+                    .ok_or_else(|| anyhow!("invalid piece layer"))?
+                    .chunks_exact(32)
+                    .map(to_hash)
+                    .collect::<Result<Vec<_>>>()?;
+                if merkle::root_from_piece_layer(&layer, info.piece_length) != root {
+                    bail!("piece layer doesn't match its pieces root");
+                }
+                piece_layers.insert(root, layer);
+            }
+        }
+
+        Ok(Self {
+            announce,
+            announce_list,
+            url_list,
+            comment: text("comment"),
+            created_by: text("created by"),
+            creation_date,
+            info,
+            info_hash,
+            info_hash_v2,
+            piece_layers,
+            info_bytes: encoded,
+        })
+    }
+
+    /// the bencoded metainfo, a .torrent file with the same info hash
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut torrent = HashMap::new();
+        if let Some(announce) = &self.announce {
+            torrent.insert(b"announce".to_vec(), announce.as_str().into());
+        }
+        if !self.announce_list.is_empty() {
+            let tiers = self
+                .announce_list
+                .iter()
+                .map(|tier| Bencode::List(tier.iter().map(|url| url.as_str().into()).collect()))
+                .collect();
+            torrent.insert(b"announce-list".to_vec(), Bencode::List(tiers));
+        }
+        if !self.url_list.is_empty() {
+            let urls = self.url_list.iter().map(|url| url.as_str().into());
+            torrent.insert(b"url-list".to_vec(), Bencode::List(urls.collect()));
+        }
+        if let Some(comment) = &self.comment {
+            torrent.insert(b"comment".to_vec(), comment.as_str().into());
+        }
+        if let Some(created_by) = &self.created_by {
+            torrent.insert(b"created by".to_vec(), created_by.as_str().into());
+        }
+        if let Some(date) = self.creation_date {
+            torrent.insert(b"creation date".to_vec(), (date as i64).into());
+        }
+        let info = Parser::new(self.info_bytes.clone()).parse()?;
+        torrent.insert(b"info".to_vec(), info);
+        if !self.piece_layers.is_empty() {
+            let layers = self
+                .piece_layers
+                .iter()
+                .map(|(root, layer)| (root.to_vec(), layer.concat().into()))
+                .collect();
+            torrent.insert(b"piece layers".to_vec(), Bencode::Dictionary(layers));
+        }
+        Ok(Bencode::Dictionary(torrent).encode())
+    }
+
+    /// v2 hashes are used when available, they also allow verifying single blocks
+    pub fn piece_hash(&self, piece: usize) -> Option<PieceHash> {
+        let info = &self.info;
+        let v2 = info.piece_file(piece).and_then(|(file, index)| {
+            let file = &info.files[file];
+            let root = file.pieces_root?;
+            let file_bytes = info
+                .piece_length
+                .min(file.length - index as u64 * info.piece_length);
+            if file.length <= info.piece_length {
+                return Some(PieceHash::V2 {
+                    root,
+                    leaves: merkle::piece_leaves(info.piece_length, file.length),
+                    file_bytes,
+                });
+            }
+            Some(PieceHash::V2 {
+                root: *self.piece_layers.get(&root)?.get(index)?,
+                leaves: (info.piece_length / LEAF_SIZE) as usize,
+                file_bytes,
+            })
+        });
+        v2.or_else(|| info.pieces.get(piece).copied().map(PieceHash::V1))
+    }
+
+    /// every tracker, from the announce list when there is one
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers: Vec<String> = self.announce_list.concat();
+        if let Some(announce) = &self.announce {
+            if !trackers.contains(announce) {
+                trackers.insert(0, announce.clone());
+            }
+        }
+        trackers
+    }
+
+    /// makes `url` the only tracker
+    pub fn set_announce(&mut self, url: &str) {
+        self.announce = Some(url.to_string());
+        self.announce_list.clear();
+    }
+
+    /// adds `url` in a tier of its own after the others, the announce url
+    /// becoming the first tier if there was no announce list
+    pub fn add_tracker(&mut self, url: &str) {
+        if self.trackers().iter().any(|tracker| tracker == url) {
+            return;
+        }
+        match &self.announce {
+            None => self.announce = Some(url.to_string()),
+            Some(announce) => {
+                if self.announce_list.is_empty() {
+                    self.announce_list.push(vec![announce.clone()]);
+                }
+                self.announce_list.push(vec![url.to_string()]);
+            }
+        }
+    }
+
+    /// from the announce list and the announce url, replaced by the first
+    /// tracker left
+    pub fn remove_tracker(&mut self, url: &str) {
+        for tier in &mut self.announce_list {
+            tier.retain(|tracker| tracker != url);
+        }
+        self.announce_list.retain(|tier| !tier.is_empty());
+        if self.announce.as_deref() == Some(url) {
+            self.announce = self.announce_list.first().map(|tier| tier[0].clone());
+        }
+    }
+
+    /// rewrites the info dictionary, which changes the info hashes, keeping
+    /// the keys this crate doesn't know about
+    pub fn edit_info(
+        &mut self,
+        edit: impl FnOnce(&mut HashMap<Vec<u8>, Bencode>) -> Result<()>,
+    ) -> Result<()> {
+        let mut info = match Parser::new(self.info_bytes.clone()).parse()? {
+            Bencode::Dictionary(info) => info,
+            _ => bail!("info is not a dictionary"),
+        };
+        edit(&mut info)?;
+        let mut torrent = HashMap::new();
+        torrent.insert(b"info".to_vec(), Bencode::Dictionary(info));
+        // the piece layers don't depend on the info dictionary
+        let edited = Self::from_bencode(&Bencode::Dictionary(torrent))?;
+        self.info = edited.info;
+        self.info_hash = edited.info_hash;
+        self.info_hash_v2 = edited.info_hash_v2;
+        self.info_bytes = edited.info_bytes;
+        Ok(())
+    }
+
+    /// changes the info hashes, the torrent is a different one for peers
+    pub fn set_private(&mut self, private: bool) -> Result<()> {
+        self.edit_info(|info| {
+            if private {
+                info.insert(b"private".to_vec(), Bencode::Integer(1));
+            } else {
+                info.remove(b"private".as_ref());
+            }
+            Ok(())
+        })
+    }
+
+    pub fn info_hash_hex(&self) -> String {
+        to_hex(&self.info_hash)
+    }
+
+    /// link to the torrent with its v1 hash, its v2 hash or both, its name
+    /// and its trackers
+    pub fn magnet_link(&self) -> String {
+        let mut link = String::from("magnet:?");
+        let mut parameters = vec![];
+        if !self.info.pieces.is_empty() {
+            parameters.push(format!("xt=urn:btih:{}", self.info_hash_hex()));
+        }
+        if let Some(hash) = &self.info_hash_v2 {
+            parameters.push(format!("xt=urn:btmh:1220{}", to_hex(hash)));
+        }
+        parameters.push(format!("dn={}", url_encode(self.info.name.as_bytes())));
+        for tracker in self.trackers() {
+            parameters.push(format!("tr={}", url_encode(tracker.as_bytes())));
+        }
+        link.push_str(&parameters.join("&"));
+        link
+    }
+
+    /// the path on disk relative to the save path and the length of every
+    /// file but the padding
+    pub fn paths(&self) -> Vec<(PathBuf, u64)> {
+        let info = &self.info;
+        info.files
+            .iter()
+            .filter(|file| !file.padding)
+            .map(|file| {
+                let path = if info.multi_file {
+                    PathBuf::from(&info.name).join(&file.path)
+                } else {
+                    file.path.clone()
+                };
+                (path, file.length)
+            })
+            .collect()
+    }
+
+    /// of the files, without the padding
+    pub fn size(&self) -> u64 {
+        self.paths().iter().map(|(_, length)| length).sum()
+    }
+
+    /// "v1", "v2" or "hybrid" for a torrent with both hashes
+    pub fn version(&self) -> &'static str {
+        match (self.info.meta_version, self.info.pieces.is_empty()) {
+            (2, true) => "v2",
+            (2, false) => "hybrid",
+            _ => "v1",
+        }
+    }
+
+    /// what the torrent holds, as `torrent_rs show --json` prints it
+    pub fn to_json(&self) -> Json {
+        let info = &self.info;
+        let files: Vec<_> = self
+            .paths()
+            .into_iter()
+            .zip(info.files.iter().filter(|file| !file.padding))
+            .map(|((path, length), file)| {
+                Json::object()
+                    .with("path", path.to_string_lossy().into_owned())
+                    .with("length", length)
+                    .with("pieces_root", file.pieces_root.map(|root| to_hex(&root)))
+            })
+            .collect();
+        let tiers = if self.announce_list.is_empty() {
+            self.announce.iter().map(|url| vec![url.clone()]).collect()
+        } else {
+            self.announce_list.clone()
+        };
+        Json::object()
+            .with("name", info.name.as_str())
+            .with("info_hash", self.info_hash_hex())
+            .with("info_hash_v2", self.info_hash_v2.map(|hash| to_hex(&hash)))
+            .with("version", self.version())
+            .with("total_length", self.size())
+            .with("piece_length", info.piece_length)
+            .with("piece_count", info.piece_count())
+            .with("private", info.private)
+            .with("trackers", Json::from(tiers))
+            .with("web_seeds", self.url_list.clone())
+            .with("comment", self.comment.clone())
+            .with("created_by", self.created_by.clone())
+            .with("creation_date", self.creation_date)
+            .with("magnet", self.magnet_link())
+            .with("files", Json::Array(files))
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn url_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (*byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn to_hash(bytes: &[u8]) -> Result<Hash> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("invalid sha256 hash of {} bytes", bytes.len()))
+}
+
+impl Info {
+    pub fn from_bencode(info: &Bencode) -> Result<Self> {
+        let name = info
+            .get("name")
+            .and_then(Bencode::as_str)
+            .ok_or_else(|| anyhow!("missing name"))
+            .and_then(path_component)?
+            .to_string();
+        let piece_length = info
+            .get("piece length")
+            .and_then(Bencode::as_integer)
+            .ok_or_else(|| anyhow!("missing piece length"))?;
+        if piece_length <= 0 || piece_length as u64 > MAX_PIECE_LENGTH {
+            bail!("invalid piece length {}", piece_length);
+        }
+
+        let meta_version = match info.get("meta version").map(Bencode::as_integer) {
+            None => 1,
+            Some(Some(2)) => 2,
+            Some(version) => bail!("unsupported meta version {:?}", version),
+        };
+        if meta_version == 2 && !(piece_length as u64).is_multiple_of(LEAF_SIZE) {
+            bail!("v2 piece length must be a multiple of 16 KiB");
+        }
+
+        let pieces = match info.get("pieces").and_then(Bencode::as_bytes) {
+            Some(pieces) => pieces,
+            None if meta_version == 2 => &[],
+            None => bail!("missing pieces"),
+        };
+        if pieces.len() % 20 != 0 {
+            bail!("pieces length is not a multiple of 20");
+        }
+        let pieces: Vec<[u8; 20]> = pieces
+            .chunks_exact(20)
+            .map(|chunk| {
+                let mut hash = [0; 20];
+                hash.copy_from_slice(chunk);
+                hash
+            })
+            .collect();
+
+        let file_tree = match info.get("file tree") {
+            Some(tree) if meta_version == 2 => {
+                let mut files = vec![];
+                parse_file_tree(tree, PathBuf::new(), &mut files)?;
+                files
+            }
+            _ => vec![],
+        };
+
+        let (mut files, multi_file) = match (info.get("length"), info.get("files")) {
+            (None, None) if meta_version == 2 => {
+                let multi_file = !matches!(file_tree.as_slice(), [file] if file.path.as_os_str() == name.as_str());
+                (
+                    pad_files(file_tree.clone(), piece_length as u64),
+                    multi_file,
+                )
+            }
+            (Some(length), None) => {
+                let length = parse_length(length)?;
+                (
+                    vec![FileInfo {
+                        path: PathBuf::from(&name),
+                        length,
+                        padding: false,
+                        pieces_root: None,
+                    }],
+                    false,
+                )
+            }
+            (None, Some(files)) => {
+                let files = files
+                    .as_list()
+                    .ok_or_else(|| anyhow!("files is not a list"))?
+                    .iter()
+                    .map(FileInfo::from_bencode)
+                    .collect::<Result<_>>()?;
+                (files, true)
+            }
+            _ => bail!("info must contain exactly one of length or files"),
+        };
+        let total_length: u64 = files.iter().map(|file| file.length).sum();
+        let piece_count = total_length.div_ceil(piece_length as u64);
+        if !pieces.is_empty() && pieces.len() as u64 != piece_count {
+            bail!(
+                "{} piece hashes for {} pieces of data",
+                pieces.len(),
+                piece_count
+            );
+        }
+        // hybrid torrents describe their files twice, the v1 list has the padding
+        for tree_file in &file_tree {
+            if let Some(file) = files
+                .iter_mut()
+                .find(|file| !file.padding && file.path == tree_file.path)
+            {
+                file.pieces_root = tree_file.pieces_root;
+            }
+        }
+
+        Ok(Self {
+            name,
+            piece_length: piece_length as u64,
+            pieces,
+            files,
+            multi_file,
+            meta_version,
+            private: info.get("private").and_then(Bencode::as_integer) == Some(1),
+        })
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    /// v2 only torrents have no v1 piece hashes to count
+    pub fn piece_count(&self) -> usize {
+        if self.pieces.is_empty() {
+            self.total_length().div_ceil(self.piece_length) as usize
+        } else {
+            self.pieces.len()
+        }
+    }
+
+    /// length of the given piece, the last one is usually shorter. Pieces of
+    /// v2 only torrents end with their file, peers don't send the padding.
+    pub fn piece_size(&self, piece: usize) -> u64 {
+        let start = piece as u64 * self.piece_length;
+        let size = self.piece_length.min(self.total_length() - start);
+        match self.piece_file(piece) {
+            Some((file, index)) if self.pieces.is_empty() => {
+                size.min(self.files[file].length - index as u64 * self.piece_length)
+            }
+            _ => size,
+        }
+    }
+
+    /// (file index, piece index in the file) of the file a piece starts in,
+    /// for v2 torrents where pieces never span files
+    pub fn piece_file(&self, piece: usize) -> Option<(usize, usize)> {
+        if self.meta_version != 2 {
+            return None;
+        }
+        let start = piece as u64 * self.piece_length;
+        let mut file_start = 0;
+        for (index, file) in self.files.iter().enumerate() {
+            let file_end = file_start + file.length;
+            if !file.padding && file_start <= start && start < file_end {
+                let offset = start - file_start;
+                return Some((index, (offset / self.piece_length) as usize));
+            }
+            file_start = file_end;
+        }
+        None
+    }
+
+    pub fn verify_piece(&self, piece: usize, data: &[u8]) -> bool {
+        match self.pieces.get(piece) {
+            Some(hash) => Sha1::digest(data).as_slice() == hash,
+            None => false,
+        }
+    }
+
+    /// (file index, bytes) of every file overlapping the piece
+    pub fn piece_file_overlaps(&self, piece: usize) -> Vec<(usize, u64)> {
+        let start = piece as u64 * self.piece_length;
+        FileMap::from_info(self)
+            .map_range(start, self.piece_size(piece))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|span| (span.file, span.length))
+            .collect()
+    }
+
+    /// pieces overlapping each file, as a half open range, empty for zero-length files
+    pub fn file_piece_ranges(&self) -> Vec<std::ops::Range<usize>> {
+        let map = FileMap::from_info(self);
+        (0..self.files.len())
+            .map(|file| map.file_pieces(file))
+            .collect()
+    }
+}
+
+impl FileInfo {
+    fn from_bencode(file: &Bencode) -> Result<Self> {
+        let length = parse_length(
+            file.get("length")
+                .ok_or_else(|| anyhow!("file is missing length"))?,
+        )?;
+        let path = file
+            .get("path")
+            .and_then(Bencode::as_list)
+            .ok_or_else(|| anyhow!("file is missing path"))?
+            .iter()
+            .map(|component| {
+                component
+                    .as_str()
+                    .ok_or_else(|| anyhow!("path component is not a string"))
+                    .and_then(path_component)
+            })
+            .collect::<Result<PathBuf>>()?;
+        if path.as_os_str().is_empty() {
+            bail!("file has an empty path");
+        }
+
+        let padding = file
+            .get("attr")
+            .and_then(Bencode::as_str)
+            .is_some_and(|attr| attr.contains('p'));
+
+        Ok(Self {
+            path,
+            length,
+            padding,
+            pieces_root: None,
+        })
+    }
+}
+
+/// files of a v2 file tree, where every file is a dictionary with an empty key
+fn parse_file_tree(tree: &Bencode, path: PathBuf, files: &mut Vec<FileInfo>) -> Result<()> {
+    let tree = tree
+        .as_dict()
+        .ok_or_else(|| anyhow!("file tree entry is not a dictionary"))?;
+    if let Some(file) = tree.get(&b""[..]) {
+        let length = parse_length(
+            file.get("length")
+                .ok_or_else(|| anyhow!("file is missing length"))?,
+        )?;
+        let pieces_root = match file.get("pieces root").and_then(Bencode::as_bytes) {
+            Some(root) => Some(to_hash(root)?),
+            None if length == 0 => None,
+            None => bail!("file is missing pieces root"),
+        };
+        files.push(FileInfo {
+            path,
+            length,
+            padding: false,
+            pieces_root,
+        });
+        return Ok(());
+    }
+    // sorted so the file order matches the bencoded order
+    let mut entries: Vec<_> = tree.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, entry) in entries {
+        let name = std::str::from_utf8(name)
+            .map_err(|_| anyhow!("path component is not a string"))
+            .and_then(path_component)?;
+        parse_file_tree(entry, path.join(name), files)?;
+    }
+    Ok(())
+}
+
+/// v2 only torrents align every file to a piece boundary without listing the
+/// padding, it is added back so offsets work the same as v1 torrents
+fn pad_files(files: Vec<FileInfo>, piece_length: u64) -> Vec<FileInfo> {
+    let count = files.len();
+    let mut padded = vec![];
+    for (index, file) in files.into_iter().enumerate() {
+        let remainder = file.length % piece_length;
+        padded.push(file);
+        if remainder != 0 && index + 1 < count {
+            padded.push(FileInfo {
+                path: PathBuf::from(".pad").join((piece_length - remainder).to_string()),
+                length: piece_length - remainder,
+                padding: true,
+                pieces_root: None,
+            });
+        }
+    }
+    padded
+}
+
+/// a file or directory name that stays under the save path, so no `..`,
+/// root or separator
+fn path_component(component: &str) -> Result<&str> {
+    let normal = matches!(
+        Path::new(component).components().collect::<Vec<_>>().as_slice(),
+        [Component::Normal(name)] if *name == component
+    );
+    if !normal || component.contains(['/', '\\', '\0']) {
+        bail!("invalid path component {:?}", component);
+    }
+    Ok(component)
+}
+
+fn parse_length(length: &Bencode) -> Result<u64> {
+    match length.as_integer() {
+        Some(length) if length >= 0 => Ok(length as u64),
+        _ => bail!("invalid length {:?}", length),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_file() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+
+        assert_eq!(metainfo.info.name, "file1.txt");
+        assert_eq!(metainfo.info.piece_length, 16384);
+        assert_eq!(metainfo.info.piece_count(), 1);
+        assert_eq!(metainfo.info.total_length(), 12);
+        assert!(!metainfo.info.multi_file);
+        assert!(!metainfo.info.private);
+        assert_eq!(
+            metainfo.info_hash_hex(),
+            "8dc3b8a5ac6d8002df36541fda949e7109b7397c"
+        );
+        assert!(metainfo.magnet_link().starts_with(
+            "magnet:?xt=urn:btih:8dc3b8a5ac6d8002df36541fda949e7109b7397c&dn=file1.txt"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn multi_file() -> Result<()> {
+        let data = "d4:infod5:filesld6:lengthi3e4:pathl1:a1:beed6:lengthi5e4:pathl1:ceee4:name3:dir12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        let metainfo = Metainfo::from_bytes(data.as_bytes().to_vec())?;
+
+        assert!(metainfo.info.multi_file);
+        assert_eq!(metainfo.info.files[0].path, PathBuf::from("a").join("b"));
+        assert_eq!(metainfo.info.file_piece_ranges(), vec![0..1, 0..2]);
+        assert_eq!(metainfo.info.piece_size(1), 4);
+        assert_eq!(metainfo.info.piece_file_overlaps(0), vec![(0, 3), (1, 1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_save_path() {
+        let torrent = |files: &str, name: &str| {
+            format!(
+                "d4:infod5:filesl{}e4:name{}:{}12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+                files,
+                name.len(),
+                name
+            )
+        };
+        let file = |path: &str| format!("d6:lengthi3e4:pathl{}ee", path);
+        let parse = |data: String| Metainfo::from_bytes(data.into_bytes());
+        assert!(parse(torrent(&file("1:a1:b"), "dir")).is_ok());
+        for path in ["2:..2:..4:evil", "5:/evil", "3:a/b", "1:.", "0:", "3:a\\b"] {
+            assert!(parse(torrent(&file(path), "dir")).is_err(), "{}", path);
+        }
+        assert!(parse(torrent(&file(""), "dir")).is_err());
+        assert!(parse(torrent(&file("1:a"), "..")).is_err());
+        assert!(parse(torrent(&file("1:a"), "/tmp")).is_err());
+    }
+
+    #[test]
+    fn files_over_4_gib() -> Result<()> {
+        let data = format!(
+            "d4:infod6:lengthi{}e4:name1:a12:piece lengthi{}e6:pieces340:{}ee",
+            (1u64 << 32) + 5,
+            MAX_PIECE_LENGTH,
+            "a".repeat(340)
+        );
+        let metainfo = Metainfo::from_bytes(data.into_bytes())?;
+        assert!(metainfo.info.total_length() > u32::MAX as u64);
+        assert_eq!(metainfo.info.piece_count(), 17);
+        assert_eq!(metainfo.info.piece_size(16), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_piece_hashes_not_matching_the_length() {
+        // 2 hashes for the 3 bytes of one piece
+        let data = "d4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        let error = Metainfo::from_bytes(data.as_bytes().to_vec()).unwrap_err();
+        assert_eq!(error.to_string(), "2 piece hashes for 1 pieces of data");
+    }
+
+    #[test]
+    fn rejects_pieces_over_256_mib() {
+        let torrent = |piece_length: u64| {
+            format!(
+                "d4:infod6:lengthi3e4:name1:a12:piece lengthi{}e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+                piece_length
+            )
+        };
+        let parse = |data: String| Metainfo::from_bytes(data.into_bytes());
+        assert!(parse(torrent(MAX_PIECE_LENGTH)).is_ok());
+        let error = parse(torrent(4 << 30)).unwrap_err();
+        assert_eq!(error.to_string(), "invalid piece length 4294967296");
+    }
+
+    #[test]
+    fn edits_trackers_web_seeds_and_info() -> Result<()> {
+        let mut metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        metainfo.add_tracker("http://a");
+        metainfo.add_tracker("http://b");
+        metainfo.add_tracker("http://a");
+        assert_eq!(metainfo.announce.as_deref(), Some("http://a"));
+        assert_eq!(metainfo.announce_list, [["http://a"], ["http://b"]]);
+        metainfo.remove_tracker("http://a");
+        assert_eq!(metainfo.trackers(), ["http://b"]);
+        metainfo.set_announce("http://c");
+        assert_eq!(metainfo.trackers(), ["http://c"]);
+
+        metainfo.url_list = vec!["http://seed/".into()];
+        let info_hash = metainfo.info_hash;
+        let edited = Metainfo::from_bytes(metainfo.to_bytes()?)?;
+        assert_eq!(edited, metainfo);
+        assert_eq!(edited.info_hash, info_hash);
+        let mut torrent = Parser::new(std::fs::read("file1.txt.torrent")?).parse()?;
+        if let Bencode::Dictionary(torrent) = &mut torrent {
+            torrent.insert(b"url-list".to_vec(), "http://seed/".into());
+        }
+        let single = Metainfo::from_bencode(&torrent)?;
+        assert_eq!(single.url_list, ["http://seed/"]);
+
+        metainfo.set_private(true)?;
+        assert!(metainfo.info.private);
+        assert_ne!(metainfo.info_hash, info_hash);
+        assert_eq!(Metainfo::from_bytes(metainfo.to_bytes()?)?, metainfo);
+        metainfo.set_private(false)?;
+        assert_eq!(metainfo.info_hash, info_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn trackers_and_creation_metadata() -> Result<()> {
+        let data = "d8:announce5:http1\
+                    13:announce-listll5:http2el5:http1ee\
+                    7:comment5:hello10:created by4:test13:creation datei1600000000e\
+                    4:infod6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let metainfo = Metainfo::from_bytes(data.as_bytes().to_vec())?;
+
+        assert_eq!(metainfo.trackers(), ["http2", "http1"]);
+        assert_eq!(metainfo.comment.as_deref(), Some("hello"));
+        assert_eq!(metainfo.created_by.as_deref(), Some("test"));
+        assert_eq!(metainfo.creation_date, Some(1_600_000_000));
+        assert_eq!(Metainfo::from_bytes(metainfo.to_bytes()?)?, metainfo);
+        assert!(metainfo.magnet_link().ends_with("&dn=a&tr=http2&tr=http1"));
+        Ok(())
+    }
+
+    fn v2_file(length: usize, root: Hash) -> Bencode {
+        let mut file = HashMap::new();
+        file.insert("length".into(), (length as i64).into());
+        file.insert("pieces root".into(), root.to_vec().into());
+        let mut entry = HashMap::new();
+        entry.insert(vec![], Bencode::Dictionary(file));
+        Bencode::Dictionary(entry)
+    }
+
+    #[test]
+    fn v2_only() -> Result<()> {
+        let piece_length = 2 * LEAF_SIZE;
+        let blocks = merkle::block_hashes(&[1; 40000]);
+        let layer = vec![merkle::root(&blocks[..2], 2), merkle::root(&blocks[2..], 2)];
+        let root_a = merkle::root_from_piece_layer(&layer, piece_length);
+        let root_b = merkle::hash_block(&[2; 100]);
+
+        let mut tree = HashMap::new();
+        tree.insert("a".into(), v2_file(40000, root_a));
+        tree.insert("b".into(), v2_file(100, root_b));
+        let mut info = HashMap::new();
+        info.insert("name".into(), "dir".into());
+        info.insert("piece length".into(), (piece_length as i64).into());
+        info.insert("meta version".into(), 2.into());
+        info.insert("file tree".into(), Bencode::Dictionary(tree));
+        let mut layers = HashMap::new();
+        layers.insert(root_a.to_vec(), layer.concat().into());
+        let mut torrent = HashMap::new();
+        torrent.insert("info".into(), Bencode::Dictionary(info));
+        torrent.insert("piece layers".into(), Bencode::Dictionary(layers));
+        let metainfo = Metainfo::from_bencode(&Bencode::Dictionary(torrent.clone()))?;
+        assert_eq!(Metainfo::from_bytes(metainfo.to_bytes()?)?, metainfo);
+
+        let info = &metainfo.info;
+        assert!(info.multi_file && metainfo.info_hash_v2.is_some());
+        assert!(info.files[1].padding);
+        assert_eq!(info.files[1].length, 2 * piece_length - 40000);
+        assert_eq!(info.piece_count(), 3);
+        assert_eq!(info.piece_size(1), 40000 - piece_length);
+        assert_eq!(
+            metainfo.piece_hash(1),
+            Some(PieceHash::V2 {
+                root: layer[1],
+                leaves: 2,
+                file_bytes: 40000 - piece_length,
+            })
+        );
+        assert_eq!(
+            metainfo.piece_hash(2),
+            Some(PieceHash::V2 {
+                root: root_b,
+                leaves: 1,
+                file_bytes: 100,
+            })
+        );
+
+        let mut layers = HashMap::new();
+        layers.insert(root_a.to_vec(), [layer[1], layer[0]].concat().into());
+        torrent.insert("piece layers".into(), Bencode::Dictionary(layers));
+        assert!(Metainfo::from_bencode(&Bencode::Dictionary(torrent)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use crate::http;
+use anyhow::Result;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// how long a scraper gets to read the metrics
+const TIMEOUT: Duration = Duration::from_secs(5);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl fmt::Display for MetricKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        })
+    }
+}
+
+/// Metrics in the Prometheus text format. Each family is declared once with
+/// `family`, then its samples follow:
+///
+/// ```text
+/// # HELP torrent_rs_peers Connected peers
+/// # TYPE torrent_rs_peers gauge
+/// torrent_rs_peers{info_hash="...",source="dht"} 3
+/// ```
+#[derive(Debug, Default)]
+pub struct Metrics {
+    text: String,
+    /// of the family the samples are added to
+    name: String,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// starts a family, its name gets the `torrent_rs_` prefix
+    pub fn family(&mut self, name: &str, kind: MetricKind, help: &str) -> &mut Self {
+        self.name = format!("torrent_rs_{}", name);
+        let _ = writeln!(self.text, "# HELP {} {}", self.name, escape_help(help));
+        let _ = writeln!(self.text, "# TYPE {} {}", self.name, kind);
+        self
+    }
+
+    /// a sample of the last family started
+    pub fn sample(&mut self, labels: &[(&str, &str)], value: f64) -> &mut Self {
+        self.text += &self.name;
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", format_value(value));
+        self
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+fn escape_help(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label(text: &str) -> String {
+    escape_help(text).replace('"', "\\\"")
+}
+
+fn format_value(value: f64) -> String {
+    match value {
+        value if value.is_nan() => "NaN".into(),
+        value if value == f64::INFINITY => "+Inf".into(),
+        value if value == f64::NEG_INFINITY => "-Inf".into(),
+        value => value.to_string(),
+    }
+}
+
+/// Serves the last metrics it was given at `GET /metrics`, for Prometheus
+/// to scrape. The session renders them on each `Session::tick`, so a scrape
+/// never waits on the locks of the torrents.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    text: Arc<Mutex<String>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn start(addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        // wakes up regularly to stop when asked to
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let text = Arc::new(Mutex::new(String::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let text = Arc::clone(&text);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || serve(listener, text, stop))
+        };
+        Ok(Self {
+            addr,
+            text,
+            stop,
+            worker: Some(worker),
+        })
+    }
+
+    /// the address actually listened on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// served from now on
+    pub fn update(&self, metrics: Metrics) {
+        *self.text.lock().unwrap() = metrics.into_text();
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn serve(listener: TcpListener, text: Arc<Mutex<String>>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // a scraper that misbehaves only loses its own request
+                let _ = respond(stream, &text);
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, text: &Mutex<String>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let (status, body) = match http::read_request(&mut stream, 0) {
+        Ok(request) if request.method != "GET" => (405, "only GET is supported\n".into()),
+        Ok(request) if request.path == "/metrics" => (200, text.lock().unwrap().clone()),
+        Ok(_) => (404, "not found\n".into()),
+        Err(refused) => (refused.status, format!("{}\n", refused.message)),
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        http::reason(status),
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker;
+
+    #[test]
+    fn formats_families_and_samples() {
+        let mut metrics = Metrics::new();
+        metrics
+            .family("peers", MetricKind::Gauge, "Connected peers")
+            .sample(&[("name", "a \"quoted\"\\name\n"), ("source", "dht")], 3.0)
+            .sample(&[], 0.5);
+        metrics
+            .family(
+                "downloaded_bytes_total",
+                MetricKind::Counter,
+                "Bytes\nreceived",
+            )
+            .sample(&[], f64::NAN)
+            .sample(&[], f64::INFINITY);
+        assert_eq!(
+            metrics.into_text(),
+            "# HELP torrent_rs_peers Connected peers\n\
+             # TYPE torrent_rs_peers gauge\n\
+             torrent_rs_peers{name=\"a \\\"quoted\\\"\\\\name\\n\",source=\"dht\"} 3\n\
+             torrent_rs_peers 0.5\n\
+             # HELP torrent_rs_downloaded_bytes_total Bytes\\nreceived\n\
+             # TYPE torrent_rs_downloaded_bytes_total counter\n\
+             torrent_rs_downloaded_bytes_total NaN\n\
+             torrent_rs_downloaded_bytes_total +Inf\n"
+        );
+    }
+
+    #[test]
+    fn serves_the_last_metrics() -> Result<()> {
+        let server = MetricsServer::start("127.0.0.1:0".parse()?)?;
+        let url = format!("http://{}", server.local_addr());
+        assert_eq!(tracker::http_get(&format!("{}/metrics", url))?, b"");
+
+        let mut metrics = Metrics::new();
+        metrics
+            .family("torrents", MetricKind::Gauge, "Torrents")
+            .sample(&[], 2.0);
+        server.update(metrics);
+        assert_eq!(
+            tracker::http_get(&format!("{}/metrics?x=1", url))?,
+            b"# HELP torrent_rs_torrents Torrents\n# TYPE torrent_rs_torrents gauge\ntorrent_rs_torrents 2\n"
+        );
+        let error = tracker::http_get(&format!("{}/other", url)).unwrap_err();
+        assert!(error.to_string().contains("404"), "{}", error);
+        Ok(())
+    }
+}
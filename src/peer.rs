@@ -0,0 +1,203 @@
+use crate::bitfield::Bitfield;
+use crate::message::Message;
+use crate::scheduler::BlockRequest;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+
+/// How we learned about a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    /// peer exchange with the connected peers
+    Pex,
+    /// local service discovery
+    Lsd,
+    /// the peer connected to us
+    Incoming,
+    /// added by the user
+    Manual,
+}
+
+impl fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PeerSource::Tracker => "tracker",
+            PeerSource::Dht => "dht",
+            PeerSource::Pex => "pex",
+            PeerSource::Lsd => "lsd",
+            PeerSource::Incoming => "incoming",
+            PeerSource::Manual => "manual",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A connected peer, as listed by `Torrent::peer_list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+    /// the program it runs, once known
+    pub client: Option<String>,
+    /// pieces the peer has
+    pub pieces: usize,
+    /// bytes per second
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// the peer chokes us
+    pub choked: bool,
+    /// we are interested in its pieces
+    pub interested: bool,
+    /// we choke the peer
+    pub choking: bool,
+    /// the peer is interested in our pieces
+    pub peer_interested: bool,
+    pub encrypted: bool,
+}
+
+impl PeerInfo {
+    /// like `CIcE`: C when it chokes us, I when we are interested, c when
+    /// we choke it, i when it is interested and E when encrypted, - for none
+    pub fn flags(&self) -> String {
+        let flags: String = [
+            (self.choked, 'C'),
+            (self.interested, 'I'),
+            (self.choking, 'c'),
+            (self.peer_interested, 'i'),
+            (self.encrypted, 'E'),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| flag)
+        .collect();
+        if flags.is_empty() {
+            "-".into()
+        } else {
+            flags
+        }
+    }
+}
+
+/// the client of an Azureus style peer id like `-qB4650-...`, with its
+/// version
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    let code = peer_id.get(1..3)?;
+    let version = peer_id.get(3..7)?;
+    if peer_id[0] != b'-' || peer_id[7] != b'-' || !version.iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let name = match code {
+        b"qB" => "qBittorrent",
+        b"TR" => "Transmission",
+        b"UT" => "µTorrent",
+        b"LT" => "libtorrent",
+        b"lt" => "rTorrent",
+        b"DE" => "Deluge",
+        b"AZ" => "Vuze",
+        b"BI" => "BiglyBT",
+        b"BT" => "BitTorrent",
+        b"KT" => "KTorrent",
+        b"TX" => "Tixati",
+        b"WW" => "WebTorrent",
+        b"RS" => "torrent_rs",
+        _ => return Some(String::from_utf8_lossy(&peer_id[1..7]).into_owned()),
+    };
+    let digits: Vec<_> = version
+        .iter()
+        .map(|digit| (*digit as char).to_string())
+        .collect();
+    Some(format!("{} {}", name, digits.join(".")))
+}
+
+/// What a torrent knows about one of its connected peers
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub source: PeerSource,
+    pub has: Bitfield,
+    /// blocks requested from the peer that didn't arrive yet
+    pub requests: Vec<BlockRequest>,
+    /// request queue size advertised by the peer in its extension handshake
+    pub reqq: Option<usize>,
+    /// bytes per second, as measured by the connection
+    pub download_rate: u64,
+    /// pieces that failed the hash check with data from this peer
+    pub failed_pieces: HashSet<usize>,
+    pub hash_failures: u32,
+    /// whether we told the peer we are interested in its pieces
+    pub am_interested: bool,
+    /// connections start choked both ways
+    pub am_choking: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// from its peer id or extension handshake
+    pub client: Option<String>,
+    /// with message stream encryption
+    pub encrypted: bool,
+    /// bytes per second sent to the peer
+    pub upload_rate: u64,
+    /// messages waiting to be sent by the connection
+    pub outbox: VecDeque<Message>,
+}
+
+impl PeerState {
+    pub fn new(piece_count: usize, source: PeerSource) -> Self {
+        Self {
+            source,
+            has: Bitfield::new(piece_count),
+            requests: vec![],
+            reqq: None,
+            download_rate: 0,
+            failed_pieces: HashSet::new(),
+            hash_failures: 0,
+            am_interested: false,
+            am_choking: true,
+            peer_choking: true,
+            peer_interested: false,
+            client: None,
+            encrypted: false,
+            upload_rate: 0,
+            outbox: VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_clients_and_flags() {
+        let id = |text: &str| {
+            let mut id = [b'x'; 20];
+            id[..text.len()].copy_from_slice(text.as_bytes());
+            id
+        };
+        assert_eq!(
+            client_name(&id("-qB4650-")).as_deref(),
+            Some("qBittorrent 4.6.5.0")
+        );
+        assert_eq!(client_name(&id("-ZZ1000-")).as_deref(), Some("ZZ1000"));
+        assert_eq!(client_name(&id("M7-4-3--")), None);
+
+        let mut peer = PeerInfo {
+            addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+            source: PeerSource::Tracker,
+            client: None,
+            pieces: 0,
+            download_rate: 0,
+            upload_rate: 0,
+            choked: false,
+            interested: false,
+            choking: false,
+            peer_interested: false,
+            encrypted: false,
+        };
+        assert_eq!(peer.flags(), "-");
+        peer.choked = true;
+        peer.interested = true;
+        peer.encrypted = true;
+        assert_eq!(peer.flags(), "CIE");
+    }
+}
@@ -0,0 +1,101 @@
+use crate::bencode::{Bencode, Parser};
+use crate::dht::krpc::{decode_peer, encode_peer};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// id peers use to send us ut_pex messages, from our extension handshake
+pub const UT_PEX_ID: u8 = 2;
+/// peers added or dropped in a single message, more are left for the next one
+pub const MAX_PEERS: usize = 50;
+/// how often each peer is told about the changes of the swarm
+pub const INTERVAL: Duration = Duration::from_secs(60);
+
+/// A ut_pex message, the peers connected and disconnected since the last one
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    /// the ipv4 and ipv6 peers go in their own compact lists
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+        for (key, key6, peers) in [
+            ("added", "added6", &self.added),
+            ("dropped", "dropped6", &self.dropped),
+        ] {
+            let (v4, v6): (Vec<_>, Vec<_>) = peers
+                .iter()
+                .take(MAX_PEERS)
+                .map(encode_peer)
+                .partition(|peer| peer.len() == 6);
+            dict.insert(key.into(), Bencode::Bytes(v4.concat()));
+            dict.insert(key6.into(), Bencode::Bytes(v6.concat()));
+        }
+        Bencode::Dictionary(dict).encode()
+    }
+
+    /// peers past `MAX_PEERS` are ignored
+    pub fn parse(payload: Vec<u8>) -> Result<Self> {
+        let dict = Parser::new(payload).parse()?;
+        if dict.as_dict().is_none() {
+            bail!("pex message is not a dictionary");
+        }
+        let peers = |key: &str, key6: &str| -> Result<Vec<SocketAddr>> {
+            let mut peers = vec![];
+            for (key, size) in [(key, 6), (key6, 18)] {
+                let bytes = match dict.get(key).and_then(Bencode::as_bytes) {
+                    Some(bytes) => bytes,
+                    None => continue,
+                };
+                if bytes.len() % size != 0 {
+                    bail!("{} of {} bytes", key, bytes.len());
+                }
+                peers.extend(bytes.chunks(size).filter_map(decode_peer));
+            }
+            peers.truncate(MAX_PEERS);
+            Ok(peers)
+        };
+        Ok(Self {
+            added: peers("added", "added6")?,
+            dropped: peers("dropped", "dropped6")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_both_families() -> Result<()> {
+        let message = PexMessage {
+            added: vec!["10.0.0.1:6881".parse()?, "[2001:db8::1]:6882".parse()?],
+            dropped: vec!["10.0.0.2:51413".parse()?],
+        };
+        assert_eq!(PexMessage::parse(message.encode())?, message);
+        assert!(PexMessage::parse(b"d5:added5:12345e".to_vec()).is_err());
+        assert!(PexMessage::parse(b"i1e".to_vec()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn caps_the_peers() -> Result<()> {
+        let added = (0..MAX_PEERS as u16 + 10)
+            .map(|port| SocketAddr::from(([10, 0, 0, 1], 1000 + port)))
+            .collect::<Vec<_>>();
+        let message = PexMessage {
+            added,
+            dropped: vec![],
+        };
+        assert_eq!(PexMessage::parse(message.encode())?.added.len(), MAX_PEERS);
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use crate::availability::Availability;
+use crate::bitfield::Bitfield;
+use crate::metainfo::Info;
+use crate::scheduler::{BlockRequest, BlockScheduler, BlockState, BLOCK_SIZE};
+use std::cmp::Reverse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// maps file priorities down to pieces, a piece straddling multiple files
+/// gets the highest priority of the files it belongs to
+pub fn piece_priorities(info: &Info, file_priorities: &[Priority]) -> Vec<Priority> {
+    let mut priorities = vec![Priority::Skip; info.piece_count()];
+    for (range, &priority) in info.file_piece_ranges().into_iter().zip(file_priorities) {
+        for piece in range {
+            priorities[piece] = priorities[piece].max(priority);
+        }
+    }
+    priorities
+}
+
+/// raises the first and last piece of every selected file to high priority so
+/// media files can be previewed before the whole file is downloaded
+pub fn prioritize_first_last(
+    priorities: &mut [Priority],
+    info: &Info,
+    file_priorities: &[Priority],
+) {
+    for (range, &priority) in info.file_piece_ranges().into_iter().zip(file_priorities) {
+        if priority == Priority::Skip || range.is_empty() {
+            continue;
+        }
+        priorities[range.start] = Priority::High;
+        priorities[range.end - 1] = Priority::High;
+    }
+}
+
+/// Everything a picker needs to know to choose the next blocks for a peer
+pub struct PickContext<'a> {
+    pub info: &'a Info,
+    /// pieces we have, are verifying or are already downloading
+    pub busy: &'a Bitfield,
+    pub peer_has: &'a Bitfield,
+    pub priorities: &'a [Priority],
+    pub availability: &'a Availability,
+    /// last pieces that passed the hash check, oldest first
+    pub recently_completed: &'a [usize],
+    /// pieces being downloaded and the state of their blocks
+    pub downloading: &'a BlockScheduler,
+    /// blocks the peer can still be asked for
+    pub count: usize,
+}
+
+impl PickContext<'_> {
+    /// pieces that can be started from the peer
+    pub fn candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.priorities.len()).filter(move |&piece| {
+            self.priorities[piece] != Priority::Skip
+                && !self.busy.get(piece)
+                && self.peer_has.get(piece)
+        })
+    }
+
+    /// blocks of pieces in progress that the peer has and nobody was asked
+    /// for, lowest piece first
+    pub fn open_blocks(&self) -> impl Iterator<Item = BlockRequest> + '_ {
+        self.downloading
+            .in_progress()
+            .filter(move |buffer| self.peer_has.get(buffer.piece()))
+            .flat_map(|buffer| {
+                buffer
+                    .blocks()
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &state)| state == BlockState::Open)
+                    .map(move |(block, _)| BlockRequest {
+                        piece: buffer.piece(),
+                        offset: block as u32 * BLOCK_SIZE,
+                        length: buffer.block_length(block),
+                    })
+            })
+    }
+
+    /// every block of a piece that isn't started
+    pub fn piece_blocks(&self, piece: usize) -> impl Iterator<Item = BlockRequest> {
+        let size = self.info.piece_size(piece) as u32;
+        (0..size.div_ceil(BLOCK_SIZE)).map(move |block| {
+            let offset = block * BLOCK_SIZE;
+            BlockRequest {
+                piece,
+                offset,
+                length: BLOCK_SIZE.min(size - offset),
+            }
+        })
+    }
+
+    /// up to `count` blocks, those open in pieces in progress first so pieces
+    /// get finished, then those of new pieces, the candidate with the highest
+    /// `key` first
+    pub fn requests<K: Ord>(&self, key: impl Fn(usize) -> K) -> Vec<BlockRequest> {
+        let mut requests: Vec<_> = self.open_blocks().take(self.count).collect();
+        let mut started = vec![];
+        while requests.len() < self.count {
+            let piece = match self
+                .candidates()
+                .filter(|piece| !started.contains(piece))
+                .max_by_key(|&piece| key(piece))
+            {
+                Some(piece) => piece,
+                None => break,
+            };
+            started.push(piece);
+            let left = self.count - requests.len();
+            requests.extend(self.piece_blocks(piece).take(left));
+        }
+        requests
+    }
+}
+
+/// Strategy deciding which blocks to download next from the availability of
+/// the pieces, the torrent requests those it can of the peer in the order
+/// they come. Implement this to plug in a custom strategy with
+/// `Torrent::set_picker`.
+pub trait PiecePicker: Send {
+    fn pick(&mut self, context: &PickContext) -> Vec<BlockRequest>;
+}
+
+/// Highest priority first, the rarest piece in the swarm wins among pieces of
+/// the same priority and remaining ties are broken by piece index
+#[derive(Debug, Clone, Default)]
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, context: &PickContext) -> Vec<BlockRequest> {
+        context.requests(|piece| {
+            (
+                context.priorities[piece],
+                Reverse(context.availability.get(piece)),
+                Reverse(piece),
+            )
+        })
+    }
+}
+
+/// Highest priority first, then in piece order regardless of availability
+#[derive(Debug, Clone, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, context: &PickContext) -> Vec<BlockRequest> {
+        context.requests(|piece| (context.priorities[piece], Reverse(piece)))
+    }
+}
+
+/// Rarest first, but pieces next to recently completed ones win among pieces of
+/// about the same rarity so writes stay close together on spinning disks.
+/// Pieces whose availability is within `rarity_slack` of each other are in the
+/// same class, 0 only breaks exact ties and higher values favor locality over
+/// rarity.
+#[derive(Debug, Clone, Default)]
+pub struct Contiguous {
+    pub rarity_slack: u32,
+}
+
+impl PiecePicker for Contiguous {
+    fn pick(&mut self, context: &PickContext) -> Vec<BlockRequest> {
+        let is_adjacent = |piece: usize| {
+            context
+                .recently_completed
+                .iter()
+                .any(|&done| done.abs_diff(piece) == 1)
+        };
+        context.requests(|piece| {
+            let availability = context.availability.get(piece);
+            (
+                context.priorities[piece],
+                Reverse(availability / (self.rarity_slack + 1)),
+                is_adjacent(piece),
+                Reverse(availability),
+                Reverse(piece),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::FileInfo;
+
+    fn info(lengths: &[u64], piece_length: u64) -> Info {
+        let files: Vec<_> = lengths
+            .iter()
+            .enumerate()
+            .map(|(index, &length)| FileInfo {
+                path: index.to_string().into(),
+                length,
+                padding: false,
+                pieces_root: None,
+            })
+            .collect();
+        let total: u64 = lengths.iter().sum();
+        let piece_count = total.div_ceil(piece_length) as usize;
+        Info {
+            name: String::from("test"),
+            piece_length,
+            pieces: vec![[0; 20]; piece_count],
+            files,
+            multi_file: true,
+            meta_version: 1,
+            private: false,
+        }
+    }
+
+    #[test]
+    fn straddling_piece_takes_highest_priority() {
+        // pieces: [0..4) [4..8) [8..10), file 0 is 0..6, file 1 is 6..10
+        let info = info(&[6, 4], 4);
+
+        assert_eq!(
+            piece_priorities(&info, &[Priority::Skip, Priority::High]),
+            vec![Priority::Skip, Priority::High, Priority::High]
+        );
+    }
+
+    #[test]
+    fn first_and_last_pieces_of_selected_files() {
+        let info = info(&[16, 8], 4);
+        let file_priorities = [Priority::Normal, Priority::Skip];
+        let mut priorities = piece_priorities(&info, &file_priorities);
+        prioritize_first_last(&mut priorities, &info, &file_priorities);
+
+        assert_eq!(
+            priorities,
+            vec![
+                Priority::High,
+                Priority::Normal,
+                Priority::Normal,
+                Priority::High,
+                Priority::Skip,
+                Priority::Skip
+            ]
+        );
+    }
+
+    fn pieces(requests: Vec<BlockRequest>) -> Vec<usize> {
+        requests.iter().map(|request| request.piece).collect()
+    }
+
+    #[test]
+    fn pick_honors_priorities() {
+        let info = info(&[4, 4, 4], 4);
+        let priorities = piece_priorities(&info, &[Priority::Low, Priority::Skip, Priority::High]);
+        let mut have = Bitfield::new(3);
+        let peer_has = Bitfield::full(3);
+        let availability = Availability::new(3);
+        let downloading = BlockScheduler::new();
+        let pick = |have: &Bitfield, count: usize| {
+            pieces(RarestFirst.pick(&PickContext {
+                info: &info,
+                busy: have,
+                peer_has: &peer_has,
+                priorities: &priorities,
+                availability: &availability,
+                recently_completed: &[],
+                downloading: &downloading,
+                count,
+            }))
+        };
+
+        assert_eq!(pick(&have, 1), vec![2]);
+        assert_eq!(pick(&have, 5), vec![2, 0]);
+        have.set(2, true);
+        assert_eq!(pick(&have, 1), vec![0]);
+        have.set(0, true);
+        assert_eq!(pick(&have, 1), vec![]);
+    }
+
+    #[test]
+    fn pieces_in_progress_first() {
+        let info = info(&[3 * BLOCK_SIZE as u64 + 10], 2 * BLOCK_SIZE as u64);
+        let mut downloading = BlockScheduler::new();
+        let first = downloading.start_piece(0, 2 * BLOCK_SIZE);
+        let mut busy = Bitfield::new(2);
+        busy.set(0, true);
+        let requests = RarestFirst.pick(&PickContext {
+            info: &info,
+            busy: &busy,
+            peer_has: &Bitfield::full(2),
+            priorities: &[Priority::Normal; 2],
+            availability: &Availability::new(2),
+            recently_completed: &[],
+            downloading: &downloading,
+            count: 4,
+        });
+
+        assert_eq!(first.offset, 0);
+        assert_eq!(
+            requests,
+            vec![
+                BlockRequest {
+                    piece: 0,
+                    offset: BLOCK_SIZE,
+                    length: BLOCK_SIZE,
+                },
+                BlockRequest {
+                    piece: 1,
+                    offset: 0,
+                    length: BLOCK_SIZE,
+                },
+                BlockRequest {
+                    piece: 1,
+                    offset: BLOCK_SIZE,
+                    length: 10,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rarest_first_within_priority() {
+        let info = info(&[3], 1);
+        let mut availability = Availability::new(3);
+        let mut without_1 = Bitfield::full(3);
+        without_1.set(1, false);
+        availability.add_peer(&Bitfield::full(3));
+        availability.add_peer(&without_1);
+        let context = PickContext {
+            info: &info,
+            busy: &Bitfield::new(3),
+            peer_has: &Bitfield::full(3),
+            priorities: &[Priority::Normal; 3],
+            availability: &availability,
+            recently_completed: &[],
+            downloading: &BlockScheduler::new(),
+            count: 1,
+        };
+
+        assert_eq!(pieces(RarestFirst.pick(&context)), vec![1]);
+        assert_eq!(pieces(Sequential.pick(&context)), vec![0]);
+    }
+
+    #[test]
+    fn contiguous_within_rarity_class() {
+        let info = info(&[6], 1);
+        let mut availability = Availability::new(6);
+        let mut without_0 = Bitfield::full(6);
+        without_0.set(0, false);
+        availability.add_peer(&Bitfield::full(6));
+        availability.add_peer(&Bitfield::full(6));
+        availability.add_peer(&without_0);
+        let mut busy = Bitfield::new(6);
+        busy.set(3, true);
+        let context = PickContext {
+            info: &info,
+            busy: &busy,
+            peer_has: &Bitfield::full(6),
+            priorities: &[Priority::Normal; 6],
+            availability: &availability,
+            recently_completed: &[3],
+            downloading: &BlockScheduler::new(),
+            count: 1,
+        };
+
+        assert_eq!(
+            pieces(Contiguous { rarity_slack: 0 }.pick(&context)),
+            vec![0]
+        );
+        assert_eq!(
+            pieces(Contiguous { rarity_slack: 1 }.pick(&context)),
+            vec![2]
+        );
+    }
+}
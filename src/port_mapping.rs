@@ -0,0 +1,912 @@
+use crate::dht::routing::random_bytes;
+use crate::tracker;
+use anyhow::{anyhow, bail, Context, Result};
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// where gateways answer NAT-PMP and PCP requests
+const PMP_PORT: u16 = 5351;
+/// where UPnP devices answer searches
+const SSDP_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(2);
+/// asked for, mappings are renewed halfway through what the gateway grants
+const LIFETIME: u32 = 2 * 60 * 60;
+/// before trying again once every method failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// of a NAT-PMP or PCP request, doubled for each of the tries (RFC 6886)
+const FIRST_WAIT: Duration = Duration::from_millis(250);
+const TRIES: u32 = 3;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const PCP_REQUEST_LENGTH: usize = 60;
+const DESCRIPTION: &str = "torrent_rs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn pmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+
+    /// IANA protocol number, as PCP sends it
+    fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+
+    fn upnp_name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// How the gateway was asked for a mapping, the methods are tried in this
+/// order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingMethod {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+impl fmt::Display for MappingMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MappingMethod::Pcp => write!(f, "PCP"),
+            MappingMethod::NatPmp => write!(f, "NAT-PMP"),
+            MappingMethod::Upnp => write!(f, "UPnP"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MappingStatus {
+    /// not asked for yet
+    Pending,
+    /// peers reach us on `external_port` of the gateway
+    Mapped {
+        external_port: u16,
+        method: MappingMethod,
+    },
+    /// by every method, tried again later
+    Failed(String),
+}
+
+/// A port of ours the gateway is asked to forward
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub protocol: Protocol,
+    pub port: u16,
+    pub status: MappingStatus,
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "port {}/{}: ", self.port, self.protocol)?;
+        match &self.status {
+            MappingStatus::Pending => write!(f, "pending"),
+            MappingStatus::Mapped {
+                external_port,
+                method,
+            } => write!(f, "mapped to {} with {}", external_port, method),
+            MappingStatus::Failed(error) => write!(f, "failed, {}", error),
+        }
+    }
+}
+
+/// The router between us and the internet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    /// answering NAT-PMP and PCP
+    pub pmp: SocketAddr,
+    /// where UPnP searches are sent
+    pub ssdp: SocketAddr,
+    /// our address on the network of the gateway
+    pub local_ip: Ipv4Addr,
+}
+
+impl Gateway {
+    /// the default route of the system, or the first address of our network
+    /// when the routes can't be read
+    pub fn find() -> Result<Self> {
+        let router = match std::fs::read_to_string("/proc/net/route") {
+            Ok(routes) => parse_default_route(&routes),
+            Err(_) => None,
+        };
+        let router = match router {
+            Some(router) => router,
+            None => {
+                // nothing is sent, the system only picks the address it
+                // would send from
+                let [a, b, c, _] = local_ip(SocketAddr::from(([192, 0, 2, 1], 9)))?.octets();
+                Ipv4Addr::new(a, b, c, 1)
+            }
+        };
+        let pmp = SocketAddr::from((router, PMP_PORT));
+        Ok(Self {
+            pmp,
+            ssdp: SocketAddr::V4(SSDP_GROUP),
+            local_ip: local_ip(pmp)?,
+        })
+    }
+}
+
+fn local_ip(to: SocketAddr) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(to)?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) if !ip.is_unspecified() => Ok(ip),
+        _ => bail!("no IPv4 address to reach {}", to),
+    }
+}
+
+/// gateway of the default route in the format of /proc/net/route, whose
+/// addresses are hexadecimal in the byte order of the host
+fn parse_default_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let columns: Vec<_> = line.split_whitespace().collect();
+        if columns.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(columns.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+/// Keeps the listen port mapped on the gateway over TCP and UDP so peers
+/// outside of our network can connect, with PCP, NAT-PMP or UPnP, whichever
+/// the gateway answers. Mappings are renewed before they expire and removed
+/// when the mapper is dropped.
+pub struct PortMapper {
+    mappings: Arc<Mutex<Vec<PortMapping>>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl PortMapper {
+    /// on the gateway of the default route
+    pub fn start(port: u16) -> Self {
+        Self::spawn(port, Gateway::find)
+    }
+
+    pub fn with_gateway(port: u16, gateway: Gateway) -> Self {
+        Self::spawn(port, move || Ok(gateway))
+    }
+
+    fn spawn(port: u16, gateway: impl FnOnce() -> Result<Gateway> + Send + 'static) -> Self {
+        let mappings = Arc::new(Mutex::new(
+            [Protocol::Tcp, Protocol::Udp]
+                .iter()
+                .map(|protocol| PortMapping {
+                    protocol: *protocol,
+                    port,
+                    status: MappingStatus::Pending,
+                })
+                .collect(),
+        ));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let mappings = Arc::clone(&mappings);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || match gateway() {
+                Ok(gateway) => {
+                    let mut nonce = [0; 12];
+                    random_bytes(&mut nonce);
+                    Worker {
+                        gateway,
+                        mappings,
+                        stop,
+                        method: None,
+                        upnp: None,
+                        nonce,
+                    }
+                    .run()
+                }
+                Err(error) => {
+                    for mapping in mappings.lock().unwrap().iter_mut() {
+                        mapping.status =
+                            MappingStatus::Failed(format!("finding the gateway: {:#}", error));
+                    }
+                }
+            })
+        };
+        Self {
+            mappings,
+            stop,
+            worker: Some(worker),
+        }
+    }
+
+    pub fn mappings(&self) -> Vec<PortMapping> {
+        self.mappings.lock().unwrap().clone()
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// A UPnP service able to map ports
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UpnpService {
+    control_url: String,
+    service_type: String,
+}
+
+struct Worker {
+    gateway: Gateway,
+    mappings: Arc<Mutex<Vec<PortMapping>>>,
+    stop: Arc<AtomicBool>,
+    /// that worked last, tried first
+    method: Option<MappingMethod>,
+    upnp: Option<UpnpService>,
+    /// identifies our PCP mappings, renewing or deleting them takes it again
+    nonce: [u8; 12],
+}
+
+impl Worker {
+    fn run(mut self) {
+        let count = self.mappings.lock().unwrap().len();
+        let mut renew = vec![Instant::now(); count];
+        while !self.stop.load(Ordering::Relaxed) {
+            for (index, renew) in renew.iter_mut().enumerate() {
+                if *renew > Instant::now() || self.stop.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let (protocol, port) = {
+                    let mappings = self.mappings.lock().unwrap();
+                    (mappings[index].protocol, mappings[index].port)
+                };
+                let status = match self.map(protocol, port) {
+                    Ok((external_port, method, lifetime)) => {
+                        *renew =
+                            Instant::now() + Duration::from_secs((lifetime / 2).max(60) as u64);
+                        MappingStatus::Mapped {
+                            external_port,
+                            method,
+                        }
+                    }
+                    Err(error) => {
+                        *renew = Instant::now() + RETRY_INTERVAL;
+                        MappingStatus::Failed(format!("{:#}", error))
+                    }
+                };
+                self.mappings.lock().unwrap()[index].status = status;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mappings = self.mappings.lock().unwrap().clone();
+        for mapping in mappings {
+            if let MappingStatus::Mapped {
+                external_port,
+                method,
+            } = mapping.status
+            {
+                // the gateway drops them once they expire anyway
+                let _ = self.unmap(method, mapping.protocol, mapping.port, external_port);
+            }
+        }
+    }
+
+    /// external port, method and lifetime in seconds of the mapping
+    fn map(&mut self, protocol: Protocol, port: u16) -> Result<(u16, MappingMethod, u32)> {
+        let methods = match self.method {
+            Some(method) => vec![method],
+            None => vec![
+                MappingMethod::Pcp,
+                MappingMethod::NatPmp,
+                MappingMethod::Upnp,
+            ],
+        };
+        let mut errors = vec![];
+        for method in methods {
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let mapped = match method {
+                MappingMethod::Pcp => self.pcp(protocol, port, LIFETIME),
+                MappingMethod::NatPmp => self.nat_pmp(protocol, port, port, LIFETIME),
+                MappingMethod::Upnp => self.upnp_map(protocol, port),
+            };
+            match mapped {
+                Ok((external_port, lifetime)) => {
+                    self.method = Some(method);
+                    return Ok((external_port, method, lifetime));
+                }
+                Err(error) => errors.push(format!("{}: {:#}", method, error)),
+            }
+        }
+        // probes every method again next time
+        self.method = None;
+        bail!("{}", errors.join(", "))
+    }
+
+    fn unmap(
+        &mut self,
+        method: MappingMethod,
+        protocol: Protocol,
+        port: u16,
+        external_port: u16,
+    ) -> Result<()> {
+        match method {
+            MappingMethod::Pcp => self.pcp(protocol, port, 0).map(drop),
+            MappingMethod::NatPmp => self.nat_pmp(protocol, port, 0, 0).map(drop),
+            MappingMethod::Upnp => {
+                let service = self.upnp_service()?;
+                soap(
+                    &service,
+                    "DeletePortMapping",
+                    &[
+                        ("NewRemoteHost", String::new()),
+                        ("NewExternalPort", external_port.to_string()),
+                        ("NewProtocol", protocol.upnp_name().to_string()),
+                    ],
+                )
+            }
+        }
+    }
+
+    fn pmp_socket(&self) -> Result<UdpSocket> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(self.gateway.pmp)?;
+        Ok(socket)
+    }
+
+    fn pcp(&self, protocol: Protocol, port: u16, lifetime: u32) -> Result<(u16, u32)> {
+        let request = pcp_request(protocol, port, lifetime, self.gateway.local_ip, &self.nonce);
+        exchange(&self.pmp_socket()?, &request, |response| {
+            parse_pcp_response(response, protocol, port, &self.nonce)
+        })
+    }
+
+    fn nat_pmp(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<(u16, u32)> {
+        let request = pmp_request(protocol, port, external_port, lifetime);
+        exchange(&self.pmp_socket()?, &request, |response| {
+            parse_pmp_response(response, protocol, port)
+        })
+    }
+
+    fn upnp_map(&mut self, protocol: Protocol, port: u16) -> Result<(u16, u32)> {
+        let service = self.upnp_service()?;
+        let mapping = |lease: u32| {
+            soap(
+                &service,
+                "AddPortMapping",
+                &[
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", port.to_string()),
+                    ("NewProtocol", protocol.upnp_name().to_string()),
+                    ("NewInternalPort", port.to_string()),
+                    ("NewInternalClient", self.gateway.local_ip.to_string()),
+                    ("NewEnabled", "1".to_string()),
+                    ("NewPortMappingDescription", DESCRIPTION.to_string()),
+                    ("NewLeaseDuration", lease.to_string()),
+                ],
+            )
+        };
+        // some gateways only take permanent mappings, still renewed in case
+        // the gateway restarts
+        match mapping(LIFETIME) {
+            Ok(()) => Ok((port, LIFETIME)),
+            Err(_) => mapping(0).map(|()| (port, LIFETIME)),
+        }
+    }
+
+    fn upnp_service(&mut self) -> Result<UpnpService> {
+        if let Some(service) = &self.upnp {
+            return Ok(service.clone());
+        }
+        let location = ssdp_search(self.gateway.ssdp)?;
+        let description = tracker::http_get(&location)
+            .with_context(|| format!("fetching the description at {}", location))?;
+        let service = parse_description(&String::from_utf8_lossy(&description), &location)
+            .ok_or_else(|| anyhow!("{} can't map ports", location))?;
+        self.upnp = Some(service.clone());
+        Ok(service)
+    }
+}
+
+/// sends `request` until `parse` takes one of the answers, waiting twice as
+/// long after each try
+fn exchange<T>(
+    socket: &UdpSocket,
+    request: &[u8],
+    mut parse: impl FnMut(&[u8]) -> Result<Option<T>>,
+) -> Result<T> {
+    let mut buffer = [0; 1100];
+    let mut wait = FIRST_WAIT;
+    for _ in 0..TRIES {
+        socket.send(request)?;
+        let deadline = Instant::now() + wait;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(left))?;
+            match socket.recv(&mut buffer) {
+                Ok(length) => {
+                    if let Some(answer) = parse(&buffer[..length])? {
+                        return Ok(answer);
+                    }
+                }
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+        wait *= 2;
+    }
+    bail!("no answer from the gateway")
+}
+
+/// NAT-PMP mapping request (RFC 6886), a lifetime of 0 deletes the mapping
+fn pmp_request(protocol: Protocol, port: u16, external_port: u16, lifetime: u32) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[1] = protocol.pmp_opcode();
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+/// external port and lifetime of the mapping, None for answers to other
+/// requests
+fn parse_pmp_response(
+    response: &[u8],
+    protocol: Protocol,
+    port: u16,
+) -> Result<Option<(u16, u32)>> {
+    if response.len() < 16
+        || response[0] != 0
+        || response[1] != 128 + protocol.pmp_opcode()
+        || response[8..10] != port.to_be_bytes()
+    {
+        return Ok(None);
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        bail!("gateway refused with result {}", result);
+    }
+    Ok(Some((
+        u16::from_be_bytes([response[10], response[11]]),
+        u32::from_be_bytes(response[12..16].try_into().unwrap()),
+    )))
+}
+
+/// PCP MAP request (RFC 6887), a lifetime of 0 deletes the mapping
+fn pcp_request(
+    protocol: Protocol,
+    port: u16,
+    lifetime: u32,
+    client: Ipv4Addr,
+    nonce: &[u8; 12],
+) -> [u8; PCP_REQUEST_LENGTH] {
+    let mut request = [0; PCP_REQUEST_LENGTH];
+    request[0] = PCP_VERSION;
+    request[1] = PCP_MAP;
+    request[4..8].copy_from_slice(&lifetime.to_be_bytes());
+    request[8..24].copy_from_slice(&client.to_ipv6_mapped().octets());
+    request[24..36].copy_from_slice(nonce);
+    request[36] = protocol.number();
+    request[40..42].copy_from_slice(&port.to_be_bytes());
+    request[42..44].copy_from_slice(&port.to_be_bytes());
+    // any external address
+    request[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    request
+}
+
+fn parse_pcp_response(
+    response: &[u8],
+    protocol: Protocol,
+    port: u16,
+    nonce: &[u8; 12],
+) -> Result<Option<(u16, u32)>> {
+    // gateways only speaking NAT-PMP answer in its version
+    if response.len() >= 4 && response[0] == 0 && response[1] >= 128 {
+        bail!("PCP isn't supported");
+    }
+    if response.len() < PCP_REQUEST_LENGTH
+        || response[0] != PCP_VERSION
+        || response[1] != 128 + PCP_MAP
+        || response[24..36] != nonce[..]
+        || response[36] != protocol.number()
+        || response[40..42] != port.to_be_bytes()
+    {
+        return Ok(None);
+    }
+    if response[3] != 0 {
+        bail!("gateway refused with result {}", response[3]);
+    }
+    Ok(Some((
+        u16::from_be_bytes([response[42], response[43]]),
+        u32::from_be_bytes(response[4..8].try_into().unwrap()),
+    )))
+}
+
+/// url of the description of the first internet gateway answering
+fn ssdp_search(to: SocketAddr) -> Result<String> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_GROUP
+    );
+    socket.send_to(search.as_bytes(), to)?;
+    let deadline = Instant::now() + SSDP_TIMEOUT;
+    let mut buffer = [0; 2048];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            bail!("no UPnP gateway answered");
+        }
+        socket.set_read_timeout(Some(left))?;
+        let length = match socket.recv_from(&mut buffer) {
+            Ok((length, _)) => length,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(error) => return Err(error.into()),
+        };
+        if let Some(location) = parse_location(&String::from_utf8_lossy(&buffer[..length])) {
+            return Ok(location);
+        }
+    }
+}
+
+fn parse_location(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// the WAN connection service of a device description, its control url
+/// made absolute with the location of the description
+fn parse_description(description: &str, location: &str) -> Option<UpnpService> {
+    let element = |text: &'_ str, name: &str| -> Option<String> {
+        let start = text.find(&format!("<{}>", name))? + name.len() + 2;
+        let end = start + text[start..].find(&format!("</{}>", name))?;
+        Some(text[start..end].trim().to_string())
+    };
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = element(service, "serviceType")?;
+        if !service_type.contains(":WANIPConnection:")
+            && !service_type.contains(":WANPPPConnection:")
+        {
+            return None;
+        }
+        let control_url = element(service, "controlURL")?;
+        let control_url = if control_url.starts_with("http://") {
+            control_url
+        } else {
+            let rest = location.strip_prefix("http://").unwrap_or(location);
+            let host = rest.split('/').next().unwrap_or_default();
+            let slash = if control_url.starts_with('/') {
+                ""
+            } else {
+                "/"
+            };
+            format!("http://{}{}{}", host, slash, control_url)
+        };
+        Some(UpnpService {
+            control_url,
+            service_type,
+        })
+    })
+}
+
+fn soap(service: &UpnpService, action: &str, arguments: &[(&str, String)]) -> Result<()> {
+    let arguments: String = arguments
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        concat!(
+            "<?xml version=\"1.0\"?>",
+            "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" ",
+            "s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">",
+            "<s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        ),
+        action, service.service_type, arguments
+    );
+    let soap_action = format!("\"{}#{}\"", service.service_type, action);
+    tracker::http_post(
+        &service.control_url,
+        &[
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", &soap_action),
+        ],
+        body.as_bytes(),
+    )
+    .with_context(|| format!("{} at {}", action, service.control_url))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    fn wait_until_mapped(mapper: &PortMapper) {
+        let start = Instant::now();
+        while mapper
+            .mappings()
+            .iter()
+            .any(|mapping| mapping.status == MappingStatus::Pending)
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{:?}",
+                mapper.mappings()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn encodes_requests_and_parses_responses() -> Result<()> {
+        let request = pmp_request(Protocol::Tcp, 6881, 6881, 7200);
+        assert_eq!(
+            request,
+            [0, 2, 0, 0, 0x1a, 0xe1, 0x1a, 0xe1, 0, 0, 0x1c, 0x20]
+        );
+        let mut response = [0; 16];
+        response[1] = 130;
+        response[8..10].copy_from_slice(&6881u16.to_be_bytes());
+        response[10..12].copy_from_slice(&7000u16.to_be_bytes());
+        response[12..].copy_from_slice(&3600u32.to_be_bytes());
+        assert_eq!(
+            parse_pmp_response(&response, Protocol::Tcp, 6881)?,
+            Some((7000, 3600))
+        );
+        assert_eq!(parse_pmp_response(&response, Protocol::Udp, 6881)?, None);
+        response[3] = 2;
+        assert!(parse_pmp_response(&response, Protocol::Tcp, 6881).is_err());
+
+        let nonce = [7; 12];
+        let client = Ipv4Addr::new(10, 0, 0, 2);
+        let request = pcp_request(Protocol::Udp, 6881, 7200, client, &nonce);
+        assert_eq!(request[..2], [2, 1]);
+        assert_eq!(request[8..24], client.to_ipv6_mapped().octets());
+        assert_eq!(request[36], 17);
+        // answers repeat the request with the assigned port and lifetime
+        let mut response = request;
+        response[1] = 129;
+        response[42..44].copy_from_slice(&7001u16.to_be_bytes());
+        assert_eq!(
+            parse_pcp_response(&response, Protocol::Udp, 6881, &nonce)?,
+            Some((7001, 7200))
+        );
+        assert_eq!(
+            parse_pcp_response(&response, Protocol::Udp, 6881, &[8; 12])?,
+            None
+        );
+        assert!(parse_pcp_response(&[0, 129, 0, 1], Protocol::Udp, 6881, &nonce).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn finds_the_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                      eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000\n";
+        let expected = match cfg!(target_endian = "little") {
+            true => Ipv4Addr::new(192, 168, 0, 1),
+            false => Ipv4Addr::new(1, 0, 168, 192),
+        };
+        assert_eq!(parse_default_route(routes), Some(expected));
+        assert_eq!(parse_default_route("Iface\tDestination\n"), None);
+    }
+
+    #[test]
+    fn maps_with_nat_pmp_and_unmaps_when_dropped() -> Result<()> {
+        // a gateway without PCP
+        let gateway = UdpSocket::bind("127.0.0.1:0")?;
+        gateway.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut mapper = Some(PortMapper::with_gateway(
+            6881,
+            Gateway {
+                pmp: gateway.local_addr()?,
+                ssdp: "127.0.0.1:9".parse()?,
+                local_ip: Ipv4Addr::LOCALHOST,
+            },
+        ));
+        let mut lifetimes = vec![];
+        let mut dropping = None;
+        let mut buffer = [0; 100];
+        while lifetimes.len() < 4 {
+            let (length, from) = gateway.recv_from(&mut buffer)?;
+            let request = &buffer[..length];
+            let mut response = [0; 16];
+            response[1] = 128 + request[1];
+            if request[0] == PCP_VERSION {
+                // unsupported version
+                response[3] = 1;
+                gateway.send_to(&response[..4], from)?;
+                continue;
+            }
+            let lifetime = u32::from_be_bytes(request[8..12].try_into().unwrap());
+            response[8..10].copy_from_slice(&request[4..6]);
+            response[10..12].copy_from_slice(&7000u16.to_be_bytes());
+            response[12..].copy_from_slice(&lifetime.to_be_bytes());
+            gateway.send_to(&response, from)?;
+            lifetimes.push(lifetime);
+
+            if lifetimes.len() == 2 {
+                let mapper = mapper.take().unwrap();
+                wait_until_mapped(&mapper);
+                for mapping in mapper.mappings() {
+                    assert_eq!(
+                        mapping.status,
+                        MappingStatus::Mapped {
+                            external_port: 7000,
+                            method: MappingMethod::NatPmp
+                        }
+                    );
+                }
+                assert_eq!(
+                    mapper.mappings()[0].to_string(),
+                    "port 6881/tcp: mapped to 7000 with NAT-PMP"
+                );
+                // waits for the deletions, answered here
+                dropping = Some(thread::spawn(move || drop(mapper)));
+            }
+        }
+        dropping.unwrap().join().unwrap();
+        assert_eq!(lifetimes, [LIFETIME, LIFETIME, 0, 0]);
+        Ok(())
+    }
+
+    fn read_request(stream: &mut TcpStream) -> Result<String> {
+        let mut request = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            let length = stream.read(&mut buffer)?;
+            request.extend_from_slice(&buffer[..length]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let body = text[..end]
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                if request.len() >= end + 4 + body {
+                    return Ok(text);
+                }
+            }
+            if length == 0 {
+                bail!("request cut short");
+            }
+        }
+    }
+
+    #[test]
+    fn maps_with_upnp() -> Result<()> {
+        let ssdp = UdpSocket::bind("127.0.0.1:0")?;
+        ssdp.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let http = TcpListener::bind("127.0.0.1:0")?;
+        // nothing answers NAT-PMP and PCP
+        let closed = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+        let mut mapper = Some(PortMapper::with_gateway(
+            6881,
+            Gateway {
+                pmp: closed,
+                ssdp: ssdp.local_addr()?,
+                local_ip: Ipv4Addr::LOCALHOST,
+            },
+        ));
+
+        let mut buffer = [0; 1024];
+        let (length, from) = ssdp.recv_from(&mut buffer)?;
+        assert!(buffer[..length].starts_with(b"M-SEARCH"));
+        let answer = format!(
+            "HTTP/1.1 200 OK\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n",
+            http.local_addr()?
+        );
+        ssdp.send_to(answer.as_bytes(), from)?;
+
+        let description = r#"<?xml version="1.0"?>
+            <root><device><serviceList>
+              <service>
+                <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+                <controlURL>/l3f</controlURL>
+              </service>
+              <service>
+                <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                <controlURL>ctl/IPConn</controlURL>
+              </service>
+            </serviceList></device></root>"#;
+        let mut requests = vec![];
+        let mut dropping = None;
+        while requests.len() < 5 {
+            let (mut stream, _) = http.accept()?;
+            let request = read_request(&mut stream)?;
+            let body = match request.starts_with("GET") {
+                true => description,
+                false => "",
+            };
+            stream.write_all(format!("HTTP/1.0 200 OK\r\n\r\n{}", body).as_bytes())?;
+            drop(stream);
+            requests.push(request);
+
+            if requests.len() == 3 {
+                let mapper = mapper.take().unwrap();
+                wait_until_mapped(&mapper);
+                assert_eq!(
+                    mapper.mappings()[1].status,
+                    MappingStatus::Mapped {
+                        external_port: 6881,
+                        method: MappingMethod::Upnp
+                    }
+                );
+                dropping = Some(thread::spawn(move || drop(mapper)));
+            }
+        }
+        dropping.unwrap().join().unwrap();
+
+        assert!(requests[0].starts_with("GET /rootDesc.xml "));
+        let service = "urn:schemas-upnp-org:service:WANIPConnection:1";
+        for (request, action, protocol) in [
+            (&requests[1], "AddPortMapping", "TCP"),
+            (&requests[2], "AddPortMapping", "UDP"),
+            (&requests[3], "DeletePortMapping", "TCP"),
+            (&requests[4], "DeletePortMapping", "UDP"),
+        ] {
+            assert!(request.starts_with("POST /ctl/IPConn "), "{}", request);
+            assert!(request.contains(&format!("SOAPAction: \"{}#{}\"", service, action)));
+            assert!(request.contains(&format!("<NewProtocol>{}</NewProtocol>", protocol)));
+        }
+        assert!(requests[1].contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+        Ok(())
+    }
+}
@@ -0,0 +1,600 @@
+//! The `torrent_rs` Python module, over `Session` and `Metainfo`, for
+//! scripts to drive the engine:
+//!
+//! ```text
+//! import torrent_rs
+//!
+//! with torrent_rs.Session("config.toml") as session:
+//!     session.on_event(lambda event: print(event["type"]))
+//!     session.add_torrent("debian.iso.torrent", save_path="/data")
+//!     for torrents in session.progress():
+//!         for stats in torrents:
+//!             print(stats.name, stats.state, f"{stats.progress:.0%}")
+//! ```
+//!
+//! Built into a wheel by maturin with pyproject.toml, which turns on
+//! `pyo3/extension-module` for Python to link it and asks cargo for the
+//! cdylib the crate doesn't otherwise build. The session only runs
+//! while it is polled, by `poll` or `progress`, and is used by the thread
+//! that made it.
+
+// from the code #[pymethods] generates around the PyResults
+#![allow(clippy::useless_conversion)]
+
+use crate::config::Config;
+use crate::events::SessionEvent;
+use crate::json::Json;
+use crate::metainfo::{to_hex, Metainfo};
+use crate::session::{AddOptions, Session};
+use crate::torrent::{TorrentHandle, TorrentState};
+use pyo3::exceptions::{PyKeyError, PyOSError, PyRuntimeError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+/// between ticks of the session, see `Session::tick`
+const TICK: Duration = Duration::from_secs(1);
+/// between polls while `progress` waits
+const POLL: Duration = Duration::from_millis(100);
+
+/// OSError when a file or socket failed, RuntimeError otherwise
+fn error(error: anyhow::Error) -> PyErr {
+    let message = format!("{:#}", error);
+    match error.chain().any(|cause| cause.is::<io::Error>()) {
+        true => PyOSError::new_err(message),
+        false => PyRuntimeError::new_err(message),
+    }
+}
+
+/// dicts, lists and the like, whole numbers as ints
+fn to_python(py: Python<'_>, json: &Json) -> PyResult<PyObject> {
+    Ok(match json {
+        Json::Null => py.None(),
+        Json::Bool(value) => value.into_py(py),
+        Json::Integer(value) => value.into_py(py),
+        Json::Number(value) if value.fract() == 0.0 && value.abs() < 2f64.powi(53) => {
+            (*value as i64).into_py(py)
+        }
+        Json::Number(value) => value.into_py(py),
+        Json::String(text) => text.into_py(py),
+        Json::Array(values) => {
+            let values = values
+                .iter()
+                .map(|value| to_python(py, value))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyList::new_bound(py, values).into_py(py)
+        }
+        Json::Object(entries) => {
+            let dict = PyDict::new_bound(py);
+            for (key, value) in entries {
+                dict.set_item(key, to_python(py, value)?)?;
+            }
+            dict.into_py(py)
+        }
+    })
+}
+
+/// A .torrent file, `Metainfo(data)` from its bytes or `Metainfo.load(path)`
+#[pyclass(name = "Metainfo", module = "torrent_rs")]
+#[derive(Clone)]
+pub struct PyMetainfo {
+    metainfo: Metainfo,
+}
+
+#[pymethods]
+impl PyMetainfo {
+    #[new]
+    fn new(data: Vec<u8>) -> PyResult<Self> {
+        let metainfo = Metainfo::from_bytes(data).map_err(error)?;
+        Ok(Self { metainfo })
+    }
+
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        let data = std::fs::read(path)?;
+        Self::new(data)
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        &self.metainfo.info.name
+    }
+
+    #[getter]
+    fn info_hash(&self) -> String {
+        self.metainfo.info_hash_hex()
+    }
+
+    #[getter]
+    fn info_hash_v2(&self) -> Option<String> {
+        self.metainfo.info_hash_v2.map(|hash| to_hex(&hash))
+    }
+
+    /// "v1", "v2" or "hybrid"
+    #[getter]
+    fn version(&self) -> &'static str {
+        self.metainfo.version()
+    }
+
+    /// of the files, without the padding
+    #[getter]
+    fn total_length(&self) -> u64 {
+        self.metainfo.size()
+    }
+
+    #[getter]
+    fn piece_length(&self) -> u64 {
+        self.metainfo.info.piece_length
+    }
+
+    #[getter]
+    fn piece_count(&self) -> usize {
+        self.metainfo.info.piece_count()
+    }
+
+    #[getter]
+    fn private(&self) -> bool {
+        self.metainfo.info.private
+    }
+
+    #[getter]
+    fn trackers(&self) -> Vec<String> {
+        self.metainfo.trackers()
+    }
+
+    /// (path, length) of every file, the paths relative to the save path
+    #[getter]
+    fn files(&self) -> Vec<(PathBuf, u64)> {
+        self.metainfo.paths()
+    }
+
+    fn magnet_link(&self) -> String {
+        self.metainfo.magnet_link()
+    }
+
+    /// everything above, as `torrent_rs show --json` prints it
+    fn to_dict(&self, py: Python<'_>) -> PyResult<PyObject> {
+        to_python(py, &self.metainfo.to_json())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Metainfo(name={:?}, info_hash={:?})",
+            self.metainfo.info.name,
+            self.metainfo.info_hash_hex()
+        )
+    }
+}
+
+/// What a torrent is doing, from `Torrent.stats`
+#[pyclass(name = "TorrentStats", module = "torrent_rs", get_all)]
+pub struct PyTorrentStats {
+    info_hash: String,
+    name: String,
+    /// as the CLI prints it, like "downloading" or "checking files"
+    state: String,
+    /// between 0 and 1, of the selected files
+    progress: f64,
+    /// bytes per second
+    download_rate: f64,
+    upload_rate: f64,
+    downloaded: u64,
+    uploaded: u64,
+    size: u64,
+    peers: usize,
+    pieces: usize,
+    piece_count: usize,
+}
+
+#[pymethods]
+impl PyTorrentStats {
+    fn __repr__(&self) -> String {
+        format!(
+            "TorrentStats(name={:?}, state={:?}, progress={:.3})",
+            self.name, self.state, self.progress
+        )
+    }
+}
+
+/// A torrent of a session, still usable once removed from it
+#[pyclass(name = "Torrent", module = "torrent_rs")]
+#[derive(Clone)]
+pub struct PyTorrent {
+    torrent: TorrentHandle,
+}
+
+#[pymethods]
+impl PyTorrent {
+    #[getter]
+    fn info_hash(&self) -> String {
+        to_hex(&self.torrent.info_hash())
+    }
+
+    #[getter]
+    fn name(&self) -> String {
+        self.torrent.root_name()
+    }
+
+    #[getter]
+    fn state(&self) -> String {
+        self.torrent.state().to_string()
+    }
+
+    #[getter]
+    fn metainfo(&self) -> PyMetainfo {
+        PyMetainfo {
+            metainfo: self.torrent.metainfo(),
+        }
+    }
+
+    fn stats(&self) -> PyTorrentStats {
+        let torrent = &self.torrent;
+        let stats = torrent.stats();
+        let (download_rate, upload_rate) = torrent.rates();
+        PyTorrentStats {
+            info_hash: self.info_hash(),
+            name: torrent.root_name(),
+            state: torrent.state().to_string(),
+            progress: stats.progress,
+            download_rate,
+            upload_rate,
+            downloaded: stats.downloaded,
+            uploaded: stats.uploaded,
+            size: torrent.metainfo().size(),
+            peers: stats.peers,
+            pieces: stats.pieces,
+            piece_count: stats.piece_count,
+        }
+    }
+
+    fn pause(&self) {
+        self.torrent.pause();
+    }
+
+    fn resume(&self) {
+        self.torrent.resume();
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Torrent(name={:?}, info_hash={:?})",
+            self.torrent.root_name(),
+            self.info_hash()
+        )
+    }
+}
+
+/// A session with its torrents, `Session(config_path)` with the TOML
+/// config at config_path or the defaults without one, see `config::Config`
+#[pyclass(name = "Session", module = "torrent_rs", unsendable)]
+pub struct PySession {
+    /// None once shut down
+    session: Option<Session>,
+    events: Receiver<SessionEvent>,
+    callbacks: Vec<PyObject>,
+    /// the magnet links added, until their metadata arrives
+    magnets: Vec<[u8; 20]>,
+    last_tick: Instant,
+}
+
+impl PySession {
+    fn session(&mut self) -> PyResult<&mut Session> {
+        self.session.as_mut().ok_or_else(shut_down)
+    }
+
+    /// whether a torrent is still on its way to seeding, as opposed to
+    /// seeding, paused or stopped by an error
+    fn busy(&mut self) -> PyResult<bool> {
+        let session = self.session.as_ref().ok_or_else(shut_down)?;
+        self.magnets
+            .retain(|hash| session.state(hash) == Some(TorrentState::FetchingMetadata));
+        Ok(!self.magnets.is_empty()
+            || session.torrents().any(|torrent| {
+                !matches!(
+                    torrent.state(),
+                    TorrentState::Seeding | TorrentState::Paused | TorrentState::Errored
+                )
+            }))
+    }
+}
+
+fn shut_down() -> PyErr {
+    PyRuntimeError::new_err("the session is shut down")
+}
+
+#[pymethods]
+impl PySession {
+    #[new]
+    #[pyo3(signature = (config_path=None))]
+    fn new(config_path: Option<PathBuf>) -> PyResult<Self> {
+        let config = match config_path {
+            Some(path) => Config::load(path).map_err(error)?,
+            None => Config::default(),
+        };
+        let session = Session::new(config).map_err(error)?;
+        Ok(Self {
+            events: session.subscribe(),
+            session: Some(session),
+            callbacks: vec![],
+            magnets: vec![],
+            last_tick: Instant::now(),
+        })
+    }
+
+    /// the port peers connect to
+    #[getter]
+    fn listen_port(&mut self) -> PyResult<u16> {
+        Ok(self.session()?.listen_port())
+    }
+
+    /// adds a .torrent, from its path or a Metainfo, downloading to
+    /// save_path or to the save path of the session
+    #[pyo3(signature = (torrent, save_path=None, paused=false))]
+    fn add_torrent(
+        &mut self,
+        torrent: &Bound<'_, PyAny>,
+        save_path: Option<PathBuf>,
+        paused: bool,
+    ) -> PyResult<PyTorrent> {
+        let metainfo = match torrent.extract::<PyMetainfo>() {
+            Ok(metainfo) => metainfo,
+            Err(_) => PyMetainfo::load(torrent.extract()?)?,
+        };
+        let options = AddOptions {
+            save_path,
+            paused,
+            ..AddOptions::default()
+        };
+        let torrent = self
+            .session()?
+            .add_torrent_with(metainfo.metainfo, options)
+            .map_err(error)?;
+        Ok(PyTorrent { torrent })
+    }
+
+    /// adds a magnet link, its torrent is added by `poll` once its metadata
+    /// is fetched. Returns its info hash.
+    #[pyo3(signature = (uri, save_path=None, paused=false))]
+    fn add_magnet(
+        &mut self,
+        uri: &str,
+        save_path: Option<PathBuf>,
+        paused: bool,
+    ) -> PyResult<String> {
+        let options = AddOptions {
+            save_path,
+            paused,
+            ..AddOptions::default()
+        };
+        let hash = self
+            .session()?
+            .add_magnet_with(uri, options)
+            .map_err(error)?;
+        self.magnets.push(hash);
+        Ok(to_hex(&hash))
+    }
+
+    /// in the order they were added, without the magnet links waiting for
+    /// metadata
+    fn torrents(&mut self) -> PyResult<Vec<PyTorrent>> {
+        Ok(self
+            .session()?
+            .torrents()
+            .map(|torrent| PyTorrent {
+                torrent: torrent.clone(),
+            })
+            .collect())
+    }
+
+    /// the torrent named id, or whose info hash starts with it, KeyError
+    /// when there isn't exactly one
+    fn find(&mut self, id: &str) -> PyResult<PyTorrent> {
+        let torrent = self
+            .session()?
+            .find(id)
+            .map_err(|error| PyKeyError::new_err(format!("{:#}", error)))?;
+        Ok(PyTorrent {
+            torrent: torrent.clone(),
+        })
+    }
+
+    #[pyo3(signature = (torrent, delete_data=false))]
+    fn remove(&mut self, torrent: PyRef<'_, PyTorrent>, delete_data: bool) -> PyResult<()> {
+        self.session()?
+            .remove(&torrent.torrent, delete_data)
+            .map_err(error)
+    }
+
+    /// calls callback from `poll` with each event of the session as a dict,
+    /// like {"type": "torrent_finished", "info_hash": ".."}. Returns it, to
+    /// be used as a decorator.
+    fn on_event(&mut self, py: Python<'_>, callback: PyObject) -> PyObject {
+        self.callbacks.push(callback.clone_ref(py));
+        callback
+    }
+
+    /// answers the api, adds the magnet links whose metadata arrived, ticks
+    /// the session once a second and passes the events since the last poll
+    /// to the callbacks, whose exceptions are raised from here. To be called
+    /// every 100 ms or so.
+    fn poll(&mut self, py: Python<'_>) -> PyResult<()> {
+        let session = self.session()?;
+        session.poll_api();
+        let added = session.poll_magnets();
+        if self.last_tick.elapsed() >= TICK {
+            self.last_tick = Instant::now();
+            self.session()?.tick().map_err(error)?;
+        }
+        for event in self.events.try_iter().collect::<Vec<_>>() {
+            let event = to_python(py, &event.to_json())?;
+            for callback in &self.callbacks {
+                callback.call1(py, (event.clone_ref(py),))?;
+            }
+        }
+        added.map_err(error)?;
+        Ok(())
+    }
+
+    /// polls the session and yields the stats of its torrents every
+    /// interval seconds, until none of them is on its way to seeding
+    #[pyo3(signature = (interval=1.0))]
+    fn progress(slf: Py<Self>, interval: f64) -> PyResult<Progress> {
+        let interval = Duration::try_from_secs_f64(interval)
+            .map_err(|error| PyRuntimeError::new_err(error.to_string()))?;
+        Ok(Progress {
+            session: slf,
+            interval,
+        })
+    }
+
+    /// saves the state of the session and tells the trackers its torrents
+    /// stopped, waiting timeout seconds at most
+    #[pyo3(signature = (timeout=5.0))]
+    fn shutdown(&mut self, py: Python<'_>, timeout: f64) -> PyResult<()> {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|error| PyRuntimeError::new_err(error.to_string()))?;
+        match self.session.take() {
+            Some(session) => py
+                .allow_threads(|| session.shutdown(timeout))
+                .map_err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _kind: PyObject,
+        _value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<()> {
+        self.shutdown(py, 5.0)
+    }
+}
+
+/// The iterator of `Session.progress`
+#[pyclass(module = "torrent_rs", unsendable)]
+pub struct Progress {
+    session: Py<PySession>,
+    interval: Duration,
+}
+
+#[pymethods]
+impl Progress {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<Vec<PyTorrentStats>>> {
+        if !self.session.borrow_mut(py).busy()? {
+            return Ok(None);
+        }
+        let until = Instant::now() + self.interval;
+        loop {
+            self.session.borrow_mut(py).poll(py)?;
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            // lets other Python threads and Ctrl-C through
+            py.allow_threads(|| std::thread::sleep(left.min(POLL)));
+            py.check_signals()?;
+        }
+        let torrents = self.session.borrow_mut(py).torrents()?;
+        Ok(Some(torrents.iter().map(PyTorrent::stats).collect()))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "torrent_rs")]
+fn init(torrent_rs: &Bound<'_, PyModule>) -> PyResult<()> {
+    torrent_rs.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    torrent_rs.add_class::<PySession>()?;
+    torrent_rs.add_class::<PyTorrent>()?;
+    torrent_rs.add_class::<PyTorrentStats>()?;
+    torrent_rs.add_class::<PyMetainfo>()?;
+    torrent_rs.add_class::<Progress>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drives_a_session_from_python() -> PyResult<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_python");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let config = dir.join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                "listen_port = 0\nsave_path = {:?}\nlsd = false\ntrackers = false\n\
+                 port_mapping = false\n[dht]\nenabled = false\n",
+                dir.join("downloads").display().to_string()
+            ),
+        )?;
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new_bound(py);
+            globals.set_item("torrent_rs", pyo3::wrap_pymodule!(init)(py))?;
+            globals.set_item("config", config.display().to_string())?;
+            py.run_bound(
+                r#"
+metainfo = torrent_rs.Metainfo.load("file1.txt.torrent")
+assert metainfo.name == "file1.txt"
+assert metainfo.files == [("file1.txt", 12)]
+assert metainfo.to_dict()["piece_count"] == 1
+assert metainfo.magnet_link().startswith("magnet:?xt=urn:btih:" + metainfo.info_hash)
+try:
+    torrent_rs.Metainfo(b"nothing")
+    assert False
+except RuntimeError:
+    pass
+
+events = []
+with torrent_rs.Session(config) as session:
+    assert session.listen_port != 0
+    session.on_event(events.append)
+    torrent = session.add_torrent(metainfo, paused=True)
+    session.poll()
+    assert "torrent_added" in [event["type"] for event in events], events
+    assert session.find("file1.txt").info_hash == metainfo.info_hash
+    try:
+        session.find("nothing")
+        assert False
+    except KeyError:
+        pass
+    try:
+        session.add_torrent("missing.torrent")
+        assert False
+    except OSError:
+        pass
+    stats = torrent.stats()
+    assert (stats.state, stats.size, stats.piece_count) == ("paused", 12, 1)
+    # nothing is on its way to seeding while paused
+    assert list(session.progress(interval=0.1)) == []
+    session.remove(torrent)
+    assert session.torrents() == []
+try:
+    session.poll()
+    assert False
+except RuntimeError:
+    pass
+"#,
+                Some(&globals),
+                None,
+            )
+        })?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use crate::torrent::Torrent;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// how long a torrent is active before it can be found slow, to give it
+/// time to find peers
+const SLOW_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueSettings {
+    pub max_active_downloads: usize,
+    pub max_active_seeds: usize,
+    /// downloads and seeds together
+    pub max_active_total: usize,
+    /// active torrents downloading and uploading slower than this many
+    /// bytes/s keep running without taking a slot, None counts them all
+    pub slow_torrent_rate: Option<u64>,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            max_active_total: 8,
+            slow_torrent_rate: None,
+        }
+    }
+}
+
+/// Orders the torrents of a session, only the first unfinished and finished
+/// torrents up to the limits are active, the others wait for a slot to free up.
+/// `update` has to be called when a torrent finishes so the next one in line
+/// gets promoted, `Session::tick` does. Paused torrents don't take a slot.
+#[derive(Default)]
+pub struct Queue {
+    settings: QueueSettings,
+    torrents: Vec<Queued>,
+}
+
+struct Queued {
+    torrent: Arc<Mutex<Torrent>>,
+    active_since: Option<Instant>,
+}
+
+impl Queue {
+    pub fn new(settings: QueueSettings) -> Self {
+        Self {
+            settings,
+            torrents: vec![],
+        }
+    }
+
+    pub fn settings(&self) -> QueueSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: QueueSettings) {
+        self.settings = settings;
+        self.update();
+    }
+
+    pub fn len(&self) -> usize {
+        self.torrents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.torrents.is_empty()
+    }
+
+    /// added torrents go to the bottom of the queue
+    pub fn push(&mut self, torrent: Arc<Mutex<Torrent>>) {
+        self.torrents.push(Queued {
+            torrent,
+            active_since: None,
+        });
+        self.update();
+    }
+
+    pub fn remove(&mut self, torrent: &Arc<Mutex<Torrent>>) {
+        self.torrents
+            .retain(|queued| !Arc::ptr_eq(&queued.torrent, torrent));
+        self.update();
+    }
+
+    pub fn position(&self, torrent: &Arc<Mutex<Torrent>>) -> Option<usize> {
+        self.torrents
+            .iter()
+            .position(|queued| Arc::ptr_eq(&queued.torrent, torrent))
+    }
+
+    /// moves the torrent to the given position, clamped to the queue
+    pub fn move_to(&mut self, torrent: &Arc<Mutex<Torrent>>, position: usize) {
+        if let Some(current) = self.position(torrent) {
+            let torrent = self.torrents.remove(current);
+            let position = position.min(self.torrents.len());
+            self.torrents.insert(position, torrent);
+            self.update();
+        }
+    }
+
+    pub fn move_top(&mut self, torrent: &Arc<Mutex<Torrent>>) {
+        self.move_to(torrent, 0);
+    }
+
+    pub fn move_up(&mut self, torrent: &Arc<Mutex<Torrent>>) {
+        if let Some(position) = self.position(torrent) {
+            self.move_to(torrent, position.saturating_sub(1));
+        }
+    }
+
+    pub fn move_down(&mut self, torrent: &Arc<Mutex<Torrent>>) {
+        if let Some(position) = self.position(torrent) {
+            self.move_to(torrent, position + 1);
+        }
+    }
+
+    pub fn move_bottom(&mut self, torrent: &Arc<Mutex<Torrent>>) {
+        self.move_to(torrent, usize::MAX);
+    }
+
+    /// hands the download and seed slots to the torrents in queue order
+    pub fn update(&mut self) {
+        self.update_at(Instant::now());
+    }
+
+    /// as `update`, slow torrents are those active since before
+    /// `now - SLOW_GRACE`
+    pub fn update_at(&mut self, now: Instant) {
+        let settings = self.settings;
+        let mut downloads = 0;
+        let mut seeds = 0;
+        let mut total = 0;
+        for queued in &mut self.torrents {
+            let mut torrent = queued.torrent.lock().unwrap();
+            let slow = settings.slow_torrent_rate.is_some_and(|rate| {
+                let since = queued.active_since.unwrap_or(now);
+                now.duration_since(since) >= SLOW_GRACE
+                    && torrent.download_rate() < rate as f64
+                    && torrent.upload_rate() < rate as f64
+            });
+            let (count, max) = match torrent.is_finished() {
+                true => (&mut seeds, settings.max_active_seeds),
+                false => (&mut downloads, settings.max_active_downloads),
+            };
+            let active = if torrent.is_paused() {
+                false
+            } else if slow && torrent.is_active() {
+                true
+            } else if *count < max && total < settings.max_active_total {
+                *count += 1;
+                total += 1;
+                true
+            } else {
+                false
+            };
+            torrent.set_active(active);
+            queued.active_since = match active {
+                true => queued.active_since.or(Some(now)),
+                false => None,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::Metainfo;
+    use anyhow::Result;
+
+    fn torrent(finished: bool) -> Result<Arc<Mutex<Torrent>>> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let mut torrent = Torrent::new(metainfo, std::env::temp_dir());
+        if finished {
+            torrent.piece_verified(0);
+        }
+        Ok(Arc::new(Mutex::new(torrent)))
+    }
+
+    fn active(torrents: &[&Arc<Mutex<Torrent>>]) -> Vec<bool> {
+        torrents
+            .iter()
+            .map(|torrent| torrent.lock().unwrap().is_active())
+            .collect()
+    }
+
+    fn queue(settings: QueueSettings, torrents: &[&Arc<Mutex<Torrent>>]) -> Queue {
+        let mut queue = Queue::new(settings);
+        for torrent in torrents {
+            queue.push(Arc::clone(torrent));
+        }
+        queue
+    }
+
+    #[test]
+    fn slots_follow_queue_order() -> Result<()> {
+        let mut queue = Queue::new(QueueSettings {
+            max_active_downloads: 1,
+            max_active_seeds: 1,
+            ..Default::default()
+        });
+        let (a, b, c) = (torrent(false)?, torrent(false)?, torrent(true)?);
+        let d = torrent(true)?;
+        for torrent in [&a, &b, &c, &d] {
+            queue.push(Arc::clone(torrent));
+        }
+        assert_eq!(active(&[&a, &b, &c, &d]), [true, false, true, false]);
+
+        queue.move_up(&b);
+        queue.move_bottom(&c);
+        assert_eq!(queue.position(&b), Some(0));
+        assert_eq!(queue.position(&c), Some(3));
+        assert_eq!(active(&[&a, &b, &c, &d]), [false, true, false, true]);
+
+        // the next download is promoted once the active one finishes
+        b.lock().unwrap().piece_verified(0);
+        queue.update();
+        assert_eq!(active(&[&a, &b, &c, &d]), [true, true, false, false]);
+
+        queue.remove(&b);
+        queue.move_top(&c);
+        assert_eq!(active(&[&a, &c, &d]), [true, true, false]);
+        Ok(())
+    }
+
+    #[test]
+    fn total_limit() -> Result<()> {
+        let (a, b, c) = (torrent(false)?, torrent(false)?, torrent(true)?);
+        let d = torrent(true)?;
+        let settings = QueueSettings {
+            max_active_downloads: 2,
+            max_active_seeds: 2,
+            max_active_total: 3,
+            slow_torrent_rate: None,
+        };
+        let mut queue = queue(settings, &[&a, &b, &c, &d]);
+        assert_eq!(active(&[&a, &b, &c, &d]), [true, true, true, false]);
+
+        a.lock().unwrap().set_paused(true);
+        queue.update();
+        assert_eq!(active(&[&a, &b, &c, &d]), [false, true, true, true]);
+        Ok(())
+    }
+
+    #[test]
+    fn slow_torrents_dont_count() -> Result<()> {
+        let (a, b, c) = (torrent(false)?, torrent(false)?, torrent(false)?);
+        let settings = QueueSettings {
+            max_active_downloads: 1,
+            slow_torrent_rate: Some(1024),
+            ..Default::default()
+        };
+        let mut queue = queue(settings, &[&a, &b, &c]);
+        let start = Instant::now();
+        queue.update_at(start);
+        assert_eq!(active(&[&a, &b, &c]), [true, false, false]);
+
+        // not a byte since its activation, the next one gets a slot too
+        queue.update_at(start + SLOW_GRACE);
+        assert_eq!(active(&[&a, &b, &c]), [true, true, false]);
+        queue.update_at(start + SLOW_GRACE + Duration::from_secs(1));
+        assert_eq!(active(&[&a, &b, &c]), [true, true, false]);
+        queue.update_at(start + SLOW_GRACE * 2);
+        assert_eq!(active(&[&a, &b, &c]), [true, true, true]);
+
+        // every torrent counts without the setting
+        queue.set_settings(QueueSettings {
+            max_active_downloads: 1,
+            ..Default::default()
+        });
+        assert_eq!(active(&[&a, &b, &c]), [true, false, false]);
+        Ok(())
+    }
+}
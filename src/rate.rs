@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+/// Transfer rate smoothed with an exponential moving average so ETAs don't
+/// jump around with every block
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    rate: f64,
+    pending: u64,
+    last_tick: Instant,
+}
+
+/// weight of the newest sample, roughly a 5 second window with 1 second ticks
+const SMOOTHING: f64 = 0.2;
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    pub fn starting_at(now: Instant) -> Self {
+        Self {
+            rate: 0.0,
+            pending: 0,
+            last_tick: now,
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.pending += bytes;
+    }
+
+    /// folds the bytes added since the last tick into the rate, meant to be
+    /// called about once a second
+    pub fn tick(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_tick);
+        if elapsed < Duration::from_millis(100) {
+            return;
+        }
+        let sample = self.pending as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            SMOOTHING * sample + (1.0 - SMOOTHING) * self.rate
+        };
+        self.pending = 0;
+        self.last_tick = now;
+    }
+
+    /// bytes per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// time to transfer `remaining` bytes at the current rate
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        if self.rate < 1.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 / self.rate))
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoothed_rate_and_eta() {
+        let start = Instant::now();
+        let mut meter = RateMeter::starting_at(start);
+        meter.add(1000);
+        meter.tick(start + Duration::from_secs(1));
+        assert_eq!(meter.rate(), 1000.0);
+
+        meter.tick(start + Duration::from_secs(2));
+        assert_eq!(meter.rate(), 800.0);
+        assert_eq!(meter.eta(1600), Some(Duration::from_secs(2)));
+        assert_eq!(RateMeter::starting_at(start).eta(10), None);
+    }
+}
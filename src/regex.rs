@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use regex::RegexBuilder;
+use std::fmt;
+
+/// A regular expression of the `regex` crate, matched in linear time, for
+/// filtering the titles of feeds. Compares and prints as its pattern.
+#[derive(Clone)]
+pub struct Regex {
+    source: String,
+    ignore_case: bool,
+    regex: regex::Regex,
+}
+
+/// of the compiled pattern, so configs can't make it huge
+const SIZE_LIMIT: usize = 1024 * 1024;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self> {
+        Self::build(pattern, false)
+    }
+
+    /// like `new` with `(?i)`
+    pub fn ignoring_case(pattern: &str) -> Result<Self> {
+        Self::build(pattern, true)
+    }
+
+    fn build(pattern: &str, ignore_case: bool) -> Result<Self> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .size_limit(SIZE_LIMIT)
+            .build()
+            .with_context(|| format!("invalid regex {}", pattern))?;
+        Ok(Self {
+            source: pattern.to_string(),
+            ignore_case,
+            regex,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// matches anywhere in `text` unless anchored
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source && self.ignore_case == other.ignore_case
+    }
+}
+
+impl Eq for Regex {}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Regex({:?})", self.source)
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_patterns() -> Result<()> {
+        let cases = [
+            ("abc", "xxabcxx", true),
+            ("^abc", "xxabc", false),
+            ("abc$", "xxabc", true),
+            ("a.c", "abc", true),
+            ("a.c", "a\nc", false),
+            ("colou?r", "color", true),
+            ("colou?r", "colouur", false),
+            ("ab*c", "ac", true),
+            ("ab+c", "ac", false),
+            ("^a{2,3}$", "aaa", true),
+            ("^a{2,3}$", "aaaa", false),
+            ("^a{2}$", "aa", true),
+            ("^a{2,}$", "aaaaa", true),
+            ("^(cat|dog)s?$", "dogs", true),
+            ("^(cat|dog)s?$", "cow", false),
+            ("^(?:ab)+$", "ababab", true),
+            (r"S\d{2}E\d{2}", "Show.S01E02.720p", true),
+            (r"\bcam\b", "Movie.CAMERA", false),
+            (r"(?i)\bcam\b", "Movie.CAM.x264", true),
+            ("[a-c]+x", "zzbcax", true),
+            ("[^0-9]$", "abc1", false),
+            (r"[\d.]+", "v1.2", true),
+            ("[]a]", "]", true),
+            ("[a-]", "-", true),
+            (r"^\W\s\S$", "! x", true),
+            ("^.*1080p.*$", "Show 1080p WEB", true),
+            ("^(a|ab)c$", "abc", true),
+            ("^(a*)*b$", "aaaaaaaaaac", false),
+            ("^a*?b$", "aaab", true),
+            (r"\.mkv$", "a.mkv", true),
+            (r"\.mkv$", "amkv", false),
+            ("", "anything", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                Regex::new(pattern)?.is_match(text),
+                expected,
+                "{} on {}",
+                pattern,
+                text
+            );
+        }
+        assert!(Regex::ignoring_case("show\\.name")?.is_match("SHOW.NAME.S01"));
+        assert!(Regex::ignoring_case("[a-z]+")?.is_match("ABC"));
+        for invalid in [
+            "(a", "a)", "[a", "*a", "a{3,2}", "a{x}", r"\q", "[z-a]", "(?=a)",
+        ] {
+            assert!(Regex::new(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,672 @@
+use crate::bencode::{Bencode, Parser};
+use crate::bitfield::Bitfield;
+use crate::metainfo::{to_hex, Metainfo};
+use crate::picker::Priority;
+use crate::seeding::{SeedLimitAction, SeedLimits};
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// State needed to restart a torrent without rechecking all of its data,
+/// stored bencoded in `<resume dir>/<info hash>.resume`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    pub pieces: Bitfield,
+    pub partial_pieces: Vec<PartialPiece>,
+    /// size and mtime of every file when the resume data was written, if they
+    /// changed since then the pieces can't be trusted anymore
+    pub files: Vec<FileStat>,
+    pub trackers: Vec<TrackerStats>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// seconds spent seeding, and without uploading, see `Torrent::seeding`
+    pub seed_time: u64,
+    pub idle_time: u64,
+    /// (file index, path relative to the root) of the files renamed by the user
+    pub renamed_files: Vec<(usize, PathBuf)>,
+    /// root folder of a multi-file torrent if it was renamed
+    pub root_name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialPiece {
+    pub piece: usize,
+    /// received blocks of the piece
+    pub blocks: Bitfield,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileStat {
+    pub size: u64,
+    /// seconds since the unix epoch
+    pub mtime: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TrackerStats {
+    pub url: String,
+    pub seeders: u64,
+    pub leechers: u64,
+    pub completed: u64,
+    /// seconds since the unix epoch
+    pub last_announce: u64,
+}
+
+impl FileStat {
+    /// missing files are reported as empty
+    pub fn read(path: &Path) -> Self {
+        match std::fs::metadata(path) {
+            Ok(metadata) => Self {
+                size: metadata.len(),
+                mtime: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |duration| duration.as_secs()),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+impl ResumeData {
+    pub fn path(dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+        dir.join(format!("{}.resume", to_hex(info_hash)))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        write_atomically(
+            &Self::path(dir, &self.info_hash),
+            &self.to_bencode().encode(),
+        )
+    }
+
+    pub fn load(dir: &Path, info_hash: &[u8; 20]) -> Result<Option<Self>> {
+        let path = Self::path(dir, info_hash);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let resume = Self::from_bencode(&Parser::new(std::fs::read(path)?).parse()?)?;
+        if &resume.info_hash != info_hash {
+            bail!("resume data belongs to another torrent");
+        }
+        Ok(Some(resume))
+    }
+
+    pub fn to_bencode(&self) -> Bencode {
+        let mut dict = HashMap::new();
+        dict.insert("info-hash".into(), self.info_hash.to_vec().into());
+        dict.insert("pieces".into(), self.pieces.as_bytes().to_vec().into());
+        dict.insert(
+            "partial".into(),
+            Bencode::List(
+                self.partial_pieces
+                    .iter()
+                    .map(|partial| {
+                        Bencode::List(vec![
+                            (partial.piece as i64).into(),
+                            (partial.blocks.len() as i64).into(),
+                            partial.blocks.as_bytes().to_vec().into(),
+                        ])
+                    })
+                    .collect(),
+            ),
+        );
+        dict.insert(
+            "files".into(),
+            Bencode::List(
+                self.files
+                    .iter()
+                    .map(|file| {
+                        Bencode::List(vec![(file.size as i64).into(), (file.mtime as i64).into()])
+                    })
+                    .collect(),
+            ),
+        );
+        dict.insert(
+            "trackers".into(),
+            Bencode::List(
+                self.trackers
+                    .iter()
+                    .map(|tracker| {
+                        let mut dict = HashMap::new();
+                        dict.insert("url".into(), tracker.url.as_str().into());
+                        dict.insert("seeders".into(), (tracker.seeders as i64).into());
+                        dict.insert("leechers".into(), (tracker.leechers as i64).into());
+                        dict.insert("completed".into(), (tracker.completed as i64).into());
+                        dict.insert(
+                            "last-announce".into(),
+                            (tracker.last_announce as i64).into(),
+                        );
+                        Bencode::Dictionary(dict)
+                    })
+                    .collect(),
+            ),
+        );
+        dict.insert("uploaded".into(), (self.uploaded as i64).into());
+        dict.insert("downloaded".into(), (self.downloaded as i64).into());
+        dict.insert("seed-time".into(), (self.seed_time as i64).into());
+        dict.insert("idle-time".into(), (self.idle_time as i64).into());
+        dict.insert("piece-count".into(), (self.pieces.len() as i64).into());
+        dict.insert(
+            "renamed-files".into(),
+            Bencode::List(
+                self.renamed_files
+                    .iter()
+                    .map(|(file, path)| {
+                        Bencode::List(vec![
+                            (*file as i64).into(),
+                            Bencode::List(
+                                path.iter()
+                                    .map(|component| component.to_string_lossy().as_ref().into())
+                                    .collect(),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            ),
+        );
+        if let Some(name) = &self.root_name {
+            dict.insert("root-name".into(), name.as_str().into());
+        }
+        Bencode::Dictionary(dict)
+    }
+
+    pub fn from_bencode(bencode: &Bencode) -> Result<Self> {
+        let info_hash = bencode
+            .get("info-hash")
+            .and_then(Bencode::as_bytes)
+            .filter(|hash| hash.len() == 20)
+            .ok_or_else(|| anyhow!("invalid info hash"))?;
+        let mut hash = [0; 20];
+        hash.copy_from_slice(info_hash);
+
+        let pieces = Bitfield::from_bytes(
+            bytes(bencode, "pieces")?.to_vec(),
+            integer(bencode, "piece-count")? as usize,
+        );
+
+        let partial_pieces = list(bencode, "partial")?
+            .iter()
+            .map(|partial| match partial.as_list() {
+                Some([Bencode::Integer(piece), Bencode::Integer(len), Bencode::Bytes(blocks)]) => {
+                    Ok(PartialPiece {
+                        piece: *piece as usize,
+                        blocks: Bitfield::from_bytes(blocks.clone(), *len as usize),
+                    })
+                }
+                _ => bail!("invalid partial piece {:?}", partial),
+            })
+            .collect::<Result<_>>()?;
+
+        let files = list(bencode, "files")?
+            .iter()
+            .map(|file| match file.as_list() {
+                Some([Bencode::Integer(size), Bencode::Integer(mtime)]) => Ok(FileStat {
+                    size: *size as u64,
+                    mtime: *mtime as u64,
+                }),
+                _ => bail!("invalid file stat {:?}", file),
+            })
+            .collect::<Result<_>>()?;
+
+        let trackers = list(bencode, "trackers")?
+            .iter()
+            .map(|tracker| {
+                Ok(TrackerStats {
+                    url: tracker
+                        .get("url")
+                        .and_then(Bencode::as_str)
+                        .ok_or_else(|| anyhow!("tracker is missing url"))?
+                        .to_string(),
+                    seeders: integer(tracker, "seeders")?,
+                    leechers: integer(tracker, "leechers")?,
+                    completed: integer(tracker, "completed")?,
+                    last_announce: integer(tracker, "last-announce")?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        // missing from resume data written before files could be renamed
+        let renamed_files = match bencode.get("renamed-files") {
+            Some(_) => list(bencode, "renamed-files")?
+                .iter()
+                .map(|renamed| match renamed.as_list() {
+                    Some([Bencode::Integer(file), Bencode::List(components)]) => Ok((
+                        *file as usize,
+                        components
+                            .iter()
+                            .map(|component| {
+                                component
+                                    .as_str()
+                                    .ok_or_else(|| anyhow!("invalid path component"))
+                            })
+                            .collect::<Result<PathBuf>>()?,
+                    )),
+                    _ => bail!("invalid renamed file {:?}", renamed),
+                })
+                .collect::<Result<_>>()?,
+            None => vec![],
+        };
+        let root_name = bencode
+            .get("root-name")
+            .and_then(Bencode::as_str)
+            .map(String::from);
+
+        Ok(Self {
+            info_hash: hash,
+            pieces,
+            partial_pieces,
+            files,
+            trackers,
+            uploaded: integer(bencode, "uploaded")?,
+            downloaded: integer(bencode, "downloaded")?,
+            // missing from resume data written before seed limits
+            seed_time: integer(bencode, "seed-time").unwrap_or(0),
+            idle_time: integer(bencode, "idle-time").unwrap_or(0),
+            renamed_files,
+            root_name,
+        })
+    }
+}
+
+/// The torrents of a session, in queue order, with what they were added
+/// with. Stored bencoded in `<resume dir>/session.state`, their metainfo next
+/// to it in `<info hash>.torrent` and the rest in their resume data.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SessionState {
+    pub torrents: Vec<SavedTorrent>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedTorrent {
+    pub info_hash: [u8; 20],
+    pub save_path: PathBuf,
+    pub paused: bool,
+    pub file_priorities: Vec<Priority>,
+    /// its own, None follows those of the session
+    pub seed_limits: Option<SeedLimits>,
+    pub category: Option<String>,
+    pub tags: BTreeSet<String>,
+}
+
+impl SessionState {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("session.state")
+    }
+
+    pub fn metainfo_path(dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+        dir.join(format!("{}.torrent", to_hex(info_hash)))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        write_atomically(&Self::path(dir), &self.to_bencode().encode())
+    }
+
+    /// empty when no state was saved yet
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = Self::path(dir);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::from_bencode(&Parser::new(std::fs::read(path)?).parse()?)
+    }
+
+    /// kept until the torrent is removed, see `remove_metainfo`
+    pub fn save_metainfo(dir: &Path, metainfo: &Metainfo) -> Result<()> {
+        let path = Self::metainfo_path(dir, &metainfo.info_hash);
+        write_atomically(&path, &metainfo.to_bytes()?)
+    }
+
+    pub fn load_metainfo(dir: &Path, info_hash: &[u8; 20]) -> Result<Metainfo> {
+        let path = Self::metainfo_path(dir, info_hash);
+        let metainfo = Metainfo::from_bytes(std::fs::read(&path)?)?;
+        if &metainfo.info_hash != info_hash {
+            bail!("{} belongs to another torrent", path.display());
+        }
+        Ok(metainfo)
+    }
+
+    pub fn remove_metainfo(dir: &Path, info_hash: &[u8; 20]) -> Result<()> {
+        match std::fs::remove_file(Self::metainfo_path(dir, info_hash)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn to_bencode(&self) -> Bencode {
+        let torrents = self
+            .torrents
+            .iter()
+            .map(|torrent| {
+                let mut dict = HashMap::new();
+                dict.insert("info-hash".into(), torrent.info_hash.to_vec().into());
+                dict.insert(
+                    "save-path".into(),
+                    torrent.save_path.to_string_lossy().as_ref().into(),
+                );
+                dict.insert("paused".into(), (torrent.paused as i64).into());
+                dict.insert(
+                    "file-priorities".into(),
+                    Bencode::List(
+                        torrent
+                            .file_priorities
+                            .iter()
+                            .map(|priority| (*priority as i64).into())
+                            .collect(),
+                    ),
+                );
+                if let Some(limits) = &torrent.seed_limits {
+                    dict.insert("seed-limits".into(), seed_limits_to_bencode(limits));
+                }
+                if let Some(category) = &torrent.category {
+                    dict.insert("category".into(), category.as_str().into());
+                }
+                dict.insert(
+                    "tags".into(),
+                    Bencode::List(torrent.tags.iter().map(|tag| tag.as_str().into()).collect()),
+                );
+                Bencode::Dictionary(dict)
+            })
+            .collect();
+        let mut dict = HashMap::new();
+        dict.insert("torrents".into(), Bencode::List(torrents));
+        Bencode::Dictionary(dict)
+    }
+
+    pub fn from_bencode(bencode: &Bencode) -> Result<Self> {
+        let torrents = list(bencode, "torrents")?
+            .iter()
+            .map(|torrent| {
+                let info_hash = bytes(torrent, "info-hash")?
+                    .try_into()
+                    .map_err(|_| anyhow!("invalid info hash"))?;
+                let save_path = torrent
+                    .get("save-path")
+                    .and_then(Bencode::as_str)
+                    .ok_or_else(|| anyhow!("invalid or missing save-path"))?;
+                let file_priorities = list(torrent, "file-priorities")?
+                    .iter()
+                    .map(|priority| match priority.as_integer() {
+                        Some(0) => Ok(Priority::Skip),
+                        Some(1) => Ok(Priority::Low),
+                        Some(2) => Ok(Priority::Normal),
+                        Some(3) => Ok(Priority::High),
+                        _ => bail!("invalid priority {:?}", priority),
+                    })
+                    .collect::<Result<_>>()?;
+                // missing from states written before tags
+                let tags = match torrent.get("tags") {
+                    Some(_) => list(torrent, "tags")?
+                        .iter()
+                        .map(|tag| {
+                            tag.as_str()
+                                .map(String::from)
+                                .ok_or_else(|| anyhow!("invalid tag {:?}", tag))
+                        })
+                        .collect::<Result<_>>()?,
+                    None => BTreeSet::new(),
+                };
+                Ok(SavedTorrent {
+                    info_hash,
+                    save_path: save_path.into(),
+                    paused: integer(torrent, "paused")? != 0,
+                    file_priorities,
+                    seed_limits: torrent
+                        .get("seed-limits")
+                        .map(seed_limits_from_bencode)
+                        .transpose()?,
+                    category: torrent
+                        .get("category")
+                        .and_then(Bencode::as_str)
+                        .map(String::from),
+                    tags,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { torrents })
+    }
+}
+
+/// The items of each feed already added, by the name of the feed, so the
+/// next session doesn't add them again. Stored bencoded in
+/// `<resume dir>/feeds.state`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FeedState {
+    pub seen: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl FeedState {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("feeds.state")
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let dict = self
+            .seen
+            .iter()
+            .map(|(feed, items)| {
+                let items = items.iter().map(|item| item.as_str().into()).collect();
+                (feed.as_bytes().to_vec(), Bencode::List(items))
+            })
+            .collect();
+        write_atomically(&Self::path(dir), &Bencode::Dictionary(dict).encode())
+    }
+
+    /// empty when nothing was saved yet
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = Self::path(dir);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let bencode = Parser::new(std::fs::read(path)?).parse()?;
+        let dict = bencode
+            .as_dict()
+            .ok_or_else(|| anyhow!("invalid feed state"))?;
+        let mut seen = BTreeMap::new();
+        for (feed, items) in dict {
+            let items = items
+                .as_list()
+                .ok_or_else(|| anyhow!("invalid feed state"))?
+                .iter()
+                .map(|item| {
+                    item.as_str()
+                        .map(String::from)
+                        .ok_or_else(|| anyhow!("invalid feed item {:?}", item))
+                })
+                .collect::<Result<_>>()?;
+            seen.insert(String::from_utf8_lossy(feed).into_owned(), items);
+        }
+        Ok(Self { seen })
+    }
+}
+
+/// the ratio is kept as a string, bencode has no floats
+fn seed_limits_to_bencode(limits: &SeedLimits) -> Bencode {
+    let mut dict = HashMap::new();
+    if let Some(ratio) = limits.ratio {
+        dict.insert("ratio".into(), ratio.to_string().as_str().into());
+    }
+    if let Some(time) = limits.seed_time {
+        dict.insert("seed-time".into(), (time.as_secs() as i64).into());
+    }
+    if let Some(time) = limits.idle_time {
+        dict.insert("idle-time".into(), (time.as_secs() as i64).into());
+    }
+    dict.insert("action".into(), limits.action.to_string().as_str().into());
+    Bencode::Dictionary(dict)
+}
+
+fn seed_limits_from_bencode(bencode: &Bencode) -> Result<SeedLimits> {
+    let seconds = |key| match bencode.get(key) {
+        Some(_) => integer(bencode, key).map(|time| Some(Duration::from_secs(time))),
+        None => Ok(None),
+    };
+    let ratio = match bencode.get("ratio") {
+        Some(ratio) => Some(
+            ratio
+                .as_str()
+                .and_then(|ratio| ratio.parse().ok())
+                .ok_or_else(|| anyhow!("invalid seed ratio"))?,
+        ),
+        None => None,
+    };
+    let action = bencode
+        .get("action")
+        .and_then(Bencode::as_str)
+        .ok_or_else(|| anyhow!("invalid or missing action"))?;
+    Ok(SeedLimits {
+        ratio,
+        seed_time: seconds("seed-time")?,
+        idle_time: seconds("idle-time")?,
+        action: SeedLimitAction::parse(action)?,
+    })
+}
+
+/// written to a temporary file first so a crash never leaves a truncated file,
+/// the temporary file is synced before the rename so it can't end up empty either
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn integer(bencode: &Bencode, key: &str) -> Result<u64> {
+    match bencode.get(key).and_then(Bencode::as_integer) {
+        Some(value) if value >= 0 => Ok(value as u64),
+        _ => bail!("invalid or missing {}", key),
+    }
+}
+
+fn bytes<'a>(bencode: &'a Bencode, key: &str) -> Result<&'a [u8]> {
+    bencode
+        .get(key)
+        .and_then(Bencode::as_bytes)
+        .ok_or_else(|| anyhow!("invalid or missing {}", key))
+}
+
+fn list<'a>(bencode: &'a Bencode, key: &str) -> Result<&'a [Bencode]> {
+    bencode
+        .get(key)
+        .and_then(Bencode::as_list)
+        .ok_or_else(|| anyhow!("invalid or missing {}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_resume_test");
+        let mut pieces = Bitfield::new(10);
+        pieces.set(3, true);
+        let mut blocks = Bitfield::new(4);
+        blocks.set(1, true);
+        let resume = ResumeData {
+            info_hash: [7; 20],
+            pieces,
+            partial_pieces: vec![PartialPiece { piece: 5, blocks }],
+            files: vec![FileStat {
+                size: 12,
+                mtime: 1615600304,
+            }],
+            trackers: vec![TrackerStats {
+                url: String::from("http://tracker.example/announce"),
+                seeders: 3,
+                ..Default::default()
+            }],
+            uploaded: 100,
+            downloaded: 200,
+            seed_time: 3600,
+            idle_time: 60,
+            renamed_files: vec![(2, PathBuf::from("sub").join("renamed"))],
+            root_name: Some(String::from("root")),
+        };
+
+        resume.save(&dir)?;
+        assert_eq!(ResumeData::load(&dir, &[7; 20])?, Some(resume));
+        assert_eq!(ResumeData::load(&dir, &[8; 20])?, None);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn saves_the_session_state() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_session_state_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(SessionState::load(&dir)?, SessionState::default());
+        let state = SessionState {
+            torrents: vec![
+                SavedTorrent {
+                    info_hash: [1; 20],
+                    save_path: "downloads".into(),
+                    paused: true,
+                    file_priorities: vec![Priority::Skip, Priority::High],
+                    seed_limits: Some(SeedLimits {
+                        ratio: Some(1.25),
+                        idle_time: Some(Duration::from_secs(600)),
+                        action: SeedLimitAction::Remove,
+                        ..Default::default()
+                    }),
+                    category: Some("linux".into()),
+                    tags: vec!["iso".to_string(), "x86".to_string()]
+                        .into_iter()
+                        .collect(),
+                },
+                SavedTorrent {
+                    info_hash: [2; 20],
+                    save_path: "other".into(),
+                    paused: false,
+                    file_priorities: vec![Priority::Low, Priority::Normal],
+                    seed_limits: None,
+                    category: None,
+                    tags: BTreeSet::new(),
+                },
+            ],
+        };
+        state.save(&dir)?;
+        assert_eq!(SessionState::load(&dir)?, state);
+
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        SessionState::save_metainfo(&dir, &metainfo)?;
+        assert_eq!(
+            SessionState::load_metainfo(&dir, &metainfo.info_hash)?,
+            metainfo
+        );
+        assert!(SessionState::load_metainfo(&dir, &[1; 20]).is_err());
+        SessionState::remove_metainfo(&dir, &metainfo.info_hash)?;
+        SessionState::remove_metainfo(&dir, &metainfo.info_hash)?;
+        assert!(SessionState::load_metainfo(&dir, &metainfo.info_hash).is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn saves_the_feed_state() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_feed_state_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(FeedState::load(&dir)?, FeedState::default());
+        let mut state = FeedState::default();
+        state.seen.insert(
+            "linux".into(),
+            vec!["a".to_string(), "b".to_string()].into_iter().collect(),
+        );
+        state.seen.insert("empty".into(), BTreeSet::new());
+        state.save(&dir)?;
+        assert_eq!(FeedState::load(&dir)?, state);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
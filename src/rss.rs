@@ -0,0 +1,457 @@
+use crate::gzip;
+use crate::regex::Regex;
+use crate::session::AddOptions;
+use crate::tracker;
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::Duration;
+
+/// how often a feed is fetched when its config doesn't say
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(15 * 60);
+/// gzipped .torrent files decompressing to more are refused
+const MAX_TORRENT_SIZE: u64 = 64 * 1024 * 1024;
+/// of nested elements in a feed, so walking it can't overflow the stack
+const MAX_DEPTH: usize = 128;
+
+/// An RSS or Atom feed whose items are added to the session with its
+/// options, those whose title matches `include` and not `exclude`. Each
+/// item is added once, see `resume::FeedState`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+    /// over http or https
+    pub url: String,
+    /// every item when None
+    pub include: Option<Regex>,
+    pub exclude: Option<Regex>,
+    pub refresh: Duration,
+    pub options: AddOptions,
+}
+
+impl Feed {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            include: None,
+            exclude: None,
+            refresh: DEFAULT_REFRESH,
+            options: AddOptions::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !tracker::is_http_url(&self.url) {
+            bail!("feed {} has to be http:// or https://", self.url);
+        }
+        if self.refresh.is_zero() {
+            bail!("feed {} is refreshed every 0 minutes", self.url);
+        }
+        Ok(())
+    }
+
+    /// whether the filters let the item through
+    pub fn wants(&self, item: &FeedItem) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(&item.title))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(&item.title))
+    }
+
+    /// the items the feed currently lists, blocking until it was fetched
+    pub fn fetch(&self) -> Result<Vec<FeedItem>> {
+        let body =
+            tracker::http_get(&self.url).with_context(|| format!("fetching {}", self.url))?;
+        parse(&String::from_utf8_lossy(&body))
+    }
+}
+
+/// An `<item>` of an RSS feed or an `<entry>` of an Atom one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedItem {
+    /// the guid or id of the item, else its link, else its title
+    pub id: String,
+    pub title: String,
+    /// to the magnet link or .torrent file, None when the item has none
+    pub link: Option<String>,
+}
+
+/// What the link of an item led to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Download {
+    Magnet(String),
+    /// the content of a .torrent file
+    Torrent(Vec<u8>),
+}
+
+impl FeedItem {
+    /// the magnet link, or the .torrent file fetched over http, which may
+    /// be gzipped
+    pub fn download(&self) -> Result<Download> {
+        let link = match &self.link {
+            Some(link) => link,
+            None => bail!("{} has no link", self.title),
+        };
+        if link.starts_with("magnet:") {
+            return Ok(Download::Magnet(link.clone()));
+        }
+        if !tracker::is_http_url(link) {
+            bail!("{} isn't a magnet, http:// or https:// link", link);
+        }
+        let body = tracker::http_get(link).with_context(|| format!("fetching {}", link))?;
+        match gzip::is_gzip(&body) {
+            true => Ok(Download::Torrent(gzip::decompress(
+                &body,
+                MAX_TORRENT_SIZE,
+            )?)),
+            false => Ok(Download::Torrent(body)),
+        }
+    }
+}
+
+/// the items of an RSS or Atom feed, in the order listed
+pub fn parse(xml: &str) -> Result<Vec<FeedItem>> {
+    let root = Element::parse(xml)?;
+    let mut elements = vec![];
+    root.find_items(&mut elements);
+    Ok(elements.into_iter().map(item).collect())
+}
+
+fn item(element: &Element) -> FeedItem {
+    let title = element.child_text("title").unwrap_or_default();
+    let enclosure = element
+        .children("enclosure")
+        .find_map(|enclosure| enclosure.attribute("url"));
+    let atom_links: Vec<_> = element
+        .children("link")
+        .filter_map(|link| Some((link.attribute("rel"), link.attribute("href")?)))
+        .collect();
+    let atom_enclosure = atom_links
+        .iter()
+        .find(|(rel, _)| *rel == Some("enclosure"))
+        .map(|(_, href)| href);
+    let atom_link = atom_links
+        .iter()
+        .find(|(rel, _)| rel.is_none_or(|rel| rel == "alternate"))
+        .map(|(_, href)| href);
+    let link = element
+        .child_text("magnetURI")
+        .or_else(|| enclosure.map(String::from))
+        .or_else(|| atom_enclosure.map(|href| href.to_string()))
+        .or_else(|| element.child_text("link"))
+        .or_else(|| atom_link.map(|href| href.to_string()));
+    let id = element
+        .child_text("guid")
+        .or_else(|| element.child_text("id"))
+        .or_else(|| link.clone())
+        .unwrap_or_else(|| title.clone());
+    FeedItem { id, title, link }
+}
+
+/// An element of an XML document, by its name without a namespace prefix,
+/// with the text directly in it
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    text: String,
+    elements: Vec<Element>,
+}
+
+impl Element {
+    /// the document as the children of an unnamed element. DTDs,
+    /// processing instructions and comments are skipped.
+    fn parse(xml: &str) -> Result<Self> {
+        let mut stack = vec![Element::default()];
+        let mut rest = xml;
+        while !rest.is_empty() {
+            let start = rest.find('<').unwrap_or(rest.len());
+            let top = stack.last_mut().unwrap();
+            top.text.push_str(&unescape(&rest[..start]));
+            rest = &rest[start..];
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(after) = rest.strip_prefix("<!--") {
+                rest = skip_past(after, "-->")?;
+            } else if let Some(after) = rest.strip_prefix("<![CDATA[") {
+                let end = after.find("]]>").ok_or_else(|| anyhow!("unclosed CDATA"))?;
+                top.text.push_str(&after[..end]);
+                rest = &after[end + 3..];
+            } else if let Some(after) = rest.strip_prefix("<?") {
+                rest = skip_past(after, "?>")?;
+            } else if let Some(after) = rest.strip_prefix("<!") {
+                rest = skip_past(after, ">")?;
+            } else if let Some(after) = rest.strip_prefix("</") {
+                let end = after.find('>').ok_or_else(|| anyhow!("unclosed tag"))?;
+                let name = local_name(after[..end].trim());
+                if stack.len() == 1 || stack.last().unwrap().name != name {
+                    bail!("unexpected </{}>", name);
+                }
+                let element = stack.pop().unwrap();
+                stack.last_mut().unwrap().elements.push(element);
+                rest = &after[end + 1..];
+            } else {
+                let (element, closed, after) = start_tag(&rest[1..])?;
+                match closed {
+                    true => stack.last_mut().unwrap().elements.push(element),
+                    false if stack.len() > MAX_DEPTH => bail!("nested too deeply"),
+                    false => stack.push(element),
+                }
+                rest = after;
+            }
+        }
+        if stack.len() > 1 {
+            bail!("<{}> isn't closed", stack.last().unwrap().name);
+        }
+        Ok(stack.pop().unwrap())
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.elements
+            .iter()
+            .filter(move |element| element.name == name)
+    }
+
+    /// the trimmed text of the first child named `name`, None if empty
+    fn child_text(&self, name: &str) -> Option<String> {
+        self.children(name)
+            .map(|element| element.text.trim())
+            .find(|text| !text.is_empty())
+            .map(String::from)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// the `item` and `entry` elements in this one, at any depth
+    fn find_items<'a>(&'a self, items: &mut Vec<&'a Element>) {
+        for element in &self.elements {
+            match element.name.as_str() {
+                "item" | "entry" => items.push(element),
+                _ => element.find_items(items),
+            }
+        }
+    }
+}
+
+fn skip_past<'a>(text: &'a str, end: &str) -> Result<&'a str> {
+    let index = text
+        .find(end)
+        .ok_or_else(|| anyhow!("missing {} in the feed", end))?;
+    Ok(&text[index + end.len()..])
+}
+
+/// `atom:link` is `link`
+fn local_name(name: &str) -> String {
+    name.rsplit(':').next().unwrap_or(name).to_string()
+}
+
+/// the element of the tag `text` starts with, past its `<`, whether it
+/// closes itself and what follows the tag
+fn start_tag(text: &str) -> Result<(Element, bool, &str)> {
+    let name_end = text
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .ok_or_else(|| anyhow!("unclosed tag"))?;
+    let mut element = Element {
+        name: local_name(&text[..name_end]),
+        ..Element::default()
+    };
+    if element.name.is_empty() {
+        bail!("a tag has no name");
+    }
+    let mut rest = &text[name_end..];
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix("/>") {
+            return Ok((element, true, after));
+        }
+        if let Some(after) = rest.strip_prefix('>') {
+            return Ok((element, false, after));
+        }
+        let equals = rest
+            .find('=')
+            .ok_or_else(|| anyhow!("invalid attribute in <{}>", element.name))?;
+        let key = local_name(rest[..equals].trim());
+        let value = rest[equals + 1..].trim_start();
+        let quote = value
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+            .ok_or_else(|| anyhow!("unquoted attribute {} in <{}>", key, element.name))?;
+        let end = value[1..]
+            .find(quote)
+            .ok_or_else(|| anyhow!("unclosed attribute {} in <{}>", key, element.name))?;
+        element.attributes.push((key, unescape(&value[1..end + 1])));
+        rest = &value[end + 2..];
+    }
+}
+
+/// replaces the predefined and numeric entities, others are kept as is
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|n| n.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match character {
+            Some(character) => {
+                unescaped.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rss() -> Result<()> {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <!-- a comment with <item> in it -->
+            <rss version="2.0" xmlns:torrent="http://xmlns.ezrss.it/0.1/">
+              <channel>
+                <title>Linux</title>
+                <item>
+                  <title>Debian 12 &amp; more</title>
+                  <link>http://example.com/debian</link>
+                  <guid isPermaLink="false">debian-12</guid>
+                  <enclosure url="http://example.com/debian.torrent?a=1&amp;b=2" type="application/x-bittorrent" />
+                </item>
+                <item>
+                  <title><![CDATA[Fedora <40>]]></title>
+                  <torrent:magnetURI>magnet:?xt=urn:btih:abc</torrent:magnetURI>
+                </item>
+                <item><title>Arch &#x2013; &#8212;</title></item>
+              </channel>
+            </rss>"#;
+        assert_eq!(
+            parse(xml)?,
+            vec![
+                FeedItem {
+                    id: "debian-12".into(),
+                    title: "Debian 12 & more".into(),
+                    link: Some("http://example.com/debian.torrent?a=1&b=2".into()),
+                },
+                FeedItem {
+                    id: "magnet:?xt=urn:btih:abc".into(),
+                    title: "Fedora <40>".into(),
+                    link: Some("magnet:?xt=urn:btih:abc".into()),
+                },
+                FeedItem {
+                    id: "Arch \u{2013} \u{2014}".into(),
+                    title: "Arch \u{2013} \u{2014}".into(),
+                    link: None,
+                },
+            ]
+        );
+        assert!(parse("<rss><item></rss>").is_err());
+        assert!(parse("<rss><item a=b></item></rss>").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_atom() -> Result<()> {
+        let xml = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+              <entry>
+                <id>urn:1</id>
+                <title type="text">Ubuntu</title>
+                <link href='http://example.com/ubuntu'/>
+                <link rel="enclosure" href="http://example.com/ubuntu.torrent"/>
+              </entry>
+              <atom:entry xmlns:atom="http://www.w3.org/2005/Atom">
+                <atom:title>Mint</atom:title>
+                <atom:link rel="alternate" href="magnet:?xt=urn:btih:def"/>
+              </atom:entry>
+            </feed>"#;
+        assert_eq!(
+            parse(xml)?,
+            vec![
+                FeedItem {
+                    id: "urn:1".into(),
+                    title: "Ubuntu".into(),
+                    link: Some("http://example.com/ubuntu.torrent".into()),
+                },
+                FeedItem {
+                    id: "magnet:?xt=urn:btih:def".into(),
+                    title: "Mint".into(),
+                    link: Some("magnet:?xt=urn:btih:def".into()),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let xml = "<a>".repeat(100_000);
+        assert_eq!(parse(&xml).unwrap_err().to_string(), "nested too deeply");
+        let xml = format!("{}<item/>{}", "<a>".repeat(100), "</a>".repeat(100));
+        assert_eq!(parse(&xml).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn filters_items() -> Result<()> {
+        let item = |title: &str| FeedItem {
+            id: title.into(),
+            title: title.into(),
+            link: None,
+        };
+        let mut feed = Feed::new("http://example.com/feed");
+        feed.validate()?;
+        assert!(feed.wants(&item("anything")));
+        feed.include = Some(Regex::ignoring_case(r"show\.s\d+e\d+")?);
+        feed.exclude = Some(Regex::ignoring_case("720p")?);
+        assert!(feed.wants(&item("Show.S01E02.1080p")));
+        assert!(!feed.wants(&item("Show.S01E02.720p")));
+        assert!(!feed.wants(&item("Other.S01E02")));
+
+        assert_eq!(
+            item("x").download().unwrap_err().to_string(),
+            "x has no link"
+        );
+        let magnet = FeedItem {
+            link: Some("magnet:?xt=urn:btih:abc".into()),
+            ..item("x")
+        };
+        assert_eq!(
+            magnet.download()?,
+            Download::Magnet("magnet:?xt=urn:btih:abc".into())
+        );
+        assert!(Feed::new("https://example.com").validate().is_ok());
+        assert!(Feed::new("ftp://example.com").validate().is_err());
+        feed.refresh = Duration::ZERO;
+        assert!(feed.validate().is_err());
+        Ok(())
+    }
+}
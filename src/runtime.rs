@@ -0,0 +1,20 @@
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// The tokio runtime the tasks of the engine run on: the peer connections
+/// and the loop feeding them to the torrents, the trackers, the DHT, local
+/// service discovery and the disk jobs, on its blocking threads. Started
+/// with the first task and shared by every session of the process.
+pub fn handle() -> Handle {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| {
+            Builder::new_multi_thread()
+                .thread_name("torrent_rs")
+                .enable_all()
+                .build()
+                .expect("starting the tokio runtime")
+        })
+        .handle()
+        .clone()
+}
@@ -0,0 +1,245 @@
+use crate::bandwidth::RateLimits;
+use anyhow::{bail, Context, Result};
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use std::sync::Once;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// A moment of the week in local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeekTime {
+    /// 0 is monday
+    pub day: u8,
+    /// since midnight
+    pub minute: u16,
+}
+
+impl WeekTime {
+    /// in the local time zone of the system, or in UTC with a warning when
+    /// the system doesn't say which it is
+    pub fn now() -> Self {
+        static UNKNOWN_ZONE: Once = Once::new();
+        let zone = TimeZone::try_system().unwrap_or_else(|error| {
+            UNKNOWN_ZONE.call_once(|| {
+                tracing::warn!(
+                    error = %error.to_string(),
+                    "the schedule follows UTC, the local time zone is unknown",
+                )
+            });
+            TimeZone::UTC
+        });
+        let now = Timestamp::now().to_zoned(zone);
+        Self {
+            day: now.weekday().to_monday_zero_offset() as u8,
+            minute: now.hour() as u16 * 60 + now.minute() as u16,
+        }
+    }
+
+    /// in UTC, seconds since the unix epoch
+    pub fn from_unix(seconds: u64) -> Self {
+        let minutes = seconds / 60;
+        // the epoch was a thursday
+        let day = (minutes / (24 * 60) + 3) % 7;
+        Self {
+            day: day as u8,
+            minute: (minutes % (24 * 60)) as u16,
+        }
+    }
+}
+
+/// What a rule does while it applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleAction {
+    /// replaces the rate limits of the session
+    Limit(RateLimits),
+    /// pauses every torrent, those it paused are resumed once it ends
+    Pause,
+}
+
+/// Applies its action on the given days from `start` to `end`, rules ending
+/// before they start run past midnight into the next day
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleRule {
+    /// indexed by `WeekTime::day`
+    pub days: [bool; 7],
+    /// minutes since midnight
+    pub start: u16,
+    /// minutes since midnight, up to 24:00
+    pub end: u16,
+    pub action: ScheduleAction,
+}
+
+impl ScheduleRule {
+    pub fn applies_at(&self, time: WeekTime) -> bool {
+        let day = time.day as usize % 7;
+        let yesterday = (day + 6) % 7;
+        if self.start <= self.end {
+            self.days[day] && self.start <= time.minute && time.minute < self.end
+        } else {
+            (self.days[day] && time.minute >= self.start)
+                || (self.days[yesterday] && time.minute < self.end)
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.days.contains(&true) {
+            bail!("no days");
+        }
+        if self.start == self.end {
+            bail!("starts when it ends");
+        }
+        if self.start >= 24 * 60 || self.end > 24 * 60 {
+            bail!("times have to be within a day");
+        }
+        if let ScheduleAction::Limit(limits) = self.action {
+            if limits.upload == Some(0) || limits.download == Some(0) {
+                bail!("rate limits have to be positive, None is unlimited");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Weekly timetable of alternative rate limits and pauses, outside of its
+/// rules the session runs with its own limits. See `Session::tick`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// the first that applies wins
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl Schedule {
+    /// None when no rule applies
+    pub fn action_at(&self, time: WeekTime) -> Option<ScheduleAction> {
+        self.rules
+            .iter()
+            .find(|rule| rule.applies_at(time))
+            .map(|rule| rule.action)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            rule.validate()
+                .with_context(|| format!("schedule rule {}", index))?;
+        }
+        Ok(())
+    }
+}
+
+/// comma separated days or ranges of days, like `mon-fri,sun`
+pub fn parse_days(text: &str) -> Result<[bool; 7]> {
+    let day = |name: &str| match DAYS.iter().position(|day| *day == name.trim()) {
+        Some(day) => Ok(day),
+        None => bail!("unknown day {}", name.trim()),
+    };
+    let mut days = [false; 7];
+    for part in text.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+        // ranges can wrap around the week, like sat-mon
+        let mut current = first;
+        loop {
+            days[current] = true;
+            if current == last {
+                break;
+            }
+            current = (current + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// `hh:mm` in minutes since midnight, 24:00 is the end of the day
+pub fn parse_time(text: &str) -> Result<u16> {
+    let parsed = text.split_once(':').and_then(|(hours, minutes)| {
+        let hours: u16 = hours.parse().ok().filter(|hours| *hours <= 24)?;
+        let minutes: u16 = minutes.parse().ok().filter(|minutes| *minutes < 60)?;
+        Some(hours * 60 + minutes).filter(|minute| *minute <= 24 * 60)
+    });
+    match parsed {
+        Some(minute) => Ok(minute),
+        None => bail!("invalid time {}, expected hh:mm", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u8, hour: u16) -> WeekTime {
+        WeekTime {
+            day,
+            minute: hour * 60,
+        }
+    }
+
+    #[test]
+    fn rules_apply_on_their_days_and_hours() -> Result<()> {
+        let limits = RateLimits {
+            upload: Some(1024),
+            download: None,
+        };
+        let schedule = Schedule {
+            rules: vec![
+                ScheduleRule {
+                    days: parse_days("mon-fri")?,
+                    start: parse_time("09:00")?,
+                    end: parse_time("17:00")?,
+                    action: ScheduleAction::Pause,
+                },
+                ScheduleRule {
+                    days: parse_days("fri,sat")?,
+                    start: parse_time("22:00")?,
+                    end: parse_time("06:00")?,
+                    action: ScheduleAction::Limit(limits),
+                },
+            ],
+        };
+        schedule.validate()?;
+        assert_eq!(schedule.action_at(at(0, 9)), Some(ScheduleAction::Pause));
+        assert_eq!(schedule.action_at(at(4, 16)), Some(ScheduleAction::Pause));
+        assert_eq!(schedule.action_at(at(4, 17)), None);
+        assert_eq!(schedule.action_at(at(5, 12)), None);
+        // past midnight of friday and saturday
+        assert_eq!(
+            schedule.action_at(at(4, 23)),
+            Some(ScheduleAction::Limit(limits))
+        );
+        assert_eq!(
+            schedule.action_at(at(5, 5)),
+            Some(ScheduleAction::Limit(limits))
+        );
+        assert_eq!(
+            schedule.action_at(at(6, 5)),
+            Some(ScheduleAction::Limit(limits))
+        );
+        assert_eq!(schedule.action_at(at(0, 5)), None);
+        assert_eq!(schedule.action_at(at(4, 5)), None);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_days_and_times() -> Result<()> {
+        assert_eq!(
+            parse_days("sat-mon")?,
+            [true, false, false, false, false, true, true]
+        );
+        assert_eq!(
+            parse_days("tue, thu")?,
+            [false, true, false, true, false, false, false]
+        );
+        assert!(parse_days("someday").is_err());
+        assert_eq!(parse_time("00:00")?, 0);
+        assert_eq!(parse_time("17:30")?, 17 * 60 + 30);
+        assert_eq!(parse_time("24:00")?, 24 * 60);
+        for time in ["24:01", "9", "12:60", "a:b"] {
+            assert!(parse_time(time).is_err(), "{}", time);
+        }
+        // thursday 1 january 1970, 01:00
+        assert_eq!(WeekTime::from_unix(3600), at(3, 1));
+        Ok(())
+    }
+}
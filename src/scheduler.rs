@@ -0,0 +1,350 @@
+use crate::bitfield::Bitfield;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub piece: usize,
+    pub offset: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    Open,
+    Requested,
+    Received,
+}
+
+/// A piece being downloaded, blocks are written into `data` as they arrive
+#[derive(Debug)]
+pub struct PieceBuffer {
+    piece: usize,
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+}
+
+/// A fully received piece waiting to be verified, the data is shared between
+/// the verifier and the write path
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletedPiece {
+    pub piece: usize,
+    pub data: Arc<[u8]>,
+}
+
+impl PieceBuffer {
+    pub fn new(piece: usize, size: u32) -> Self {
+        let block_count = size.div_ceil(BLOCK_SIZE) as usize;
+        Self {
+            piece,
+            data: vec![0; size as usize],
+            blocks: vec![BlockState::Open; block_count],
+        }
+    }
+
+    pub fn piece(&self) -> usize {
+        self.piece
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    /// the final block of a piece is shorter unless the piece size is a multiple of the block size
+    pub fn block_length(&self, block: usize) -> u32 {
+        let offset = block as u32 * BLOCK_SIZE;
+        BLOCK_SIZE.min(self.size() - offset)
+    }
+
+    pub fn blocks(&self) -> &[BlockState] {
+        &self.blocks
+    }
+
+    pub fn received_blocks(&self) -> Bitfield {
+        let mut received = Bitfield::new(self.blocks.len());
+        for (block, &state) in self.blocks.iter().enumerate() {
+            received.set(block, state == BlockState::Received);
+        }
+        received
+    }
+
+    fn request(&mut self, block: usize) -> BlockRequest {
+        self.blocks[block] = BlockState::Requested;
+        BlockRequest {
+            piece: self.piece,
+            offset: block as u32 * BLOCK_SIZE,
+            length: self.block_length(block),
+        }
+    }
+
+    fn next_open(&self) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|&state| state == BlockState::Open)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.blocks
+            .iter()
+            .all(|&state| state == BlockState::Received)
+    }
+}
+
+/// Splits pieces into blocks and reassembles them as they are received
+#[derive(Debug, Default)]
+pub struct BlockScheduler {
+    pieces: BTreeMap<usize, PieceBuffer>,
+}
+
+impl BlockScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_in_progress(&self, piece: usize) -> bool {
+        self.pieces.contains_key(&piece)
+    }
+
+    pub fn is_received(&self, request: &BlockRequest) -> bool {
+        let block = (request.offset / BLOCK_SIZE) as usize;
+        self.pieces
+            .get(&request.piece)
+            .and_then(|buffer| buffer.blocks.get(block))
+            == Some(&BlockState::Received)
+    }
+
+    /// memory held by the buffers of pieces in progress
+    pub fn buffered_bytes(&self) -> u64 {
+        self.pieces
+            .values()
+            .map(|buffer| buffer.size() as u64)
+            .sum()
+    }
+
+    pub fn in_progress(&self) -> impl Iterator<Item = &PieceBuffer> {
+        self.pieces.values()
+    }
+
+    /// data of a piece in progress, blocks that didn't arrive are zeros
+    pub fn piece_data(&self, piece: usize) -> Option<&[u8]> {
+        self.pieces.get(&piece).map(|buffer| &buffer.data[..])
+    }
+
+    /// next open block of a piece already in progress that passes the filter
+    pub fn request_in_progress(
+        &mut self,
+        mut filter: impl FnMut(usize) -> bool,
+    ) -> Option<BlockRequest> {
+        self.pieces
+            .values_mut()
+            .filter(|buffer| filter(buffer.piece))
+            .find_map(|buffer| buffer.next_open().map(|block| buffer.request(block)))
+    }
+
+    /// next open block of the given piece if it is in progress
+    pub fn request_open(&mut self, piece: usize) -> Option<BlockRequest> {
+        let buffer = self.pieces.get_mut(&piece)?;
+        let block = buffer.next_open()?;
+        Some(buffer.request(block))
+    }
+
+    /// a block of the piece that was already requested from someone else, used
+    /// to race slow peers for time critical pieces
+    pub fn request_duplicate(
+        &self,
+        piece: usize,
+        exclude: &[BlockRequest],
+    ) -> Option<BlockRequest> {
+        let buffer = self.pieces.get(&piece)?;
+        (0..buffer.blocks.len())
+            .filter(|&block| buffer.blocks[block] == BlockState::Requested)
+            .map(|block| BlockRequest {
+                piece,
+                offset: block as u32 * BLOCK_SIZE,
+                length: buffer.block_length(block),
+            })
+            .find(|request| !exclude.contains(request))
+    }
+
+    /// starts downloading a new piece and requests its first block
+    pub fn start_piece(&mut self, piece: usize, size: u32) -> BlockRequest {
+        self.pieces
+            .entry(piece)
+            .or_insert_with(|| PieceBuffer::new(piece, size))
+            .request(0)
+    }
+
+    /// marks a block picked for a peer as requested, starting its piece of
+    /// `size` bytes if needed. False if the block isn't open or isn't one of
+    /// the piece.
+    pub fn claim(&mut self, request: &BlockRequest, size: u32) -> bool {
+        let block = (request.offset / BLOCK_SIZE) as usize;
+        let buffer = self
+            .pieces
+            .entry(request.piece)
+            .or_insert_with(|| PieceBuffer::new(request.piece, size));
+        if !request.offset.is_multiple_of(BLOCK_SIZE)
+            || buffer.blocks.get(block) != Some(&BlockState::Open)
+            || request.length != buffer.block_length(block)
+        {
+            if !buffer.blocks.contains(&BlockState::Requested)
+                && !buffer.blocks.contains(&BlockState::Received)
+            {
+                self.pieces.remove(&request.piece);
+            }
+            return false;
+        }
+        buffer.request(block);
+        true
+    }
+
+    /// recreates a partially downloaded piece from `data`, only the blocks set
+    /// in `received` are considered valid
+    pub fn restore_piece(&mut self, piece: usize, data: Vec<u8>, received: &Bitfield) {
+        let mut buffer = PieceBuffer::new(piece, data.len() as u32);
+        if received.len() != buffer.blocks.len() {
+            return;
+        }
+        for (block, state) in buffer.blocks.iter_mut().enumerate() {
+            if received.get(block) {
+                *state = BlockState::Received;
+            }
+        }
+        if buffer.is_complete() {
+            return;
+        }
+        buffer.data = data;
+        self.pieces.insert(piece, buffer);
+    }
+
+    /// puts a requested block back in the pool, ie. when the peer disconnects or chokes us
+    pub fn cancel(&mut self, request: &BlockRequest) {
+        if let Some(buffer) = self.pieces.get_mut(&request.piece) {
+            let block = (request.offset / BLOCK_SIZE) as usize;
+            if buffer.blocks.get(block) == Some(&BlockState::Requested) {
+                buffer.blocks[block] = BlockState::Open;
+            }
+        }
+    }
+
+    /// stores the block and returns the whole piece once every block has arrived
+    pub fn block_received(
+        &mut self,
+        request: &BlockRequest,
+        data: &[u8],
+    ) -> Result<Option<CompletedPiece>> {
+        let buffer = match self.pieces.get_mut(&request.piece) {
+            Some(buffer) => buffer,
+            None => bail!("received block for piece {} not in progress", request.piece),
+        };
+        let block = (request.offset / BLOCK_SIZE) as usize;
+        if !request.offset.is_multiple_of(BLOCK_SIZE)
+            || block >= buffer.blocks.len()
+            || data.len() as u32 != buffer.block_length(block)
+        {
+            bail!("received unexpected block {:?}", request);
+        }
+        if buffer.blocks[block] == BlockState::Received {
+            return Ok(None);
+        }
+
+        let offset = request.offset as usize;
+        buffer.data[offset..offset + data.len()].copy_from_slice(data);
+        buffer.blocks[block] = BlockState::Received;
+
+        if buffer.is_complete() {
+            let buffer = self.pieces.remove(&request.piece).unwrap();
+            return Ok(Some(CompletedPiece {
+                piece: buffer.piece,
+                data: buffer.data.into(),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// drops a piece and all of its blocks, used when it fails verification
+    pub fn abort_piece(&mut self, piece: usize) {
+        self.pieces.remove(&piece);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_final_block() -> Result<()> {
+        let mut scheduler = BlockScheduler::new();
+        let first = scheduler.start_piece(3, BLOCK_SIZE + 10);
+        let last = scheduler.request_in_progress(|_| true).unwrap();
+
+        assert_eq!(first.length, BLOCK_SIZE);
+        assert_eq!(
+            last,
+            BlockRequest {
+                piece: 3,
+                offset: BLOCK_SIZE,
+                length: 10
+            }
+        );
+        assert_eq!(scheduler.request_in_progress(|_| true), None);
+
+        assert!(scheduler.block_received(&last, &[1; 10])?.is_none());
+        let completed = scheduler
+            .block_received(&first, &vec![2; BLOCK_SIZE as usize])?
+            .unwrap();
+        assert_eq!(completed.piece, 3);
+        assert_eq!(completed.data.len(), BLOCK_SIZE as usize + 10);
+        assert_eq!(completed.data[BLOCK_SIZE as usize], 1);
+        assert!(!scheduler.is_in_progress(3));
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_blocks_are_requested_again() {
+        let mut scheduler = BlockScheduler::new();
+        let request = scheduler.start_piece(0, 100);
+        assert_eq!(scheduler.request_in_progress(|_| true), None);
+
+        scheduler.cancel(&request);
+        assert_eq!(scheduler.request_in_progress(|_| true), Some(request));
+    }
+
+    #[test]
+    fn restored_piece_only_requests_missing_blocks() {
+        let mut scheduler = BlockScheduler::new();
+        let mut received = Bitfield::new(3);
+        received.set(0, true);
+        received.set(2, true);
+        scheduler.restore_piece(0, vec![0; 2 * BLOCK_SIZE as usize + 1], &received);
+
+        let request = scheduler.request_in_progress(|_| true).unwrap();
+        assert_eq!(request.offset, BLOCK_SIZE);
+        assert_eq!(scheduler.request_in_progress(|_| true), None);
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let mut scheduler = BlockScheduler::new();
+        let request = scheduler.start_piece(0, 100);
+        assert!(scheduler.block_received(&request, &[0; 99]).is_err());
+    }
+
+    #[test]
+    fn claims_only_open_blocks() {
+        let mut scheduler = BlockScheduler::new();
+        let block = |offset, length| BlockRequest {
+            piece: 2,
+            offset,
+            length,
+        };
+        assert!(!scheduler.claim(&block(0, 10), BLOCK_SIZE + 10));
+        assert!(!scheduler.is_in_progress(2));
+        assert!(scheduler.claim(&block(BLOCK_SIZE, 10), BLOCK_SIZE + 10));
+        assert!(!scheduler.claim(&block(BLOCK_SIZE, 10), BLOCK_SIZE + 10));
+        assert_eq!(scheduler.request_open(2), Some(block(0, BLOCK_SIZE)));
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::Duration;
+
+/// What is done with a torrent once it reaches one of its seed limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedLimitAction {
+    /// paused like `Session::pause`. Resumed, it is paused again as long
+    /// as it is over its limits, so they have to be raised first.
+    #[default]
+    Pause,
+    /// paused like `Session::pause`, with its seed limits lifted so it
+    /// seeds on without any once resumed
+    Stop,
+    /// removed from the session, its files are left where they are
+    Remove,
+    /// removed from the session and its files deleted from the disk, like
+    /// `Session::remove` with `delete_data`. Nothing else in the save path
+    /// is touched, but what was downloaded is gone for good.
+    RemoveWithData,
+}
+
+impl SeedLimitAction {
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(match text {
+            "pause" => SeedLimitAction::Pause,
+            "stop" => SeedLimitAction::Stop,
+            "remove" => SeedLimitAction::Remove,
+            "remove-with-data" => SeedLimitAction::RemoveWithData,
+            _ => bail!("unknown seed limit action {}", text),
+        })
+    }
+}
+
+impl fmt::Display for SeedLimitAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SeedLimitAction::Pause => "pause",
+            SeedLimitAction::Stop => "stop",
+            SeedLimitAction::Remove => "remove",
+            SeedLimitAction::RemoveWithData => "remove-with-data",
+        })
+    }
+}
+
+/// Which seed limit a torrent reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedLimit {
+    Ratio,
+    SeedTime,
+    IdleTime,
+}
+
+impl fmt::Display for SeedLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SeedLimit::Ratio => "ratio",
+            SeedLimit::SeedTime => "seed time",
+            SeedLimit::IdleTime => "idle time",
+        })
+    }
+}
+
+/// When finished torrents stop seeding, the limits left out don't apply.
+/// The session has one set for every torrent, which a torrent can override
+/// with its own, see `Session::set_torrent_seed_limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SeedLimits {
+    /// uploaded bytes over the size of the selected files
+    pub ratio: Option<f64>,
+    /// spent seeding, across sessions
+    pub seed_time: Option<Duration>,
+    /// spent seeding without uploading anything
+    pub idle_time: Option<Duration>,
+    pub action: SeedLimitAction,
+}
+
+impl SeedLimits {
+    /// the first limit the torrent reached, see `Seeding`
+    pub fn reached(&self, seeding: &Seeding) -> Option<SeedLimit> {
+        if self.ratio.is_some_and(|ratio| seeding.ratio >= ratio) {
+            Some(SeedLimit::Ratio)
+        } else if self.seed_time.is_some_and(|time| seeding.seed_time >= time) {
+            Some(SeedLimit::SeedTime)
+        } else if self.idle_time.is_some_and(|time| seeding.idle_time >= time) {
+            Some(SeedLimit::IdleTime)
+        } else {
+            None
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self
+            .ratio
+            .is_some_and(|ratio| !(ratio > 0.0 && ratio.is_finite()))
+        {
+            bail!("the seed ratio has to be positive");
+        }
+        if self.seed_time.is_some_and(|time| time.is_zero())
+            || self.idle_time.is_some_and(|time| time.is_zero())
+        {
+            bail!("seed times have to be positive");
+        }
+        Ok(())
+    }
+}
+
+/// How much and how long a finished torrent seeded, see
+/// `Torrent::seeding`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Seeding {
+    pub ratio: f64,
+    pub seed_time: Duration,
+    pub idle_time: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reaches_limits() -> Result<()> {
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+        let limits = SeedLimits {
+            ratio: Some(2.0),
+            seed_time: Some(minutes(60)),
+            idle_time: Some(minutes(10)),
+            action: SeedLimitAction::parse("remove")?,
+        };
+        let seeding = Seeding {
+            ratio: 1.5,
+            seed_time: minutes(30),
+            idle_time: minutes(5),
+        };
+        assert_eq!(limits.reached(&seeding), None);
+        assert_eq!(SeedLimits::default().reached(&seeding), None);
+        for (seeding, limit) in [
+            (
+                Seeding {
+                    ratio: 2.0,
+                    ..seeding
+                },
+                SeedLimit::Ratio,
+            ),
+            (
+                Seeding {
+                    seed_time: minutes(61),
+                    ..seeding
+                },
+                SeedLimit::SeedTime,
+            ),
+            (
+                Seeding {
+                    idle_time: minutes(10),
+                    ..seeding
+                },
+                SeedLimit::IdleTime,
+            ),
+        ] {
+            assert_eq!(limits.reached(&seeding), Some(limit));
+        }
+        limits.validate()?;
+        for invalid in [
+            SeedLimits {
+                ratio: Some(0.0),
+                ..limits
+            },
+            SeedLimits {
+                ratio: Some(f64::NAN),
+                ..limits
+            },
+            SeedLimits {
+                idle_time: Some(Duration::ZERO),
+                ..limits
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+        assert!(SeedLimitAction::parse("delete").is_err());
+        let action = SeedLimitAction::RemoveWithData;
+        assert_eq!(SeedLimitAction::parse(&action.to_string())?, action);
+        Ok(())
+    }
+}
@@ -0,0 +1,2678 @@
+use crate::api::ApiServer;
+use crate::bandwidth::{Bandwidth, RateLimits};
+use crate::category::{self, Category};
+use crate::config::{BlocklistSource, Config, EncryptionPolicy, Proxy};
+use crate::connections::{ConnectionLimits, Connections};
+use crate::dht::routing::random_bytes;
+use crate::dht::{DhtEvent, DhtStats, DhtTask};
+use crate::events::{Alert, EventBus, SessionEvent};
+use crate::hooks::{self, Completion, HooksConfig};
+use crate::in_flight::InFlight;
+use crate::ip_filter::IpFilter;
+use crate::listen::{self, IpFamilies};
+use crate::lsd::LsdTask;
+use crate::magnet::Magnet;
+use crate::metadata::{MetadataDownload, MetadataEvent, MetadataTask};
+use crate::metainfo::{to_hex, Metainfo};
+use crate::metrics::{MetricKind, Metrics, MetricsServer};
+use crate::picker::Priority;
+use crate::port_mapping::{PortMapper, PortMapping};
+use crate::queue::Queue;
+use crate::resume::{FeedState, ResumeData, SavedTorrent, SessionState, TrackerStats};
+use crate::rss::{Download, Feed, FeedItem};
+use crate::schedule::{Schedule, ScheduleAction, WeekTime};
+use crate::scheduler::BLOCK_SIZE;
+use crate::seeding::{SeedLimitAction, SeedLimits};
+use crate::stream::StreamServer;
+use crate::swarm::SwarmTask;
+use crate::torrent::{Event, Torrent, TorrentHandle, TorrentState, TorrentStats};
+use crate::tracker::TrackerTask;
+use crate::watch::{self, WatchFolder};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// client and version at the start of our peer ids
+const PEER_ID_PREFIX: &[u8; 8] = b"-RS0100-";
+/// blocks in flight across every torrent, as many as the buffers of a
+/// single torrent hold by default, see `RequestLimits::max_buffer_memory`
+const MAX_REQUESTS_IN_FLIGHT: usize = 64 * 1024 * 1024 / BLOCK_SIZE as usize;
+
+/// Azureus style, our prefix and random digits
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0; 20];
+    random_bytes(&mut peer_id);
+    for byte in &mut peer_id[8..] {
+        *byte = b'0' + *byte % 10;
+    }
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    peer_id
+}
+
+/// The torrents of a client and what finds their peers: the listen socket,
+/// the trackers, the DHT and local service discovery, each running on its
+/// own thread. Torrents are added from their metainfo, or from a magnet link
+/// whose metadata is fetched first, and share the download and seed slots
+/// of the queue.
+pub struct Session {
+    peer_id: [u8; 20],
+    save_path: PathBuf,
+    resume_dir: Option<PathBuf>,
+    encryption: EncryptionPolicy,
+    proxy: Option<Proxy>,
+    ip_families: IpFamilies,
+    listener: TcpListener,
+    /// connects the torrents to their peers, over a clone of `listener`
+    swarm: Option<SwarmTask>,
+    queue: Arc<Mutex<Queue>>,
+    bandwidth: Arc<Mutex<Bandwidth>>,
+    events: EventBus,
+    /// in the order they were added
+    torrents: Vec<TorrentHandle>,
+    /// waiting for their metadata, see `poll_magnets`
+    magnets: HashMap<[u8; 20], MetadataTask>,
+    /// how the magnets are added once their metadata is received
+    magnet_options: HashMap<[u8; 20], AddOptions>,
+    trackers: Option<TrackerTask>,
+    dht: Option<DhtTask>,
+    lsd: Option<LsdTask>,
+    port_mapper: Option<PortMapper>,
+    metrics: Option<MetricsServer>,
+    stream: Option<StreamServer>,
+    api: Option<ApiServer>,
+    /// of the session itself, those of the torrents are kept by each
+    alerts: Vec<Alert>,
+    schedule: Schedule,
+    /// the rule of the schedule in effect, see `apply_schedule`
+    scheduled: Option<Scheduled>,
+    /// shared by the torrents and the magnet links
+    ip_filter: Arc<RwLock<IpFilter>>,
+    /// of each torrent, the limit of the session is kept by `connections`
+    max_peers: Option<usize>,
+    /// shared by the torrents
+    connections: Arc<Connections>,
+    /// blocks requested by all the torrents, shared by them
+    in_flight: Arc<InFlight>,
+    blocklist: Option<BlocklistRefresh>,
+    /// of the torrents without their own
+    seed_limits: SeedLimits,
+    categories: BTreeMap<String, Category>,
+    watch: Vec<WatchFolder>,
+    /// when the watch folders are next looked into
+    next_watch: Instant,
+    feeds: Vec<FeedRefresh>,
+    hooks: Option<Hooks>,
+}
+
+/// How a torrent is added, see `Session::add_torrent_with`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddOptions {
+    /// None downloads to the save path of its category, or of the session
+    pub save_path: Option<PathBuf>,
+    /// waits to be resumed
+    pub paused: bool,
+    /// one of `Session::categories`
+    pub category: Option<String>,
+    pub tags: BTreeSet<String>,
+}
+
+/// Keeps the ip filter up to date with a blocklist url, see `tick`
+struct BlocklistRefresh {
+    url: String,
+    every: Duration,
+    next: Instant,
+    fetch: Option<JoinHandle<Result<IpFilter>>>,
+}
+
+/// Adds the new items of a feed every `feed.refresh`, see `tick`
+struct FeedRefresh {
+    name: String,
+    feed: Feed,
+    /// the ids of the items that were added, of those the feed last listed
+    seen: BTreeSet<String>,
+    next: Instant,
+    fetch: Option<JoinHandle<Result<FetchedFeed>>>,
+}
+
+/// What a feed listed, with the downloads of the items to add
+struct FetchedFeed {
+    ids: Vec<String>,
+    downloads: Vec<(FeedItem, Result<Download>)>,
+}
+
+/// Runs the hooks of the config as torrents complete, see `tick`
+struct Hooks {
+    config: HooksConfig,
+    events: Receiver<SessionEvent>,
+    /// finished torrents whose data is still on its way to the disk
+    finished: Vec<[u8; 20]>,
+    running: Vec<([u8; 20], JoinHandle<Result<()>>)>,
+}
+
+/// the items of the feed, downloading those it wants that weren't seen
+fn fetch_feed(feed: &Feed, seen: &BTreeSet<String>) -> Result<FetchedFeed> {
+    let items = feed.fetch()?;
+    Ok(FetchedFeed {
+        ids: items.iter().map(|item| item.id.clone()).collect(),
+        downloads: items
+            .into_iter()
+            .filter(|item| !seen.contains(&item.id) && feed.wants(item))
+            .map(|item| {
+                let download = item.download();
+                (item, download)
+            })
+            .collect(),
+    })
+}
+
+/// name, kind, help and value of a metric with a sample per `T`
+type Metric<T> = (&'static str, MetricKind, &'static str, fn(&T) -> f64);
+
+/// What `Session::metrics` reports of a torrent, read once
+struct TorrentMetrics {
+    info_hash: String,
+    name: String,
+    state: TorrentState,
+    /// download and upload, in bytes/s
+    rates: (f64, f64),
+    stats: TorrentStats,
+    trackers: Vec<TrackerStats>,
+}
+
+impl TorrentMetrics {
+    fn labels(&self) -> [(&str, &str); 2] {
+        [("info_hash", &self.info_hash), ("name", &self.name)]
+    }
+}
+
+/// What to undo once a rule of the schedule stops applying
+struct Scheduled {
+    action: ScheduleAction,
+    /// of the session before the rule applied
+    limits: RateLimits,
+    /// by the rule, the torrents paused before are left paused
+    paused: Vec<[u8; 20]>,
+}
+
+impl Session {
+    /// fails on invalid configs, see `Config::validate`
+    pub fn new(config: Config) -> Result<Self> {
+        config.validate()?;
+        let peer_id = generate_peer_id();
+        let events = EventBus::new();
+        let mut alerts = vec![];
+        let ip = match &config.listen_interface {
+            Some(interface) => Some(listen::interface_address(interface)?),
+            None => None,
+        };
+        if let Some(ip) = ip.filter(|ip| !config.ip_families.allows(*ip)) {
+            bail!(
+                "can't listen on {} with ip families {}",
+                ip,
+                config.ip_families
+            );
+        }
+        let listeners = listen::bind(
+            ip,
+            config.listen_port,
+            config.dht.is_some(),
+            config.ip_families,
+        )?;
+        let port = listeners.port()?;
+        alerts.extend(listeners.alert);
+        let listener = listeners.tcp;
+        let dht = match (config.dht, listeners.udp, listeners.udp6) {
+            (Some(dht), Some(socket), Some(socket6)) => {
+                Some(DhtTask::start_dual_stack(socket, socket6, dht)?)
+            }
+            (Some(dht), Some(socket), None) | (Some(dht), None, Some(socket)) => {
+                Some(DhtTask::start(socket, dht)?)
+            }
+            _ => None,
+        };
+        let lsd = match config.lsd {
+            true => Some(LsdTask::start(port)?),
+            false => None,
+        };
+        let port_mapper = config.port_mapping.then(|| PortMapper::start(port));
+        let metrics = match config.metrics {
+            Some(addr) => Some(
+                MetricsServer::start(addr)
+                    .with_context(|| format!("serving metrics on {}", addr))?,
+            ),
+            None => None,
+        };
+        let stream = match config.stream {
+            Some(addr) => Some(
+                StreamServer::start(addr, config.api.as_ref().and_then(|api| api.token.clone()))
+                    .with_context(|| format!("streaming files on {}", addr))?,
+            ),
+            None => None,
+        };
+        let api = match config.api {
+            Some(api) => {
+                let addr = api.addr;
+                Some(
+                    ApiServer::start(api, events.subscribe())
+                        .with_context(|| format!("serving the api on {}", addr))?,
+                )
+            }
+            None => None,
+        };
+        let hooks = match config.hooks.is_empty() {
+            true => None,
+            false => Some(Hooks {
+                config: config.hooks,
+                events: events.subscribe(),
+                finished: vec![],
+                running: vec![],
+            }),
+        };
+        let mut ip_filter = IpFilter::new();
+        let blocklist = match config.blocklist {
+            Some(BlocklistSource::File(path)) => {
+                ip_filter = IpFilter::load(path)?;
+                None
+            }
+            Some(BlocklistSource::Url { url, refresh }) => Some(BlocklistRefresh {
+                url,
+                every: refresh,
+                next: Instant::now(),
+                fetch: None,
+            }),
+            None => None,
+        };
+        let feed_state = match &config.resume_dir {
+            Some(dir) => FeedState::load(dir)?,
+            None => FeedState::default(),
+        };
+        let feeds = config
+            .feeds
+            .into_iter()
+            .map(|(name, feed)| FeedRefresh {
+                seen: feed_state.seen.get(&name).cloned().unwrap_or_default(),
+                name,
+                feed,
+                next: Instant::now(),
+                fetch: None,
+            })
+            .collect();
+        let bandwidth = Arc::new(Mutex::new(Bandwidth::new(config.rate_limits)));
+        let swarm = SwarmTask::start(listener.try_clone()?, peer_id, Arc::clone(&bandwidth))?;
+        let mut session = Self {
+            peer_id,
+            save_path: config.save_path,
+            resume_dir: config.resume_dir,
+            encryption: config.encryption,
+            proxy: config.proxy,
+            ip_families: config.ip_families,
+            schedule: config.schedule,
+            scheduled: None,
+            listener,
+            swarm: Some(swarm),
+            queue: Arc::new(Mutex::new(Queue::new(config.queue))),
+            bandwidth,
+            torrents: vec![],
+            magnets: HashMap::new(),
+            magnet_options: HashMap::new(),
+            trackers: config
+                .trackers
+                .then(|| TrackerTask::start(peer_id, events.clone())),
+            events,
+            dht,
+            lsd,
+            port_mapper,
+            metrics,
+            stream,
+            api,
+            alerts,
+            ip_filter: Arc::new(RwLock::new(ip_filter)),
+            max_peers: config.connection_limits.max_peers,
+            connections: Arc::new(Connections::new(config.connection_limits.max_connections)),
+            in_flight: Arc::new(InFlight::new(MAX_REQUESTS_IN_FLIGHT)),
+            blocklist,
+            seed_limits: config.seed_limits,
+            categories: config.categories,
+            watch: config.watch,
+            next_watch: Instant::now(),
+            feeds,
+            hooks,
+        };
+        session.refresh_blocklist(Instant::now());
+        session.restore()?;
+        Ok(session)
+    }
+
+    /// raised by the session since the last call, see
+    /// `TorrentHandle::take_alerts` for those of the torrents
+    pub fn take_alerts(&mut self) -> Vec<Alert> {
+        std::mem::take(&mut self.alerts)
+    }
+
+    /// the events of the session and its torrents from now on, a
+    /// subscriber can wait for them on its own thread
+    pub fn subscribe(&self) -> Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// the port actually listened on, which trackers, the DHT and local
+    /// service discovery advertise
+    pub fn listen_port(&self) -> u16 {
+        self.listener.local_addr().map_or(0, |addr| addr.port())
+    }
+
+    pub fn save_path(&self) -> &PathBuf {
+        &self.save_path
+    }
+
+    pub fn resume_dir(&self) -> Option<&PathBuf> {
+        self.resume_dir.as_ref()
+    }
+
+    pub fn encryption(&self) -> EncryptionPolicy {
+        self.encryption
+    }
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    pub fn ip_families(&self) -> IpFamilies {
+        self.ip_families
+    }
+
+    /// accepts the connections of peers
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    pub fn dht(&self) -> Option<&DhtTask> {
+        self.dht.as_ref()
+    }
+
+    pub fn lsd(&self) -> Option<&LsdTask> {
+        self.lsd.as_ref()
+    }
+
+    /// of the listen port on the gateway, empty without port mapping
+    pub fn port_mappings(&self) -> Vec<PortMapping> {
+        self.port_mapper
+            .as_ref()
+            .map(PortMapper::mappings)
+            .unwrap_or_default()
+    }
+
+    /// where the metrics are served, see `Config::metrics`
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().map(MetricsServer::local_addr)
+    }
+
+    /// where the files are streamed, see `Config::stream`
+    pub fn stream_addr(&self) -> Option<SocketAddr> {
+        self.stream.as_ref().map(StreamServer::local_addr)
+    }
+
+    /// where the REST api is served, see `Config::api`
+    pub fn api_addr(&self) -> Option<SocketAddr> {
+        self.api.as_ref().map(ApiServer::local_addr)
+    }
+
+    /// answers the api requests waiting, from the loop that owns the
+    /// session like `tick`
+    pub fn poll_api(&mut self) {
+        while let Some(request) = self.api.as_ref().and_then(ApiServer::poll) {
+            request.apply(self);
+        }
+    }
+
+    pub fn queue(&self) -> &Arc<Mutex<Queue>> {
+        &self.queue
+    }
+
+    /// what peer connections ask before transferring anything
+    pub fn bandwidth(&self) -> &Arc<Mutex<Bandwidth>> {
+        &self.bandwidth
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.bandwidth.lock().unwrap().limits()
+    }
+
+    /// shared by every torrent, applies right away
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.bandwidth.lock().unwrap().set_limits(limits);
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_peers: self.max_peers,
+            max_connections: self.connections.max(),
+        }
+    }
+
+    /// peers connected over the new limits stay until they leave, no new
+    /// ones are connected to until then
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.max_peers = limits.max_peers;
+        self.connections.set_max(limits.max_connections);
+        for torrent in &self.torrents {
+            torrent.set_max_peers(limits.max_peers);
+        }
+    }
+
+    /// to peers of every torrent
+    pub fn connection_count(&self) -> usize {
+        self.connections.open()
+    }
+
+    pub fn torrent_rate_limits(&self, torrent: &TorrentHandle) -> RateLimits {
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .torrent_limits(&torrent.info_hash())
+    }
+
+    /// within the limits of the session
+    pub fn set_torrent_rate_limits(&self, torrent: &TorrentHandle, limits: RateLimits) {
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .set_torrent_limits(torrent.info_hash(), limits);
+    }
+
+    /// within the limits of the torrent
+    pub fn set_peer_rate_limits(
+        &self,
+        torrent: &TorrentHandle,
+        peer: SocketAddr,
+        limits: RateLimits,
+    ) {
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .set_peer_limits(torrent.info_hash(), peer, limits);
+    }
+
+    pub fn seed_limits(&self) -> SeedLimits {
+        self.seed_limits
+    }
+
+    /// of the torrents without their own
+    pub fn set_seed_limits(&mut self, limits: SeedLimits) -> Result<()> {
+        limits.validate()?;
+        self.seed_limits = limits;
+        Ok(())
+    }
+
+    /// None makes the torrent follow the limits of the session
+    pub fn set_torrent_seed_limits(
+        &self,
+        torrent: &TorrentHandle,
+        limits: Option<SeedLimits>,
+    ) -> Result<()> {
+        if let Some(limits) = &limits {
+            limits.validate()?;
+        }
+        torrent.set_seed_limits(limits);
+        self.save_state()
+    }
+
+    /// by name
+    pub fn categories(&self) -> &BTreeMap<String, Category> {
+        &self.categories
+    }
+
+    /// adds or changes a category, its torrents get its new rate limits and
+    /// queue priority
+    pub fn set_category(&mut self, name: &str, category: Category) -> Result<()> {
+        category::validate_label(name)?;
+        category.validate()?;
+        self.categories.insert(name.to_string(), category);
+        for torrent in self.torrents_in_category(name) {
+            self.apply_category(&torrent);
+        }
+        Ok(())
+    }
+
+    /// its torrents are left without a category, and without rate limits
+    pub fn remove_category(&mut self, name: &str) -> Result<()> {
+        if self.categories.remove(name).is_none() {
+            bail!("unknown category {}", name);
+        }
+        for torrent in self.torrents_in_category(name) {
+            torrent.set_category(None);
+            self.set_torrent_rate_limits(&torrent, RateLimits::default());
+        }
+        self.save_state()
+    }
+
+    /// gives the torrent the rate limits of the category, replacing its own,
+    /// and queues it ahead of the torrents of lower priority categories. Its
+    /// files stay where they are, the save path of a category only applies
+    /// to the torrents added to it. None leaves it without a category.
+    pub fn set_torrent_category(&self, torrent: &TorrentHandle, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name.filter(|name| !self.categories.contains_key(*name)) {
+            bail!("unknown category {}", name);
+        }
+        torrent.set_category(name.map(String::from));
+        self.apply_category(torrent);
+        self.save_state()
+    }
+
+    /// replaces the tags of the torrent, free labels unlike categories
+    pub fn set_torrent_tags<T: Into<String>>(
+        &self,
+        torrent: &TorrentHandle,
+        tags: impl IntoIterator<Item = T>,
+    ) -> Result<()> {
+        let tags: BTreeSet<String> = tags.into_iter().map(Into::into).collect();
+        for tag in &tags {
+            category::validate_label(tag)?;
+        }
+        torrent.set_tags(tags);
+        self.save_state()
+    }
+
+    /// in the order they were added
+    pub fn torrents_in_category(&self, name: &str) -> Vec<TorrentHandle> {
+        self.torrents
+            .iter()
+            .filter(|torrent| torrent.category().as_deref() == Some(name))
+            .cloned()
+            .collect()
+    }
+
+    /// in the order they were added
+    pub fn torrents_tagged(&self, tag: &str) -> Vec<TorrentHandle> {
+        self.torrents
+            .iter()
+            .filter(|torrent| torrent.tags().contains(tag))
+            .cloned()
+            .collect()
+    }
+
+    /// 0 without a category
+    fn queue_priority(&self, torrent: &TorrentHandle) -> i32 {
+        torrent
+            .category()
+            .and_then(|name| self.categories.get(&name))
+            .map_or(0, |category| category.queue_priority)
+    }
+
+    /// see `set_torrent_category`
+    fn apply_category(&self, torrent: &TorrentHandle) {
+        let limits = torrent
+            .category()
+            .and_then(|name| self.categories.get(&name))
+            .map(|category| category.rate_limits)
+            .unwrap_or_default();
+        self.set_torrent_rate_limits(torrent, limits);
+        let priority = self.queue_priority(torrent);
+        let mut others: Vec<_> = self
+            .torrents
+            .iter()
+            .filter(|other| other.info_hash() != torrent.info_hash())
+            .filter_map(|other| Some((other.queue_position()?, self.queue_priority(other))))
+            .collect();
+        others.sort_unstable();
+        // behind the torrents of the same or a higher priority
+        let position = others
+            .iter()
+            .rposition(|(_, other)| *other >= priority)
+            .map_or(0, |index| index + 1);
+        torrent.queue_move_to(position);
+    }
+
+    /// adds the torrent at the bottom of the queue and starts looking for
+    /// its peers, replacing its magnet link if it was added from one
+    pub fn add_torrent(&mut self, metainfo: Metainfo) -> Result<TorrentHandle> {
+        self.add_torrent_with(metainfo, AddOptions::default())
+    }
+
+    /// like `add_torrent`, downloading to `save_path` instead of the save
+    /// path of the session
+    pub fn add_torrent_to(
+        &mut self,
+        metainfo: Metainfo,
+        save_path: impl Into<PathBuf>,
+    ) -> Result<TorrentHandle> {
+        let options = AddOptions {
+            save_path: Some(save_path.into()),
+            ..Default::default()
+        };
+        self.add_torrent_with(metainfo, options)
+    }
+
+    /// like `add_torrent`, with options. With a resume dir the torrent is
+    /// added back by the next session, see `save_state`.
+    pub fn add_torrent_with(
+        &mut self,
+        metainfo: Metainfo,
+        options: AddOptions,
+    ) -> Result<TorrentHandle> {
+        if self.torrent(&metainfo.info_hash).is_some() {
+            bail!("torrent {} already added", metainfo.info.name);
+        }
+        self.check_options(&options)?;
+        if let Some(dir) = &self.resume_dir {
+            SessionState::save_metainfo(dir, &metainfo)?;
+        }
+        let category = options.category.as_ref().map(|name| &self.categories[name]);
+        let save_path = options
+            .save_path
+            .or_else(|| category.and_then(|category| category.save_path.clone()))
+            .unwrap_or_else(|| self.save_path.clone());
+        let handle = self.insert_torrent(metainfo, save_path, options.paused, None)?;
+        handle.set_tags(options.tags);
+        if options.category.is_some() {
+            handle.set_category(options.category);
+            self.apply_category(&handle);
+        }
+        self.save_state()?;
+        Ok(handle)
+    }
+
+    /// the category has to exist and the tags to be valid labels
+    fn check_options(&self, options: &AddOptions) -> Result<()> {
+        if let Some(name) = &options.category {
+            if !self.categories.contains_key(name) {
+                bail!("unknown category {}", name);
+            }
+        }
+        for tag in &options.tags {
+            category::validate_label(tag)?;
+        }
+        Ok(())
+    }
+
+    /// adds the torrent, paused or not and with its file priorities, without
+    /// saving the state of the session
+    fn insert_torrent(
+        &mut self,
+        metainfo: Metainfo,
+        save_path: PathBuf,
+        paused: bool,
+        file_priorities: Option<&[Priority]>,
+    ) -> Result<TorrentHandle> {
+        self.magnets.remove(&metainfo.info_hash);
+        let info_hash = metainfo.info_hash;
+        let mut torrent = Torrent::new(metainfo, save_path);
+        // where the last session left off, see `shutdown`, or what is
+        // already on disk
+        torrent.restore(self.resume_dir.as_deref())?;
+        if let Some(priorities) = file_priorities {
+            torrent.set_file_priorities(priorities)?;
+        }
+        torrent.set_paused(paused);
+        torrent.set_event_bus(self.events.clone());
+        torrent.set_ip_filter(Arc::clone(&self.ip_filter));
+        torrent.set_ip_families(self.ip_families);
+        torrent.set_max_peers(self.max_peers);
+        torrent.set_connections(Arc::clone(&self.connections));
+        torrent.set_in_flight(Arc::clone(&self.in_flight));
+        let state = torrent.state();
+        tracing::info!(
+            info_hash = %to_hex(&info_hash),
+            name = %torrent.root_name(),
+            %state,
+            "torrent added"
+        );
+        self.events.send(SessionEvent::TorrentAdded { info_hash });
+        self.events
+            .send(SessionEvent::StateChanged { info_hash, state });
+        let handle = TorrentHandle::queued(torrent, &self.queue);
+        let port = self.listen_port();
+        if let Some(trackers) = &self.trackers {
+            trackers.add_torrent(&handle, port);
+        }
+        // paused torrents are announced once resumed
+        if let Some(dht) = self.dht.as_ref().filter(|_| !paused) {
+            dht.add_torrent(&handle, port);
+        }
+        if let Some(lsd) = self.lsd.as_ref().filter(|_| !paused) {
+            lsd.add_torrent(&handle);
+        }
+        if let Some(swarm) = &self.swarm {
+            swarm.add_torrent(&handle);
+        }
+        self.torrents.push(handle.clone());
+        Ok(handle)
+    }
+
+    /// writes the torrents, in queue order, with their save path, whether
+    /// they are paused and their file priorities to the resume dir. Done as
+    /// torrents are added and removed, and on `shutdown`.
+    pub fn save_state(&self) -> Result<()> {
+        let dir = match &self.resume_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let mut torrents: Vec<_> = self.torrents.iter().collect();
+        torrents.sort_by_key(|torrent| torrent.queue_position().unwrap_or(usize::MAX));
+        let state = SessionState {
+            torrents: torrents
+                .into_iter()
+                .map(|torrent| SavedTorrent {
+                    info_hash: torrent.info_hash(),
+                    save_path: torrent.save_path(),
+                    paused: torrent.is_paused(),
+                    file_priorities: torrent.file_priorities(),
+                    seed_limits: torrent.seed_limits(),
+                    category: torrent.category(),
+                    tags: torrent.tags(),
+                })
+                .collect(),
+        };
+        state.save(dir)
+    }
+
+    /// adds back the torrents saved by the last session, those that can't
+    /// be are alerts
+    fn restore(&mut self) -> Result<()> {
+        let dir = match &self.resume_dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(()),
+        };
+        for saved in SessionState::load(&dir)?.torrents {
+            let info_hash = saved.info_hash;
+            let restored = SessionState::load_metainfo(&dir, &info_hash).and_then(|metainfo| {
+                let torrent = self.insert_torrent(
+                    metainfo,
+                    saved.save_path,
+                    saved.paused,
+                    Some(&saved.file_priorities),
+                )?;
+                torrent.set_seed_limits(saved.seed_limits);
+                torrent.set_tags(saved.tags);
+                // categories removed from the config since are dropped
+                if let Some(category) = saved
+                    .category
+                    .as_ref()
+                    .and_then(|name| self.categories.get(name))
+                {
+                    torrent.set_category(saved.category.clone());
+                    self.set_torrent_rate_limits(&torrent, category.rate_limits);
+                }
+                Ok(torrent)
+            });
+            if let Err(error) = restored {
+                let torrent = to_hex(&info_hash);
+                let error = format!("{:#}", error);
+                tracing::warn!(info_hash = %torrent, %error, "restoring a torrent failed");
+                self.alerts.push(Alert::RestoreFailed { torrent, error });
+            }
+        }
+        Ok(())
+    }
+
+    /// starts fetching the metadata of the torrent from the peers its
+    /// trackers and the DHT know about, it is added once `poll_magnets`
+    /// finds the metadata complete
+    pub fn add_magnet(&mut self, uri: &str) -> Result<[u8; 20]> {
+        self.add_magnet_with(uri, AddOptions::default())
+    }
+
+    /// like `add_magnet`, the torrent being added with the options
+    pub fn add_magnet_with(&mut self, uri: &str, options: AddOptions) -> Result<[u8; 20]> {
+        self.check_options(&options)?;
+        let magnet = Magnet::parse(uri)?;
+        let info_hash = magnet.info_hash;
+        if self.magnets.contains_key(&info_hash) || self.torrent(&info_hash).is_some() {
+            bail!("torrent {} already added", uri);
+        }
+        if let Some(dht) = &self.dht {
+            dht.dht().lock().unwrap().lookup(info_hash);
+        }
+        let download = MetadataDownload::new(magnet);
+        let task = MetadataTask::start(download, self.peer_id, self.listen_port());
+        task.set_ip_filter(Arc::clone(&self.ip_filter));
+        self.magnets.insert(info_hash, task);
+        self.magnet_options.insert(info_hash, options);
+        self.events.send(SessionEvent::StateChanged {
+            info_hash,
+            state: TorrentState::FetchingMetadata,
+        });
+        Ok(info_hash)
+    }
+
+    /// metadata download of a magnet link added with `add_magnet`
+    pub fn magnet(&self, info_hash: &[u8; 20]) -> Option<&MetadataTask> {
+        self.magnets.get(info_hash)
+    }
+
+    /// of the magnet links still fetching their metadata
+    pub fn magnet_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes: Vec<_> = self.magnets.keys().copied().collect();
+        hashes.sort_unstable();
+        hashes
+    }
+
+    /// updates the transfer rates of the torrents, hands the queue slots
+    /// that opened up, as torrents finished or turned out slow, to the next
+    /// ones in line, acts on the torrents that reached their seed limits,
+    /// follows the schedule, adds what showed up in the watch folders and
+    /// the rss feeds, runs the hooks of the torrents that completed and
+    /// refreshes the served metrics and streamed torrents. Meant to be
+    /// called about once a second.
+    pub fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        for torrent in &self.torrents {
+            torrent.tick(now);
+        }
+        self.queue.lock().unwrap().update_at(now);
+        self.apply_seed_limits()?;
+        self.refresh_blocklist(now);
+        self.apply_schedule(WeekTime::now())?;
+        self.scan_watch_folders(now);
+        self.refresh_feeds(now);
+        self.run_hooks();
+        if let Some(server) = &self.stream {
+            server.update(self.torrents.clone());
+        }
+        if let Some(server) = &self.metrics {
+            server.update(self.metrics());
+        }
+        Ok(())
+    }
+
+    /// of the session, its torrents, their trackers, the DHT and the disk,
+    /// as served to Prometheus
+    pub fn metrics(&self) -> Metrics {
+        use MetricKind::{Counter, Gauge};
+        let mut metrics = Metrics::new();
+        let torrents: Vec<_> = self
+            .torrents
+            .iter()
+            .map(|torrent| TorrentMetrics {
+                info_hash: to_hex(&torrent.info_hash()),
+                name: torrent.root_name(),
+                state: torrent.state(),
+                rates: torrent.rates(),
+                stats: torrent.stats(),
+                trackers: torrent.trackers(),
+            })
+            .collect();
+
+        let mut states: BTreeMap<String, usize> = [
+            TorrentState::FetchingMetadata,
+            TorrentState::Allocating,
+            TorrentState::CheckingFiles,
+            TorrentState::Downloading,
+            TorrentState::Seeding,
+            TorrentState::Paused,
+            TorrentState::Errored,
+        ]
+        .iter()
+        .map(|state| (state.to_string(), 0))
+        .collect();
+        *states
+            .get_mut(&TorrentState::FetchingMetadata.to_string())
+            .unwrap() += self.magnets.len();
+        for torrent in &torrents {
+            *states.entry(torrent.state.to_string()).or_default() += 1;
+        }
+        metrics.family(
+            "torrents",
+            Gauge,
+            "Torrents by state, magnet links included",
+        );
+        for (state, count) in &states {
+            metrics.sample(&[("state", state)], *count as f64);
+        }
+        let (download_rate, upload_rate) = torrents.iter().fold((0.0, 0.0), |rates, torrent| {
+            (rates.0 + torrent.rates.0, rates.1 + torrent.rates.1)
+        });
+        metrics
+            .family(
+                "session_download_rate_bytes",
+                Gauge,
+                "Bytes received per second by every torrent",
+            )
+            .sample(&[], download_rate)
+            .family(
+                "session_upload_rate_bytes",
+                Gauge,
+                "Bytes sent per second by every torrent",
+            )
+            .sample(&[], upload_rate)
+            .family(
+                "ip_filter_blocked_total",
+                Counter,
+                "Peer addresses refused by the ip filter",
+            )
+            .sample(&[], self.ip_filter.read().unwrap().hits() as f64);
+
+        let per_torrent: [Metric<TorrentMetrics>; 16] = [
+            (
+                "torrent_progress_ratio",
+                Gauge,
+                "Share of the selected data downloaded",
+                |torrent| torrent.stats.progress,
+            ),
+            (
+                "torrent_downloaded_bytes_total",
+                Counter,
+                "Bytes received from peers",
+                |torrent| torrent.stats.downloaded as f64,
+            ),
+            (
+                "torrent_uploaded_bytes_total",
+                Counter,
+                "Bytes sent to peers",
+                |torrent| torrent.stats.uploaded as f64,
+            ),
+            (
+                "torrent_wasted_bytes_total",
+                Counter,
+                "Bytes received and thrown away",
+                |torrent| torrent.stats.wasted as f64,
+            ),
+            (
+                "torrent_download_rate_bytes",
+                Gauge,
+                "Bytes received per second",
+                |torrent| torrent.rates.0,
+            ),
+            (
+                "torrent_upload_rate_bytes",
+                Gauge,
+                "Bytes sent per second",
+                |torrent| torrent.rates.1,
+            ),
+            (
+                "torrent_distributed_copies",
+                Gauge,
+                "Full copies available among connected peers",
+                |torrent| torrent.stats.distributed_copies,
+            ),
+            (
+                "torrent_cache_hits_total",
+                Counter,
+                "Upload requests served from the read cache",
+                |torrent| torrent.stats.cache_hits as f64,
+            ),
+            (
+                "torrent_cache_misses_total",
+                Counter,
+                "Upload requests served from the disk",
+                |torrent| torrent.stats.cache_misses as f64,
+            ),
+            (
+                "disk_queue_depth",
+                Gauge,
+                "Disk jobs waiting or running",
+                |torrent| torrent.stats.disk.queue_depth as f64,
+            ),
+            (
+                "disk_pending_bytes",
+                Gauge,
+                "Bytes received but not written yet",
+                |torrent| torrent.stats.disk.pending_bytes as f64,
+            ),
+            (
+                "disk_read_rate_bytes",
+                Gauge,
+                "Bytes read from the disk per second",
+                |torrent| torrent.stats.disk.read_rate,
+            ),
+            (
+                "disk_write_rate_bytes",
+                Gauge,
+                "Bytes written to the disk per second",
+                |torrent| torrent.stats.disk.write_rate,
+            ),
+            (
+                "disk_cache_hit_ratio",
+                Gauge,
+                "Share of upload requests served from the read cache",
+                |torrent| torrent.stats.disk.cache_hit_rate,
+            ),
+            (
+                "disk_read_errors_total",
+                Counter,
+                "Failed disk reads",
+                |torrent| torrent.stats.disk.read_errors as f64,
+            ),
+            (
+                "disk_write_errors_total",
+                Counter,
+                "Failed disk writes",
+                |torrent| torrent.stats.disk.write_errors as f64,
+            ),
+        ];
+        for (name, kind, help, value) in &per_torrent {
+            metrics.family(name, *kind, help);
+            for torrent in &torrents {
+                metrics.sample(&torrent.labels(), value(torrent));
+            }
+        }
+        metrics.family(
+            "torrent_peers",
+            Gauge,
+            "Connected peers by how they were found",
+        );
+        for torrent in &torrents {
+            for (source, count) in &torrent.stats.peers_by_source {
+                let source = source.to_string();
+                let mut labels = torrent.labels().to_vec();
+                labels.push(("source", &source));
+                metrics.sample(&labels, *count as f64);
+            }
+        }
+
+        let per_tracker: [Metric<TrackerStats>; 4] = [
+            (
+                "tracker_seeders",
+                Gauge,
+                "Seeders in the last announce or scrape",
+                |tracker| tracker.seeders as f64,
+            ),
+            (
+                "tracker_leechers",
+                Gauge,
+                "Leechers in the last announce or scrape",
+                |tracker| tracker.leechers as f64,
+            ),
+            (
+                "tracker_completed",
+                Gauge,
+                "Downloads the tracker saw complete",
+                |tracker| tracker.completed as f64,
+            ),
+            (
+                "tracker_last_announce_timestamp_seconds",
+                Gauge,
+                "Unix time of the last announce",
+                |tracker| tracker.last_announce as f64,
+            ),
+        ];
+        for (name, kind, help, value) in &per_tracker {
+            metrics.family(name, *kind, help);
+            for torrent in &torrents {
+                for tracker in &torrent.trackers {
+                    let labels = [
+                        ("info_hash", torrent.info_hash.as_str()),
+                        ("url", &tracker.url),
+                    ];
+                    metrics.sample(&labels, value(tracker));
+                }
+            }
+        }
+
+        if let Some(dht) = &self.dht {
+            // the main node runs over ipv6 only when ipv4 isn't allowed
+            let family = match self.ip_families {
+                IpFamilies::V6Only => "ipv6",
+                _ => "ipv4",
+            };
+            let mut nodes = vec![(family, dht.stats())];
+            nodes.extend(dht.stats6().map(|stats| ("ipv6", stats)));
+            let per_node: [Metric<DhtStats>; 9] = [
+                ("dht_nodes", Gauge, "Nodes in the routing table", |dht| {
+                    dht.nodes as f64
+                }),
+                ("dht_lookups", Gauge, "Lookups running", |dht| {
+                    dht.lookups as f64
+                }),
+                (
+                    "dht_queries",
+                    Gauge,
+                    "Queries waiting for an answer",
+                    |dht| dht.queries as f64,
+                ),
+                (
+                    "dht_received_packets_total",
+                    Counter,
+                    "Packets received",
+                    |dht| dht.packets_in as f64,
+                ),
+                ("dht_sent_packets_total", Counter, "Packets sent", |dht| {
+                    dht.packets_out as f64
+                }),
+                (
+                    "dht_received_bytes_total",
+                    Counter,
+                    "Bytes received",
+                    |dht| dht.bytes_in as f64,
+                ),
+                ("dht_sent_bytes_total", Counter, "Bytes sent", |dht| {
+                    dht.bytes_out as f64
+                }),
+                (
+                    "dht_dropped_packets_total",
+                    Counter,
+                    "Packets over the rate limits",
+                    |dht| dht.packets_dropped as f64,
+                ),
+                (
+                    "dht_stored_peers",
+                    Gauge,
+                    "Peers stored for other nodes",
+                    |dht| dht.peers as f64,
+                ),
+            ];
+            for (name, kind, help, value) in &per_node {
+                metrics.family(name, *kind, help);
+                for (family, stats) in &nodes {
+                    metrics.sample(&[("family", family)], value(stats));
+                }
+            }
+        }
+        metrics
+    }
+
+    /// the blocked addresses, with how many peers it blocked
+    pub fn ip_filter(&self) -> &Arc<RwLock<IpFilter>> {
+        &self.ip_filter
+    }
+
+    /// whether a connection from the address is to be refused, to check
+    /// the connections `listener` accepts. Counted as a hit when it is.
+    pub fn blocks(&self, addr: SocketAddr) -> bool {
+        self.ip_filter.read().unwrap().blocks(addr.ip())
+    }
+
+    /// starts fetching the blocklist when it is time to, and swaps it in
+    /// once fetched. Failures are alerts and keep the previous one.
+    fn refresh_blocklist(&mut self, now: Instant) {
+        let refresh = match &mut self.blocklist {
+            Some(refresh) => refresh,
+            None => return,
+        };
+        match refresh.fetch.take() {
+            Some(fetch) if fetch.is_finished() => {
+                let error = match fetch.join() {
+                    Ok(Ok(filter)) => {
+                        tracing::info!(ranges = filter.len(), "blocklist refreshed");
+                        self.ip_filter.write().unwrap().replace(filter);
+                        None
+                    }
+                    Ok(Err(error)) => Some(format!("{:#}", error)),
+                    Err(_) => Some("fetching panicked".into()),
+                };
+                if let Some(error) = error {
+                    tracing::warn!(%error, "blocklist refresh failed");
+                    self.alerts.push(Alert::BlocklistFailed { error });
+                }
+                refresh.next = now + refresh.every;
+            }
+            Some(fetch) => refresh.fetch = Some(fetch),
+            None if now >= refresh.next => {
+                let url = refresh.url.clone();
+                refresh.fetch = Some(thread::spawn(move || IpFilter::fetch(&url)));
+            }
+            None => {}
+        }
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// followed from the next `tick`
+    pub fn set_schedule(&mut self, schedule: Schedule) -> Result<()> {
+        schedule.validate()?;
+        self.schedule = schedule;
+        Ok(())
+    }
+
+    /// applies the rule of the schedule for the time once it starts, and
+    /// restores the rate limits and resumes the torrents it paused once it
+    /// ends
+    pub fn apply_schedule(&mut self, time: WeekTime) -> Result<()> {
+        let action = self.schedule.action_at(time);
+        if self.scheduled.as_ref().map(|scheduled| scheduled.action) == action {
+            return Ok(());
+        }
+        if let Some(ended) = self.scheduled.take() {
+            self.set_rate_limits(ended.limits);
+            for info_hash in ended.paused {
+                if let Some(torrent) = self.torrent(&info_hash) {
+                    self.resume(torrent);
+                }
+            }
+        }
+        let action = match action {
+            Some(action) => action,
+            None => return Ok(()),
+        };
+        self.scheduled = Some(Scheduled {
+            action,
+            limits: self.rate_limits(),
+            paused: vec![],
+        });
+        match action {
+            ScheduleAction::Limit(limits) => self.set_rate_limits(limits),
+            ScheduleAction::Pause => {
+                for torrent in self.torrents.clone() {
+                    if torrent.is_paused() {
+                        continue;
+                    }
+                    if let Some(scheduled) = &mut self.scheduled {
+                        scheduled.paused.push(torrent.info_hash());
+                    }
+                    self.pause(&torrent)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// pauses or removes the seeding torrents over their seed limits, or
+    /// those of the session
+    fn apply_seed_limits(&mut self) -> Result<()> {
+        let reached: Vec<_> = self
+            .torrents
+            .iter()
+            .filter(|torrent| torrent.state() == TorrentState::Seeding)
+            .filter_map(|torrent| {
+                let limits = torrent.seed_limits().unwrap_or(self.seed_limits);
+                let limit = limits.reached(&torrent.seeding())?;
+                Some((torrent.clone(), limit, limits.action))
+            })
+            .collect();
+        for (torrent, limit, action) in reached {
+            let info_hash = torrent.info_hash();
+            tracing::info!(info_hash = %to_hex(&info_hash), %limit, %action, "seed limit reached");
+            self.events
+                .send(SessionEvent::SeedLimitReached { info_hash, limit });
+            match action {
+                SeedLimitAction::Pause => {
+                    self.pause(&torrent)?;
+                    self.save_state()?;
+                }
+                SeedLimitAction::Stop => {
+                    self.pause(&torrent)?;
+                    torrent.set_seed_limits(Some(SeedLimits::default()));
+                    self.save_state()?;
+                }
+                SeedLimitAction::Remove => self.remove(&torrent, false)?,
+                SeedLimitAction::RemoveWithData => self.remove(&torrent, true)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// starts the hooks of the files and torrents that completed, torrents
+    /// once their data is written, and reports the hooks that failed
+    fn run_hooks(&mut self) {
+        let hooks = match &mut self.hooks {
+            Some(hooks) => hooks,
+            None => return,
+        };
+        let torrents = &self.torrents;
+        let find = |info_hash: [u8; 20]| {
+            torrents
+                .iter()
+                .find(|torrent| torrent.info_hash() == info_hash)
+        };
+        let mut completions = vec![];
+        for event in hooks.events.try_iter() {
+            match event {
+                SessionEvent::TorrentFinished { info_hash } => hooks.finished.push(info_hash),
+                SessionEvent::Torrent {
+                    info_hash,
+                    event: Event::FileCompleted { file, path },
+                } if hooks.config.files => {
+                    if let Some(torrent) = find(info_hash) {
+                        completions.push(completion(torrent, Some((file, path))));
+                    }
+                }
+                _ => {}
+            }
+        }
+        hooks.finished.retain(|&info_hash| match find(info_hash) {
+            Some(torrent) if torrent.has_unwritten_data() => true,
+            Some(torrent) => {
+                completions.push(completion(torrent, None));
+                false
+            }
+            None => false,
+        });
+        for completion in completions {
+            tracing::info!(
+                event = %completion.kind(),
+                path = %completion.path.display(),
+                "running hooks"
+            );
+            let config = hooks.config.clone();
+            let info_hash = completion.info_hash;
+            let run = thread::spawn(move || hooks::run(&config, &completion));
+            hooks.running.push((info_hash, run));
+        }
+        for (info_hash, run) in std::mem::take(&mut hooks.running) {
+            if !run.is_finished() {
+                hooks.running.push((info_hash, run));
+                continue;
+            }
+            let error = match run.join() {
+                Ok(Ok(())) => continue,
+                Ok(Err(error)) => format!("{:#}", error),
+                Err(_) => "the hook panicked".into(),
+            };
+            let torrent = to_hex(&info_hash);
+            tracing::warn!(%torrent, %error, "hook failed");
+            self.alerts.push(Alert::HookFailed { torrent, error });
+        }
+    }
+
+    /// adds the files of the watch folders that are done being written,
+    /// moving each to the `processed` or `failed` subfolder of its folder
+    fn scan_watch_folders(&mut self, now: Instant) {
+        if self.watch.is_empty() || now < self.next_watch {
+            return;
+        }
+        self.next_watch = now + watch::SCAN_INTERVAL;
+        for folder in self.watch.clone() {
+            let files = match folder.ready_files(SystemTime::now()) {
+                Ok(files) => files,
+                Err(error) => {
+                    tracing::warn!(
+                        path = %folder.path.display(),
+                        %error,
+                        "reading a watch folder failed"
+                    );
+                    continue;
+                }
+            };
+            for file in files {
+                let added = self.add_watched(&file, &folder.options);
+                let subfolder = match added {
+                    Ok(()) => watch::PROCESSED_DIR,
+                    Err(_) => watch::FAILED_DIR,
+                };
+                if let Err(error) = added.and(watch::move_to(&file, subfolder).map(drop)) {
+                    let file = file.display().to_string();
+                    let error = format!("{:#}", error);
+                    tracing::warn!(%file, %error, "adding a watched file failed");
+                    self.alerts.push(Alert::WatchFailed { file, error });
+                }
+            }
+        }
+    }
+
+    fn add_watched(&mut self, file: &Path, options: &AddOptions) -> Result<()> {
+        let download = match watch::is_magnet(file) {
+            true => Download::Magnet(fs::read_to_string(file)?.trim().to_string()),
+            false => Download::Torrent(fs::read(file)?),
+        };
+        self.add_download(download, options)?;
+        tracing::info!(file = %file.display(), "watched file added");
+        Ok(())
+    }
+
+    /// a torrent or magnet that was already added counts as added
+    fn add_download(&mut self, download: Download, options: &AddOptions) -> Result<()> {
+        match download {
+            Download::Magnet(uri) => {
+                let info_hash = Magnet::parse(&uri)?.info_hash;
+                if !self.magnets.contains_key(&info_hash) && self.torrent(&info_hash).is_none() {
+                    self.add_magnet_with(&uri, options.clone())?;
+                }
+            }
+            Download::Torrent(data) => {
+                let metainfo = Metainfo::from_bytes(data)?;
+                if self.torrent(&metainfo.info_hash).is_none() {
+                    self.add_torrent_with(metainfo, options.clone())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// starts fetching the feeds that are due, and adds the items they
+    /// listed once fetched. Items whose download failed are tried again at
+    /// the next refresh, those that couldn't be added aren't.
+    fn refresh_feeds(&mut self, now: Instant) {
+        let mut fetched = vec![];
+        for refresh in &mut self.feeds {
+            match refresh.fetch.take() {
+                Some(fetch) if fetch.is_finished() => {
+                    refresh.next = now + refresh.feed.refresh;
+                    match fetch.join() {
+                        Ok(Ok(feed)) => fetched.push((refresh.name.clone(), feed)),
+                        Ok(Err(error)) => {
+                            let error = format!("{:#}", error);
+                            tracing::warn!(feed = %refresh.name, %error, "feed refresh failed");
+                            self.alerts.push(Alert::FeedFailed {
+                                feed: refresh.name.clone(),
+                                error,
+                            });
+                        }
+                        Err(_) => self.alerts.push(Alert::FeedFailed {
+                            feed: refresh.name.clone(),
+                            error: "fetching panicked".into(),
+                        }),
+                    }
+                }
+                Some(fetch) => refresh.fetch = Some(fetch),
+                None if now >= refresh.next => {
+                    let feed = refresh.feed.clone();
+                    let seen = refresh.seen.clone();
+                    refresh.fetch = Some(thread::spawn(move || fetch_feed(&feed, &seen)));
+                }
+                None => {}
+            }
+        }
+        if fetched.is_empty() {
+            return;
+        }
+        for (name, feed) in fetched {
+            self.add_feed_items(&name, feed);
+        }
+        if let Some(dir) = &self.resume_dir {
+            let state = FeedState {
+                seen: self
+                    .feeds
+                    .iter()
+                    .map(|refresh| (refresh.name.clone(), refresh.seen.clone()))
+                    .collect(),
+            };
+            if let Err(error) = state.save(dir) {
+                tracing::warn!(%error, "saving the feed state failed");
+            }
+        }
+    }
+
+    fn add_feed_items(&mut self, name: &str, fetched: FetchedFeed) {
+        let index = match self.feeds.iter().position(|refresh| refresh.name == name) {
+            Some(index) => index,
+            None => return,
+        };
+        let options = self.feeds[index].feed.options.clone();
+        let mut seen = std::mem::take(&mut self.feeds[index].seen);
+        // the items the feed no longer lists won't be seen again
+        seen.retain(|id| fetched.ids.contains(id));
+        for (item, download) in fetched.downloads {
+            let added = download.map(|download| self.add_download(download, &options));
+            let error = match added {
+                Ok(Ok(())) => {
+                    tracing::info!(feed = %name, title = %item.title, "feed item added");
+                    seen.insert(item.id);
+                    continue;
+                }
+                Ok(Err(error)) => {
+                    seen.insert(item.id);
+                    error
+                }
+                Err(error) => error,
+            };
+            let error = format!("adding {}: {:#}", item.title, error);
+            tracing::warn!(feed = %name, %error, "adding a feed item failed");
+            self.alerts.push(Alert::FeedFailed {
+                feed: name.to_string(),
+                error,
+            });
+        }
+        self.feeds[index].seen = seen;
+    }
+
+    /// hands the peers the DHT found to the magnet links and adds the
+    /// torrents whose metadata is complete, to be called regularly while
+    /// magnet links are pending
+    pub fn poll_magnets(&mut self) -> Result<Vec<TorrentHandle>> {
+        if let Some(dht) = &self.dht {
+            while let Some(event) = dht.poll_event() {
+                if let DhtEvent::Peers { info_hash, peers } = event {
+                    if let Some(magnet) = self.magnets.get(&info_hash) {
+                        magnet.add_peers(peers);
+                    }
+                }
+            }
+        }
+        let received: Vec<_> = self
+            .magnets
+            .values()
+            .filter_map(|magnet| match magnet.poll_event() {
+                Some(MetadataEvent::Received(metainfo)) => Some(*metainfo),
+                _ => None,
+            })
+            .collect();
+        received
+            .into_iter()
+            .map(|metainfo| self.metadata_received(metainfo))
+            .collect()
+    }
+
+    /// adds the torrent of a magnet link once its metadata was fetched
+    pub fn metadata_received(&mut self, metainfo: Metainfo) -> Result<TorrentHandle> {
+        if !self.magnets.contains_key(&metainfo.info_hash) {
+            bail!("no magnet link for torrent {}", metainfo.info.name);
+        }
+        self.events.send(SessionEvent::MetadataReceived {
+            info_hash: metainfo.info_hash,
+            metainfo: Box::new(metainfo.clone()),
+        });
+        let options = self
+            .magnet_options
+            .remove(&metainfo.info_hash)
+            .unwrap_or_default();
+        self.add_torrent_with(metainfo, options)
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<&TorrentHandle> {
+        self.torrents
+            .iter()
+            .find(|torrent| torrent.info_hash() == *info_hash)
+    }
+
+    /// of a torrent or of a magnet link still fetching its metadata
+    pub fn state(&self, info_hash: &[u8; 20]) -> Option<TorrentState> {
+        if self.magnets.contains_key(info_hash) {
+            return Some(TorrentState::FetchingMetadata);
+        }
+        self.torrent(info_hash).map(TorrentHandle::state)
+    }
+
+    pub fn torrents(&self) -> impl Iterator<Item = &TorrentHandle> {
+        self.torrents.iter()
+    }
+
+    /// the torrent with a matching info hash prefix or name, which has to be
+    /// the only one
+    pub fn find(&self, name: &str) -> Result<&TorrentHandle> {
+        let lower = name.to_ascii_lowercase();
+        let matches: Vec<_> = self
+            .torrents()
+            .filter(|torrent| {
+                to_hex(&torrent.info_hash()).starts_with(&lower) || torrent.root_name() == name
+            })
+            .collect();
+        match matches.as_slice() {
+            [torrent] => Ok(torrent),
+            [] => bail!("no torrent {}", name),
+            _ => bail!("{} matches {} torrents", name, matches.len()),
+        }
+    }
+
+    /// stops the torrent, tells its trackers it stopped and frees its queue
+    /// slot. Its resume data is deleted, and its files too with
+    /// `delete_data`, the rest of the save path is left alone.
+    pub fn remove(&mut self, torrent: &TorrentHandle, delete_data: bool) -> Result<()> {
+        let info_hash = torrent.info_hash();
+        self.torrents.retain(|added| added.info_hash() != info_hash);
+        if let Some(trackers) = &self.trackers {
+            trackers.remove_torrent(&info_hash);
+        }
+        if let Some(dht) = &self.dht {
+            dht.remove_torrent(&info_hash);
+        }
+        if let Some(lsd) = &self.lsd {
+            lsd.remove_torrent(&info_hash);
+        }
+        if let Some(swarm) = &self.swarm {
+            swarm.remove_torrent(&info_hash);
+        }
+        // disconnects the peers
+        torrent.pause();
+        torrent.dequeue();
+        self.bandwidth.lock().unwrap().remove_torrent(&info_hash);
+        self.events.send(SessionEvent::TorrentRemoved { info_hash });
+        tracing::info!(
+            info_hash = %to_hex(&info_hash),
+            delete_data,
+            "torrent removed"
+        );
+
+        if let Some(dir) = &self.resume_dir {
+            match std::fs::remove_file(ResumeData::path(dir, &info_hash)) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+            SessionState::remove_metainfo(dir, &info_hash)?;
+            self.save_state()?;
+        }
+        if delete_data {
+            torrent.delete_files()?;
+        }
+        Ok(())
+    }
+
+    /// disconnects the peers of the torrent, stops announcing it and writes
+    /// what it received to disk, its state is kept until it is resumed
+    pub fn pause(&self, torrent: &TorrentHandle) -> Result<()> {
+        if torrent.is_paused() {
+            return Ok(());
+        }
+        let info_hash = torrent.info_hash();
+        torrent.pause();
+        // the trackers are told by their task once it sees the torrent paused
+        if let Some(dht) = &self.dht {
+            dht.remove_torrent(&info_hash);
+        }
+        if let Some(lsd) = &self.lsd {
+            lsd.remove_torrent(&info_hash);
+        }
+        self.events.send(SessionEvent::TorrentPaused { info_hash });
+        torrent.flush_disk()
+    }
+
+    pub fn resume(&self, torrent: &TorrentHandle) {
+        if !torrent.is_paused() {
+            return;
+        }
+        torrent.resume();
+        let port = self.listen_port();
+        if let Some(dht) = &self.dht {
+            dht.add_torrent(torrent, port);
+        }
+        if let Some(lsd) = &self.lsd {
+            lsd.add_torrent(torrent);
+        }
+        self.events.send(SessionEvent::TorrentResumed {
+            info_hash: torrent.info_hash(),
+        });
+    }
+
+    /// pauses every torrent, the first error is returned once they all are
+    pub fn pause_all(&self) -> Result<()> {
+        self.torrents
+            .iter()
+            .map(|torrent| self.pause(torrent))
+            .fold(Ok(()), Result::and)
+    }
+
+    pub fn resume_all(&self) {
+        for torrent in &self.torrents {
+            self.resume(torrent);
+        }
+    }
+
+    /// Stops the session within `timeout`: peers can't connect anymore, the
+    /// trackers are told we stopped and the port mappings are removed while
+    /// the received data of every torrent is written and synced and its
+    /// resume data saved to the resume dir, then the DHT state is saved. Every step is attempted even when one
+    /// fails, the first error is returned.
+    pub fn shutdown(self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut errors = vec![];
+        if let Err(error) = self.save_state() {
+            errors.push(error.context("saving the session state"));
+        }
+        let Session {
+            listener,
+            swarm,
+            lsd,
+            trackers,
+            torrents,
+            resume_dir,
+            dht,
+            port_mapper,
+            ..
+        } = self;
+        // the disk jobs of the peers are done before the torrents are flushed
+        drop(swarm);
+        drop(listener);
+        drop(lsd);
+        let trackers = trackers.map(|trackers| thread::spawn(move || trackers.shutdown(deadline)));
+        let port_mapper = port_mapper.map(|mapper| thread::spawn(move || drop(mapper)));
+
+        for (index, torrent) in torrents.iter().enumerate() {
+            if Instant::now() >= deadline {
+                errors.push(anyhow!(
+                    "timed out before saving {} torrents",
+                    torrents.len() - index
+                ));
+                break;
+            }
+            let name = to_hex(&torrent.info_hash());
+            if let Err(error) = torrent.flush_disk() {
+                errors.push(error.context(format!("flushing {}", name)));
+            }
+            if let Some(dir) = &resume_dir {
+                if let Err(error) = torrent.save_resume_data(dir) {
+                    errors.push(error.context(format!("saving the resume data of {}", name)));
+                }
+            }
+        }
+        if let Some(dht) = dht {
+            if let Err(error) = dht.save_state() {
+                errors.push(error.context("saving the DHT state"));
+            }
+        }
+        if let Some(trackers) = trackers {
+            match trackers.join() {
+                Ok(Err(error)) => errors.push(error),
+                Ok(Ok(())) => {}
+                Err(_) => errors.push(anyhow!("announcing to the trackers panicked")),
+            }
+        }
+        if let Some(port_mapper) = port_mapper {
+            if port_mapper.join().is_err() {
+                errors.push(anyhow!("removing the port mappings panicked"));
+            }
+        }
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// of the torrent, or of its file with the path it completed at
+fn completion(torrent: &TorrentHandle, file: Option<(usize, PathBuf)>) -> Completion {
+    let metainfo = torrent.metainfo();
+    let save_path = torrent.save_path();
+    let path = match &file {
+        Some((_, path)) => path.clone(),
+        None if metainfo.info.multi_file => save_path.join(torrent.root_name()),
+        None => torrent.file_paths().swap_remove(0),
+    };
+    Completion {
+        info_hash: metainfo.info_hash,
+        name: torrent.root_name(),
+        file: file.map(|(file, _)| file),
+        path,
+        save_path,
+        tracker: torrent.announce_urls().into_iter().next(),
+        category: torrent.category(),
+        tags: torrent.tags(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{Bencode, Parser};
+    use crate::message::{Handshake, Message, HANDSHAKE_LENGTH};
+    use crate::metadata::{MetadataMessage, UT_METADATA_ID};
+    use crate::peer::PeerSource;
+    use crate::queue::QueueSettings;
+    use crate::regex::Regex;
+    use crate::schedule::ScheduleRule;
+    use crate::seeding::SeedLimit;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn session(max_active_downloads: usize) -> Result<Session> {
+        Session::new(
+            Config::builder()
+                .listen_port(0)
+                .save_path(std::env::temp_dir())
+                .queue(QueueSettings {
+                    max_active_downloads,
+                    max_active_seeds: 1,
+                    ..Default::default()
+                })
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .build()?,
+        )
+    }
+
+    #[test]
+    fn manages_torrents() -> Result<()> {
+        let mut session = session(0)?;
+        assert_ne!(session.listen_port(), 0);
+        assert!(session.peer_id().starts_with(PEER_ID_PREFIX));
+
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let info_hash = metainfo.info_hash;
+        let handle = session.add_torrent(metainfo.clone())?;
+        assert!(session.add_torrent(metainfo.clone()).is_err());
+        assert_eq!(session.torrents().count(), 1);
+        assert_eq!(handle.queue_position(), Some(0));
+        assert!(!handle.is_active());
+
+        session.queue().lock().unwrap().set_settings(QueueSettings {
+            max_active_downloads: 1,
+            max_active_seeds: 1,
+            ..Default::default()
+        });
+        assert!(handle.is_active());
+        session.pause(&handle)?;
+        assert!(handle.is_paused() && !handle.is_active());
+        session.resume(&handle);
+        assert!(handle.is_active());
+        session.pause_all()?;
+        assert!(session.torrents().all(TorrentHandle::is_paused));
+        session.resume_all();
+        assert!(handle.is_active());
+
+        let limits = RateLimits {
+            upload: Some(1000),
+            download: None,
+        };
+        session.set_rate_limits(limits);
+        session.set_torrent_rate_limits(&handle, limits);
+        assert_eq!(session.rate_limits(), limits);
+        assert_eq!(session.torrent_rate_limits(&handle), limits);
+
+        let limits = ConnectionLimits {
+            max_peers: Some(10),
+            max_connections: Some(50),
+        };
+        session.set_connection_limits(limits);
+        assert_eq!(session.connection_limits(), limits);
+        assert_eq!(handle.max_peers(), Some(10));
+        assert_eq!(session.connection_count(), 0);
+
+        session.remove(&handle, false)?;
+        assert_eq!(session.torrent_rate_limits(&handle), RateLimits::default());
+        assert!(session.torrent(&info_hash).is_none());
+        assert_eq!(handle.queue_position(), None);
+        assert!(session.queue().lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn serves_metrics() -> Result<()> {
+        let mut session = Session::new(
+            Config::builder()
+                .listen_port(0)
+                .save_path(std::env::temp_dir())
+                .metrics("127.0.0.1:0".parse()?)
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .build()?,
+        )?;
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let labels = format!(
+            "{{info_hash=\"{}\",name=\"{}\"}}",
+            to_hex(&metainfo.info_hash),
+            metainfo.info.name
+        );
+        session.add_torrent(metainfo)?;
+        session.tick()?;
+
+        let url = format!("http://{}/metrics", session.metrics_addr().unwrap());
+        let text = String::from_utf8(crate::tracker::http_get(&url)?)?;
+        let torrents: f64 = text
+            .lines()
+            .filter(|line| line.starts_with("torrent_rs_torrents{"))
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<f64>().unwrap())
+            .sum();
+        assert_eq!(torrents, 1.0);
+        assert!(text.contains("# TYPE torrent_rs_torrent_downloaded_bytes_total counter\n"));
+        assert!(text.contains(&format!(
+            "torrent_rs_torrent_downloaded_bytes_total{} 0\n",
+            labels
+        )));
+        assert!(text.contains("torrent_rs_ip_filter_blocked_total 0\n"));
+        for disk in [
+            "disk_queue_depth",
+            "disk_pending_bytes",
+            "disk_read_rate_bytes",
+            "disk_write_rate_bytes",
+            "disk_cache_hit_ratio",
+            "disk_read_errors_total",
+            "disk_write_errors_total",
+        ] {
+            assert!(text.contains(&format!("torrent_rs_{}{} 0\n", disk, labels)));
+        }
+        // no DHT, no DHT metrics
+        assert!(!text.contains("dht_nodes"));
+        Ok(())
+    }
+
+    #[test]
+    fn listens_on_another_port_if_taken() -> Result<()> {
+        let taken = TcpListener::bind("0.0.0.0:0")?;
+        let port = taken.local_addr()?.port();
+        let mut session = Session::new(
+            Config::builder()
+                .listen_port(port)
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .build()?,
+        )?;
+        assert_ne!(session.listen_port(), port);
+        match session.take_alerts().as_slice() {
+            [Alert::ListenFailed { port: failed, .. }] => assert_eq!(*failed, port),
+            alerts => panic!("unexpected alerts {:?}", alerts),
+        }
+        assert!(session.take_alerts().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn follows_the_schedule() -> Result<()> {
+        let limits = RateLimits {
+            upload: Some(1024),
+            download: None,
+        };
+        let rule = |start, end, action| ScheduleRule {
+            days: [true; 7],
+            start,
+            end,
+            action,
+        };
+        let mut session = session(2)?;
+        session.set_schedule(Schedule {
+            rules: vec![
+                rule(8 * 60, 12 * 60, ScheduleAction::Pause),
+                rule(12 * 60, 14 * 60, ScheduleAction::Limit(limits)),
+            ],
+        })?;
+        let at = |hour: u16| WeekTime {
+            day: 0,
+            minute: hour * 60,
+        };
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let running = session.add_torrent(metainfo.clone())?;
+        let mut other = metainfo;
+        other.info_hash = [1; 20];
+        let paused = session.add_torrent(other)?;
+        session.pause(&paused)?;
+
+        session.apply_schedule(at(9))?;
+        assert!(running.is_paused());
+        session.apply_schedule(at(12))?;
+        assert!(!running.is_paused());
+        // paused before the schedule did
+        assert!(paused.is_paused());
+        assert_eq!(session.rate_limits(), limits);
+        session.apply_schedule(at(15))?;
+        assert_eq!(session.rate_limits(), RateLimits::default());
+
+        assert!(session
+            .set_schedule(Schedule {
+                rules: vec![rule(60, 60, ScheduleAction::Pause)],
+            })
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fetches_the_blocklist() -> Result<()> {
+        let server = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/blocklist.txt", server.local_addr()?);
+        let serve = thread::spawn(move || -> Result<()> {
+            for body in ["Example:10.0.0.0-10.255.255.255\n", "not a blocklist"] {
+                let (mut stream, _) = server.accept()?;
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request)?;
+                write!(stream, "HTTP/1.0 200 OK\r\n\r\n{}", body)?;
+            }
+            Ok(())
+        });
+        let mut session = Session::new(
+            Config::builder()
+                .listen_port(0)
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .blocklist(BlocklistSource::Url {
+                    url,
+                    refresh: Duration::from_millis(1),
+                })
+                .build()?,
+        )?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.ip_filter().read().unwrap().is_empty() && Instant::now() < deadline {
+            session.tick()?;
+            thread::sleep(Duration::from_millis(10));
+        }
+        let blocked = SocketAddr::from(([10, 0, 0, 1], 6881));
+        assert!(session.blocks(blocked));
+        assert!(!session.blocks(SocketAddr::from(([11, 0, 0, 1], 6881))));
+
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let torrent = session.add_torrent(metainfo)?;
+        torrent.add_peers(vec![blocked], PeerSource::Dht);
+        assert!(torrent.peer_candidates().is_empty());
+        assert_eq!(session.ip_filter().read().unwrap().hits(), 2);
+
+        // the next refresh fails and keeps the list
+        while session.take_alerts().is_empty() && Instant::now() < deadline {
+            session.tick()?;
+            thread::sleep(Duration::from_millis(10));
+        }
+        serve.join().unwrap()?;
+        assert!(session.blocks(blocked));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_events() -> Result<()> {
+        let mut session = session(1)?;
+        let events = session.subscribe();
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let info_hash = metainfo.info_hash;
+        let handle = session.add_torrent(metainfo)?;
+        session.pause(&handle)?;
+        // already paused
+        session.pause(&handle)?;
+        session.remove(&handle, false)?;
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                SessionEvent::TorrentAdded { info_hash },
+                SessionEvent::StateChanged {
+                    info_hash,
+                    state: TorrentState::Downloading
+                },
+                SessionEvent::StateChanged {
+                    info_hash,
+                    state: TorrentState::Paused
+                },
+                SessionEvent::Torrent {
+                    info_hash,
+                    event: crate::torrent::Event::Queued
+                },
+                SessionEvent::TorrentPaused { info_hash },
+                SessionEvent::TorrentRemoved { info_hash },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn adds_magnets_once_their_metadata_is_received() -> Result<()> {
+        let mut session = session(1)?;
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        assert!(session.metadata_received(metainfo.clone()).is_err());
+
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}",
+            crate::metainfo::to_hex(&metainfo.info_hash)
+        );
+        let info_hash = session.add_magnet(&uri)?;
+        assert_eq!(info_hash, metainfo.info_hash);
+        assert!(session.add_magnet(&uri).is_err());
+        assert!(session.magnet(&info_hash).is_some());
+        assert_eq!(
+            session.state(&info_hash),
+            Some(TorrentState::FetchingMetadata)
+        );
+        assert_eq!(session.torrents().count(), 0);
+
+        let handle = session.metadata_received(metainfo)?;
+        assert!(session.magnet(&info_hash).is_none());
+        assert_eq!(
+            session.torrent(&info_hash).unwrap().info_hash(),
+            handle.info_hash()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn saves_state_on_shutdown() -> Result<()> {
+        let resume_dir = std::env::temp_dir().join("torrent_rs_shutdown_resume");
+        let _ = std::fs::remove_dir_all(&resume_dir);
+        let config = Config::builder()
+            .listen_port(0)
+            .save_path(std::env::temp_dir())
+            .resume_dir(&resume_dir)
+            .dht(None)
+            .lsd(false)
+            .trackers(false)
+            .port_mapping(false)
+            .build()?;
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let info_hash = metainfo.info_hash;
+
+        let other = other_torrent("other.txt")?;
+        let other_path = resume_dir.join("other");
+
+        let mut session = Session::new(config.clone())?;
+        let handle = session.add_torrent(metainfo)?;
+        let other = session.add_torrent_to(other, &other_path)?;
+        other.queue_top();
+        session.pause(&handle)?;
+        handle.set_file_priorities(&[Priority::High])?;
+        session.shutdown(Duration::from_secs(5))?;
+        assert!(ResumeData::load(&resume_dir, &info_hash)?.is_some());
+
+        // added back by the next session, in the same order
+        let mut session = Session::new(config.clone())?;
+        let restored: Vec<_> = session.torrents().cloned().collect();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].info_hash(), other.info_hash());
+        assert_eq!(restored[0].save_path(), other_path);
+        assert_eq!(restored[0].queue_position(), Some(0));
+        assert!(!restored[0].is_paused());
+        assert_eq!(restored[1].info_hash(), info_hash);
+        assert!(restored[1].is_paused());
+        assert_eq!(restored[1].file_priorities(), [Priority::High]);
+        assert!(session.take_alerts().is_empty());
+        session.remove(&restored[0], false)?;
+        session.shutdown(Duration::from_secs(5))?;
+
+        // torrents whose metainfo is gone are alerts
+        std::fs::remove_file(SessionState::metainfo_path(&resume_dir, &info_hash))?;
+        let mut session = Session::new(config)?;
+        assert_eq!(session.torrents().count(), 0);
+        assert!(matches!(
+            session.take_alerts()[..],
+            [Alert::RestoreFailed { .. }]
+        ));
+        session.shutdown(Duration::from_secs(5))?;
+        std::fs::remove_dir_all(&resume_dir)?;
+        Ok(())
+    }
+
+    /// reads one length prefixed message
+    fn read_message(stream: &mut TcpStream) -> Result<Message> {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length)?;
+        let mut data = length.to_vec();
+        data.resize(4 + u32::from_be_bytes(length) as usize, 0);
+        stream.read_exact(&mut data[4..])?;
+        Ok(Message::decode(&data)?.unwrap().0)
+    }
+
+    #[test]
+    fn fetches_the_metadata_of_magnets() -> Result<()> {
+        let torrent = std::fs::read("file1.txt.torrent")?;
+        let metainfo = Metainfo::from_bytes(torrent.clone())?;
+        let info_hash = metainfo.info_hash;
+        let info = Parser::new(torrent).parse()?.get("info").unwrap().encode();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peer = listener.local_addr()?;
+        // a seed sharing the metadata, which fits in one block
+        let seed = thread::spawn(move || -> Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut handshake = [0; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake)?;
+            assert_eq!(Handshake::decode(&handshake)?.info_hash, info_hash);
+            stream.write_all(&Handshake::new(info_hash, [2; 20]).encode())?;
+            let payload = format!("d1:md11:ut_metadatai3ee13:metadata_sizei{}ee", info.len());
+            let extensions = Message::Extended {
+                id: 0,
+                payload: payload.into_bytes(),
+            };
+            stream.write_all(&extensions.encode())?;
+            loop {
+                // ut_metadata is 3 for the seed
+                if let Message::Extended { id: 3, payload } = read_message(&mut stream)? {
+                    assert_eq!(
+                        MetadataMessage::decode(&payload)?,
+                        MetadataMessage::Request(0)
+                    );
+                    let data = MetadataMessage::Data {
+                        piece: 0,
+                        total_size: info.len(),
+                        data: info.clone(),
+                    };
+                    let message = Message::Extended {
+                        id: UT_METADATA_ID,
+                        payload: data.encode(),
+                    };
+                    stream.write_all(&message.encode())?;
+                    return Ok(());
+                }
+            }
+        });
+
+        let mut session = session(1)?;
+        let events = session.subscribe();
+        let uri = format!("magnet:?xt=urn:btih:{}", to_hex(&info_hash));
+        session.add_magnet(&uri)?;
+        session.magnet(&info_hash).unwrap().add_peers([peer]);
+        let start = Instant::now();
+        let added = loop {
+            let added = session.poll_magnets()?;
+            if !added.is_empty() || start.elapsed() > Duration::from_secs(10) {
+                break added;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        seed.join().unwrap()?;
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].info_hash(), info_hash);
+        assert!(session.magnet(&info_hash).is_none());
+        assert_eq!(
+            events.try_recv().ok(),
+            Some(SessionEvent::StateChanged {
+                info_hash,
+                state: TorrentState::FetchingMetadata
+            })
+        );
+        match events.try_iter().next() {
+            Some(SessionEvent::MetadataReceived { metainfo, .. }) => {
+                assert_eq!(metainfo.info_hash, info_hash)
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        Ok(())
+    }
+
+    /// a single file torrent of 10 bytes
+    fn other_torrent(name: &str) -> Result<Metainfo> {
+        let mut info = HashMap::new();
+        info.insert("name".into(), name.into());
+        info.insert("piece length".into(), 16384.into());
+        info.insert("length".into(), 10.into());
+        info.insert("pieces".into(), vec![0; 20].into());
+        let mut torrent = HashMap::new();
+        torrent.insert("info".into(), Bencode::Dictionary(info));
+        Metainfo::from_bencode(&Bencode::Dictionary(torrent))
+    }
+
+    #[test]
+    fn sorts_torrents_into_categories() -> Result<()> {
+        let resume_dir = std::env::temp_dir().join("torrent_rs_session_categories");
+        let _ = fs::remove_dir_all(&resume_dir);
+        let movies = Category {
+            save_path: Some(resume_dir.join("movies")),
+            rate_limits: RateLimits {
+                upload: Some(1024),
+                download: None,
+            },
+            queue_priority: 1,
+        };
+        let config = Config::builder()
+            .listen_port(0)
+            .save_path(std::env::temp_dir())
+            .resume_dir(&resume_dir)
+            .dht(None)
+            .lsd(false)
+            .trackers(false)
+            .port_mapping(false)
+            .category("movies", movies.clone())
+            .build()?;
+        let mut session = Session::new(config.clone())?;
+        let metainfo = Metainfo::from_bytes(fs::read("file1.txt.torrent")?)?;
+        let unknown = AddOptions {
+            category: Some("books".into()),
+            ..Default::default()
+        };
+        assert!(session.add_torrent_with(metainfo.clone(), unknown).is_err());
+        let first = session.add_torrent(metainfo)?;
+        let options = AddOptions {
+            category: Some("movies".into()),
+            tags: vec!["hd".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let second = session.add_torrent_with(other_torrent("second")?, options)?;
+
+        // queued ahead of the torrents without a category
+        assert_eq!(second.save_path(), resume_dir.join("movies"));
+        assert_eq!(second.queue_position(), Some(0));
+        assert_eq!(session.torrent_rate_limits(&second), movies.rate_limits);
+        let hashes = |torrents: Vec<TorrentHandle>| -> Vec<_> {
+            torrents.iter().map(TorrentHandle::info_hash).collect()
+        };
+        assert_eq!(
+            hashes(session.torrents_in_category("movies")),
+            [second.info_hash()]
+        );
+        assert_eq!(hashes(session.torrents_tagged("hd")), [second.info_hash()]);
+        assert!(session.set_torrent_tags(&first, ["a,b"]).is_err());
+        session.set_torrent_tags(&first, ["hd", "old"])?;
+        assert_eq!(session.torrents_tagged("hd").len(), 2);
+        assert!(session.set_torrent_category(&first, Some("books")).is_err());
+        session.set_torrent_category(&first, Some("movies"))?;
+        assert_eq!(first.queue_position(), Some(1));
+
+        // a higher priority category goes first
+        session.set_category(
+            "urgent",
+            Category {
+                queue_priority: 2,
+                ..Default::default()
+            },
+        )?;
+        session.set_torrent_category(&first, Some("urgent"))?;
+        assert_eq!(first.queue_position(), Some(0));
+        session.shutdown(Duration::from_secs(5))?;
+
+        // categories missing from the config are dropped on restart
+        let mut session = Session::new(config)?;
+        let restored: Vec<_> = session.torrents().cloned().collect();
+        assert_eq!(restored[0].category(), None);
+        assert_eq!(
+            restored[0].tags(),
+            vec!["hd".to_string(), "old".to_string()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(restored[1].category().as_deref(), Some("movies"));
+        assert_eq!(
+            session.torrent_rate_limits(&restored[1]),
+            movies.rate_limits
+        );
+        session.remove_category("movies")?;
+        assert_eq!(restored[1].category(), None);
+        assert_eq!(
+            session.torrent_rate_limits(&restored[1]),
+            RateLimits::default()
+        );
+        session.shutdown(Duration::from_secs(5))?;
+        fs::remove_dir_all(&resume_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn stops_seeding_at_the_seed_limits() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_session_seed_limits");
+        fs::create_dir_all(&dir)?;
+        fs::copy("file1.txt", dir.join("file1.txt"))?;
+        let mut session = session(1)?;
+        let events = session.subscribe();
+        let metainfo = Metainfo::from_bytes(fs::read("file1.txt.torrent")?)?;
+        let info_hash = metainfo.info_hash;
+        let torrent = session.add_torrent_to(metainfo, &dir)?;
+        torrent.force_recheck()?;
+        assert_eq!(torrent.state(), TorrentState::Seeding);
+        let limits = SeedLimits {
+            seed_time: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        assert!(session
+            .set_torrent_seed_limits(
+                &torrent,
+                Some(SeedLimits {
+                    ratio: Some(-1.0),
+                    ..limits
+                })
+            )
+            .is_err());
+        session.set_torrent_seed_limits(&torrent, Some(limits))?;
+        session.tick()?;
+        assert!(!torrent.is_paused());
+        thread::sleep(Duration::from_millis(1100));
+        session.tick()?;
+        assert!(torrent.is_paused());
+        assert!(events.try_iter().any(|event| event
+            == SessionEvent::SeedLimitReached {
+                info_hash,
+                limit: SeedLimit::SeedTime
+            }));
+
+        // stopped, it seeds on without limits once resumed
+        session.set_torrent_seed_limits(
+            &torrent,
+            Some(SeedLimits {
+                action: SeedLimitAction::Stop,
+                ..limits
+            }),
+        )?;
+        session.resume(&torrent);
+        session.tick()?;
+        assert!(torrent.is_paused());
+        assert_eq!(torrent.seed_limits(), Some(SeedLimits::default()));
+        session.resume(&torrent);
+        session.tick()?;
+        assert!(!torrent.is_paused());
+
+        // the limits of the session apply once the torrent has none
+        session.set_seed_limits(SeedLimits {
+            action: SeedLimitAction::Remove,
+            ..limits
+        })?;
+        session.set_torrent_seed_limits(&torrent, None)?;
+        session.tick()?;
+        assert!(session.torrent(&info_hash).is_none());
+        assert!(dir.join("file1.txt").exists());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn downloads_from_a_seeding_session() -> Result<()> {
+        let seeds = std::env::temp_dir().join("torrent_rs_session_seeds");
+        let downloads = std::env::temp_dir().join("torrent_rs_session_downloads");
+        let _ = fs::remove_dir_all(&downloads);
+        fs::create_dir_all(&seeds)?;
+        fs::copy("file1.txt", seeds.join("file1.txt"))?;
+        let metainfo = Metainfo::from_bytes(fs::read("file1.txt.torrent")?)?;
+        let mut seeder = session(1)?;
+        let seeding = seeder.add_torrent_to(metainfo.clone(), &seeds)?;
+        assert_eq!(seeding.state(), TorrentState::Seeding);
+        let mut leecher = session(1)?;
+        let downloading = leecher.add_torrent_to(metainfo, &downloads)?;
+        seeder.tick()?;
+        leecher.tick()?;
+        downloading.add_peers(
+            [SocketAddr::from(([127, 0, 0, 1], seeder.listen_port()))],
+            PeerSource::Manual,
+        );
+
+        for _ in 0..500 {
+            if downloading.is_finished() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            seeder.tick()?;
+            leecher.tick()?;
+        }
+        assert!(downloading.is_finished());
+        leecher.shutdown(Duration::from_secs(5))?;
+        assert_eq!(
+            fs::read(downloads.join("file1.txt"))?,
+            fs::read("file1.txt")?
+        );
+        assert_eq!(seeding.stats().uploaded, 12);
+        fs::remove_dir_all(&seeds)?;
+        fs::remove_dir_all(&downloads)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_hooks_once_torrents_complete() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_session_hooks");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let out = dir.join("hook");
+        let mut session = Session::new(
+            Config::builder()
+                .listen_port(0)
+                .save_path(&dir)
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .hooks(HooksConfig {
+                    command: Some(format!(
+                        "echo \"$TORRENT_EVENT $TORRENT_PATH\" > {}; exit 1",
+                        out.display()
+                    )),
+                    webhook: None,
+                    files: false,
+                })
+                .build()?,
+        )?;
+        let metainfo = Metainfo::from_bytes(fs::read("file1.txt.torrent")?)?;
+        let info_hash = metainfo.info_hash;
+        let torrent = session.add_torrent(metainfo)?;
+        // completed once added, the files are rechecked when it is
+        fs::copy("file1.txt", dir.join("file1.txt"))?;
+        torrent.force_recheck()?;
+        session.tick()?;
+        let mut alerts = vec![];
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(20));
+            session.tick()?;
+            alerts.extend(session.take_alerts());
+            if !alerts.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(
+            fs::read_to_string(&out)?,
+            format!("torrent_finished {}\n", dir.join("file1.txt").display())
+        );
+        match &alerts[..] {
+            [Alert::HookFailed { torrent, .. }] => assert_eq!(*torrent, to_hex(&info_hash)),
+            other => panic!("{:?}", other),
+        }
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn adds_torrents_from_watch_folders() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_session_watch");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let mut folder = WatchFolder::new(&dir);
+        folder.options = AddOptions {
+            save_path: Some(dir.join("downloads")),
+            paused: true,
+            ..Default::default()
+        };
+        let mut session = Session::new(
+            Config::builder()
+                .listen_port(0)
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .watch(folder)
+                .build()?,
+        )?;
+        let magnet = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567";
+        for (name, data) in [
+            ("file1.torrent", fs::read("file1.txt.torrent")?),
+            ("link.magnet", format!("{}\n", magnet).into_bytes()),
+            ("broken.torrent", b"d4:infoe".to_vec()),
+        ] {
+            let file = fs::File::create(dir.join(name))?;
+            std::io::Write::write_all(&mut &file, &data)?;
+            // done being written a while ago
+            file.set_modified(SystemTime::now() - Duration::from_secs(60))?;
+        }
+        session.tick()?;
+
+        let torrent = session.torrents().next().unwrap();
+        assert_eq!(torrent.save_path(), dir.join("downloads"));
+        assert!(torrent.is_paused());
+        assert!(session.magnet(&Magnet::parse(magnet)?.info_hash).is_some());
+        assert!(dir.join("processed/file1.torrent").exists());
+        assert!(dir.join("processed/link.magnet").exists());
+        assert!(dir.join("failed/broken.torrent").exists());
+        assert!(matches!(
+            &session.take_alerts()[..],
+            [Alert::WatchFailed { file, .. }] if file.ends_with("broken.torrent")
+        ));
+        session.shutdown(Duration::from_secs(5))?;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn adds_torrents_from_feeds() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_session_feeds");
+        let _ = fs::remove_dir_all(&dir);
+        let server = TcpListener::bind("127.0.0.1:0")?;
+        let addr = server.local_addr()?;
+        let magnet = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567";
+        let items = format!(
+            "<item><title>Magnet 1080p</title><link>{}</link></item>\
+             <item><title>File 1080p</title><guid>file</guid>\
+             <enclosure url=\"http://{}/file1.torrent\"/></item>\
+             <item><title>File 720p</title><link>ftp://excluded</link></item>",
+            magnet, addr
+        );
+        let later = "<item><title>Broken 1080p</title><link>ftp://broken</link></item>";
+        // the torrent is downloaded once, the second fetch only lists a new item
+        let bodies = vec![
+            format!("<rss><channel>{}</channel></rss>", items).into_bytes(),
+            fs::read("file1.txt.torrent")?,
+            format!("<rss><channel>{}{}</channel></rss>", items, later).into_bytes(),
+        ];
+        let serve = thread::spawn(move || -> Result<Vec<String>> {
+            let mut paths = vec![];
+            for body in bodies {
+                let (mut stream, _) = server.accept()?;
+                let mut request = [0; 1024];
+                let read = stream.read(&mut request)?;
+                let request = String::from_utf8_lossy(&request[..read]).into_owned();
+                paths.extend(request.split(' ').nth(1).map(String::from));
+                stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n")?;
+                stream.write_all(&body)?;
+            }
+            Ok(paths)
+        });
+        let mut feed = Feed::new(format!("http://{}/feed.xml", addr));
+        feed.include = Some(Regex::ignoring_case("1080P")?);
+        feed.refresh = Duration::from_millis(1);
+        feed.options.paused = true;
+        let mut session = Session::new(
+            Config::builder()
+                .listen_port(0)
+                .save_path(&dir)
+                .resume_dir(&dir)
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .feed("linux", feed)
+                .build()?,
+        )?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut alerts = vec![];
+        while alerts.is_empty() && Instant::now() < deadline {
+            session.tick()?;
+            alerts = session.take_alerts();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            serve.join().unwrap()?,
+            ["/feed.xml", "/file1.torrent", "/feed.xml"]
+        );
+        assert!(matches!(
+            &alerts[..],
+            [Alert::FeedFailed { feed, error }]
+                if feed == "linux" && error.starts_with("adding Broken 1080p")
+        ));
+        assert_eq!(session.torrents().count(), 1);
+        assert!(session.torrents().next().unwrap().is_paused());
+        assert!(session.magnet(&Magnet::parse(magnet)?.info_hash).is_some());
+        let seen = vec![magnet.to_string(), "file".to_string()];
+        assert_eq!(
+            FeedState::load(&dir)?.seen["linux"],
+            seen.into_iter().collect()
+        );
+        session.shutdown(Duration::from_secs(5))?;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn removes_torrents_with_their_data() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_remove");
+        let _ = std::fs::remove_dir_all(&dir);
+        let resume_dir = dir.join("resume");
+        let mut session = Session::new(
+            Config::builder()
+                .listen_port(0)
+                .save_path(&dir)
+                .resume_dir(&resume_dir)
+                .dht(None)
+                .lsd(false)
+                .trackers(false)
+                .port_mapping(false)
+                .build()?,
+        )?;
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let info_hash = metainfo.info_hash;
+        let handle = session.add_torrent(metainfo)?;
+        let files = handle.file_paths();
+        for file in &files {
+            std::fs::create_dir_all(file.parent().unwrap())?;
+            std::fs::write(file, b"data")?;
+        }
+        let other = dir.join("other.txt");
+        std::fs::write(&other, b"not ours")?;
+        handle.save_resume_data(&resume_dir)?;
+
+        session.remove(&handle, true)?;
+        assert!(files.iter().all(|file| !file.exists()));
+        assert!(other.exists());
+        assert!(!ResumeData::path(&resume_dir, &info_hash).exists());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
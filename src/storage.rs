@@ -0,0 +1,702 @@
+use crate::file_map::{FileMap, FileSpan};
+use crate::file_pool::{self, FilePool};
+use anyhow::{bail, Result};
+use memmap2::{Mmap, MmapMut};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// How files are created on the first write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// files get their final size right away without using disk space, the
+    /// download starts instantly but files can end up fragmented
+    #[default]
+    Sparse,
+    /// every byte is reserved up front, slower to start but the files are
+    /// contiguous and the disk can't fill up mid-download
+    Full,
+}
+
+impl Allocation {
+    fn allocate(self, file: &File, length: u64) -> Result<()> {
+        match self {
+            Allocation::Sparse => file.set_len(length)?,
+            Allocation::Full => preallocate(file, length)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, length: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor stays open for the duration of the call
+    let result = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) };
+    match result {
+        0 => Ok(()),
+        // not every filesystem supports it
+        libc::EOPNOTSUPP | libc::EINVAL => fill_zeros(file, length),
+        error => Err(std::io::Error::from_raw_os_error(error).into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, length: u64) -> Result<()> {
+    fill_zeros(file, length)
+}
+
+/// (file system id, bytes available to us) of the file system holding `path`,
+/// which doesn't have to exist yet
+pub(crate) fn free_space(path: &Path) -> Result<Option<(u64, u64)>> {
+    let existing = match path.ancestors().find(|ancestor| ancestor.exists()) {
+        Some(existing) => existing,
+        None => return Ok(None),
+    };
+    let available = fs4::available_space(existing)?;
+    Ok(Some((file_system_id(existing)?, available)))
+}
+
+#[cfg(unix)]
+fn file_system_id(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path)?.dev())
+}
+
+/// the drive or share the path is on, each is a volume of its own
+#[cfg(not(unix))]
+fn file_system_id(path: &Path) -> io::Result<u64> {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::fs::canonicalize(path)?
+        .components()
+        .next()
+        .hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn fill_zeros(file: &File, length: u64) -> Result<()> {
+    let zeros = vec![0; 1 << 20];
+    let mut offset = 0;
+    while offset < length {
+        let chunk = (length - offset).min(zeros.len() as u64) as usize;
+        file_pool::write_at(file, &zeros[..chunk], offset)?;
+        offset += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Where files live until they are complete, they are moved to their final
+/// path once every piece overlapping them is verified and written
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum IncompleteStorage {
+    #[default]
+    InPlace,
+    /// files are downloaded under this directory, with the same layout as in
+    /// the save path
+    Directory(PathBuf),
+    /// files are downloaded next to their final path with a `.part` suffix
+    PartSuffix,
+}
+
+impl IncompleteStorage {
+    /// where a file is stored while incomplete, `relative` is its path under
+    /// the save path
+    pub fn path(&self, final_path: &Path, relative: &Path) -> PathBuf {
+        match self {
+            IncompleteStorage::InPlace => final_path.to_path_buf(),
+            IncompleteStorage::Directory(dir) => dir.join(relative),
+            IncompleteStorage::PartSuffix => {
+                let mut path = final_path.as_os_str().to_owned();
+                path.push(".part");
+                path.into()
+            }
+        }
+    }
+}
+
+/// How reads and writes reach the files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// one syscall per request
+    #[default]
+    File,
+    /// files are mapped in memory once and requests become copies, cheaper
+    /// when seeding lots of small requests. Writes go through the mapping too
+    /// when `writes` is set. Files that can't be mapped yet, because they are
+    /// missing or short, fall back to regular reads and writes.
+    Mmap { writes: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageConfig {
+    /// only affects files that don't exist yet
+    pub allocation: Allocation,
+    pub backend: Backend,
+    /// files kept open at once, the least recently used is closed beyond that
+    pub max_open_files: usize,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            allocation: Allocation::default(),
+            backend: Backend::default(),
+            max_open_files: 256,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Mapping {
+    Read(Mmap),
+    Write(MmapMut),
+}
+
+impl Mapping {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Mapping::Read(map) => map,
+            Mapping::Write(map) => map,
+        }
+    }
+}
+
+/// Part of an open file
+#[derive(Debug, Clone)]
+pub struct FileRange {
+    pub file: Arc<File>,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Disk access of a torrent, blocks are addressed by piece and offset and can
+/// span multiple files
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    fn read_block(&self, piece: usize, offset: u32, length: u32) -> Result<Vec<u8>>;
+
+    fn write_block(&self, piece: usize, offset: u32, data: &[u8]) -> Result<()>;
+
+    /// makes the writes so far durable
+    fn flush(&self) -> Result<()>;
+
+    /// the parts of the files holding the block, for uploads that let the kernel read the files. None when the data isn't in
+    /// files.
+    fn block_files(
+        &self,
+        _piece: usize,
+        _offset: u32,
+        _length: u32,
+    ) -> Result<Option<Vec<FileRange>>> {
+        Ok(None)
+    }
+
+    fn path(&self, index: usize) -> PathBuf;
+
+    fn paths(&self) -> Vec<PathBuf>;
+
+    /// moves a file to `path`, creating its parent directories, and fails
+    /// rather than replace a file already there. a file that wasn't written
+    /// yet is only created at the new path later on. Reads and writes wait
+    /// for the move to finish.
+    fn rename_file(&self, index: usize, path: &Path) -> Result<()>;
+
+    /// fails if the files can't grow by the bytes still to download,
+    /// `remaining` has one entry per file and is 0 for skipped files
+    fn check_space(&self, _remaining: &[u64]) -> Result<()> {
+        Ok(())
+    }
+
+    /// creates the files that don't exist yet at their full size, `wanted`
+    /// has one entry per file and is false for the files to leave alone
+    fn allocate(&self, _wanted: &[bool]) -> Result<()> {
+        Ok(())
+    }
+
+    /// moves every file, `paths` has one entry per file
+    fn move_files(&self, paths: &[PathBuf]) -> Result<()> {
+        for (index, path) in paths.iter().enumerate() {
+            self.rename_file(index, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads and writes pieces to the files of a torrent on disk
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    /// shared between clones so a renamed file is seen by every one of them
+    paths: Arc<RwLock<Vec<PathBuf>>>,
+    map: FileMap,
+    config: StorageConfig,
+    /// files mapped by the mmap backend, shared between clones
+    mappings: Arc<Mutex<HashMap<usize, Mapping>>>,
+    /// shared between clones
+    pool: Arc<Mutex<FilePool>>,
+}
+
+impl FileStorage {
+    pub fn new(files: Vec<(PathBuf, u64)>, piece_length: u64) -> Self {
+        let (paths, lengths): (_, Vec<u64>) = files.into_iter().unzip();
+        Self {
+            paths: Arc::new(RwLock::new(paths)),
+            map: FileMap::new(
+                lengths.into_iter().map(|length| (length, false)),
+                piece_length,
+            ),
+            config: StorageConfig::default(),
+            mappings: Arc::default(),
+            pool: Arc::new(Mutex::new(FilePool::new(
+                StorageConfig::default().max_open_files,
+            ))),
+        }
+    }
+
+    /// padding files of hybrid torrents read as zeros and are never
+    /// created
+    pub fn with_padding(mut self, padding: &[bool]) -> Self {
+        let lengths: Vec<_> = (0..self.map.file_count())
+            .map(|file| self.map.file_length(file))
+            .collect();
+        self.map = FileMap::new(
+            lengths.into_iter().zip(padding.iter().copied()),
+            self.map.piece_length(),
+        );
+        self
+    }
+
+    pub fn config(&self) -> StorageConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: StorageConfig) {
+        self.config = config;
+        self.mappings.lock().unwrap().clear();
+        self.pool
+            .lock()
+            .unwrap()
+            .set_capacity(config.max_open_files);
+    }
+
+    /// closes every file, they are reopened on the next read or write
+    pub fn close_files(&self) {
+        self.mappings.lock().unwrap().clear();
+        self.pool.lock().unwrap().close_all();
+    }
+
+    fn open(&self, index: usize, path: &Path, writable: bool) -> Result<(Arc<File>, bool)> {
+        Ok(self.pool.lock().unwrap().open(index, path, writable)?)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.map.total_length()
+    }
+
+    /// runs `f` on the mapping of the file, None when the backend doesn't use
+    /// mmap or the file can't be mapped
+    fn with_mapping<T>(
+        &self,
+        index: usize,
+        path: &Path,
+        f: impl FnOnce(&mut Mapping) -> T,
+    ) -> Result<Option<T>> {
+        let writable = match self.config.backend {
+            Backend::File => return Ok(None),
+            Backend::Mmap { writes } => writes,
+        };
+        let mut mappings = self.mappings.lock().unwrap();
+        let mapping = match mappings.entry(index) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let length = self.map.file_length(index);
+                let file = match OpenOptions::new().read(true).write(writable).open(path) {
+                    Ok(file) => file,
+                    Err(_) => return Ok(None),
+                };
+                if length == 0 || file.metadata()?.len() != length {
+                    return Ok(None);
+                }
+                // SAFETY: the files are only modified through the storage, a file
+                // truncated by another process while mapped would crash us
+                entry.insert(unsafe {
+                    if writable {
+                        Mapping::Write(MmapMut::map_mut(&file)?)
+                    } else {
+                        Mapping::Read(Mmap::map(&file)?)
+                    }
+                })
+            }
+        };
+        Ok(Some(f(mapping)))
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&self, piece: usize, offset: u32, length: u32) -> Result<Vec<u8>> {
+        let start = piece as u64 * self.map.piece_length() + offset as u64;
+        let spans = match self.map.map_range(start, length as u64) {
+            Some(spans) => spans,
+            None => bail!("read past the end of the torrent"),
+        };
+        // renames wait for the reads and writes in progress
+        let paths = self.paths.read().unwrap();
+        let mut data = vec![0; length as usize];
+        let mut position = 0;
+        for FileSpan {
+            file: index,
+            offset: file_offset,
+            length: span,
+        } in spans
+        {
+            // padding is all zeros and never written to disk
+            if self.map.is_padding(index) {
+                position += span as usize;
+                continue;
+            }
+            let buffer = &mut data[position..position + span as usize];
+            let range = file_offset as usize..(file_offset + span) as usize;
+            let mapped = self.with_mapping(index, &paths[index], |mapping| {
+                buffer.copy_from_slice(&mapping.as_slice()[range]);
+            })?;
+            if mapped.is_none() {
+                let (file, _) = self.open(index, &paths[index], false)?;
+                file_pool::read_at(&file, buffer, file_offset)?;
+            }
+            position += span as usize;
+        }
+        Ok(data)
+    }
+
+    fn allocate(&self, wanted: &[bool]) -> Result<()> {
+        let paths = self.paths.read().unwrap();
+        for (index, path) in paths.iter().enumerate() {
+            let length = self.map.file_length(index);
+            if !wanted[index] || length == 0 || self.map.is_padding(index) {
+                continue;
+            }
+            let (file, created) = self.open(index, path, true)?;
+            if created {
+                self.config.allocation.allocate(&file, length)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// creates missing files and directories as needed, new files are
+    /// allocated according to the configured allocation
+    fn write_block(&self, piece: usize, offset: u32, data: &[u8]) -> Result<()> {
+        let start = piece as u64 * self.map.piece_length() + offset as u64;
+        let spans = match self.map.map_range(start, data.len() as u64) {
+            Some(spans) => spans,
+            None => bail!("write past the end of the torrent"),
+        };
+        let paths = self.paths.read().unwrap();
+        let mut position = 0;
+        for FileSpan {
+            file: index,
+            offset: file_offset,
+            length: span,
+        } in spans
+        {
+            if self.map.is_padding(index) {
+                position += span as usize;
+                continue;
+            }
+            let (path, length) = (&paths[index], self.map.file_length(index));
+            let (file, created) = self.open(index, path, true)?;
+            if created {
+                self.config.allocation.allocate(&file, length)?;
+            }
+            let block = &data[position..position + span as usize];
+            let range = file_offset as usize..(file_offset + span) as usize;
+            let mapped = self.with_mapping(index, path, |mapping| match mapping {
+                Mapping::Write(map) => {
+                    map[range].copy_from_slice(block);
+                    true
+                }
+                Mapping::Read(_) => false,
+            })?;
+            if mapped != Some(true) {
+                file_pool::write_at(&file, block, file_offset)?;
+            }
+            position += span as usize;
+        }
+        Ok(())
+    }
+
+    fn block_files(
+        &self,
+        piece: usize,
+        offset: u32,
+        length: u32,
+    ) -> Result<Option<Vec<FileRange>>> {
+        let start = piece as u64 * self.map.piece_length() + offset as u64;
+        let spans = match self.map.map_range(start, length as u64) {
+            Some(spans) => spans,
+            None => bail!("read past the end of the torrent"),
+        };
+        let paths = self.paths.read().unwrap();
+        let mut files = vec![];
+        for span in spans {
+            if self.map.is_padding(span.file) {
+                return Ok(None);
+            }
+            let (file, _) = self.open(span.file, &paths[span.file], false)?;
+            // a short file would make the upload end early
+            if file.metadata()?.len() < span.offset + span.length {
+                bail!("{} is shorter than expected", paths[span.file].display());
+            }
+            files.push(FileRange {
+                file,
+                offset: span.offset,
+                length: span.length,
+            });
+        }
+        Ok(Some(files))
+    }
+
+    fn flush(&self) -> Result<()> {
+        for mapping in self.mappings.lock().unwrap().values() {
+            if let Mapping::Write(map) = mapping {
+                map.flush()?;
+            }
+        }
+        self.pool.lock().unwrap().sync_all()?;
+        Ok(())
+    }
+
+    /// sparse files only grow as data is written, fully allocated files take
+    /// their whole length when created and nothing after that
+    fn check_space(&self, remaining: &[u64]) -> Result<()> {
+        // files can be spread over several file systems with incomplete storage
+        let mut file_systems: HashMap<u64, (PathBuf, u64, u64)> = HashMap::new();
+        for (index, &bytes) in remaining.iter().enumerate() {
+            let path = self.path(index);
+            let needed = match self.config.allocation {
+                _ if bytes == 0 => continue,
+                Allocation::Sparse => bytes,
+                Allocation::Full if path.exists() => continue,
+                Allocation::Full => self.map.file_length(index),
+            };
+            if let Some((id, available)) = free_space(&path)? {
+                file_systems.entry(id).or_insert((path, available, 0)).2 += needed;
+            }
+        }
+        for (path, available, needed) in file_systems.into_values() {
+            if needed > available {
+                bail!(
+                    "not enough disk space for {}: {} bytes needed, {} available",
+                    path.display(),
+                    needed,
+                    available
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        self.paths.read().unwrap()[index].clone()
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        self.paths.read().unwrap().clone()
+    }
+
+    fn rename_file(&self, index: usize, path: &Path) -> Result<()> {
+        let mut paths = self.paths.write().unwrap();
+        if paths[index] == path {
+            return Ok(());
+        }
+        self.mappings.lock().unwrap().remove(&index);
+        self.pool.lock().unwrap().close(index);
+        if paths[index].exists() {
+            // a file of the torrent that wasn't written yet may take an
+            // existing file, one with data never replaces it
+            if path.exists() {
+                bail!("{} already exists", path.display());
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // rename fails across file systems, copy the data instead
+            if std::fs::rename(&paths[index], path).is_err() {
+                copy_new(&paths[index], path)?;
+                std::fs::remove_file(&paths[index])?;
+            }
+        }
+        paths[index] = path.to_path_buf();
+        Ok(())
+    }
+}
+
+/// copies `from` to a new file at `to`, removed again if the copy fails
+fn copy_new(from: &Path, to: &Path) -> io::Result<()> {
+    let mut target = OpenOptions::new().write(true).create_new(true).open(to)?;
+    let copied = io::copy(&mut File::open(from)?, &mut target).and_then(|_| target.sync_all());
+    if copied.is_err() {
+        let _ = std::fs::remove_file(to);
+    }
+    copied
+}
+
+/// Keeps the pieces in memory, for tests of the download pipeline that
+/// shouldn't touch the disk
+#[derive(Debug)]
+pub struct MemoryStorage {
+    paths: Mutex<Vec<PathBuf>>,
+    map: FileMap,
+    pieces: Mutex<HashMap<usize, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(files: Vec<(PathBuf, u64)>, piece_length: u64) -> Self {
+        let (paths, lengths): (Vec<_>, Vec<u64>) = files.into_iter().unzip();
+        Self {
+            paths: Mutex::new(paths),
+            map: FileMap::new(
+                lengths.into_iter().map(|length| (length, false)),
+                piece_length,
+            ),
+            pieces: Mutex::default(),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    /// fails like a missing file if nothing was written to the piece
+    fn read_block(&self, piece: usize, offset: u32, length: u32) -> Result<Vec<u8>> {
+        let end = offset as usize + length as usize;
+        match self.pieces.lock().unwrap().get(&piece) {
+            Some(data) if end <= data.len() => Ok(data[offset as usize..end].to_vec()),
+            Some(_) => bail!("read past the end of piece {}", piece),
+            None => bail!("piece {} was not written", piece),
+        }
+    }
+
+    fn write_block(&self, piece: usize, offset: u32, data: &[u8]) -> Result<()> {
+        let size = self.map.piece_size(piece) as usize;
+        let end = offset as usize + data.len();
+        if end > size {
+            bail!("write past the end of the torrent");
+        }
+        let mut pieces = self.pieces.lock().unwrap();
+        let piece = pieces.entry(piece).or_insert_with(|| vec![0; size]);
+        piece[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        self.paths.lock().unwrap()[index].clone()
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        self.paths.lock().unwrap().clone()
+    }
+
+    fn rename_file(&self, index: usize, path: &Path) -> Result<()> {
+        self.paths.lock().unwrap()[index] = path.to_path_buf();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn piece_spanning_files() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_storage_test");
+        let storage = FileStorage::new(vec![(dir.join("a"), 3), (dir.join("sub").join("b"), 5)], 4);
+
+        storage.write_block(0, 0, b"abcd")?;
+        storage.write_block(1, 0, b"efgh")?;
+        assert_eq!(std::fs::read(dir.join("a"))?, b"abc");
+        assert_eq!(std::fs::read(dir.join("sub").join("b"))?, b"defgh");
+        assert_eq!(storage.read_block(0, 2, 4)?, b"cdef");
+        assert!(storage.read_block(1, 2, 4).is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mmap_backend() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_mmap_test");
+        let files = vec![(dir.join("a"), 3), (dir.join("b"), 5)];
+        let mut storage = FileStorage::new(files.clone(), 4);
+        storage.set_config(StorageConfig {
+            backend: Backend::Mmap { writes: true },
+            ..Default::default()
+        });
+
+        storage.write_block(0, 0, b"abcd")?;
+        storage.write_block(1, 0, b"efgh")?;
+        assert_eq!(storage.read_block(0, 2, 4)?, b"cdef");
+
+        // a read only mapping sees the writes of another storage
+        let mut reader = FileStorage::new(files, 4);
+        reader.set_config(StorageConfig {
+            backend: Backend::Mmap { writes: false },
+            ..Default::default()
+        });
+        assert_eq!(reader.read_block(0, 0, 4)?, b"abcd");
+        storage.write_block(1, 0, b"EFGH")?;
+        assert_eq!(reader.read_block(1, 0, 4)?, b"EFGH");
+        drop(storage);
+        drop(reader);
+        assert_eq!(std::fs::read(dir.join("b"))?, b"dEFGH");
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn full_allocation() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_allocation_test");
+        let length = 1 << 20;
+        let mut storage = FileStorage::new(vec![(dir.join("a"), length)], 1 << 16);
+        storage.set_config(StorageConfig {
+            allocation: Allocation::Full,
+            ..Default::default()
+        });
+
+        storage.write_block(15, 0, b"end")?;
+        let metadata = std::fs::metadata(dir.join("a"))?;
+        assert_eq!(metadata.len(), length);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(metadata.blocks() * 512 >= length);
+        }
+        assert_eq!(storage.read_block(15, 0, 4)?, b"end\0");
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn free_space_check() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_free_space_test");
+        let huge = 1 << 62;
+        let mut storage = FileStorage::new(vec![(dir.join("a"), 4), (dir.join("b"), huge)], 4);
+
+        assert!(storage.check_space(&[4, huge]).is_err());
+        // skipped files don't count
+        storage.check_space(&[4, 0])?;
+
+        // a fully allocated file that exists already has its space
+        storage.set_config(StorageConfig {
+            allocation: Allocation::Full,
+            ..Default::default()
+        });
+        storage.write_block(0, 0, b"abcd")?;
+        storage.check_space(&[4, 0])?;
+        assert!(storage.check_space(&[0, 1]).is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,509 @@
+use crate::api;
+use crate::file_map::FileMap;
+use crate::http;
+use crate::magnet::percent_decode;
+use crate::metainfo::to_hex;
+use crate::picker::{Priority, Sequential};
+use crate::scheduler::BlockRequest;
+use crate::torrent::TorrentHandle;
+use anyhow::Result;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// how long a player that stopped reading, paused, keeps its connection
+const WRITE_TIMEOUT: Duration = Duration::from_secs(120);
+/// pieces ahead of the one being read that get a deadline
+const READAHEAD: usize = 8;
+/// between the deadlines of consecutive pieces of the readahead
+const DEADLINE_STEP: Duration = Duration::from_millis(500);
+/// between looks at whether the piece being read arrived
+const WAIT: Duration = Duration::from_millis(50);
+/// players served at once, each has a thread, the others wait to be
+/// accepted
+const MAX_STREAMS: usize = 16;
+
+/// Serves the files of the torrents over HTTP while they download, at
+/// `GET /<info hash>/<file index>` with anything after it ignored so
+/// players can be given a name like `/<info hash>/0/movie.mkv`. Ranges are
+/// supported so players can seek, and a read waits for its pieces to be
+/// verified and written. A torrent that is streamed downloads in piece
+/// order from then on, the pieces just ahead of what is read with a
+/// deadline. With a token, players give it as `?token=` or in an
+/// `Authorization: Bearer` header.
+pub struct StreamServer {
+    addr: SocketAddr,
+    torrents: Arc<RwLock<Vec<TorrentHandle>>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl StreamServer {
+    /// `token` is that of the api, None serves anyone reaching the address
+    pub fn start(addr: SocketAddr, token: Option<String>) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        // wakes up regularly to stop when asked to
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let torrents = Arc::new(RwLock::new(vec![]));
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let torrents = Arc::clone(&torrents);
+            let stop = Arc::clone(&stop);
+            let streams = Streams {
+                torrents,
+                token,
+                stop,
+                serving: AtomicUsize::new(0),
+            };
+            thread::spawn(move || serve(listener, Arc::new(streams)))
+        };
+        Ok(Self {
+            addr,
+            torrents,
+            stop,
+            worker: Some(worker),
+        })
+    }
+
+    /// the address actually listened on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// the torrents served from now on, streams of those left out stop
+    pub fn update(&self, torrents: Vec<TorrentHandle>) {
+        *self.torrents.write().unwrap() = torrents;
+    }
+}
+
+impl Drop for StreamServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// What the threads answering players share
+struct Streams {
+    torrents: Arc<RwLock<Vec<TorrentHandle>>>,
+    token: Option<String>,
+    stop: Arc<AtomicBool>,
+    /// players being answered
+    serving: AtomicUsize,
+}
+
+impl Streams {
+    fn torrent(&self, info_hash: &str) -> Option<TorrentHandle> {
+        self.torrents
+            .read()
+            .unwrap()
+            .iter()
+            .find(|torrent| to_hex(&torrent.info_hash()) == info_hash)
+            .cloned()
+    }
+
+    /// the torrent is still served and the server running
+    fn is_serving(&self, torrent: &TorrentHandle) -> bool {
+        let info_hash = torrent.info_hash();
+        !self.stop.load(Ordering::Relaxed)
+            && self
+                .torrents
+                .read()
+                .unwrap()
+                .iter()
+                .any(|served| served.info_hash() == info_hash)
+    }
+}
+
+fn serve(listener: TcpListener, streams: Arc<Streams>) {
+    while !streams.stop.load(Ordering::Relaxed) {
+        if streams.serving.load(Ordering::Relaxed) >= MAX_STREAMS {
+            thread::sleep(Duration::from_millis(50));
+            continue;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                streams.serving.fetch_add(1, Ordering::Relaxed);
+                let streams = Arc::clone(&streams);
+                // a stream waits for its pieces without holding up the
+                // others, and stops waiting with the server
+                thread::spawn(move || {
+                    let _ = respond(stream, &streams);
+                    streams.serving.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/// A request as read from the player
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+    /// of `?token=` or the `Authorization` header
+    token: Option<String>,
+}
+
+fn respond(mut stream: TcpStream, streams: &Streams) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(refused) => {
+            let status = format!("{} {}", refused.status, http::reason(refused.status));
+            return error(stream, &status, refused.message);
+        }
+    };
+    if let Some(token) = &streams.token {
+        let given = request.token.as_deref().unwrap_or_default();
+        if !api::same_token(given.as_bytes(), token.as_bytes()) {
+            return error(stream, "401 Unauthorized", "missing or wrong token");
+        }
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return error(stream, "405 Method Not Allowed", "only GET is supported");
+    }
+    let mut parts = request.path.split('/').skip(1);
+    let (info_hash, file) = match (parts.next(), parts.next().map(str::parse::<usize>)) {
+        (Some(info_hash), Some(Ok(file))) => (info_hash.to_ascii_lowercase(), file),
+        _ => return error(stream, "404 Not Found", "not found"),
+    };
+    let torrent = match streams.torrent(&info_hash) {
+        Some(torrent) => torrent,
+        None => return error(stream, "404 Not Found", "no such torrent"),
+    };
+    let metainfo = torrent.metainfo();
+    let info = &metainfo.info;
+    let selected = torrent.file_priorities().get(file) != Some(&Priority::Skip);
+    if file >= info.files.len() || info.files[file].padding || !selected {
+        return error(
+            stream,
+            "404 Not Found",
+            "no such file, or it isn't selected",
+        );
+    }
+    let map = FileMap::from_info(info);
+    let length = map.file_length(file);
+    let range = byte_range(request.range.as_deref(), length);
+    let (status, start, end) = match range {
+        Ok(Some((start, end))) => ("206 Partial Content", start, end),
+        Ok(None) => ("200 OK", 0, length),
+        Err(()) => {
+            let response = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n",
+                length
+            );
+            return stream.write_all(response.as_bytes());
+        }
+    };
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n",
+        status,
+        content_type(&info.files[file].path),
+        end - start
+    );
+    if let Ok(Some(_)) = range {
+        head.push_str(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            start,
+            end - 1,
+            length
+        ));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())?;
+    if request.method == "HEAD" || start == end {
+        return Ok(());
+    }
+    torrent.set_picker(Box::new(Sequential));
+    let offset = map.file_offset(file);
+    let sent = send(
+        &mut stream,
+        streams,
+        &torrent,
+        &map,
+        offset + start..offset + end,
+    );
+    clear_deadlines(&torrent, &map, offset + start..offset + end);
+    sent
+}
+
+/// the bytes of the torrent in `range`, piece by piece as each is ready
+fn send(
+    stream: &mut TcpStream,
+    streams: &Streams,
+    torrent: &TorrentHandle,
+    map: &FileMap,
+    range: std::ops::Range<u64>,
+) -> io::Result<()> {
+    let piece_length = map.piece_length();
+    let mut position = range.start;
+    while position < range.end {
+        let piece = (position / piece_length) as usize;
+        let last = ((range.end - 1) / piece_length) as usize;
+        for (ahead, next) in (piece..=last.min(piece + READAHEAD - 1)).enumerate() {
+            if !torrent.is_piece_readable(next) {
+                torrent.set_piece_deadline(next, DEADLINE_STEP * (ahead as u32 + 1));
+            }
+        }
+        while !torrent.is_piece_readable(piece) {
+            if !streams.is_serving(torrent) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            thread::sleep(WAIT);
+        }
+        let piece_start = piece as u64 * piece_length;
+        let piece_end = (piece_start + map.piece_size(piece)).min(range.end);
+        let data = torrent
+            .read_block(&BlockRequest {
+                piece,
+                offset: (position - piece_start) as u32,
+                length: (piece_end - position) as u32,
+            })
+            .map_err(io::Error::other)?;
+        stream.write_all(&data)?;
+        position = piece_end;
+    }
+    Ok(())
+}
+
+/// of the pieces a stream that ended didn't get to
+fn clear_deadlines(torrent: &TorrentHandle, map: &FileMap, range: std::ops::Range<u64>) {
+    let piece_length = map.piece_length();
+    let first = (range.start / piece_length) as usize;
+    let last = ((range.end - 1) / piece_length) as usize;
+    for piece in first..=last {
+        torrent.clear_piece_deadline(piece);
+    }
+}
+
+fn error(mut stream: TcpStream, status: &str, message: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        message.len() + 1,
+        message
+    );
+    stream.write_all(response.as_bytes())
+}
+
+/// the start and end, past the last byte, of the range the header asks
+/// for. None serves the whole file, as for lists of ranges which players
+/// don't send, and an error is a range past the end of the file.
+fn byte_range(header: Option<&str>, length: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (first, last) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // the last bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => (length.saturating_sub(suffix), length),
+        (Ok(start), Err(_)) if last.is_empty() => (start, length),
+        (Ok(start), Ok(last)) if last >= start => (start, (last + 1).min(length)),
+        _ => return Err(()),
+    };
+    if start >= length || start == end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// by extension, for the players that go by it
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "srt" => "application/x-subrip",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+/// the request of the player, which has no body
+fn read_request(stream: &mut TcpStream) -> Result<Request, http::Error> {
+    let request = http::read_request(stream, 0)?;
+    let token = request
+        .query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .and_then(|token| percent_decode(token).ok())
+        .or_else(|| {
+            request
+                .header("authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(String::from)
+        });
+    Ok(Request {
+        range: request.header("range").map(String::from),
+        token,
+        method: request.method,
+        path: request.path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::LEAF_SIZE;
+    use crate::metainfo::Metainfo;
+    use crate::torrent::Torrent;
+    use std::io::Read;
+    use std::time::Instant;
+
+    fn get(addr: SocketAddr, path: &str, range: Option<&str>) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(addr)?;
+        let range = range
+            .map(|range| format!("Range: {}\r\n", range))
+            .unwrap_or_default();
+        write!(stream, "GET {} HTTP/1.1\r\n{}\r\n", path, range)?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+
+    fn split(response: &[u8]) -> (String, &[u8]) {
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        (
+            String::from_utf8_lossy(&response[..end]).into_owned(),
+            &response[end + 4..],
+        )
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(byte_range(None, 10), Ok(None));
+        assert_eq!(byte_range(Some("bytes=2-4"), 10), Ok(Some((2, 5))));
+        assert_eq!(byte_range(Some("bytes=2-"), 10), Ok(Some((2, 10))));
+        assert_eq!(byte_range(Some("bytes=-3"), 10), Ok(Some((7, 10))));
+        assert_eq!(byte_range(Some("bytes=5-100"), 10), Ok(Some((5, 10))));
+        assert_eq!(byte_range(Some("bytes=0-1,4-5"), 10), Ok(None));
+        assert_eq!(byte_range(Some("bytes=10-"), 10), Err(()));
+        assert_eq!(byte_range(Some("bytes=4-2"), 10), Err(()));
+        assert_eq!(byte_range(Some("bytes=-0"), 10), Err(()));
+        assert_eq!(byte_range(Some("bytes=a-b"), 10), Err(()));
+    }
+
+    #[test]
+    fn asks_for_the_token_and_limits_the_players() -> Result<()> {
+        let server = StreamServer::start("127.0.0.1:0".parse()?, Some("secret".into()))?;
+        let addr = server.local_addr();
+        let (head, _) = split(&get(addr, "/ab/0", None)?);
+        assert!(head.starts_with("HTTP/1.1 401 "), "{}", head);
+        let (head, _) = split(&get(addr, "/ab/0?token=wrong", None)?);
+        assert!(head.starts_with("HTTP/1.1 401 "), "{}", head);
+        let (head, _) = split(&get(addr, "/ab/0/a.mkv?token=secret", None)?);
+        assert!(head.starts_with("HTTP/1.1 404 "), "{}", head);
+
+        // players that don't send their request hold their thread
+        let idle: Vec<_> = (0..MAX_STREAMS)
+            .map(|_| TcpStream::connect(addr))
+            .collect::<io::Result<_>>()?;
+        thread::sleep(Duration::from_millis(200));
+        let mut waiting = TcpStream::connect(addr)?;
+        write!(waiting, "GET /ab/0?token=secret HTTP/1.1\r\n\r\n")?;
+        waiting.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let mut response = vec![];
+        assert!(waiting.read_to_end(&mut response).is_err());
+        drop(idle);
+        waiting.set_read_timeout(Some(Duration::from_secs(5)))?;
+        waiting.read_to_end(&mut response)?;
+        let (head, _) = split(&response);
+        assert!(head.starts_with("HTTP/1.1 404 "), "{}", head);
+        Ok(())
+    }
+
+    #[test]
+    fn streams_files_as_they_download() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_stream");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("movie.mkv");
+        let data: Vec<u8> = (0..3 * LEAF_SIZE).map(|byte| byte as u8).collect();
+        std::fs::write(&path, &data)?;
+        let bytes = crate::create::TorrentCreator::new(&path)
+            .piece_length(LEAF_SIZE)
+            .create(|_, _| {})?;
+        let mut torrent = Torrent::new(Metainfo::from_bytes(bytes)?, &dir);
+        // the last piece is still downloading
+        torrent.piece_verified(0);
+        torrent.piece_verified(1);
+        let torrent = TorrentHandle::new(torrent);
+        let info_hash = to_hex(&torrent.info_hash());
+        let server = StreamServer::start("127.0.0.1:0".parse()?, None)?;
+        let addr = server.local_addr();
+        server.update(vec![torrent]);
+
+        let path = format!("/{}/0/movie.mkv", info_hash);
+        let response = get(addr, &path, Some("bytes=100-16483"))?;
+        let (head, body) = split(&response);
+        assert!(
+            head.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("Content-Type: video/x-matroska\r\n"));
+        assert!(head.contains("Content-Range: bytes 100-16483/49152\r\n"));
+        assert_eq!(body, &data[100..16484]);
+
+        let response = get(addr, &path, Some("bytes=60000-"))?;
+        let (head, _) = split(&response);
+        assert!(head.starts_with("HTTP/1.1 416 "), "{}", head);
+        assert!(head.contains("Content-Range: bytes */49152\r\n"));
+        let (head, _) = split(&get(addr, &format!("/{}/1", info_hash), None)?);
+        assert!(head.starts_with("HTTP/1.1 404 "), "{}", head);
+
+        // the whole file waits for the last piece, and stops with the server
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\n\r\n", path)?;
+        stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+        let mut received = vec![];
+        let mut buffer = [0; 4096];
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(300) {
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => received.extend_from_slice(&buffer[..read]),
+                Err(_) => break,
+            }
+        }
+        let (head, body) = split(&received);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Length: 49152\r\n"));
+        assert_eq!(body, &data[..2 * LEAF_SIZE as usize]);
+        drop(server);
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert_eq!(stream.read(&mut buffer)?, 0);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,989 @@
+use crate::bandwidth::{Bandwidth, Direction};
+use crate::bencode::{Bencode, Parser};
+use crate::bitfield::Bitfield;
+use crate::disk::DiskCompletion;
+use crate::message::{Handshake, Message, HANDSHAKE_LENGTH};
+use crate::metainfo::to_hex;
+use crate::peer::{self, PeerSource};
+use crate::pex::{self, PexMessage, UT_PEX_ID};
+use crate::runtime;
+use crate::scheduler::{BlockRequest, BLOCK_SIZE};
+use crate::storage::Storage;
+use crate::torrent::{Torrent, TorrentHandle};
+use crate::upload::UploadBlock;
+use crate::verifier::{self, Verification};
+use anyhow::{anyhow, bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, MissedTickBehavior};
+
+/// peers a torrent connects to at once
+const MAX_DIALS: usize = 8;
+/// accepted connections that didn't send their handshake yet
+const MAX_HANDSHAKES: usize = 32;
+/// to connect and exchange handshakes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// peers are dropped after this long without a message, keep alives are
+/// sent after half of it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// larger messages than a block end the connection, bitfields of torrents
+/// with millions of pieces still fit
+const MAX_MESSAGE: usize = 1024 * 1024;
+/// blocks a peer may ask for, as advertised in our extension handshake
+const REQQ: i64 = 250;
+/// how often the engine dials peers, sends what the torrents queued and
+/// dispatches their disk jobs
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Connects the torrents added with `add_torrent` to their peers: accepts
+/// the connections of the listen socket, dials the candidates of the
+/// torrents and runs their disk jobs and hash checks on the blocking threads
+/// of the runtime. Each connection is a task of its own, the messages go
+/// through the engine task which is the only one feeding them to the
+/// torrents.
+pub struct SwarmTask {
+    torrents: Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>,
+    stop: watch::Sender<bool>,
+    /// disconnected once the engine is done
+    done: std_mpsc::Receiver<()>,
+}
+
+impl SwarmTask {
+    /// peers connect to `listener`, we introduce ourselves as `peer_id`
+    pub fn start(
+        listener: std::net::TcpListener,
+        peer_id: [u8; 20],
+        bandwidth: Arc<Mutex<Bandwidth>>,
+    ) -> io::Result<Self> {
+        let runtime = runtime::handle();
+        let _entered = runtime.enter();
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        let (stop, stopped) = watch::channel(false);
+        let (events, received) = mpsc::unbounded_channel();
+        let (completions, completed) = mpsc::unbounded_channel();
+        let (done, finished) = std_mpsc::channel();
+        let engine = Engine {
+            listener,
+            peer_id,
+            link: Link {
+                events,
+                bandwidth,
+                stop: stopped,
+            },
+            received,
+            completions,
+            completed,
+            added: Arc::clone(&torrents),
+            torrents: HashMap::new(),
+            connections: HashMap::new(),
+            next_id: 0,
+            last_rates: Instant::now(),
+            _done: done,
+        };
+        runtime.spawn(engine.run());
+        Ok(Self {
+            torrents,
+            stop,
+            done: finished,
+        })
+    }
+
+    /// connects the torrent to its peers until it is removed
+    pub fn add_torrent(&self, torrent: &TorrentHandle) {
+        self.torrents
+            .lock()
+            .unwrap()
+            .insert(torrent.info_hash(), torrent.clone());
+    }
+
+    /// its connections are closed
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+}
+
+impl Drop for SwarmTask {
+    /// closes the connections and waits for the disk jobs and hash checks
+    /// already started
+    fn drop(&mut self) {
+        let _ = self.stop.send(true);
+        let _ = self.done.recv();
+    }
+}
+
+/// What the connection tasks tell the engine
+enum PeerEvent {
+    Handshake { id: u64, handshake: Handshake },
+    Message { id: u64, message: Message },
+    Closed { id: u64, error: Option<String> },
+}
+
+/// What a connection task sends its peer
+enum Outgoing {
+    Handshake(Handshake),
+    Message(Message),
+    /// piece message of a block, its data isn't copied
+    Block {
+        piece: u32,
+        offset: u32,
+        block: UploadBlock,
+    },
+    Close,
+}
+
+/// What the blocking threads hand back to the engine
+enum Completion {
+    Disk([u8; 20], DiskCompletion),
+    Verified([u8; 20], Verification),
+}
+
+/// What the connection tasks share with the engine
+#[derive(Clone)]
+struct Link {
+    events: mpsc::UnboundedSender<PeerEvent>,
+    bandwidth: Arc<Mutex<Bandwidth>>,
+    stop: watch::Receiver<bool>,
+}
+
+impl Link {
+    /// waits until the bandwidth limits allow some of `bytes`, returns how
+    /// many, 0 once stopped
+    async fn quota(
+        &self,
+        info_hash: &[u8; 20],
+        peer: SocketAddr,
+        direction: Direction,
+        bytes: u64,
+    ) -> u64 {
+        loop {
+            let granted = self.bandwidth.lock().unwrap().request(
+                info_hash,
+                peer,
+                direction,
+                bytes,
+                Instant::now(),
+            );
+            if granted > 0 || self.is_stopped() {
+                return granted;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// waits until the bandwidth limits allow reading some bytes, returns
+    /// how many up to `bytes` without taking them, 0 once stopped
+    async fn available(
+        &self,
+        info_hash: &[u8; 20],
+        peer: SocketAddr,
+        direction: Direction,
+        bytes: u64,
+    ) -> u64 {
+        loop {
+            let available = self.bandwidth.lock().unwrap().available(
+                info_hash,
+                peer,
+                direction,
+                Instant::now(),
+            );
+            if available > 0 || self.is_stopped() {
+                return available.min(bytes);
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stop.borrow()
+    }
+}
+
+/// A connection as seen by the engine
+struct Connection {
+    addr: SocketAddr,
+    /// dialed for a torrent, or named by the handshake of the peer
+    info_hash: Option<[u8; 20]>,
+    source: PeerSource,
+    /// to the task of the connection, which writes once the handshakes are
+    /// exchanged
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    /// the torrent accepted the peer
+    connected: bool,
+    /// piece data received and sent since the rates were last updated
+    downloaded: u64,
+    uploaded: u64,
+    /// ut_pex id of the peer, from its extension handshake
+    pex_id: Option<u8>,
+    /// the peers it was told about, and when
+    pex_sent: HashSet<SocketAddr>,
+    last_pex: Instant,
+}
+
+impl Connection {
+    fn send(&self, outgoing: Outgoing) {
+        let _ = self.outgoing.send(outgoing);
+    }
+
+    fn is_dialing(&self) -> bool {
+        self.source != PeerSource::Incoming && !self.connected
+    }
+}
+
+/// What the engine keeps of each torrent
+struct Swarm {
+    torrent: TorrentHandle,
+    jobs: Jobs,
+}
+
+/// The disk jobs and hash checks of a torrent being run
+struct Jobs {
+    /// the disk jobs run on, replaced with that of the torrent once idle
+    storage: Arc<dyn Storage>,
+    disk_jobs: usize,
+    verifications: usize,
+}
+
+impl Jobs {
+    fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            disk_jobs: 0,
+            verifications: 0,
+        }
+    }
+
+    /// hands the queued disk jobs to the blocking threads
+    fn dispatch(
+        &mut self,
+        info_hash: [u8; 20],
+        torrent: &mut Torrent,
+        completions: &mpsc::UnboundedSender<Completion>,
+    ) {
+        if !Arc::ptr_eq(&self.storage, torrent.storage()) {
+            if self.disk_jobs > 0 {
+                return;
+            }
+            self.storage = Arc::clone(torrent.storage());
+        }
+        while let Some(job) = torrent.poll_disk_job() {
+            self.disk_jobs += 1;
+            let storage = Arc::clone(&self.storage);
+            let completions = completions.clone();
+            tokio::task::spawn_blocking(move || {
+                let completion = job.run(&*storage);
+                let _ = completions.send(Completion::Disk(info_hash, completion));
+            });
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.disk_jobs == 0 && self.verifications == 0
+    }
+}
+
+struct Engine {
+    listener: TcpListener,
+    peer_id: [u8; 20],
+    link: Link,
+    received: mpsc::UnboundedReceiver<PeerEvent>,
+    completions: mpsc::UnboundedSender<Completion>,
+    completed: mpsc::UnboundedReceiver<Completion>,
+    /// as added to the task
+    added: Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>,
+    torrents: HashMap<[u8; 20], Swarm>,
+    connections: HashMap<u64, Connection>,
+    next_id: u64,
+    last_rates: Instant,
+    /// dropped with the engine, which the task waits for
+    _done: std_mpsc::Sender<()>,
+}
+
+impl Engine {
+    async fn run(mut self) {
+        let mut stop = self.link.stop.clone();
+        let mut ticks = time::interval(POLL_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = stop.changed() => break,
+                Some(event) = self.received.recv() => self.handle(event),
+                Some(completion) = self.completed.recv() => self.complete(completion),
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, addr)) => self.accept(stream, addr),
+                    Err(error) => tracing::debug!(error = %error, "accepting a peer failed"),
+                },
+                _ = ticks.tick() => {
+                    self.sync_torrents();
+                    self.dial();
+                    self.pump();
+                    if self.last_rates.elapsed() >= Duration::from_secs(1) {
+                        self.update_rates();
+                        self.exchange_peers();
+                    }
+                }
+            }
+        }
+        let ids: Vec<_> = self.connections.keys().copied().collect();
+        for id in ids {
+            self.close(id);
+        }
+        while !self.torrents.values().all(|swarm| swarm.jobs.is_idle()) {
+            match self.completed.recv().await {
+                Some(completion) => self.complete(completion),
+                None => break,
+            }
+        }
+    }
+
+    /// follows the torrents added to and removed from the task
+    fn sync_torrents(&mut self) {
+        let added = self.added.lock().unwrap().clone();
+        let removed: Vec<_> = self
+            .torrents
+            .keys()
+            .filter(|info_hash| !added.contains_key(*info_hash))
+            .copied()
+            .collect();
+        for info_hash in removed {
+            let ids: Vec<_> = self
+                .connections
+                .iter()
+                .filter(|(_, connection)| connection.info_hash == Some(info_hash))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in ids {
+                self.close(id);
+            }
+            // the jobs already started still complete, unseen
+            self.torrents.remove(&info_hash);
+        }
+        for (info_hash, torrent) in added {
+            self.torrents.entry(info_hash).or_insert_with(|| {
+                let storage = Arc::clone(torrent.lock().storage());
+                Swarm {
+                    torrent,
+                    jobs: Jobs::new(storage),
+                }
+            });
+        }
+    }
+
+    fn accept(&mut self, stream: TcpStream, addr: SocketAddr) {
+        let handshaking = self
+            .connections
+            .values()
+            .filter(|connection| connection.info_hash.is_none())
+            .count();
+        if handshaking >= MAX_HANDSHAKES {
+            return;
+        }
+        let (id, outgoing) = self.add_connection(addr, None, PeerSource::Incoming);
+        let link = self.link.clone();
+        tokio::spawn(async move {
+            let result = run_connection(stream, id, addr, None, outgoing, &link).await;
+            closed(&link, id, result);
+        });
+    }
+
+    /// connects to the candidates of the active torrents, seeds included
+    fn dial(&mut self) {
+        let mut dials = vec![];
+        for (info_hash, swarm) in &self.torrents {
+            let mut torrent = swarm.torrent.lock();
+            if !torrent.is_active() || torrent.is_paused() {
+                continue;
+            }
+            let mut dialing = self
+                .connections
+                .values()
+                .filter(|connection| {
+                    connection.info_hash == Some(*info_hash) && connection.is_dialing()
+                })
+                .count();
+            while dialing < MAX_DIALS {
+                let (addr, source) = match torrent.poll_peer_candidate() {
+                    Some(candidate) => candidate,
+                    None => break,
+                };
+                let known = self.connections.values().any(|connection| {
+                    connection.addr == addr && connection.info_hash == Some(*info_hash)
+                });
+                if !known {
+                    dials.push((*info_hash, addr, source));
+                    dialing += 1;
+                }
+            }
+        }
+        for (info_hash, addr, source) in dials {
+            let (id, outgoing) = self.add_connection(addr, Some(info_hash), source);
+            let ours = Handshake::new(info_hash, self.peer_id);
+            let link = self.link.clone();
+            tokio::spawn(async move {
+                let result = match time::timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(addr)).await
+                {
+                    Ok(Ok(stream)) => {
+                        run_connection(stream, id, addr, Some(ours), outgoing, &link).await
+                    }
+                    Ok(Err(error)) => Err(error.into()),
+                    Err(_) => Err(anyhow!("connecting timed out")),
+                };
+                closed(&link, id, result);
+            });
+        }
+    }
+
+    fn add_connection(
+        &mut self,
+        addr: SocketAddr,
+        info_hash: Option<[u8; 20]>,
+        source: PeerSource,
+    ) -> (u64, mpsc::UnboundedReceiver<Outgoing>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (outgoing, receiver) = mpsc::unbounded_channel();
+        self.connections.insert(
+            id,
+            Connection {
+                addr,
+                info_hash,
+                source,
+                outgoing,
+                connected: false,
+                downloaded: 0,
+                uploaded: 0,
+                pex_id: None,
+                pex_sent: HashSet::new(),
+                last_pex: Instant::now(),
+            },
+        );
+        (id, receiver)
+    }
+
+    fn handle(&mut self, event: PeerEvent) {
+        match event {
+            PeerEvent::Handshake { id, handshake } => self.handshake(id, handshake),
+            PeerEvent::Message { id, message } => {
+                if let Err(error) = self.message(id, message) {
+                    self.log_close(id, &error.to_string());
+                    self.close(id);
+                }
+            }
+            PeerEvent::Closed { id, error } => {
+                if let Some(error) = error {
+                    self.log_close(id, &error);
+                }
+                self.close(id);
+            }
+        }
+    }
+
+    /// hands what the blocking threads did to the torrent
+    fn complete(&mut self, completion: Completion) {
+        match completion {
+            Completion::Disk(info_hash, completion) => {
+                if let Some(swarm) = self.torrents.get_mut(&info_hash) {
+                    swarm.jobs.disk_jobs -= 1;
+                    swarm.torrent.lock().disk_job_done(completion);
+                }
+            }
+            Completion::Verified(info_hash, verification) => {
+                if let Some(swarm) = self.torrents.get_mut(&info_hash) {
+                    swarm.jobs.verifications -= 1;
+                    swarm.torrent.lock().verification_done(verification);
+                }
+            }
+        }
+    }
+
+    fn log_close(&self, id: u64, error: &str) {
+        if let Some(connection) = self.connections.get(&id) {
+            tracing::debug!(
+                info_hash = %connection.info_hash.map(|hash| to_hex(&hash)).unwrap_or_default(),
+                peer = %connection.addr,
+                error,
+                "peer connection closed"
+            );
+        }
+    }
+
+    /// the torrent gets the peer if it still wants peers
+    fn handshake(&mut self, id: u64, handshake: Handshake) {
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let info_hash = handshake.info_hash;
+        connection.info_hash = Some(info_hash);
+        // the torrent may have been added since the last tick
+        if !self.torrents.contains_key(&info_hash) {
+            self.sync_torrents();
+        }
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let swarm = match self.torrents.get(&info_hash) {
+            Some(swarm) if handshake.peer_id != self.peer_id => swarm,
+            _ => return self.close(id),
+        };
+        let mut torrent = swarm.torrent.lock();
+        if !torrent.is_active()
+            || torrent.is_paused()
+            || !torrent.peer_connected(connection.addr, connection.source)
+        {
+            drop(torrent);
+            return self.close(id);
+        }
+        connection.connected = true;
+        if let Some(client) = peer::client_name(&handshake.peer_id) {
+            torrent.set_peer_client(connection.addr, client);
+        }
+        if connection.source == PeerSource::Incoming {
+            connection.send(Outgoing::Handshake(Handshake::new(info_hash, self.peer_id)));
+        }
+        if handshake.supports_extensions() {
+            connection.send(Outgoing::Message(Message::Extended {
+                id: 0,
+                payload: extension_handshake(!torrent.metainfo().info.private),
+            }));
+        }
+        let have = torrent.have();
+        if have.count_ones() > 0 {
+            connection.send(Outgoing::Message(Message::Bitfield(
+                have.as_bytes().to_vec(),
+            )));
+        }
+    }
+
+    fn message(&mut self, id: u64, message: Message) -> Result<()> {
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) if connection.connected => connection,
+            _ => return Ok(()),
+        };
+        let addr = connection.addr;
+        let torrents = &mut self.torrents;
+        let completions = &self.completions;
+        let (info_hash, swarm) = match connection
+            .info_hash
+            .and_then(|hash| Some((hash, torrents.get_mut(&hash)?)))
+        {
+            Some(found) => found,
+            None => bail!("the torrent was removed"),
+        };
+        let mut torrent = swarm.torrent.lock();
+        let peer = match torrent.peers().get(&addr) {
+            Some(peer) => peer,
+            None => bail!("the torrent disconnected the peer"),
+        };
+        let piece_count = peer.has.len();
+        match message {
+            Message::KeepAlive | Message::Port(_) | Message::Cancel { .. } => {}
+            Message::Choke => {
+                torrent.set_peer_choking(addr, true);
+                for request in torrent.peers()[&addr].requests.clone() {
+                    torrent.cancel_request(addr, &request);
+                }
+            }
+            Message::Unchoke => torrent.set_peer_choking(addr, false),
+            Message::Interested => torrent.set_peer_interested(addr, true),
+            Message::NotInterested => torrent.set_peer_interested(addr, false),
+            Message::Have(piece) if piece as usize >= piece_count => {
+                bail!("have of piece {} out of {}", piece, piece_count)
+            }
+            Message::Have(piece) => torrent.peer_have(addr, piece as usize),
+            Message::Bitfield(bytes) => {
+                if bytes.len() != piece_count.div_ceil(8) {
+                    bail!(
+                        "bitfield of {} bytes for {} pieces",
+                        bytes.len(),
+                        piece_count
+                    );
+                }
+                torrent.peer_bitfield(addr, Bitfield::from_bytes(bytes, piece_count));
+            }
+            Message::Request {
+                piece,
+                offset,
+                length,
+            } => {
+                if torrent.peers()[&addr].am_choking || length > BLOCK_SIZE * 8 {
+                    return Ok(());
+                }
+                let request = BlockRequest {
+                    piece: piece as usize,
+                    offset,
+                    length,
+                };
+                // requests for pieces we don't have are ignored
+                if let Ok(block) = torrent.upload_block(&request) {
+                    torrent.record_upload(block.len());
+                    connection.uploaded += block.len();
+                    connection.send(Outgoing::Block {
+                        piece,
+                        offset,
+                        block,
+                    });
+                }
+            }
+            Message::Piece {
+                piece,
+                offset,
+                data,
+            } => {
+                let request = BlockRequest {
+                    piece: piece as usize,
+                    offset,
+                    length: data.len() as u32,
+                };
+                connection.downloaded += data.len() as u64;
+                if !torrent.peers()[&addr].requests.contains(&request) {
+                    return Ok(());
+                }
+                if let Some(completed) = torrent.block_received(addr, &request, &data)? {
+                    swarm.jobs.verifications += 1;
+                    let job = torrent.verify_job(&completed);
+                    let completions = completions.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let verification = Verification {
+                            piece: job.piece,
+                            valid: verifier::is_valid(&job),
+                        };
+                        let _ = completions.send(Completion::Verified(info_hash, verification));
+                    });
+                }
+            }
+            Message::Extended { id: 0, payload } => {
+                let handshake = Parser::new(payload).parse()?;
+                if let Some(client) = handshake.get("v").and_then(Bencode::as_str) {
+                    torrent.set_peer_client(addr, client.to_string());
+                }
+                if let Some(reqq) = handshake.get("reqq").and_then(Bencode::as_integer) {
+                    torrent.set_peer_request_queue(addr, reqq.clamp(1, REQQ * 10) as usize);
+                }
+                connection.pex_id = handshake
+                    .get("m")
+                    .and_then(|extensions| extensions.get("ut_pex"))
+                    .and_then(Bencode::as_integer)
+                    .filter(|&id| id > 0 && id <= u8::MAX as i64)
+                    .map(|id| id as u8);
+            }
+            // private torrents only get their peers from the trackers
+            Message::Extended { id: UT_PEX_ID, .. } if torrent.metainfo().info.private => {}
+            Message::Extended {
+                id: UT_PEX_ID,
+                payload,
+            } => {
+                let message = PexMessage::parse(payload)?;
+                torrent.add_peers(message.added, PeerSource::Pex);
+            }
+            Message::Extended { .. } => {}
+            Message::HashRequest(request) => torrent.hash_requested(addr, request),
+            Message::Hashes { request, hashes } => {
+                torrent.hashes_received(addr, request, &hashes)?
+            }
+            Message::HashReject(request) => torrent.hashes_rejected(addr, request),
+        }
+        Ok(())
+    }
+
+    /// sends what the torrents queued for their peers, requests blocks from
+    /// the peers unchoking us and dispatches the disk jobs
+    fn pump(&mut self) {
+        let mut forgotten = vec![];
+        for (info_hash, swarm) in &mut self.torrents {
+            let mut torrent = swarm.torrent.lock();
+            for (id, connection) in &self.connections {
+                if connection.info_hash != Some(*info_hash) || !connection.connected {
+                    continue;
+                }
+                let addr = connection.addr;
+                let peer = match torrent.peers().get(&addr) {
+                    Some(peer) => peer,
+                    None => {
+                        forgotten.push(*id);
+                        continue;
+                    }
+                };
+                let requesting = !peer.peer_choking;
+                while let Some(message) = torrent.poll_peer_message(addr) {
+                    connection.send(Outgoing::Message(message));
+                }
+                if requesting && torrent.peers()[&addr].am_interested {
+                    while let Some(request) = torrent.request_block(addr) {
+                        connection.send(Outgoing::Message(Message::Request {
+                            piece: request.piece as u32,
+                            offset: request.offset,
+                            length: request.length,
+                        }));
+                    }
+                    while let Some(message) = torrent.poll_peer_message(addr) {
+                        connection.send(Outgoing::Message(message));
+                    }
+                }
+            }
+            swarm
+                .jobs
+                .dispatch(*info_hash, &mut torrent, &self.completions);
+        }
+        for id in forgotten {
+            self.close(id);
+        }
+    }
+
+    /// tells the peers supporting ut_pex which peers of the swarm connected
+    /// and disconnected since the last message. Only the peers we dialed are
+    /// shared, the port of the others is not the one they listen on.
+    fn exchange_peers(&mut self) {
+        let mut swarms: HashMap<[u8; 20], HashSet<SocketAddr>> = HashMap::new();
+        for connection in self.connections.values() {
+            if let Some(info_hash) = connection.info_hash {
+                if connection.connected && connection.source != PeerSource::Incoming {
+                    swarms.entry(info_hash).or_default().insert(connection.addr);
+                }
+            }
+        }
+        let torrents = &self.torrents;
+        for connection in self.connections.values_mut() {
+            let (info_hash, pex_id) = match (connection.info_hash, connection.pex_id) {
+                (Some(info_hash), Some(pex_id))
+                    if connection.connected && connection.last_pex.elapsed() >= pex::INTERVAL =>
+                {
+                    (info_hash, pex_id)
+                }
+                _ => continue,
+            };
+            connection.last_pex = Instant::now();
+            match torrents.get(&info_hash) {
+                Some(swarm) if !swarm.torrent.lock().metainfo().info.private => {}
+                _ => continue,
+            }
+            let mut swarm = swarms.get(&info_hash).cloned().unwrap_or_default();
+            swarm.remove(&connection.addr);
+            let message = PexMessage {
+                added: swarm
+                    .difference(&connection.pex_sent)
+                    .take(pex::MAX_PEERS)
+                    .copied()
+                    .collect(),
+                dropped: connection
+                    .pex_sent
+                    .difference(&swarm)
+                    .take(pex::MAX_PEERS)
+                    .copied()
+                    .collect(),
+            };
+            if message.is_empty() {
+                continue;
+            }
+            connection.pex_sent.extend(&message.added);
+            for addr in &message.dropped {
+                connection.pex_sent.remove(addr);
+            }
+            connection.send(Outgoing::Message(Message::Extended {
+                id: pex_id,
+                payload: message.encode(),
+            }));
+        }
+    }
+
+    /// the bytes of the last second of each connection
+    fn update_rates(&mut self) {
+        let seconds = self.last_rates.elapsed().as_secs_f64();
+        self.last_rates = Instant::now();
+        let torrents = &self.torrents;
+        for connection in self.connections.values_mut() {
+            let swarm = match connection.info_hash.and_then(|hash| torrents.get(&hash)) {
+                Some(swarm) if connection.connected => swarm,
+                _ => continue,
+            };
+            let mut torrent = swarm.torrent.lock();
+            let rate = |bytes: u64| (bytes as f64 / seconds) as u64;
+            torrent.set_peer_download_rate(connection.addr, rate(connection.downloaded));
+            torrent.set_peer_upload_rate(connection.addr, rate(connection.uploaded));
+            connection.downloaded = 0;
+            connection.uploaded = 0;
+        }
+    }
+
+    /// the torrent forgets the peer
+    fn close(&mut self, id: u64) {
+        let connection = match self.connections.remove(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.send(Outgoing::Close);
+        let info_hash = match connection.info_hash {
+            Some(info_hash) if connection.connected => info_hash,
+            _ => return,
+        };
+        if let Some(swarm) = self.torrents.get(&info_hash) {
+            swarm.torrent.lock().peer_disconnected(connection.addr);
+        }
+        self.link
+            .bandwidth
+            .lock()
+            .unwrap()
+            .remove_peer(&info_hash, connection.addr);
+    }
+}
+
+/// payload of our extension handshake, advertises ut_pex unless the
+/// torrent is private
+fn extension_handshake(pex: bool) -> Vec<u8> {
+    let mut extensions = HashMap::new();
+    if pex {
+        extensions.insert("ut_pex".into(), (UT_PEX_ID as i64).into());
+    }
+    let mut dict = HashMap::new();
+    dict.insert("m".into(), Bencode::Dictionary(extensions));
+    dict.insert(
+        "v".into(),
+        concat!("torrent_rs ", env!("CARGO_PKG_VERSION")).into(),
+    );
+    dict.insert("reqq".into(), REQQ.into());
+    Bencode::Dictionary(dict).encode()
+}
+
+fn closed(link: &Link, id: u64, result: Result<()>) {
+    let error = result.err().map(|error| format!("{:#}", error));
+    let _ = link.events.send(PeerEvent::Closed { id, error });
+}
+
+/// exchanges the handshakes, `ours` is sent first to the peers we dial,
+/// then reads the messages of the peer and writes those of the engine until
+/// either closes the connection
+async fn run_connection(
+    mut stream: TcpStream,
+    id: u64,
+    addr: SocketAddr,
+    ours: Option<Handshake>,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    link: &Link,
+) -> Result<()> {
+    let exchange = async {
+        if let Some(ours) = &ours {
+            stream.write_all(&ours.encode()).await?;
+        }
+        let mut data = [0; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut data).await?;
+        Handshake::decode(&data)
+    };
+    let theirs = match time::timeout(HANDSHAKE_TIMEOUT, exchange).await {
+        Ok(handshake) => handshake?,
+        Err(_) => bail!("the handshake timed out"),
+    };
+    if ours.is_some_and(|ours| ours.info_hash != theirs.info_hash) {
+        bail!("the peer has another torrent");
+    }
+    let info_hash = theirs.info_hash;
+    let handshake = PeerEvent::Handshake {
+        id,
+        handshake: theirs,
+    };
+    if link.events.send(handshake).is_err() {
+        return Ok(());
+    }
+    let mut data = vec![];
+    let mut buffer = vec![0; BLOCK_SIZE as usize];
+    let mut last_read = time::Instant::now();
+    let mut keep_alive = time::interval_at(last_read + IDLE_TIMEOUT / 2, IDLE_TIMEOUT / 2);
+    loop {
+        tokio::select! {
+            read = read_some(&mut stream, &mut buffer, info_hash, addr, link) => {
+                let read = match read? {
+                    0 => return Ok(()),
+                    read => read,
+                };
+                last_read = time::Instant::now();
+                data.extend_from_slice(&buffer[..read]);
+                let mut start = 0;
+                while let Some((message, length)) = Message::decode(&data[start..])? {
+                    start += length;
+                    if link.events.send(PeerEvent::Message { id, message }).is_err() {
+                        return Ok(());
+                    }
+                }
+                data.drain(..start);
+                if let Some(length) = data.get(..4) {
+                    let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]);
+                    if length as usize > MAX_MESSAGE {
+                        bail!("message of {} bytes", length);
+                    }
+                }
+            }
+            next = outgoing.recv() => match next {
+                Some(Outgoing::Handshake(handshake)) => stream.write_all(&handshake.encode()).await?,
+                Some(Outgoing::Message(message)) => stream.write_all(&message.encode()).await?,
+                Some(Outgoing::Block { piece, offset, block }) => {
+                    let mut granted = 0;
+                    while granted < block.len() && !link.is_stopped() {
+                        granted += link
+                            .quota(&info_hash, addr, Direction::Upload, block.len() - granted)
+                            .await;
+                    }
+                    block.send_async(&mut stream, piece, offset).await?;
+                }
+                Some(Outgoing::Close) | None => return Ok(()),
+            },
+            _ = keep_alive.tick() => {
+                if last_read.elapsed() >= IDLE_TIMEOUT {
+                    bail!("the peer sent nothing for {:?}", IDLE_TIMEOUT);
+                }
+                stream.write_all(&Message::KeepAlive.encode()).await?;
+            }
+        }
+    }
+}
+
+/// reads what the download limits allow. Only the bytes read are taken
+/// from the quota, a read that gets less or is cancelled costs nothing.
+async fn read_some(
+    stream: &mut TcpStream,
+    buffer: &mut [u8],
+    info_hash: [u8; 20],
+    addr: SocketAddr,
+    link: &Link,
+) -> io::Result<usize> {
+    let available = link
+        .available(&info_hash, addr, Direction::Download, buffer.len() as u64)
+        .await;
+    let read = stream.read(&mut buffer[..available as usize]).await?;
+    link.bandwidth
+        .lock()
+        .unwrap()
+        .consume(&info_hash, addr, Direction::Download, read as u64);
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertises_its_request_queue() -> Result<()> {
+        let handshake = Parser::new(extension_handshake(true)).parse()?;
+        assert_eq!(
+            handshake.get("reqq").and_then(Bencode::as_integer),
+            Some(REQQ)
+        );
+        let ut_pex = |handshake: &Bencode| {
+            handshake
+                .get("m")
+                .and_then(|extensions| extensions.get("ut_pex"))
+                .and_then(Bencode::as_integer)
+        };
+        assert_eq!(ut_pex(&handshake), Some(UT_PEX_ID as i64));
+        let private = Parser::new(extension_handshake(false)).parse()?;
+        assert!(private.get("m").and_then(Bencode::as_dict).is_some());
+        assert_eq!(ut_pex(&private), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,4338 @@
+use crate::availability::Availability;
+use crate::bitfield::Bitfield;
+use crate::cache::ReadCache;
+use crate::connections::Connections;
+use crate::dht::routing::random_bytes;
+use crate::disk::{DiskCompletion, DiskJob};
+use crate::events::{Alert, EventBus, SessionEvent};
+use crate::in_flight::InFlight;
+use crate::ip_filter::IpFilter;
+use crate::listen::{self, IpFamilies};
+use crate::merkle::{self, Hash, LEAF_SIZE};
+use crate::message::{HashRequest, Message, MAX_HASHES};
+use crate::metainfo::{to_hex, Metainfo, PieceHash};
+use crate::peer::{PeerInfo, PeerSource, PeerState};
+use crate::picker::{self, PickContext, PiecePicker, Priority, RarestFirst};
+use crate::queue::Queue;
+use crate::rate::RateMeter;
+use crate::resume::{FileStat, PartialPiece, ResumeData, TrackerStats};
+use crate::scheduler::{BlockRequest, BlockScheduler, CompletedPiece, BLOCK_SIZE};
+use crate::seeding::{SeedLimits, Seeding};
+use crate::storage::{FileStorage, IncompleteStorage, Storage, StorageConfig};
+use crate::tracker::AnnounceResponse;
+use crate::upload::UploadBlock;
+use crate::verifier::{self, Verification, Verifier, VerifyJob};
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// default byte budget of the read cache
+const READ_CACHE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// how many verified pieces are remembered for `PickContext::recently_completed`
+const RECENTLY_COMPLETED: usize = 16;
+
+/// peers waiting for a connection, the oldest are dropped past this many
+const MAX_PEER_CANDIDATES: usize = 1000;
+
+/// alerts not taken yet, the oldest are dropped past this many
+const MAX_ALERTS: usize = 100;
+
+/// peers that sent this much corrupt data are banned from the torrent
+const MAX_HASH_FAILURES: u32 = 5;
+
+/// interested peers we upload to at once, the best ones and one unchoked
+/// optimistically
+const UPLOAD_SLOTS: usize = 4;
+
+/// how often the peers to upload to are chosen again
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// how long a peer stays unchoked optimistically
+const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// every selected file is downloaded, fires again if more files get selected and completed
+    Finished,
+    PieceVerified(usize),
+    HashFailed(usize),
+    /// progress of a recheck of the data on disk
+    Checking {
+        checked: usize,
+        total: usize,
+    },
+    /// the torrent got a queue slot and can download or seed
+    Activated,
+    /// the torrent lost its queue slot, its peers should be disconnected
+    Queued,
+    /// reading or writing the data failed, the torrent stops downloading
+    /// until the error is cleared
+    DiskError(String),
+    /// a downloaded piece doesn't match its hash anymore, it is downloaded again
+    Corrupted(usize),
+    StateChanged(TorrentState),
+    /// a completed file was moved out of the incomplete storage
+    FileMoved {
+        file: usize,
+        path: PathBuf,
+    },
+    /// a file downloaded since the torrent was added is written and at its
+    /// final path, `path`
+    FileCompleted {
+        file: usize,
+        path: PathBuf,
+    },
+    /// progress of `move_storage`
+    MovingStorage {
+        moved: usize,
+        total: usize,
+    },
+    /// every file is now under the new save path
+    StorageMoved(PathBuf),
+    /// the files were moved back to the old save path, or as many as possible
+    StorageMoveFailed(String),
+}
+
+/// Where a torrent is in its life, each change is reported with
+/// `Event::StateChanged`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TorrentState {
+    /// a magnet link whose metadata is being fetched, before the torrent
+    /// exists, see `Session::state`
+    FetchingMetadata,
+    /// creating the files at their full size, see `Torrent::allocate_files`
+    Allocating,
+    /// hashing the data on disk, see `Torrent::force_recheck`
+    CheckingFiles,
+    Downloading,
+    /// every selected file is downloaded
+    Seeding,
+    Paused,
+    /// stopped by a disk error until it is cleared
+    Errored,
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TorrentState::FetchingMetadata => "fetching metadata",
+            TorrentState::Allocating => "allocating",
+            TorrentState::CheckingFiles => "checking files",
+            TorrentState::Downloading => "downloading",
+            TorrentState::Seeding => "seeding",
+            TorrentState::Paused => "paused",
+            TorrentState::Errored => "errored",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Events waiting for `Torrent::poll_event`, also sent to the subscribers
+/// of the session the torrent belongs to
+#[derive(Debug, Default)]
+struct TorrentEvents {
+    queue: VecDeque<Event>,
+    bus: Option<(EventBus, [u8; 20])>,
+}
+
+impl TorrentEvents {
+    fn push_back(&mut self, event: Event) {
+        if let Some((bus, info_hash)) = &self.bus {
+            bus.send(SessionEvent::from_torrent(*info_hash, event.clone()));
+        }
+        self.queue.push_back(event);
+    }
+
+    fn pop_front(&mut self) -> Option<Event> {
+        self.queue.pop_front()
+    }
+
+    fn send(&self, event: impl FnOnce([u8; 20]) -> SessionEvent) {
+        if let Some((bus, info_hash)) = &self.bus {
+            bus.send(event(*info_hash));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStats {
+    pub progress: f64,
+    pub pieces: usize,
+    pub piece_count: usize,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub wasted: u64,
+    pub peers: usize,
+    /// connected peers by how they were discovered
+    pub peers_by_source: BTreeMap<PeerSource, usize>,
+    /// full copies of the torrent available among connected peers
+    pub distributed_copies: f64,
+    /// upload requests served from the read cache and from the disk
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub disk: DiskStats,
+}
+
+/// Activity of the disk for one torrent, to tell whether the disk or the
+/// network is the bottleneck
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskStats {
+    /// jobs waiting to be submitted plus the ones the disk threads are running
+    pub queue_depth: usize,
+    /// bytes received but not written yet
+    pub pending_bytes: u64,
+    /// smoothed bytes per second
+    pub read_rate: f64,
+    pub write_rate: f64,
+    /// share of upload requests served from the read cache, between 0 and 1
+    pub cache_hit_rate: f64,
+    pub read_errors: u64,
+    pub write_errors: u64,
+}
+
+/// Bounds on outstanding requests so a large swarm can't make us buffer
+/// unbounded amounts of data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// blocks in flight per peer, lowered further by the peer's advertised `reqq`
+    pub max_requests_per_peer: usize,
+    /// memory used by pieces being downloaded, also caps the blocks in flight
+    /// across all peers
+    pub max_buffer_memory: u64,
+    /// bytes waiting to be written, requesting stops until the disk catches up
+    pub max_disk_queue: u64,
+    /// blocks of unfinished pieces are kept in memory until this many bytes
+    /// are waiting, then written in piece and offset order
+    pub max_unflushed: u64,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_requests_per_peer: 64,
+            max_buffer_memory: 64 * 1024 * 1024,
+            max_disk_queue: 16 * 1024 * 1024,
+            max_unflushed: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// When written data is synced to the disk. Resume data only marks the blocks
+/// and pieces that are known to be durable, so a power loss can't leave a
+/// torrent that claims pieces the disk never got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// the files are synced once every block of a verified piece is written
+    #[default]
+    PieceCompletion,
+    /// the files are synced on `tick` when this long passed since the last sync
+    Periodic(Duration),
+    /// the OS writes the data back whenever it wants and the resume data
+    /// trusts every completed write, faster but a power loss can corrupt
+    /// pieces marked as downloaded
+    Never,
+}
+
+/// How the data on disk of a piece compares to its hash, see
+/// `Torrent::check_pieces`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceCheck {
+    Complete,
+    /// its data can't be read, a file is missing or too short, or its hash
+    /// isn't known yet
+    Missing,
+    /// read but doesn't match its hash
+    Corrupt,
+}
+
+/// Progress of the torrent over the selected files
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub bytes_done: u64,
+    pub bytes_wanted: u64,
+    pub total_bytes: u64,
+    /// between 0 and 100
+    pub percent: f64,
+    pub pieces: usize,
+    pub piece_count: usize,
+    /// smoothed bytes per second
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// None while the download is stalled
+    pub eta: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileProgress {
+    pub path: PathBuf,
+    pub bytes_done: u64,
+    pub length: u64,
+    pub priority: Priority,
+    /// of a hybrid torrent, aligning the next file to a piece
+    pub padding: bool,
+}
+
+pub struct Torrent {
+    metainfo: Metainfo,
+    save_path: PathBuf,
+    /// directory of multi-file torrents, the name from the metainfo unless renamed
+    root_name: String,
+    /// path of every file relative to the root, see `rename_file`
+    file_names: Vec<PathBuf>,
+    storage: Arc<dyn Storage>,
+    incomplete: IncompleteStorage,
+    /// completed files waiting for their blocks to be written before moving
+    pending_moves: BTreeSet<usize>,
+    /// files completed by a downloaded piece, reported once their blocks
+    /// are written and they are moved
+    unreported_files: BTreeSet<usize>,
+    /// `move_storage` is in progress, completed files wait for it
+    moving_storage: bool,
+    have: Bitfield,
+    file_priorities: Vec<Priority>,
+    piece_priorities: Vec<Priority>,
+    /// download the first and last piece of each selected file first
+    prioritize_first_last: bool,
+    picker: Box<dyn PiecePicker>,
+    availability: Availability,
+    peers: HashMap<SocketAddr, PeerState>,
+    /// peers discovered but not connected yet, see `add_peers`
+    peer_candidates: VecDeque<(SocketAddr, PeerSource)>,
+    /// last pieces verified, for pickers that care about disk locality
+    recently_completed: Vec<usize>,
+    /// time critical pieces, requested before anything else
+    deadlines: HashMap<usize, Instant>,
+    /// peers that sent blocks of each piece in progress, blamed if it fails the hash check
+    contributors: HashMap<usize, HashSet<SocketAddr>>,
+    /// peers that failed the hash checks too often, never connected again
+    banned: HashSet<SocketAddr>,
+    request_limits: RequestLimits,
+    /// don't send have messages to peers that already have the piece
+    suppress_redundant_have: bool,
+    /// verified bytes of each file, updated as pieces complete
+    file_bytes_done: Vec<u64>,
+    download_rate: RateMeter,
+    upload_rate: RateMeter,
+    scheduler: BlockScheduler,
+    /// writes waiting to be handed to the disk threads, see `poll_disk_job`
+    disk_jobs: VecDeque<DiskJob>,
+    /// bytes handed off for writing that didn't complete yet
+    disk_queue: u64,
+    /// jobs handed to the disk threads that didn't complete yet
+    disk_jobs_running: usize,
+    disk_read_rate: RateMeter,
+    disk_write_rate: RateMeter,
+    disk_read_errors: u64,
+    disk_write_errors: u64,
+    /// offsets of the blocks of each piece waiting to be written, they aren't
+    /// on disk yet so they can't go in the resume data
+    unwritten: HashMap<usize, HashSet<u32>>,
+    /// offsets of the received blocks not turned into disk jobs yet, they are
+    /// merged into contiguous writes when flushed
+    unflushed: BTreeMap<usize, BTreeSet<u32>>,
+    unflushed_bytes: u64,
+    durability: Durability,
+    /// pieces with writes that weren't synced yet, with the sequence number
+    /// of their last write, see `Durability`
+    unsynced: HashMap<usize, u64>,
+    /// incremented on every completed write
+    write_sequence: u64,
+    last_sync: Instant,
+    error: Option<String>,
+    /// pieces recently read to serve upload requests
+    read_cache: ReadCache,
+    /// hash pieces read from disk before uploading them
+    verify_uploads: bool,
+    /// bytes of downloaded pieces rehashed on every tick, 0 disables scrubbing
+    scrub_rate: u64,
+    /// next piece to scrub
+    scrub_cursor: usize,
+    /// verified leaf hashes of v2 pieces being downloaded, used to check every
+    /// block as it arrives
+    block_hashes: HashMap<usize, Vec<Hash>>,
+    hash_requests: HashMap<HashRequest, SocketAddr>,
+    /// when `rechoke` last ran
+    last_rechoke: Option<Instant>,
+    /// the peer unchoked optimistically, and since when
+    optimistic: Option<(SocketAddr, Instant)>,
+    /// pieces fully received and waiting on the verifier
+    verifying: Bitfield,
+    finished: bool,
+    /// inactive torrents wait in the queue and don't request anything
+    active: bool,
+    /// paused torrents stay inactive whatever their place in the queue
+    paused: bool,
+    state: TorrentState,
+    events: TorrentEvents,
+    alerts: VecDeque<Alert>,
+    /// shared by the torrents of a session
+    ip_filter: Option<Arc<RwLock<IpFilter>>>,
+    ip_families: IpFamilies,
+    /// None is unlimited
+    max_peers: Option<usize>,
+    /// shared by the torrents of a session
+    connections: Option<Arc<Connections>>,
+    /// the blocks requested by every torrent of a session
+    in_flight: Option<Arc<InFlight>>,
+    trackers: Vec<TrackerStats>,
+    uploaded: u64,
+    downloaded: u64,
+    wasted: u64,
+    /// spent seeding while active, across sessions
+    seed_time: Duration,
+    /// spent seeding since the last upload
+    idle_time: Duration,
+    /// of the last `tick`, with the bytes uploaded then
+    last_tick: Option<(Instant, u64)>,
+    /// None follows those of the session
+    seed_limits: Option<SeedLimits>,
+    /// see `Session::set_torrent_category`
+    category: Option<String>,
+    tags: BTreeSet<String>,
+}
+
+/// the connections and requests of a removed torrent free their slots in
+/// the session
+impl Drop for Torrent {
+    fn drop(&mut self) {
+        if let Some(connections) = &self.connections {
+            self.peers.keys().for_each(|_| connections.close());
+        }
+        self.release_requests(self.requests_in_flight());
+    }
+}
+
+impl Torrent {
+    pub fn new(metainfo: Metainfo, save_path: impl Into<PathBuf>) -> Self {
+        let piece_count = metainfo.info.piece_count();
+        let file_count = metainfo.info.files.len();
+        let save_path = save_path.into();
+        let root_name = metainfo.info.name.clone();
+        let file_names: Vec<_> = metainfo
+            .info
+            .files
+            .iter()
+            .map(|file| file.path.clone())
+            .collect();
+        let files = file_paths(&metainfo, &root_name, &file_names, &save_path)
+            .into_iter()
+            .zip(metainfo.info.files.iter().map(|file| file.length))
+            .collect();
+        let padding: Vec<_> = metainfo
+            .info
+            .files
+            .iter()
+            .map(|file| file.padding)
+            .collect();
+        let storage =
+            Arc::new(FileStorage::new(files, metainfo.info.piece_length).with_padding(&padding));
+        Self {
+            metainfo,
+            save_path,
+            root_name,
+            file_names,
+            storage,
+            incomplete: IncompleteStorage::default(),
+            pending_moves: BTreeSet::new(),
+            unreported_files: BTreeSet::new(),
+            moving_storage: false,
+            have: Bitfield::new(piece_count),
+            file_priorities: vec![Priority::Normal; file_count],
+            piece_priorities: vec![Priority::Normal; piece_count],
+            prioritize_first_last: false,
+            picker: Box::new(RarestFirst),
+            availability: Availability::new(piece_count),
+            peers: HashMap::new(),
+            peer_candidates: VecDeque::new(),
+            recently_completed: vec![],
+            deadlines: HashMap::new(),
+            contributors: HashMap::new(),
+            banned: HashSet::new(),
+            request_limits: RequestLimits::default(),
+            suppress_redundant_have: false,
+            file_bytes_done: vec![0; file_count],
+            download_rate: RateMeter::new(),
+            upload_rate: RateMeter::new(),
+            scheduler: BlockScheduler::new(),
+            disk_jobs: VecDeque::new(),
+            disk_queue: 0,
+            disk_jobs_running: 0,
+            disk_read_rate: RateMeter::new(),
+            disk_write_rate: RateMeter::new(),
+            disk_read_errors: 0,
+            disk_write_errors: 0,
+            unwritten: HashMap::new(),
+            unflushed: BTreeMap::new(),
+            unflushed_bytes: 0,
+            durability: Durability::default(),
+            unsynced: HashMap::new(),
+            write_sequence: 0,
+            last_sync: Instant::now(),
+            error: None,
+            read_cache: ReadCache::new(READ_CACHE_SIZE),
+            verify_uploads: false,
+            scrub_rate: 0,
+            scrub_cursor: 0,
+            block_hashes: HashMap::new(),
+            hash_requests: HashMap::new(),
+            last_rechoke: None,
+            optimistic: None,
+            verifying: Bitfield::new(piece_count),
+            finished: false,
+            active: true,
+            paused: false,
+            state: TorrentState::Downloading,
+            events: TorrentEvents::default(),
+            alerts: VecDeque::new(),
+            ip_filter: None,
+            ip_families: IpFamilies::default(),
+            max_peers: None,
+            connections: None,
+            in_flight: None,
+            trackers: vec![],
+            uploaded: 0,
+            downloaded: 0,
+            wasted: 0,
+            seed_time: Duration::ZERO,
+            idle_time: Duration::ZERO,
+            last_tick: None,
+            seed_limits: None,
+            category: None,
+            tags: BTreeSet::new(),
+        }
+    }
+
+    pub fn metainfo(&self) -> &Metainfo {
+        &self.metainfo
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn save_path(&self) -> &PathBuf {
+        &self.save_path
+    }
+
+    /// where the files currently are, see `set_incomplete_storage`
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        self.storage.paths()
+    }
+
+    /// paths of the files once complete
+    pub fn final_paths(&self) -> Vec<PathBuf> {
+        self.paths_under(&self.save_path)
+    }
+
+    /// deletes the files of the torrent wherever they currently are and the
+    /// folders they leave empty, nothing else in the save path is touched
+    pub fn delete_files(&mut self) -> Result<()> {
+        let paths = self.storage.paths();
+        for path in &paths {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => bail!("deleting {}: {}", path.display(), error),
+            }
+        }
+        remove_empty_dirs(&paths, &self.save_path);
+        Ok(())
+    }
+
+    fn paths_under(&self, save_path: &Path) -> Vec<PathBuf> {
+        file_paths(&self.metainfo, &self.root_name, &self.file_names, save_path)
+    }
+
+    /// renames a file, `path` is relative to the root folder of the torrent.
+    /// The file is moved right away if it exists and the new name is kept in
+    /// the resume data.
+    pub fn rename_file(&mut self, file: usize, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        if !is_relative_name(&path) {
+            bail!("invalid file name {}", path.display());
+        }
+        let old_final = self.final_paths();
+        match self.file_names.get_mut(file) {
+            Some(name) => {
+                let old = std::mem::replace(name, path);
+                self.relocate_files(&old_final)
+                    .inspect_err(|_| self.file_names[file] = old)
+            }
+            None => bail!("file index {} out of range", file),
+        }
+    }
+
+    /// renames the folder holding the files of a multi-file torrent
+    pub fn rename_root(&mut self, name: impl Into<String>) -> Result<()> {
+        let name = name.into();
+        if !self.metainfo.info.multi_file {
+            bail!("single file torrents have no root folder, rename the file instead");
+        }
+        if !is_folder_name(&name) {
+            bail!("invalid folder name {}", name);
+        }
+        let old_final = self.final_paths();
+        let old = std::mem::replace(&mut self.root_name, name);
+        self.relocate_files(&old_final)
+            .inspect_err(|_| self.root_name = old)
+    }
+
+    /// the folder of multi-file torrents, the metainfo name unless renamed
+    pub fn root_name(&self) -> &str {
+        &self.root_name
+    }
+
+    /// moves the files to the paths given by their current names, `old_final`
+    /// are the final paths under the previous names
+    fn relocate_files(&mut self, old_final: &[PathBuf]) -> Result<()> {
+        let old_paths = self.storage.paths();
+        let targets = self.storage_targets(old_final, &self.save_path);
+        move_files(self.storage.as_ref(), &old_paths, &targets, |_, _| {})?;
+        remove_empty_dirs(&old_paths, &self.save_path);
+        Ok(())
+    }
+
+    /// incomplete files are moved to the new location, files already at
+    /// their final path stay there, ie. ones completed before a restart
+    pub fn set_incomplete_storage(&mut self, incomplete: IncompleteStorage) -> Result<()> {
+        self.incomplete = incomplete;
+        for (file, final_path) in self.final_paths().into_iter().enumerate() {
+            let relative = final_path
+                .strip_prefix(&self.save_path)
+                .unwrap_or(&final_path);
+            let incomplete_path = self.incomplete.path(&final_path, relative);
+            let done =
+                self.is_file_complete(file) || (final_path.exists() && !incomplete_path.exists());
+            let path = if done { final_path } else { incomplete_path };
+            self.storage.rename_file(file, &path)?;
+        }
+        Ok(())
+    }
+
+    fn is_file_complete(&self, file: usize) -> bool {
+        let info = &self.metainfo.info.files[file];
+        info.padding || self.file_bytes_done[file] == info.length
+    }
+
+    /// moves the files under `save_path`, files in an incomplete directory
+    /// stay there. `TorrentHandle::move_storage` keeps the torrent running
+    /// during the move.
+    pub fn move_storage(&mut self, save_path: impl Into<PathBuf>) -> Result<()> {
+        let save_path = save_path.into();
+        let old_paths = self.storage.paths();
+        let targets = self.storage_targets(&self.final_paths(), &save_path);
+        let storage = Arc::clone(&self.storage);
+        let result = move_files(storage.as_ref(), &old_paths, &targets, |moved, total| {
+            self.events.push_back(Event::MovingStorage { moved, total })
+        });
+        self.storage_moved(save_path, &old_paths, result)
+    }
+
+    /// where every file goes when moving the storage to `save_path`, files
+    /// at their final path `old_final` go to their new final path
+    fn storage_targets(&self, old_final: &[PathBuf], save_path: &Path) -> Vec<PathBuf> {
+        let current = self.storage.paths();
+        self.paths_under(save_path)
+            .into_iter()
+            .enumerate()
+            .map(|(file, final_path)| {
+                if current[file] == old_final[file] {
+                    return final_path;
+                }
+                let relative = final_path.strip_prefix(save_path).unwrap_or(&final_path);
+                self.incomplete.path(&final_path, relative)
+            })
+            .collect()
+    }
+
+    fn storage_moved(
+        &mut self,
+        save_path: PathBuf,
+        old_paths: &[PathBuf],
+        result: Result<()>,
+    ) -> Result<()> {
+        self.moving_storage = false;
+        match &result {
+            Ok(()) => {
+                remove_empty_dirs(old_paths, &self.save_path);
+                self.save_path = save_path.clone();
+                self.events.push_back(Event::StorageMoved(save_path));
+            }
+            Err(error) => self
+                .events
+                .push_back(Event::StorageMoveFailed(error.to_string())),
+        }
+        self.move_completed_files();
+        result
+    }
+
+    /// queues the move of a file that just completed to its final path
+    fn file_completed(&mut self, file: usize) {
+        if self.storage.path(file) != self.final_paths()[file] {
+            self.pending_moves.insert(file);
+        }
+    }
+
+    /// moves the completed files whose data is all on disk, then reports
+    /// those downloaded
+    fn move_completed_files(&mut self) {
+        if self.moving_storage
+            || (self.pending_moves.is_empty() && self.unreported_files.is_empty())
+        {
+            return;
+        }
+        let ranges = self.metainfo.info.file_piece_ranges();
+        let final_paths = self.final_paths();
+        for file in std::mem::take(&mut self.pending_moves) {
+            if ranges[file]
+                .clone()
+                .any(|piece| self.unwritten.contains_key(&piece))
+            {
+                self.pending_moves.insert(file);
+                continue;
+            }
+            let path = final_paths[file].clone();
+            match self.storage.rename_file(file, &path) {
+                Ok(()) => self.events.push_back(Event::FileMoved { file, path }),
+                Err(error) => {
+                    self.disk_error(format!("moving {}: {}", path.display(), error));
+                }
+            }
+        }
+        let paths = self.storage.paths();
+        for file in std::mem::take(&mut self.unreported_files) {
+            if self.pending_moves.contains(&file)
+                || ranges[file]
+                    .clone()
+                    .any(|piece| self.unwritten.contains_key(&piece))
+            {
+                self.unreported_files.insert(file);
+                continue;
+            }
+            let path = paths[file].clone();
+            self.events.push_back(Event::FileCompleted { file, path });
+        }
+    }
+
+    /// verified and written, so `read_block` finds it on the disk
+    pub fn is_piece_readable(&self, piece: usize) -> bool {
+        self.have.get(piece) && !self.unwritten.contains_key(&piece)
+    }
+
+    /// blocks are waiting for the disk or completed files to be moved
+    pub fn has_unwritten_data(&self) -> bool {
+        !self.unwritten.is_empty() || !self.pending_moves.is_empty()
+    }
+
+    /// shared with the disk threads
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// files on disk by default, replace it before handing it to the disk
+    /// threads
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.storage = storage;
+    }
+
+    /// sparse files read and written with syscalls by default, the files stay
+    /// where they currently are
+    pub fn set_storage_config(&mut self, config: StorageConfig) {
+        let files = self
+            .storage
+            .paths()
+            .into_iter()
+            .zip(self.metainfo.info.files.iter().map(|file| file.length))
+            .collect();
+        let padding: Vec<_> = self
+            .metainfo
+            .info
+            .files
+            .iter()
+            .map(|file| file.padding)
+            .collect();
+        let mut storage =
+            FileStorage::new(files, self.metainfo.info.piece_length).with_padding(&padding);
+        storage.set_config(config);
+        self.storage = Arc::new(storage);
+    }
+
+    /// restores the pieces we have when adding the torrent, from the resume data
+    /// if it is still valid, otherwise by hashing the files already on disk
+    pub fn restore(&mut self, resume_dir: Option<&Path>) -> Result<()> {
+        if let Some(dir) = resume_dir {
+            if self.load_resume_data(dir)? {
+                return Ok(());
+            }
+        }
+        if self.file_paths().iter().any(|path| path.exists()) {
+            self.force_recheck()?;
+        }
+        self.check_free_space();
+        Ok(())
+    }
+
+    /// stops the torrent with a disk error if the selected files don't fit on
+    /// disk, returns whether they do
+    pub fn check_free_space(&mut self) -> bool {
+        let remaining: Vec<_> = self
+            .metainfo
+            .info
+            .files
+            .iter()
+            .enumerate()
+            .map(|(file, info)| {
+                if info.padding || self.file_priorities[file] == Priority::Skip {
+                    0
+                } else {
+                    info.length - self.file_bytes_done[file]
+                }
+            })
+            .collect();
+        match self.storage.check_space(&remaining) {
+            Ok(()) => true,
+            Err(error) => {
+                self.disk_error(error.to_string());
+                false
+            }
+        }
+    }
+
+    /// creates the selected files that don't exist yet at their full size,
+    /// as the storage config allocates them, instead of on their first write
+    pub fn allocate_files(&mut self) -> Result<()> {
+        self.set_state(TorrentState::Allocating);
+        let wanted: Vec<_> = self
+            .metainfo
+            .info
+            .files
+            .iter()
+            .zip(&self.file_priorities)
+            .map(|(file, priority)| !file.padding && *priority != Priority::Skip)
+            .collect();
+        let result = self.storage.allocate(&wanted);
+        match &result {
+            Ok(()) => self.update_state(),
+            Err(error) => self.disk_error(format!("allocating: {}", error)),
+        }
+        result
+    }
+
+    /// hashes the data on disk without changing what the torrent has,
+    /// `progress` is called with the pieces checked so far and the total
+    pub fn check_pieces(&self, mut progress: impl FnMut(usize, usize)) -> Vec<PieceCheck> {
+        let verifier = Verifier::with_available_parallelism();
+        let total = self.metainfo.info.piece_count();
+        // bounds the memory used by pieces waiting to be hashed
+        let max_in_flight = 16;
+        let mut checks = vec![PieceCheck::Missing; total];
+        let mut in_flight = 0;
+        let mut checked = 0;
+
+        for piece in 0..total {
+            let size = self.metainfo.info.piece_size(piece) as u32;
+            match (
+                self.metainfo.piece_hash(piece),
+                self.storage.read_block(piece, 0, size),
+            ) {
+                (Some(hash), Ok(data)) => {
+                    verifier.submit(VerifyJob {
+                        piece,
+                        hash,
+                        data: data.into(),
+                    });
+                    in_flight += 1;
+                }
+                _ => {
+                    checked += 1;
+                    progress(checked, total);
+                }
+            }
+            while in_flight >= max_in_flight || (piece + 1 == total && in_flight > 0) {
+                let verification = verifier.recv().expect("verifier stopped");
+                checks[verification.piece] = if verification.valid {
+                    PieceCheck::Complete
+                } else {
+                    PieceCheck::Corrupt
+                };
+                in_flight -= 1;
+                checked += 1;
+                progress(checked, total);
+            }
+        }
+        checks
+    }
+
+    /// hashes the data on disk to rebuild the pieces we have, pieces that can't
+    /// be read are considered missing
+    pub fn force_recheck(&mut self) -> Result<()> {
+        self.set_state(TorrentState::CheckingFiles);
+        let total = self.metainfo.info.piece_count();
+        let mut events = vec![];
+        let checks = self.check_pieces(|checked, total| {
+            events.push(Event::Checking { checked, total });
+        });
+        for event in events {
+            self.events.push_back(event);
+        }
+        let mut have = Bitfield::new(total);
+        for (piece, check) in checks.into_iter().enumerate() {
+            have.set(piece, check == PieceCheck::Complete);
+        }
+
+        for piece in 0..total {
+            self.scheduler.abort_piece(piece);
+        }
+        self.verifying = Bitfield::new(total);
+        self.contributors.clear();
+        self.read_cache.clear();
+        // blocks of aborted pieces that were never handed to the disk
+        for (piece, offsets) in std::mem::take(&mut self.unflushed) {
+            if let Some(unwritten) = self.unwritten.get_mut(&piece) {
+                unwritten.retain(|offset| !offsets.contains(offset));
+                if unwritten.is_empty() {
+                    self.unwritten.remove(&piece);
+                }
+            }
+        }
+        self.disk_queue = self.disk_queue.saturating_sub(self.unflushed_bytes);
+        self.unflushed_bytes = 0;
+        self.have = have;
+        self.reset_file_progress();
+        self.update_finished();
+        self.update_all_interest();
+        Ok(())
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// what the seed limits are checked against
+    pub fn seeding(&self) -> Seeding {
+        let (_, wanted) = self.selected_bytes();
+        Seeding {
+            ratio: self.uploaded as f64 / wanted.max(1) as f64,
+            seed_time: self.seed_time,
+            idle_time: self.idle_time,
+        }
+    }
+
+    pub fn seed_limits(&self) -> Option<SeedLimits> {
+        self.seed_limits
+    }
+
+    /// None follows the limits of the session
+    pub fn set_seed_limits(&mut self, limits: Option<SeedLimits>) {
+        self.seed_limits = limits;
+    }
+
+    pub fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    /// only sets the label, `Session::set_torrent_category` applies the
+    /// settings of the category too
+    pub fn set_category(&mut self, category: Option<String>) {
+        self.category = category;
+    }
+
+    pub fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+
+    pub fn set_tags(&mut self, tags: BTreeSet<String>) {
+        self.tags = tags;
+    }
+
+    pub fn record_upload(&mut self, bytes: u64) {
+        self.uploaded += bytes;
+        self.upload_rate.add(bytes);
+    }
+
+    /// in bytes/s
+    pub fn download_rate(&self) -> f64 {
+        self.download_rate.rate()
+    }
+
+    /// in bytes/s
+    pub fn upload_rate(&self) -> f64 {
+        self.upload_rate.rate()
+    }
+
+    /// updates the transfer rates and the seeding times and runs periodic
+    /// syncs, meant to be called about once a second
+    pub fn tick(&mut self, now: Instant) {
+        if let Some((last, uploaded)) = self.last_tick {
+            if self.state == TorrentState::Seeding && self.active {
+                let elapsed = now.saturating_duration_since(last);
+                self.seed_time += elapsed;
+                self.idle_time = match self.uploaded > uploaded {
+                    true => Duration::ZERO,
+                    false => self.idle_time + elapsed,
+                };
+            }
+        }
+        self.last_tick = Some((now, self.uploaded));
+        self.download_rate.tick(now);
+        self.upload_rate.tick(now);
+        self.disk_read_rate.tick(now);
+        self.disk_write_rate.tick(now);
+        if let Durability::Periodic(interval) = self.durability {
+            if now.duration_since(self.last_sync) >= interval {
+                self.last_sync = now;
+                self.queue_sync();
+            }
+        }
+        self.scrub();
+        if self
+            .last_rechoke
+            .is_none_or(|last| now.saturating_duration_since(last) >= RECHOKE_INTERVAL)
+        {
+            self.rechoke(now);
+        }
+    }
+
+    /// unchokes the interested peers that upload the most to us, or that we
+    /// upload the most to once seeding, and one more picked at random every
+    /// `OPTIMISTIC_INTERVAL` so that new peers get a chance to show what
+    /// they give. The others are choked.
+    fn rechoke(&mut self, now: Instant) {
+        self.last_rechoke = Some(now);
+        let seeding = self.state == TorrentState::Seeding;
+        let mut interested: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.peer_interested)
+            .map(|(addr, peer)| match seeding {
+                true => (*addr, peer.upload_rate),
+                false => (*addr, peer.download_rate),
+            })
+            .collect();
+        interested.sort_by_key(|&(addr, rate)| (Reverse(rate), addr));
+        let mut unchoked: HashSet<_> = interested
+            .iter()
+            .take(UPLOAD_SLOTS - 1)
+            .map(|&(addr, _)| addr)
+            .collect();
+        let peers = &self.peers;
+        self.optimistic = match self.optimistic {
+            Some((addr, since))
+                if now.saturating_duration_since(since) < OPTIMISTIC_INTERVAL
+                    && !unchoked.contains(&addr)
+                    && peers.get(&addr).is_some_and(|peer| peer.peer_interested) =>
+            {
+                Some((addr, since))
+            }
+            _ => {
+                let others: Vec<_> = interested
+                    .iter()
+                    .map(|&(addr, _)| addr)
+                    .filter(|addr| !unchoked.contains(addr))
+                    .collect();
+                let mut random = [0; 8];
+                random_bytes(&mut random);
+                let index = u64::from_le_bytes(random) as usize % others.len().max(1);
+                others.get(index).map(|&addr| (addr, now))
+            }
+        };
+        unchoked.extend(self.optimistic.map(|(addr, _)| addr));
+        for (addr, peer) in &mut self.peers {
+            let choke = !unchoked.contains(addr);
+            if choke != peer.am_choking {
+                peer.am_choking = choke;
+                peer.outbox.push_back(match choke {
+                    true => Message::Choke,
+                    false => Message::Unchoke,
+                });
+            }
+        }
+    }
+
+    /// rehashes up to `scrub_rate` bytes of downloaded pieces, continuing
+    /// where the previous tick stopped
+    fn scrub(&mut self) {
+        let total = self.have.len();
+        let mut budget = self.scrub_rate;
+        let mut visited = 0;
+        while budget > 0 && visited < total && self.error.is_none() {
+            let piece = self.scrub_cursor;
+            self.scrub_cursor = (piece + 1) % total;
+            visited += 1;
+            // pieces still being written can't be checked yet
+            if !self.have.get(piece) || self.unwritten.contains_key(&piece) {
+                continue;
+            }
+            let size = self.metainfo.info.piece_size(piece);
+            budget = budget.saturating_sub(size);
+            if let Ok(data) = self.storage.read_block(piece, 0, size as u32) {
+                self.check_piece_on_disk(piece, data.into());
+            }
+        }
+    }
+
+    /// forgets the piece if its data doesn't match the hash anymore, returns
+    /// whether it does
+    fn check_piece_on_disk(&mut self, piece: usize, data: Arc<[u8]>) -> bool {
+        let hash = match self.metainfo.piece_hash(piece) {
+            Some(hash) => hash,
+            None => return true,
+        };
+        if verifier::is_valid(&VerifyJob { piece, hash, data }) {
+            return true;
+        }
+        self.have.set(piece, false);
+        self.read_cache.remove(piece);
+        for (file, bytes) in self.metainfo.info.piece_file_overlaps(piece) {
+            self.file_bytes_done[file] -= bytes;
+        }
+        self.update_finished();
+        self.update_all_interest();
+        self.events.push_back(Event::Corrupted(piece));
+        false
+    }
+
+    /// hashing every uploaded piece read from disk costs cpu but stops bitrot
+    /// from spreading to the swarm, pieces found corrupt are downloaded again
+    pub fn set_verify_uploads(&mut self, enabled: bool) {
+        self.verify_uploads = enabled;
+    }
+
+    /// rehashes downloaded pieces in the background, `bytes` is the budget of
+    /// each tick
+    pub fn set_scrub_rate(&mut self, bytes: u64) {
+        self.scrub_rate = bytes;
+    }
+
+    pub fn progress_report(&self) -> Progress {
+        let (bytes_done, bytes_wanted) = self.selected_bytes();
+        Progress {
+            bytes_done,
+            bytes_wanted,
+            total_bytes: self.metainfo.info.total_length(),
+            percent: self.progress() * 100.0,
+            pieces: self.have.count_ones(),
+            piece_count: self.have.len(),
+            download_rate: self.download_rate.rate(),
+            upload_rate: self.upload_rate.rate(),
+            eta: self.download_rate.eta(bytes_wanted - bytes_done),
+        }
+    }
+
+    pub fn file_progress(&self) -> Vec<FileProgress> {
+        self.metainfo
+            .info
+            .files
+            .iter()
+            .zip(&self.file_names)
+            .zip(&self.file_bytes_done)
+            .zip(&self.file_priorities)
+            .map(|(((file, name), &bytes_done), &priority)| FileProgress {
+                path: name.clone(),
+                bytes_done,
+                length: file.length,
+                priority,
+                padding: file.padding,
+            })
+            .collect()
+    }
+
+    /// recomputes the per-file progress from scratch after `have` was replaced
+    fn reset_file_progress(&mut self) {
+        self.pending_moves.clear();
+        self.unreported_files.clear();
+        self.file_bytes_done = vec![0; self.metainfo.info.files.len()];
+        for piece in 0..self.have.len() {
+            if self.have.get(piece) {
+                self.add_file_progress(piece);
+            }
+        }
+        self.move_completed_files();
+    }
+
+    /// the files the piece completed
+    fn add_file_progress(&mut self, piece: usize) -> Vec<usize> {
+        let mut completed = vec![];
+        for (file, bytes) in self.metainfo.info.piece_file_overlaps(piece) {
+            self.file_bytes_done[file] += bytes;
+            if self.is_file_complete(file) {
+                self.file_completed(file);
+                if !self.metainfo.info.files[file].padding {
+                    completed.push(file);
+                }
+            }
+        }
+        completed
+    }
+
+    pub fn trackers(&self) -> &[TrackerStats] {
+        &self.trackers
+    }
+
+    /// the tracker of the metainfo and the ones restored from the resume data
+    pub fn announce_urls(&self) -> Vec<String> {
+        let mut urls = self.metainfo.trackers();
+        for tracker in &self.trackers {
+            if !urls.contains(&tracker.url) {
+                urls.push(tracker.url.clone());
+            }
+        }
+        urls
+    }
+
+    /// records the swarm size the tracker reported
+    pub fn tracker_announced(&mut self, url: &str, response: &AnnounceResponse) {
+        let index = match self.trackers.iter().position(|tracker| tracker.url == url) {
+            Some(index) => index,
+            None => {
+                self.trackers.push(TrackerStats {
+                    url: url.into(),
+                    ..Default::default()
+                });
+                self.trackers.len() - 1
+            }
+        };
+        let tracker = &mut self.trackers[index];
+        tracker.seeders = response.seeders;
+        tracker.leechers = response.leechers;
+        tracker.completed = response.completed;
+        tracker.last_announce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+    }
+
+    /// blocks still waiting to be written or synced are left out
+    pub fn resume_data(&self) -> ResumeData {
+        let mut pieces = self.have.clone();
+        for &piece in self.unwritten.keys().chain(self.unsynced.keys()) {
+            pieces.set(piece, false);
+        }
+        ResumeData {
+            info_hash: self.metainfo.info_hash,
+            pieces,
+            partial_pieces: self
+                .scheduler
+                .in_progress()
+                .map(|buffer| {
+                    let mut blocks = buffer.received_blocks();
+                    for &offset in self.unwritten.get(&buffer.piece()).into_iter().flatten() {
+                        blocks.set((offset / BLOCK_SIZE) as usize, false);
+                    }
+                    // which blocks were written since the last sync isn't tracked
+                    if self.unsynced.contains_key(&buffer.piece()) {
+                        blocks = Bitfield::new(blocks.len());
+                    }
+                    PartialPiece {
+                        piece: buffer.piece(),
+                        blocks,
+                    }
+                })
+                .filter(|partial| partial.blocks.count_ones() > 0)
+                .collect(),
+            files: self
+                .file_paths()
+                .iter()
+                .map(|path| FileStat::read(path))
+                .collect(),
+            trackers: self.trackers.clone(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            seed_time: self.seed_time.as_secs(),
+            idle_time: self.idle_time.as_secs(),
+            renamed_files: self
+                .file_names
+                .iter()
+                .zip(&self.metainfo.info.files)
+                .enumerate()
+                .filter(|(_, (name, file))| **name != file.path)
+                .map(|(index, (name, _))| (index, name.clone()))
+                .collect(),
+            root_name: Some(self.root_name.clone()).filter(|name| *name != self.metainfo.info.name),
+        }
+    }
+
+    pub fn save_resume_data(&self, dir: &Path) -> Result<()> {
+        self.resume_data().save(dir)
+    }
+
+    /// loads the resume file of this torrent from `dir` if there is one, returns
+    /// false if the torrent has to be rechecked
+    pub fn load_resume_data(&mut self, dir: &Path) -> Result<bool> {
+        match ResumeData::load(dir, &self.metainfo.info_hash)? {
+            Some(resume) => Ok(self.apply_resume_data(resume)),
+            None => Ok(false),
+        }
+    }
+
+    /// restores the state saved in `resume`, the pieces are only trusted if the
+    /// files on disk didn't change since the resume data was written, returns
+    /// false when a recheck is needed
+    pub fn apply_resume_data(&mut self, resume: ResumeData) -> bool {
+        if resume.info_hash != self.metainfo.info_hash {
+            return false;
+        }
+        self.trackers = resume.trackers;
+        self.uploaded = resume.uploaded;
+        self.downloaded = resume.downloaded;
+        self.seed_time = Duration::from_secs(resume.seed_time);
+        self.idle_time = Duration::from_secs(resume.idle_time);
+        // the files were already moved when they were renamed
+        for (file, name) in resume.renamed_files {
+            if file < self.file_names.len() && is_relative_name(&name) {
+                self.file_names[file] = name;
+            }
+        }
+        if let Some(name) = resume.root_name.filter(|name| is_folder_name(name)) {
+            self.root_name = name;
+        }
+        if self
+            .set_incomplete_storage(self.incomplete.clone())
+            .is_err()
+        {
+            return false;
+        }
+
+        let files: Vec<_> = self
+            .file_paths()
+            .iter()
+            .map(|path| FileStat::read(path))
+            .collect();
+        if files != resume.files || resume.pieces.len() != self.have.len() {
+            return false;
+        }
+        self.have = resume.pieces;
+        self.reset_file_progress();
+        for partial in resume.partial_pieces {
+            self.restore_partial_piece(&partial);
+        }
+        self.update_finished();
+        true
+    }
+
+    /// blocks are written in place as they arrive, so a piece interrupted by a
+    /// restart is rebuilt from disk using the block bitmap of the resume data
+    fn restore_partial_piece(&mut self, partial: &PartialPiece) {
+        let piece = partial.piece;
+        if piece >= self.have.len() || self.have.get(piece) {
+            return;
+        }
+        let size = self.metainfo.info.piece_size(piece) as u32;
+        let mut data = vec![0; size as usize];
+        for block in 0..partial.blocks.len() {
+            if !partial.blocks.get(block) {
+                continue;
+            }
+            let offset = block as u32 * BLOCK_SIZE;
+            let length = BLOCK_SIZE.min(size - offset);
+            match self.storage.read_block(piece, offset, length) {
+                Ok(block) => {
+                    data[offset as usize..(offset + length) as usize].copy_from_slice(&block)
+                }
+                Err(_) => return,
+            }
+        }
+        self.scheduler.restore_piece(piece, data, &partial.blocks);
+    }
+
+    pub fn set_file_priorities(&mut self, priorities: &[Priority]) -> Result<()> {
+        if priorities.len() != self.metainfo.info.files.len() {
+            bail!(
+                "expected {} file priorities, got {}",
+                self.metainfo.info.files.len(),
+                priorities.len()
+            );
+        }
+        self.file_priorities = priorities.to_vec();
+        self.update_piece_priorities();
+        Ok(())
+    }
+
+    /// raise the first and last piece of every selected file to high priority,
+    /// useful to preview media files early
+    pub fn set_prioritize_first_last(&mut self, enabled: bool) {
+        self.prioritize_first_last = enabled;
+        self.update_piece_priorities();
+    }
+
+    /// saves upload bandwidth by not telling peers about pieces they already
+    /// have, at the cost of them having a less accurate view of the swarm
+    pub fn set_suppress_redundant_have(&mut self, enabled: bool) {
+        self.suppress_redundant_have = enabled;
+    }
+
+    fn broadcast_have(&mut self, piece: usize) {
+        for peer in self.peers.values_mut() {
+            if self.suppress_redundant_have && peer.has.get(piece) {
+                continue;
+            }
+            peer.outbox.push_back(Message::Have(piece as u32));
+        }
+    }
+
+    /// we are interested in a peer as long as it has a wanted piece we don't
+    fn update_interest(&mut self, addr: SocketAddr) {
+        let peer = match self.peers.get(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let interested = (0..self.have.len())
+            .any(|piece| peer.has.get(piece) && !self.have.get(piece) && self.is_wanted(piece));
+        let peer = self.peers.get_mut(&addr).unwrap();
+        if interested != peer.am_interested {
+            peer.am_interested = interested;
+            peer.outbox.push_back(if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            });
+        }
+    }
+
+    fn update_all_interest(&mut self) {
+        let addrs: Vec<_> = self.peers.keys().copied().collect();
+        for addr in addrs {
+            self.update_interest(addr);
+        }
+    }
+
+    /// next message to send to the peer
+    pub fn poll_peer_message(&mut self, addr: SocketAddr) -> Option<Message> {
+        self.peers.get_mut(&addr)?.outbox.pop_front()
+    }
+
+    fn update_piece_priorities(&mut self) {
+        let info = &self.metainfo.info;
+        self.piece_priorities = picker::piece_priorities(info, &self.file_priorities);
+        if self.prioritize_first_last {
+            picker::prioritize_first_last(&mut self.piece_priorities, info, &self.file_priorities);
+        }
+        self.update_finished();
+    }
+
+    /// deselected files only have their exclusive pieces skipped, pieces shared
+    /// with a selected file are still downloaded
+    pub fn set_file_selected(&mut self, file: usize, selected: bool) -> Result<()> {
+        let mut priorities = self.file_priorities.clone();
+        match priorities.get_mut(file) {
+            Some(priority) if selected && *priority == Priority::Skip => {
+                *priority = Priority::Normal
+            }
+            Some(priority) if !selected => *priority = Priority::Skip,
+            Some(_) => {}
+            None => bail!("file index {} out of range", file),
+        }
+        self.set_file_priorities(&priorities)
+    }
+
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.file_priorities
+    }
+
+    pub fn piece_priorities(&self) -> &[Priority] {
+        &self.piece_priorities
+    }
+
+    /// replaces the strategy used to choose which piece to download next
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    /// asks for `piece` to be downloaded before `deadline` elapses, pieces with
+    /// a deadline are picked before anything else, earliest deadline first, and
+    /// only from the fastest peers
+    pub fn set_piece_deadline(&mut self, piece: usize, deadline: Duration) {
+        if piece < self.have.len() && !self.have.get(piece) {
+            self.deadlines.insert(piece, Instant::now() + deadline);
+        }
+    }
+
+    pub fn clear_piece_deadline(&mut self, piece: usize) {
+        self.deadlines.remove(&piece);
+    }
+
+    /// next block to request from the peer, time critical pieces come first,
+    /// then blocks of pieces already in progress are preferred over starting
+    /// new pieces
+    pub fn request_block(&mut self, addr: SocketAddr) -> Option<BlockRequest> {
+        let peer = self.peers.get(&addr)?;
+        if !self.active
+            || self.error.is_some()
+            || self.is_disk_congested()
+            || peer.requests.len() >= self.peer_request_budget(addr)
+            || self.requests_in_flight() >= self.max_requests_in_flight()
+        {
+            return None;
+        }
+        // pieces the peer sent corrupt data for are fetched from someone
+        // else, from the peer again when nobody else has them
+        let mut peer_has = peer.has.clone();
+        for &piece in &peer.failed_pieces {
+            let elsewhere = self.peers.iter().any(|(other, state)| {
+                *other != addr && state.has.get(piece) && !state.failed_pieces.contains(&piece)
+            });
+            if elsewhere {
+                peer_has.set(piece, false);
+            }
+        }
+        // the slot is taken first, the other torrents request under their own locks
+        if !self
+            .in_flight
+            .as_ref()
+            .is_none_or(|in_flight| in_flight.try_add())
+        {
+            return None;
+        }
+        self.request_piece_layers(addr);
+        let request = match self
+            .request_deadline_block(addr, &peer_has)
+            .or_else(|| self.request_picked_block(&peer_has))
+        {
+            Some(request) => request,
+            None => {
+                self.release_requests(1);
+                return None;
+            }
+        };
+        self.peers.get_mut(&addr)?.requests.push(request);
+        self.request_block_hashes(addr, request.piece);
+        Some(request)
+    }
+
+    /// asks the peer for the piece layers missing from the metadata, pieces of
+    /// those files can't be verified until they arrive
+    fn request_piece_layers(&mut self, addr: SocketAddr) {
+        let info = &self.metainfo.info;
+        let piece_layer = (info.piece_length / LEAF_SIZE).trailing_zeros();
+        let missing: Vec<_> = info
+            .files
+            .iter()
+            .filter_map(|file| {
+                let root = file.pieces_root?;
+                if file.length <= info.piece_length
+                    || self.metainfo.piece_layers.contains_key(&root)
+                {
+                    return None;
+                }
+                let length = (file.length.div_ceil(info.piece_length) as u32).next_power_of_two();
+                (length <= MAX_HASHES).then_some(HashRequest {
+                    pieces_root: root,
+                    base_layer: piece_layer,
+                    index: 0,
+                    length,
+                    proof_layers: 0,
+                })
+            })
+            .collect();
+        for request in missing {
+            self.send_hash_request(addr, request);
+        }
+    }
+
+    /// asks the peer for the leaf hashes of a v2 piece so its blocks can be
+    /// verified one by one
+    fn request_block_hashes(&mut self, addr: SocketAddr, piece: usize) {
+        if self.block_hashes.contains_key(&piece) {
+            return;
+        }
+        let (file, index) = match self.metainfo.info.piece_file(piece) {
+            Some(location) => location,
+            None => return,
+        };
+        let leaves = match self.metainfo.piece_hash(piece) {
+            Some(PieceHash::V2 { leaves, .. }) => leaves as u32,
+            _ => return,
+        };
+        // a single block is checked against the piece hash anyway
+        if !(2..=MAX_HASHES).contains(&leaves) {
+            return;
+        }
+        if let Some(pieces_root) = self.metainfo.info.files[file].pieces_root {
+            self.send_hash_request(
+                addr,
+                HashRequest {
+                    pieces_root,
+                    base_layer: 0,
+                    index: index as u32 * leaves,
+                    length: leaves,
+                    proof_layers: 0,
+                },
+            );
+        }
+    }
+
+    fn send_hash_request(&mut self, addr: SocketAddr, request: HashRequest) {
+        if self.hash_requests.contains_key(&request) {
+            return;
+        }
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.outbox.push_back(Message::HashRequest(request));
+            self.hash_requests.insert(request, addr);
+        }
+    }
+
+    /// checks hashes sent by a peer against the merkle tree and keeps them,
+    /// the peer is blamed if they don't match
+    pub fn hashes_received(
+        &mut self,
+        addr: SocketAddr,
+        request: HashRequest,
+        hashes: &[Hash],
+    ) -> Result<()> {
+        if self.hash_requests.get(&request) != Some(&addr) {
+            bail!("received unrequested hashes {:?}", request);
+        }
+        self.hash_requests.remove(&request);
+        let length = request.length as usize;
+        if hashes.len() < length {
+            bail!("received {} hashes instead of {}", hashes.len(), length);
+        }
+        let hashes = &hashes[..length];
+
+        let info = &self.metainfo.info;
+        let file = info
+            .files
+            .iter()
+            .position(|file| file.pieces_root == Some(request.pieces_root))
+            .ok_or_else(|| anyhow!("received hashes of an unknown file"))?;
+        let piece_layer = (info.piece_length / LEAF_SIZE).trailing_zeros();
+        let valid = if request.base_layer == piece_layer && request.index == 0 {
+            let count = info.files[file].length.div_ceil(info.piece_length) as usize;
+            let layer = hashes[..count.min(length)].to_vec();
+            let valid =
+                merkle::root_from_piece_layer(&layer, info.piece_length) == request.pieces_root;
+            if valid {
+                self.metainfo
+                    .piece_layers
+                    .insert(request.pieces_root, layer);
+            }
+            valid
+        } else if request.base_layer == 0 {
+            let piece =
+                info.file_piece_ranges()[file].start + (request.index / request.length) as usize;
+            match self.metainfo.piece_hash(piece) {
+                Some(PieceHash::V2 {
+                    root,
+                    leaves,
+                    file_bytes,
+                }) if leaves == length => {
+                    let valid = merkle::root(hashes, leaves) == root;
+                    if valid {
+                        let blocks = file_bytes.div_ceil(LEAF_SIZE) as usize;
+                        self.block_hashes.insert(piece, hashes[..blocks].to_vec());
+                    }
+                    valid
+                }
+                _ => bail!("received hashes of an unexpected range {:?}", request),
+            }
+        } else {
+            bail!("received hashes of an unexpected layer {:?}", request);
+        };
+
+        if !valid {
+            self.blame(addr, None);
+            bail!("hashes don't match the merkle tree");
+        }
+        Ok(())
+    }
+
+    /// answers a v2 hash request of the peer with the hashes of the piece
+    /// layer of a file, or of a layer above it, and their proof. The leaf
+    /// hashes are not kept once a piece is verified, requests for them are
+    /// rejected like those for unknown files.
+    pub fn hash_requested(&mut self, addr: SocketAddr, request: HashRequest) {
+        let hashes = self.requested_hashes(&request);
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.outbox.push_back(match hashes {
+                Some(hashes) => Message::Hashes { request, hashes },
+                None => Message::HashReject(request),
+            });
+        }
+    }
+
+    fn requested_hashes(&self, request: &HashRequest) -> Option<Vec<Hash>> {
+        let piece_layer = (self.metainfo.info.piece_length / LEAF_SIZE).trailing_zeros();
+        if request.length > MAX_HASHES || request.base_layer < piece_layer {
+            return None;
+        }
+        let layer = self.metainfo.piece_layers.get(&request.pieces_root)?;
+        merkle::hashes_with_proof(
+            &merkle::tree(layer, piece_layer),
+            (request.base_layer - piece_layer) as usize,
+            request.index as usize,
+            request.length as usize,
+            request.proof_layers as usize,
+        )
+    }
+
+    /// the request is sent again to the next peer we request blocks from
+    pub fn hashes_rejected(&mut self, addr: SocketAddr, request: HashRequest) {
+        if self.hash_requests.get(&request) == Some(&addr) {
+            self.hash_requests.remove(&request);
+        }
+    }
+
+    pub fn set_request_limits(&mut self, limits: RequestLimits) {
+        self.request_limits = limits;
+    }
+
+    /// how many blocks can be in flight to the peer at once
+    pub fn peer_request_budget(&self, addr: SocketAddr) -> usize {
+        let max = self.request_limits.max_requests_per_peer;
+        match self.peers.get(&addr).and_then(|peer| peer.reqq) {
+            Some(reqq) => max.min(reqq),
+            None => max,
+        }
+    }
+
+    pub fn requests_in_flight(&self) -> usize {
+        self.peers.values().map(|peer| peer.requests.len()).sum()
+    }
+
+    /// counts the requests of the torrent among those of the session
+    pub fn set_in_flight(&mut self, in_flight: Arc<InFlight>) {
+        let requests = self.requests_in_flight();
+        self.release_requests(requests);
+        for _ in 0..requests {
+            in_flight.try_add();
+        }
+        self.in_flight = Some(in_flight);
+    }
+
+    fn release_requests(&self, count: usize) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.remove(count);
+        }
+    }
+
+    /// takes a request off the peer, false if it wasn't pending
+    fn forget_request(&mut self, addr: SocketAddr, request: &BlockRequest) -> bool {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return false,
+        };
+        let pending = peer.requests.len();
+        peer.requests.retain(|other| other != request);
+        let forgotten = pending - peer.requests.len();
+        self.release_requests(forgotten);
+        forgotten > 0
+    }
+
+    fn max_requests_in_flight(&self) -> usize {
+        (self.request_limits.max_buffer_memory / BLOCK_SIZE as u64).max(1) as usize
+    }
+
+    fn request_deadline_block(
+        &mut self,
+        addr: SocketAddr,
+        peer_has: &Bitfield,
+    ) -> Option<BlockRequest> {
+        if self.deadlines.is_empty() || !self.is_fast_peer(addr) {
+            return None;
+        }
+        let mut pieces: Vec<_> = self
+            .deadlines
+            .iter()
+            .filter(|&(&piece, _)| {
+                peer_has.get(piece) && !self.have.get(piece) && !self.verifying.get(piece)
+            })
+            .map(|(&piece, &deadline)| (deadline, piece))
+            .collect();
+        pieces.sort();
+
+        for (_, piece) in pieces {
+            if !self.scheduler.is_in_progress(piece) {
+                return self.start_piece(piece);
+            }
+            let request = self.scheduler.request_open(piece).or_else(|| {
+                self.scheduler
+                    .request_duplicate(piece, &self.peers[&addr].requests)
+            });
+            if request.is_some() {
+                return request;
+            }
+        }
+        None
+    }
+
+    /// peers at least as fast as the median peer
+    fn is_fast_peer(&self, addr: SocketAddr) -> bool {
+        let mut rates: Vec<_> = self.peers.values().map(|peer| peer.download_rate).collect();
+        rates.sort_unstable();
+        let median = rates[rates.len() / 2];
+        self.peers[&addr].download_rate >= median
+    }
+
+    /// the first of the blocks the picker chose that can be requested
+    fn request_picked_block(&mut self, peer_has: &Bitfield) -> Option<BlockRequest> {
+        let mut busy = self.have.clone();
+        for piece in 0..busy.len() {
+            if self.verifying.get(piece) {
+                busy.set(piece, true);
+            }
+        }
+        for buffer in self.scheduler.in_progress() {
+            busy.set(buffer.piece(), true);
+        }
+        let requests = self.picker.pick(&PickContext {
+            info: &self.metainfo.info,
+            busy: &busy,
+            peer_has,
+            priorities: &self.piece_priorities,
+            availability: &self.availability,
+            recently_completed: &self.recently_completed,
+            downloading: &self.scheduler,
+            count: 1,
+        });
+        requests
+            .into_iter()
+            .find(|request| self.claim_block(request))
+    }
+
+    /// marks a picked block as requested, a new piece is started only if it
+    /// can be verified and its buffer fits the memory budget
+    fn claim_block(&mut self, request: &BlockRequest) -> bool {
+        let piece = request.piece;
+        if piece >= self.have.len() || self.have.get(piece) || self.verifying.get(piece) {
+            return false;
+        }
+        if !self.scheduler.is_in_progress(piece) {
+            if self.metainfo.piece_hash(piece).is_none() {
+                return false;
+            }
+            let size = self.metainfo.info.piece_size(piece);
+            let buffered = self.scheduler.buffered_bytes();
+            if buffered > 0 && buffered + size > self.request_limits.max_buffer_memory {
+                return false;
+            }
+        }
+        let size = self.metainfo.info.piece_size(piece) as u32;
+        self.scheduler.claim(request, size)
+    }
+
+    /// starts a new piece unless its buffer would go over the memory budget,
+    /// a single piece is always allowed so huge pieces can still be downloaded
+    fn start_piece(&mut self, piece: usize) -> Option<BlockRequest> {
+        // v2 pieces wait for their piece layer
+        self.metainfo.piece_hash(piece)?;
+        let size = self.metainfo.info.piece_size(piece);
+        let buffered = self.scheduler.buffered_bytes();
+        if buffered > 0 && buffered + size > self.request_limits.max_buffer_memory {
+            return None;
+        }
+        Some(self.scheduler.start_piece(piece, size as u32))
+    }
+
+    pub fn cancel_request(&mut self, addr: SocketAddr, request: &BlockRequest) {
+        self.forget_request(addr, request);
+        self.scheduler.cancel(request);
+    }
+
+    /// assembles the block and returns the piece once complete, it has to be
+    /// verified before being marked as downloaded, see `verify_job`
+    pub fn block_received(
+        &mut self,
+        addr: SocketAddr,
+        request: &BlockRequest,
+        data: &[u8],
+    ) -> Result<Option<CompletedPiece>> {
+        self.forget_request(addr, request);
+        self.downloaded += data.len() as u64;
+        self.download_rate.add(data.len() as u64);
+        // duplicate requests of time critical pieces can arrive after the block is done
+        if self.scheduler.is_received(request)
+            || (!self.scheduler.is_in_progress(request.piece)
+                && (self.have.get(request.piece) || self.verifying.get(request.piece)))
+        {
+            self.wasted += data.len() as u64;
+            return Ok(None);
+        }
+        if !self.is_valid_block(request, data) {
+            self.scheduler.cancel(request);
+            self.wasted += data.len() as u64;
+            self.blame(addr, Some(request.piece));
+            return Ok(None);
+        }
+        let completed = self.scheduler.block_received(request, data)?;
+        self.disk_queue += data.len() as u64;
+        self.unwritten
+            .entry(request.piece)
+            .or_default()
+            .insert(request.offset);
+        self.unflushed
+            .entry(request.piece)
+            .or_default()
+            .insert(request.offset);
+        self.unflushed_bytes += data.len() as u64;
+        match &completed {
+            Some(completed) => self.queue_writes(completed.piece, &completed.data),
+            None if self.unflushed_bytes > self.request_limits.max_unflushed => self.flush_writes(),
+            None => {}
+        }
+        self.contributors
+            .entry(request.piece)
+            .or_default()
+            .insert(addr);
+        if let Some(completed) = &completed {
+            self.verifying.set(completed.piece, true);
+        }
+        Ok(completed)
+    }
+
+    /// data of a block requested by a peer, the whole piece is read and
+    /// cached since peers usually request the following blocks too
+    pub fn read_block(&mut self, request: &BlockRequest) -> Result<Vec<u8>> {
+        if !self.have.get(request.piece) {
+            bail!("piece {} isn't downloaded", request.piece);
+        }
+        let piece = match self.read_cache.get(request.piece) {
+            Some(piece) => piece,
+            None => {
+                let size = self.metainfo.info.piece_size(request.piece) as u32;
+                let piece: Arc<[u8]> = match self.storage.read_block(request.piece, 0, size) {
+                    Ok(data) => data.into(),
+                    Err(error) => {
+                        tracing::warn!(
+                            info_hash = %to_hex(&self.metainfo.info_hash),
+                            piece = request.piece,
+                            error = format_args!("{:#}", error),
+                            "disk read failed"
+                        );
+                        self.disk_read_errors += 1;
+                        self.raise_alert(Alert::DiskReadFailed {
+                            piece: request.piece,
+                            error: error.to_string(),
+                        });
+                        return Err(error);
+                    }
+                };
+                self.disk_read_rate.add(size as u64);
+                if self.verify_uploads && !self.check_piece_on_disk(request.piece, piece.clone()) {
+                    bail!("piece {} is corrupt on disk", request.piece);
+                }
+                self.read_cache.insert(request.piece, Arc::clone(&piece));
+                piece
+            }
+        };
+        let start = request.offset as usize;
+        match piece.get(start..start + request.length as usize) {
+            Some(block) => Ok(block.to_vec()),
+            None => bail!("request {:?} is out of the piece", request),
+        }
+    }
+
+    /// data of a block requested by a peer, served without copying it. Cached
+    /// pieces are sent from memory, the others straight from the files unless
+    /// uploads are verified, in which case the piece is read and hashed first.
+    pub fn upload_block(&mut self, request: &BlockRequest) -> Result<UploadBlock> {
+        if !self.have.get(request.piece) {
+            bail!("piece {} isn't downloaded", request.piece);
+        }
+        let start = request.offset as usize;
+        let range = start..start + request.length as usize;
+        if range.end as u64 > self.metainfo.info.piece_size(request.piece) {
+            bail!("request {:?} is out of the piece", request);
+        }
+        if let Some(piece) = self.read_cache.get(request.piece) {
+            return Ok(UploadBlock::Memory { piece, range });
+        }
+        if !self.verify_uploads {
+            let files = self
+                .storage
+                .block_files(request.piece, request.offset, request.length);
+            match files {
+                Ok(Some(files)) => {
+                    self.disk_read_rate.add(request.length as u64);
+                    return Ok(UploadBlock::Files(files));
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    tracing::warn!(
+                        info_hash = %to_hex(&self.metainfo.info_hash),
+                        piece = request.piece,
+                        error = format_args!("{:#}", error),
+                        "disk read failed"
+                    );
+                    self.disk_read_errors += 1;
+                    self.raise_alert(Alert::DiskReadFailed {
+                        piece: request.piece,
+                        error: error.to_string(),
+                    });
+                    return Err(error);
+                }
+            }
+        }
+        // reads the piece into the cache
+        self.read_block(request)?;
+        match self.read_cache.get(request.piece) {
+            Some(piece) => Ok(UploadBlock::Memory { piece, range }),
+            None => {
+                let size = self.metainfo.info.piece_size(request.piece) as u32;
+                let piece = self.storage.read_block(request.piece, 0, size)?.into();
+                Ok(UploadBlock::Memory { piece, range })
+            }
+        }
+    }
+
+    /// 0 disables the cache
+    pub fn set_read_cache_size(&mut self, bytes: u64) {
+        self.read_cache.set_budget(bytes);
+    }
+
+    /// turns the unflushed blocks of the piece into as few writes as possible
+    fn queue_writes(&mut self, piece: usize, data: &[u8]) {
+        let offsets = match self.unflushed.remove(&piece) {
+            Some(offsets) => offsets,
+            None => return,
+        };
+        let mut runs: Vec<(u32, u32)> = vec![];
+        for offset in offsets {
+            let end = (offset + BLOCK_SIZE).min(data.len() as u32);
+            match runs.last_mut() {
+                Some((_, run_end)) if *run_end == offset => *run_end = end,
+                _ => runs.push((offset, end)),
+            }
+        }
+        for (start, end) in runs {
+            self.unflushed_bytes -= (end - start) as u64;
+            self.disk_jobs.push_back(DiskJob::Write {
+                piece,
+                offset: start,
+                data: data[start as usize..end as usize].to_vec(),
+            });
+        }
+    }
+
+    /// queues writes for every block received so far, the blocks of
+    /// unfinished pieces are otherwise held until `max_unflushed` is reached
+    pub fn flush_writes(&mut self) {
+        let pieces: Vec<_> = self.unflushed.keys().copied().collect();
+        for piece in pieces {
+            if let Some(data) = self.scheduler.piece_data(piece) {
+                let data = data.to_vec();
+                self.queue_writes(piece, &data);
+            }
+        }
+    }
+
+    /// next write to submit to the disk threads
+    pub fn poll_disk_job(&mut self) -> Option<DiskJob> {
+        let job = self.disk_jobs.pop_front()?;
+        self.disk_jobs_running += 1;
+        Some(job)
+    }
+
+    pub fn disk_job_done(&mut self, completion: DiskCompletion) {
+        self.disk_jobs_running = self.disk_jobs_running.saturating_sub(1);
+        match completion {
+            DiskCompletion::Written {
+                piece,
+                offset,
+                length,
+            } => {
+                self.disk_write_rate.add(length as u64);
+                self.block_written(piece, offset, length)
+            }
+            DiskCompletion::Read { data, .. } => self.disk_read_rate.add(data.len() as u64),
+            DiskCompletion::Synced { sequence } => {
+                self.unsynced.retain(|_, &mut written| written > sequence)
+            }
+            DiskCompletion::SyncFailed { error } => {
+                self.disk_write_errors += 1;
+                self.disk_error(format!("sync: {}", error));
+            }
+            DiskCompletion::Failed {
+                piece,
+                offset,
+                length,
+                error,
+            } => {
+                tracing::warn!(
+                    info_hash = %to_hex(&self.metainfo.info_hash),
+                    piece,
+                    offset,
+                    error = format_args!("{:#}", error),
+                    "disk write failed"
+                );
+                self.disk_write_errors += 1;
+                self.block_written(piece, offset, length);
+                // a full disk is reported as such rather than as a failed write
+                if self.check_free_space() {
+                    self.disk_error(format!("piece {}: {}", piece, error));
+                }
+            }
+        }
+    }
+
+    fn block_written(&mut self, piece: usize, offset: u32, length: u32) {
+        self.disk_queue = self.disk_queue.saturating_sub(length as u64);
+        if let Some(offsets) = self.unwritten.get_mut(&piece) {
+            offsets.retain(|&block| block < offset || block >= offset + length);
+            if offsets.is_empty() {
+                self.unwritten.remove(&piece);
+            }
+        }
+        if self.durability != Durability::Never {
+            self.write_sequence += 1;
+            self.unsynced.insert(piece, self.write_sequence);
+            self.sync_if_complete(piece);
+        }
+        self.move_completed_files();
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+        if durability == Durability::Never {
+            self.unsynced.clear();
+        }
+    }
+
+    /// syncs the files on the calling thread so the next resume data covers
+    /// every completed write, meant for shutdown
+    pub fn sync(&mut self) -> Result<()> {
+        self.storage.flush()?;
+        self.unsynced.clear();
+        Ok(())
+    }
+
+    /// writes every received block on the calling thread then syncs the
+    /// files, for shutdown when the disk threads are gone. Jobs already
+    /// handed to the disk threads aren't waited for.
+    pub fn flush_disk(&mut self) -> Result<()> {
+        self.flush_writes();
+        while let Some(job) = self.poll_disk_job() {
+            let completion = job.run(&*self.storage);
+            self.disk_job_done(completion);
+        }
+        self.sync()
+    }
+
+    /// syncs the files once a verified piece is entirely written, with
+    /// `Durability::PieceCompletion`
+    fn sync_if_complete(&mut self, piece: usize) {
+        if self.durability == Durability::PieceCompletion
+            && self.have.get(piece)
+            && self.unsynced.contains_key(&piece)
+            && !self.unwritten.contains_key(&piece)
+        {
+            self.queue_sync();
+        }
+    }
+
+    /// queues a sync covering every write completed so far
+    fn queue_sync(&mut self) {
+        if !self.unsynced.is_empty() {
+            self.disk_jobs.push_back(DiskJob::Sync {
+                sequence: self.write_sequence,
+            });
+        }
+    }
+
+    /// bytes received but not written yet, including the jobs not submitted
+    pub fn disk_queue(&self) -> u64 {
+        self.disk_queue
+    }
+
+    pub fn is_disk_congested(&self) -> bool {
+        self.disk_queue >= self.request_limits.max_disk_queue
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// stops downloading until the error is cleared
+    fn disk_error(&mut self, error: String) {
+        tracing::error!(
+            info_hash = %to_hex(&self.metainfo.info_hash),
+            %error,
+            "stopped by a disk error"
+        );
+        self.events.push_back(Event::DiskError(error.clone()));
+        self.raise_alert(Alert::DiskWriteFailed {
+            error: error.clone(),
+        });
+        self.error = Some(error);
+        self.update_state();
+    }
+
+    /// resumes downloading after a disk error, a recheck may be needed if
+    /// pieces were lost. The error stays if the files still don't fit on disk.
+    pub fn clear_error(&mut self) {
+        self.error = None;
+        if self.check_free_space() {
+            self.update_state();
+        }
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.state
+    }
+
+    /// the state the torrent settles in once it isn't checking or
+    /// allocating its files
+    fn update_state(&mut self) {
+        let state = if self.error.is_some() {
+            TorrentState::Errored
+        } else if self.paused {
+            TorrentState::Paused
+        } else if self.finished {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
+        self.set_state(state);
+    }
+
+    fn set_state(&mut self, state: TorrentState) {
+        if state != self.state {
+            self.state = state;
+            self.events.push_back(Event::StateChanged(state));
+        }
+    }
+
+    /// blocks of v2 pieces are checked against their leaf hash when we have
+    /// it, the padding of hybrid torrents has none
+    fn is_valid_block(&self, request: &BlockRequest, data: &[u8]) -> bool {
+        let hashes = match self.block_hashes.get(&request.piece) {
+            Some(hashes) => hashes,
+            None => return true,
+        };
+        let file_bytes = match self.metainfo.piece_hash(request.piece) {
+            Some(PieceHash::V2 { file_bytes, .. }) => file_bytes,
+            _ => return true,
+        };
+        let block = (request.offset / BLOCK_SIZE) as usize;
+        let length = (file_bytes.saturating_sub(request.offset as u64) as usize).min(data.len());
+        match hashes.get(block) {
+            Some(hash) => merkle::hash_block(&data[..length]) == *hash,
+            None => true,
+        }
+    }
+
+    /// pieces are only started once their hash is known
+    pub fn verify_job(&self, completed: &CompletedPiece) -> VerifyJob {
+        VerifyJob {
+            piece: completed.piece,
+            hash: self
+                .metainfo
+                .piece_hash(completed.piece)
+                .expect("piece hash is known"),
+            data: completed.data.clone(),
+        }
+    }
+
+    /// marks the piece as downloaded, or requeues it if the hash didn't match.
+    /// Every peer that sent a block of a corrupt piece is blamed, see `blame`.
+    pub fn verification_done(&mut self, verification: Verification) {
+        let piece = verification.piece;
+        self.verifying.set(piece, false);
+        let contributors = self.contributors.remove(&piece).unwrap_or_default();
+        if verification.valid {
+            tracing::trace!(
+                info_hash = %to_hex(&self.metainfo.info_hash),
+                piece,
+                "piece verified"
+            );
+            self.piece_verified(piece);
+            return;
+        }
+        tracing::warn!(
+            info_hash = %to_hex(&self.metainfo.info_hash),
+            piece,
+            peers = contributors.len(),
+            "piece failed the hash check"
+        );
+
+        self.wasted += self.metainfo.info.piece_size(piece);
+        for addr in contributors {
+            self.blame(addr, Some(piece));
+        }
+        self.events.push_back(Event::HashFailed(piece));
+        self.raise_alert(Alert::HashFailed { piece });
+    }
+
+    /// the peer sent corrupt data, of `piece` when known. It is only asked
+    /// for that piece again when no other peer has it, and is banned and
+    /// disconnected after `MAX_HASH_FAILURES`.
+    fn blame(&mut self, addr: SocketAddr, piece: Option<usize>) {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.failed_pieces.extend(piece);
+        peer.hash_failures += 1;
+        if peer.hash_failures >= MAX_HASH_FAILURES {
+            tracing::info!(
+                info_hash = %to_hex(&self.metainfo.info_hash),
+                peer = %addr,
+                "peer banned after sending corrupt data"
+            );
+            self.banned.insert(addr);
+            self.peer_disconnected(addr);
+        }
+    }
+
+    /// bytes downloaded for nothing, from corrupt pieces or redundant blocks
+    pub fn wasted(&self) -> u64 {
+        self.wasted
+    }
+
+    pub fn is_wanted(&self, piece: usize) -> bool {
+        self.piece_priorities[piece] != Priority::Skip
+    }
+
+    pub fn piece_verified(&mut self, piece: usize) {
+        if self.have.get(piece) {
+            return;
+        }
+        self.have.set(piece, true);
+        self.block_hashes.remove(&piece);
+        if self.recently_completed.len() == RECENTLY_COMPLETED {
+            self.recently_completed.remove(0);
+        }
+        self.recently_completed.push(piece);
+        self.broadcast_have(piece);
+        self.update_all_interest();
+        let completed = self.add_file_progress(piece);
+        self.unreported_files.extend(completed);
+        self.deadlines.remove(&piece);
+        self.events.push_back(Event::PieceVerified(piece));
+        self.sync_if_complete(piece);
+        self.move_completed_files();
+        self.update_finished();
+    }
+
+    /// (done, wanted) bytes over the selected pieces
+    pub fn selected_bytes(&self) -> (u64, u64) {
+        let info = &self.metainfo.info;
+        (0..info.piece_count())
+            .filter(|&piece| self.is_wanted(piece))
+            .fold((0, 0), |(done, wanted), piece| {
+                let size = info.piece_size(piece);
+                let done = if self.have.get(piece) {
+                    done + size
+                } else {
+                    done
+                };
+                (done, wanted + size)
+            })
+    }
+
+    /// between 0 and 1, computed over the selected files only
+    pub fn progress(&self) -> f64 {
+        match self.selected_bytes() {
+            (_, 0) => 1.0,
+            (done, wanted) => done as f64 / wanted as f64,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// pausing disconnects the peers and queues the writes of every block
+    /// received, the rest of the state is kept for resuming. Resumed
+    /// torrents go back to their place in the queue, see
+    /// `TorrentHandle::resume`.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.update_state();
+        if paused {
+            self.set_active(false);
+            let peers: Vec<_> = self.peers.keys().copied().collect();
+            for peer in peers {
+                self.peer_disconnected(peer);
+            }
+            self.flush_writes();
+        }
+    }
+
+    /// managed by the queue, the free space is checked again on activation
+    pub fn set_active(&mut self, active: bool) {
+        if active && !self.active {
+            self.check_free_space();
+        }
+        if active != self.active {
+            self.active = active;
+            self.events.push_back(if active {
+                Event::Activated
+            } else {
+                Event::Queued
+            });
+        }
+    }
+
+    /// peers found by the trackers, the DHT and the like, connected to
+    /// through `poll_peer_candidate`
+    /// blocked addresses are left out
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = SocketAddr>, source: PeerSource) {
+        for addr in peers.into_iter().map(listen::unmapped) {
+            if self.ip_families.allows(addr.ip())
+                && !self.is_blocked(addr)
+                && !self.peers.contains_key(&addr)
+                && !self
+                    .peer_candidates
+                    .iter()
+                    .any(|(candidate, _)| *candidate == addr)
+            {
+                self.peer_candidates.push_back((addr, source));
+            }
+        }
+        while self.peer_candidates.len() > MAX_PEER_CANDIDATES {
+            self.peer_candidates.pop_front();
+        }
+    }
+
+    /// next discovered peer to connect to, with the source to hand to
+    /// `peer_connected`. Those of the preferred IP family go first. None
+    /// while the torrent or the session has as many peers as it may.
+    pub fn poll_peer_candidate(&mut self) -> Option<(SocketAddr, PeerSource)> {
+        if !self.has_peer_slot() {
+            return None;
+        }
+        let peers = &self.peers;
+        self.peer_candidates
+            .retain(|(addr, _)| !peers.contains_key(addr));
+        let families = self.ip_families;
+        let index = self
+            .peer_candidates
+            .iter()
+            .position(|(addr, _)| families.prefers(addr.ip()))
+            .unwrap_or(0);
+        self.peer_candidates.remove(index)
+    }
+
+    /// discovered peers waiting for a connection
+    pub fn peer_candidates(&self) -> impl Iterator<Item = &(SocketAddr, PeerSource)> {
+        self.peer_candidates.iter()
+    }
+
+    /// peers connecting to us are `PeerSource::Incoming`
+    /// false when the address is blocked or there are as many peers as
+    /// allowed, the connection is to be closed
+    pub fn peer_connected(&mut self, addr: SocketAddr, source: PeerSource) -> bool {
+        if self.is_blocked(addr) {
+            tracing::debug!(
+                info_hash = %to_hex(&self.metainfo.info_hash),
+                peer = %addr,
+                "peer blocked"
+            );
+            return false;
+        }
+        let torrent_full = self
+            .max_peers
+            .is_some_and(|max| self.peers.len() >= max && !self.peers.contains_key(&addr));
+        let session_full = !torrent_full
+            && !self.peers.contains_key(&addr)
+            && self
+                .connections
+                .as_ref()
+                .is_some_and(|connections| !connections.try_open());
+        if torrent_full || session_full {
+            tracing::debug!(
+                info_hash = %to_hex(&self.metainfo.info_hash),
+                peer = %addr,
+                "peer refused, too many connections"
+            );
+            return false;
+        }
+        tracing::debug!(
+            info_hash = %to_hex(&self.metainfo.info_hash),
+            peer = %addr,
+            %source,
+            "peer connected"
+        );
+        let peer = PeerState::new(self.have.len(), source);
+        self.availability.add_peer(&peer.has);
+        self.peers.insert(addr, peer);
+        self.events.send(|info_hash| SessionEvent::PeerConnected {
+            info_hash,
+            addr,
+            source,
+        });
+        true
+    }
+
+    /// None is unlimited, peers over it stay connected until they leave
+    pub fn set_max_peers(&mut self, max: Option<usize>) {
+        self.max_peers = max;
+    }
+
+    pub fn max_peers(&self) -> Option<usize> {
+        self.max_peers
+    }
+
+    /// counts the peers of the torrent among those of the session
+    pub fn set_connections(&mut self, connections: Arc<Connections>) {
+        if let Some(old) = &self.connections {
+            self.peers.keys().for_each(|_| old.close());
+        }
+        for _ in self.peers.keys() {
+            connections.try_open();
+        }
+        self.connections = Some(connections);
+    }
+
+    /// whether another peer can be connected to
+    fn has_peer_slot(&self) -> bool {
+        self.max_peers.is_none_or(|max| self.peers.len() < max)
+            && self
+                .connections
+                .as_ref()
+                .is_none_or(|connections| connections.has_slot())
+    }
+
+    /// peers in its ranges are neither added nor connected
+    pub fn set_ip_filter(&mut self, filter: Arc<RwLock<IpFilter>>) {
+        self.ip_filter = Some(filter);
+    }
+
+    /// peers of the other family are dropped when one is required
+    pub fn set_ip_families(&mut self, families: IpFamilies) {
+        self.ip_families = families;
+    }
+
+    pub fn ip_families(&self) -> IpFamilies {
+        self.ip_families
+    }
+
+    /// by the ip filter, counted as a hit when it is, or banned for corrupt data
+    fn is_blocked(&self, addr: SocketAddr) -> bool {
+        self.banned.contains(&addr)
+            || self
+                .ip_filter
+                .as_ref()
+                .is_some_and(|filter| filter.read().unwrap().blocks(addr.ip()))
+    }
+
+    /// forgets the peer and puts its pending requests back in the pool
+    pub fn peer_disconnected(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.remove(&addr) {
+            tracing::debug!(
+                info_hash = %to_hex(&self.metainfo.info_hash),
+                peer = %addr,
+                "peer disconnected"
+            );
+            self.availability.remove_peer(&peer.has);
+            for request in &peer.requests {
+                self.scheduler.cancel(request);
+            }
+            self.release_requests(peer.requests.len());
+            if let Some(connections) = &self.connections {
+                connections.close();
+            }
+            self.events
+                .send(|info_hash| SessionEvent::PeerDisconnected { info_hash, addr });
+        }
+        self.hash_requests.retain(|_, peer| *peer != addr);
+    }
+
+    pub fn peer_bitfield(&mut self, addr: SocketAddr, bitfield: Bitfield) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            self.availability.remove_peer(&peer.has);
+            self.availability.add_peer(&bitfield);
+            peer.has = bitfield;
+        }
+        self.update_interest(addr);
+    }
+
+    pub fn peer_have(&mut self, addr: SocketAddr, piece: usize) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            if !peer.has.get(piece) {
+                peer.has.set(piece, true);
+                self.availability.add_piece(piece);
+            }
+        }
+        self.update_interest(addr);
+    }
+
+    /// the `reqq` value from the peer's extension handshake
+    pub fn set_peer_request_queue(&mut self, addr: SocketAddr, reqq: usize) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.reqq = Some(reqq);
+        }
+    }
+
+    pub fn set_peer_download_rate(&mut self, addr: SocketAddr, rate: u64) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.download_rate = rate;
+        }
+    }
+
+    pub fn set_peer_upload_rate(&mut self, addr: SocketAddr, rate: u64) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.upload_rate = rate;
+        }
+    }
+
+    /// from the peer id of its handshake, see `peer::client_name`, or the
+    /// `v` of its extension handshake
+    pub fn set_peer_client(&mut self, addr: SocketAddr, client: String) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.client = Some(client);
+        }
+    }
+
+    pub fn set_peer_encrypted(&mut self, addr: SocketAddr, encrypted: bool) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.encrypted = encrypted;
+        }
+    }
+
+    /// what the peer told us with choke, unchoke, interested and not
+    /// interested
+    pub fn set_peer_choking(&mut self, addr: SocketAddr, choking: bool) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.peer_choking = choking;
+        }
+    }
+
+    /// a peer getting interested takes a free upload slot right away, the
+    /// others wait for the next rechoke
+    pub fn set_peer_interested(&mut self, addr: SocketAddr, interested: bool) {
+        let unchoked = self
+            .peers
+            .values()
+            .filter(|peer| peer.peer_interested && !peer.am_choking)
+            .count();
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.peer_interested = interested;
+            if interested && peer.am_choking && unchoked < UPLOAD_SLOTS {
+                peer.am_choking = false;
+                peer.outbox.push_back(Message::Unchoke);
+            }
+        }
+    }
+
+    /// whether we choke the peer, as decided by the connection
+    pub fn set_am_choking(&mut self, addr: SocketAddr, choking: bool) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.am_choking = choking;
+        }
+    }
+
+    pub fn peers(&self) -> &HashMap<SocketAddr, PeerState> {
+        &self.peers
+    }
+
+    /// the connected peers, sorted by address
+    pub fn peer_list(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(addr, peer)| PeerInfo {
+                addr: *addr,
+                source: peer.source,
+                client: peer.client.clone(),
+                pieces: peer.has.count_ones(),
+                download_rate: peer.download_rate,
+                upload_rate: peer.upload_rate,
+                choked: peer.peer_choking,
+                interested: peer.am_interested,
+                choking: peer.am_choking,
+                peer_interested: peer.peer_interested,
+                encrypted: peer.encrypted,
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.addr);
+        peers
+    }
+
+    pub fn availability(&self) -> &Availability {
+        &self.availability
+    }
+
+    pub fn stats(&self) -> TorrentStats {
+        TorrentStats {
+            progress: self.progress(),
+            pieces: self.have.count_ones(),
+            piece_count: self.have.len(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            wasted: self.wasted,
+            peers: self.availability.peers(),
+            peers_by_source: self
+                .peers
+                .values()
+                .fold(BTreeMap::new(), |mut sources, peer| {
+                    *sources.entry(peer.source).or_insert(0) += 1;
+                    sources
+                }),
+            distributed_copies: self.availability.distributed_copies(),
+            cache_hits: self.read_cache.hits(),
+            cache_misses: self.read_cache.misses(),
+            disk: self.disk_stats(),
+        }
+    }
+
+    pub fn disk_stats(&self) -> DiskStats {
+        let (hits, misses) = (self.read_cache.hits(), self.read_cache.misses());
+        DiskStats {
+            queue_depth: self.disk_jobs.len() + self.disk_jobs_running,
+            pending_bytes: self.disk_queue,
+            read_rate: self.disk_read_rate.rate(),
+            write_rate: self.disk_write_rate.rate(),
+            cache_hit_rate: match hits + misses {
+                0 => 0.0,
+                total => hits as f64 / total as f64,
+            },
+            read_errors: self.disk_read_errors,
+            write_errors: self.disk_write_errors,
+        }
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// keeps the alert until `take_alerts` and sends it to the subscribers
+    /// of the bus
+    pub fn raise_alert(&mut self, alert: Alert) {
+        self.events.send(|info_hash| SessionEvent::Alert {
+            info_hash,
+            alert: alert.clone(),
+        });
+        if self.alerts.len() == MAX_ALERTS {
+            self.alerts.pop_front();
+        }
+        self.alerts.push_back(alert);
+    }
+
+    /// raised since the last call, oldest first
+    pub fn take_alerts(&mut self) -> Vec<Alert> {
+        self.alerts.drain(..).collect()
+    }
+
+    /// sends the events of the torrent and of its peers to the subscribers
+    /// of the bus too
+    pub fn set_event_bus(&mut self, bus: EventBus) {
+        self.events.bus = Some((bus, self.metainfo.info_hash));
+    }
+
+    fn update_finished(&mut self) {
+        let finished =
+            (0..self.have.len()).all(|piece| !self.is_wanted(piece) || self.have.get(piece));
+        if finished && !self.finished {
+            self.events.push_back(Event::Finished);
+        }
+        self.finished = finished;
+        self.update_state();
+    }
+}
+
+/// location of every file on disk, multi-file torrents live in a directory named after the torrent
+fn file_paths(
+    metainfo: &Metainfo,
+    root_name: &str,
+    names: &[PathBuf],
+    save_path: &Path,
+) -> Vec<PathBuf> {
+    let root = if metainfo.info.multi_file {
+        save_path.join(root_name)
+    } else {
+        save_path.to_path_buf()
+    };
+    names.iter().map(|name| root.join(name)).collect()
+}
+
+fn is_folder_name(name: &str) -> bool {
+    Path::new(name).components().count() == 1 && is_relative_name(Path::new(name))
+}
+
+/// a non empty relative path that can't escape its parent directory
+fn is_relative_name(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+}
+
+/// moves every file from `old_paths` to `targets`, the files already moved are
+/// moved back if one fails
+fn move_files(
+    storage: &dyn Storage,
+    old_paths: &[PathBuf],
+    targets: &[PathBuf],
+    mut progress: impl FnMut(usize, usize),
+) -> Result<()> {
+    for (file, path) in targets.iter().enumerate() {
+        if let Err(error) = storage.rename_file(file, path) {
+            for (moved, old_path) in old_paths.iter().enumerate().take(file) {
+                let _ = storage.rename_file(moved, old_path);
+            }
+            bail!("moving {}: {}", path.display(), error);
+        }
+        progress(file + 1, targets.len());
+    }
+    Ok(())
+}
+
+/// removes the directories left empty by moving `paths` away, up to `root`
+fn remove_empty_dirs(paths: &[PathBuf], root: &Path) {
+    for path in paths {
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(root) || dir == root || std::fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Cheap to clone reference to a torrent shared with the engine
+#[derive(Clone)]
+pub struct TorrentHandle {
+    inner: Arc<Mutex<Torrent>>,
+    queue: Option<Arc<Mutex<Queue>>>,
+}
+
+impl TorrentHandle {
+    pub fn new(torrent: Torrent) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(torrent)),
+            queue: None,
+        }
+    }
+
+    /// adds the torrent at the bottom of the queue
+    pub fn queued(torrent: Torrent, queue: &Arc<Mutex<Queue>>) -> Self {
+        let inner = Arc::new(Mutex::new(torrent));
+        queue.lock().unwrap().push(Arc::clone(&inner));
+        Self {
+            inner,
+            queue: Some(Arc::clone(queue)),
+        }
+    }
+
+    /// None if the torrent isn't queued
+    pub fn queue_position(&self) -> Option<usize> {
+        let queue = self.queue.as_ref()?;
+        queue.lock().unwrap().position(&self.inner)
+    }
+
+    pub fn queue_top(&self) {
+        if let Some(queue) = &self.queue {
+            queue.lock().unwrap().move_top(&self.inner);
+        }
+    }
+
+    pub fn queue_up(&self) {
+        if let Some(queue) = &self.queue {
+            queue.lock().unwrap().move_up(&self.inner);
+        }
+    }
+
+    pub fn queue_down(&self) {
+        if let Some(queue) = &self.queue {
+            queue.lock().unwrap().move_down(&self.inner);
+        }
+    }
+
+    pub fn queue_bottom(&self) {
+        if let Some(queue) = &self.queue {
+            queue.lock().unwrap().move_bottom(&self.inner);
+        }
+    }
+
+    /// for the swarm, which feeds the torrent what its peers send
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Torrent> {
+        self.inner.lock().unwrap()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.inner.lock().unwrap().is_active()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.lock().unwrap().is_paused()
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.inner.lock().unwrap().state()
+    }
+
+    /// the disk error that stopped the torrent, see `TorrentState::Errored`
+    pub fn error(&self) -> Option<String> {
+        self.inner.lock().unwrap().error().map(String::from)
+    }
+
+    /// the pieces downloaded and verified
+    pub fn have(&self) -> Bitfield {
+        self.inner.lock().unwrap().have().clone()
+    }
+
+    pub fn allocate_files(&self) -> Result<()> {
+        self.inner.lock().unwrap().allocate_files()
+    }
+
+    pub fn set_event_bus(&self, bus: EventBus) {
+        self.inner.lock().unwrap().set_event_bus(bus)
+    }
+
+    pub fn set_ip_filter(&self, filter: Arc<RwLock<IpFilter>>) {
+        self.inner.lock().unwrap().set_ip_filter(filter)
+    }
+
+    pub fn set_max_peers(&self, max: Option<usize>) {
+        self.inner.lock().unwrap().set_max_peers(max)
+    }
+
+    pub fn max_peers(&self) -> Option<usize> {
+        self.inner.lock().unwrap().max_peers()
+    }
+
+    pub fn set_connections(&self, connections: Arc<Connections>) {
+        self.inner.lock().unwrap().set_connections(connections)
+    }
+
+    pub fn set_in_flight(&self, in_flight: Arc<InFlight>) {
+        self.inner.lock().unwrap().set_in_flight(in_flight)
+    }
+
+    pub fn set_ip_families(&self, families: IpFamilies) {
+        self.inner.lock().unwrap().set_ip_families(families)
+    }
+
+    pub fn ip_families(&self) -> IpFamilies {
+        self.inner.lock().unwrap().ip_families()
+    }
+
+    /// deactivates the torrent, handing its queue slot to the next one
+    pub fn pause(&self) {
+        self.inner.lock().unwrap().set_paused(true);
+        if let Some(queue) = &self.queue {
+            queue.lock().unwrap().update();
+        }
+    }
+
+    /// activates the torrent again if its place in the queue allows it
+    pub fn resume(&self) {
+        self.inner.lock().unwrap().set_paused(false);
+        match &self.queue {
+            Some(queue) => queue.lock().unwrap().update(),
+            None => self.inner.lock().unwrap().set_active(true),
+        }
+    }
+
+    /// takes the torrent out of its queue, when it is removed
+    pub fn dequeue(&self) {
+        if let Some(queue) = &self.queue {
+            queue.lock().unwrap().remove(&self.inner);
+        }
+    }
+
+    pub fn announce_urls(&self) -> Vec<String> {
+        self.inner.lock().unwrap().announce_urls()
+    }
+
+    pub fn trackers(&self) -> Vec<TrackerStats> {
+        self.inner.lock().unwrap().trackers().to_vec()
+    }
+
+    pub fn tracker_announced(&self, url: &str, response: &AnnounceResponse) {
+        self.inner.lock().unwrap().tracker_announced(url, response)
+    }
+
+    pub fn raise_alert(&self, alert: Alert) {
+        self.inner.lock().unwrap().raise_alert(alert)
+    }
+
+    pub fn take_alerts(&self) -> Vec<Alert> {
+        self.inner.lock().unwrap().take_alerts()
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.inner.lock().unwrap().metainfo().info_hash
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.inner.lock().unwrap().metainfo().info.private
+    }
+
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>, source: PeerSource) {
+        self.inner.lock().unwrap().add_peers(peers, source)
+    }
+
+    pub fn peer_list(&self) -> Vec<PeerInfo> {
+        self.inner.lock().unwrap().peer_list()
+    }
+
+    pub fn peer_candidates(&self) -> Vec<(SocketAddr, PeerSource)> {
+        self.inner
+            .lock()
+            .unwrap()
+            .peer_candidates()
+            .copied()
+            .collect()
+    }
+
+    pub fn set_file_priorities(&self, priorities: &[Priority]) -> Result<()> {
+        self.inner.lock().unwrap().set_file_priorities(priorities)
+    }
+
+    pub fn file_priorities(&self) -> Vec<Priority> {
+        self.inner.lock().unwrap().file_priorities().to_vec()
+    }
+
+    pub fn save_path(&self) -> PathBuf {
+        self.inner.lock().unwrap().save_path().clone()
+    }
+
+    pub fn metainfo(&self) -> Metainfo {
+        self.inner.lock().unwrap().metainfo().clone()
+    }
+
+    pub fn piece_priorities(&self) -> Vec<Priority> {
+        self.inner.lock().unwrap().piece_priorities().to_vec()
+    }
+
+    pub fn set_file_selected(&self, file: usize, selected: bool) -> Result<()> {
+        self.inner.lock().unwrap().set_file_selected(file, selected)
+    }
+
+    pub fn progress(&self) -> f64 {
+        self.inner.lock().unwrap().progress()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.inner.lock().unwrap().is_finished()
+    }
+
+    pub fn stats(&self) -> TorrentStats {
+        self.inner.lock().unwrap().stats()
+    }
+
+    /// download and upload rates in bytes/s
+    pub fn rates(&self) -> (f64, f64) {
+        let torrent = self.inner.lock().unwrap();
+        (torrent.download_rate(), torrent.upload_rate())
+    }
+
+    pub fn root_name(&self) -> String {
+        self.inner.lock().unwrap().root_name().to_string()
+    }
+
+    pub fn seeding(&self) -> Seeding {
+        self.inner.lock().unwrap().seeding()
+    }
+
+    pub fn seed_limits(&self) -> Option<SeedLimits> {
+        self.inner.lock().unwrap().seed_limits()
+    }
+
+    pub fn set_seed_limits(&self, limits: Option<SeedLimits>) {
+        self.inner.lock().unwrap().set_seed_limits(limits)
+    }
+
+    pub fn category(&self) -> Option<String> {
+        self.inner.lock().unwrap().category().map(String::from)
+    }
+
+    pub fn set_category(&self, category: Option<String>) {
+        self.inner.lock().unwrap().set_category(category)
+    }
+
+    pub fn tags(&self) -> BTreeSet<String> {
+        self.inner.lock().unwrap().tags().clone()
+    }
+
+    pub fn set_tags(&self, tags: BTreeSet<String>) {
+        self.inner.lock().unwrap().set_tags(tags)
+    }
+
+    /// clamped to the queue
+    pub fn queue_move_to(&self, position: usize) {
+        if let Some(queue) = &self.queue {
+            queue.lock().unwrap().move_to(&self.inner, position);
+        }
+    }
+
+    pub fn progress_report(&self) -> Progress {
+        self.inner.lock().unwrap().progress_report()
+    }
+
+    pub fn file_progress(&self) -> Vec<FileProgress> {
+        self.inner.lock().unwrap().file_progress()
+    }
+
+    pub fn set_picker(&self, picker: Box<dyn PiecePicker>) {
+        self.inner.lock().unwrap().set_picker(picker)
+    }
+
+    pub fn set_prioritize_first_last(&self, enabled: bool) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_prioritize_first_last(enabled)
+    }
+
+    pub fn set_piece_deadline(&self, piece: usize, deadline: Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_piece_deadline(piece, deadline)
+    }
+
+    pub fn clear_piece_deadline(&self, piece: usize) {
+        self.inner.lock().unwrap().clear_piece_deadline(piece)
+    }
+
+    pub fn flush_disk(&self) -> Result<()> {
+        self.inner.lock().unwrap().flush_disk()
+    }
+
+    pub fn tick(&self, now: Instant) {
+        self.inner.lock().unwrap().tick(now)
+    }
+
+    pub fn save_resume_data(&self, dir: &Path) -> Result<()> {
+        self.inner.lock().unwrap().save_resume_data(dir)
+    }
+
+    pub fn load_resume_data(&self, dir: &Path) -> Result<bool> {
+        self.inner.lock().unwrap().load_resume_data(dir)
+    }
+
+    pub fn force_recheck(&self) -> Result<()> {
+        self.inner.lock().unwrap().force_recheck()
+    }
+
+    pub fn rename_file(&self, file: usize, path: impl Into<PathBuf>) -> Result<()> {
+        self.inner.lock().unwrap().rename_file(file, path)
+    }
+
+    pub fn has_unwritten_data(&self) -> bool {
+        self.inner.lock().unwrap().has_unwritten_data()
+    }
+
+    pub fn is_piece_readable(&self, piece: usize) -> bool {
+        self.inner.lock().unwrap().is_piece_readable(piece)
+    }
+
+    pub fn read_block(&self, request: &BlockRequest) -> Result<Vec<u8>> {
+        self.inner.lock().unwrap().read_block(request)
+    }
+
+    pub fn rename_root(&self, name: impl Into<String>) -> Result<()> {
+        self.inner.lock().unwrap().rename_root(name)
+    }
+
+    /// the torrent is only locked to report progress, it keeps downloading
+    /// and seeding while the files are moved
+    pub fn move_storage(&self, save_path: impl Into<PathBuf>) -> Result<()> {
+        let save_path = save_path.into();
+        let (storage, old_paths, targets) = {
+            let mut torrent = self.inner.lock().unwrap();
+            torrent.moving_storage = true;
+            (
+                Arc::clone(&torrent.storage),
+                torrent.storage.paths(),
+                torrent.storage_targets(&torrent.final_paths(), &save_path),
+            )
+        };
+        let result = move_files(storage.as_ref(), &old_paths, &targets, |moved, total| {
+            self.inner
+                .lock()
+                .unwrap()
+                .events
+                .push_back(Event::MovingStorage { moved, total })
+        });
+        self.inner
+            .lock()
+            .unwrap()
+            .storage_moved(save_path, &old_paths, result)
+    }
+
+    /// where the files currently are, see `Torrent::file_paths`
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        self.inner.lock().unwrap().file_paths()
+    }
+
+    /// deletes the files of the torrent and the folders they leave empty,
+    /// see `Torrent::delete_files`
+    pub fn delete_files(&self) -> Result<()> {
+        self.inner.lock().unwrap().delete_files()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::verifier::Verifier;
+
+    fn connect_seed(torrent: &mut Torrent, port: u16) -> SocketAddr {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        torrent.peer_connected(addr, PeerSource::Tracker);
+        torrent.peer_bitfield(addr, Bitfield::full(torrent.have().len()));
+        addr
+    }
+
+    /// runs the queued writes on the test thread
+    fn flush_disk(torrent: &mut Torrent) {
+        torrent.flush_writes();
+        while let Some(job) = torrent.poll_disk_job() {
+            let completion = job.run(torrent.storage().as_ref());
+            torrent.disk_job_done(completion);
+        }
+    }
+
+    /// empty directory to download into
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrent_rs_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// v2 torrent of a single 40000 bytes file with 2 block pieces and
+    /// without its piece layer
+    fn v2_torrent(data: &[u8]) -> Result<Torrent> {
+        let blocks = merkle::block_hashes(data);
+        let layer: Vec<_> = blocks.chunks(2).map(|pair| merkle::root(pair, 2)).collect();
+        let root = merkle::root_from_piece_layer(&layer, 2 * LEAF_SIZE);
+        let info = format!(
+            "d9:file treed4:filed0:d6:lengthi{}e11:pieces root32:",
+            data.len()
+        );
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(info.as_bytes());
+        bytes.extend_from_slice(&root);
+        bytes.extend_from_slice(b"eee12:meta versioni2e4:name4:file12:piece lengthi32768eee");
+        Ok(Torrent::new(
+            Metainfo::from_bytes(bytes)?,
+            test_dir("v2_torrent"),
+        ))
+    }
+
+    #[test]
+    fn checks_pieces_on_disk() -> Result<()> {
+        let dir = test_dir("check_pieces");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("data");
+        let data = vec![7; 3 * LEAF_SIZE as usize];
+        std::fs::write(&path, &data)?;
+        let bytes = crate::create::TorrentCreator::new(&path)
+            .piece_length(LEAF_SIZE)
+            .create(|_, _| {})?;
+        let torrent = Torrent::new(Metainfo::from_bytes(bytes)?, &dir);
+        let mut corrupt = data.clone();
+        corrupt[LEAF_SIZE as usize] = 0;
+        corrupt.truncate(2 * LEAF_SIZE as usize + 1);
+        std::fs::write(&path, &corrupt)?;
+
+        let mut calls = 0;
+        let checks = torrent.check_pieces(|checked, total| {
+            calls += 1;
+            assert_eq!((checked, total), (calls, 3));
+        });
+        assert_eq!(
+            checks,
+            [
+                PieceCheck::Complete,
+                PieceCheck::Corrupt,
+                PieceCheck::Missing
+            ]
+        );
+        assert_eq!(torrent.have().count_ones(), 0);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    fn two_piece_torrent() -> Result<Torrent> {
+        // files of 3 and 5 bytes with 4 byte pieces, piece 0 is shared
+        let data = "d4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi5e4:pathl1:beee4:name3:dir12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        Ok(Torrent::new(
+            Metainfo::from_bytes(data.as_bytes().to_vec())?,
+            test_dir("two_piece_torrent"),
+        ))
+    }
+
+    #[test]
+    fn queue_operations() -> Result<()> {
+        let queue = Arc::new(Mutex::new(Queue::new(Default::default())));
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let first = TorrentHandle::queued(Torrent::new(metainfo.clone(), "."), &queue);
+        let second = TorrentHandle::queued(Torrent::new(metainfo.clone(), "."), &queue);
+
+        second.queue_up();
+        assert_eq!(second.queue_position(), Some(0));
+        first.queue_top();
+        assert_eq!(first.queue_position(), Some(0));
+        first.queue_down();
+        assert_eq!(second.queue_position(), Some(0));
+        assert_eq!(
+            TorrentHandle::new(Torrent::new(metainfo, ".")).queue_position(),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_file_priorities() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let handle = TorrentHandle::new(Torrent::new(metainfo, "."));
+
+        assert!(handle.set_file_priorities(&[]).is_err());
+        handle.set_file_priorities(&[Priority::Skip])?;
+        assert_eq!(handle.piece_priorities(), vec![Priority::Skip]);
+        Ok(())
+    }
+
+    #[test]
+    fn finished_when_selection_is_done() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+
+        torrent.set_file_selected(1, false)?;
+        assert!(torrent.is_wanted(0));
+        assert!(!torrent.is_wanted(1));
+        assert_eq!(torrent.progress(), 0.0);
+
+        torrent.piece_verified(0);
+        assert_eq!(torrent.progress(), 1.0);
+        assert_eq!(torrent.poll_event(), Some(Event::PieceVerified(0)));
+        let path = torrent.save_path().join("dir").join("a");
+        assert_eq!(
+            torrent.poll_event(),
+            Some(Event::FileCompleted { file: 0, path })
+        );
+        assert_eq!(torrent.poll_event(), Some(Event::Finished));
+
+        torrent.set_file_selected(1, true)?;
+        assert!(!torrent.is_finished());
+        assert_eq!(torrent.progress(), 0.5);
+
+        let report = torrent.progress_report();
+        assert_eq!((report.bytes_done, report.bytes_wanted), (4, 8));
+        assert_eq!(report.pieces, 1);
+        let files: Vec<_> = torrent
+            .file_progress()
+            .iter()
+            .map(|file| file.bytes_done)
+            .collect();
+        assert_eq!(files, vec![3, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn download_single_piece() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let dir = test_dir("download_single_piece");
+        let mut torrent = Torrent::new(metainfo, &dir);
+        let peer = connect_seed(&mut torrent, 1);
+
+        let request = torrent.request_block(peer).unwrap();
+        assert_eq!(request.length, 12);
+        assert!(torrent.request_block(peer).is_none());
+
+        let completed = torrent
+            .block_received(peer, &request, &std::fs::read("file1.txt")?)?
+            .unwrap();
+        assert!(torrent.request_block(peer).is_none());
+        flush_disk(&mut torrent);
+
+        let verifier = Verifier::new(1);
+        verifier.submit(torrent.verify_job(&completed));
+        torrent.verification_done(verifier.recv().unwrap());
+        assert!(torrent.is_finished());
+        assert_eq!(std::fs::read(dir.join("file1.txt"))?, b"Hello world!");
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn download_into_memory() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let dir = test_dir("download_into_memory");
+        let mut torrent = Torrent::new(metainfo.clone(), &dir);
+        let files = torrent
+            .file_paths()
+            .into_iter()
+            .zip(metainfo.info.files.iter().map(|file| file.length))
+            .collect();
+        torrent.set_storage(Arc::new(MemoryStorage::new(
+            files,
+            metainfo.info.piece_length,
+        )));
+        let peer = connect_seed(&mut torrent, 1);
+
+        let request = torrent.request_block(peer).unwrap();
+        let completed = torrent
+            .block_received(peer, &request, b"Hello world!")?
+            .unwrap();
+        flush_disk(&mut torrent);
+        let verifier = Verifier::new(1);
+        verifier.submit(torrent.verify_job(&completed));
+        torrent.verification_done(verifier.recv().unwrap());
+
+        assert!(torrent.is_finished());
+        assert_eq!(torrent.storage().read_block(0, 6, 6)?, b"world!");
+        assert!(!dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn resume_data_waits_for_sync() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let metainfo = torrent.metainfo().clone();
+        let files = torrent
+            .file_paths()
+            .into_iter()
+            .zip(metainfo.info.files.iter().map(|file| file.length))
+            .collect();
+        torrent.set_storage(Arc::new(MemoryStorage::new(files, 4)));
+        let peer = connect_seed(&mut torrent, 1);
+        let request = std::iter::from_fn(|| torrent.request_block(peer))
+            .find(|request| request.piece == 0)
+            .unwrap();
+        torrent.block_received(peer, &request, b"abcd")?;
+        torrent.piece_verified(0);
+
+        // the write completes first, the sync is queued behind it
+        let job = torrent.poll_disk_job().unwrap();
+        torrent.disk_job_done(job.run(torrent.storage().as_ref()));
+        assert!(!torrent.resume_data().pieces.get(0));
+        let sync = torrent.poll_disk_job().unwrap();
+        assert!(matches!(sync, DiskJob::Sync { .. }));
+        torrent.disk_job_done(sync.run(torrent.storage().as_ref()));
+        assert!(torrent.resume_data().pieces.get(0));
+
+        // periodic syncs happen on tick
+        torrent.set_durability(Durability::Periodic(Duration::ZERO));
+        torrent.piece_verified(1);
+        torrent.disk_job_done(DiskCompletion::Written {
+            piece: 1,
+            offset: 0,
+            length: 4,
+        });
+        assert_eq!(torrent.poll_disk_job(), None);
+        torrent.tick(Instant::now());
+        assert!(matches!(
+            torrent.poll_disk_job(),
+            Some(DiskJob::Sync { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_piece_is_requeued() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let mut torrent = Torrent::new(metainfo, test_dir("failed_piece_is_requeued"));
+        let peer = connect_seed(&mut torrent, 1);
+
+        let request = torrent.request_block(peer).unwrap();
+        let completed = torrent.block_received(peer, &request, &[0; 12])?.unwrap();
+        let verifier = Verifier::new(1);
+        verifier.submit(torrent.verify_job(&completed));
+        torrent.verification_done(verifier.recv().unwrap());
+
+        assert_eq!(torrent.poll_event(), Some(Event::HashFailed(0)));
+        assert_eq!(torrent.take_alerts(), [Alert::HashFailed { piece: 0 }]);
+        assert!(torrent.take_alerts().is_empty());
+        assert_eq!(torrent.wasted(), 12);
+        // the peer that sent the corrupt data is not asked again while
+        // another peer has the piece
+        let other = connect_seed(&mut torrent, 2);
+        assert_eq!(torrent.request_block(peer), None);
+        assert_eq!(torrent.request_block(other), Some(request));
+        Ok(())
+    }
+
+    #[test]
+    fn single_seed_is_asked_again() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let mut torrent = Torrent::new(metainfo, test_dir("single_seed_is_asked_again"));
+        let peer = connect_seed(&mut torrent, 1);
+        let verifier = Verifier::new(1);
+
+        for _ in 1..MAX_HASH_FAILURES {
+            let request = torrent.request_block(peer).unwrap();
+            let completed = torrent.block_received(peer, &request, &[0; 12])?.unwrap();
+            verifier.submit(torrent.verify_job(&completed));
+            torrent.verification_done(verifier.recv().unwrap());
+            assert_eq!(torrent.poll_event(), Some(Event::HashFailed(0)));
+        }
+        // nobody else has the piece, the seed is the only one to ask
+        let request = torrent.request_block(peer).unwrap();
+        let completed = torrent.block_received(peer, &request, &[0; 12])?.unwrap();
+        verifier.submit(torrent.verify_job(&completed));
+        torrent.verification_done(verifier.recv().unwrap());
+
+        // until it sent corrupt data once too often
+        assert!(!torrent.peers().contains_key(&peer));
+        assert!(!torrent.peer_connected(peer, PeerSource::Tracker));
+        torrent.add_peers(vec![peer], PeerSource::Tracker);
+        assert_eq!(torrent.peer_candidates().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn disconnect_requeues_requests() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let first = connect_seed(&mut torrent, 1);
+        let second = connect_seed(&mut torrent, 2);
+
+        let request = torrent.request_block(first).unwrap();
+        torrent.peer_disconnected(first);
+        assert_eq!(torrent.request_block(second), Some(request));
+        assert_eq!(torrent.stats().peers, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn discovered_peers_are_queued() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let connected = connect_seed(&mut torrent, 1);
+        let (first, second) = (
+            SocketAddr::from(([127, 0, 0, 1], 2)),
+            SocketAddr::from(([127, 0, 0, 1], 3)),
+        );
+
+        torrent.add_peers(vec![connected, first], PeerSource::Dht);
+        torrent.add_peers(vec![second, first], PeerSource::Lsd);
+        assert_eq!(
+            torrent.peer_candidates().copied().collect::<Vec<_>>(),
+            vec![(first, PeerSource::Dht), (second, PeerSource::Lsd)]
+        );
+        torrent.peer_connected(first, PeerSource::Dht);
+        assert_eq!(
+            torrent.poll_peer_candidate(),
+            Some((second, PeerSource::Lsd))
+        );
+        assert_eq!(torrent.poll_peer_candidate(), None);
+
+        let incoming = SocketAddr::from(([127, 0, 0, 1], 4));
+        torrent.peer_connected(incoming, PeerSource::Incoming);
+        let list = torrent.peer_list();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0].source, PeerSource::Tracker);
+        assert_eq!(list[0].pieces, 2);
+        assert_eq!(list[1].source, PeerSource::Dht);
+        let stats = torrent.stats();
+        assert_eq!(stats.peers_by_source[&PeerSource::Incoming], 1);
+        assert_eq!(stats.peers_by_source.get(&PeerSource::Pex), None);
+        Ok(())
+    }
+
+    #[test]
+    fn pausing_disconnects_peers() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        connect_seed(&mut torrent, 1);
+        let candidate = SocketAddr::from(([127, 0, 0, 1], 2));
+        torrent.add_peers(vec![candidate], PeerSource::Dht);
+        torrent.set_active(true);
+
+        torrent.set_paused(true);
+        assert!(torrent.is_paused() && !torrent.is_active());
+        assert!(torrent.peer_list().is_empty());
+        // kept to reconnect once resumed
+        assert_eq!(
+            torrent.peer_candidates().copied().collect::<Vec<_>>(),
+            vec![(candidate, PeerSource::Dht)]
+        );
+        torrent.set_paused(false);
+        assert!(!torrent.is_paused());
+        Ok(())
+    }
+
+    #[test]
+    fn blocked_peers_are_refused() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let filter = Arc::new(RwLock::new(IpFilter::parse("10.0.0.0/8")?));
+        torrent.set_ip_filter(Arc::clone(&filter));
+        let blocked = SocketAddr::from(([10, 1, 2, 3], 1));
+        let allowed = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        torrent.add_peers(vec![blocked, allowed], PeerSource::Tracker);
+        assert_eq!(
+            torrent.peer_candidates().copied().collect::<Vec<_>>(),
+            vec![(allowed, PeerSource::Tracker)]
+        );
+        assert!(!torrent.peer_connected(blocked, PeerSource::Incoming));
+        assert!(torrent.peer_connected(allowed, PeerSource::Incoming));
+        assert_eq!(torrent.peer_list().len(), 1);
+        assert_eq!(filter.read().unwrap().hits(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn peers_over_the_limits_are_refused() -> Result<()> {
+        let connections = Arc::new(Connections::new(Some(3)));
+        let mut first = two_piece_torrent()?;
+        let mut second = two_piece_torrent()?;
+        first.set_max_peers(Some(2));
+        first.set_connections(Arc::clone(&connections));
+        second.set_connections(Arc::clone(&connections));
+        let peer = |port| SocketAddr::from(([127, 0, 0, 1], port));
+
+        first.add_peers(vec![peer(9)], PeerSource::Dht);
+        assert!(first.peer_connected(peer(1), PeerSource::Incoming));
+        assert!(first.peer_connected(peer(2), PeerSource::Incoming));
+        assert!(!first.peer_connected(peer(3), PeerSource::Incoming));
+        assert_eq!(first.poll_peer_candidate(), None);
+        assert!(second.peer_connected(peer(1), PeerSource::Incoming));
+        assert!(!second.peer_connected(peer(2), PeerSource::Incoming));
+        assert_eq!(connections.open(), 3);
+
+        first.peer_disconnected(peer(1));
+        assert_eq!(
+            first.poll_peer_candidate(),
+            Some((peer(9), PeerSource::Dht))
+        );
+        drop(second);
+        assert_eq!(connections.open(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn peers_of_the_preferred_family_go_first() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let v4 = SocketAddr::from(([1, 2, 3, 4], 1));
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:1".parse()?;
+        let v6: SocketAddr = "[2001:db8::1]:1".parse()?;
+        torrent.set_ip_families(IpFamilies::PreferV6);
+        // the mapped address is the same peer
+        torrent.add_peers(vec![v4, mapped, v6], PeerSource::Tracker);
+        assert_eq!(torrent.peer_candidates().count(), 2);
+        assert_eq!(
+            torrent.poll_peer_candidate(),
+            Some((v6, PeerSource::Tracker))
+        );
+        assert_eq!(
+            torrent.poll_peer_candidate(),
+            Some((v4, PeerSource::Tracker))
+        );
+
+        torrent.set_ip_families(IpFamilies::V4Only);
+        torrent.add_peers(vec![v6, v4], PeerSource::Dht);
+        assert_eq!(torrent.poll_peer_candidate(), Some((v4, PeerSource::Dht)));
+        assert_eq!(torrent.poll_peer_candidate(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn state_changes() -> Result<()> {
+        let metainfo = two_piece_torrent()?.metainfo.clone();
+        let dir = test_dir("state_changes");
+        let mut torrent = Torrent::new(metainfo, &dir);
+        assert_eq!(torrent.state(), TorrentState::Downloading);
+        torrent.set_file_priorities(&[Priority::Normal, Priority::Skip])?;
+        while torrent.poll_event().is_some() {}
+
+        torrent.allocate_files()?;
+        assert_eq!(std::fs::metadata(dir.join("dir/a"))?.len(), 3);
+        assert!(!dir.join("dir/b").exists());
+        torrent.set_paused(true);
+        assert_eq!(torrent.state(), TorrentState::Paused);
+        torrent.set_paused(false);
+        let states: Vec<_> = std::iter::from_fn(|| torrent.poll_event())
+            .filter_map(|event| match event {
+                Event::StateChanged(state) => Some(state),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            states,
+            [
+                TorrentState::Allocating,
+                TorrentState::Downloading,
+                TorrentState::Paused,
+                TorrentState::Downloading
+            ]
+        );
+        assert_eq!(TorrentState::CheckingFiles.to_string(), "checking files");
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn have_broadcast_and_interest() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let seed = connect_seed(&mut torrent, 1);
+        let leech = SocketAddr::from(([127, 0, 0, 1], 2));
+        torrent.peer_connected(leech, PeerSource::Incoming);
+        torrent.set_suppress_redundant_have(true);
+        assert_eq!(torrent.poll_peer_message(seed), Some(Message::Interested));
+
+        torrent.piece_verified(0);
+        torrent.piece_verified(1);
+        assert_eq!(
+            torrent.poll_peer_message(seed),
+            Some(Message::NotInterested)
+        );
+        assert_eq!(torrent.poll_peer_message(seed), None);
+        assert_eq!(torrent.poll_peer_message(leech), Some(Message::Have(0)));
+        assert_eq!(torrent.poll_peer_message(leech), Some(Message::Have(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn request_budgets() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let first = connect_seed(&mut torrent, 1);
+        let second = connect_seed(&mut torrent, 2);
+
+        torrent.set_peer_request_queue(first, 1);
+        assert!(torrent.request_block(first).is_some());
+        assert!(torrent.request_block(first).is_none());
+
+        torrent.set_request_limits(RequestLimits {
+            max_requests_per_peer: 10,
+            max_buffer_memory: BLOCK_SIZE as u64,
+            ..Default::default()
+        });
+        assert!(torrent.request_block(second).is_none());
+        assert_eq!(torrent.requests_in_flight(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn in_flight_cap_spans_torrents() -> Result<()> {
+        let in_flight = Arc::new(InFlight::new(1));
+        let mut first = two_piece_torrent()?;
+        let mut second = two_piece_torrent()?;
+        first.set_in_flight(Arc::clone(&in_flight));
+        second.set_in_flight(Arc::clone(&in_flight));
+        let peer = connect_seed(&mut first, 1);
+        connect_seed(&mut second, 1);
+
+        let request = first.request_block(peer).unwrap();
+        assert!(second.request_block(peer).is_none());
+        first.cancel_request(peer, &request);
+        assert!(second.request_block(peer).is_some());
+        assert!(first.request_block(peer).is_none());
+        drop(second);
+        assert_eq!(in_flight.requests(), 0);
+        assert!(first.request_block(peer).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn deadline_pieces_go_to_fast_peers() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let slow = connect_seed(&mut torrent, 1);
+        let fast = connect_seed(&mut torrent, 2);
+        let other_fast = connect_seed(&mut torrent, 3);
+        torrent.set_peer_download_rate(slow, 10);
+        torrent.set_peer_download_rate(fast, 1000);
+        torrent.set_peer_download_rate(other_fast, 1000);
+        torrent.set_piece_deadline(1, Duration::from_secs(1));
+
+        assert_eq!(torrent.request_block(slow).map(|r| r.piece), Some(0));
+        let request = torrent.request_block(fast).unwrap();
+        assert_eq!(request.piece, 1);
+        // the block is raced on another fast peer rather than waiting
+        assert_eq!(torrent.request_block(other_fast), Some(request));
+        assert_eq!(torrent.request_block(fast), None);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_requires_unchanged_files() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_torrent_resume_test");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::copy("file1.txt", dir.join("file1.txt"))?;
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+
+        let mut torrent = Torrent::new(metainfo.clone(), &dir);
+        torrent.piece_verified(0);
+        let resume = torrent.resume_data();
+
+        torrent.save_resume_data(&dir)?;
+        let mut restored = Torrent::new(metainfo.clone(), &dir);
+        assert!(restored.load_resume_data(&dir)?);
+        assert!(restored.is_finished());
+
+        std::fs::write(dir.join("file1.txt"), "changed")?;
+        let mut restored = Torrent::new(metainfo, &dir);
+        assert!(!restored.apply_resume_data(resume));
+        assert!(!restored.is_finished());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_seeding() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_torrent_seeding_test");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::copy("file1.txt", dir.join("file1.txt"))?;
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let mut torrent = Torrent::new(metainfo, &dir);
+        torrent.force_recheck()?;
+        assert_eq!(torrent.state(), TorrentState::Seeding);
+        let start = Instant::now();
+        torrent.tick(start);
+        torrent.tick(start + Duration::from_secs(10));
+        assert_eq!(torrent.seeding().idle_time, Duration::from_secs(10));
+        torrent.record_upload(24);
+        torrent.tick(start + Duration::from_secs(15));
+        let seeding = Seeding {
+            ratio: 2.0,
+            seed_time: Duration::from_secs(15),
+            idle_time: Duration::ZERO,
+        };
+        assert_eq!(torrent.seeding(), seeding);
+        // neither does time spent paused
+        torrent.set_paused(true);
+        torrent.tick(start + Duration::from_secs(30));
+        assert_eq!(torrent.seeding(), seeding);
+
+        let resume = torrent.resume_data();
+        assert_eq!((resume.seed_time, resume.idle_time), (15, 0));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn disk_backpressure_and_errors() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        torrent.set_request_limits(RequestLimits {
+            max_disk_queue: 4,
+            ..Default::default()
+        });
+        let peer = connect_seed(&mut torrent, 1);
+        let first = torrent.request_block(peer).unwrap();
+        let second = torrent.request_block(peer).unwrap();
+        torrent.block_received(peer, &first, b"abcd")?;
+        assert!(torrent.is_disk_congested());
+        torrent.block_received(peer, &second, b"efgh")?;
+        assert_eq!(torrent.request_block(peer), None);
+        let disk = torrent.disk_stats();
+        assert_eq!((disk.queue_depth, disk.pending_bytes), (2, 8));
+
+        let job = torrent.poll_disk_job().unwrap();
+        let completion = job.run(torrent.storage().as_ref());
+        torrent.disk_job_done(completion);
+        assert_eq!(torrent.disk_queue(), 4);
+        torrent.disk_job_done(DiskCompletion::Failed {
+            piece: second.piece,
+            offset: 0,
+            length: 4,
+            error: anyhow::anyhow!("disk full"),
+        });
+        assert!(!torrent.is_disk_congested());
+        assert_eq!(torrent.error(), Some("piece 1: disk full"));
+        assert!(matches!(torrent.poll_event(), Some(Event::DiskError(_))));
+        assert_eq!(
+            torrent.take_alerts(),
+            [Alert::DiskWriteFailed {
+                error: "piece 1: disk full".into()
+            }]
+        );
+        let disk = torrent.disk_stats();
+        assert_eq!((disk.write_errors, disk.read_errors), (1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_disk_activity() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let files = torrent.file_paths().into_iter().zip([3, 5]).collect();
+        torrent.set_storage(Arc::new(MemoryStorage::new(files, 4)));
+        let peer = connect_seed(&mut torrent, 1);
+        for data in [b"abcd", b"efgh"] {
+            let request = torrent.request_block(peer).unwrap();
+            torrent.block_received(peer, &request, data)?;
+        }
+        let disk = torrent.disk_stats();
+        assert_eq!((disk.queue_depth, disk.pending_bytes), (2, 8));
+        flush_disk(&mut torrent);
+        let disk = torrent.disk_stats();
+        assert_eq!((disk.queue_depth, disk.pending_bytes), (0, 0));
+
+        torrent.piece_verified(0);
+        let request = BlockRequest {
+            piece: 0,
+            offset: 0,
+            length: 4,
+        };
+        assert_eq!(torrent.read_block(&request)?, b"abcd");
+        assert_eq!(torrent.read_block(&request)?, b"abcd");
+        // a second after the meters started, the bytes of that second
+        torrent.tick(Instant::now() + Duration::from_secs(1));
+        let disk = torrent.disk_stats();
+        assert!(
+            (7.0..=8.0).contains(&disk.write_rate),
+            "{}",
+            disk.write_rate
+        );
+        assert!((3.5..=4.0).contains(&disk.read_rate), "{}", disk.read_rate);
+        assert_eq!(disk.cache_hit_rate, 0.5);
+        assert_eq!((disk.read_errors, disk.write_errors), (0, 0));
+        assert_eq!(torrent.stats().disk, disk);
+        Ok(())
+    }
+
+    #[test]
+    fn uploads_are_cached() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let mut torrent = Torrent::new(metainfo, ".");
+        let block = |offset, length| BlockRequest {
+            piece: 0,
+            offset,
+            length,
+        };
+        assert!(torrent.read_block(&block(0, 5)).is_err());
+
+        torrent.force_recheck()?;
+        assert_eq!(torrent.read_block(&block(0, 5))?, b"Hello");
+        assert_eq!(torrent.read_block(&block(6, 6))?, b"world!");
+        assert!(torrent.read_block(&block(6, 7)).is_err());
+        let stats = torrent.stats();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (2, 1));
+        assert_eq!(stats.disk.cache_hit_rate, 2.0 / 3.0);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_pieces_are_not_uploaded() -> Result<()> {
+        let mut torrent = two_piece_torrent()?;
+        let files = torrent.file_paths().into_iter().zip([3, 5]).collect();
+        torrent.set_storage(Arc::new(MemoryStorage::new(files, 4)));
+        // the hashes of the test torrent match none of this data
+        torrent.storage().write_block(0, 0, b"abcd")?;
+        torrent.storage().write_block(1, 0, b"efgh")?;
+        torrent.piece_verified(0);
+        torrent.piece_verified(1);
+        let corrupted = |torrent: &mut Torrent| {
+            std::iter::from_fn(|| torrent.poll_event())
+                .filter(|event| matches!(event, Event::Corrupted(_)))
+                .collect::<Vec<_>>()
+        };
+
+        torrent.set_verify_uploads(true);
+        let request = BlockRequest {
+            piece: 0,
+            offset: 0,
+            length: 4,
+        };
+        assert!(torrent.read_block(&request).is_err());
+        assert_eq!(corrupted(&mut torrent), vec![Event::Corrupted(0)]);
+        assert!(!torrent.have().get(0));
+        assert_eq!(torrent.file_progress()[0].bytes_done, 0);
+
+        torrent.set_scrub_rate(4);
+        torrent.tick(Instant::now());
+        assert_eq!(corrupted(&mut torrent), vec![Event::Corrupted(1)]);
+        assert_eq!(torrent.progress(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn uploads_avoid_copies() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let mut torrent = Torrent::new(metainfo, ".");
+        torrent.force_recheck()?;
+        let block = BlockRequest {
+            piece: 0,
+            offset: 6,
+            length: 6,
+        };
+
+        // uncached pieces are sent from the file
+        assert!(matches!(
+            torrent.upload_block(&block)?,
+            UploadBlock::Files(_)
+        ));
+        torrent.read_block(&block)?;
+        match torrent.upload_block(&block)? {
+            UploadBlock::Memory { piece, range } => assert_eq!(&piece[range], b"world!"),
+            block => panic!("unexpected upload {:?}", block),
+        }
+        assert!(torrent
+            .upload_block(&BlockRequest { length: 7, ..block })
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_are_coalesced() -> Result<()> {
+        let length = 3 * BLOCK_SIZE;
+        let data = format!(
+            "d4:infod6:lengthi{}e4:name4:file12:piece lengthi{}e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+            length, length
+        );
+        let mut torrent = Torrent::new(
+            Metainfo::from_bytes(data.into_bytes())?,
+            test_dir("writes_are_coalesced"),
+        );
+        let peer = connect_seed(&mut torrent, 1);
+        let requests: Vec<_> = (0..3)
+            .map(|_| torrent.request_block(peer).unwrap())
+            .collect();
+
+        torrent.block_received(peer, &requests[2], &[2; BLOCK_SIZE as usize])?;
+        torrent.block_received(peer, &requests[0], &[0; BLOCK_SIZE as usize])?;
+        assert_eq!(torrent.poll_disk_job(), None);
+        torrent.flush_writes();
+        let offsets: Vec<_> = std::iter::from_fn(|| torrent.poll_disk_job())
+            .map(|job| match job {
+                DiskJob::Write { offset, data, .. } => (offset, data.len() as u32),
+                job => panic!("unexpected job {:?}", job),
+            })
+            .collect();
+        assert_eq!(offsets, [(0, BLOCK_SIZE), (2 * BLOCK_SIZE, BLOCK_SIZE)]);
+
+        torrent.block_received(peer, &requests[1], &[1; BLOCK_SIZE as usize])?;
+        assert!(matches!(
+            torrent.poll_disk_job(),
+            Some(DiskJob::Write { offset, .. }) if offset == BLOCK_SIZE
+        ));
+        assert_eq!(torrent.disk_queue(), length as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn completed_files_are_moved() -> Result<()> {
+        let metainfo = two_piece_torrent()?.metainfo().clone();
+        let mut torrent = Torrent::new(metainfo, test_dir("completed_files_are_moved"));
+        torrent.set_incomplete_storage(IncompleteStorage::PartSuffix)?;
+        let dir = torrent.save_path().join("dir");
+        assert_eq!(torrent.file_paths()[0], dir.join("a.part"));
+
+        let peer = connect_seed(&mut torrent, 1);
+        let request = std::iter::from_fn(|| torrent.request_block(peer))
+            .find(|request| request.piece == 0)
+            .unwrap();
+        torrent.block_received(peer, &request, b"abcd")?;
+        torrent.piece_verified(0);
+        let moved = |torrent: &mut Torrent| {
+            std::iter::from_fn(|| torrent.poll_event())
+                .filter(|event| {
+                    matches!(event, Event::FileMoved { .. } | Event::FileCompleted { .. })
+                })
+                .collect::<Vec<_>>()
+        };
+        // the move waits for the piece to be written
+        assert_eq!(moved(&mut torrent), vec![]);
+        assert!(torrent.has_unwritten_data());
+        flush_disk(&mut torrent);
+
+        assert_eq!(
+            moved(&mut torrent),
+            vec![
+                Event::FileMoved {
+                    file: 0,
+                    path: dir.join("a")
+                },
+                Event::FileCompleted {
+                    file: 0,
+                    path: dir.join("a")
+                }
+            ]
+        );
+        assert!(!torrent.has_unwritten_data());
+        assert_eq!(std::fs::read(dir.join("a"))?, b"abc");
+        assert!(dir.join("b.part").exists() && !dir.join("b").exists());
+        assert_eq!(torrent.storage().read_block(0, 0, 4)?, b"abcd");
+        Ok(())
+    }
+
+    #[test]
+    fn storage_is_moved() -> Result<()> {
+        let metainfo = two_piece_torrent()?.metainfo().clone();
+        let old = test_dir("storage_is_moved_old");
+        let new = test_dir("storage_is_moved_new");
+        let torrent = Torrent::new(metainfo, &old);
+        torrent.storage().write_block(0, 0, b"abcd")?;
+        torrent.storage().write_block(1, 0, b"efgh")?;
+
+        let handle = TorrentHandle::new(torrent);
+        handle.move_storage(&new)?;
+        let mut torrent = handle.inner.lock().unwrap();
+        let events: Vec<_> = std::iter::from_fn(|| torrent.poll_event()).collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::MovingStorage { moved: 1, total: 2 },
+                Event::MovingStorage { moved: 2, total: 2 },
+                Event::StorageMoved(new.clone()),
+            ]
+        );
+        assert_eq!(std::fs::read(new.join("dir").join("b"))?, b"defgh");
+        assert!(!old.join("dir").exists());
+        assert_eq!(torrent.storage().read_block(0, 0, 4)?, b"abcd");
+        assert_eq!(torrent.final_paths()[0], new.join("dir").join("a"));
+        std::fs::remove_dir_all(new)?;
+        Ok(())
+    }
+
+    #[test]
+    fn moves_never_replace_existing_files() -> Result<()> {
+        let metainfo = two_piece_torrent()?.metainfo().clone();
+        let old = test_dir("moves_never_replace_old");
+        let new = test_dir("moves_never_replace_new");
+        std::fs::create_dir_all(new.join("dir"))?;
+        std::fs::write(new.join("dir").join("b"), b"mine")?;
+        let mut torrent = Torrent::new(metainfo, &old);
+        torrent.storage().write_block(0, 0, b"abcd")?;
+        torrent.storage().write_block(1, 0, b"efgh")?;
+
+        assert!(torrent.move_storage(&new).is_err());
+        assert_eq!(std::fs::read(new.join("dir").join("b"))?, b"mine");
+        assert!(!new.join("dir").join("a").exists());
+        assert_eq!(torrent.storage().read_block(1, 0, 4)?, b"efgh");
+        assert!(torrent.rename_file(0, "b").is_err());
+        assert_eq!(torrent.storage().read_block(0, 0, 4)?, b"abcd");
+        std::fs::remove_dir_all(old)?;
+        std::fs::remove_dir_all(new)?;
+        Ok(())
+    }
+
+    #[test]
+    fn renamed_files_are_resumed() -> Result<()> {
+        let metainfo = two_piece_torrent()?.metainfo().clone();
+        let dir = test_dir("renamed_files_are_resumed");
+        let mut torrent = Torrent::new(metainfo.clone(), &dir);
+        torrent.storage().write_block(0, 0, b"abcd")?;
+
+        assert!(torrent.rename_file(0, "../escape").is_err());
+        torrent.rename_file(0, Path::new("sub").join("c"))?;
+        torrent.rename_root("renamed")?;
+        let a = dir.join("renamed").join("sub").join("c");
+        assert_eq!(std::fs::read(&a)?, b"abc");
+        assert!(!dir.join("dir").exists());
+        assert_eq!(torrent.file_paths()[0], a);
+        torrent.save_resume_data(&dir)?;
+
+        let mut restored = Torrent::new(metainfo, &dir);
+        assert!(restored.load_resume_data(&dir)?);
+        assert_eq!(restored.root_name(), "renamed");
+        assert_eq!(restored.file_paths(), torrent.file_paths());
+        assert_eq!(restored.storage().read_block(0, 0, 3)?, b"abc");
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn partial_piece_survives_restart() -> Result<()> {
+        let length = BLOCK_SIZE + 100;
+        let data = format!(
+            "d4:infod6:lengthi{}e4:name4:file12:piece lengthi{}e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+            length,
+            2 * BLOCK_SIZE
+        );
+        let metainfo = Metainfo::from_bytes(data.into_bytes())?;
+        let dir = test_dir("partial_piece_survives_restart");
+
+        let mut torrent = Torrent::new(metainfo.clone(), &dir);
+        let peer = connect_seed(&mut torrent, 1);
+        let first = torrent.request_block(peer).unwrap();
+        torrent.block_received(peer, &first, &vec![1; BLOCK_SIZE as usize])?;
+        assert_eq!(torrent.resume_data().partial_pieces, vec![]);
+        flush_disk(&mut torrent);
+        // written but not durable yet
+        assert_eq!(torrent.resume_data().partial_pieces, vec![]);
+        torrent.sync()?;
+        torrent.save_resume_data(&dir)?;
+
+        let mut restored = Torrent::new(metainfo, &dir);
+        assert!(restored.load_resume_data(&dir)?);
+        let peer = connect_seed(&mut restored, 1);
+        assert_eq!(
+            restored.request_block(peer).map(|r| r.offset),
+            Some(BLOCK_SIZE)
+        );
+        assert_eq!(restored.request_block(peer), None);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn recheck_existing_files() -> Result<()> {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        let mut torrent = Torrent::new(metainfo, ".");
+
+        torrent.restore(Some(Path::new("no_resume_dir")))?;
+        assert!(torrent.is_finished());
+        assert_eq!(torrent.state(), TorrentState::Seeding);
+        assert_eq!(
+            std::iter::from_fn(|| torrent.poll_event()).collect::<Vec<_>>(),
+            [
+                Event::StateChanged(TorrentState::CheckingFiles),
+                Event::Checking {
+                    checked: 1,
+                    total: 1
+                },
+                Event::Finished,
+                Event::StateChanged(TorrentState::Seeding),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn v2_blocks_are_verified_on_arrival() -> Result<()> {
+        let data: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let blocks = merkle::block_hashes(&data);
+        let mut torrent = v2_torrent(&data)?;
+        torrent.set_picker(Box::new(crate::picker::Sequential));
+        let first = connect_seed(&mut torrent, 6881);
+        let hash_request = |torrent: &mut Torrent| loop {
+            match torrent.poll_peer_message(first) {
+                Some(Message::HashRequest(request)) => return request,
+                Some(_) => continue,
+                None => panic!("no hash request"),
+            }
+        };
+
+        // nothing can be verified before the piece layer arrives
+        assert_eq!(torrent.request_block(first), None);
+        let request = hash_request(&mut torrent);
+        assert_eq!((request.base_layer, request.length), (1, 2));
+        let layer: Vec<_> = blocks.chunks(2).map(|pair| merkle::root(pair, 2)).collect();
+        assert!(torrent
+            .hashes_received(first, request, &[layer[1], layer[0]])
+            .is_err());
+        assert_eq!(torrent.request_block(first), None);
+        assert_eq!(hash_request(&mut torrent), request);
+        torrent.hashes_received(first, request, &layer)?;
+
+        let block = torrent.request_block(first).unwrap();
+        let request = hash_request(&mut torrent);
+        assert_eq!((request.base_layer, request.index), (0, 0));
+        torrent.hashes_received(first, request, &blocks[..2])?;
+
+        // the corrupt block is requested again from another peer
+        assert_eq!(torrent.block_received(first, &block, &[0; 16384])?, None);
+        assert_eq!(torrent.wasted(), 16384);
+        let second = connect_seed(&mut torrent, 6882);
+        assert_eq!(torrent.request_block(second), Some(block));
+        assert_eq!(
+            torrent.block_received(second, &block, &data[..16384])?,
+            None
+        );
+        let block = torrent.request_block(second).unwrap();
+        let completed = torrent
+            .block_received(second, &block, &data[16384..32768])?
+            .unwrap();
+
+        let verifier = Verifier::new(1);
+        verifier.submit(torrent.verify_job(&completed));
+        torrent.verification_done(verifier.recv().unwrap());
+        assert!(torrent.have().get(0));
+        Ok(())
+    }
+
+    #[test]
+    fn answers_hash_requests() -> Result<()> {
+        let data: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let blocks = merkle::block_hashes(&data);
+        let layer: Vec<_> = blocks.chunks(2).map(|pair| merkle::root(pair, 2)).collect();
+        let mut torrent = v2_torrent(&data)?;
+        let peer = connect_seed(&mut torrent, 6881);
+        let answer = |torrent: &mut Torrent, request| {
+            torrent.hash_requested(peer, request);
+            std::iter::from_fn(|| torrent.poll_peer_message(peer))
+                .find(|message| matches!(message, Message::Hashes { .. } | Message::HashReject(_)))
+        };
+
+        assert_eq!(torrent.request_block(peer), None);
+        let request = std::iter::from_fn(|| torrent.poll_peer_message(peer))
+            .find_map(|message| match message {
+                Message::HashRequest(request) => Some(request),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            answer(&mut torrent, request),
+            Some(Message::HashReject(request))
+        );
+        torrent.hashes_received(peer, request, &layer)?;
+        assert_eq!(
+            answer(&mut torrent, request),
+            Some(Message::Hashes {
+                request,
+                hashes: layer.clone()
+            })
+        );
+        let second = HashRequest {
+            index: 1,
+            length: 1,
+            proof_layers: 1,
+            ..request
+        };
+        assert_eq!(
+            answer(&mut torrent, second),
+            Some(Message::Hashes {
+                request: second,
+                hashes: vec![layer[1], layer[0]]
+            })
+        );
+        // the leaves are not kept
+        let leaves = HashRequest {
+            base_layer: 0,
+            ..request
+        };
+        assert_eq!(
+            answer(&mut torrent, leaves),
+            Some(Message::HashReject(leaves))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unchokes_the_best_peers() {
+        let metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent").unwrap()).unwrap();
+        let mut torrent = Torrent::new(metainfo, test_dir("unchokes_the_best_peers"));
+        let peers: Vec<_> = (1..=6)
+            .map(|port| {
+                let addr = SocketAddr::from(([127, 0, 0, 1], port));
+                torrent.peer_connected(addr, PeerSource::Tracker);
+                torrent.set_peer_download_rate(addr, port as u64 * 100);
+                torrent.set_peer_interested(addr, true);
+                addr
+            })
+            .collect();
+        let unchoked = |torrent: &Torrent| -> Vec<_> {
+            peers
+                .iter()
+                .copied()
+                .filter(|addr| !torrent.peers()[addr].am_choking)
+                .collect()
+        };
+        // the free slots are taken as peers get interested
+        assert_eq!(unchoked(&torrent), peers[..UPLOAD_SLOTS]);
+        assert_eq!(torrent.poll_peer_message(peers[0]), Some(Message::Unchoke));
+        assert_eq!(torrent.poll_peer_message(peers[5]), None);
+
+        // then the fastest ones keep theirs, with one more at random
+        let now = Instant::now();
+        torrent.tick(now);
+        let unchoked = unchoked(&torrent);
+        assert_eq!(unchoked.len(), UPLOAD_SLOTS);
+        assert!(unchoked.ends_with(&peers[3..]));
+        assert_eq!(torrent.poll_peer_message(peers[5]), Some(Message::Unchoke));
+        let optimistic = torrent.optimistic.unwrap().0;
+        assert!(peers[..3].contains(&optimistic));
+        for addr in &peers[..3] {
+            if *addr != optimistic {
+                let last = std::iter::from_fn(|| torrent.poll_peer_message(*addr)).last();
+                assert_eq!(last, Some(Message::Choke));
+            }
+        }
+
+        // the optimistic unchoke lasts until its time is up
+        torrent.tick(now + RECHOKE_INTERVAL);
+        assert_eq!(torrent.optimistic.unwrap().0, optimistic);
+        torrent.set_peer_interested(optimistic, false);
+        torrent.tick(now + RECHOKE_INTERVAL * 2);
+        assert_ne!(torrent.optimistic.unwrap().0, optimistic);
+    }
+}
@@ -0,0 +1,1227 @@
+use crate::bencode::{Bencode, Parser};
+use crate::dht::krpc::decode_peer;
+use crate::dht::routing::random_bytes;
+use crate::events::{Alert, EventBus, SessionEvent};
+use crate::listen::{self, IpFamilies};
+use crate::metainfo::{to_hex, url_encode};
+use crate::peer::PeerSource;
+use crate::runtime;
+use crate::torrent::TorrentHandle;
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time;
+
+/// connecting to and hearing back from a tracker
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// for a whole http request, redirects included
+const HTTP_DEADLINE: Duration = Duration::from_secs(60);
+/// blocklists and feeds are the largest answers expected
+const MAX_HTTP_BODY: usize = 64 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+/// when the tracker doesn't say
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// trackers asking for less are announced to this often anyway
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// after a failed announce, doubled for each failure in a row
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const NUM_WANT: u32 = 50;
+/// magic number of the connect request of udp trackers (BEP 15)
+const UDP_PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// regular announce
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn name(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    fn udp_action(self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    /// where we accept peer connections
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// bytes we still want
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub num_want: u32,
+    /// the addresses of the tracker that are tried
+    pub families: IpFamilies,
+    /// ours, sent to http trackers so they learn both when they are
+    /// reached over one family (BEP 7)
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    /// until the next announce
+    pub interval: Duration,
+    pub peers: Vec<SocketAddr>,
+    pub seeders: u64,
+    pub leechers: u64,
+    pub completed: u64,
+}
+
+/// announces to an http or udp tracker, blocking until it answers
+pub fn announce(url: &str, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+    if is_http_url(url) {
+        http_announce(url, request)
+    } else if let Some(host) = url.strip_prefix("udp://") {
+        // udp://host:port/announce
+        let host = host.split('/').next().unwrap_or(host);
+        udp_announce(host, request)
+    } else {
+        bail!("unsupported tracker {}", url)
+    }
+}
+
+/// the query string of an http announce
+pub fn announce_url(url: &str, request: &AnnounceRequest) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let mut query = format!(
+        "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&numwant={}",
+        url,
+        separator,
+        url_encode(&request.info_hash),
+        url_encode(&request.peer_id),
+        request.port,
+        request.uploaded,
+        request.downloaded,
+        request.left,
+        request.num_want
+    );
+    if let Some(ip) = request.ipv4 {
+        query.push_str(&format!("&ipv4={}", ip));
+    }
+    if let Some(ip) = request.ipv6 {
+        query.push_str(&format!("&ipv6={}", url_encode(ip.to_string().as_bytes())));
+    }
+    if let Some(event) = request.event.name() {
+        query.push_str("&event=");
+        query.push_str(event);
+    }
+    query
+}
+
+fn http_announce(url: &str, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+    let url = announce_url(url, request);
+    parse_response(&http_request("GET", &url, &[], b"", request.families)?)
+}
+
+/// body of a GET over HTTP/1.0, so it isn't chunked and ends with the
+/// connection, over TLS for https urls. Redirects are followed, other
+/// answers than 2xx are errors.
+pub fn http_get(url: &str) -> Result<Vec<u8>> {
+    http_request("GET", url, &[], b"", IpFamilies::Both)
+}
+
+/// like `http_get`, sending `body` after the given headers
+pub fn http_post(url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Vec<u8>> {
+    http_request("POST", url, headers, body, IpFamilies::Both)
+}
+
+/// to the first address of the host in `families`, following up to
+/// `MAX_REDIRECTS` redirects. The whole exchange has to be done within
+/// `HTTP_DEADLINE` and the body fit in `MAX_HTTP_BODY`.
+fn http_request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    families: IpFamilies,
+) -> Result<Vec<u8>> {
+    let deadline = Instant::now() + HTTP_DEADLINE;
+    let (mut method, mut url, mut body) = (method, url.to_string(), body);
+    for _ in 0..=MAX_REDIRECTS {
+        match http_exchange(method, &url, headers, body, families, deadline)? {
+            HttpAnswer::Body(body) => return Ok(body),
+            HttpAnswer::Redirect { status, location } => {
+                url = redirect_url(&url, &location)?;
+                // the others are to be sent again as they were
+                if status == 303 {
+                    method = "GET";
+                    body = b"";
+                }
+            }
+        }
+    }
+    bail!("more than {} redirects", MAX_REDIRECTS)
+}
+
+/// What a server answered with, other statuses being errors
+enum HttpAnswer {
+    Body(Vec<u8>),
+    Redirect { status: u16, location: String },
+}
+
+fn http_exchange(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    families: IpFamilies,
+    deadline: Instant,
+) -> Result<HttpAnswer> {
+    let HttpUrl {
+        tls,
+        host,
+        name,
+        port,
+        path,
+    } = parse_http_url(url)?;
+    let addr = families
+        .pick((name, port).to_socket_addrs()?)
+        .ok_or_else(|| anyhow!("host {} not found", host))?;
+    let left = || {
+        deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+            .map(|left| left.min(TIMEOUT))
+            .ok_or_else(|| anyhow!("{} took too long to answer", host))
+    };
+    let socket = TcpStream::connect_timeout(&addr, left()?)?;
+    socket.set_write_timeout(Some(left()?))?;
+    let mut stream = match tls {
+        true => {
+            let name = ServerName::try_from(name.to_string())
+                .with_context(|| format!("invalid host {}", host))?;
+            let connection = ClientConnection::new(tls_config(), name)?;
+            HttpStream::Tls(Box::new(StreamOwned::new(connection, socket)))
+        }
+        false => HttpStream::Plain(socket),
+    };
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n",
+        method, path, host
+    );
+    for (name, value) in headers {
+        request += &format!("{}: {}\r\n", name, value);
+    }
+    if !body.is_empty() {
+        request += &format!("Content-Length: {}\r\n", body.len());
+    }
+    request += "\r\n";
+    stream.write_all(&[request.as_bytes(), body].concat())?;
+    let mut response = vec![];
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        stream.socket().set_read_timeout(Some(left()?))?;
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => response.extend_from_slice(&buffer[..read]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            // servers often close the connection without telling TLS first
+            Err(error) if tls && error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error.into()),
+        }
+        // with room for the headers
+        if response.len() > MAX_HTTP_BODY + 64 * 1024 {
+            bail!("the answer of {} is over {} bytes", host, MAX_HTTP_BODY);
+        }
+    }
+
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("invalid http response"))?;
+    let head = String::from_utf8_lossy(&response[..header_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    let status = status.split(' ').nth(1).unwrap_or_default();
+    match status.parse::<u16>() {
+        Ok(status @ (301 | 302 | 303 | 307 | 308)) => {
+            let location = lines
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+                .map(|(_, location)| location.trim().to_string())
+                .ok_or_else(|| anyhow!("{} redirected without a location", host))?;
+            Ok(HttpAnswer::Redirect { status, location })
+        }
+        Ok(200..=299) => {
+            let body = response.split_off(header_end + 4);
+            if body.len() > MAX_HTTP_BODY {
+                bail!("the answer of {} is over {} bytes", host, MAX_HTTP_BODY);
+            }
+            Ok(HttpAnswer::Body(body))
+        }
+        _ => bail!("{} answered with status {}", host, status),
+    }
+}
+
+/// whether `url` can be fetched with `http_get`
+pub fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Where an http or https url points to
+#[derive(Debug, PartialEq, Eq)]
+struct HttpUrl<'a> {
+    tls: bool,
+    /// with the port if the url has one, as sent in the Host header
+    host: &'a str,
+    /// without the port nor the brackets of ipv6 addresses, as checked
+    /// against the certificate of https servers
+    name: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+fn parse_http_url(url: &str) -> Result<HttpUrl<'_>> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else {
+        bail!("unsupported url {}", url)
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (
+            name,
+            port.parse()
+                .with_context(|| format!("invalid port in {}", url))?,
+        ),
+        _ => (host, if tls { 443 } else { 80 }),
+    };
+    Ok(HttpUrl {
+        tls,
+        host,
+        name: name.trim_start_matches('[').trim_end_matches(']'),
+        port,
+        path,
+    })
+}
+
+/// A connection to a web server, encrypted for https
+enum HttpStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl HttpStream {
+    fn socket(&self) -> &TcpStream {
+        match self {
+            HttpStream::Plain(socket) => socket,
+            HttpStream::Tls(stream) => &stream.sock,
+        }
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HttpStream::Plain(socket) => socket.read(buffer),
+            HttpStream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for HttpStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            HttpStream::Plain(socket) => socket.write(data),
+            HttpStream::Tls(stream) => stream.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            HttpStream::Plain(socket) => socket.flush(),
+            HttpStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// trusting the root certificates of Mozilla, built once
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    });
+    Arc::clone(config)
+}
+
+/// `location` as an absolute url, relative to the url that was redirected
+fn redirect_url(url: &str, location: &str) -> Result<String> {
+    if location.contains("://") {
+        return Ok(location.to_string());
+    }
+    let scheme_end = url.find("://").map_or(0, |index| index + 3);
+    let origin_end = url[scheme_end..]
+        .find('/')
+        .map_or(url.len(), |index| scheme_end + index);
+    match location.strip_prefix('/') {
+        Some(_) => Ok(format!("{}{}", &url[..origin_end], location)),
+        None => {
+            let path = url[origin_end..]
+                .split(['?', '#'])
+                .next()
+                .unwrap_or_default();
+            let dir = &path[..path.rfind('/').map_or(0, |index| index + 1)];
+            let dir = if dir.is_empty() { "/" } else { dir };
+            Ok(format!("{}{}{}", &url[..origin_end], dir, location))
+        }
+    }
+}
+
+/// the bencoded body of an http announce response
+pub fn parse_response(body: &[u8]) -> Result<AnnounceResponse> {
+    let bencode = Parser::new(body.to_vec()).parse()?;
+    if let Some(reason) = bencode.get("failure reason").and_then(Bencode::as_str) {
+        bail!("tracker error: {}", reason);
+    }
+    let integer = |key| {
+        bencode
+            .get(key)
+            .and_then(Bencode::as_integer)
+            .map(|value| value.max(0) as u64)
+    };
+    let mut peers = vec![];
+    match bencode.get("peers") {
+        Some(Bencode::Bytes(compact)) => {
+            peers.extend(compact.chunks_exact(6).filter_map(decode_peer));
+        }
+        Some(Bencode::List(list)) => {
+            peers.extend(list.iter().filter_map(|peer| {
+                let ip = peer.get("ip")?.as_str()?.parse().ok()?;
+                let port = peer.get("port")?.as_integer()?;
+                Some(SocketAddr::new(ip, port as u16))
+            }));
+        }
+        _ => {}
+    }
+    if let Some(compact) = bencode.get("peers6").and_then(Bencode::as_bytes) {
+        peers.extend(compact.chunks_exact(18).filter_map(decode_peer));
+    }
+    peers.retain(|peer| peer.port() != 0);
+    Ok(AnnounceResponse {
+        interval: integer("interval").map_or(DEFAULT_INTERVAL, Duration::from_secs),
+        peers,
+        seeders: integer("complete").unwrap_or(0),
+        leechers: integer("incomplete").unwrap_or(0),
+        completed: integer("downloaded").unwrap_or(0),
+    })
+}
+
+/// connects then announces (BEP 15), the connection id isn't reused
+fn udp_announce(host: &str, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+    let addr = request
+        .families
+        .pick(host.to_socket_addrs()?)
+        .ok_or_else(|| anyhow!("tracker {} not found", host))?;
+    let socket = UdpSocket::bind(if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+    socket.connect(addr)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+
+    let transaction = transaction_id();
+    let mut packet = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&transaction.to_be_bytes());
+    let response = udp_exchange(&socket, &packet, 0, transaction)?;
+    if response.len() < 16 {
+        bail!("invalid connect response");
+    }
+    let connection_id = &response[8..16];
+
+    let transaction = transaction_id();
+    let mut packet = connection_id.to_vec();
+    packet.extend_from_slice(&1u32.to_be_bytes());
+    packet.extend_from_slice(&transaction.to_be_bytes());
+    packet.extend_from_slice(&request.info_hash);
+    packet.extend_from_slice(&request.peer_id);
+    packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    packet.extend_from_slice(&request.event.udp_action().to_be_bytes());
+    // ip, the one the packet comes from, and key
+    packet.extend_from_slice(&[0; 8]);
+    packet.extend_from_slice(&(request.num_want as i32).to_be_bytes());
+    packet.extend_from_slice(&request.port.to_be_bytes());
+    let response = udp_exchange(&socket, &packet, 1, transaction)?;
+    if response.len() < 20 {
+        bail!("invalid announce response");
+    }
+    let word = |offset: usize| {
+        u32::from_be_bytes([
+            response[offset],
+            response[offset + 1],
+            response[offset + 2],
+            response[offset + 3],
+        ])
+    };
+    let peer_len = if addr.is_ipv4() { 6 } else { 18 };
+    Ok(AnnounceResponse {
+        interval: Duration::from_secs(word(8) as u64),
+        peers: response[20..]
+            .chunks_exact(peer_len)
+            .filter_map(decode_peer)
+            .filter(|peer| peer.port() != 0)
+            .collect(),
+        leechers: word(12) as u64,
+        seeders: word(16) as u64,
+        completed: 0,
+    })
+}
+
+/// sends the packet and waits for the response to it, failing on an error
+/// from the tracker
+fn udp_exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    action: u32,
+    transaction: u32,
+) -> Result<Vec<u8>> {
+    socket.send(packet)?;
+    let mut buffer = [0; 2048];
+    loop {
+        let length = socket.recv(&mut buffer).context("tracker didn't answer")?;
+        let response = &buffer[..length];
+        if length < 8 || response[4..8] != transaction.to_be_bytes() {
+            continue;
+        }
+        match u32::from_be_bytes([response[0], response[1], response[2], response[3]]) {
+            3 => bail!("tracker error: {}", String::from_utf8_lossy(&response[8..])),
+            received if received == action => return Ok(response.to_vec()),
+            _ => bail!("unexpected tracker response"),
+        }
+    }
+}
+
+fn transaction_id() -> u32 {
+    let mut bytes = [0; 4];
+    random_bytes(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
+
+/// When one tracker of a torrent is announced to next
+#[derive(Debug, Clone)]
+struct Tracker {
+    url: String,
+    next_announce: Instant,
+    started: bool,
+    /// failed announces in a row
+    failures: u32,
+}
+
+impl Tracker {
+    fn new(url: String, now: Instant) -> Self {
+        Self {
+            url,
+            next_announce: now,
+            started: false,
+            failures: 0,
+        }
+    }
+}
+
+struct TrackedTorrent {
+    handle: TorrentHandle,
+    port: u16,
+    trackers: Vec<Tracker>,
+    /// whether the trackers were told the torrent completed
+    completed: bool,
+}
+
+impl TrackedTorrent {
+    /// to the trackers that heard from us
+    fn stopped_announces(
+        &self,
+        peer_id: [u8; 20],
+        info_hash: [u8; 20],
+    ) -> Vec<(String, AnnounceRequest)> {
+        self.trackers
+            .iter()
+            .filter(|tracker| tracker.started)
+            .map(|tracker| {
+                let request = announce_request(peer_id, info_hash, self, AnnounceEvent::Stopped);
+                (tracker.url.clone(), request)
+            })
+            .collect()
+    }
+}
+
+/// Announces the torrents added with `add_torrent` to their trackers from a
+/// task of the runtime and hands them the peers the trackers return,
+/// reporting each announce on the event bus. Announces block, they run on
+/// the blocking threads of the runtime, all trackers at once.
+pub struct TrackerTask {
+    peer_id: [u8; 20],
+    torrents: Arc<Mutex<HashMap<[u8; 20], TrackedTorrent>>>,
+    events: EventBus,
+    stop: watch::Sender<bool>,
+    /// disconnected once the task is done
+    done: mpsc::Receiver<()>,
+}
+
+impl TrackerTask {
+    pub fn start(peer_id: [u8; 20], events: EventBus) -> Self {
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        let (stop, stopped) = watch::channel(false);
+        let (finished, done) = mpsc::channel();
+        runtime::handle().spawn(run(
+            peer_id,
+            Arc::clone(&torrents),
+            events.clone(),
+            stopped,
+            finished,
+        ));
+        Self {
+            peer_id,
+            torrents,
+            events,
+            stop,
+            done,
+        }
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// announces the torrent to its trackers until it is removed, false if
+    /// it has none
+    pub fn add_torrent(&self, torrent: &TorrentHandle, port: u16) -> bool {
+        let now = Instant::now();
+        let trackers: Vec<_> = torrent
+            .announce_urls()
+            .into_iter()
+            .map(|url| Tracker::new(url, now))
+            .collect();
+        if trackers.is_empty() {
+            return false;
+        }
+        self.torrents.lock().unwrap().insert(
+            torrent.info_hash(),
+            TrackedTorrent {
+                handle: torrent.clone(),
+                port,
+                trackers,
+                completed: torrent.is_finished(),
+            },
+        );
+        true
+    }
+
+    /// stops announcing the torrent, the trackers that heard from us are
+    /// told it stopped in the background
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        let torrent = match self.torrents.lock().unwrap().remove(info_hash) {
+            Some(torrent) => torrent,
+            None => return,
+        };
+        let announces = torrent.stopped_announces(self.peer_id, *info_hash);
+        let events = self.events.clone();
+        runtime::handle().spawn_blocking(move || {
+            for (url, request) in announces {
+                if let Err(error) = announce(&url, &request) {
+                    events.send(SessionEvent::TrackerError {
+                        info_hash: request.info_hash,
+                        url,
+                        error: error.to_string(),
+                    });
+                }
+            }
+        });
+    }
+
+    /// stops announcing and tells the trackers that heard from us that we
+    /// stopped, all at once. Announces still running at `deadline` are
+    /// abandoned and reported as an error.
+    pub fn shutdown(self, deadline: Instant) -> Result<()> {
+        let _ = self.stop.send(true);
+        let _ = self.done.recv();
+        let (sender, results) = mpsc::channel();
+        let mut pending = 0;
+        for (info_hash, torrent) in self.torrents.lock().unwrap().iter() {
+            for (url, request) in torrent.stopped_announces(self.peer_id, *info_hash) {
+                let sender = sender.clone();
+                runtime::handle().spawn_blocking(move || {
+                    let result = announce(&url, &request);
+                    let _ = sender.send((request.info_hash, url, result));
+                });
+                pending += 1;
+            }
+        }
+        while pending > 0 {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let (info_hash, url, result) = match results.recv_timeout(timeout) {
+                Ok(result) => result,
+                Err(_) => bail!("{} trackers didn't answer in time", pending),
+            };
+            pending -= 1;
+            if let Err(error) = result {
+                self.events.send(SessionEvent::TrackerError {
+                    info_hash,
+                    url,
+                    error: error.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn announce_request(
+    peer_id: [u8; 20],
+    info_hash: [u8; 20],
+    torrent: &TrackedTorrent,
+    event: AnnounceEvent,
+) -> AnnounceRequest {
+    let stats = torrent.handle.stats();
+    let progress = torrent.handle.progress_report();
+    let families = torrent.handle.ip_families();
+    AnnounceRequest {
+        info_hash,
+        peer_id,
+        port: torrent.port,
+        uploaded: stats.uploaded,
+        downloaded: stats.downloaded,
+        left: progress.bytes_wanted - progress.bytes_done,
+        event,
+        num_want: NUM_WANT,
+        families,
+        ipv4: families
+            .allows(Ipv4Addr::UNSPECIFIED.into())
+            .then(listen::public_ipv4)
+            .flatten(),
+        ipv6: families
+            .allows(Ipv6Addr::UNSPECIFIED.into())
+            .then(listen::public_ipv6)
+            .flatten(),
+    }
+}
+
+impl Drop for TrackerTask {
+    /// the announces already running finish on their own
+    fn drop(&mut self) {
+        let _ = self.stop.send(true);
+        let _ = self.done.recv();
+    }
+}
+
+/// announces the torrents as they are due until stopped
+async fn run(
+    peer_id: [u8; 20],
+    torrents: Arc<Mutex<HashMap<[u8; 20], TrackedTorrent>>>,
+    events: EventBus,
+    mut stop: watch::Receiver<bool>,
+    done: mpsc::Sender<()>,
+) {
+    let mut announcing = JoinSet::new();
+    let mut ticks = time::interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            _ = stop.changed() => break,
+            Some(announced) = announcing.join_next() => {
+                if let Ok((url, request, result)) = announced {
+                    record(&torrents, &events, url, &request, result);
+                }
+            }
+            _ = ticks.tick() => {
+                for (url, request) in due_announces(peer_id, &torrents, Instant::now()) {
+                    announcing.spawn_blocking(move || {
+                        let span = tracing::info_span!(
+                            "announce",
+                            info_hash = %to_hex(&request.info_hash),
+                            %url
+                        );
+                        let result = span.in_scope(|| announce(&url, &request));
+                        (url, request, result)
+                    });
+                }
+            }
+        }
+    }
+    // the announces still running finish unseen
+    announcing.detach_all();
+    drop(done);
+}
+
+/// the announces due at `now`, their trackers aren't due again until they
+/// are done
+fn due_announces(
+    peer_id: [u8; 20],
+    torrents: &Mutex<HashMap<[u8; 20], TrackedTorrent>>,
+    now: Instant,
+) -> Vec<(String, AnnounceRequest)> {
+    let mut torrents = torrents.lock().unwrap();
+    let mut due = vec![];
+    for (info_hash, torrent) in torrents.iter_mut() {
+        let paused = torrent.handle.is_paused();
+        let finished = !paused && torrent.handle.is_finished();
+        let completed = finished && !torrent.completed;
+        torrent.completed |= finished;
+        let mut announces = vec![];
+        for tracker in &mut torrent.trackers {
+            // paused torrents tell their trackers they stopped and
+            // start over once resumed
+            let event = if paused {
+                if !tracker.started {
+                    continue;
+                }
+                tracker.started = false;
+                AnnounceEvent::Stopped
+            } else if tracker.next_announce > now && !(completed && tracker.started) {
+                continue;
+            } else if !tracker.started {
+                AnnounceEvent::Started
+            } else if completed {
+                AnnounceEvent::Completed
+            } else {
+                AnnounceEvent::None
+            };
+            // announced again once this one is done
+            tracker.next_announce = now + DEFAULT_INTERVAL;
+            announces.push((tracker.url.clone(), event));
+        }
+        for (url, event) in announces {
+            due.push((url, announce_request(peer_id, *info_hash, torrent, event)));
+        }
+    }
+    due
+}
+
+/// hands the peers to the torrent and schedules the next announce
+fn record(
+    torrents: &Mutex<HashMap<[u8; 20], TrackedTorrent>>,
+    events: &EventBus,
+    url: String,
+    request: &AnnounceRequest,
+    result: Result<AnnounceResponse>,
+) {
+    let span = tracing::info_span!("announce", info_hash = %to_hex(&request.info_hash), %url);
+    let _entered = span.enter();
+    let mut torrents = torrents.lock().unwrap();
+    let torrent = match torrents.get_mut(&request.info_hash) {
+        Some(torrent) => torrent,
+        None => return,
+    };
+    let tracker = match torrent
+        .trackers
+        .iter_mut()
+        .find(|tracker| tracker.url == url)
+    {
+        Some(tracker) => tracker,
+        None => return,
+    };
+    let stopped = request.event == AnnounceEvent::Stopped;
+    match result {
+        Ok(response) => {
+            tracing::debug!(
+                event = %request.event.name().unwrap_or("none"),
+                peers = response.peers.len(),
+                interval = response.interval.as_secs(),
+                "announced"
+            );
+            tracker.started = !stopped;
+            tracker.failures = 0;
+            tracker.next_announce = match stopped {
+                // started again as soon as the torrent is resumed
+                true => Instant::now(),
+                false => Instant::now() + response.interval.max(MIN_INTERVAL),
+            };
+            torrent
+                .handle
+                .add_peers(response.peers.iter().copied(), PeerSource::Tracker);
+            torrent.handle.tracker_announced(&url, &response);
+            events.send(SessionEvent::TrackerAnnounced {
+                info_hash: request.info_hash,
+                url,
+                peers: response.peers.len(),
+            });
+        }
+        Err(error) if stopped => {
+            tracing::warn!(
+                error = format_args!("{:#}", error),
+                "stopped announce failed"
+            );
+            tracker.next_announce = Instant::now();
+            torrent.handle.raise_alert(Alert::TrackerFailed {
+                url: url.clone(),
+                error: error.to_string(),
+            });
+            events.send(SessionEvent::TrackerError {
+                info_hash: request.info_hash,
+                url,
+                error: error.to_string(),
+            });
+        }
+        Err(error) => {
+            tracker.next_announce =
+                Instant::now() + RETRY_INTERVAL * 2u32.pow(tracker.failures.min(5));
+            tracker.failures += 1;
+            tracing::warn!(
+                error = format_args!("{:#}", error),
+                failures = tracker.failures,
+                "announce failed"
+            );
+            torrent.handle.raise_alert(Alert::TrackerFailed {
+                url: url.clone(),
+                error: error.to_string(),
+            });
+            events.send(SessionEvent::TrackerError {
+                info_hash: request.info_hash,
+                url,
+                error: error.to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::Metainfo;
+    use crate::torrent::Torrent;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [0xab; 20],
+            peer_id: *b"-RS0001-aaaaaaaaaaaa",
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: AnnounceEvent::Started,
+            num_want: NUM_WANT,
+            families: IpFamilies::Both,
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    #[test]
+    fn http_announces() -> Result<()> {
+        let url = announce_url("http://tracker/announce?key=1", &request());
+        assert!(url.starts_with("http://tracker/announce?key=1&info_hash=%AB%AB"));
+        assert!(url.contains(
+            "&peer_id=-RS0001-aaaaaaaaaaaa&port=6881&uploaded=1&downloaded=2&left=3&compact=1"
+        ));
+        assert!(url.ends_with("&event=started"));
+        let url = announce_url(
+            "http://tracker/announce",
+            &AnnounceRequest {
+                ipv4: Some(Ipv4Addr::new(1, 2, 3, 4)),
+                ipv6: Some("2001:db8::1".parse()?),
+                ..request()
+            },
+        );
+        assert!(url.contains("&ipv4=1.2.3.4&ipv6=2001%3Adb8%3A%3A1&event=started"));
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = thread::spawn(move || -> Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let length = stream.read(&mut buffer)?;
+                request.extend_from_slice(&buffer[..length]);
+            }
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nd8:completei5e10:incompletei2e8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e")?;
+            Ok(String::from_utf8_lossy(&request).into_owned())
+        });
+        let response = announce(&format!("http://127.0.0.1:{}/announce", port), &request())?;
+        assert!(server
+            .join()
+            .unwrap()?
+            .starts_with("GET /announce?info_hash="));
+        assert_eq!(
+            response,
+            AnnounceResponse {
+                interval: Duration::from_secs(900),
+                peers: vec![SocketAddr::from(([127, 0, 0, 1], 6881))],
+                seeders: 5,
+                leechers: 2,
+                completed: 0,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parses_http_and_https_urls() -> Result<()> {
+        assert_eq!(
+            parse_http_url("https://example.com/feed?a=1")?,
+            HttpUrl {
+                tls: true,
+                host: "example.com",
+                name: "example.com",
+                port: 443,
+                path: "/feed?a=1",
+            }
+        );
+        assert_eq!(
+            parse_http_url("http://[::1]:8080")?,
+            HttpUrl {
+                tls: false,
+                host: "[::1]:8080",
+                name: "::1",
+                port: 8080,
+                path: "/",
+            }
+        );
+        assert_eq!(parse_http_url("http://[::1]/")?.port, 80);
+        assert!(parse_http_url("https://host:port/").is_err());
+        assert!(parse_http_url("ftp://host/").is_err());
+        assert!(is_http_url("https://host/") && !is_http_url("udp://host:1"));
+        Ok(())
+    }
+
+    #[test]
+    fn follows_redirects_up_to_a_limit() -> Result<()> {
+        assert_eq!(redirect_url("http://host/a/b?c", "d")?, "http://host/a/d");
+        assert_eq!(redirect_url("http://host", "/d")?, "http://host/d");
+        assert_eq!(
+            redirect_url("http://host/a", "http://other/")?,
+            "http://other/"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let server = thread::spawn(move || -> Result<()> {
+            let answers: Vec<&[u8]> = vec![
+                b"HTTP/1.0 302 Found\r\nLocation: /list\r\n\r\n",
+                b"HTTP/1.0 200 OK\r\n\r\nlist",
+                b"HTTP/1.0 301 Moved\r\nLocation: /loop\r\n\r\n",
+            ];
+            for (index, answer) in answers.into_iter().enumerate() {
+                let answer_count = if index == 2 { MAX_REDIRECTS + 1 } else { 1 };
+                for _ in 0..answer_count {
+                    let (mut stream, _) = listener.accept()?;
+                    let mut request = vec![];
+                    let mut buffer = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let length = stream.read(&mut buffer)?;
+                        request.extend_from_slice(&buffer[..length]);
+                    }
+                    stream.write_all(answer)?;
+                }
+            }
+            Ok(())
+        });
+        assert_eq!(http_get(&format!("{}/blocklist", url))?, b"list");
+        let error = http_get(&format!("{}/loop", url)).unwrap_err();
+        assert_eq!(error.to_string(), "more than 5 redirects");
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn parse_responses() -> Result<()> {
+        let response = parse_response(
+            b"d5:peersld2:ip9:127.0.0.14:porti6882eed2:ip3:::14:porti0eee6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe3e",
+        )?;
+        assert_eq!(response.interval, DEFAULT_INTERVAL);
+        assert_eq!(
+            response.peers,
+            vec![
+                SocketAddr::from(([127, 0, 0, 1], 6882)),
+                "[::1]:6883".parse()?
+            ]
+        );
+        let error = parse_response(b"d14:failure reason9:not founde").unwrap_err();
+        assert_eq!(error.to_string(), "tracker error: not found");
+        Ok(())
+    }
+
+    #[test]
+    fn udp_announces() -> Result<()> {
+        let tracker = UdpSocket::bind("127.0.0.1:0")?;
+        let port = tracker.local_addr()?.port();
+        let server = thread::spawn(move || -> Result<Vec<u8>> {
+            let mut buffer = [0; 1024];
+            let (length, from) = tracker.recv_from(&mut buffer)?;
+            assert_eq!(length, 16);
+            assert_eq!(buffer[..8], UDP_PROTOCOL_ID.to_be_bytes());
+            let mut response = vec![0, 0, 0, 0];
+            response.extend_from_slice(&buffer[12..16]);
+            response.extend_from_slice(&[7; 8]);
+            tracker.send_to(&response, from)?;
+
+            let (length, from) = tracker.recv_from(&mut buffer)?;
+            let mut response = vec![0, 0, 0, 1];
+            response.extend_from_slice(&buffer[12..16]);
+            response.extend_from_slice(&[0, 0, 3, 132, 0, 0, 0, 2, 0, 0, 0, 5]);
+            response.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+            tracker.send_to(&response, from)?;
+            Ok(buffer[..length].to_vec())
+        });
+        let response = announce(&format!("udp://127.0.0.1:{}/announce", port), &request())?;
+        let packet = server.join().unwrap()?;
+        assert_eq!(packet.len(), 98);
+        assert_eq!(packet[..8], [7; 8]);
+        assert_eq!(packet[16..36], [0xab; 20]);
+        // started
+        assert_eq!(packet[80..84], [0, 0, 0, 2]);
+        assert_eq!(packet[96..], [0x1a, 0xe1]);
+        assert_eq!(
+            response,
+            AnnounceResponse {
+                interval: Duration::from_secs(900),
+                peers: vec![SocketAddr::from(([127, 0, 0, 1], 6881))],
+                seeders: 5,
+                leechers: 2,
+                completed: 0,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stopped_on_shutdown() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = thread::spawn(move || -> Result<Vec<String>> {
+            let mut requests = vec![];
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept()?;
+                let mut request = vec![];
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let length = stream.read(&mut buffer)?;
+                    request.extend_from_slice(&buffer[..length]);
+                }
+                stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nd8:intervali900e5:peers0:e")?;
+                requests.push(String::from_utf8_lossy(&request).into_owned());
+            }
+            Ok(requests)
+        });
+
+        let mut metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        metainfo.announce = Some(format!("http://127.0.0.1:{}/announce", port));
+        let torrent = TorrentHandle::new(Torrent::new(metainfo, std::env::temp_dir()));
+        let events = EventBus::new();
+        let receiver = events.subscribe();
+        let task = TrackerTask::start([1; 20], events);
+        assert!(task.add_torrent(&torrent, 6881));
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(5))?,
+            SessionEvent::TrackerAnnounced { .. }
+        ));
+        task.shutdown(Instant::now() + Duration::from_secs(5))?;
+        let requests = server.join().unwrap()?;
+        assert!(requests[0].contains("&event=started"));
+        assert!(requests[1].contains("&event=stopped"));
+        Ok(())
+    }
+
+    #[test]
+    fn paused_torrents_stop_announcing() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let mut metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        metainfo.announce = Some(format!("http://127.0.0.1:{}/announce", port));
+        let torrent = TorrentHandle::new(Torrent::new(metainfo, std::env::temp_dir()));
+        let task = TrackerTask::start([1; 20], EventBus::new());
+        assert!(task.add_torrent(&torrent, 6881));
+
+        let accept = || -> Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let length = stream.read(&mut buffer)?;
+                request.extend_from_slice(&buffer[..length]);
+            }
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nd8:intervali900e5:peers0:e")?;
+            Ok(String::from_utf8_lossy(&request).into_owned())
+        };
+        assert!(accept()?.contains("&event=started"));
+        torrent.pause();
+        assert!(accept()?.contains("&event=stopped"));
+        torrent.resume();
+        assert!(accept()?.contains("&event=started"));
+        Ok(())
+    }
+
+    #[test]
+    fn failures_are_alerts() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = thread::spawn(move || -> Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let length = stream.read(&mut buffer)?;
+                request.extend_from_slice(&buffer[..length]);
+            }
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nd14:failure reason9:not founde")?;
+            Ok(())
+        });
+
+        let url = format!("http://127.0.0.1:{}/announce", port);
+        let mut metainfo = Metainfo::from_bytes(std::fs::read("file1.txt.torrent")?)?;
+        metainfo.announce = Some(url.clone());
+        let torrent = TorrentHandle::new(Torrent::new(metainfo, std::env::temp_dir()));
+        let events = EventBus::new();
+        let receiver = events.subscribe();
+        let task = TrackerTask::start([1; 20], events);
+        assert!(task.add_torrent(&torrent, 6881));
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(5))?,
+            SessionEvent::TrackerError { .. }
+        ));
+        server.join().unwrap()?;
+        match torrent.take_alerts().as_slice() {
+            [Alert::TrackerFailed { url: failed, error }] => {
+                assert_eq!(*failed, url);
+                assert!(error.contains("not found"));
+            }
+            alerts => panic!("unexpected alerts {:?}", alerts),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1020 @@
+//! The RPC protocol of Transmission, served by the api at
+//! `/transmission/rpc` so its clients like transmission-remote, Sonarr,
+//! Radarr or mobile apps work against a session unchanged. Requests are
+//! `{"method":..,"arguments":{..},"tag":..}` and answers
+//! `{"result":"success","arguments":{..},"tag":..}` or an error in `result`.
+//!
+//! The methods are session-get, session-set, session-stats, torrent-get,
+//! torrent-add, torrent-set, torrent-start, torrent-start-now,
+//! torrent-stop, torrent-verify, torrent-remove, torrent-set-location,
+//! torrent-rename-path, queue-move-top, queue-move-up, queue-move-down,
+//! queue-move-bottom and free-space. Settings and fields the session doesn't
+//! have are left out, and ignored when set, like Transmission does with
+//! those it doesn't know. Speeds are in kB/s of 1000 bytes, as told by
+//! `units`.
+
+use crate::api;
+use crate::base64;
+use crate::dht::routing::random_bytes;
+use crate::json::Json;
+use crate::magnet::Magnet;
+use crate::metainfo::{to_hex, Metainfo};
+use crate::picker::Priority;
+use crate::seeding::SeedLimits;
+use crate::session::{AddOptions, Session};
+use crate::storage;
+use crate::torrent::{TorrentHandle, TorrentState};
+use crate::tracker;
+use anyhow::{anyhow, bail, Context, Result};
+use std::cell::OnceCell;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// that of Transmission 4.0
+pub const RPC_VERSION: u64 = 17;
+pub const RPC_VERSION_MINIMUM: u64 = 14;
+/// clients repeat it from the 409 answer to their first request, which a
+/// web page posting across sites can't read
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+const SPEED_BYTES: u64 = 1000;
+
+/// What the RPC keeps between requests: torrents keep the id they were
+/// first seen with until they are removed, and ids aren't reused
+#[derive(Debug)]
+pub struct Rpc {
+    session_id: String,
+    ids: HashMap<[u8; 20], u64>,
+    next_id: u64,
+    started: Instant,
+}
+
+impl Default for Rpc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rpc {
+    pub fn new() -> Self {
+        let mut bytes = [0; 24];
+        random_bytes(&mut bytes);
+        Self {
+            session_id: to_hex(&bytes),
+            ids: HashMap::new(),
+            next_id: 1,
+            started: Instant::now(),
+        }
+    }
+
+    /// what `SESSION_ID_HEADER` has to be
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn id(&mut self, info_hash: [u8; 20]) -> u64 {
+        let next_id = &mut self.next_id;
+        *self.ids.entry(info_hash).or_insert_with(|| {
+            *next_id += 1;
+            *next_id - 1
+        })
+    }
+
+    /// answers a request, failures are in its result like Transmission
+    pub fn call(&mut self, session: &mut Session, request: &Json) -> Json {
+        let method = request
+            .get("method")
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::object());
+        let (result, arguments) = match self.method(session, method, &arguments) {
+            Ok(arguments) => ("success".to_string(), arguments),
+            Err(error) => (format!("{:#}", error), Json::object()),
+        };
+        let response = Json::object()
+            .with("arguments", arguments)
+            .with("result", result);
+        match request.get("tag") {
+            Some(tag) => response.with("tag", tag.clone()),
+            None => response,
+        }
+    }
+
+    fn method(&mut self, session: &mut Session, method: &str, args: &Json) -> Result<Json> {
+        match method {
+            "session-get" => Ok(filter_fields(self.session_get(session), args)),
+            "session-set" => {
+                session_set(session, args)?;
+                Ok(Json::object())
+            }
+            "session-stats" => Ok(self.session_stats(session)),
+            "torrent-get" => Ok(self.torrent_get(session, args)),
+            "torrent-add" => self.torrent_add(session, args),
+            "torrent-set" => {
+                for torrent in self.select(session, args)? {
+                    torrent_set(session, &torrent, args)?;
+                }
+                Ok(Json::object())
+            }
+            "torrent-start" | "torrent-start-now" | "torrent-stop" | "torrent-verify" => {
+                for torrent in self.select(session, args)? {
+                    match method {
+                        "torrent-stop" => session.pause(&torrent)?,
+                        "torrent-verify" => torrent.force_recheck()?,
+                        _ => {
+                            if method == "torrent-start-now" {
+                                torrent.queue_top();
+                            }
+                            session.resume(&torrent);
+                        }
+                    }
+                }
+                Ok(Json::object())
+            }
+            "torrent-remove" => {
+                let delete_data = optional(args, "delete-local-data", Json::as_bool)?;
+                for torrent in self.select(session, args)? {
+                    session.remove(&torrent, delete_data.unwrap_or(false))?;
+                    self.ids.remove(&torrent.info_hash());
+                }
+                Ok(Json::object())
+            }
+            "torrent-set-location" => {
+                let location = optional(args, "location", Json::as_str)?
+                    .ok_or_else(|| anyhow!("location is missing"))?;
+                if optional(args, "move", Json::as_bool)? != Some(true) {
+                    bail!("only moving the data to the location is supported");
+                }
+                for torrent in self.select(session, args)? {
+                    torrent.move_storage(location)?;
+                }
+                Ok(Json::object())
+            }
+            "torrent-rename-path" => self.rename_path(session, args),
+            "queue-move-top" | "queue-move-up" | "queue-move-down" | "queue-move-bottom" => {
+                for torrent in self.select(session, args)? {
+                    match method {
+                        "queue-move-top" => torrent.queue_top(),
+                        "queue-move-up" => torrent.queue_up(),
+                        "queue-move-down" => torrent.queue_down(),
+                        _ => torrent.queue_bottom(),
+                    }
+                }
+                Ok(Json::object())
+            }
+            "free-space" => {
+                let path = optional(args, "path", Json::as_str)?
+                    .ok_or_else(|| anyhow!("path is missing"))?;
+                let free = storage::free_space(Path::new(path))?.map(|(_, free)| free as i64);
+                Ok(Json::object()
+                    .with("path", path)
+                    .with("size-bytes", free.unwrap_or(-1)))
+            }
+            _ => bail!("method name not recognized"),
+        }
+    }
+
+    /// the torrents of `ids`, every one when it's left out: an id, an info
+    /// hash, an array of them, or "recently-active" for the active ones
+    fn select(&mut self, session: &Session, args: &Json) -> Result<Vec<TorrentHandle>> {
+        let torrents: Vec<_> = session.torrents().cloned().collect();
+        for torrent in &torrents {
+            self.id(torrent.info_hash());
+        }
+        let wanted = match args.get("ids") {
+            None => return Ok(torrents),
+            Some(Json::String(ids)) if ids == "recently-active" => {
+                return Ok(torrents.into_iter().filter(|t| t.is_active()).collect())
+            }
+            Some(Json::Array(ids)) => ids.clone(),
+            Some(id) => vec![id.clone()],
+        };
+        let matches = |torrent: &TorrentHandle| {
+            let info_hash = torrent.info_hash();
+            wanted.iter().any(|id| match id {
+                Json::Integer(_) | Json::Number(_) => {
+                    self.ids
+                        .get(&info_hash)
+                        .map(|own| Json::from(*own))
+                        .as_ref()
+                        == Some(id)
+                }
+                Json::String(hash) => to_hex(&info_hash).eq_ignore_ascii_case(hash),
+                _ => false,
+            })
+        };
+        Ok(torrents.iter().filter(|t| matches(t)).cloned().collect())
+    }
+
+    fn session_get(&self, session: &Session) -> Json {
+        let rates = session.rate_limits();
+        let connections = session.connection_limits();
+        let queue = session.queue().lock().unwrap().settings();
+        let seed_limits = session.seed_limits();
+        let units = Json::object()
+            .with("speed-units", vec!["kB/s", "MB/s", "GB/s", "TB/s"])
+            .with("speed-bytes", SPEED_BYTES)
+            .with("size-units", vec!["kB", "MB", "GB", "TB"])
+            .with("size-bytes", 1000u64)
+            .with("memory-units", vec!["KiB", "MiB", "GiB", "TiB"])
+            .with("memory-bytes", 1024u64);
+        Json::object()
+            .with(
+                "version",
+                format!("3.00 (torrent_rs {})", env!("CARGO_PKG_VERSION")),
+            )
+            .with("rpc-version", RPC_VERSION)
+            .with("rpc-version-minimum", RPC_VERSION_MINIMUM)
+            .with("session-id", self.session_id.as_str())
+            .with("download-dir", session.save_path().display().to_string())
+            .with("peer-port", u64::from(session.listen_port()))
+            .with("speed-limit-down", kilobytes(rates.download))
+            .with("speed-limit-down-enabled", rates.download.is_some())
+            .with("speed-limit-up", kilobytes(rates.upload))
+            .with("speed-limit-up-enabled", rates.upload.is_some())
+            .with("alt-speed-enabled", false)
+            .with(
+                "peer-limit-global",
+                connections.max_connections.unwrap_or(0),
+            )
+            .with("peer-limit-per-torrent", connections.max_peers.unwrap_or(0))
+            .with("download-queue-enabled", true)
+            .with("download-queue-size", queue.max_active_downloads)
+            .with("seed-queue-enabled", true)
+            .with("seed-queue-size", queue.max_active_seeds)
+            .with("seedRatioLimited", seed_limits.ratio.is_some())
+            .with("seedRatioLimit", seed_limits.ratio.unwrap_or(2.0))
+            .with(
+                "idle-seeding-limit-enabled",
+                seed_limits.idle_time.is_some(),
+            )
+            .with(
+                "idle-seeding-limit",
+                seed_limits.idle_time.map_or(30, |idle| idle.as_secs() / 60),
+            )
+            .with("dht-enabled", session.dht().is_some())
+            .with("lpd-enabled", session.lsd().is_some())
+            .with("pex-enabled", true)
+            .with(
+                "port-forwarding-enabled",
+                !session.port_mappings().is_empty(),
+            )
+            // peer connections are plaintext, the closest value Transmission has
+            .with("encryption", "tolerated")
+            .with("units", units)
+    }
+
+    fn session_stats(&self, session: &Session) -> Json {
+        let (mut downloaded, mut uploaded) = (0, 0);
+        let (mut download_rate, mut upload_rate) = (0.0, 0.0);
+        let (mut active, mut paused) = (0usize, 0usize);
+        for torrent in session.torrents() {
+            let stats = torrent.stats();
+            downloaded += stats.downloaded;
+            uploaded += stats.uploaded;
+            let (down, up) = torrent.rates();
+            download_rate += down;
+            upload_rate += up;
+            if torrent.is_paused() {
+                paused += 1;
+            } else if torrent.is_active() {
+                active += 1;
+            }
+        }
+        // nothing is kept across sessions, both are since this one started
+        let stats = Json::object()
+            .with("uploadedBytes", uploaded)
+            .with("downloadedBytes", downloaded)
+            .with("filesAdded", session.torrents().count())
+            .with("sessionCount", 1u64)
+            .with("secondsActive", self.started.elapsed().as_secs());
+        Json::object()
+            .with("activeTorrentCount", active)
+            .with("pausedTorrentCount", paused)
+            .with("torrentCount", session.torrents().count())
+            .with("downloadSpeed", download_rate.round() as u64)
+            .with("uploadSpeed", upload_rate.round() as u64)
+            .with("cumulative-stats", stats.clone())
+            .with("current-stats", stats)
+    }
+
+    /// the `fields` asked of each torrent, as objects or as a table whose
+    /// first row are the field names with `"format":"table"`
+    fn torrent_get(&mut self, session: &Session, args: &Json) -> Json {
+        let fields: Vec<String> = match args.get("fields") {
+            Some(Json::Array(fields)) => fields
+                .iter()
+                .filter_map(|field| field.as_str().map(String::from))
+                .collect(),
+            _ => vec![],
+        };
+        let mut torrents = vec![];
+        for torrent in self.select(session, args).unwrap_or_default() {
+            let id = self.id(torrent.info_hash());
+            torrents.push(torrent_fields(session, &torrent, id, &fields));
+        }
+        // magnet links are shown while their metadata is fetched
+        if args.get("ids").is_none() {
+            for info_hash in session.magnet_hashes() {
+                let id = self.id(info_hash);
+                let magnet = Json::object()
+                    .with("id", id)
+                    .with("hashString", to_hex(&info_hash))
+                    .with("name", to_hex(&info_hash))
+                    .with("status", 4u64)
+                    .with("percentDone", 0.0)
+                    .with("metadataPercentComplete", 0.0);
+                torrents.push(filter_fields(
+                    magnet,
+                    &Json::object().with("fields", fields.clone()),
+                ));
+            }
+        }
+        if args.get("format").and_then(Json::as_str) == Some("table") {
+            let mut rows = vec![Json::from(fields.clone())];
+            for torrent in torrents {
+                rows.push(Json::Array(
+                    fields
+                        .iter()
+                        .map(|field| torrent.get(field).cloned().unwrap_or(Json::Null))
+                        .collect(),
+                ));
+            }
+            return Json::object().with("torrents", Json::Array(rows));
+        }
+        Json::object().with("torrents", Json::Array(torrents))
+    }
+
+    /// from `filename`, the path of a .torrent, an http url or a magnet link, or from
+    /// `metainfo`, the .torrent in base64
+    fn torrent_add(&mut self, session: &mut Session, args: &Json) -> Result<Json> {
+        let options = AddOptions {
+            save_path: optional(args, "download-dir", Json::as_str)?.map(Into::into),
+            paused: optional(args, "paused", Json::as_bool)?.unwrap_or(false),
+            tags: strings(args, "labels")?.unwrap_or_default(),
+            ..Default::default()
+        };
+        let filename = optional(args, "filename", Json::as_str)?;
+        let metainfo = match (filename, optional(args, "metainfo", Json::as_str)?) {
+            (_, Some(metainfo)) => base64::decode(metainfo).context("metainfo isn't base64")?,
+            (Some(uri), None) if uri.starts_with("magnet:") => {
+                let magnet = Magnet::parse(uri)?;
+                let duplicate = session.torrent(&magnet.info_hash).is_some()
+                    || session.magnet_hashes().contains(&magnet.info_hash);
+                let info_hash = if duplicate {
+                    magnet.info_hash
+                } else {
+                    session.add_magnet_with(uri, options)?
+                };
+                let name = magnet.name.unwrap_or_else(|| to_hex(&info_hash));
+                return Ok(self.added(info_hash, &name, duplicate));
+            }
+            (Some(url), None) if tracker::is_http_url(url) => tracker::http_get(url)?,
+            (Some(path), None) => {
+                api::read_torrent_file(Path::new(path)).map_err(|error| anyhow!(error.message))?
+            }
+            (None, None) => bail!("no filename or metainfo given"),
+        };
+        let metainfo = Metainfo::from_bytes(metainfo)?;
+        if let Some(torrent) = session.torrent(&metainfo.info_hash) {
+            return Ok(self.added(metainfo.info_hash, &torrent.root_name(), true));
+        }
+        let torrent = session.add_torrent_with(metainfo, options)?;
+        set_files(&torrent, args)?;
+        Ok(self.added(torrent.info_hash(), &torrent.root_name(), false))
+    }
+
+    fn added(&mut self, info_hash: [u8; 20], name: &str, duplicate: bool) -> Json {
+        let torrent = Json::object()
+            .with("id", self.id(info_hash))
+            .with("name", name)
+            .with("hashString", to_hex(&info_hash));
+        let key = match duplicate {
+            true => "torrent-duplicate",
+            false => "torrent-added",
+        };
+        Json::object().with(key, torrent)
+    }
+
+    /// renames the root folder or a file, `path` as in the `files` field
+    /// and `name` its new last part
+    fn rename_path(&mut self, session: &Session, args: &Json) -> Result<Json> {
+        let torrents = self.select(session, args)?;
+        let torrent = match torrents.as_slice() {
+            [torrent] => torrent,
+            _ => bail!("torrent-rename-path takes exactly one torrent"),
+        };
+        let path =
+            optional(args, "path", Json::as_str)?.ok_or_else(|| anyhow!("path is missing"))?;
+        let name =
+            optional(args, "name", Json::as_str)?.ok_or_else(|| anyhow!("name is missing"))?;
+        let root = torrent.root_name();
+        let multi_file = torrent.metainfo().info.multi_file;
+        if multi_file && path == root {
+            torrent.rename_root(name)?;
+        } else {
+            let relative = match multi_file {
+                true => Path::new(path)
+                    .strip_prefix(&root)
+                    .map_err(|_| anyhow!("no file {}", path))?,
+                false => Path::new(path),
+            };
+            let index = torrent
+                .file_progress()
+                .iter()
+                .position(|file| file.path == relative)
+                .ok_or_else(|| anyhow!("no file {}", path))?;
+            torrent.rename_file(index, relative.with_file_name(name))?;
+        }
+        Ok(Json::object()
+            .with("id", self.id(torrent.info_hash()))
+            .with("path", path)
+            .with("name", name))
+    }
+}
+
+/// a limit in the kB/s of Transmission, which still shows its value when
+/// disabled
+fn kilobytes(limit: Option<u64>) -> u64 {
+    limit.map_or(100, |limit| limit / SPEED_BYTES)
+}
+
+fn session_set(session: &mut Session, args: &Json) -> Result<()> {
+    let mut rates = session.rate_limits();
+    rates.download = speed_limit(args, "speed-limit-down", rates.download)?;
+    rates.upload = speed_limit(args, "speed-limit-up", rates.upload)?;
+    let mut connections = session.connection_limits();
+    if let Some(max) = optional(args, "peer-limit-global", count)? {
+        connections.max_connections = Some(max).filter(|max| *max > 0);
+    }
+    if let Some(max) = optional(args, "peer-limit-per-torrent", count)? {
+        connections.max_peers = Some(max).filter(|max| *max > 0);
+    }
+    let mut queue = session.queue().lock().unwrap().settings();
+    if let Some(size) = optional(args, "download-queue-size", count)? {
+        queue.max_active_downloads = size;
+    }
+    if let Some(size) = optional(args, "seed-queue-size", count)? {
+        queue.max_active_seeds = size;
+    }
+    let mut seed_limits = session.seed_limits();
+    let ratio = optional(args, "seedRatioLimit", Json::as_f64)?;
+    match optional(args, "seedRatioLimited", Json::as_bool)? {
+        Some(false) => seed_limits.ratio = None,
+        Some(true) => seed_limits.ratio = ratio.or(seed_limits.ratio).or(Some(2.0)),
+        None if seed_limits.ratio.is_some() => seed_limits.ratio = ratio.or(seed_limits.ratio),
+        None => {}
+    }
+    let idle = optional(args, "idle-seeding-limit", count)?
+        .map(|minutes| Duration::from_secs(minutes as u64 * 60));
+    match optional(args, "idle-seeding-limit-enabled", Json::as_bool)? {
+        Some(false) => seed_limits.idle_time = None,
+        Some(true) => {
+            seed_limits.idle_time = idle
+                .or(seed_limits.idle_time)
+                .or(Some(Duration::from_secs(30 * 60)))
+        }
+        None if seed_limits.idle_time.is_some() => {
+            seed_limits.idle_time = idle.or(seed_limits.idle_time)
+        }
+        None => {}
+    }
+    session.set_seed_limits(seed_limits)?;
+    session.set_rate_limits(rates);
+    session.set_connection_limits(connections);
+    session.queue().lock().unwrap().set_settings(queue);
+    Ok(())
+}
+
+/// `key` in kB/s and `key-enabled`, in bytes per second
+fn speed_limit(args: &Json, key: &str, current: Option<u64>) -> Result<Option<u64>> {
+    let limit = optional(args, key, count)?.map(|limit| limit as u64 * SPEED_BYTES);
+    let enabled = optional(args, &format!("{}-enabled", key), Json::as_bool)?;
+    Ok(match enabled.unwrap_or(current.is_some()) {
+        true => limit
+            .or(current)
+            .or(Some(100 * SPEED_BYTES))
+            .filter(|limit| *limit > 0),
+        false => None,
+    })
+}
+
+fn torrent_set(session: &Session, torrent: &TorrentHandle, args: &Json) -> Result<()> {
+    set_files(torrent, args)?;
+    if let Some(labels) = strings(args, "labels")? {
+        torrent.set_tags(labels);
+    }
+    let mut rates = session.torrent_rate_limits(torrent);
+    rates.download = torrent_limit(args, "downloadLimit", "downloadLimited", rates.download)?;
+    rates.upload = torrent_limit(args, "uploadLimit", "uploadLimited", rates.upload)?;
+    session.set_torrent_rate_limits(torrent, rates);
+    if let Some(max) = optional(args, "peer-limit", count)? {
+        torrent.set_max_peers(Some(max).filter(|max| *max > 0));
+    }
+    if let Some(position) = optional(args, "queuePosition", count)? {
+        torrent.queue_move_to(position);
+    }
+    // 0 follows the session, 1 the ratio of the torrent, 2 is unlimited
+    let ratio = optional(args, "seedRatioLimit", Json::as_f64)?;
+    let own = torrent.seed_limits();
+    let limits = match optional(args, "seedRatioMode", count)? {
+        Some(0) => None,
+        Some(1) => Some(SeedLimits {
+            ratio: ratio.or(own.and_then(|own| own.ratio)).or(Some(2.0)),
+            ..own.unwrap_or(session.seed_limits())
+        }),
+        Some(2) => Some(SeedLimits {
+            ratio: None,
+            ..own.unwrap_or(session.seed_limits())
+        }),
+        Some(mode) => bail!("seedRatioMode can't be {}", mode),
+        None => match (own, ratio) {
+            (Some(own), Some(ratio)) if own.ratio.is_some() => Some(SeedLimits {
+                ratio: Some(ratio),
+                ..own
+            }),
+            _ => own,
+        },
+    };
+    if let Some(limits) = &limits {
+        limits.validate()?;
+    }
+    torrent.set_seed_limits(limits);
+    Ok(())
+}
+
+/// like `speed_limit` for the `downloadLimit` and `downloadLimited` of a
+/// torrent
+fn torrent_limit(
+    args: &Json,
+    key: &str,
+    enabled: &str,
+    current: Option<u64>,
+) -> Result<Option<u64>> {
+    let limit = optional(args, key, count)?.map(|limit| limit as u64 * SPEED_BYTES);
+    Ok(
+        match optional(args, enabled, Json::as_bool)?.unwrap_or(current.is_some()) {
+            true => limit
+                .or(current)
+                .or(Some(100 * SPEED_BYTES))
+                .filter(|limit| *limit > 0),
+            false => None,
+        },
+    )
+}
+
+/// `files-wanted`, `files-unwanted` and `priority-high`, `-low` and
+/// `-normal`, arrays of file indices where an empty one is every file.
+/// Padding files aren't shown to clients so they don't count.
+fn set_files(torrent: &TorrentHandle, args: &Json) -> Result<()> {
+    let files = torrent.file_progress();
+    let shown: Vec<_> = (0..files.len()).filter(|&i| !files[i].padding).collect();
+    let indices = |key: &str| -> Result<Option<Vec<usize>>> {
+        let values = match args.get(key) {
+            None | Some(Json::Null) => return Ok(None),
+            Some(Json::Array(values)) => values,
+            Some(_) => bail!("{} has to be an array of file indices", key),
+        };
+        if values.is_empty() {
+            return Ok(Some(shown.clone()));
+        }
+        values
+            .iter()
+            .map(|value| match value.as_f64() {
+                Some(index) if index >= 0.0 && (index as usize) < shown.len() => {
+                    Ok(shown[index as usize])
+                }
+                _ => bail!("{} has an invalid file index {}", key, value),
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    };
+    let mut priorities = torrent.file_priorities();
+    let before = priorities.clone();
+    for (key, priority) in [
+        ("priority-low", Priority::Low),
+        ("priority-normal", Priority::Normal),
+        ("priority-high", Priority::High),
+    ] {
+        for index in indices(key)?.unwrap_or_default() {
+            if priorities[index] != Priority::Skip {
+                priorities[index] = priority;
+            }
+        }
+    }
+    for index in indices("files-wanted")?.unwrap_or_default() {
+        if priorities[index] == Priority::Skip {
+            priorities[index] = Priority::Normal;
+        }
+    }
+    for index in indices("files-unwanted")?.unwrap_or_default() {
+        priorities[index] = Priority::Skip;
+    }
+    if priorities != before {
+        torrent.set_file_priorities(&priorities)?;
+    }
+    Ok(())
+}
+
+/// 0 stopped, 2 checking, 3 queued to download, 4 downloading, 5 queued
+/// to seed and 6 seeding
+fn status(torrent: &TorrentHandle) -> u64 {
+    match torrent.state() {
+        TorrentState::Paused | TorrentState::Errored => 0,
+        TorrentState::Allocating | TorrentState::CheckingFiles => 2,
+        TorrentState::Downloading if !torrent.is_active() => 3,
+        TorrentState::Downloading | TorrentState::FetchingMetadata => 4,
+        TorrentState::Seeding if !torrent.is_active() => 5,
+        TorrentState::Seeding => 6,
+    }
+}
+
+fn priority_number(priority: Priority) -> i64 {
+    match priority {
+        Priority::Low => -1,
+        Priority::Skip | Priority::Normal => 0,
+        Priority::High => 1,
+    }
+}
+
+/// the fields of a torrent asked for, those it doesn't have are left out
+fn torrent_fields(session: &Session, torrent: &TorrentHandle, id: u64, fields: &[String]) -> Json {
+    let progress = torrent.progress_report();
+    let stats = torrent.stats();
+    let metainfo = OnceCell::new();
+    let metainfo = || metainfo.get_or_init(|| torrent.metainfo());
+    let files = OnceCell::new();
+    let files = || {
+        files.get_or_init(|| {
+            let root = torrent.root_name();
+            let multi_file = metainfo().info.multi_file;
+            torrent
+                .file_progress()
+                .into_iter()
+                .filter(|file| !file.padding)
+                .map(|file| {
+                    let name = match multi_file {
+                        true => Path::new(&root).join(&file.path),
+                        false => file.path.clone(),
+                    };
+                    (name.to_string_lossy().into_owned(), file)
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+    let peers = OnceCell::new();
+    let peers = || peers.get_or_init(|| torrent.peer_list());
+    let rates = session.torrent_rate_limits(torrent);
+    let seed_limits = torrent.seed_limits();
+    let mut json = Json::object();
+    for field in fields {
+        let value: Json = match field.as_str() {
+            "id" => id.into(),
+            "hashString" => to_hex(&torrent.info_hash()).into(),
+            "name" => torrent.root_name().into(),
+            "status" => status(torrent).into(),
+            "error" => (if torrent.error().is_some() { 3u64 } else { 0 }).into(),
+            "errorString" => torrent.error().unwrap_or_default().into(),
+            "percentDone" | "percentComplete" => (progress.percent / 100.0).into(),
+            "metadataPercentComplete" => 1.0.into(),
+            "totalSize" => progress.total_bytes.into(),
+            "sizeWhenDone" => progress.bytes_wanted.into(),
+            "leftUntilDone" => (progress.bytes_wanted - progress.bytes_done).into(),
+            "haveValid" => progress.bytes_done.into(),
+            "haveUnchecked" => 0u64.into(),
+            "downloadedEver" => stats.downloaded.into(),
+            "uploadedEver" => stats.uploaded.into(),
+            "corruptEver" => stats.wasted.into(),
+            "uploadRatio" => torrent.seeding().ratio.into(),
+            "rateDownload" => (progress.download_rate.round() as u64).into(),
+            "rateUpload" => (progress.upload_rate.round() as u64).into(),
+            "eta" => progress.eta.map_or(-1, |eta| eta.as_secs() as i64).into(),
+            "peersConnected" => stats.peers.into(),
+            "peersSendingToUs" => peers()
+                .iter()
+                .filter(|peer| peer.download_rate > 0)
+                .count()
+                .into(),
+            "peersGettingFromUs" => peers()
+                .iter()
+                .filter(|peer| peer.upload_rate > 0)
+                .count()
+                .into(),
+            "downloadDir" => torrent.save_path().display().to_string().into(),
+            // done seeding, Transmission's finished
+            "isFinished" => (torrent.is_finished() && torrent.is_paused()).into(),
+            "isStalled" => (torrent.state() == TorrentState::Downloading
+                && progress.download_rate < 1.0)
+                .into(),
+            "isPrivate" => torrent.is_private().into(),
+            "labels" => torrent.tags().into_iter().collect::<Vec<_>>().into(),
+            "magnetLink" => metainfo().magnet_link().into(),
+            "queuePosition" => torrent.queue_position().unwrap_or(0).into(),
+            "pieceCount" => progress.piece_count.into(),
+            "pieceSize" => metainfo().info.piece_length.into(),
+            "comment" => metainfo().comment.clone().unwrap_or_default().into(),
+            "creator" => metainfo().created_by.clone().unwrap_or_default().into(),
+            "dateCreated" => metainfo().creation_date.unwrap_or(0).into(),
+            "files" => Json::Array(
+                files()
+                    .iter()
+                    .map(|(name, file)| {
+                        Json::object()
+                            .with("name", name.as_str())
+                            .with("length", file.length)
+                            .with("bytesCompleted", file.bytes_done)
+                    })
+                    .collect(),
+            ),
+            "fileStats" => Json::Array(
+                files()
+                    .iter()
+                    .map(|(_, file)| {
+                        Json::object()
+                            .with("bytesCompleted", file.bytes_done)
+                            .with("wanted", file.priority != Priority::Skip)
+                            .with("priority", priority_number(file.priority))
+                    })
+                    .collect(),
+            ),
+            "wanted" => Json::Array(
+                files()
+                    .iter()
+                    .map(|(_, file)| u64::from(file.priority != Priority::Skip).into())
+                    .collect(),
+            ),
+            "priorities" => Json::Array(
+                files()
+                    .iter()
+                    .map(|(_, file)| priority_number(file.priority).into())
+                    .collect(),
+            ),
+            "trackers" => Json::Array(
+                torrent
+                    .announce_urls()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, url)| {
+                        Json::object()
+                            .with("id", index)
+                            .with("announce", url)
+                            .with("tier", index)
+                    })
+                    .collect(),
+            ),
+            "trackerStats" => Json::Array(
+                torrent
+                    .trackers()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, tracker)| {
+                        Json::object()
+                            .with("id", index)
+                            .with("announce", tracker.url)
+                            .with("tier", index)
+                            .with("seederCount", tracker.seeders)
+                            .with("leecherCount", tracker.leechers)
+                            .with("downloadCount", tracker.completed)
+                            .with("lastAnnounceTime", tracker.last_announce)
+                    })
+                    .collect(),
+            ),
+            "peers" => {
+                let pieces = progress.piece_count.max(1) as f64;
+                Json::Array(
+                    peers()
+                        .iter()
+                        .map(|peer| {
+                            Json::object()
+                                .with("address", peer.addr.ip().to_string())
+                                .with("port", u64::from(peer.addr.port()))
+                                .with("clientName", peer.client.clone().unwrap_or_default())
+                                .with("flagStr", peer.flags())
+                                .with("progress", peer.pieces as f64 / pieces)
+                                .with("rateToClient", peer.download_rate)
+                                .with("rateToPeer", peer.upload_rate)
+                                .with("isEncrypted", peer.encrypted)
+                                .with("isDownloadingFrom", peer.download_rate > 0)
+                                .with("isUploadingTo", peer.upload_rate > 0)
+                        })
+                        .collect(),
+                )
+            }
+            "downloadLimit" => kilobytes(rates.download).into(),
+            "downloadLimited" => rates.download.is_some().into(),
+            "uploadLimit" => kilobytes(rates.upload).into(),
+            "uploadLimited" => rates.upload.is_some().into(),
+            "peer-limit" => torrent.max_peers().unwrap_or(0).into(),
+            "seedRatioMode" => match seed_limits {
+                None => 0u64,
+                Some(SeedLimits { ratio: Some(_), .. }) => 1,
+                Some(_) => 2,
+            }
+            .into(),
+            "seedRatioLimit" => seed_limits
+                .and_then(|limits| limits.ratio)
+                .or(session.seed_limits().ratio)
+                .unwrap_or(0.0)
+                .into(),
+            "bandwidthPriority" => 0u64.into(),
+            _ => continue,
+        };
+        json = json.with(field, value);
+    }
+    json
+}
+
+/// only the keys of `fields` when given, like Transmission does for
+/// session-get
+fn filter_fields(json: Json, args: &Json) -> Json {
+    match (json, args.get("fields")) {
+        (Json::Object(entries), Some(Json::Array(fields))) => Json::Object(
+            entries
+                .into_iter()
+                .filter(|(key, _)| fields.iter().any(|field| field.as_str() == Some(key)))
+                .collect(),
+        ),
+        (json, _) => json,
+    }
+}
+
+/// a key that has to be of a kind when given
+fn optional<'a, T>(
+    args: &'a Json,
+    key: &str,
+    get: impl FnOnce(&'a Json) -> Option<T>,
+) -> Result<Option<T>> {
+    match args.get(key) {
+        None | Some(Json::Null) => Ok(None),
+        Some(value) => get(value)
+            .map(Some)
+            .ok_or_else(|| anyhow!("{} has the wrong type", key)),
+    }
+}
+
+fn count(value: &Json) -> Option<usize> {
+    value
+        .as_f64()
+        .filter(|number| *number >= 0.0 && number.fract() == 0.0)
+        .map(|number| number as usize)
+}
+
+fn strings(args: &Json, key: &str) -> Result<Option<BTreeSet<String>>> {
+    optional(args, key, |value| match value {
+        Json::Array(values) => values
+            .iter()
+            .map(|value| value.as_str().map(String::from))
+            .collect(),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn call(rpc: &mut Rpc, session: &mut Session, method: &str, arguments: Json) -> Json {
+        let request = Json::object()
+            .with("method", method)
+            .with("arguments", arguments)
+            .with("tag", 7u64);
+        let response = rpc.call(session, &request);
+        assert_eq!(response.get("tag"), Some(&Json::Number(7.0)));
+        response
+    }
+
+    fn arguments(response: Json) -> Json {
+        assert_eq!(
+            response.get("result").and_then(Json::as_str),
+            Some("success"),
+            "{}",
+            response
+        );
+        response.get("arguments").cloned().unwrap()
+    }
+
+    #[test]
+    fn answers_transmission_clients() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_transmission_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let config = Config::builder()
+            .save_path(&dir)
+            .listen_port(0)
+            .dht(None)
+            .lsd(false)
+            .trackers(false)
+            .port_mapping(false)
+            .build()?;
+        let mut session = Session::new(config)?;
+        let mut rpc = Rpc::new();
+
+        let path = std::fs::canonicalize("file1.txt.torrent")?;
+        let add = Json::object()
+            .with("filename", path.display().to_string())
+            .with("paused", true)
+            .with("labels", vec!["tv"]);
+        let added = arguments(call(&mut rpc, &mut session, "torrent-add", add.clone()));
+        let info_hash = "8dc3b8a5ac6d8002df36541fda949e7109b7397c";
+        assert_eq!(
+            added,
+            Json::object().with(
+                "torrent-added",
+                Json::object()
+                    .with("id", 1u64)
+                    .with("name", "file1.txt")
+                    .with("hashString", info_hash)
+            )
+        );
+        let again = arguments(call(&mut rpc, &mut session, "torrent-add", add));
+        assert!(again.get("torrent-duplicate").is_some());
+
+        let get = Json::object().with("ids", vec![Json::from(1u64)]).with(
+            "fields",
+            vec!["id", "name", "status", "labels", "files", "unknown"],
+        );
+        let torrents = arguments(call(&mut rpc, &mut session, "torrent-get", get));
+        let torrent = match torrents.get("torrents") {
+            Some(Json::Array(torrents)) if torrents.len() == 1 => torrents[0].clone(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(torrent.get("status"), Some(&Json::Number(0.0)));
+        assert_eq!(torrent.get("labels"), Some(&Json::from(vec!["tv"])));
+        assert!(torrent.get("unknown").is_none());
+        match torrent.get("files") {
+            Some(Json::Array(files)) => {
+                assert_eq!(
+                    files[0].get("name").and_then(Json::as_str),
+                    Some("file1.txt")
+                )
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let table = Json::object()
+            .with("fields", vec!["id", "hashString"])
+            .with("format", "table");
+        let table = arguments(call(&mut rpc, &mut session, "torrent-get", table));
+        assert_eq!(
+            table.get("torrents"),
+            Some(&Json::Array(vec![
+                Json::from(vec!["id", "hashString"]),
+                Json::Array(vec![1u64.into(), info_hash.into()]),
+            ]))
+        );
+
+        let set = Json::object()
+            .with("speed-limit-down", 500u64)
+            .with("speed-limit-down-enabled", true)
+            .with("peer-limit-per-torrent", 30u64)
+            .with("download-queue-size", 2u64);
+        arguments(call(&mut rpc, &mut session, "session-set", set));
+        assert_eq!(session.rate_limits().download, Some(500_000));
+        assert_eq!(session.connection_limits().max_peers, Some(30));
+        let fields = Json::object().with("fields", vec!["speed-limit-down", "rpc-version"]);
+        assert_eq!(
+            arguments(call(&mut rpc, &mut session, "session-get", fields)),
+            Json::object()
+                .with("rpc-version", RPC_VERSION)
+                .with("speed-limit-down", 500u64)
+        );
+
+        let set = Json::object()
+            .with("ids", info_hash)
+            .with("uploadLimit", 20u64)
+            .with("uploadLimited", true)
+            .with("seedRatioMode", 1u64)
+            .with("seedRatioLimit", 1.5);
+        arguments(call(&mut rpc, &mut session, "torrent-set", set));
+        let torrent = session.find(info_hash)?.clone();
+        assert_eq!(session.torrent_rate_limits(&torrent).upload, Some(20_000));
+        assert_eq!(
+            torrent.seed_limits().and_then(|limits| limits.ratio),
+            Some(1.5)
+        );
+
+        let stats = arguments(call(
+            &mut rpc,
+            &mut session,
+            "session-stats",
+            Json::object(),
+        ));
+        assert_eq!(stats.get("torrentCount"), Some(&Json::Number(1.0)));
+        assert_eq!(stats.get("pausedTorrentCount"), Some(&Json::Number(1.0)));
+
+        let unknown = call(&mut rpc, &mut session, "torrent-fly", Json::object());
+        assert_eq!(
+            unknown.get("result").and_then(Json::as_str),
+            Some("method name not recognized")
+        );
+
+        let remove = Json::object().with("ids", vec![Json::from(1u64)]);
+        arguments(call(&mut rpc, &mut session, "torrent-remove", remove));
+        assert_eq!(session.torrents().count(), 0);
+
+        session.shutdown(Duration::from_secs(1))?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
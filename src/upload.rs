@@ -0,0 +1,227 @@
+use crate::message;
+use crate::storage::FileRange;
+use std::fs::File;
+use std::io::{self, IoSlice, Write};
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Where the data of a block requested by a peer comes from, it is written to
+/// the socket without being copied into a message first
+#[derive(Debug, Clone)]
+pub enum UploadBlock {
+    /// part of a piece in memory, sent with a single vectored write after the
+    /// message header
+    Memory {
+        piece: Arc<[u8]>,
+        range: Range<usize>,
+    },
+    /// parts of the files holding the block, sent by the kernel straight from
+    /// the page cache where supported
+    Files(Vec<FileRange>),
+}
+
+/// What uploads are written to, sendfile needs the descriptor of the socket
+#[cfg(target_os = "linux")]
+pub trait Socket: Write + std::os::unix::io::AsRawFd {}
+
+#[cfg(target_os = "linux")]
+impl<T: Write + std::os::unix::io::AsRawFd> Socket for T {}
+
+#[cfg(not(target_os = "linux"))]
+pub trait Socket: Write {}
+
+#[cfg(not(target_os = "linux"))]
+impl<T: Write> Socket for T {}
+
+impl UploadBlock {
+    pub fn len(&self) -> u64 {
+        match self {
+            UploadBlock::Memory { range, .. } => range.len() as u64,
+            UploadBlock::Files(files) => files.iter().map(|range| range.length).sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// writes the piece message carrying the block to `socket`
+    pub fn send(&self, socket: &mut impl Socket, piece: u32, offset: u32) -> io::Result<()> {
+        let header = message::piece_header(piece, offset, self.len() as u32);
+        match self {
+            UploadBlock::Memory { piece, range } => write_all_vectored(
+                socket,
+                &mut [IoSlice::new(&header), IoSlice::new(&piece[range.clone()])],
+            ),
+            UploadBlock::Files(files) => {
+                socket.write_all(&header)?;
+                for range in files {
+                    send_file(socket, &range.file, range.offset, range.length)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// like `send`, over a socket of the runtime
+    pub async fn send_async(
+        &self,
+        socket: &mut tokio::net::TcpStream,
+        piece: u32,
+        offset: u32,
+    ) -> io::Result<()> {
+        let header = message::piece_header(piece, offset, self.len() as u32);
+        socket.write_all(&header).await?;
+        match self {
+            UploadBlock::Memory { piece, range } => socket.write_all(&piece[range.clone()]).await,
+            UploadBlock::Files(files) => {
+                for range in files {
+                    send_file_async(socket, &range.file, range.offset, range.length).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn write_all_vectored(socket: &mut impl Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match socket.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn send_file(socket: &mut impl Socket, file: &File, offset: u64, length: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // buffered data has to go out before the kernel writes to the descriptor
+    socket.flush()?;
+    let mut offset = offset as libc::off_t;
+    let mut remaining = length as usize;
+    while remaining > 0 {
+        // SAFETY: both descriptors stay open for the duration of the call
+        let sent =
+            unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, remaining) };
+        match sent {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            sent if sent > 0 => remaining -= sent as usize,
+            _ => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_file(socket: &mut impl Socket, file: &File, offset: u64, length: u64) -> io::Result<()> {
+    let mut buffer = vec![0; length as usize];
+    crate::file_pool::read_at(file, &mut buffer, offset)?;
+    socket.write_all(&buffer)
+}
+
+/// waits for the socket to be writable whenever the kernel can't take more
+#[cfg(target_os = "linux")]
+async fn send_file_async(
+    socket: &tokio::net::TcpStream,
+    file: &File,
+    offset: u64,
+    length: u64,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::Interest;
+    let mut offset = offset as libc::off_t;
+    let mut remaining = length as usize;
+    while remaining > 0 {
+        socket.writable().await?;
+        let sent = socket.try_io(Interest::WRITABLE, || {
+            // SAFETY: both descriptors stay open for the duration of the call
+            match unsafe {
+                libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, remaining)
+            } {
+                sent if sent >= 0 => Ok(sent as usize),
+                _ => Err(io::Error::last_os_error()),
+            }
+        });
+        match sent {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(sent) => remaining -= sent,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_file_async(
+    socket: &mut tokio::net::TcpStream,
+    file: &File,
+    offset: u64,
+    length: u64,
+) -> io::Result<()> {
+    let mut buffer = vec![0; length as usize];
+    crate::file_pool::read_at(file, &mut buffer, offset)?;
+    socket.write_all(&buffer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    #[test]
+    fn sends_piece_messages() -> io::Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_upload_test");
+        std::fs::create_dir_all(&dir)?;
+        let data = dir.join("data");
+        std::fs::write(&data, b"Hello world!")?;
+        let file = Arc::new(File::open(&data)?);
+        let expected = Message::Piece {
+            piece: 1,
+            offset: 2,
+            data: b"lo wor".to_vec(),
+        }
+        .encode();
+
+        let blocks = [
+            UploadBlock::Memory {
+                piece: Arc::from(&b"Hello world!"[..]),
+                range: 3..9,
+            },
+            UploadBlock::Files(vec![
+                FileRange {
+                    file: Arc::clone(&file),
+                    offset: 3,
+                    length: 2,
+                },
+                FileRange {
+                    file,
+                    offset: 5,
+                    length: 4,
+                },
+            ]),
+        ];
+        for block in &blocks {
+            let path = dir.join("socket");
+            let mut socket = File::create(&path)?;
+            block.send(&mut socket, 1, 2)?;
+            assert_eq!(std::fs::read(&path)?, expected);
+        }
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
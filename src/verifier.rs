@@ -0,0 +1,158 @@
+use crate::merkle;
+use crate::metainfo::PieceHash;
+use sha1::{Digest, Sha1};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+#[derive(Debug, Clone)]
+pub struct VerifyJob {
+    pub piece: usize,
+    pub hash: PieceHash,
+    pub data: Arc<[u8]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verification {
+    pub piece: usize,
+    pub valid: bool,
+}
+
+/// Pool of threads hashing pieces in parallel so hashing never runs on the
+/// network thread. Jobs share the piece data with the write path instead of
+/// copying it, so writing and hashing happen concurrently.
+pub struct Verifier {
+    jobs: Option<Sender<VerifyJob>>,
+    results: Receiver<Verification>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Verifier {
+    pub fn new(threads: usize) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<VerifyJob>();
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let jobs = Arc::clone(&job_receiver);
+                let results = result_sender.clone();
+                thread::spawn(move || loop {
+                    let job = match jobs.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let valid = is_valid(&job);
+                    if results
+                        .send(Verification {
+                            piece: job.piece,
+                            valid,
+                        })
+                        .is_err()
+                    {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            results,
+            workers,
+        }
+    }
+
+    /// one worker per core
+    pub fn with_available_parallelism() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |count| count.get()))
+    }
+
+    /// queues the piece for hashing and returns immediately
+    pub fn submit(&self, job: VerifyJob) {
+        if let Some(jobs) = &self.jobs {
+            // workers only exit once the sender is dropped
+            jobs.send(job).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Verification> {
+        self.results.try_recv().ok()
+    }
+
+    /// blocks until the next verification is done
+    pub fn recv(&self) -> Option<Verification> {
+        self.results.recv().ok()
+    }
+}
+
+/// hashes the piece on the calling thread
+pub fn is_valid(job: &VerifyJob) -> bool {
+    match job.hash {
+        PieceHash::V1(hash) => Sha1::digest(&job.data).as_slice() == hash,
+        PieceHash::V2 {
+            root,
+            leaves,
+            file_bytes,
+        } => {
+            let data = &job.data[..(file_bytes as usize).min(job.data.len())];
+            let blocks = merkle::block_hashes(data);
+            blocks.len() <= leaves && merkle::root(&blocks, leaves) == root
+        }
+    }
+}
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_in_parallel() {
+        let verifier = Verifier::new(4);
+        let data: Arc<[u8]> = Arc::from(&b"Hello world!"[..]);
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&Sha1::digest(&data));
+
+        for piece in 0..8 {
+            verifier.submit(VerifyJob {
+                piece,
+                hash: PieceHash::V1(if piece % 2 == 0 { hash } else { [0; 20] }),
+                data: Arc::clone(&data),
+            });
+        }
+
+        let mut results: Vec<_> = (0..8).map(|_| verifier.recv().unwrap()).collect();
+        results.sort_by_key(|result| result.piece);
+        for result in results {
+            assert_eq!(result.valid, result.piece % 2 == 0);
+        }
+    }
+
+    #[test]
+    fn merkle_root() {
+        let data: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let blocks = merkle::block_hashes(&data);
+        let job = |root, file_bytes| VerifyJob {
+            piece: 0,
+            hash: PieceHash::V2 {
+                root,
+                leaves: 4,
+                file_bytes,
+            },
+            data: Arc::from(&data[..]),
+        };
+
+        assert!(is_valid(&job(merkle::root(&blocks, 4), 40000)));
+        assert!(!is_valid(&job(merkle::root(&blocks, 4), 30000)));
+        assert!(!is_valid(&job([0; 32], 40000)));
+    }
+}
@@ -0,0 +1,192 @@
+//! The parsers of .torrent files, magnet links and bencode for web tools,
+//! in a build for wasm32-unknown-unknown that needs no files or network.
+//! Without the default `engine` feature the rest of the crate is left out:
+//!
+//! ```text
+//! cargo rustc --lib --release --target wasm32-unknown-unknown \
+//!     --no-default-features --features wasm --crate-type cdylib
+//! ```
+//!
+//! torrent_rs.js, next to this file, loads the resulting torrent_rs.wasm
+//! and hides its memory behind `inspectTorrent`, `parseMagnet`,
+//! `decodeBencode` and `encodeBencode`. Underneath, the input is copied
+//! into a buffer from `torrent_rs_alloc`, the call returns 0 on success or
+//! 1 on failure, and its JSON, bencode or error message is left at
+//! `torrent_rs_output` until the next call.
+
+// the pointers are the ones torrent_rs.js got from torrent_rs_alloc
+#![allow(clippy::missing_safety_doc)]
+
+use crate::bencode::{self, Parser};
+use crate::json::Json;
+use crate::magnet::{Magnet, MutableMagnet};
+use crate::metainfo::{to_hex, Metainfo};
+use anyhow::{bail, Result};
+use std::cell::RefCell;
+
+thread_local! {
+    static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// what `torrent_rs show --json` prints of a .torrent
+pub fn inspect_torrent(data: &[u8]) -> Result<Json> {
+    Ok(Metainfo::from_bytes(data.to_vec())?.to_json())
+}
+
+/// the info hash, name and trackers of a magnet link, or the public key,
+/// salt and DHT target of one to a mutable item (BEP 46)
+pub fn parse_magnet(uri: &str) -> Result<Json> {
+    if uri.contains("xs=urn:btpk:") {
+        let magnet = MutableMagnet::parse(uri)?;
+        return Ok(Json::object()
+            .with("public_key", to_hex(&magnet.public_key))
+            .with("salt", to_hex(&magnet.salt))
+            .with("target", to_hex(&magnet.target()))
+            .with("name", magnet.name)
+            .with("trackers", magnet.trackers));
+    }
+    let magnet = Magnet::parse(uri)?;
+    Ok(Json::object()
+        .with("info_hash", to_hex(&magnet.info_hash))
+        .with("name", magnet.name)
+        .with("trackers", magnet.trackers))
+}
+
+/// see `bencode::to_json`, the data has to be a single value
+pub fn decode_bencode(data: &[u8]) -> Result<Json> {
+    let mut parser = Parser::new(data.to_vec());
+    let value = parser.parse()?;
+    if parser.position() < data.len() {
+        bail!(
+            "{} bytes after the bencoded value",
+            data.len() - parser.position()
+        );
+    }
+    Ok(bencode::to_json(&value))
+}
+
+/// the reverse of `decode_bencode`
+pub fn encode_bencode(json: &str) -> Result<Vec<u8>> {
+    Ok(bencode::from_json(&Json::parse(json)?)?.encode())
+}
+
+/// a buffer of `length` bytes for the input of a call
+#[no_mangle]
+pub extern "C" fn torrent_rs_alloc(length: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; length].into_boxed_slice()) as *mut u8
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_free(buffer: *mut u8, length: usize) {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+        buffer, length,
+    )));
+}
+
+/// where the output of the last call starts
+#[no_mangle]
+pub extern "C" fn torrent_rs_output() -> *const u8 {
+    OUTPUT.with(|output| output.borrow().as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn torrent_rs_output_len() -> usize {
+    OUTPUT.with(|output| output.borrow().len())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_inspect_torrent(data: *const u8, length: usize) -> u32 {
+    respond(inspect_torrent(input(data, length)).map(|json| json.to_string().into_bytes()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_parse_magnet(uri: *const u8, length: usize) -> u32 {
+    respond(
+        std::str::from_utf8(input(uri, length))
+            .map_err(Into::into)
+            .and_then(parse_magnet)
+            .map(|json| json.to_string().into_bytes()),
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_decode_bencode(data: *const u8, length: usize) -> u32 {
+    respond(decode_bencode(input(data, length)).map(|json| json.to_string().into_bytes()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn torrent_rs_encode_bencode(json: *const u8, length: usize) -> u32 {
+    respond(
+        std::str::from_utf8(input(json, length))
+            .map_err(Into::into)
+            .and_then(encode_bencode),
+    )
+}
+
+unsafe fn input<'a>(data: *const u8, length: usize) -> &'a [u8] {
+    match length {
+        0 => &[],
+        _ => std::slice::from_raw_parts(data, length),
+    }
+}
+
+/// keeps the output, or the error message, for `torrent_rs_output`
+fn respond(result: Result<Vec<u8>>) -> u32 {
+    let (status, output) = match result {
+        Ok(output) => (0, output),
+        Err(error) => (1, format!("{:#}", error).into_bytes()),
+    };
+    OUTPUT.with(|last| *last.borrow_mut() = output);
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output() -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(torrent_rs_output(), torrent_rs_output_len()) }.to_vec()
+    }
+
+    fn output_text() -> String {
+        String::from_utf8(output()).unwrap()
+    }
+
+    #[test]
+    fn inspects_torrents_through_memory() -> Result<()> {
+        let data = std::fs::read("file1.txt.torrent")?;
+        let buffer = torrent_rs_alloc(data.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+            assert_eq!(torrent_rs_inspect_torrent(buffer, data.len()), 0);
+            assert!(output_text().starts_with(r#"{"name":"file1.txt","info_hash":"#));
+            assert_eq!(torrent_rs_inspect_torrent(buffer, 10), 1);
+            assert!(!output().is_empty());
+
+            assert_eq!(torrent_rs_decode_bencode(buffer, data.len()), 0);
+            let json = output_text();
+            assert!(json.contains(r#""pieces":{"$hex":"#));
+            assert_eq!(torrent_rs_encode_bencode(json.as_ptr(), json.len()), 0);
+            assert_eq!(output(), data);
+            torrent_rs_free(buffer, data.len());
+            assert_eq!(torrent_rs_decode_bencode(std::ptr::null(), 0), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parses_magnets() -> Result<()> {
+        let json = parse_magnet("magnet:?xt=urn:btih:8dc3b8a5ac6d8002df36541fda949e7109b7397c&dn=file1.txt&tr=http%3A%2F%2Ftracker.example%2Fannounce")?;
+        assert_eq!(
+            json.to_string(),
+            r#"{"info_hash":"8dc3b8a5ac6d8002df36541fda949e7109b7397c","name":"file1.txt","trackers":["http://tracker.example/announce"]}"#
+        );
+        let key = "8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e";
+        let json = parse_magnet(&format!("magnet:?xs=urn:btpk:{}&s=666f6f626172", key))?;
+        assert_eq!(json.get("public_key"), Some(&Json::from(key)));
+        assert_eq!(json.get("salt"), Some(&Json::from("666f6f626172")));
+        assert!(parse_magnet("magnet:?dn=nothing").is_err());
+        assert!(decode_bencode(b"i1ei2e").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+// The parsers of torrent_rs for the browser or node, over the wasm build of
+// the crate, see src/wasm/mod.rs:
+//
+//   import { load } from "./torrent_rs.js";
+//   const torrent_rs = await load(fetch("torrent_rs.wasm"));
+//   const torrent = torrent_rs.inspectTorrent(new Uint8Array(await file.arrayBuffer()));
+//
+// Every function throws an Error with the message of the engine when the
+// input can't be parsed.
+
+const encoder = new TextEncoder();
+const decoder = new TextDecoder();
+
+// source is the wasm as a Response, bytes or a WebAssembly.Module, or a
+// promise of one
+export async function load(source) {
+  source = await source;
+  if (typeof Response !== "undefined" && source instanceof Response) {
+    source = await source.arrayBuffer();
+  }
+  const instance = await WebAssembly.instantiate(source, {});
+  const exports = (instance.instance || instance).exports;
+
+  // the output of calling the export named name with bytes
+  function call(name, bytes) {
+    const input = exports.torrent_rs_alloc(bytes.length);
+    new Uint8Array(exports.memory.buffer, input, bytes.length).set(bytes);
+    const status = exports[name](input, bytes.length);
+    exports.torrent_rs_free(input, bytes.length);
+    // the memory may have grown, so the view is made after the call
+    const output = new Uint8Array(
+      exports.memory.buffer,
+      exports.torrent_rs_output(),
+      exports.torrent_rs_output_len(),
+    ).slice();
+    if (status !== 0) {
+      throw new Error(decoder.decode(output));
+    }
+    return output;
+  }
+
+  return {
+    // the name, hashes, trackers and files of a .torrent, as
+    // `torrent_rs show --json` prints them
+    inspectTorrent(bytes) {
+      return JSON.parse(decoder.decode(call("torrent_rs_inspect_torrent", bytes)));
+    },
+    // the info hash, name and trackers of a magnet link
+    parseMagnet(uri) {
+      return JSON.parse(decoder.decode(call("torrent_rs_parse_magnet", encoder.encode(uri))));
+    },
+    // any bencoded value, byte strings that aren't utf-8 become
+    // {"$hex": "..."} objects
+    decodeBencode(bytes) {
+      return JSON.parse(decoder.decode(call("torrent_rs_decode_bencode", bytes)));
+    },
+    // back to the bytes decodeBencode read
+    encodeBencode(value) {
+      return call("torrent_rs_encode_bencode", encoder.encode(JSON.stringify(value)));
+    },
+  };
+}
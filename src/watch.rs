@@ -0,0 +1,139 @@
+use crate::session::AddOptions;
+use anyhow::{Context, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// how often `Session::tick` looks into the watch folders
+pub const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+/// files modified more recently than this may still be being written
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+/// where the files that were added are moved
+pub const PROCESSED_DIR: &str = "processed";
+/// where the files that couldn't be added are moved
+pub const FAILED_DIR: &str = "failed";
+
+/// A folder whose .torrent files, and magnet links in .magnet or .uri files,
+/// are added to the session with its options. Once added they are moved to
+/// its `processed` subfolder, or to `failed` when they can't be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchFolder {
+    pub path: PathBuf,
+    pub options: AddOptions,
+}
+
+impl WatchFolder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            options: AddOptions::default(),
+        }
+    }
+
+    /// the torrent and magnet files left alone for `SETTLE_TIME` at `now`,
+    /// by name. Hidden files, subfolders and other files are skipped.
+    pub fn ready_files(&self, now: SystemTime) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            let metadata = entry.metadata()?;
+            if hidden || !metadata.is_file() || !(is_torrent(&path) || is_magnet(&path)) {
+                continue;
+            }
+            let settled = match now.duration_since(metadata.modified()?) {
+                Ok(age) => age >= SETTLE_TIME,
+                // modified in the future, by a clock that was off
+                Err(_) => false,
+            };
+            if settled {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|extension| extensions.contains(&extension.as_str()))
+}
+
+pub fn is_torrent(path: &Path) -> bool {
+    has_extension(path, &["torrent"])
+}
+
+/// a file holding a magnet link
+pub fn is_magnet(path: &Path) -> bool {
+    has_extension(path, &["magnet", "uri"])
+}
+
+/// moves the file to `subfolder` of its folder, numbering it like
+/// `name.1.torrent` if a file of the same name is already there
+pub fn move_to(file: &Path, subfolder: &str) -> Result<PathBuf> {
+    let dir = file
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(subfolder);
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let name = file.file_name().context("no file name")?;
+    let mut target = dir.join(name);
+    let mut number = 0;
+    while target.exists() {
+        number += 1;
+        let mut name = file.file_stem().unwrap_or(name).to_os_string();
+        name.push(format!(".{}", number));
+        if let Some(extension) = file.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        target = dir.join(name);
+    }
+    fs::rename(file, &target)
+        .with_context(|| format!("moving {} to {}", file.display(), target.display()))?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_settled_files_and_moves_them() -> Result<()> {
+        let dir = std::env::temp_dir().join("torrent_rs_watch_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(PROCESSED_DIR))?;
+        for name in ["b.torrent", "a.URI", "c.magnet", "notes.txt", ".d.torrent"] {
+            fs::write(dir.join(name), "")?;
+        }
+        fs::create_dir(dir.join("e.torrent"))?;
+        let folder = WatchFolder::new(&dir);
+
+        // just written
+        assert!(folder.ready_files(SystemTime::now())?.is_empty());
+        let later = SystemTime::now() + SETTLE_TIME;
+        assert_eq!(
+            folder.ready_files(later)?,
+            [
+                dir.join("a.URI"),
+                dir.join("b.torrent"),
+                dir.join("c.magnet")
+            ]
+        );
+
+        let moved = move_to(&dir.join("b.torrent"), PROCESSED_DIR)?;
+        assert_eq!(moved, dir.join(PROCESSED_DIR).join("b.torrent"));
+        fs::write(dir.join("b.torrent"), "")?;
+        let moved = move_to(&dir.join("b.torrent"), PROCESSED_DIR)?;
+        assert_eq!(moved, dir.join(PROCESSED_DIR).join("b.1.torrent"));
+        move_to(&dir.join("c.magnet"), FAILED_DIR)?;
+        assert!(dir.join(FAILED_DIR).join("c.magnet").exists());
+        assert_eq!(folder.ready_files(later)?, [dir.join("a.URI")]);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
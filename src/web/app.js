@@ -0,0 +1,324 @@
+// The page of the web ui, over the REST api of the same server. Everything
+// shown is set with textContent, names and paths of torrents are never
+// parsed as HTML.
+"use strict";
+
+const TOKEN_KEY = "torrent_rs.token";
+const REFRESH_MS = 2000;
+// while the events are streamed, polled only for the rates
+const STREAMING_REFRESH_MS = 10000;
+// events come in bursts, one refresh answers a burst
+const EVENT_DELAY_MS = 300;
+const RECONNECT_MS = 5000;
+let selected = null;
+let streaming = false;
+let lastRefresh = 0;
+
+// answers the JSON of a request, asking for the token once on 401
+async function api(method, path, body, contentType) {
+  for (let attempt = 0; attempt < 2; attempt++) {
+    const headers = {};
+    const token = localStorage.getItem(TOKEN_KEY);
+    if (token) {
+      headers["Authorization"] = "Bearer " + token;
+    }
+    if (body !== undefined) {
+      headers["Content-Type"] = contentType || "application/json";
+    }
+    const response = await fetch(path, { method, headers, body });
+    if (response.status === 401 && attempt === 0) {
+      const given = prompt("Token of the api");
+      if (given === null) {
+        break;
+      }
+      localStorage.setItem(TOKEN_KEY, given);
+      continue;
+    }
+    const json = await response.json();
+    if (!response.ok) {
+      throw new Error(json.error || response.statusText);
+    }
+    return json;
+  }
+  throw new Error("the api needs a token");
+}
+
+function showError(error) {
+  const element = document.getElementById("error");
+  element.hidden = !error;
+  element.textContent = error ? error.message : "";
+}
+
+function bytes(count) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+  while (count >= 1024 && unit < units.length - 1) {
+    count /= 1024;
+    unit++;
+  }
+  return (unit ? count.toFixed(1) : count) + " " + units[unit];
+}
+
+function rate(count) {
+  return count >= 1 ? bytes(Math.round(count)) + "/s" : "";
+}
+
+function duration(seconds) {
+  if (seconds === null || seconds === undefined) {
+    return "";
+  }
+  const hours = Math.floor(seconds / 3600);
+  const minutes = Math.floor((seconds % 3600) / 60);
+  return hours ? hours + "h " + minutes + "m" : minutes + "m " + (seconds % 60) + "s";
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text;
+  if (className) {
+    td.className = className;
+  }
+  return td;
+}
+
+function button(parent, label, action) {
+  const element = document.createElement("button");
+  element.type = "button";
+  element.textContent = label;
+  element.addEventListener("click", async (event) => {
+    event.stopPropagation();
+    try {
+      await action();
+      await refresh();
+    } catch (error) {
+      showError(error);
+    }
+  });
+  parent.appendChild(element);
+}
+
+function torrentRow(body, torrent) {
+  const row = body.insertRow();
+  const hash = torrent.info_hash;
+  if (hash === selected) {
+    row.className = "selected";
+  }
+  const name = cell(row, torrent.name || hash, "name");
+  name.addEventListener("click", () => {
+    selected = hash;
+    refresh();
+  });
+  cell(row, torrent.state);
+  const progress = document.createElement("progress");
+  progress.max = 100;
+  progress.value = torrent.progress || 0;
+  const progressCell = row.insertCell();
+  progressCell.appendChild(progress);
+  progressCell.append(" " + (torrent.progress || 0).toFixed(1) + "%");
+  cell(row, torrent.size === undefined ? "" : bytes(torrent.size), "number");
+  cell(row, rate(torrent.download_rate), "number");
+  cell(row, rate(torrent.upload_rate), "number");
+  cell(row, torrent.peers === undefined ? "" : torrent.peers, "number");
+  cell(row, torrent.ratio === undefined ? "" : torrent.ratio.toFixed(2), "number");
+  cell(row, duration(torrent.eta), "number");
+  const actions = row.insertCell();
+  if (torrent.name === null) {
+    return;
+  }
+  const path = "/api/torrents/" + hash;
+  if (torrent.state === "paused") {
+    button(actions, "Resume", () => api("POST", path + "/resume"));
+  } else {
+    button(actions, "Pause", () => api("POST", path + "/pause"));
+  }
+  button(actions, "Remove", async () => {
+    if (confirm("Remove " + torrent.name + "?")) {
+      await api("DELETE", path);
+    }
+  });
+  button(actions, "Delete", async () => {
+    if (confirm("Remove " + torrent.name + " and delete its files?")) {
+      await api("DELETE", path + "?delete_data=true");
+    }
+  });
+}
+
+function showDetails(torrent) {
+  const section = document.getElementById("details");
+  section.hidden = !torrent;
+  if (!torrent) {
+    return;
+  }
+  document.getElementById("details-name").textContent = torrent.name;
+  const files = document.querySelector("#details-files tbody");
+  files.replaceChildren();
+  for (const file of torrent.files) {
+    const row = files.insertRow();
+    cell(row, file.path, "name");
+    cell(row, bytes(file.length), "number");
+    const percent = file.length ? (file.done * 100) / file.length : 100;
+    cell(row, percent.toFixed(1) + "%", "number");
+    cell(row, file.priority);
+  }
+  const peers = document.querySelector("#details-peers tbody");
+  peers.replaceChildren();
+  for (const peer of torrent.peers_list) {
+    const row = peers.insertRow();
+    cell(row, peer.addr);
+    cell(row, peer.client || "");
+    cell(row, peer.flags);
+    cell(row, rate(peer.download_rate), "number");
+    cell(row, rate(peer.upload_rate), "number");
+    cell(row, peer.progress.toFixed(1) + "%", "number");
+  }
+  const trackers = document.getElementById("details-trackers");
+  trackers.replaceChildren();
+  for (const url of torrent.trackers) {
+    const item = document.createElement("li");
+    item.textContent = url;
+    trackers.appendChild(item);
+  }
+}
+
+async function refresh() {
+  lastRefresh = Date.now();
+  try {
+    const [torrents, stats] = await Promise.all([
+      api("GET", "/api/torrents"),
+      api("GET", "/api/stats"),
+    ]);
+    document.getElementById("rates").textContent =
+      "↓ " + (rate(stats.download_rate) || "0 B/s") +
+      "  ↑ " + (rate(stats.upload_rate) || "0 B/s") +
+      "  " + stats.connections + " connections";
+    const body = document.querySelector("#torrents tbody");
+    body.replaceChildren();
+    for (const torrent of torrents) {
+      torrentRow(body, torrent);
+    }
+    if (selected && !torrents.some((t) => t.info_hash === selected && t.name !== null)) {
+      selected = null;
+    }
+    showDetails(selected ? await api("GET", "/api/torrents/" + selected) : null);
+    showError(null);
+  } catch (error) {
+    showError(error);
+  }
+}
+
+async function add(event) {
+  event.preventDefault();
+  const file = document.getElementById("add-file").files[0];
+  const uri = document.getElementById("add-uri").value.trim();
+  const savePath = document.getElementById("add-save-path").value.trim();
+  const paused = document.getElementById("add-paused").checked;
+  try {
+    if (file) {
+      const query = new URLSearchParams({ paused });
+      if (savePath) {
+        query.set("save_path", savePath);
+      }
+      await api("POST", "/api/torrents?" + query, await file.arrayBuffer(), "application/x-bittorrent");
+    } else if (uri) {
+      const request = { paused };
+      request[uri.startsWith("magnet:") ? "uri" : "path"] = uri;
+      if (savePath) {
+        request.save_path = savePath;
+      }
+      await api("POST", "/api/torrents", JSON.stringify(request));
+    } else {
+      return;
+    }
+    event.target.reset();
+    await refresh();
+  } catch (error) {
+    showError(error);
+  }
+}
+
+// rates are shown in KiB/s and sent in bytes per second
+const RATES = ["download_rate_limit", "upload_rate_limit"];
+
+async function showSettings() {
+  try {
+    const settings = await api("GET", "/api/settings");
+    const form = document.getElementById("settings-form");
+    for (const [key, value] of Object.entries(settings)) {
+      const input = form.elements[key];
+      if (input) {
+        input.value = value === null ? "" : RATES.includes(key) ? Math.round(value / 1024) : value;
+      }
+    }
+    document.getElementById("settings").showModal();
+  } catch (error) {
+    showError(error);
+  }
+}
+
+async function saveSettings() {
+  const form = document.getElementById("settings-form");
+  const settings = {};
+  for (const input of form.querySelectorAll("input")) {
+    const value = input.value === "" ? null : Number(input.value);
+    settings[input.name] = value !== null && RATES.includes(input.name) ? value * 1024 : value;
+  }
+  try {
+    await api("PATCH", "/api/settings", JSON.stringify(settings));
+  } catch (error) {
+    showError(error);
+  }
+}
+
+document.getElementById("add").addEventListener("submit", add);
+document.getElementById("show-settings").addEventListener("click", showSettings);
+document.getElementById("settings").addEventListener("close", (event) => {
+  if (event.target.returnValue === "save") {
+    saveSettings();
+  }
+});
+document.getElementById("hide-details").addEventListener("click", () => {
+  selected = null;
+  showDetails(null);
+});
+// refreshes on the events of the session, resuming from the last cursor
+// seen when the connection drops
+function streamEvents(cursor) {
+  const query = new URLSearchParams();
+  const token = localStorage.getItem(TOKEN_KEY);
+  if (token) {
+    query.set("token", token);
+  }
+  if (cursor !== undefined) {
+    query.set("cursor", cursor);
+  }
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  const socket = new WebSocket(scheme + "//" + location.host + "/api/events?" + query);
+  let pending = null;
+  socket.addEventListener("open", () => {
+    streaming = true;
+  });
+  socket.addEventListener("message", (message) => {
+    const data = JSON.parse(message.data);
+    if (data.cursor !== undefined) {
+      cursor = data.cursor;
+    }
+    if (!pending) {
+      pending = setTimeout(() => {
+        pending = null;
+        refresh();
+      }, EVENT_DELAY_MS);
+    }
+  });
+  socket.addEventListener("close", () => {
+    streaming = false;
+    setTimeout(() => streamEvents(cursor), RECONNECT_MS);
+  });
+}
+
+refresh();
+streamEvents();
+setInterval(() => {
+  if (!streaming || Date.now() - lastRefresh >= STREAMING_REFRESH_MS) {
+    refresh();
+  }
+}, REFRESH_MS);
@@ -0,0 +1,133 @@
+//! The server side of WebSocket (RFC 6455), as far as pushing text to a
+//! client needs it: the handshake key, unmasked frames out and masked
+//! frames in. Messages of the client aren't reassembled, only its control
+//! frames matter.
+
+use crate::base64;
+use sha1::{Digest, Sha1};
+use std::io::{self, Read, Write};
+
+pub const TEXT: u8 = 0x1;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xa;
+
+/// appended to the key of the client before hashing it
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// A frame of the client, unmasked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// `Sec-WebSocket-Accept` for the `Sec-WebSocket-Key` of a client
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&Sha1::digest(format!("{}{}", key.trim(), GUID)))
+}
+
+/// a whole message in one frame, unmasked as servers send them
+pub fn write_frame(writer: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)
+}
+
+/// the next frame of a client, which must be masked and at most `max` long
+pub fn read_frame(reader: &mut impl Read, max: usize) -> io::Result<Frame> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let opcode = head[0] & 0x0f;
+    if head[1] & 0x80 == 0 {
+        return Err(invalid("frames of clients must be masked"));
+    }
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u64::from(u16::from_be_bytes(length))
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => u64::from(length),
+    };
+    // control frames are never fragmented nor longer than 125
+    if opcode & 0x8 != 0 && (length > 125 || head[0] & 0x80 == 0) {
+        return Err(invalid("invalid control frame"));
+    }
+    if length > max as u64 {
+        return Err(invalid("frame too large"));
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin: head[0] & 0x80 != 0,
+        opcode,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn frames_messages() -> io::Result<()> {
+        // the example of the RFC
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let mut out = vec![];
+        write_frame(&mut out, TEXT, b"Hello")?;
+        assert_eq!(out, b"\x81\x05Hello");
+        out.clear();
+        write_frame(&mut out, TEXT, &[b'a'; 300])?;
+        assert_eq!(&out[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(out.len(), 304);
+
+        let frame = read_frame(&mut &masked(PING, b"hi")[..], 125)?;
+        assert_eq!(
+            frame,
+            Frame {
+                fin: true,
+                opcode: PING,
+                payload: b"hi".to_vec()
+            }
+        );
+        assert!(read_frame(&mut &b"\x81\x02hi"[..], 125).is_err());
+        assert!(read_frame(&mut &masked(TEXT, &[0; 100])[..], 10).is_err());
+        Ok(())
+    }
+}